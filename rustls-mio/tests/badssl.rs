@@ -33,9 +33,7 @@ mod online {
     fn expired() {
         connect("expired.badssl.com")
             .fails()
-            .expect(
-                r#"TLS error: InvalidCertificateData\("invalid peer certificate: CertExpired"\)"#,
-            )
+            .expect(r"TLS error: CertExpired")
             .go()
             .unwrap();
     }
@@ -44,7 +42,7 @@ mod online {
     fn wrong_host() {
         connect("wrong.host.badssl.com")
             .fails()
-            .expect(r#"TLS error: InvalidCertificateData\("invalid peer certificate: CertNotValidForName"\)"#)
+            .expect(r"TLS error: CertNotValidForName")
             .go()
             .unwrap();
     }
@@ -53,9 +51,7 @@ mod online {
     fn self_signed() {
         connect("self-signed.badssl.com")
             .fails()
-            .expect(
-                r#"TLS error: InvalidCertificateData\("invalid peer certificate: UnknownIssuer"\)"#,
-            )
+            .expect(r"TLS error: UnknownIssuer")
             .go()
             .unwrap();
     }
@@ -122,9 +118,7 @@ mod online {
     fn sha1_2016() {
         connect("sha1-2016.badssl.com")
             .fails()
-            .expect(
-                r#"TLS error: InvalidCertificateData\("invalid peer certificate: CertExpired"\)"#,
-            )
+            .expect(r"TLS error: CertExpired")
             .go()
             .unwrap();
     }
@@ -4,10 +4,18 @@
 /// See the comment in lib.rs to understand why we need this.
 
 #[cfg_attr(feature = "read_buf", rustversion::not(nightly))]
-fn main() {}
+fn main() {
+    declare_read_buf_cfg();
+}
 
 #[cfg(feature = "read_buf")]
 #[rustversion::nightly]
 fn main() {
+    declare_read_buf_cfg();
     println!("cargo:rustc-cfg=read_buf");
 }
+
+/// Tells newer compilers that `read_buf` is an expected cfg name.
+fn declare_read_buf_cfg() {
+    println!("cargo:rustc-check-cfg=cfg(read_buf)");
+}
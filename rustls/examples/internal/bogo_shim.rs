@@ -9,7 +9,7 @@ use env_logger;
 use rustls;
 
 use rustls::internal::msgs::codec::{Codec, Reader};
-use rustls::internal::msgs::enums::{CipherSuite, ProtocolVersion};
+use rustls::internal::msgs::enums::ProtocolVersion;
 use rustls::internal::msgs::persist;
 use rustls::quic::{self, ClientQuicExt, QuicExt, ServerQuicExt};
 use rustls::server::ClientHello;
@@ -196,7 +196,7 @@ impl rustls::server::ClientCertVerifier for DummyClientAuth {
         true
     }
 
-    fn client_auth_mandatory(&self) -> Option<bool> {
+    fn client_auth_mandatory(&self, _server_name: Option<&str>) -> Option<bool> {
         Some(self.mandatory)
     }
 
@@ -459,12 +459,9 @@ impl rustls::client::StoresClientSessions for ClientCacheWithoutKxHints {
             return true;
         }
 
-        let mut reader = Reader::init(&value[2..]);
-        let csv = CipherSuite::read_bytes(&value[..2])
-            .and_then(|suite| {
-                persist::ClientSessionValue::read(&mut reader, suite, &rustls::ALL_CIPHER_SUITES)
-            })
-            .unwrap();
+        let mut reader = Reader::init(&value);
+        let csv =
+            persist::ClientSessionValue::read(&mut reader, &rustls::ALL_CIPHER_SUITES).unwrap();
 
         let value = match csv {
             persist::ClientSessionValue::Tls13(mut tls13) => {
@@ -83,6 +83,10 @@ fn communicate(
                     | Error::InvalidCertificateSignature
                     | Error::InvalidCertificateSignatureType
                     | Error::InvalidCertificateEncoding
+                    | Error::CertExpired
+                    | Error::CertNotValidYet
+                    | Error::CertNotValidForName
+                    | Error::UnknownIssuer
                     | Error::AlertReceived(_) => Ok(Verdict::Reject(err)),
                     _ => Err(From::from(format!("{:?}", err))),
                 };
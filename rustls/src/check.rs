@@ -1,9 +1,18 @@
+use crate::conn::CommonState;
 use crate::error::Error;
+use crate::key;
 #[cfg(feature = "logging")]
 use crate::log::warn;
-use crate::msgs::enums::{ContentType, HandshakeType};
+use crate::msgs::enums::{AlertDescription, ContentType, HandshakeType};
 use crate::msgs::message::MessagePayload;
 
+/// The default limit on how many certificates a peer may send us.
+pub(crate) const DEFAULT_MAX_CERT_CHAIN_LENGTH: usize = 10;
+
+/// The default limit on the encoded size of each certificate a peer
+/// sends us.
+pub(crate) const DEFAULT_MAX_CERT_SIZE: usize = 0x8000;
+
 /// For a Message $m, and a HandshakePayload enum member $payload_type,
 /// return Ok(payload) if $m is both a handshake message and one that
 /// has the given $payload_type.  If not, return Err(rustls::Error) quoting
@@ -75,3 +84,38 @@ pub(crate) fn inappropriate_handshake_message(
         payload => inappropriate_message(payload, content_types),
     }
 }
+
+/// Check a certificate chain received from the peer against our limits
+/// on its length and on the size of each certificate, so an abusive
+/// peer is turned away before we do any expensive work on the chain.
+pub(crate) fn check_cert_chain_limits(
+    common: &mut CommonState,
+    chain: &[key::Certificate],
+    max_chain_length: usize,
+    max_cert_size: usize,
+) -> Result<(), Error> {
+    if chain.len() > max_chain_length {
+        warn!(
+            "peer sent {} certificates, limit is {}",
+            chain.len(),
+            max_chain_length
+        );
+        common.send_fatal_alert(AlertDescription::BadCertificate);
+        return Err(Error::PeerMisbehavedError(
+            "certificate chain too long".to_string(),
+        ));
+    }
+
+    if chain
+        .iter()
+        .any(|cert| cert.0.len() > max_cert_size)
+    {
+        warn!("peer sent certificate over size limit {}", max_cert_size);
+        common.send_fatal_alert(AlertDescription::BadCertificate);
+        return Err(Error::PeerMisbehavedError(
+            "certificate too large".to_string(),
+        ));
+    }
+
+    Ok(())
+}
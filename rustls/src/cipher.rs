@@ -1,19 +1,97 @@
+use crate::crypto::NONCE_LEN;
 use crate::error::Error;
 use crate::msgs::codec;
-use crate::msgs::message::{BorrowedPlainMessage, OpaqueMessage, PlainMessage};
+use crate::msgs::message::{
+    BorrowedOpaqueMessage, BorrowedPlainMessage, OpaqueMessage, PlainMessage,
+};
 
-use ring::{aead, hkdf};
+use std::cmp;
 
 /// Objects with this trait can decrypt TLS messages.
 pub trait MessageDecrypter: Send + Sync {
     /// Perform the decryption over the concerned TLS message.
 
     fn decrypt(&self, m: OpaqueMessage, seq: u64) -> Result<PlainMessage, Error>;
+
+    /// Like `decrypt`, but decrypt `m` in the buffer holding it, and
+    /// return its plaintext as part of that buffer.  rustls uses this
+    /// for every received record, so it need not allocate.
+    ///
+    /// The default implementation copies `m` and calls `decrypt`.
+    fn decrypt_in_place<'a>(
+        &self,
+        m: BorrowedOpaqueMessage<'a>,
+        seq: u64,
+    ) -> Result<BorrowedPlainMessage<'a>, Error> {
+        let plain = self.decrypt(m.to_opaque_message(), seq)?;
+        let payload = m
+            .payload
+            .get_mut(..plain.payload.0.len())
+            .ok_or(Error::DecryptError)?;
+        payload.copy_from_slice(&plain.payload.0);
+        Ok(BorrowedPlainMessage {
+            typ: plain.typ,
+            version: plain.version,
+            payload,
+        })
+    }
 }
 
 /// Objects with this trait can encrypt TLS messages.
 pub(crate) trait MessageEncrypter: Send + Sync {
     fn encrypt(&self, m: BorrowedPlainMessage, seq: u64) -> Result<OpaqueMessage, Error>;
+
+    /// Like `encrypt`, but pad the record according to `padding`, if the
+    /// protocol version allows it.  The padded record must still carry no
+    /// more than `max_fragment_len` bytes of content and padding.
+    fn encrypt_padded(
+        &self,
+        m: BorrowedPlainMessage,
+        seq: u64,
+        padding: PaddingPolicy,
+        max_fragment_len: usize,
+    ) -> Result<OpaqueMessage, Error> {
+        let _ = (padding, max_fragment_len);
+        self.encrypt(m, seq)
+    }
+}
+
+/// How to pad TLS1.3 records, to hide the length of their contents
+/// from an observer.  Padding is zero bytes added to each record before
+/// it is encrypted (RFC8446 section 5.4), and the peer discards it.
+///
+/// Lengths here are of the encrypted record, not counting its 5 byte
+/// header: that is, what an observer sees.  Records are never padded
+/// beyond the largest the peer accepts, so a full-sized record may not
+/// reach the padded length.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaddingPolicy {
+    /// Don't pad records.
+    None,
+
+    /// Pad each record to a multiple of this many bytes.
+    PadToMultiple(usize),
+
+    /// Pad each record to this many bytes, if it is shorter.
+    PadToFixed(usize),
+}
+
+impl PaddingPolicy {
+    /// How many bytes of padding to add to a record `len` bytes long,
+    /// which may grow to `max_len` bytes.
+    pub(crate) fn padding_len(self, len: usize, max_len: usize) -> usize {
+        let padded = match self {
+            Self::None => len,
+            Self::PadToMultiple(n) if n > 1 => match len % n {
+                0 => len,
+                rem => len.saturating_add(n - rem),
+            },
+            Self::PadToMultiple(_) => len,
+            Self::PadToFixed(n) => cmp::max(len, n),
+        };
+
+        cmp::min(padded, cmp::max(len, max_len)) - len
+    }
 }
 
 impl dyn MessageEncrypter {
@@ -30,17 +108,17 @@ impl dyn MessageDecrypter {
 
 /// A write or read IV.
 #[derive(Default)]
-pub(crate) struct Iv(pub(crate) [u8; ring::aead::NONCE_LEN]);
+pub(crate) struct Iv(pub(crate) [u8; NONCE_LEN]);
 
 impl Iv {
     #[cfg(feature = "tls12")]
-    fn new(value: [u8; ring::aead::NONCE_LEN]) -> Self {
+    fn new(value: [u8; NONCE_LEN]) -> Self {
         Self(value)
     }
 
     #[cfg(feature = "tls12")]
     pub(crate) fn copy(value: &[u8]) -> Self {
-        debug_assert_eq!(value.len(), ring::aead::NONCE_LEN);
+        debug_assert_eq!(value.len(), NONCE_LEN);
         let mut iv = Self::new(Default::default());
         iv.0.copy_from_slice(value);
         iv
@@ -52,24 +130,14 @@ impl Iv {
     }
 }
 
-pub(crate) struct IvLen;
-
-impl hkdf::KeyType for IvLen {
-    fn len(&self) -> usize {
-        aead::NONCE_LEN
+impl Drop for Iv {
+    fn drop(&mut self) {
+        crate::zeroize::zeroize(&mut self.0);
     }
 }
 
-impl From<hkdf::Okm<'_, IvLen>> for Iv {
-    fn from(okm: hkdf::Okm<IvLen>) -> Self {
-        let mut r = Self(Default::default());
-        okm.fill(&mut r.0[..]).unwrap();
-        r
-    }
-}
-
-pub(crate) fn make_nonce(iv: &Iv, seq: u64) -> ring::aead::Nonce {
-    let mut nonce = [0u8; ring::aead::NONCE_LEN];
+pub(crate) fn make_nonce(iv: &Iv, seq: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
     codec::put_u64(seq, &mut nonce[4..]);
 
     nonce
@@ -79,7 +147,7 @@ pub(crate) fn make_nonce(iv: &Iv, seq: u64) -> ring::aead::Nonce {
             *nonce ^= *iv;
         });
 
-    aead::Nonce::assume_unique_for_key(nonce)
+    nonce
 }
 
 /// A `MessageEncrypter` which doesn't work.
@@ -1,18 +1,23 @@
 use crate::anchors;
 use crate::builder::{ConfigBuilder, WantsVerifier};
+use crate::check;
 use crate::client::handy;
 use crate::client::{ClientConfig, ResolvesClientCert};
+use crate::crypto::ring::Ring;
 use crate::error::Error;
 use crate::key;
 use crate::kx::SupportedKxGroup;
+use crate::msgs::enums::MaxFragmentLength;
+use crate::msgs::hsjoiner::DEFAULT_MAX_HANDSHAKE_SIZE;
+use crate::rand::DefaultSecureRandom;
 use crate::suites::SupportedCipherSuite;
-use crate::verify::{self, CertificateTransparencyPolicy};
+use crate::verify::{self, CertificateTransparencyPolicy, CrlExpiryPolicy};
 use crate::versions;
-use crate::NoKeyLog;
+use crate::{DefaultTimeProvider, NoKeyLog};
 
 use std::marker::PhantomData;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 impl ConfigBuilder<ClientConfig, WantsVerifier> {
     /// Choose how to verify client certificates.
@@ -25,7 +30,8 @@ impl ConfigBuilder<ClientConfig, WantsVerifier> {
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
-                root_store,
+                verifier: verify::WebPkiVerifier::new(root_store, None),
+                max_fragment_length: None,
             },
             side: PhantomData::default(),
         }
@@ -43,6 +49,7 @@ impl ConfigBuilder<ClientConfig, WantsVerifier> {
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
                 verifier,
+                max_fragment_length: None,
             },
             side: PhantomData::default(),
         }
@@ -60,10 +67,82 @@ pub struct WantsTransparencyPolicyOrClientCert {
     cipher_suites: Vec<SupportedCipherSuite>,
     kx_groups: Vec<&'static SupportedKxGroup>,
     versions: versions::EnabledVersions,
-    root_store: anchors::RootCertStore,
+    verifier: verify::WebPkiVerifier,
+    max_fragment_length: Option<usize>,
 }
 
 impl ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert> {
+    /// Check server certificates against DER-encoded certificate
+    /// revocation lists.
+    ///
+    /// A server certificate chain is rejected with [`Error::CertRevoked`]
+    /// if any certificate in it appears in a CRL signed by its issuer.
+    /// `expiry_policy` says what to do if a CRL has expired.
+    ///
+    /// A chain is also rejected, with [`Error::InvalidCrl`], if a CRL
+    /// naming the issuer of one of its certificates is not signed by it.
+    ///
+    /// This function fails if any of `crls` cannot be parsed, or is
+    /// signed with an unsupported algorithm such as RSASSA-PSS.
+    pub fn with_crls(
+        mut self,
+        crls: Vec<Vec<u8>>,
+        expiry_policy: CrlExpiryPolicy,
+    ) -> Result<Self, Error> {
+        self.state.verifier.with_crls(crls)?;
+        self.state
+            .verifier
+            .set_crl_expiry_policy(expiry_policy);
+        Ok(self)
+    }
+
+    /// Set how far our clock may be wrong.  A server certificate chain
+    /// which has expired, or is not yet valid, is accepted if it would
+    /// be valid within `clock_skew` of the current time.
+    ///
+    /// The default is zero.
+    pub fn with_clock_skew(mut self, clock_skew: Duration) -> Self {
+        self.state
+            .verifier
+            .set_clock_skew(clock_skew);
+        self
+    }
+
+    /// Set whether to reject a server certificate chain if any certificate
+    /// in it is signed using MD5 or SHA-1, even one which is not needed to
+    /// reach a trust anchor.
+    ///
+    /// The default is false.
+    pub fn with_reject_weak_signature_algorithms(mut self, reject: bool) -> Self {
+        self.state
+            .verifier
+            .set_reject_weak_signature_algorithms(reject);
+        self
+    }
+
+    /// Set whether to reject server certificates with the RFC7633 TLS
+    /// feature extension requiring `status_request` ("OCSP must-staple"),
+    /// unless the server staples a valid and current OCSP response for it.
+    ///
+    /// The default is false.
+    pub fn with_ocsp_must_staple(mut self, enforce: bool) -> Self {
+        self.state
+            .verifier
+            .set_enforce_ocsp_must_staple(enforce);
+        self
+    }
+
+    /// Ask the server to limit record payloads to `len` bytes, using the
+    /// RFC6066 max_fragment_length extension.  This sets
+    /// [`ClientConfig::max_fragment_length`].
+    ///
+    /// This function fails with [`Error::BadMaxFragmentSize`] unless `len`
+    /// is 512, 1024, 2048 or 4096.
+    pub fn with_max_fragment_length(mut self, len: usize) -> Result<Self, Error> {
+        self.state.max_fragment_length = Some(check_max_fragment_length(len)?);
+        Ok(self)
+    }
+
     /// Set Certificate Transparency logs to use for server certificate validation.
     ///
     /// Because Certificate Transparency logs are sharded on a per-year basis and can be trusted or
@@ -88,7 +167,8 @@ impl ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert> {
     /// `cert_chain` is a vector of DER-encoded certificates.
     /// `key_der` is a DER-encoded RSA, ECDSA, or Ed25519 private key.
     ///
-    /// This function fails if `key_der` is invalid.
+    /// This function fails if `key_der` is invalid, or is not the key
+    /// for the end-entity certificate.
     pub fn with_single_cert(
         self,
         cert_chain: Vec<key::Certificate>,
@@ -114,18 +194,17 @@ impl ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert> {
     }
 
     fn with_logs(
-        self,
+        mut self,
         ct_policy: Option<CertificateTransparencyPolicy>,
     ) -> ConfigBuilder<ClientConfig, WantsClientCert> {
+        self.state.verifier.ct_policy = ct_policy;
         ConfigBuilder {
             state: WantsClientCert {
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
-                verifier: Arc::new(verify::WebPkiVerifier::new(
-                    self.state.root_store,
-                    ct_policy,
-                )),
+                verifier: Arc::new(self.state.verifier),
+                max_fragment_length: self.state.max_fragment_length,
             },
             side: PhantomData,
         }
@@ -141,16 +220,29 @@ pub struct WantsClientCert {
     kx_groups: Vec<&'static SupportedKxGroup>,
     versions: versions::EnabledVersions,
     verifier: Arc<dyn verify::ServerCertVerifier>,
+    max_fragment_length: Option<usize>,
 }
 
 impl ConfigBuilder<ClientConfig, WantsClientCert> {
+    /// Ask the server to limit record payloads to `len` bytes, using the
+    /// RFC6066 max_fragment_length extension.  This sets
+    /// [`ClientConfig::max_fragment_length`].
+    ///
+    /// This function fails with [`Error::BadMaxFragmentSize`] unless `len`
+    /// is 512, 1024, 2048 or 4096.
+    pub fn with_max_fragment_length(mut self, len: usize) -> Result<Self, Error> {
+        self.state.max_fragment_length = Some(check_max_fragment_length(len)?);
+        Ok(self)
+    }
+
     /// Sets a single certificate chain and matching private key for use
     /// in client authentication.
     ///
     /// `cert_chain` is a vector of DER-encoded certificates.
     /// `key_der` is a DER-encoded RSA, ECDSA, or Ed25519 private key.
     ///
-    /// This function fails if `key_der` is invalid.
+    /// This function fails if `key_der` is invalid, or is not the key
+    /// for the end-entity certificate.
     pub fn with_single_cert(
         self,
         cert_chain: Vec<key::Certificate>,
@@ -171,18 +263,45 @@ impl ConfigBuilder<ClientConfig, WantsClientCert> {
         client_auth_cert_resolver: Arc<dyn ResolvesClientCert>,
     ) -> ClientConfig {
         ClientConfig {
-            cipher_suites: self.state.cipher_suites,
+            cipher_suites: self.state.cipher_suites.into(),
+            signature_schemes: None,
+            certificate_signature_schemes: None,
             kx_groups: self.state.kx_groups,
             alpn_protocols: Vec::new(),
             session_storage: handy::ClientSessionMemoryCache::new(256),
             max_fragment_size: None,
+            max_fragment_length: self.state.max_fragment_length,
+            record_size_limit: None,
+            max_handshake_message_size: DEFAULT_MAX_HANDSHAKE_SIZE,
+            max_cert_chain_length: check::DEFAULT_MAX_CERT_CHAIN_LENGTH,
+            max_cert_size: check::DEFAULT_MAX_CERT_SIZE,
+            require_ems: false,
+            record_padding: None,
+            key_update_threshold: None,
+            limit_outgoing_plaintext: None,
+            on_handshake_complete: None,
             client_auth_cert_resolver,
             enable_tickets: true,
             versions: self.state.versions,
             enable_sni: true,
             verifier: self.state.verifier,
+            raw_public_key_verifier: None,
+            delegated_credential_schemes: Vec::new(),
+            time_provider: Arc::new(DefaultTimeProvider),
+            secure_random: Arc::new(DefaultSecureRandom),
+            crypto_provider: Arc::new(Ring),
             key_log: Arc::new(NoKeyLog {}),
             enable_early_data: false,
+            cert_compressors: Vec::new(),
+            cert_decompressors: Vec::new(),
+            enable_post_handshake_auth: false,
         }
     }
 }
+
+fn check_max_fragment_length(len: usize) -> Result<usize, Error> {
+    match MaxFragmentLength::from_fragment_len(len) {
+        Some(_) => Ok(len),
+        None => Err(Error::BadMaxFragmentSize),
+    }
+}
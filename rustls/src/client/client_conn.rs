@@ -1,5 +1,8 @@
 use crate::builder::{ConfigBuilder, WantsCipherSuites};
-use crate::conn::{CommonState, ConnectionCommon, Protocol, Side};
+use crate::cipher::PaddingPolicy;
+use crate::compress;
+use crate::conn::{CommonState, ConnectionCommon, HandshakeCompleteCallback, Protocol, Side};
+use crate::crypto::CryptoProvider;
 use crate::error::Error;
 use crate::kx::SupportedKxGroup;
 #[cfg(feature = "logging")]
@@ -7,14 +10,20 @@ use crate::log::trace;
 #[cfg(feature = "quic")]
 use crate::msgs::enums::AlertDescription;
 use crate::msgs::enums::CipherSuite;
+use crate::msgs::enums::MaxFragmentLength;
 use crate::msgs::enums::ProtocolVersion;
 use crate::msgs::enums::SignatureScheme;
+use crate::msgs::fragmenter;
 use crate::msgs::handshake::ClientExtension;
+use crate::msgs::persist::ResumptionPsk;
+use crate::rand::SecureRandom;
 use crate::sign;
-use crate::suites::SupportedCipherSuite;
+use crate::suites::{self, SupportedCipherSuite};
+use crate::tls13::key_schedule::Secret;
 use crate::verify;
 use crate::versions;
 use crate::KeyLog;
+use crate::TimeProvider;
 
 use super::hs;
 #[cfg(feature = "quic")]
@@ -23,13 +32,22 @@ use crate::quic;
 use std::convert::TryFrom;
 use std::error::Error as StdError;
 use std::marker::PhantomData;
+use std::net::IpAddr;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
+use std::time::SystemTime;
 use std::{fmt, io, mem};
 
 /// A trait for the ability to store client session data.
 /// The keys and values are opaque.
 ///
+/// The keys and values are byte strings, so they can be kept outside
+/// this process: for example, in a cache shared by several processes
+/// connecting to the same servers.  Their encoding is the same for all
+/// releases with the same minor version (all 0.20.x releases, say).
+/// Each value starts with a format tag, and a value written by a release
+/// with a different encoding is ignored: we do a full handshake instead.
+///
 /// Both the keys and values should be treated as
 /// **highly sensitive data**, containing enough key material
 /// to break all security of the corresponding session.
@@ -46,6 +64,47 @@ pub trait StoresClientSessions: Send + Sync {
     /// Returns the latest value for `key`.  Returns `None`
     /// if there's no such value.
     fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+    /// Stores a new resumable session `value` for `key`.  Returns
+    /// `true` if the value was stored.
+    ///
+    /// `expires` is when the server said the session stops being
+    /// useful, if it did.  `get` need not return the value after
+    /// then, so implementations can use it, along with the size
+    /// `value.len()`, to choose which entries to evict.  rustls
+    /// ignores expired sessions in any case.
+    ///
+    /// Sessions are stored with this rather than `put`, which is
+    /// used for data without an expiry.  The default implementation
+    /// calls `put`.
+    fn put_with_expiry(&self, key: Vec<u8>, value: Vec<u8>, expires: Option<SystemTime>) -> bool {
+        let _ = expires;
+        self.put(key, value)
+    }
+
+    /// Stores a new TLS1.3 resumable session `value` for `key`, as
+    /// `put_with_expiry` does.  Returns `true` if the value was stored.
+    ///
+    /// `psk` is the pre-shared key that `value` holds.  Its identity
+    /// is the ticket the server issued, so a cache shared between
+    /// processes can use it to find the entry the server stored
+    /// with [`StoresServerSessions::put_tls13_session`]; `get` is
+    /// still called with `key`.  `value` contains `psk.secret()`, so
+    /// treat it as sensitive.
+    ///
+    /// The default implementation calls `put_with_expiry`.
+    ///
+    /// [`StoresServerSessions::put_tls13_session`]: crate::server::StoresServerSessions::put_tls13_session
+    fn put_tls13_session(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        psk: &ResumptionPsk<'_>,
+        expires: Option<SystemTime>,
+    ) -> bool {
+        let _ = psk;
+        self.put_with_expiry(key, value, expires)
+    }
 }
 
 /// A trait for the ability to choose a certificate chain and
@@ -55,9 +114,13 @@ pub trait ResolvesClientCert: Send + Sync {
     /// the server's supported signature schemes in `sigschemes`,
     /// return a certificate chain and signing key to authenticate.
     ///
-    /// `acceptable_issuers` is undecoded and unverified by the rustls
-    /// library, but it should be expected to contain a DER encodings
-    /// of X501 NAMEs.
+    /// `acceptable_issuers` comes from the CertificateRequest's
+    /// `certificate_authorities` list in TLS1.2, or its
+    /// `certificate_authorities` extension in TLS1.3; it is empty if
+    /// the server sent none.  It is undecoded and unverified by the
+    /// rustls library, but it should be expected to contain DER
+    /// encodings of X501 NAMEs.  A resolver holding several identities
+    /// can use these to choose one issued by a CA the server accepts.
     ///
     /// Return None to continue the handshake without any client
     /// authentication.  The server may reject the handshake later
@@ -70,6 +133,20 @@ pub trait ResolvesClientCert: Send + Sync {
 
     /// Return true if any certificates at all are available.
     fn has_certs(&self) -> bool;
+
+    /// Return true if this resolver only provides raw public keys
+    /// (RFC7250): that is, every [`sign::CertifiedKey`] it returns has
+    /// a `cert` of one DER-encoded SubjectPublicKeyInfo, rather than a
+    /// certificate chain.
+    ///
+    /// We then offer to authenticate with a raw public key, and only
+    /// call `resolve` if the server accepts one.  This is only supported
+    /// in TLS1.3.
+    ///
+    /// The default implementation returns false.
+    fn only_raw_public_keys(&self) -> bool {
+        false
+    }
 }
 
 /// Common configuration for (typically) all connections made by
@@ -78,6 +155,11 @@ pub trait ResolvesClientCert: Send + Sync {
 /// Making one of these can be expensive, and should be
 /// once per process rather than once per connection.
 ///
+/// One `Arc<ClientConfig>` is meant to be shared by all the connections
+/// using it.  Cloning a `ClientConfig` to adjust it is also cheap: the
+/// root certificates, verifiers, resolvers, session storage and cipher
+/// suites are behind `Arc`s, and are shared with the clone.
+///
 /// These must be created via the [`ClientConfig::builder()`] function.
 ///
 /// # Defaults
@@ -89,7 +171,15 @@ pub trait ResolvesClientCert: Send + Sync {
 #[derive(Clone)]
 pub struct ClientConfig {
     /// List of ciphersuites, in preference order.
-    pub(super) cipher_suites: Vec<SupportedCipherSuite>,
+    pub(super) cipher_suites: Arc<[SupportedCipherSuite]>,
+
+    /// The signature schemes we offer, in preference order, or None
+    /// to offer those supported by `verifier`.
+    pub(super) signature_schemes: Option<Vec<SignatureScheme>>,
+
+    /// The signature schemes we accept in the server's certificates,
+    /// in preference order, or None to use `signature_schemes`.
+    pub(super) certificate_signature_schemes: Option<Vec<SignatureScheme>>,
 
     /// List of supported key exchange algorithms, in preference order -- the
     /// first element is the highest priority.
@@ -114,6 +204,105 @@ pub struct ClientConfig {
     /// Setting this value to the TCP MSS may improve latency for stream-y workloads.
     pub max_fragment_size: Option<usize>,
 
+    /// If set, ask the server to limit record payloads to this many bytes,
+    /// using the RFC6066 max_fragment_length extension.  If the server
+    /// agrees, both sides limit the records they send, and refuse larger
+    /// ones.
+    ///
+    /// This must be 512, 1024, 2048 or 4096.  Set it with
+    /// [`ConfigBuilder::with_max_fragment_length`] to have other values
+    /// rejected when building the config; values set here directly are
+    /// reported as errors from ClientConnection::new.
+    ///
+    /// The default is None: the extension is not sent.
+    pub max_fragment_length: Option<usize>,
+
+    /// If set, advertise this as the largest record we will accept,
+    /// using the RFC8449 record_size_limit extension.  The server's
+    /// limit, if it sends one, is then respected when we send.
+    ///
+    /// This must be between 64 and 16385.  Other values are reported
+    /// as errors from ClientConnection::new.
+    ///
+    /// The default is None: the extension is not sent.
+    pub record_size_limit: Option<u16>,
+
+    /// The largest handshake message we will accept from the peer, in
+    /// bytes.  Larger messages are rejected with a `decode_error` alert
+    /// as soon as their header arrives, so a peer cannot make us buffer
+    /// up to the 16MB the protocol allows.
+    ///
+    /// The default is 64KB less one byte.  Raise this if you expect to
+    /// receive unusually large certificate chains.
+    pub max_handshake_message_size: usize,
+
+    /// The most certificates we will accept in a chain from the peer.
+    /// Longer chains are rejected with a `bad_certificate` alert before
+    /// any attempt is made to verify them.
+    ///
+    /// The default is 10.
+    pub max_cert_chain_length: usize,
+
+    /// The largest certificate we will accept from the peer, in bytes.
+    /// A chain including a larger certificate is rejected with a
+    /// `bad_certificate` alert before any attempt is made to verify it.
+    ///
+    /// The default is 32KB.
+    pub max_cert_size: usize,
+
+    /// Whether to refuse TLS1.2 connections with servers which do not
+    /// support the extended master secret (RFC7627).  Without it, TLS1.2
+    /// sessions are open to the triple handshake attack.
+    ///
+    /// This has no effect on TLS1.3 connections, whose keys always
+    /// depend on the whole handshake.
+    ///
+    /// The default is false, for interoperability with older servers.
+    pub require_ems: bool,
+
+    /// How to pad TLS1.3 records we send, to hide the length of the
+    /// data in them.  This has no effect on TLS1.2 or QUIC connections.
+    ///
+    /// The default is None: records are not padded.
+    pub record_padding: Option<PaddingPolicy>,
+
+    /// The number of records to send under one TLS1.3 traffic key.  Once
+    /// this many have been sent, we send a KeyUpdate and change to the next
+    /// key before writing more data.
+    ///
+    /// Whatever this is set to, we change keys before sending 2^24 records
+    /// under any one key.  This has no effect on TLS1.2 or QUIC connections.
+    ///
+    /// The default is None: we only change keys to stay within that limit.
+    pub key_update_threshold: Option<u64>,
+
+    /// The most application data we will buffer for sending, either
+    /// before the handshake completes or while waiting for
+    /// `write_tls` to drain earlier records.  Once this much is
+    /// buffered, writes to [`Connection::writer`](crate::Connection::writer)
+    /// are short, and return `Ok(0)` if nothing more can be accepted.
+    ///
+    /// This sets the starting value of
+    /// [`CommonState::set_buffer_limit`](crate::CommonState::set_buffer_limit)
+    /// for each connection, which can still be changed afterwards.
+    ///
+    /// The default is None: connections start with a 64KB limit.
+    pub limit_outgoing_plaintext: Option<usize>,
+
+    /// Called once, when the handshake completes.  By then all the
+    /// details of the connection, such as the negotiated cipher suite
+    /// and the server's certificates, are available from the
+    /// [`CommonState`](crate::CommonState) passed in.  This is useful to
+    /// wake a task waiting for the handshake, without polling
+    /// [`CommonState::is_handshaking`](crate::CommonState::is_handshaking).
+    ///
+    /// The callback is made from within
+    /// [`Connection::process_new_packets`](crate::Connection::process_new_packets),
+    /// so it must not block.
+    ///
+    /// The default is None.
+    pub on_handshake_complete: Option<Arc<HandshakeCompleteCallback>>,
+
     /// How to decide what client auth certificate/keys to use.
     pub client_auth_cert_resolver: Arc<dyn ResolvesClientCert>,
 
@@ -131,12 +320,50 @@ pub struct ClientConfig {
     /// Whether to send the Server Name Indication (SNI) extension
     /// during the client handshake.
     ///
+    /// Turning this off does not change certificate verification: the
+    /// server's certificate is still checked against the name passed
+    /// to [`ClientConnection::new`], it just isn't sent to the server.
+    ///
     /// The default is true.
     pub enable_sni: bool,
 
     /// How to verify the server certificate chain.
     pub(super) verifier: Arc<dyn verify::ServerCertVerifier>,
 
+    /// If set, we offer to accept a raw public key (RFC7250) from the
+    /// server in place of a certificate chain, and verify it with this.
+    /// Servers that send a certificate chain are still verified as usual.
+    ///
+    /// This only has an effect on TLS1.3 connections.
+    ///
+    /// The default is None: we only accept certificate chains.
+    pub raw_public_key_verifier: Option<Arc<dyn verify::RawPublicKeyVerifier>>,
+
+    /// The signature schemes we accept for delegated credentials
+    /// (RFC9345): short-lived keys the server's certificate key has
+    /// authorised to sign the handshake.  A delegated credential is
+    /// only accepted if its certificate is verified by `verifier`.
+    ///
+    /// This only has an effect on TLS1.3 connections.
+    ///
+    /// The default is empty: we don't accept delegated credentials.
+    pub delegated_credential_schemes: Vec<SignatureScheme>,
+
+    /// How to find the current time, which is passed to `verifier`.
+    /// The default uses the system clock.
+    pub time_provider: Arc<dyn TimeProvider>,
+
+    /// The source of random bytes for the values rustls generates
+    /// itself: the random value in our hello message, session IDs,
+    /// and so on.  See [`SecureRandom`] for what this does not cover.
+    /// The default uses the operating system's RNG.
+    pub secure_random: Arc<dyn SecureRandom>,
+
+    /// The implementation of the cryptography used by the protocol:
+    /// hashing, HMAC, HKDF and record protection.  The default is
+    /// backed by *ring*.
+    pub crypto_provider: Arc<dyn CryptoProvider>,
+
     /// How to output key material for debugging.  The default
     /// does nothing.
     pub key_log: Arc<dyn KeyLog>,
@@ -146,6 +373,29 @@ pub struct ClientConfig {
     ///
     /// The default is false.
     pub enable_early_data: bool,
+
+    /// Certificate compression algorithms we can use to compress our
+    /// client certificate, in preference order.  We only compress
+    /// if the server asks for one of these.
+    ///
+    /// The default is empty: we never compress.
+    pub cert_compressors: Vec<Arc<dyn compress::CertCompressor>>,
+
+    /// Certificate compression algorithms we offer to decompress, in
+    /// preference order.  If empty, we don't offer certificate
+    /// compression in TLS1.3.
+    ///
+    /// The default is empty.
+    pub cert_decompressors: Vec<Arc<dyn compress::CertDecompressor>>,
+
+    /// Whether to offer TLS1.3 post-handshake client authentication.
+    /// If the server then requests it, we answer using
+    /// `client_auth_cert_resolver`.
+    ///
+    /// This is not offered to QUIC servers.
+    ///
+    /// The default is false.
+    pub enable_post_handshake_auth: bool,
 }
 
 impl ClientConfig {
@@ -171,6 +421,75 @@ impl ClientConfig {
                 .any(|cs| cs.version().version == v)
     }
 
+    /// Change the order in which cipher suites are offered to the server.
+    ///
+    /// The suites in `order` are offered first, in that order, followed by
+    /// the rest of the configured suites in their existing order.  For
+    /// example, a device without AES hardware might prefer the ChaCha20
+    /// suites.
+    ///
+    /// This fails, leaving the order unchanged, if `order` contains a suite
+    /// which is not configured.
+    pub fn set_cipher_suite_order(&mut self, order: &[SupportedCipherSuite]) -> Result<(), Error> {
+        self.cipher_suites = suites::reorder_suites(&self.cipher_suites, order)?.into();
+        Ok(())
+    }
+
+    /// The signature schemes we offer to the server in the
+    /// `signature_algorithms` extension, in preference order.  The server
+    /// must sign the handshake with one of these.
+    ///
+    /// By default, these are the schemes the certificate verifier supports.
+    pub fn signature_schemes(&self) -> Vec<SignatureScheme> {
+        match &self.signature_schemes {
+            Some(schemes) => schemes.clone(),
+            None => self.verifier.supported_verify_schemes(),
+        }
+    }
+
+    /// Set the signature schemes we offer to the server, in preference
+    /// order.  For example, leaving out the `RSA_PKCS1_*` schemes forbids
+    /// PKCS#1 v1.5 signatures.
+    ///
+    /// This fails, leaving the schemes unchanged, if `schemes` is empty,
+    /// repeats a scheme, or contains a scheme the certificate verifier
+    /// doesn't support.
+    pub fn set_signature_schemes(&mut self, schemes: &[SignatureScheme]) -> Result<(), Error> {
+        let supported = self.verifier.supported_verify_schemes();
+        sign::check_signature_schemes(schemes, |scheme| supported.contains(&scheme))?;
+        self.signature_schemes = Some(schemes.to_vec());
+        Ok(())
+    }
+
+    /// The signature schemes we accept in the server's certificate
+    /// chain, in preference order.  These are offered in the
+    /// `signature_algorithms_cert` extension, which is only sent
+    /// if they differ from [`ClientConfig::signature_schemes`].
+    ///
+    /// By default, these are the same as `signature_schemes`.
+    pub fn certificate_signature_schemes(&self) -> Vec<SignatureScheme> {
+        match &self.certificate_signature_schemes {
+            Some(schemes) => schemes.clone(),
+            None => self.signature_schemes(),
+        }
+    }
+
+    /// Set the signature schemes we accept in the server's certificate
+    /// chain, independently of those used to sign the handshake.  A server
+    /// with several certificates can use these to pick one we can verify.
+    ///
+    /// This fails, leaving the schemes unchanged, for the same reasons as
+    /// [`ClientConfig::set_signature_schemes`].
+    pub fn set_certificate_signature_schemes(
+        &mut self,
+        schemes: &[SignatureScheme],
+    ) -> Result<(), Error> {
+        let supported = self.verifier.supported_verify_schemes();
+        sign::check_signature_schemes(schemes, |scheme| supported.contains(&scheme))?;
+        self.certificate_signature_schemes = Some(schemes.to_vec());
+        Ok(())
+    }
+
     /// Access configuration options whose use is dangerous and requires
     /// extra care.
     #[cfg(feature = "dangerous_configuration")]
@@ -182,26 +501,32 @@ impl ClientConfig {
         self.cipher_suites
             .iter()
             .copied()
-            .find(|&scs| scs.suite() == suite)
+            .find(|&scs| {
+                scs.suite() == suite
+                    && self
+                        .crypto_provider
+                        .supports_aead(scs.common().bulk)
+            })
     }
 }
 
 /// Encodes ways a client can know the expected name of the server.
 ///
-/// This currently covers knowing the DNS name of the server, but
-/// will be extended in the future to knowing the IP address of the
-/// server, as well as supporting privacy-preserving names for the
-/// server ("ECH").  For this reason this enum is `non_exhaustive`.
+/// This currently covers knowing the DNS name or IP address of the
+/// server, but will be extended in the future to supporting
+/// privacy-preserving names for the server ("ECH").  For this reason
+/// this enum is `non_exhaustive`.
 ///
 /// # Making one
 ///
-/// If you have a DNS name as a `&str`, this type implements `TryFrom<&str>`,
-/// so you can do:
+/// If you have a DNS name or IP address as a `&str`, this type implements
+/// `TryFrom<&str>`, so you can do:
 ///
 /// ```
 /// # use std::convert::{TryInto, TryFrom};
 /// # use rustls::ServerName;
 /// ServerName::try_from("example.com").expect("invalid DNS name");
+/// ServerName::try_from("192.0.2.1").expect("invalid IP address");
 ///
 /// // or, alternatively...
 ///
@@ -215,6 +540,10 @@ pub enum ServerName {
     /// is sent in the TLS Server Name Indication (SNI)
     /// extension.
     DnsName(verify::DnsName),
+
+    /// The server is identified by an IP address.  SNI is not
+    /// done, because SNI only carries DNS names.
+    IpAddress(IpAddr),
 }
 
 impl ServerName {
@@ -224,6 +553,7 @@ impl ServerName {
     pub(crate) fn for_sni(&self) -> Option<webpki::DnsNameRef> {
         match self {
             Self::DnsName(dns_name) => Some(dns_name.0.as_ref()),
+            Self::IpAddress(_) => None,
         }
     }
 
@@ -231,34 +561,53 @@ impl ServerName {
     pub(crate) fn encode(&self) -> Vec<u8> {
         enum UniqueTypeCode {
             DnsName = 0x01,
+            IpAddress = 0x02,
         }
 
-        let Self::DnsName(dns_name) = self;
-        let bytes = dns_name.0.as_ref();
+        let (code, bytes): (_, Vec<u8>) = match self {
+            Self::DnsName(dns_name) => (
+                UniqueTypeCode::DnsName,
+                dns_name.as_ref().as_bytes().to_vec(),
+            ),
+            Self::IpAddress(IpAddr::V4(ip)) => (UniqueTypeCode::IpAddress, ip.octets().to_vec()),
+            Self::IpAddress(IpAddr::V6(ip)) => (UniqueTypeCode::IpAddress, ip.octets().to_vec()),
+        };
 
-        let mut r = Vec::with_capacity(2 + bytes.as_ref().len());
-        r.push(UniqueTypeCode::DnsName as u8);
-        r.push(bytes.as_ref().len() as u8);
-        r.extend_from_slice(bytes.as_ref());
+        let mut r = Vec::with_capacity(2 + bytes.len());
+        r.push(code as u8);
+        r.push(bytes.len() as u8);
+        r.extend_from_slice(&bytes);
 
         r
     }
 }
 
 /// Attempt to make a ServerName from a string by parsing
-/// it as a DNS name.
+/// it as a DNS name, or failing that as an IP address.
+///
+/// Internationalized domain names may be given in Unicode: they
+/// are converted to their ASCII form, which is what is sent in
+/// the SNI extension and matched against the server's certificate.
 impl TryFrom<&str> for ServerName {
     type Error = InvalidDnsNameError;
     fn try_from(s: &str) -> Result<Self, Self::Error> {
-        match webpki::DnsNameRef::try_from_ascii_str(s) {
+        let ascii = super::idna::to_ascii(s).ok_or(InvalidDnsNameError)?;
+        match webpki::DnsNameRef::try_from_ascii_str(&ascii) {
             Ok(dns) => Ok(Self::DnsName(verify::DnsName(dns.into()))),
-            Err(webpki::InvalidDnsNameError) => Err(InvalidDnsNameError),
+            Err(webpki::InvalidDnsNameError) => match s.parse() {
+                Ok(ip) => Ok(Self::IpAddress(ip)),
+                Err(_) => Err(InvalidDnsNameError),
+            },
         }
     }
 }
 
 /// The provided input could not be parsed because
-/// it is not a syntactically-valid DNS Name.
+/// it is not a syntactically-valid DNS Name or IP address.
+///
+/// This includes internationalized domain names with characters
+/// that can't appear in a hostname, and `xn--` labels that are not
+/// valid punycode.
 #[derive(Debug)]
 pub struct InvalidDnsNameError;
 
@@ -289,6 +638,12 @@ pub(super) mod danger {
         pub fn set_certificate_verifier(&mut self, verifier: Arc<dyn ServerCertVerifier>) {
             self.cfg.verifier = verifier;
         }
+
+        /// The `ServerCertVerifier` in use.  This is shared with any
+        /// clones of the `ClientConfig`.
+        pub fn certificate_verifier(&self) -> &Arc<dyn ServerCertVerifier> {
+            &self.cfg.verifier
+        }
     }
 }
 
@@ -325,6 +680,17 @@ impl EarlyData {
         )
     }
 
+    /// Whether the server accepted early data, or `None` if
+    /// we haven't offered it or don't know yet.
+    #[cfg(feature = "quic")]
+    fn outcome(&self) -> Option<bool> {
+        match self.state {
+            EarlyDataState::Disabled | EarlyDataState::Ready => None,
+            EarlyDataState::Accepted | EarlyDataState::AcceptedFinished => Some(true),
+            EarlyDataState::Rejected => Some(false),
+        }
+    }
+
     pub(super) fn enable(&mut self, max_data: usize) {
         assert_eq!(self.state, EarlyDataState::Disabled);
         self.state = EarlyDataState::Ready;
@@ -425,14 +791,60 @@ impl ClientConnection {
         Self::new_inner(config, name, Vec::new(), Protocol::Tcp)
     }
 
+    /// Make a new ClientConnection, like [`ClientConnection::new`],
+    /// which only offers the protocol versions in `versions`.
+    ///
+    /// `versions` must be a subset of the versions enabled in `config`,
+    /// with at least one usable cipher suite.  `config` itself is not
+    /// changed, so it can be shared with connections using all of them.
+    pub fn new_with_versions(
+        config: Arc<ClientConfig>,
+        name: ServerName,
+        versions: &[&'static versions::SupportedProtocolVersion],
+    ) -> Result<Self, Error> {
+        if versions
+            .iter()
+            .any(|v| !config.versions.contains(v.version))
+        {
+            return Err(Error::General(
+                "protocol version not enabled in config".into(),
+            ));
+        }
+
+        let mut config = (*config).clone();
+        config.versions = versions::EnabledVersions::new(versions);
+        if !config.supports_version(ProtocolVersion::TLSv1_2)
+            && !config.supports_version(ProtocolVersion::TLSv1_3)
+        {
+            return Err(Error::General("no usable protocol versions".into()));
+        }
+
+        Self::new_inner(Arc::new(config), name, Vec::new(), Protocol::Tcp)
+    }
+
     fn new_inner(
         config: Arc<ClientConfig>,
         name: ServerName,
         extra_exts: Vec<ClientExtension>,
         proto: Protocol,
     ) -> Result<Self, Error> {
+        if let Some(len) = config.max_fragment_length {
+            if MaxFragmentLength::from_fragment_len(len).is_none() {
+                return Err(Error::BadMaxFragmentSize);
+            }
+        }
+
+        fragmenter::check_record_size_limit(config.record_size_limit)?;
+
         let mut common_state = CommonState::new(config.max_fragment_size, Side::Client)?;
         common_state.protocol = proto;
+        if let Some(limit) = config.limit_outgoing_plaintext {
+            common_state.set_buffer_limit(Some(limit));
+        }
+        common_state.on_handshake_complete = config.on_handshake_complete.clone();
+        common_state
+            .record_layer
+            .set_padding(config.record_padding);
         let mut data = ClientConnectionData::new();
 
         let mut cx = hs::ClientContext {
@@ -440,8 +852,10 @@ impl ClientConnection {
             data: &mut data,
         };
 
+        let max_handshake_message_size = config.max_handshake_message_size;
         let state = hs::start_handshake(name, extra_exts, config, &mut cx)?;
-        let inner = ConnectionCommon::new(state, data, common_state);
+        let mut inner = ConnectionCommon::new(state, data, common_state);
+        inner.set_max_handshake_message_size(max_handshake_message_size);
 
         Ok(Self { inner })
     }
@@ -463,7 +877,10 @@ impl ClientConnection {
     ///
     /// The server can choose not to accept any sent early data --
     /// in this case the data is lost but the connection continues.  You
-    /// can tell this happened using `is_early_data_accepted`.
+    /// can tell this happened using `is_early_data_accepted`.  Once the
+    /// server's rejection has been processed, this returns None.  Data
+    /// which must arrive should then be written again with `writer()`;
+    /// it is sent once the handshake completes.
     pub fn early_data(&mut self) -> Option<WriteEarlyData> {
         if self.inner.data.early_data.is_enabled() {
             Some(WriteEarlyData::new(self))
@@ -474,6 +891,9 @@ impl ClientConnection {
 
     /// Returns True if the server signalled it will process early data.
     ///
+    /// The server signals this in its EncryptedExtensions message, so
+    /// this returns false until that has been received.
+    ///
     /// If you sent early data and this returns false at the end of the
     /// handshake then the server will not process the data.  This
     /// is not an error, but you may wish to resend the data.
@@ -481,6 +901,59 @@ impl ClientConnection {
         self.inner.data.early_data.is_accepted()
     }
 
+    /// Derives key material from the TLS1.3 early exporter secret.
+    ///
+    /// This works like [`ConnectionCommon::export_keying_material()`], but
+    /// uses the `early_exporter_master_secret` from RFC8446, which is
+    /// available as soon as early data can be sent.
+    ///
+    /// This fails if early data was not offered, or once the server has
+    /// rejected it.
+    pub fn export_keying_material_early(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        let data = &self.inner.data;
+        match &data.early_exporter_secret {
+            Some(secret) if data.early_data.is_enabled() || data.early_data.is_accepted() => {
+                secret.export_keying_material(output, label, context)
+            }
+            _ => Err(Error::General("early data was not used".into())),
+        }
+    }
+
+    /// Retrieves the OCSP response the server stapled to its certificate.
+    ///
+    /// This is exactly as the server sent it.  It is passed to the
+    /// certificate verifier, which only checks it if asked to enforce
+    /// OCSP must-staple (see [`ConfigBuilder::with_ocsp_must_staple`]).
+    ///
+    /// [`ConfigBuilder::with_ocsp_must_staple`]: crate::ConfigBuilder::with_ocsp_must_staple
+    ///
+    /// This returns None if the server didn't staple a response, and
+    /// for resumed handshakes.
+    pub fn get_peer_stapled_ocsp(&self) -> Option<&[u8]> {
+        self.inner.data.stapled_ocsp.as_deref()
+    }
+
+    /// Retrieves the Signed Certificate Timestamps (SCTs) for the server's
+    /// certificate, as a `SignedCertificateTimestampList` encoding (see
+    /// RFC6962).
+    ///
+    /// These are the SCTs the server sent in the handshake or, failing
+    /// that, the SCTs embedded in its certificate.  They are passed to the
+    /// certificate verifier, but rustls does not otherwise check them.
+    ///
+    /// This returns None if there are no SCTs, and for resumed handshakes.
+    pub fn get_peer_signed_cert_timestamps(&self) -> Option<&[u8]> {
+        self.inner
+            .data
+            .signed_cert_timestamps
+            .as_deref()
+    }
+
     fn write_early_data(&mut self, data: &[u8]) -> io::Result<usize> {
         self.inner
             .data
@@ -530,14 +1003,20 @@ impl From<ClientConnection> for crate::Connection {
 /// State associated with a client connection.
 pub struct ClientConnectionData {
     pub(super) early_data: EarlyData,
+    pub(super) early_exporter_secret: Option<Box<Secret>>,
     pub(super) resumption_ciphersuite: Option<SupportedCipherSuite>,
+    pub(super) stapled_ocsp: Option<Vec<u8>>,
+    pub(super) signed_cert_timestamps: Option<Vec<u8>>,
 }
 
 impl ClientConnectionData {
     fn new() -> Self {
         Self {
             early_data: EarlyData::new(),
+            early_exporter_secret: None,
             resumption_ciphersuite: None,
+            stapled_ocsp: None,
+            signed_cert_timestamps: None,
         }
     }
 }
@@ -556,6 +1035,10 @@ impl quic::QuicExt for ClientConnection {
     }
 
     fn zero_rtt_keys(&self) -> Option<quic::DirectionalKeys> {
+        if self.inner.data.early_data.state == EarlyDataState::Rejected {
+            return None;
+        }
+
         Some(quic::DirectionalKeys::new(
             self.inner
                 .data
@@ -565,10 +1048,14 @@ impl quic::QuicExt for ClientConnection {
                 .common_state
                 .quic
                 .early_secret
-                .as_ref()?,
+                .as_deref()?,
         ))
     }
 
+    fn zero_rtt_accepted(&self) -> Option<bool> {
+        self.inner.data.early_data.outcome()
+    }
+
     fn read_hs(&mut self, plaintext: &[u8]) -> Result<(), Error> {
         self.inner.read_quic_hs(plaintext)
     }
@@ -588,6 +1075,9 @@ pub trait ClientQuicExt {
     /// Make a new QUIC ClientConnection. This differs from `ClientConnection::new()`
     /// in that it takes an extra argument, `params`, which contains the
     /// TLS-encoded transport parameters to send.
+    ///
+    /// `params` is sent unmodified in the ClientHello; the server's parameters
+    /// are available from [`quic::QuicExt::get_quic_transport_parameters()`].
     fn new_quic(
         config: Arc<ClientConfig>,
         quic_version: quic::Version,
@@ -599,6 +1089,7 @@ pub trait ClientQuicExt {
                 "TLS 1.3 support is required for QUIC".into(),
             ));
         }
+        quic::check_cipher_suites(&config.cipher_suites)?;
 
         let ext = match quic_version {
             quic::Version::V1Draft => ClientExtension::TransportParametersDraft(params),
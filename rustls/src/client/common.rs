@@ -1,10 +1,12 @@
 use super::ResolvesClientCert;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
-use crate::msgs::enums::ExtensionType;
+use crate::msgs::codec::Codec;
+use crate::msgs::enums::{CertificateType, ExtensionType};
 use crate::msgs::handshake::CertificatePayload;
 use crate::msgs::handshake::SCTList;
 use crate::msgs::handshake::ServerExtension;
+use crate::x509;
 use crate::{sign, DistinguishedNames, SignatureScheme};
 
 use std::sync::Arc;
@@ -35,6 +37,19 @@ impl ServerCertDetails {
             .iter()
             .map(|payload| payload.0.as_slice())
     }
+
+    /// The encoded SCT list sent in the handshake, or else the one
+    /// embedded in the end-entity certificate.
+    pub(super) fn sct_list_encoding(&self) -> Option<Vec<u8>> {
+        match &self.scts {
+            Some(scts) => Some(scts.get_encoding()),
+            None => self
+                .cert_chain
+                .first()
+                .and_then(|cert| x509::embedded_sct_list(&cert.0))
+                .map(<[u8]>::to_vec),
+        }
+    }
 }
 
 pub(super) struct ClientHelloDetails {
@@ -85,10 +100,19 @@ pub(super) enum ClientAuthDetails {
 impl ClientAuthDetails {
     pub(super) fn resolve(
         resolver: &dyn ResolvesClientCert,
+        cert_type: CertificateType,
         canames: Option<&DistinguishedNames>,
         sigschemes: &[SignatureScheme],
         auth_context_tls13: Option<Vec<u8>>,
     ) -> Self {
+        // A resolver provides either raw public keys or certificate
+        // chains: if it's not the kind the server wants, we have nothing.
+        let raw_public_key = cert_type == CertificateType::RawPublicKey;
+        if resolver.only_raw_public_keys() != raw_public_key {
+            debug!("Client auth requested but no {:?} available", cert_type);
+            return Self::Empty { auth_context_tls13 };
+        }
+
         let acceptable_issuers = canames
             .map(Vec::as_slice)
             .unwrap_or_default()
@@ -4,8 +4,10 @@ use crate::key;
 use crate::limited_cache;
 use crate::msgs::enums::SignatureScheme;
 use crate::sign;
+use crate::x509;
 
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 /// An implementer of `StoresClientSessions` which does nothing.
 pub struct NoClientSessionStorage {}
@@ -22,9 +24,15 @@ impl client::StoresClientSessions for NoClientSessionStorage {
 
 /// An implementer of `StoresClientSessions` that stores everything
 /// in memory.  It enforces a limit on the number of entries
-/// to bound memory usage.
+/// to bound memory usage, evicting the least recently used
+/// entry first.  Expired sessions are never returned.
 pub struct ClientSessionMemoryCache {
-    cache: Mutex<limited_cache::LimitedCache<Vec<u8>, Vec<u8>>>,
+    cache: Mutex<limited_cache::LimitedCache<Vec<u8>, CacheEntry>>,
+}
+
+struct CacheEntry {
+    value: Vec<u8>,
+    expires: Option<SystemTime>,
 }
 
 impl ClientSessionMemoryCache {
@@ -40,19 +48,32 @@ impl ClientSessionMemoryCache {
 
 impl client::StoresClientSessions for ClientSessionMemoryCache {
     fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        self.cache
-            .lock()
-            .unwrap()
-            .insert(key, value);
-        true
+        self.put_with_expiry(key, value, None)
     }
 
     fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut cache = self.cache.lock().unwrap();
+        let expired = match cache.get_and_freshen(key)?.expires {
+            Some(expires) => expires < SystemTime::now(),
+            None => false,
+        };
+
+        if expired {
+            cache.remove(key);
+            return None;
+        }
+
+        cache
+            .get(key)
+            .map(|entry| entry.value.clone())
+    }
+
+    fn put_with_expiry(&self, key: Vec<u8>, value: Vec<u8>, expires: Option<SystemTime>) -> bool {
         self.cache
             .lock()
             .unwrap()
-            .get(key)
-            .cloned()
+            .insert(key, CacheEntry { value, expires });
+        true
     }
 }
 
@@ -81,7 +102,9 @@ impl AlwaysResolvesClientCert {
     ) -> Result<Self, Error> {
         let key = sign::any_supported_type(priv_key)
             .map_err(|_| Error::General("invalid private key".into()))?;
-        Ok(Self(Arc::new(sign::CertifiedKey::new(chain, key))))
+        let certified_key = sign::CertifiedKey::new(chain, key);
+        certified_key.keys_match()?;
+        Ok(Self(Arc::new(certified_key)))
     }
 }
 
@@ -99,10 +122,98 @@ impl client::ResolvesClientCert for AlwaysResolvesClientCert {
     }
 }
 
+/// Something that resolves to one of several client cert chains/keys,
+/// based on the certificate authorities the server says it accepts.
+///
+/// A chain is chosen if any certificate in it was issued by a CA named
+/// in the server's CertificateRequest, and its key can make one of the
+/// signatures the server accepts.  Chains are tried in the order they
+/// were added.  If none is suitable, or the server names no CAs, the
+/// default chain is used, if there is one.
+pub struct ResolvesClientCertUsingCaHints {
+    identities: Vec<Arc<sign::CertifiedKey>>,
+    default: Option<Arc<sign::CertifiedKey>>,
+}
+
+impl ResolvesClientCertUsingCaHints {
+    /// Create a new and empty (i.e., knows no certificates) resolver.
+    pub fn new() -> Self {
+        Self {
+            identities: Vec::new(),
+            default: None,
+        }
+    }
+
+    /// Add a new `sign::CertifiedKey`, to be used if the server names
+    /// a CA which issued any certificate in its chain.
+    ///
+    /// This function fails if the certificate chain is syntactically
+    /// faulty, or if the key doesn't match the certificate.
+    pub fn add(&mut self, ck: sign::CertifiedKey) -> Result<(), Error> {
+        ck.cross_check_end_entity_cert(None)?;
+        ck.keys_match()?;
+        self.identities.push(Arc::new(ck));
+        Ok(())
+    }
+
+    /// Set the `sign::CertifiedKey` to use if no other is suitable.
+    ///
+    /// This fails for the same reasons as [`ResolvesClientCertUsingCaHints::add`].
+    pub fn set_default(&mut self, ck: sign::CertifiedKey) -> Result<(), Error> {
+        ck.cross_check_end_entity_cert(None)?;
+        ck.keys_match()?;
+        self.default = Some(Arc::new(ck));
+        Ok(())
+    }
+}
+
+/// Whether a certificate in `ck`'s chain was issued by one of
+/// `acceptable_issuers`: DER-encoded X501 Names.
+fn issued_by_any(ck: &sign::CertifiedKey, acceptable_issuers: &[&[u8]]) -> bool {
+    ck.cert.iter().any(|cert| {
+        let (issuer, _) = match x509::issuer_and_subject(&cert.0) {
+            Some(names) => names,
+            None => return false,
+        };
+        let mut name = issuer.to_vec();
+        x509::wrap_in_sequence(&mut name);
+
+        // Some servers send the Name without its outer SEQUENCE.
+        acceptable_issuers
+            .iter()
+            .any(|acceptable| *acceptable == name.as_slice() || *acceptable == issuer)
+    })
+}
+
+impl client::ResolvesClientCert for ResolvesClientCertUsingCaHints {
+    fn resolve(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        self.identities
+            .iter()
+            .find(|ck| {
+                issued_by_any(ck, acceptable_issuers)
+                    && ck
+                        .key
+                        .choose_scheme(sigschemes)
+                        .is_some()
+            })
+            .or(self.default.as_ref())
+            .map(Arc::clone)
+    }
+
+    fn has_certs(&self) -> bool {
+        !self.identities.is_empty() || self.default.is_some()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::client::StoresClientSessions;
+    use std::time::Duration;
 
     #[test]
     fn test_noclientsessionstorage_drops_put() {
@@ -141,6 +252,45 @@ mod test {
         assert_eq!(c.get(&[0x01]), Some(vec![0x04]));
     }
 
+    #[test]
+    fn test_clientsessionmemorycache_evicts_least_recently_used() {
+        let c = ClientSessionMemoryCache::new(3);
+        assert_eq!(c.put(vec![0x01], vec![0x02]), true);
+        assert_eq!(c.put(vec![0x03], vec![0x04]), true);
+        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+        assert_eq!(c.put(vec![0x05], vec![0x06]), true);
+
+        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+        assert_eq!(c.get(&[0x03]), None);
+        assert_eq!(c.get(&[0x05]), Some(vec![0x06]));
+    }
+
+    #[test]
+    fn test_clientsessionmemorycache_never_returns_expired() {
+        let c = ClientSessionMemoryCache::new(4);
+        let past = SystemTime::now() - Duration::from_secs(1);
+        let future = SystemTime::now() + Duration::from_secs(3600);
+
+        assert_eq!(c.put_with_expiry(vec![0x01], vec![0x02], Some(past)), true);
+        assert_eq!(
+            c.put_with_expiry(vec![0x03], vec![0x04], Some(future)),
+            true
+        );
+        assert_eq!(c.put_with_expiry(vec![0x05], vec![0x06], None), true);
+
+        assert_eq!(c.get(&[0x01]), None);
+        assert_eq!(c.get(&[0x01]), None);
+        assert_eq!(c.get(&[0x03]), Some(vec![0x04]));
+        assert_eq!(c.get(&[0x05]), Some(vec![0x06]));
+    }
+
+    #[test]
+    fn test_noclientsessionstorage_drops_put_with_expiry() {
+        let c = NoClientSessionStorage {};
+        assert_eq!(c.put_with_expiry(vec![0x01], vec![0x02], None), false);
+        assert_eq!(c.get(&[0x01]), None);
+    }
+
     #[test]
     fn test_clientsessionmemorycache_drops_to_maintain_size_invariant() {
         let c = ClientSessionMemoryCache::new(2);
@@ -14,8 +14,9 @@ use crate::msgs::codec::{Codec, Reader};
 use crate::msgs::enums::{
     AlertDescription, CipherSuite, Compression, ContentType, ProtocolVersion,
 };
-use crate::msgs::enums::{ECPointFormat, PSKKeyExchangeMode};
+use crate::msgs::enums::{CertificateType, ECPointFormat, MaxFragmentLength, PSKKeyExchangeMode};
 use crate::msgs::enums::{ExtensionType, HandshakeType};
+use crate::msgs::fragmenter;
 use crate::msgs::handshake::{CertificateStatusRequest, ClientSessionTicket, SCTList};
 use crate::msgs::handshake::{ClientExtension, HasServerExtensions};
 use crate::msgs::handshake::{ClientHelloPayload, HandshakeMessagePayload, HandshakePayload};
@@ -25,8 +26,10 @@ use crate::msgs::handshake::{HelloRetryRequest, KeyShareEntry};
 use crate::msgs::handshake::{Random, SessionID};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
+use crate::suites;
 use crate::ticketer::TimeBase;
 use crate::tls13::key_schedule::KeyScheduleEarly;
+use crate::zeroize::Zeroizing;
 use crate::SupportedCipherSuite;
 
 #[cfg(feature = "tls12")]
@@ -57,13 +60,11 @@ fn find_session(
             None
         })?;
 
+    let value = Zeroizing::new(value);
     #[allow(unused_mut)]
-    let mut reader = Reader::init(&value[2..]);
+    let mut reader = Reader::init(&value);
     #[allow(clippy::bind_instead_of_map)] // https://github.com/rust-lang/rust-clippy/issues/8082
-    CipherSuite::read_bytes(&value[..2])
-        .and_then(|suite| {
-            persist::ClientSessionValue::read(&mut reader, suite, &config.cipher_suites)
-        })
+    persist::ClientSessionValue::read(&mut reader, &config.cipher_suites)
         .and_then(|resuming| {
             let retrieved = persist::Retrieved::new(resuming, TimeBase::now().ok()?);
             match retrieved.has_expired() {
@@ -118,7 +119,7 @@ pub(super) fn start_handshake(
             // we're  doing an abbreviated handshake.  See section 3.4 in
             // RFC5077.
             if !inner.ticket().is_empty() {
-                inner.session_id = SessionID::random()?;
+                inner.session_id = SessionID::random(&*config.secure_random)?;
             }
             session_id = Some(inner.session_id);
         }
@@ -131,10 +132,10 @@ pub(super) fn start_handshake(
     // https://tools.ietf.org/html/rfc8446#appendix-D.4
     // https://tools.ietf.org/html/draft-ietf-quic-tls-34#section-8.4
     if session_id.is_none() && !cx.common.is_quic() {
-        session_id = Some(SessionID::random()?);
+        session_id = Some(SessionID::random(&*config.secure_random)?);
     }
 
-    let random = Random::new()?;
+    let random = Random::new(&*config.secure_random)?;
     let hello_details = ClientHelloDetails::new();
     let sent_tls13_fake_ccs = false;
     let may_send_sct_list = config.verifier.request_scts();
@@ -231,18 +232,20 @@ fn emit_client_hello_for_retry(
             config
                 .kx_groups
                 .iter()
+                .filter(|skxg| support_tls13 || skxg.usable_for_tls12())
                 .map(|skxg| skxg.name)
                 .collect(),
         ),
-        ClientExtension::SignatureAlgorithms(
-            config
-                .verifier
-                .supported_verify_schemes(),
-        ),
+        ClientExtension::SignatureAlgorithms(config.signature_schemes()),
         ClientExtension::ExtendedMasterSecretRequest,
         ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()),
     ];
 
+    let cert_schemes = config.certificate_signature_schemes();
+    if cert_schemes != config.signature_schemes() {
+        exts.push(ClientExtension::SignatureAlgorithmsCert(cert_schemes));
+    }
+
     if let (Some(sni_name), true) = (server_name.for_sni(), config.enable_sni) {
         exts.push(ClientExtension::make_sni(sni_name));
     }
@@ -268,6 +271,64 @@ fn emit_client_hello_for_retry(
         exts.push(ClientExtension::PresharedKeyModes(psk_modes));
     }
 
+    if let Some(mfl) = config
+        .max_fragment_length
+        .and_then(MaxFragmentLength::from_fragment_len)
+    {
+        if !cx.common.is_quic() {
+            exts.push(ClientExtension::MaxFragmentLength(mfl));
+        }
+    }
+
+    if let Some(limit) = config.record_size_limit {
+        if !cx.common.is_quic() {
+            exts.push(ClientExtension::RecordSizeLimit(limit));
+        }
+    }
+
+    if support_tls13 && !config.cert_decompressors.is_empty() {
+        exts.push(ClientExtension::CertificateCompressionAlgorithms(
+            config
+                .cert_decompressors
+                .iter()
+                .map(|d| d.algorithm())
+                .collect(),
+        ));
+    }
+
+    if support_tls13 && config.enable_post_handshake_auth && !cx.common.is_quic() {
+        exts.push(ClientExtension::PostHandshakeAuth);
+    }
+
+    // RFC7250 raw public keys.  We only support these in TLS1.3.
+    if support_tls13 {
+        let client_types = offered_client_certificate_types(&config);
+        if !client_types.is_empty() {
+            exts.push(ClientExtension::ClientCertificateType(
+                client_types.to_vec(),
+            ));
+        }
+
+        let server_types = offered_server_certificate_types(&config);
+        if !server_types.is_empty() {
+            exts.push(ClientExtension::ServerCertificateType(
+                server_types.to_vec(),
+            ));
+        }
+    }
+
+    if support_tls13
+        && !config
+            .delegated_credential_schemes
+            .is_empty()
+    {
+        exts.push(ClientExtension::DelegatedCredential(
+            config
+                .delegated_credential_schemes
+                .clone(),
+        ));
+    }
+
     if !config.alpn_protocols.is_empty() {
         exts.push(ClientExtension::Protocols(ProtocolNameList::from_slices(
             &config
@@ -330,11 +391,11 @@ fn emit_client_hello_for_retry(
         .collect();
 
     let session_id = session_id.unwrap_or_else(SessionID::empty);
-    let mut cipher_suites: Vec<_> = config
-        .cipher_suites
-        .iter()
-        .map(|cs| cs.suite())
-        .collect();
+    let mut cipher_suites: Vec<_> =
+        suites::reduce_given_provider(&config.cipher_suites, &*config.crypto_provider)
+            .iter()
+            .map(|cs| cs.suite())
+            .collect();
     // We don't do renegotiation at all, in fact.
     cipher_suites.push(CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV);
 
@@ -351,7 +412,12 @@ fn emit_client_hello_for_retry(
     };
 
     let early_key_schedule = if let Some(resuming) = fill_in_binder {
-        let schedule = tls13::fill_in_psk_binder(&resuming, &transcript_buffer, &mut chp);
+        let schedule = tls13::fill_in_psk_binder(
+            &resuming,
+            Arc::clone(&config.crypto_provider),
+            &transcript_buffer,
+            &mut chp,
+        );
         Some((resuming.suite(), schedule))
     } else {
         None
@@ -388,6 +454,7 @@ fn emit_client_hello_for_retry(
 
         tls13::derive_early_traffic_secret(
             &*config.key_log,
+            config.crypto_provider.as_ref(),
             cx,
             resuming_suite,
             &schedule,
@@ -420,6 +487,56 @@ fn emit_client_hello_for_retry(
     }
 }
 
+pub(super) fn process_max_fragment_length(
+    common: &mut CommonState,
+    config: &ClientConfig,
+    mfl: Option<MaxFragmentLength>,
+) -> Result<(), Error> {
+    let mfl = match mfl {
+        Some(mfl) => mfl,
+        None => return Ok(()),
+    };
+
+    match mfl.fragment_len() {
+        Some(len) if config.max_fragment_length == Some(len) => {
+            debug!("Negotiated max_fragment_length {}", len);
+            common.limit_max_fragment_len(len);
+            common.limit_inbound_fragment_len(len);
+            Ok(())
+        }
+        _ => Err(common.illegal_param("server sent non-offered max_fragment_length")),
+    }
+}
+
+pub(super) fn process_record_size_limit(
+    common: &mut CommonState,
+    config: &ClientConfig,
+    limit: Option<u16>,
+) -> Result<(), Error> {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+
+    if limit < fragmenter::MIN_RECORD_SIZE_LIMIT {
+        return Err(common.illegal_param("server sent too-small record_size_limit"));
+    }
+
+    debug!("Server record_size_limit is {}", limit);
+    let is_tls13 = common.is_tls13();
+    let len = fragmenter::record_size_limit_to_fragment_len(limit, is_tls13);
+    common.limit_max_fragment_len(len);
+
+    // The server only replies if we offered a limit of our own, which now
+    // applies to what it sends us.
+    if let Some(ours) = config.record_size_limit {
+        common.limit_inbound_fragment_len(fragmenter::record_size_limit_to_fragment_len(
+            ours, is_tls13,
+        ));
+    }
+    Ok(())
+}
+
 pub(super) fn process_alpn_protocol(
     common: &mut CommonState,
     config: &ClientConfig,
@@ -545,6 +662,16 @@ impl State<ClientConnectionData> for ExpectServerHello {
         // Extract ALPN protocol
         if !cx.common.is_tls13() {
             process_alpn_protocol(cx.common, &self.config, server_hello.get_alpn_protocol())?;
+            process_max_fragment_length(
+                cx.common,
+                &self.config,
+                server_hello.get_max_fragment_length(),
+            )?;
+            process_record_size_limit(
+                cx.common,
+                &self.config,
+                server_hello.get_record_size_limit(),
+            )?;
         }
 
         // If ECPointFormats extension is supplied by the server, it must contain
@@ -590,7 +717,7 @@ impl State<ClientConnectionData> for ExpectServerHello {
         // Start our handshake hash, and input the server-hello.
         let mut transcript = self
             .transcript_buffer
-            .start_hash(suite.hash_algorithm());
+            .start_hash(Arc::clone(&self.config.crypto_provider), suite.hash());
         transcript.add_message(&m);
 
         let randoms = ConnectionRandoms::new(self.random, server_hello.random);
@@ -743,7 +870,7 @@ impl ExpectServerHelloOrHelloRetryRequest {
         let transcript = self
             .next
             .transcript_buffer
-            .start_hash(cs.hash_algorithm());
+            .start_hash(Arc::clone(&self.next.config.crypto_provider), cs.hash());
         let mut transcript_buffer = transcript.into_hrr_buffer();
         transcript_buffer.add_message(&m);
 
@@ -764,7 +891,8 @@ impl ExpectServerHelloOrHelloRetryRequest {
                         cx.common
                             .illegal_param("server requested hrr with bad group")
                     })?;
-                kx::KeyExchange::start(group).ok_or(Error::FailedToGetRandomBytes)?
+                kx::KeyExchange::start(group, &*self.next.config.secure_random)
+                    .ok_or(Error::FailedToGetRandomBytes)?
             }
             _ => offered_key_share,
         };
@@ -811,6 +939,35 @@ impl State<ClientConnectionData> for ExpectServerHelloOrHelloRetryRequest {
     }
 }
 
+/// The certificate types (RFC7250) we offer to authenticate ourselves
+/// with, in preference order.  If this is empty, we don't send the
+/// extension and can only use certificate chains.
+pub(super) fn offered_client_certificate_types(
+    config: &ClientConfig,
+) -> &'static [CertificateType] {
+    if config
+        .client_auth_cert_resolver
+        .only_raw_public_keys()
+    {
+        &[CertificateType::RawPublicKey]
+    } else {
+        &[]
+    }
+}
+
+/// The certificate types (RFC7250) we offer to accept from the server,
+/// in preference order.  If this is empty, we don't send the extension
+/// and can only accept certificate chains.
+pub(super) fn offered_server_certificate_types(
+    config: &ClientConfig,
+) -> &'static [CertificateType] {
+    if config.raw_public_key_verifier.is_some() {
+        &[CertificateType::RawPublicKey, CertificateType::X509]
+    } else {
+        &[]
+    }
+}
+
 pub(super) fn send_cert_error_alert(common: &mut CommonState, err: Error) -> Error {
     match err {
         Error::InvalidCertificateEncoding => {
@@ -819,6 +976,18 @@ pub(super) fn send_cert_error_alert(common: &mut CommonState, err: Error) -> Err
         Error::PeerMisbehavedError(_) => {
             common.send_fatal_alert(AlertDescription::IllegalParameter);
         }
+        Error::CertExpired | Error::CertNotValidYet => {
+            common.send_fatal_alert(AlertDescription::CertificateExpired);
+        }
+        Error::UnknownIssuer => {
+            common.send_fatal_alert(AlertDescription::UnknownCA);
+        }
+        Error::CertRevoked => {
+            common.send_fatal_alert(AlertDescription::CertificateRevoked);
+        }
+        Error::MissingOcspStaple => {
+            common.send_fatal_alert(AlertDescription::BadCertificateStatusResponse);
+        }
         _ => {
             common.send_fatal_alert(AlertDescription::BadCertificate);
         }
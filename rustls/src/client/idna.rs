@@ -0,0 +1,261 @@
+//! Conversion of internationalized domain names to their ASCII form.
+//!
+//! This covers what we need for SNI and certificate name matching:
+//! labels are case-folded and any that contain non-ASCII characters
+//! are encoded as A-labels (`xn--` followed by RFC3492 punycode).
+//! Full IDNA2008 validation (normalisation, bidi and contextual rules)
+//! is not attempted.
+
+const BASE: u32 = 36;
+const T_MIN: u32 = 1;
+const T_MAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 0x80;
+
+const ACE_PREFIX: &str = "xn--";
+
+/// Convert `name` to the form it takes on the wire: ASCII labels
+/// are kept as they are, and labels with non-ASCII characters become
+/// lowercase A-labels.
+///
+/// Returns `None` if a label is not a plausible IDN label, or is an
+/// A-label that is not valid punycode.
+pub(super) fn to_ascii(name: &str) -> Option<String> {
+    let mut out = String::with_capacity(name.len());
+
+    // IDNA treats these full stops the same as ".".
+    for (i, label) in name
+        .split(&['.', '\u{3002}', '\u{ff0e}', '\u{ff61}'][..])
+        .enumerate()
+    {
+        if i > 0 {
+            out.push('.');
+        }
+
+        if label.is_ascii() {
+            if is_ace_label(label) {
+                check_a_label(label)?;
+            }
+            out.push_str(label);
+            continue;
+        }
+
+        let label = label
+            .chars()
+            .flat_map(char::to_lowercase)
+            .collect::<Vec<char>>();
+        if !label
+            .iter()
+            .all(|c| c.is_alphanumeric() || *c == '-')
+        {
+            return None;
+        }
+
+        out.push_str(ACE_PREFIX);
+        out.push_str(&encode(&label)?);
+    }
+
+    Some(out)
+}
+
+fn is_ace_label(label: &str) -> bool {
+    label.len() >= ACE_PREFIX.len() && label[..ACE_PREFIX.len()].eq_ignore_ascii_case(ACE_PREFIX)
+}
+
+/// An A-label must decode to something non-ASCII, and encode back to itself.
+fn check_a_label(label: &str) -> Option<()> {
+    let label = label.to_ascii_lowercase();
+    let decoded = decode(&label[ACE_PREFIX.len()..])?;
+    if decoded.iter().all(char::is_ascii) || encode(&decoded)? != label[ACE_PREFIX.len()..] {
+        return None;
+    }
+    Some(())
+}
+
+fn adapt(mut delta: u32, num_points: u32, first_time: bool) -> u32 {
+    delta /= if first_time { DAMP } else { 2 };
+    delta += delta / num_points;
+
+    let mut k = 0;
+    while delta > ((BASE - T_MIN) * T_MAX) / 2 {
+        delta /= BASE - T_MIN;
+        k += BASE;
+    }
+
+    k + (((BASE - T_MIN + 1) * delta) / (delta + SKEW))
+}
+
+fn threshold(k: u32, bias: u32) -> u32 {
+    if k <= bias {
+        T_MIN
+    } else if k >= bias + T_MAX {
+        T_MAX
+    } else {
+        k - bias
+    }
+}
+
+fn encode_digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    }
+}
+
+fn decode_digit(c: u8) -> Option<u32> {
+    match c {
+        b'a'..=b'z' => Some((c - b'a') as u32),
+        b'A'..=b'Z' => Some((c - b'A') as u32),
+        b'0'..=b'9' => Some((c - b'0') as u32 + 26),
+        _ => None,
+    }
+}
+
+/// Punycode-encode `input`, as in RFC3492 section 6.3.
+fn encode(input: &[char]) -> Option<String> {
+    let mut output = input
+        .iter()
+        .filter(|c| c.is_ascii())
+        .collect::<String>();
+    let basic = output.len() as u32;
+    if basic > 0 {
+        output.push('-');
+    }
+
+    let mut n = INITIAL_N;
+    let mut delta = 0u32;
+    let mut bias = INITIAL_BIAS;
+    let mut handled = basic;
+
+    while (handled as usize) < input.len() {
+        let m = input
+            .iter()
+            .map(|&c| c as u32)
+            .filter(|&c| c >= n)
+            .min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+
+        for &c in input {
+            let c = c as u32;
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = threshold(k, bias);
+                    if q < t {
+                        break;
+                    }
+                    output.push(encode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(encode_digit(q));
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+
+        delta = delta.checked_add(1)?;
+        n = n.checked_add(1)?;
+    }
+
+    Some(output)
+}
+
+/// Punycode-decode `input`, as in RFC3492 section 6.2.
+fn decode(input: &str) -> Option<Vec<char>> {
+    let (basic, extended) = match input.rfind('-') {
+        Some(pos) => (&input[..pos], &input[pos + 1..]),
+        None => ("", input),
+    };
+    if !basic.is_ascii() {
+        return None;
+    }
+
+    let mut output = basic.chars().collect::<Vec<char>>();
+    let mut n = INITIAL_N;
+    let mut i = 0u32;
+    let mut bias = INITIAL_BIAS;
+    let mut digits = extended.bytes();
+
+    while digits.len() > 0 {
+        let old_i = i;
+        let mut w = 1u32;
+        let mut k = BASE;
+        loop {
+            let digit = decode_digit(digits.next()?)?;
+            i = i.checked_add(digit.checked_mul(w)?)?;
+            let t = threshold(k, bias);
+            if digit < t {
+                break;
+            }
+            w = w.checked_mul(BASE - t)?;
+            k += BASE;
+        }
+
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - old_i, len, old_i == 0);
+        n = n.checked_add(i / len)?;
+        i %= len;
+        output.insert(i as usize, std::char::from_u32(n)?);
+        i += 1;
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes_known_labels() {
+        for (unicode, punycode) in &[
+            ("bücher", "bcher-kva"),
+            ("münchen", "mnchen-3ya"),
+            ("mañana", "maana-pta"),
+            ("日本語", "wgv71a119e"),
+            ("ü", "tda"),
+        ] {
+            let chars = unicode.chars().collect::<Vec<char>>();
+            assert_eq!(encode(&chars).as_deref(), Some(*punycode));
+            assert_eq!(decode(punycode), Some(chars));
+        }
+    }
+
+    #[test]
+    fn converts_names_to_ascii() {
+        assert_eq!(
+            to_ascii("Bücher.Example").as_deref(),
+            Some("xn--bcher-kva.Example")
+        );
+        assert_eq!(to_ascii("日本語。jp").as_deref(), Some("xn--wgv71a119e.jp"));
+        assert_eq!(
+            to_ascii("xn--bcher-kva.example").as_deref(),
+            Some("xn--bcher-kva.example")
+        );
+        assert_eq!(
+            to_ascii("XN--BCHER-KVA.example").as_deref(),
+            Some("XN--BCHER-KVA.example")
+        );
+        assert_eq!(to_ascii("example.com").as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        assert_eq!(to_ascii("bü cher.example"), None);
+        assert_eq!(to_ascii("bü_cher.example"), None);
+        assert_eq!(to_ascii("xn--bcher-kv!.example"), None);
+        assert_eq!(to_ascii("xn--.example"), None);
+        assert_eq!(to_ascii("xn--zzzzzzzzzzzzzzzzzzzz.example"), None);
+        assert_eq!(to_ascii("xn--abc-.example"), None);
+    }
+}
@@ -1,4 +1,6 @@
-use crate::check::{inappropriate_handshake_message, inappropriate_message};
+use crate::check::{
+    check_cert_chain_limits, inappropriate_handshake_message, inappropriate_message,
+};
 use crate::conn::{CommonState, ConnectionRandoms, Side, State};
 use crate::error::Error;
 use crate::hash_hs::HandshakeHash;
@@ -7,7 +9,7 @@ use crate::log::{debug, trace};
 use crate::msgs::base::{Payload, PayloadU8};
 use crate::msgs::ccs::ChangeCipherSpecPayload;
 use crate::msgs::codec::Codec;
-use crate::msgs::enums::{AlertDescription, ProtocolVersion};
+use crate::msgs::enums::{AlertDescription, CertificateType, ProtocolVersion};
 use crate::msgs::enums::{ContentType, HandshakeType};
 use crate::msgs::handshake::{CertificatePayload, DecomposedSignatureScheme, SCTList, SessionID};
 use crate::msgs::handshake::{DigitallySignedStruct, ServerECDHParams};
@@ -26,7 +28,6 @@ use crate::client::common::ClientAuthDetails;
 use crate::client::common::ServerCertDetails;
 use crate::client::{hs, ClientConfig, ServerName};
 
-use ring::agreement::PublicKey;
 use ring::constant_time;
 
 use std::sync::Arc;
@@ -71,8 +72,19 @@ mod server_hello {
                     .illegal_param("downgrade to TLS1.2 when TLS1.3 is supported"));
             }
 
+            if !server_hello.session_id.is_empty() {
+                cx.common.session_id = Some(server_hello.session_id);
+            }
+
             // Doing EMS?
             self.using_ems = server_hello.ems_support_acked();
+            if self.config.require_ems && !self.using_ems {
+                cx.common
+                    .send_fatal_alert(AlertDescription::HandshakeFailure);
+                return Err(Error::PeerIncompatibleError(
+                    "server does not support extended master secret".to_string(),
+                ));
+            }
 
             // Might the server send a ticket?
             let must_issue_new_ticket = if server_hello
@@ -125,8 +137,12 @@ mod server_hello {
                         return Err(Error::PeerMisbehavedError(error_msg));
                     }
 
-                    let secrets =
-                        ConnectionSecrets::new_resume(self.randoms, suite, resuming.secret());
+                    let secrets = ConnectionSecrets::new_resume(
+                        self.randoms,
+                        Arc::clone(&self.config.crypto_provider),
+                        suite,
+                        resuming.secret(),
+                    );
                     self.config.key_log.log(
                         "CLIENT_RANDOM",
                         &secrets.randoms.client,
@@ -138,6 +154,7 @@ mod server_hello {
                     // Since we're resuming, we verified the certificate and
                     // proof of possession in the prior session.
                     cx.common.peer_certificates = Some(resuming.server_cert_chain().to_vec());
+                    cx.common.resumed = true;
                     let cert_verified = verify::ServerCertVerified::assertion();
                     let sig_verified = verify::HandshakeSignatureValid::assertion();
 
@@ -204,17 +221,19 @@ struct ExpectCertificate {
 }
 
 impl State<ClientConnectionData> for ExpectCertificate {
-    fn handle(
-        mut self: Box<Self>,
-        _cx: &mut ClientContext<'_>,
-        m: Message,
-    ) -> hs::NextStateOrError {
+    fn handle(mut self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
         self.transcript.add_message(&m);
         let server_cert_chain = require_handshake_msg_move!(
             m,
             HandshakeType::Certificate,
             HandshakePayload::Certificate
         )?;
+        check_cert_chain_limits(
+            cx.common,
+            &server_cert_chain,
+            self.config.max_cert_chain_length,
+            self.config.max_cert_size,
+        )?;
 
         if self.may_send_cert_status {
             Ok(Box::new(ExpectCertificateStatusOrServerKx {
@@ -248,6 +267,10 @@ impl State<ClientConnectionData> for ExpectCertificate {
             }))
         }
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 struct ExpectCertificateStatusOrServerKx {
@@ -314,6 +337,10 @@ impl State<ClientConnectionData> for ExpectCertificateStatusOrServerKx {
             )),
         }
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 struct ExpectCertificateStatus {
@@ -368,6 +395,10 @@ impl State<ClientConnectionData> for ExpectCertificateStatus {
             must_issue_new_ticket: self.must_issue_new_ticket,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 struct ExpectServerKx {
@@ -424,6 +455,10 @@ impl State<ClientConnectionData> for ExpectServerKx {
             must_issue_new_ticket: self.must_issue_new_ticket,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 fn emit_certificate(
@@ -443,9 +478,9 @@ fn emit_certificate(
     common.send_msg(cert, false);
 }
 
-fn emit_clientkx(transcript: &mut HandshakeHash, common: &mut CommonState, pubkey: &PublicKey) {
+fn emit_clientkx(transcript: &mut HandshakeHash, common: &mut CommonState, pubkey: &[u8]) {
     let mut buf = Vec::new();
-    let ecpoint = PayloadU8::new(Vec::from(pubkey.as_ref()));
+    let ecpoint = PayloadU8::new(Vec::from(pubkey));
     ecpoint.encode(&mut buf);
     let pubkey = Payload::new(buf);
 
@@ -591,6 +626,10 @@ impl State<ClientConnectionData> for ExpectServerDoneOrCertReq {
             .handle(cx, m)
         }
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 struct ExpectCertificateRequest {
@@ -632,6 +671,7 @@ impl State<ClientConnectionData> for ExpectCertificateRequest {
             self.config
                 .client_auth_cert_resolver
                 .as_ref(),
+            CertificateType::X509,
             Some(&certreq.canames),
             &certreq.sigschemes,
             NO_CONTEXT,
@@ -652,6 +692,10 @@ impl State<ClientConnectionData> for ExpectCertificateRequest {
             must_issue_new_ticket: self.must_issue_new_ticket,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 struct ExpectServerDone {
@@ -713,8 +757,8 @@ impl State<ClientConnectionData> for ExpectServerDone {
             .cert_chain
             .split_first()
             .ok_or(Error::NoCertificatesPresented)?;
-        let now = std::time::SystemTime::now();
-        let cert_verified = st
+        let now = st.config.time_provider.now()?;
+        let mut cert_verified = st
             .config
             .verifier
             .verify_server_cert(
@@ -726,6 +770,7 @@ impl State<ClientConnectionData> for ExpectServerDone {
                 now,
             )
             .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+        let verified_chain = cert_verified.take_verified_chain();
 
         // 3.
         // Build up the contents of the signed message.
@@ -748,12 +793,27 @@ impl State<ClientConnectionData> for ExpectServerDone {
                 return Err(Error::PeerMisbehavedError(error_message));
             }
 
+            if !st
+                .config
+                .signature_schemes()
+                .contains(&sig.scheme)
+            {
+                return Err(cx
+                    .common
+                    .illegal_param("server signed kx with unoffered scheme"));
+            }
+
             st.config
                 .verifier
                 .verify_tls12_signature(&message, &st.server_cert.cert_chain[0], sig)
                 .map_err(|err| hs::send_cert_error_alert(cx.common, err))?
         };
+        cx.data.signed_cert_timestamps = st.server_cert.sct_list_encoding();
         cx.common.peer_certificates = Some(st.server_cert.cert_chain);
+        cx.common.verified_cert_chain = verified_chain;
+        if !st.server_cert.ocsp_response.is_empty() {
+            cx.data.stapled_ocsp = Some(st.server_cert.ocsp_response);
+        }
 
         // 4.
         if let Some(client_auth) = &st.client_auth {
@@ -769,10 +829,13 @@ impl State<ClientConnectionData> for ExpectServerDone {
             tls12::decode_ecdh_params::<ServerECDHParams>(cx.common, &st.server_kx.kx_params)?;
         let group =
             kx::KeyExchange::choose(ecdh_params.curve_params.named_group, &st.config.kx_groups)
+                .filter(|skxg| skxg.usable_for_tls12())
                 .ok_or_else(|| {
                     Error::PeerMisbehavedError("peer chose an unsupported group".to_string())
                 })?;
-        let kx = kx::KeyExchange::start(group).ok_or(Error::FailedToGetRandomBytes)?;
+        let kx = kx::KeyExchange::start(group, &*st.config.secure_random)
+            .ok_or(Error::FailedToGetRandomBytes)?;
+        cx.common.kx_group = Some(group.name);
 
         // 5b.
         let mut transcript = st.transcript;
@@ -796,6 +859,7 @@ impl State<ClientConnectionData> for ExpectServerDone {
             &ecdh_params.public.0,
             ems_seed,
             st.randoms,
+            Arc::clone(&st.config.crypto_provider),
             suite,
         )?;
 
@@ -842,6 +906,10 @@ impl State<ClientConnectionData> for ExpectServerDone {
             }))
         }
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 struct ExpectNewTicket {
@@ -885,6 +953,10 @@ impl State<ClientConnectionData> for ExpectNewTicket {
             sig_verified: self.sig_verified,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 // -- Waiting for their CCS --
@@ -936,6 +1008,10 @@ impl State<ClientConnectionData> for ExpectCcs {
             sig_verified: self.sig_verified,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 struct ExpectFinished {
@@ -999,7 +1075,7 @@ impl ExpectFinished {
         let worked = self
             .config
             .session_storage
-            .put(key.get_encoding(), value.get_encoding());
+            .put_with_expiry(key.get_encoding(), value.get_encoding(), value.expires_at());
 
         if worked {
             debug!("Session saved");
@@ -1048,16 +1124,22 @@ impl State<ClientConnectionData> for ExpectFinished {
         cx.common.start_traffic();
         Ok(Box::new(ExpectTraffic {
             secrets: st.secrets,
+            transcript: st.transcript,
             _cert_verified: st.cert_verified,
             _sig_verified: st.sig_verified,
             _fin_verified,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 // -- Traffic transit state --
 struct ExpectTraffic {
     secrets: ConnectionSecrets,
+    transcript: HandshakeHash,
     _cert_verified: verify::ServerCertVerified,
     _sig_verified: verify::HandshakeSignatureValid,
     _fin_verified: verify::FinishedMessageVerified,
@@ -1079,6 +1161,10 @@ impl State<ClientConnectionData> for ExpectTraffic {
         Ok(self)
     }
 
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
+
     fn export_keying_material(
         &self,
         output: &mut [u8],
@@ -1086,7 +1172,6 @@ impl State<ClientConnectionData> for ExpectTraffic {
         context: Option<&[u8]>,
     ) -> Result<(), Error> {
         self.secrets
-            .export_keying_material(output, label, context);
-        Ok(())
+            .export_keying_material(output, label, context)
     }
 }
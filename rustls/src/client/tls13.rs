@@ -1,5 +1,7 @@
-use crate::check::inappropriate_handshake_message;
+use crate::check::{check_cert_chain_limits, inappropriate_handshake_message};
+use crate::compress::{self, CertCompressor};
 use crate::conn::{CommonState, ConnectionRandoms, State};
+use crate::crypto::{CryptoProvider, Output};
 use crate::error::Error;
 use crate::hash_hs::{HandshakeHash, HandshakeHashBuffer};
 use crate::kx;
@@ -9,9 +11,11 @@ use crate::msgs::base::{Payload, PayloadU8};
 use crate::msgs::ccs::ChangeCipherSpecPayload;
 use crate::msgs::codec::Codec;
 use crate::msgs::enums::KeyUpdateRequest;
-use crate::msgs::enums::{AlertDescription, NamedGroup, ProtocolVersion};
+use crate::msgs::enums::{AlertDescription, CertificateType, NamedGroup, ProtocolVersion};
 use crate::msgs::enums::{ContentType, ExtensionType, HandshakeType, SignatureScheme};
+use crate::msgs::handshake::CertificateRequestPayloadTLS13;
 use crate::msgs::handshake::ClientExtension;
+use crate::msgs::handshake::DelegatedCredentialPayload;
 use crate::msgs::handshake::DigitallySignedStruct;
 use crate::msgs::handshake::EncryptedExtensions;
 use crate::msgs::handshake::NewSessionTicketPayloadTLS13;
@@ -34,7 +38,7 @@ use super::client_conn::ClientConnectionData;
 use super::hs::ClientContext;
 use crate::client::common::ServerCertDetails;
 use crate::client::common::{ClientAuthDetails, ClientHelloDetails};
-use crate::client::{hs, ClientConfig, ServerName, StoresClientSessions};
+use crate::client::{hs, ClientConfig, ResolvesClientCert, ServerName, StoresClientSessions};
 
 use crate::ticketer::TimeBase;
 use ring::constant_time;
@@ -116,6 +120,7 @@ pub(super) fn handle_server_hello(
             }
 
             debug!("Resuming using PSK");
+            cx.common.resumed = true;
             // The key schedule has been initialized and set in fill_in_psk_binder()
         } else {
             return Err(Error::PeerMisbehavedError(
@@ -126,10 +131,12 @@ pub(super) fn handle_server_hello(
     } else {
         debug!("Not resuming");
         // Discard the early data key schedule.
-        cx.data.early_data.rejected();
+        if cx.data.early_data.is_enabled() {
+            cx.data.early_data.rejected();
+        }
         cx.common.early_traffic = false;
         resuming_session.take();
-        KeySchedulePreHandshake::new(suite.hkdf_algorithm)
+        KeySchedulePreHandshake::new(Arc::clone(&config.crypto_provider), suite.hash)
     };
 
     let key_schedule = our_key_share.complete(&their_key_share.payload.0, |secret| {
@@ -138,6 +145,7 @@ pub(super) fn handle_server_hello(
 
     // Remember what KX group the server liked for next time.
     save_kx_hint(&config, &server_name, their_key_share.group);
+    cx.common.kx_group = Some(their_key_share.group);
 
     // If we change keying when a subsequent handshake message is being joined,
     // the two halves will have different record layer protections.  Disallow this.
@@ -217,7 +225,7 @@ pub(super) fn initial_key_share(
                 .expect("No kx groups configured")
         });
 
-    kx::KeyExchange::start(group).ok_or(Error::FailedToGetRandomBytes)
+    kx::KeyExchange::start(group, &*config.secure_random).ok_or(Error::FailedToGetRandomBytes)
 }
 
 fn save_kx_hint(config: &ClientConfig, server_name: &ServerName, group: NamedGroup) {
@@ -232,21 +240,22 @@ fn save_kx_hint(config: &ClientConfig, server_name: &ServerName, group: NamedGro
 /// data dependency on the message they are contained within.
 pub(super) fn fill_in_psk_binder(
     resuming: &persist::Tls13ClientSessionValue,
+    provider: Arc<dyn CryptoProvider>,
     transcript: &HandshakeHashBuffer,
     hmp: &mut HandshakeMessagePayload,
 ) -> KeyScheduleEarly {
     // We need to know the hash function of the suite we're trying to resume into.
-    let hkdf_alg = resuming.suite().hkdf_algorithm;
-    let suite_hash = resuming.suite().hash_algorithm();
+    let suite_hash = resuming.suite().hash;
 
     // The binder is calculated over the clienthello, but doesn't include itself or its
     // length, or the length of its container.
     let binder_plaintext = hmp.get_encoding_for_binder_signing();
-    let handshake_hash = transcript.get_hash_given(suite_hash, &binder_plaintext);
+    let handshake_hash =
+        transcript.get_hash_given(provider.as_ref(), suite_hash, &binder_plaintext);
 
     // Run a fake key_schedule to simulate what the server will do if it chooses
     // to resume.
-    let key_schedule = KeyScheduleEarly::new(hkdf_alg, resuming.secret());
+    let key_schedule = KeyScheduleEarly::new(provider, suite_hash, resuming.secret());
     let real_binder = key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash);
 
     if let HandshakePayload::ClientHello(ref mut ch) = hmp.payload {
@@ -296,6 +305,7 @@ pub(super) fn prepare_resumption(
 
 pub(super) fn derive_early_traffic_secret(
     key_log: &dyn KeyLog,
+    provider: &dyn CryptoProvider,
     cx: &mut ClientContext<'_>,
     resuming_suite: &'static Tls13CipherSuite,
     early_key_schedule: &KeyScheduleEarly,
@@ -306,9 +316,12 @@ pub(super) fn derive_early_traffic_secret(
     // For middlebox compatibility
     emit_fake_ccs(sent_tls13_fake_ccs, cx.common);
 
-    let client_hello_hash = transcript_buffer.get_hash_given(resuming_suite.hash_algorithm(), &[]);
+    let client_hello_hash = transcript_buffer.get_hash_given(provider, resuming_suite.hash, &[]);
     let client_early_traffic_secret =
         early_key_schedule.client_early_traffic_secret(&client_hello_hash, key_log, client_random);
+    cx.data.early_exporter_secret = Some(Box::new(
+        early_key_schedule.early_exporter_master_secret(&client_hello_hash, key_log, client_random),
+    ));
     // Set early data encryption key
     cx.common
         .record_layer
@@ -316,7 +329,8 @@ pub(super) fn derive_early_traffic_secret(
 
     #[cfg(feature = "quic")]
     {
-        cx.common.quic.early_secret = Some(client_early_traffic_secret);
+        cx.common.quic.early_secret =
+            Some(Box::new(quic::ring_secret(&client_early_traffic_secret)));
     }
 
     // Now the client can send encrypted early data
@@ -371,6 +385,20 @@ fn validate_encrypted_extensions(
     Ok(())
 }
 
+/// Check the certificate type (RFC7250) the server chose, if any, is
+/// one of those we `offered`.
+fn process_certificate_type(
+    common: &mut CommonState,
+    offered: &[CertificateType],
+    chosen: Option<CertificateType>,
+) -> Result<CertificateType, Error> {
+    match chosen {
+        None => Ok(CertificateType::X509),
+        Some(typ) if offered.contains(&typ) => Ok(typ),
+        Some(_) => Err(common.illegal_param("server chose unoffered certificate type")),
+    }
+}
+
 struct ExpectEncryptedExtensions {
     config: Arc<ClientConfig>,
     resuming_session: Option<persist::Tls13ClientSessionValue>,
@@ -394,6 +422,8 @@ impl State<ClientConnectionData> for ExpectEncryptedExtensions {
 
         validate_encrypted_extensions(cx.common, &self.hello, exts)?;
         hs::process_alpn_protocol(cx.common, &self.config, exts.get_alpn_protocol())?;
+        hs::process_max_fragment_length(cx.common, &self.config, exts.get_max_fragment_length())?;
+        hs::process_record_size_limit(cx.common, &self.config, exts.get_record_size_limit())?;
 
         #[cfg(feature = "quic")]
         {
@@ -449,6 +479,7 @@ impl State<ClientConnectionData> for ExpectEncryptedExtensions {
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
                 client_auth: None,
+                cert_compressor: None,
                 cert_verified,
                 sig_verified,
             }))
@@ -457,6 +488,18 @@ impl State<ClientConnectionData> for ExpectEncryptedExtensions {
                 let msg = "server sent early data extension without resumption".to_string();
                 return Err(Error::PeerMisbehavedError(msg));
             }
+
+            let client_cert_type = process_certificate_type(
+                cx.common,
+                hs::offered_client_certificate_types(&self.config),
+                exts.get_client_certificate_type(),
+            )?;
+            let server_cert_type = process_certificate_type(
+                cx.common,
+                hs::offered_server_certificate_types(&self.config),
+                exts.get_server_certificate_type(),
+            )?;
+
             Ok(Box::new(ExpectCertificateOrCertReq {
                 config: self.config,
                 server_name: self.server_name,
@@ -465,9 +508,15 @@ impl State<ClientConnectionData> for ExpectEncryptedExtensions {
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
                 may_send_sct_list: self.hello.server_may_send_sct_list(),
+                client_cert_type,
+                server_cert_type,
             }))
         }
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 struct ExpectCertificateOrCertReq {
@@ -478,6 +527,8 @@ struct ExpectCertificateOrCertReq {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     may_send_sct_list: bool,
+    client_cert_type: CertificateType,
+    server_cert_type: CertificateType,
 }
 
 impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
@@ -486,6 +537,10 @@ impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
             MessagePayload::Handshake(HandshakeMessagePayload {
                 payload: HandshakePayload::CertificateTLS13(..),
                 ..
+            })
+            | MessagePayload::Handshake(HandshakeMessagePayload {
+                payload: HandshakePayload::CompressedCertificate(..),
+                ..
             }) => Box::new(ExpectCertificate {
                 config: self.config,
                 server_name: self.server_name,
@@ -494,7 +549,9 @@ impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
                 may_send_sct_list: self.may_send_sct_list,
+                server_cert_type: self.server_cert_type,
                 client_auth: None,
+                cert_compressor: None,
             })
             .handle(cx, m),
             MessagePayload::Handshake(HandshakeMessagePayload {
@@ -508,6 +565,8 @@ impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
                 may_send_sct_list: self.may_send_sct_list,
+                client_cert_type: self.client_cert_type,
+                server_cert_type: self.server_cert_type,
             })
             .handle(cx, m),
             payload => Err(inappropriate_handshake_message(
@@ -515,11 +574,16 @@ impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
                 &[ContentType::Handshake],
                 &[
                     HandshakeType::Certificate,
+                    HandshakeType::CompressedCertificate,
                     HandshakeType::CertificateRequest,
                 ],
             )),
         }
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 // TLS1.3 version of CertificateRequest handling.  We then move to expecting the server
@@ -533,6 +597,8 @@ struct ExpectCertificateRequest {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     may_send_sct_list: bool,
+    client_cert_type: CertificateType,
+    server_cert_type: CertificateType,
 }
 
 impl State<ClientConnectionData> for ExpectCertificateRequest {
@@ -556,16 +622,7 @@ impl State<ClientConnectionData> for ExpectCertificateRequest {
             return Err(Error::CorruptMessagePayload(ContentType::Handshake));
         }
 
-        let tls13_sign_schemes = sign::supported_sign_tls13();
-        let no_sigschemes = Vec::new();
-        let compat_sigschemes = certreq
-            .get_sigalgs_extension()
-            .unwrap_or(&no_sigschemes)
-            .iter()
-            .cloned()
-            .filter(|scheme| tls13_sign_schemes.contains(scheme))
-            .collect::<Vec<SignatureScheme>>();
-
+        let compat_sigschemes = compatible_sigschemes(certreq);
         if compat_sigschemes.is_empty() {
             cx.common
                 .send_fatal_alert(AlertDescription::HandshakeFailure);
@@ -578,11 +635,18 @@ impl State<ClientConnectionData> for ExpectCertificateRequest {
             self.config
                 .client_auth_cert_resolver
                 .as_ref(),
+            self.client_cert_type,
             certreq.get_authorities_extension(),
             &compat_sigschemes,
             Some(certreq.context.0.clone()),
         );
 
+        let cert_compressor = certreq
+            .get_cert_compression_extension()
+            .and_then(|offered| {
+                compress::choose_compressor(&self.config.cert_compressors, offered)
+            });
+
         Ok(Box::new(ExpectCertificate {
             config: self.config,
             server_name: self.server_name,
@@ -591,9 +655,28 @@ impl State<ClientConnectionData> for ExpectCertificateRequest {
             transcript: self.transcript,
             key_schedule: self.key_schedule,
             may_send_sct_list: self.may_send_sct_list,
+            server_cert_type: self.server_cert_type,
             client_auth: Some(client_auth),
+            cert_compressor,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
+}
+
+/// The signature schemes offered in `certreq` that we can sign with.
+fn compatible_sigschemes(certreq: &CertificateRequestPayloadTLS13) -> Vec<SignatureScheme> {
+    let tls13_sign_schemes = sign::supported_sign_tls13();
+    let no_sigschemes = Vec::new();
+    certreq
+        .get_sigalgs_extension()
+        .unwrap_or(&no_sigschemes)
+        .iter()
+        .cloned()
+        .filter(|scheme| tls13_sign_schemes.contains(scheme))
+        .collect()
 }
 
 struct ExpectCertificate {
@@ -604,16 +687,33 @@ struct ExpectCertificate {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     may_send_sct_list: bool,
+    server_cert_type: CertificateType,
     client_auth: Option<ClientAuthDetails>,
+    cert_compressor: Option<Arc<dyn CertCompressor>>,
 }
 
 impl State<ClientConnectionData> for ExpectCertificate {
     fn handle(mut self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
-        let cert_chain = require_handshake_msg!(
-            m,
-            HandshakeType::Certificate,
-            HandshakePayload::CertificateTLS13
-        )?;
+        let decompressed;
+        let cert_chain = match &m.payload {
+            MessagePayload::Handshake(HandshakeMessagePayload {
+                payload: HandshakePayload::CompressedCertificate(compressed),
+                ..
+            }) => {
+                decompressed = compress::decompress_certificate(
+                    &self.config.cert_decompressors,
+                    self.config.max_handshake_message_size,
+                    compressed,
+                    cx.common,
+                )?;
+                &decompressed
+            }
+            _ => require_handshake_msg!(
+                m,
+                HandshakeType::Certificate,
+                HandshakePayload::CertificateTLS13
+            )?,
+        };
         self.transcript.add_message(&m);
 
         // This is only non-empty for client auth.
@@ -635,8 +735,34 @@ impl State<ClientConnectionData> for ExpectCertificate {
             ));
         }
 
+        // Delegated credentials only go with the end-entity certificate,
+        // and only if we offered to accept them.
+        let delegated_credential = cert_chain.get_end_entity_delegated_credential();
+        if cert_chain.any_intermediate_has_delegated_credential()
+            || (delegated_credential.is_some()
+                && (self
+                    .config
+                    .delegated_credential_schemes
+                    .is_empty()
+                    || self.server_cert_type != CertificateType::X509))
+        {
+            cx.common
+                .send_fatal_alert(AlertDescription::UnsupportedExtension);
+            return Err(Error::PeerMisbehavedError(
+                "server sent unsolicited delegated credential".to_string(),
+            ));
+        }
+
+        let chain = cert_chain.convert();
+        check_cert_chain_limits(
+            cx.common,
+            &chain,
+            self.config.max_cert_chain_length,
+            self.config.max_cert_size,
+        )?;
+
         let server_cert = ServerCertDetails::new(
-            cert_chain.convert(),
+            chain,
             cert_chain.get_end_entity_ocsp(),
             cert_chain.get_end_entity_scts(),
         );
@@ -661,9 +787,16 @@ impl State<ClientConnectionData> for ExpectCertificate {
             transcript: self.transcript,
             key_schedule: self.key_schedule,
             server_cert,
+            server_cert_type: self.server_cert_type,
+            delegated_credential,
             client_auth: self.client_auth,
+            cert_compressor: self.cert_compressor,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 // --- TLS1.3 CertificateVerify ---
@@ -675,7 +808,10 @@ struct ExpectCertificateVerify {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     server_cert: ServerCertDetails,
+    server_cert_type: CertificateType,
+    delegated_credential: Option<DelegatedCredentialPayload>,
     client_auth: Option<ClientAuthDetails>,
+    cert_compressor: Option<Arc<dyn CertCompressor>>,
 }
 
 impl State<ClientConnectionData> for ExpectCertificateVerify {
@@ -688,14 +824,18 @@ impl State<ClientConnectionData> for ExpectCertificateVerify {
 
         trace!("Server cert is {:?}", self.server_cert.cert_chain);
 
+        if self.server_cert_type == CertificateType::RawPublicKey {
+            return self.handle_raw_public_key(cx, m);
+        }
+
         // 1. Verify the certificate chain.
         let (end_entity, intermediates) = self
             .server_cert
             .cert_chain
             .split_first()
             .ok_or(Error::NoCertificatesPresented)?;
-        let now = std::time::SystemTime::now();
-        let cert_verified = self
+        let now = self.config.time_provider.now()?;
+        let mut cert_verified = self
             .config
             .verifier
             .verify_server_cert(
@@ -707,15 +847,131 @@ impl State<ClientConnectionData> for ExpectCertificateVerify {
                 now,
             )
             .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+        let verified_chain = cert_verified.take_verified_chain();
+
+        // 2. Verify their signature on the handshake, made with their
+        // delegated credential if they sent one.  The certificate's key
+        // must have signed with a scheme we offered.
+        let cert_scheme = match &self.delegated_credential {
+            Some(dc) => dc.algorithm,
+            None => cert_verify.scheme,
+        };
+        if !self
+            .config
+            .signature_schemes()
+            .contains(&cert_scheme)
+        {
+            return Err(cx
+                .common
+                .illegal_param("server signed with unoffered scheme"));
+        }
 
-        // 2. Verify their signature on the handshake.
         let handshake_hash = self.transcript.get_current_hash();
-        let sig_verified = self
+        let message = verify::construct_tls13_server_verify_message(&handshake_hash);
+        let sig_verified = match &self.delegated_credential {
+            Some(dc) => verify::verify_tls13_delegated(
+                self.config.verifier.as_ref(),
+                &message,
+                end_entity,
+                dc,
+                &self.config.delegated_credential_schemes,
+                cert_verify,
+                now,
+            )
+            .map_err(|err| {
+                cx.common
+                    .send_fatal_alert(AlertDescription::IllegalParameter);
+                err
+            })?,
+            None => self
+                .config
+                .verifier
+                .verify_tls13_signature(&message, end_entity, cert_verify)
+                .map_err(|err| hs::send_cert_error_alert(cx.common, err))?,
+        };
+
+        cx.data.signed_cert_timestamps = self.server_cert.sct_list_encoding();
+        cx.common.peer_certificates = Some(self.server_cert.cert_chain);
+        cx.common.verified_cert_chain = verified_chain;
+        if !self
+            .server_cert
+            .ocsp_response
+            .is_empty()
+        {
+            cx.data.stapled_ocsp = Some(self.server_cert.ocsp_response);
+        }
+        self.transcript.add_message(&m);
+
+        Ok(Box::new(ExpectFinished {
+            config: self.config,
+            server_name: self.server_name,
+            randoms: self.randoms,
+            suite: self.suite,
+            transcript: self.transcript,
+            key_schedule: self.key_schedule,
+            client_auth: self.client_auth,
+            cert_compressor: self.cert_compressor,
+            cert_verified,
+            sig_verified,
+        }))
+    }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
+}
+
+impl ExpectCertificateVerify {
+    fn handle_raw_public_key(
+        mut self: Box<Self>,
+        cx: &mut ClientContext<'_>,
+        m: Message,
+    ) -> hs::NextStateOrError {
+        let cert_verify = require_handshake_msg!(
+            m,
+            HandshakeType::CertificateVerify,
+            HandshakePayload::CertificateVerify
+        )?;
+
+        // We only offer raw public keys if we have a verifier for them.
+        let verifier = match &self.config.raw_public_key_verifier {
+            Some(verifier) => Arc::clone(verifier),
+            None => return Err(Error::General("no raw public key verifier".to_string())),
+        };
+
+        // 1. Verify the raw public key.
+        let spki = match self.server_cert.cert_chain.as_slice() {
+            [spki] => spki,
+            [] => return Err(Error::NoCertificatesPresented),
+            _ => {
+                return Err(hs::send_cert_error_alert(
+                    cx.common,
+                    Error::PeerMisbehavedError("server sent more than one raw public key".into()),
+                ));
+            }
+        };
+        let now = self.config.time_provider.now()?;
+        verifier
+            .verify_raw_public_key(&spki.0, now)
+            .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+        let cert_verified = verify::ServerCertVerified::assertion();
+
+        // 2. Verify their signature on the handshake.
+        if !self
             .config
-            .verifier
+            .signature_schemes()
+            .contains(&cert_verify.scheme)
+        {
+            return Err(cx
+                .common
+                .illegal_param("server signed with unoffered scheme"));
+        }
+
+        let handshake_hash = self.transcript.get_current_hash();
+        let sig_verified = verifier
             .verify_tls13_signature(
                 &verify::construct_tls13_server_verify_message(&handshake_hash),
-                &self.server_cert.cert_chain[0],
+                &spki.0,
                 cert_verify,
             )
             .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
@@ -731,6 +987,7 @@ impl State<ClientConnectionData> for ExpectCertificateVerify {
             transcript: self.transcript,
             key_schedule: self.key_schedule,
             client_auth: self.client_auth,
+            cert_compressor: self.cert_compressor,
             cert_verified,
             sig_verified,
         }))
@@ -741,6 +998,7 @@ fn emit_certificate_tls13(
     transcript: &mut HandshakeHash,
     certkey: Option<&CertifiedKey>,
     auth_context: Option<Vec<u8>>,
+    compressor: Option<&dyn CertCompressor>,
     common: &mut CommonState,
 ) {
     let context = auth_context.unwrap_or_default();
@@ -758,12 +1016,20 @@ fn emit_certificate_tls13(
         }
     }
 
-    let m = Message {
-        version: ProtocolVersion::TLSv1_3,
-        payload: MessagePayload::Handshake(HandshakeMessagePayload {
+    let payload = match compressor.and_then(|c| compress::compress_certificate(c, &cert_payload)) {
+        Some(compressed) => HandshakeMessagePayload {
+            typ: HandshakeType::CompressedCertificate,
+            payload: HandshakePayload::CompressedCertificate(compressed),
+        },
+        None => HandshakeMessagePayload {
             typ: HandshakeType::Certificate,
             payload: HandshakePayload::CertificateTLS13(cert_payload),
-        }),
+        },
+    };
+
+    let m = Message {
+        version: ProtocolVersion::TLSv1_3,
+        payload: MessagePayload::Handshake(payload),
     };
     transcript.add_message(&m);
     common.send_msg(m, true);
@@ -793,9 +1059,34 @@ fn emit_certverify_tls13(
     Ok(())
 }
 
+fn emit_client_auth_tls13(
+    transcript: &mut HandshakeHash,
+    client_auth: ClientAuthDetails,
+    compressor: Option<&dyn CertCompressor>,
+    common: &mut CommonState,
+) -> Result<(), Error> {
+    match client_auth {
+        ClientAuthDetails::Empty {
+            auth_context_tls13: auth_context,
+        } => {
+            emit_certificate_tls13(transcript, None, auth_context, compressor, common);
+        }
+        ClientAuthDetails::Verify {
+            certkey,
+            signer,
+            auth_context_tls13: auth_context,
+        } => {
+            emit_certificate_tls13(transcript, Some(&certkey), auth_context, compressor, common);
+            emit_certverify_tls13(transcript, signer.as_ref(), common)?;
+        }
+    }
+
+    Ok(())
+}
+
 fn emit_finished_tls13(
     transcript: &mut HandshakeHash,
-    verify_data: ring::hmac::Tag,
+    verify_data: Output,
     common: &mut CommonState,
 ) {
     let verify_data_payload = Payload::new(verify_data.as_ref());
@@ -837,6 +1128,7 @@ struct ExpectFinished {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     client_auth: Option<ClientAuthDetails>,
+    cert_compressor: Option<Arc<dyn CertCompressor>>,
     cert_verified: verify::ServerCertVerified,
     sig_verified: verify::HandshakeSignatureValid,
 }
@@ -880,26 +1172,12 @@ impl State<ClientConnectionData> for ExpectFinished {
         /* Send our authentication/finished messages.  These are still encrypted
          * with our handshake keys. */
         if let Some(client_auth) = st.client_auth {
-            match client_auth {
-                ClientAuthDetails::Empty {
-                    auth_context_tls13: auth_context,
-                } => {
-                    emit_certificate_tls13(&mut st.transcript, None, auth_context, cx.common);
-                }
-                ClientAuthDetails::Verify {
-                    certkey,
-                    signer,
-                    auth_context_tls13: auth_context,
-                } => {
-                    emit_certificate_tls13(
-                        &mut st.transcript,
-                        Some(&certkey),
-                        auth_context,
-                        cx.common,
-                    );
-                    emit_certverify_tls13(&mut st.transcript, signer.as_ref(), cx.common)?;
-                }
-            }
+            emit_client_auth_tls13(
+                &mut st.transcript,
+                client_auth,
+                st.cert_compressor.as_deref(),
+                cx.common,
+            )?;
         }
 
         let (key_schedule_finished, client_key, server_key) = st
@@ -934,6 +1212,12 @@ impl State<ClientConnectionData> for ExpectFinished {
             transcript: st.transcript,
             key_schedule: key_schedule_traffic,
             want_write_key_update: false,
+            key_update_threshold: st.config.key_update_threshold,
+            post_handshake_auth: if st.config.enable_post_handshake_auth && !cx.common.is_quic() {
+                Some(Arc::clone(&st.config.client_auth_cert_resolver))
+            } else {
+                None
+            },
             _cert_verified: st.cert_verified,
             _sig_verified: st.sig_verified,
             _fin_verified: fin,
@@ -950,6 +1234,10 @@ impl State<ClientConnectionData> for ExpectFinished {
 
         Ok(Box::new(st))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 // -- Traffic transit state (TLS1.3) --
@@ -962,6 +1250,9 @@ struct ExpectTraffic {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleTraffic,
     want_write_key_update: bool,
+    key_update_threshold: Option<u64>,
+    /// Set if we offered post-handshake client authentication.
+    post_handshake_auth: Option<Arc<dyn ResolvesClientCert>>,
     _cert_verified: verify::ServerCertVerified,
     _sig_verified: verify::HandshakeSignatureValid,
     _fin_verified: verify::FinishedMessageVerified,
@@ -1021,6 +1312,7 @@ impl ExpectTraffic {
         }
 
         let key = persist::ClientSessionKey::session_for_server_name(&self.server_name);
+        let expires = value.expires_at();
         #[allow(unused_mut)]
         let mut ticket = value.get_encoding();
 
@@ -1031,9 +1323,10 @@ impl ExpectTraffic {
             PayloadU16::encode_slice(quic_params, &mut ticket);
         }
 
-        let worked = self
-            .session_storage
-            .put(key.get_encoding(), ticket);
+        let psk = persist::ResumptionPsk::new(value.ticket(), value.secret());
+        let worked =
+            self.session_storage
+                .put_tls13_session(key.get_encoding(), ticket, &psk, expires);
 
         if worked {
             debug!("Ticket saved");
@@ -1043,6 +1336,58 @@ impl ExpectTraffic {
         Ok(())
     }
 
+    fn handle_post_handshake_cert_req(
+        &mut self,
+        cx: &mut ClientContext<'_>,
+        m: &Message,
+        certreq: &CertificateRequestPayloadTLS13,
+    ) -> Result<(), Error> {
+        let resolver = match &self.post_handshake_auth {
+            Some(resolver) => resolver,
+            None => {
+                cx.common
+                    .send_fatal_alert(AlertDescription::UnexpectedMessage);
+                return Err(Error::PeerMisbehavedError(
+                    "server sent unsolicited post-handshake CertificateRequest".into(),
+                ));
+            }
+        };
+
+        // Mustn't be interleaved with other handshake messages.
+        cx.common.check_aligned_handshake()?;
+        debug!("Got post-handshake CertificateRequest {:?}", certreq);
+
+        let compat_sigschemes = compatible_sigschemes(certreq);
+        if compat_sigschemes.is_empty() {
+            cx.common
+                .send_fatal_alert(AlertDescription::HandshakeFailure);
+            return Err(Error::PeerIncompatibleError(
+                "server sent bad certreq schemes".to_string(),
+            ));
+        }
+
+        // Post-handshake authentication always uses certificate chains.
+        let client_auth = ClientAuthDetails::resolve(
+            resolver.as_ref(),
+            CertificateType::X509,
+            certreq.get_authorities_extension(),
+            &compat_sigschemes,
+            Some(certreq.context.0.clone()),
+        );
+
+        // Each exchange extends the transcript as it stood at the end
+        // of the handshake (RFC8446 section 4.4).
+        let mut transcript = self.transcript.clone();
+        transcript.add_message(m);
+        emit_client_auth_tls13(&mut transcript, client_auth, None, cx.common)?;
+
+        let verify_data = self
+            .key_schedule
+            .sign_post_handshake_client_finish(&transcript.get_current_hash());
+        emit_finished_tls13(&mut transcript, verify_data, cx.common);
+        Ok(())
+    }
+
     fn handle_key_update(
         &mut self,
         common: &mut CommonState,
@@ -1085,6 +1430,18 @@ impl ExpectTraffic {
 
         Ok(())
     }
+
+    /// Send `key_update` and start using the next write key.
+    fn write_key_update(&mut self, common: &mut CommonState, key_update: Message) {
+        common.send_msg_encrypt(key_update.into());
+
+        let write_key = self
+            .key_schedule
+            .next_client_application_traffic_secret();
+        common
+            .record_layer
+            .set_message_encrypter(self.suite.derive_encrypter(&write_key));
+    }
 }
 
 impl State<ClientConnectionData> for ExpectTraffic {
@@ -1101,11 +1458,19 @@ impl State<ClientConnectionData> for ExpectTraffic {
                 payload: HandshakePayload::KeyUpdate(ref key_update),
                 ..
             }) => self.handle_key_update(cx.common, key_update)?,
+            MessagePayload::Handshake(HandshakeMessagePayload {
+                payload: HandshakePayload::CertificateRequestTLS13(ref certreq),
+                ..
+            }) => self.handle_post_handshake_cert_req(cx, &m, certreq)?,
             payload => {
                 return Err(inappropriate_handshake_message(
                     &payload,
                     &[ContentType::ApplicationData, ContentType::Handshake],
-                    &[HandshakeType::NewSessionTicket, HandshakeType::KeyUpdate],
+                    &[
+                        HandshakeType::NewSessionTicket,
+                        HandshakeType::KeyUpdate,
+                        HandshakeType::CertificateRequest,
+                    ],
                 ));
             }
         }
@@ -1113,6 +1478,10 @@ impl State<ClientConnectionData> for ExpectTraffic {
         Ok(self)
     }
 
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
+
     fn export_keying_material(
         &self,
         output: &mut [u8],
@@ -1124,18 +1493,22 @@ impl State<ClientConnectionData> for ExpectTraffic {
     }
 
     fn perhaps_write_key_update(&mut self, common: &mut CommonState) {
-        if self.want_write_key_update {
-            self.want_write_key_update = false;
-            common.send_msg_encrypt(Message::build_key_update_notify().into());
-
-            let write_key = self
-                .key_schedule
-                .next_client_application_traffic_secret();
-            common
+        if self.want_write_key_update
+            || common
                 .record_layer
-                .set_message_encrypter(self.suite.derive_encrypter(&write_key));
+                .wants_key_update(self.key_update_threshold)
+        {
+            self.want_write_key_update = false;
+            self.write_key_update(common, Message::build_key_update_notify());
         }
     }
+
+    fn refresh_traffic_keys(&mut self, common: &mut CommonState) -> Result<(), Error> {
+        // This also answers any update the peer requested.
+        self.want_write_key_update = false;
+        self.write_key_update(common, Message::build_key_update_request());
+        Ok(())
+    }
 }
 
 #[cfg(feature = "quic")]
@@ -1154,6 +1527,10 @@ impl State<ClientConnectionData> for ExpectQuicTraffic {
         Ok(self)
     }
 
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        self.0.handshake_transcript()
+    }
+
     fn export_keying_material(
         &self,
         output: &mut [u8],
@@ -0,0 +1,160 @@
+use crate::conn::CommonState;
+use crate::error::Error;
+#[cfg(feature = "logging")]
+use crate::log::warn;
+use crate::msgs::base::PayloadU24;
+use crate::msgs::codec::{Codec, Reader};
+pub use crate::msgs::enums::CertCompressionAlgorithm;
+use crate::msgs::enums::{AlertDescription, ContentType};
+use crate::msgs::handshake::{CertificatePayloadTLS13, CompressedCertificatePayload};
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::Arc;
+
+/// An implementation of certificate compression, for sending
+/// `CompressedCertificate` messages.
+pub trait CertCompressor: Send + Sync {
+    /// Compress `input`, returning the compressed data.
+    ///
+    /// If this fails, the certificate is sent uncompressed.
+    fn compress(&self, input: &[u8]) -> Result<Vec<u8>, CompressionFailed>;
+
+    /// Which algorithm this compressor implements.
+    fn algorithm(&self) -> CertCompressionAlgorithm;
+}
+
+/// An implementation of certificate decompression, for receiving
+/// `CompressedCertificate` messages.
+pub trait CertDecompressor: Send + Sync {
+    /// Decompress `input`, writing the result into `output` and
+    /// returning the number of bytes written.
+    ///
+    /// `output` is exactly as long as the `uncompressed_length` claimed
+    /// by the peer.  Implementations must fail if the decompressed data
+    /// does not fit in `output`: this is what protects us from
+    /// decompression bombs.  The message is rejected if fewer bytes
+    /// than that were written.
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> Result<usize, DecompressionFailed>;
+
+    /// Which algorithm this decompressor implements.
+    fn algorithm(&self) -> CertCompressionAlgorithm;
+}
+
+/// A certificate compression failure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressionFailed;
+
+impl fmt::Display for CompressionFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "certificate compression failed")
+    }
+}
+
+impl StdError for CompressionFailed {}
+
+/// A certificate decompression failure.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecompressionFailed;
+
+impl fmt::Display for DecompressionFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "certificate decompression failed")
+    }
+}
+
+impl StdError for DecompressionFailed {}
+
+/// Choose our first compressor which the peer said it can decompress.
+pub(crate) fn choose_compressor(
+    compressors: &[Arc<dyn CertCompressor>],
+    offered: &[CertCompressionAlgorithm],
+) -> Option<Arc<dyn CertCompressor>> {
+    compressors
+        .iter()
+        .find(|c| offered.contains(&c.algorithm()))
+        .cloned()
+}
+
+/// Compress `cert`, returning None if `compressor` fails.
+pub(crate) fn compress_certificate(
+    compressor: &dyn CertCompressor,
+    cert: &CertificatePayloadTLS13,
+) -> Option<CompressedCertificatePayload> {
+    let uncompressed = cert.get_encoding();
+    let compressed = compressor
+        .compress(&uncompressed)
+        .map_err(|_| {
+            warn!(
+                "{:?} certificate compression failed",
+                compressor.algorithm()
+            )
+        })
+        .ok()?;
+
+    Some(CompressedCertificatePayload {
+        alg: compressor.algorithm(),
+        uncompressed_len: uncompressed.len() as u32,
+        compressed: PayloadU24::new(compressed),
+    })
+}
+
+/// Decompress a peer's `CompressedCertificate` message, using one of
+/// `decompressors` -- which must be the algorithms we offered.
+///
+/// We allocate `uncompressed_length` bytes before decompression starts,
+/// so it is limited to `max_len`: the largest handshake message we'd
+/// accept uncompressed.
+pub(crate) fn decompress_certificate(
+    decompressors: &[Arc<dyn CertDecompressor>],
+    max_len: usize,
+    compressed: &CompressedCertificatePayload,
+    common: &mut CommonState,
+) -> Result<CertificatePayloadTLS13, Error> {
+    let decompressor = match decompressors
+        .iter()
+        .find(|d| d.algorithm() == compressed.alg)
+    {
+        Some(decompressor) => decompressor,
+        None => {
+            common.send_fatal_alert(AlertDescription::IllegalParameter);
+            return Err(Error::PeerMisbehavedError(
+                "peer used unoffered certificate compression algorithm".to_string(),
+            ));
+        }
+    };
+
+    let len = compressed.uncompressed_len as usize;
+    if len == 0 || len > max_len {
+        warn!("unacceptable certificate uncompressed_length {}", len);
+        common.send_fatal_alert(AlertDescription::DecodeError);
+        return Err(Error::CorruptMessagePayload(ContentType::Handshake));
+    }
+
+    let mut uncompressed = vec![0u8; len];
+    match decompressor.decompress(&compressed.compressed.0, &mut uncompressed) {
+        Ok(written) if written == len => {}
+        Ok(written) => {
+            warn!(
+                "{:?} certificate decompressed to {} bytes, expected {}",
+                compressed.alg, written, len
+            );
+            common.send_fatal_alert(AlertDescription::DecodeError);
+            return Err(Error::CorruptMessagePayload(ContentType::Handshake));
+        }
+        Err(_) => {
+            warn!("{:?} certificate decompression failed", compressed.alg);
+            common.send_fatal_alert(AlertDescription::DecodeError);
+            return Err(Error::CorruptMessagePayload(ContentType::Handshake));
+        }
+    }
+
+    let mut rd = Reader::init(&uncompressed);
+    match CertificatePayloadTLS13::read(&mut rd) {
+        Some(cert) if !rd.any_left() => Ok(cert),
+        _ => {
+            common.send_fatal_alert(AlertDescription::DecodeError);
+            Err(Error::CorruptMessagePayload(ContentType::Handshake))
+        }
+    }
+}
@@ -1,4 +1,6 @@
+use crate::crypto::HashAlgorithm;
 use crate::error::Error;
+use crate::hash_hs::HandshakeHash;
 use crate::key;
 #[cfg(feature = "logging")]
 use crate::log::{debug, error, trace, warn};
@@ -6,26 +8,33 @@ use crate::msgs::alert::AlertMessagePayload;
 use crate::msgs::base::Payload;
 use crate::msgs::deframer::MessageDeframer;
 use crate::msgs::enums::HandshakeType;
-use crate::msgs::enums::{AlertDescription, AlertLevel, ContentType, ProtocolVersion};
+use crate::msgs::enums::{AlertDescription, AlertLevel, ContentType, NamedGroup, ProtocolVersion};
 use crate::msgs::fragmenter::MessageFragmenter;
-use crate::msgs::handshake::Random;
+use crate::msgs::handshake::{Random, SessionID};
 use crate::msgs::hsjoiner::HandshakeJoiner;
 use crate::msgs::message::{
-    BorrowedPlainMessage, Message, MessagePayload, OpaqueMessage, PlainMessage,
+    BorrowedOpaqueMessage, BorrowedPlainMessage, Message, MessagePayload, OpaqueMessage,
+    PlainMessage,
 };
 #[cfg(feature = "quic")]
 use crate::quic;
 use crate::record_layer;
+use crate::sign;
 use crate::suites::SupportedCipherSuite;
 #[cfg(feature = "tls12")]
 use crate::tls12::ConnectionSecrets;
 use crate::vecbuf::ChunkVecBuffer;
+use crate::x509;
 
+use ring::digest;
+
+use std::cmp;
 use std::collections::VecDeque;
 use std::convert::TryFrom;
 use std::io;
 use std::mem;
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 
 /// A client or server connection.
 pub enum Connection {
@@ -87,6 +96,40 @@ impl Connection {
         }
     }
 
+    /// Fills `out` with channel binding data of the given `kind`.
+    ///
+    /// See [`ConnectionCommon::get_channel_binding()`] for more information.
+    pub fn get_channel_binding(
+        &self,
+        kind: ChannelBindingKind,
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        match self {
+            Connection::Client(conn) => conn.get_channel_binding(kind, out),
+            Connection::Server(conn) => conn.get_channel_binding(kind, out),
+        }
+    }
+
+    /// Returns the current handshake transcript hash and its hash function.
+    ///
+    /// See [`ConnectionCommon::get_handshake_transcript_hash()`] for more information.
+    pub fn get_handshake_transcript_hash(&self) -> Option<(HashAlgorithm, Vec<u8>)> {
+        match self {
+            Connection::Client(conn) => conn.get_handshake_transcript_hash(),
+            Connection::Server(conn) => conn.get_handshake_transcript_hash(),
+        }
+    }
+
+    /// Sends a TLS1.3 KeyUpdate message and starts using new keys.
+    ///
+    /// See [`ConnectionCommon::refresh_traffic_keys()`] for more information.
+    pub fn refresh_traffic_keys(&mut self) -> Result<(), Error> {
+        match self {
+            Connection::Client(conn) => conn.refresh_traffic_keys(),
+            Connection::Server(conn) => conn.refresh_traffic_keys(),
+        }
+    }
+
     /// This function uses `io` to complete any outstanding IO for this connection.
     ///
     /// See [`ConnectionCommon::complete_io()`] for more information.
@@ -118,6 +161,13 @@ impl crate::quic::QuicExt for Connection {
         }
     }
 
+    fn zero_rtt_accepted(&self) -> Option<bool> {
+        match self {
+            Connection::Client(conn) => conn.zero_rtt_accepted(),
+            Connection::Server(conn) => conn.zero_rtt_accepted(),
+        }
+    }
+
     fn read_hs(&mut self, plaintext: &[u8]) -> Result<(), Error> {
         match self {
             Connection::Client(conn) => conn.read_quic_hs(plaintext),
@@ -190,6 +240,10 @@ impl IoState {
     /// This is also signalled via returning `Ok(0)` from
     /// [`std::io::Read`], after all the received bytes have been
     /// retrieved.
+    ///
+    /// This is set as soon as the batch containing the close_notify
+    /// is processed, even if plaintext received before it is still
+    /// waiting to be read, and stays set in every later `IoState`.
     pub fn peer_has_closed(&self) -> bool {
         self.peer_has_closed
     }
@@ -227,16 +281,7 @@ impl<'a> io::Read for Reader<'a> {
         let len = self.received_plaintext.read(buf)?;
 
         if len == 0 && !buf.is_empty() {
-            // No bytes available:
-            match (self.peer_cleanly_closed, self.has_seen_eof) {
-                // cleanly closed; don't care about TCP EOF: express this as Ok(0)
-                (true, _) => {}
-                // unclean closure
-                (false, true) => return Err(io::ErrorKind::UnexpectedEof.into()),
-                // connection still going, but need more data: signal `WouldBlock` so that
-                // the caller knows this
-                (false, false) => return Err(io::ErrorKind::WouldBlock.into()),
-            }
+            self.check_no_data()?;
         }
 
         Ok(len)
@@ -264,28 +309,75 @@ impl<'a> io::Read for Reader<'a> {
     /// You may learn the number of bytes available at any time by inspecting
     /// the return of [`Connection::process_new_packets`].
     #[cfg(read_buf)]
-    fn read_buf(&mut self, buf: &mut io::ReadBuf<'_>) -> io::Result<()> {
-        let before = buf.filled_len();
-        self.received_plaintext.read_buf(buf)?;
-        let len = buf.filled_len() - before;
-
-        if len == 0 && buf.capacity() > 0 {
-            // No bytes available:
-            match (self.peer_cleanly_closed, self.has_seen_eof) {
-                // cleanly closed; don't care about TCP EOF: express this as Ok(0)
-                (true, _) => {}
-                // unclean closure
-                (false, true) => return Err(io::ErrorKind::UnexpectedEof.into()),
-                // connection still going, but need more data: signal `WouldBlock` so that
-                // the caller knows this
-                (false, false) => return Err(io::ErrorKind::WouldBlock.into()),
-            }
+    fn read_buf(&mut self, mut cursor: io::BorrowedCursor<'_>) -> io::Result<()> {
+        let before = cursor.written();
+        self.received_plaintext
+            .read_buf(cursor.reborrow())?;
+        let len = cursor.written() - before;
+
+        if len == 0 && cursor.capacity() > 0 {
+            self.check_no_data()?;
         }
 
         Ok(())
     }
 }
 
+impl<'a> io::BufRead for Reader<'a> {
+    /// Borrow plaintext data received from the peer over this TLS connection,
+    /// without copying or consuming it.
+    ///
+    /// The returned slice is the next contiguous run of received plaintext,
+    /// which is often a single record's worth.  It may be shorter than the
+    /// total available: call [`io::BufRead::consume`] and then this function
+    /// again to see the rest.
+    ///
+    /// Once all pending data has been consumed, this behaves like
+    /// [`io::Read::read`]: it returns an empty slice if the peer closed the
+    /// TLS session cleanly, `Err(ErrorKind::UnexpectedEof.into())` if it
+    /// closed uncleanly, and otherwise `Err(ErrorKind::WouldBlock.into())`.
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.received_plaintext.is_empty() {
+            self.check_no_data()?;
+        }
+
+        Ok(self.received_plaintext.peek())
+    }
+
+    /// Mark `amt` bytes of plaintext returned by [`io::BufRead::fill_buf`]
+    /// as consumed, so they are not returned again.
+    fn consume(&mut self, amt: usize) {
+        self.received_plaintext.consume(amt);
+    }
+}
+
+impl<'a> Reader<'a> {
+    /// As [`io::BufRead::fill_buf`], but the returned slice borrows
+    /// the connection rather than this `Reader`.
+    pub(crate) fn into_first_chunk(self) -> io::Result<&'a [u8]> {
+        if self.received_plaintext.is_empty() {
+            self.check_no_data()?;
+        }
+
+        let received_plaintext: &'a ChunkVecBuffer = self.received_plaintext;
+        Ok(received_plaintext.peek())
+    }
+
+    /// Called when no bytes are available: decide whether that means
+    /// EOF, unexpected EOF, or that the caller should wait for more.
+    fn check_no_data(&self) -> io::Result<()> {
+        match (self.peer_cleanly_closed, self.has_seen_eof) {
+            // cleanly closed; don't care about TCP EOF: express this as Ok(0)
+            (true, _) => Ok(()),
+            // unclean closure
+            (false, true) => Err(io::ErrorKind::UnexpectedEof.into()),
+            // connection still going, but need more data: signal `WouldBlock` so that
+            // the caller knows this
+            (false, false) => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
 /// Internal trait implemented by the [`ServerConnection`]/[`ClientConnection`]
 /// allowing them to be the subject of a [`Writer`].
 pub trait PlaintextSink {
@@ -296,15 +388,13 @@ pub trait PlaintextSink {
 
 impl<T> PlaintextSink for ConnectionCommon<T> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_not_exhausted()?;
         Ok(self.send_some_plaintext(buf))
     }
 
     fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
-        let mut sz = 0;
-        for buf in bufs {
-            sz += self.send_some_plaintext(buf);
-        }
-        Ok(sz)
+        self.check_not_exhausted()?;
+        Ok(self.send_some_plaintext_vectored(bufs))
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -424,6 +514,41 @@ impl<Data> ConnectionCommon<Data> {
         Writer::new(self)
     }
 
+    /// Returns an approximation of the heap memory held by this
+    /// connection, in bytes.
+    ///
+    /// This counts buffered plaintext and TLS data in both directions,
+    /// any handshake transcript kept for client authentication, and
+    /// the handshake state itself.  It is not exact, but is cheap
+    /// enough to use to choose which idle connections to drop.
+    pub fn approximate_memory_usage(&self) -> usize {
+        let state = match &self.state {
+            Ok(state) => {
+                mem::size_of_val(&**state)
+                    + state
+                        .handshake_transcript()
+                        .map_or(0, HandshakeHash::buffer_memory_usage)
+            }
+            Err(_) => 0,
+        };
+
+        state
+            + self
+                .common_state
+                .received_plaintext
+                .memory_usage()
+            + self
+                .common_state
+                .sendable_plaintext
+                .memory_usage()
+            + self
+                .common_state
+                .sendable_tls
+                .memory_usage()
+            + self.message_deframer.memory_usage()
+            + self.handshake_joiner.memory_usage()
+    }
+
     /// This function uses `io` to complete any outstanding IO for
     /// this connection.
     ///
@@ -505,16 +630,16 @@ impl<Data> ConnectionCommon<Data> {
     /// This is a shortcut to the `process_new_packets()` -> `process_msg()` ->
     /// `process_handshake_messages()` path, specialized for the first handshake message.
     pub(crate) fn first_handshake_message(&mut self) -> Result<Option<Message>, Error> {
-        if self.message_deframer.desynced {
-            return Err(Error::CorruptMessage);
-        }
-
-        let msg = match self.message_deframer.frames.pop_front() {
+        let msg = self.message_deframer.pop().map(|msg| {
+            msg.into_plain_message()
+                .to_plain_message()
+        });
+        let msg = match msg {
             Some(msg) => msg,
+            None if self.message_deframer.desynced => return Err(Error::CorruptMessage),
             None => return Ok(None),
         };
 
-        let msg = msg.into_plain_message();
         if !self.handshake_joiner.want_message(&msg) {
             return Err(Error::CorruptMessagePayload(ContentType::Handshake));
         }
@@ -533,44 +658,54 @@ impl<Data> ConnectionCommon<Data> {
         Ok(self.handshake_joiner.frames.pop_front())
     }
 
+    pub(crate) fn set_max_handshake_message_size(&mut self, max: usize) {
+        self.handshake_joiner
+            .set_max_message_size(max);
+    }
+
     pub(crate) fn replace_state(&mut self, new: Box<dyn State<Data>>) {
         self.state = Ok(new);
     }
 
     fn process_msg(
-        &mut self,
-        msg: OpaqueMessage,
+        msg: BorrowedOpaqueMessage,
         state: Box<dyn State<Data>>,
+        common_state: &mut CommonState,
+        handshake_joiner: &mut HandshakeJoiner,
+        data: &mut Data,
     ) -> Result<Box<dyn State<Data>>, Error> {
         // pass message to handshake state machine if any of these are true:
         // - TLS1.2 (where it's part of the state machine),
-        // - prior to determining the version (it's illegal as a first message)
+        // - prior to determining the version (it's illegal as a first message,
+        //   unless we may be answering the second ClientHello after a
+        //   stateless HelloRetryRequest)
         // - if it's not a CCS at all
         // - if we've finished the handshake
         if msg.typ == ContentType::ChangeCipherSpec
-            && !self
-                .common_state
-                .may_receive_application_data
-            && self.common_state.is_tls13()
+            && !common_state.may_receive_application_data
+            && (common_state.is_tls13()
+                || (common_state.allow_ccs_before_client_hello
+                    && common_state
+                        .negotiated_version
+                        .is_none()))
         {
-            if self.common_state.received_middlebox_ccs > TLS13_MAX_DROPPED_CCS {
+            if common_state.received_middlebox_ccs > TLS13_MAX_DROPPED_CCS {
                 return Err(Error::PeerMisbehavedError(
                     "illegal middlebox CCS received".into(),
                 ));
             } else {
-                self.common_state.received_middlebox_ccs += 1;
+                common_state.received_middlebox_ccs += 1;
                 trace!("Dropping CCS");
                 return Ok(state);
             }
         }
 
         // Decrypt if demanded by current state.
-        let msg = match self
-            .common_state
+        let msg = match common_state
             .record_layer
             .is_decrypting()
         {
-            true => match self.common_state.decrypt_incoming(msg) {
+            true => match common_state.decrypt_incoming(msg) {
                 Ok(None) => {
                     // message dropped
                     return Ok(state);
@@ -583,22 +718,40 @@ impl<Data> ConnectionCommon<Data> {
             false => msg.into_plain_message(),
         };
 
+        // Once traffic is flowing, application data goes straight
+        // to the plaintext buffer: every state accepts it then, and
+        // this avoids allocating a `Message` for each record.
+        if msg.typ == ContentType::ApplicationData
+            && common_state.may_receive_application_data
+            && !common_state.is_quic()
+        {
+            common_state
+                .received_plaintext
+                .append_copy(msg.payload);
+            return Ok(state);
+        }
+
         // For handshake messages, we need to join them before parsing
         // and processing.
-        if self.handshake_joiner.want_message(&msg) {
+        let msg = msg.to_plain_message();
+        if handshake_joiner.want_message(&msg) {
             // First decryptable handshake message concludes trial decryption
-            self.common_state
+            common_state
                 .record_layer
                 .finish_trial_decryption();
 
-            self.handshake_joiner
+            handshake_joiner
                 .take_message(msg)
                 .ok_or_else(|| {
-                    self.common_state
-                        .send_fatal_alert(AlertDescription::DecodeError);
+                    common_state.send_fatal_alert(AlertDescription::DecodeError);
                     Error::CorruptMessagePayload(ContentType::Handshake)
                 })?;
-            return self.process_new_handshake_messages(state);
+            return Self::process_new_handshake_messages(
+                state,
+                common_state,
+                handshake_joiner,
+                data,
+            );
         }
 
         // Now we can fully parse the message payload.
@@ -606,12 +759,11 @@ impl<Data> ConnectionCommon<Data> {
 
         // For alerts, we have separate logic.
         if let MessagePayload::Alert(alert) = &msg.payload {
-            self.common_state.process_alert(alert)?;
+            common_state.process_alert(alert)?;
             return Ok(state);
         }
 
-        self.common_state
-            .process_main_protocol(msg, state, &mut self.data)
+        common_state.process_main_protocol(msg, state, data)
     }
 
     /// Processes any new packets read by a previous call to
@@ -641,12 +793,14 @@ impl<Data> ConnectionCommon<Data> {
             }
         };
 
-        if self.message_deframer.desynced {
-            return Err(Error::CorruptMessage);
-        }
-
-        while let Some(msg) = self.message_deframer.frames.pop_front() {
-            match self.process_msg(msg, state) {
+        while let Some(msg) = self.message_deframer.pop() {
+            match Self::process_msg(
+                msg,
+                state,
+                &mut self.common_state,
+                &mut self.handshake_joiner,
+                &mut self.data,
+            ) {
                 Ok(new) => state = new,
                 Err(e) => {
                     self.state = Err(e.clone());
@@ -655,30 +809,61 @@ impl<Data> ConnectionCommon<Data> {
             }
         }
 
+        // Messages received before any bad data have been processed.
+        if self.message_deframer.desynced {
+            self.state = Err(Error::CorruptMessage);
+            return Err(Error::CorruptMessage);
+        }
+
         self.state = Ok(state);
+        self.common_state
+            .notify_if_handshake_complete();
         Ok(self.common_state.current_io_state())
     }
 
     fn process_new_handshake_messages(
-        &mut self,
         mut state: Box<dyn State<Data>>,
+        common_state: &mut CommonState,
+        handshake_joiner: &mut HandshakeJoiner,
+        data: &mut Data,
     ) -> Result<Box<dyn State<Data>>, Error> {
-        self.common_state.aligned_handshake = self.handshake_joiner.is_empty();
-        while let Some(msg) = self.handshake_joiner.frames.pop_front() {
-            state = self
-                .common_state
-                .process_main_protocol(msg, state, &mut self.data)?;
+        common_state.aligned_handshake = handshake_joiner.is_empty();
+        while let Some(msg) = handshake_joiner.frames.pop_front() {
+            state = common_state.process_main_protocol(msg, state, data)?;
         }
 
         Ok(state)
     }
 
     pub(crate) fn send_some_plaintext(&mut self, buf: &[u8]) -> usize {
-        if let Ok(st) = &mut self.state {
-            st.perhaps_write_key_update(&mut self.common_state);
+        match &mut self.state {
+            Ok(st) => self
+                .common_state
+                .send_some_plaintext(buf, &mut |common| st.perhaps_write_key_update(common)),
+            Err(_) => self
+                .common_state
+                .send_some_plaintext(buf, &mut |_| {}),
+        }
+    }
+
+    pub(crate) fn send_some_plaintext_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> usize {
+        match &mut self.state {
+            Ok(st) => self
+                .common_state
+                .send_some_plaintext_vectored(bufs, &mut |common| {
+                    st.perhaps_write_key_update(common)
+                }),
+            Err(_) => self
+                .common_state
+                .send_some_plaintext_vectored(bufs, &mut |_| {}),
+        }
+    }
+
+    pub(crate) fn request_client_auth(&mut self) -> Result<(), Error> {
+        match &mut self.state {
+            Ok(st) => st.request_client_auth(&mut self.common_state),
+            Err(e) => Err(e.clone()),
         }
-        self.common_state
-            .send_some_plaintext(buf)
     }
 
     /// Read TLS content from `rd`.  This method does internal
@@ -715,8 +900,13 @@ impl<Data> ConnectionCommon<Data> {
     /// For TLS1.3 connections, this function does not use the
     /// "early" exporter at any point.
     ///
+    /// `context` may be any length in TLS1.3, but at most 65535 bytes in
+    /// TLS1.2.  In TLS1.3, `output` may be at most 255 times the length
+    /// of the cipher suite's hash, and `label` at most 249 bytes.
+    ///
     /// This function fails if called prior to the handshake completing;
-    /// check with [`CommonState::is_handshaking`] first.
+    /// check with [`CommonState::is_handshaking`] first.  It also fails if
+    /// the limits above are exceeded.
     pub fn export_keying_material(
         &self,
         output: &mut [u8],
@@ -728,8 +918,150 @@ impl<Data> ConnectionCommon<Data> {
             Err(e) => Err(e.clone()),
         }
     }
+
+    /// Sends a TLS1.3 KeyUpdate message and starts using new keys
+    /// to send data.
+    ///
+    /// The KeyUpdate asks the peer to update its keys in turn, so this
+    /// refreshes the keys in both directions.  Use it to limit the amount
+    /// of data protected by any one key on long-lived connections.
+    ///
+    /// This function fails if called prior to the handshake completing,
+    /// for TLS1.2 connections, which have no key update mechanism, and for
+    /// QUIC connections, which update keys without TLS.
+    pub fn refresh_traffic_keys(&mut self) -> Result<(), Error> {
+        if self.is_handshaking() {
+            return Err(Error::HandshakeNotComplete);
+        }
+
+        if self.common_state.negotiated_version != Some(ProtocolVersion::TLSv1_3) {
+            return Err(Error::General(
+                "key updates are not supported in TLS1.2".into(),
+            ));
+        }
+
+        if self.common_state.is_quic() {
+            return Err(Error::General(
+                "key updates are not supported in QUIC".into(),
+            ));
+        }
+
+        match &mut self.state {
+            Ok(st) => st.refresh_traffic_keys(&mut self.common_state),
+            Err(e) => Err(e.clone()),
+        }
+    }
+
+    /// Returns the hash of the handshake transcript so far, and the hash
+    /// function used to compute it.
+    ///
+    /// The transcript is the concatenation of every handshake message sent
+    /// and received, as defined for the Finished message of the negotiated
+    /// protocol version.  This returns `None` until the hash function is
+    /// known, which is once the cipher suite has been chosen.  After the
+    /// handshake it is the hash up to and including both Finished messages.
+    ///
+    /// Calling this does not affect the handshake.
+    pub fn get_handshake_transcript_hash(&self) -> Option<(HashAlgorithm, Vec<u8>)> {
+        let transcript = self
+            .state
+            .as_ref()
+            .ok()?
+            .handshake_transcript()?;
+        Some((
+            transcript.algorithm(),
+            transcript
+                .get_current_hash()
+                .as_ref()
+                .to_vec(),
+        ))
+    }
+
+    /// Fills `out` with channel binding data of the given `kind`,
+    /// replacing any existing contents.
+    ///
+    /// Channel bindings allow an authentication protocol running over
+    /// TLS to prove that both ends are party to the same TLS connection.
+    /// See [`ChannelBindingKind`] for the exact bytes produced for each
+    /// kind.
+    ///
+    /// This function fails if called prior to the handshake completing,
+    /// or if `kind` is not defined for the negotiated protocol version.
+    pub fn get_channel_binding(
+        &self,
+        kind: ChannelBindingKind,
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        match kind {
+            ChannelBindingKind::TlsExporter => {
+                if self.common_state.negotiated_version == Some(ProtocolVersion::TLSv1_2) {
+                    return Err(Error::General(
+                        "tls-exporter channel binding is not defined for TLS1.2".into(),
+                    ));
+                }
+
+                let mut binding = [0u8; TLS_EXPORTER_BINDING_LEN];
+                self.export_keying_material(&mut binding, TLS_EXPORTER_BINDING_LABEL, None)?;
+                out.clear();
+                out.extend_from_slice(&binding);
+                Ok(())
+            }
+            ChannelBindingKind::TlsServerEndPoint => {
+                if self.is_handshaking() {
+                    return Err(Error::HandshakeNotComplete);
+                }
+
+                let cert = match self.common_state.side {
+                    Side::Client => self
+                        .common_state
+                        .peer_certificates
+                        .as_ref()
+                        .and_then(|certs| certs.first()),
+                    Side::Server => self
+                        .common_state
+                        .server_certified_key
+                        .as_ref()
+                        .and_then(|ck| ck.end_entity_cert().ok()),
+                }
+                .ok_or(Error::NoCertificatesPresented)?;
+
+                let alg = x509::server_end_point_hash(&cert.0).ok_or_else(|| {
+                    Error::General(
+                        "cannot determine tls-server-end-point hash for certificate".into(),
+                    )
+                })?;
+                out.clear();
+                out.extend_from_slice(digest::digest(alg, &cert.0).as_ref());
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The kinds of channel binding data available from
+/// [`ConnectionCommon::get_channel_binding`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelBindingKind {
+    /// The `tls-exporter` channel binding defined in RFC9266.
+    ///
+    /// This is 32 bytes of keying material exported (per RFC5705) with
+    /// the label `"EXPORTER-Channel-Binding"` and an empty context.  It
+    /// is only defined for TLS1.3 connections.
+    TlsExporter,
+
+    /// The `tls-server-end-point` channel binding defined in RFC5929.
+    ///
+    /// This is the hash of the server's end-entity certificate, using
+    /// the hash function from the certificate's signature algorithm.
+    /// MD5 and SHA-1 are replaced with SHA-256.  It is available on
+    /// both clients and servers.
+    TlsServerEndPoint,
 }
 
+const TLS_EXPORTER_BINDING_LABEL: &[u8] = b"EXPORTER-Channel-Binding";
+const TLS_EXPORTER_BINDING_LEN: usize = 32;
+
 #[cfg(feature = "quic")]
 impl<Data> ConnectionCommon<Data> {
     pub(crate) fn read_quic_hs(&mut self, plaintext: &[u8]) -> Result<(), Error> {
@@ -756,8 +1088,15 @@ impl<Data> ConnectionCommon<Data> {
             return Err(Error::CorruptMessage);
         }
 
-        self.process_new_handshake_messages(state)
-            .map(|state| self.state = Ok(state))
+        self.state = Ok(Self::process_new_handshake_messages(
+            state,
+            &mut self.common_state,
+            &mut self.handshake_joiner,
+            &mut self.data,
+        )?);
+        self.common_state
+            .notify_if_handshake_complete();
+        Ok(())
     }
 }
 
@@ -775,23 +1114,42 @@ impl<T> DerefMut for ConnectionCommon<T> {
     }
 }
 
+/// A callback made when a connection's handshake completes: see
+/// [`ClientConfig::on_handshake_complete`](crate::ClientConfig::on_handshake_complete)
+/// and [`ServerConfig::on_handshake_complete`](crate::ServerConfig::on_handshake_complete).
+pub type HandshakeCompleteCallback = dyn Fn(&CommonState) + Send + Sync;
+
 /// Connection state common to both client and server connections.
 pub struct CommonState {
     pub(crate) negotiated_version: Option<ProtocolVersion>,
     pub(crate) side: Side,
     pub(crate) record_layer: record_layer::RecordLayer,
     pub(crate) suite: Option<SupportedCipherSuite>,
+    pub(crate) kx_group: Option<NamedGroup>,
+    pub(crate) resumed: bool,
+    pub(crate) session_id: Option<SessionID>,
     pub(crate) alpn_protocol: Option<Vec<u8>>,
     aligned_handshake: bool,
     pub(crate) may_send_application_data: bool,
     pub(crate) may_receive_application_data: bool,
     pub(crate) early_traffic: bool,
     sent_fatal_alert: bool,
+    /// If we have signaled end of stream.
+    has_sent_close_notify: bool,
     /// If the peer has signaled end of stream.
     has_received_close_notify: bool,
     has_seen_eof: bool,
     received_middlebox_ccs: u8,
+    /// A server doing stateless HelloRetryRequests may see a client's
+    /// middlebox CCS before the second ClientHello, on a new connection.
+    pub(crate) allow_ccs_before_client_hello: bool,
     pub(crate) peer_certificates: Option<Vec<key::Certificate>>,
+    pub(crate) verified_cert_chain: Option<Vec<key::Certificate>>,
+    /// The certificate chain and key selected by a server for this connection.
+    pub(crate) server_certified_key: Option<Arc<sign::CertifiedKey>>,
+    /// Called once the handshake completes, if set.
+    pub(crate) on_handshake_complete: Option<Arc<HandshakeCompleteCallback>>,
+    notified_handshake_complete: bool,
     message_fragmenter: MessageFragmenter,
     received_plaintext: ChunkVecBuffer,
     sendable_plaintext: ChunkVecBuffer,
@@ -810,16 +1168,25 @@ impl CommonState {
             side,
             record_layer: record_layer::RecordLayer::new(),
             suite: None,
+            kx_group: None,
+            resumed: false,
+            session_id: None,
             alpn_protocol: None,
             aligned_handshake: true,
             may_send_application_data: false,
             may_receive_application_data: false,
             early_traffic: false,
             sent_fatal_alert: false,
+            has_sent_close_notify: false,
             has_received_close_notify: false,
             has_seen_eof: false,
             received_middlebox_ccs: 0,
+            allow_ccs_before_client_hello: false,
             peer_certificates: None,
+            verified_cert_chain: None,
+            server_certified_key: None,
+            on_handshake_complete: None,
+            notified_handshake_complete: false,
             message_fragmenter: MessageFragmenter::new(max_fragment_size)
                 .map_err(|_| Error::BadMaxFragmentSize)?,
             received_plaintext: ChunkVecBuffer::new(Some(0)),
@@ -838,15 +1205,45 @@ impl CommonState {
         !self.sendable_tls.is_empty()
     }
 
+    /// How many bytes of TLS data are queued to be written by
+    /// [`CommonState::write_tls`].  This is non-zero exactly when
+    /// [`CommonState::wants_write`] is true.
+    pub fn tls_bytes_to_write(&self) -> usize {
+        self.sendable_tls.len()
+    }
+
+    /// How many bytes of received plaintext are buffered, and so can
+    /// be read without further I/O.
+    pub fn plaintext_bytes_to_read(&self) -> usize {
+        self.received_plaintext.len()
+    }
+
     /// Returns true if the connection is currently performing the TLS handshake.
     ///
+    /// This is true until both Finished messages have been exchanged: that is,
+    /// until this side has received its peer's Finished message and has sent
+    /// (or queued for sending) its own.  Only then can application data flow in
+    /// both directions.
+    ///
     /// During this time plaintext written to the connection is buffered in memory. After
     /// [`Connection::process_new_packets`] has been called, this might start to return `false`
     /// while the final handshake packets still need to be extracted from the connection's buffers.
+    /// So callers driving the handshake should keep calling [`CommonState::write_tls`] while
+    /// [`CommonState::wants_write`] is true, even once this returns `false`.
     pub fn is_handshaking(&self) -> bool {
         !(self.may_send_application_data && self.may_receive_application_data)
     }
 
+    /// Returns true if the peer has sent us a close_notify alert,
+    /// so it will not send any further data.
+    ///
+    /// This is independent of [`CommonState::send_close_notify`]: each
+    /// side closes its own direction, and data can still be received
+    /// after we have sent our close_notify.
+    pub fn peer_has_closed(&self) -> bool {
+        self.has_received_close_notify
+    }
+
     /// Retrieves the certificate chain used by the peer to authenticate.
     ///
     /// The order of the certificate chain is as it appears in the TLS
@@ -866,6 +1263,20 @@ impl CommonState {
         self.peer_certificates.as_deref()
     }
 
+    /// Retrieves the server certificate chain that was validated by the
+    /// certificate verifier.
+    ///
+    /// Unlike [`CommonState::peer_certificates`], this is the path from
+    /// the server's certificate to (but not including) a trust anchor, as
+    /// built during verification.  Any extra certificates the server sent
+    /// are omitted.
+    ///
+    /// This returns None for servers, for resumed handshakes, and if the
+    /// verifier did not report the chain it validated.
+    pub fn get_verified_cert_chain(&self) -> Option<&[key::Certificate]> {
+        self.verified_cert_chain.as_deref()
+    }
+
     /// Retrieves the protocol agreed with the peer via ALPN.
     ///
     /// A return value of `None` after handshake completion
@@ -875,6 +1286,16 @@ impl CommonState {
         self.get_alpn_protocol()
     }
 
+    /// Like [`CommonState::alpn_protocol`], but returns an owned copy
+    /// which does not borrow from the connection.
+    ///
+    /// This is available on both clients and servers as soon as
+    /// `is_handshaking()` returns false.
+    pub fn get_alpn_protocol_owned(&self) -> Option<Vec<u8>> {
+        self.get_alpn_protocol()
+            .map(<[u8]>::to_vec)
+    }
+
     /// Retrieves the ciphersuite agreed with the peer.
     ///
     /// This returns None until the ciphersuite is agreed.
@@ -882,6 +1303,47 @@ impl CommonState {
         self.suite
     }
 
+    /// Retrieves the key exchange group agreed with the peer.
+    ///
+    /// This returns `None` until the server has chosen a group, and for
+    /// TLS1.2 resumed handshakes, which do not do a key exchange.
+    pub fn get_negotiated_key_exchange_group(&self) -> Option<NamedGroup> {
+        self.kx_group
+    }
+
+    /// Returns whether this connection resumed an earlier session.
+    ///
+    /// This is `Some(true)` after a successful TLS1.2 session ID or
+    /// ticket resumption, or TLS1.3 PSK resumption, and `Some(false)`
+    /// after a full handshake.  It returns `None` while handshaking.
+    pub fn is_session_resumed(&self) -> Option<bool> {
+        match self.is_handshaking() {
+            true => None,
+            false => Some(self.resumed),
+        }
+    }
+
+    /// Retrieves the session ID the server chose for a TLS1.2 connection.
+    ///
+    /// This returns `None` until the ServerHello has been processed, and
+    /// if the server sent an empty session ID: for example, because it
+    /// does not cache sessions.  It is always `None` for TLS1.3, where
+    /// session IDs are only sent for middlebox compatibility.
+    pub fn get_session_id(&self) -> Option<&[u8]> {
+        self.session_id
+            .as_ref()
+            .map(|id| id.as_ref())
+    }
+
+    /// Retrieves how many records this connection has encrypted and
+    /// decrypted so far, and how much data they carried.
+    ///
+    /// Records are encrypted as data is written, not as it is sent
+    /// with [`CommonState::write_tls`].
+    pub fn get_record_counters(&self) -> record_layer::RecordCounters {
+        self.record_layer.counters()
+    }
+
     /// Retrieves the protocol version agreed with the peer.
     ///
     /// This returns `None` until the version is agreed.
@@ -889,6 +1351,16 @@ impl CommonState {
         self.negotiated_version
     }
 
+    /// Retrieves the protocol version agreed with the peer:
+    /// `TLSv1_2` or `TLSv1_3`.
+    ///
+    /// This returns `None` until the ServerHello is processed, so
+    /// it's available while the rest of the handshake is in progress.
+    /// It is the same as [`CommonState::protocol_version`].
+    pub fn get_protocol_version(&self) -> Option<ProtocolVersion> {
+        self.protocol_version()
+    }
+
     pub(crate) fn is_tls13(&self) -> bool {
         matches!(self.negotiated_version, Some(ProtocolVersion::TLSv1_3))
     }
@@ -927,13 +1399,87 @@ impl CommonState {
         }
     }
 
+    /// Fail if we can no longer encrypt records because our sequence
+    /// number has reached its limit.  This can only happen on a TLS1.2
+    /// connection that has sent almost 2^64 records.
+    fn check_not_exhausted(&self) -> io::Result<()> {
+        if self.record_layer.encrypt_exhausted() {
+            return Err(io::Error::new(io::ErrorKind::Other, Error::EncryptError));
+        }
+        Ok(())
+    }
+
     /// Send plaintext application data, fragmenting and
     /// encrypting it as it goes out.
     ///
     /// If internal buffers are too small, this function will not accept
-    /// all the data.
-    pub(crate) fn send_some_plaintext(&mut self, data: &[u8]) -> usize {
-        self.send_plain(data, Limit::Yes)
+    /// all the data.  `before_record` is called before each record is
+    /// encrypted, giving a TLS1.3 connection the chance to update its
+    /// keys part-way through a large write.
+    pub(crate) fn send_some_plaintext(
+        &mut self,
+        data: &[u8],
+        before_record: &mut dyn FnMut(&mut Self),
+    ) -> usize {
+        self.send_plain(data, Limit::Yes, before_record)
+    }
+
+    /// Like send_some_plaintext, but for several buffers at once.
+    ///
+    /// Small buffers are coalesced so that we emit full-sized records,
+    /// rather than one record per buffer.  Returns how many bytes were
+    /// taken, which may be fewer than offered if the buffer limit is hit.
+    pub(crate) fn send_some_plaintext_vectored(
+        &mut self,
+        bufs: &[io::IoSlice<'_>],
+        before_record: &mut dyn FnMut(&mut Self),
+    ) -> usize {
+        if let [buf] = bufs {
+            return self.send_some_plaintext(buf, before_record);
+        }
+
+        let max_frag = self
+            .message_fragmenter
+            .max_fragment_len();
+        let mut pending = Vec::new();
+        let mut sent = 0;
+
+        for buf in bufs {
+            let mut buf: &[u8] = buf;
+
+            while !buf.is_empty() {
+                // Send whole fragments straight from the caller's buffer
+                // if nothing is waiting to be coalesced.
+                if pending.is_empty() && buf.len() >= max_frag {
+                    let whole = buf.len() - buf.len() % max_frag;
+                    let used = self.send_some_plaintext(&buf[..whole], before_record);
+                    sent += used;
+                    if used < whole {
+                        return sent;
+                    }
+                    buf = &buf[whole..];
+                    continue;
+                }
+
+                let take = cmp::min(max_frag - pending.len(), buf.len());
+                pending.extend_from_slice(&buf[..take]);
+                buf = &buf[take..];
+
+                if pending.len() == max_frag {
+                    let used = self.send_some_plaintext(&pending, before_record);
+                    sent += used;
+                    if used < pending.len() {
+                        return sent;
+                    }
+                    pending.clear();
+                }
+            }
+        }
+
+        if !pending.is_empty() {
+            sent += self.send_some_plaintext(&pending, before_record);
+        }
+        sent
     }
 
     pub(crate) fn send_early_plaintext(&mut self, data: &[u8]) -> usize {
@@ -945,7 +1491,7 @@ impl CommonState {
             return 0;
         }
 
-        self.send_appdata_encrypt(data, Limit::Yes)
+        self.send_appdata_encrypt(data, Limit::Yes, &mut |_| {})
     }
 
     // Changing the keys must not span any fragmented handshake
@@ -968,10 +1514,10 @@ impl CommonState {
         Error::PeerMisbehavedError(why.to_string())
     }
 
-    pub(crate) fn decrypt_incoming(
+    pub(crate) fn decrypt_incoming<'a>(
         &mut self,
-        encr: OpaqueMessage,
-    ) -> Result<Option<PlainMessage>, Error> {
+        encr: BorrowedOpaqueMessage<'a>,
+    ) -> Result<Option<BorrowedPlainMessage<'a>>, Error> {
         if self
             .record_layer
             .wants_close_before_decrypt()
@@ -979,7 +1525,7 @@ impl CommonState {
             self.send_close_notify();
         }
 
-        let encrypted_len = encr.payload.0.len();
+        let encrypted_len = encr.payload.len();
         let plain = self.record_layer.decrypt_incoming(encr);
 
         match plain {
@@ -1017,7 +1563,13 @@ impl CommonState {
     }
 
     /// Like send_msg_encrypt, but operate on an appdata directly.
-    fn send_appdata_encrypt(&mut self, payload: &[u8], limit: Limit) -> usize {
+    /// `before_record` is called before each fragment is encrypted.
+    fn send_appdata_encrypt(
+        &mut self,
+        payload: &[u8],
+        limit: Limit,
+        before_record: &mut dyn FnMut(&mut Self),
+    ) -> usize {
         // Here, the limit on sendable_tls applies to encrypted data,
         // but we're respecting it for plaintext data -- so we'll
         // be out by whatever the cipher+record overhead is.  That's a
@@ -1038,6 +1590,7 @@ impl CommonState {
         );
 
         for m in plain_messages {
+            before_record(self);
             self.send_single_fragment(m);
         }
 
@@ -1054,14 +1607,17 @@ impl CommonState {
             self.send_close_notify();
         }
 
-        // Refuse to wrap counter at all costs.  This
-        // is basically untestable unfortunately.
-        if self.record_layer.encrypt_exhausted() {
-            return;
+        // Refuse to wrap counter at all costs.  Plaintext writes
+        // fail before we get here; anything else is dropped.
+        let max_fragment_len = self
+            .message_fragmenter
+            .max_fragment_len();
+        if let Ok(em) = self
+            .record_layer
+            .encrypt_outgoing(m, max_fragment_len)
+        {
+            self.queue_tls_message(em);
         }
-
-        let em = self.record_layer.encrypt_outgoing(m);
-        self.queue_tls_message(em);
     }
 
     /// Writes TLS messages to `wr`.
@@ -1080,7 +1636,12 @@ impl CommonState {
     ///
     /// Returns the number of bytes written from `data`: this might
     /// be less than `data.len()` if buffer limits were exceeded.
-    fn send_plain(&mut self, data: &[u8], limit: Limit) -> usize {
+    fn send_plain(
+        &mut self,
+        data: &[u8],
+        limit: Limit,
+        before_record: &mut dyn FnMut(&mut Self),
+    ) -> usize {
         if !self.may_send_application_data {
             // If we haven't completed handshaking, buffer
             // plaintext to send once we do.
@@ -1102,7 +1663,7 @@ impl CommonState {
             return 0;
         }
 
-        self.send_appdata_encrypt(data, limit)
+        self.send_appdata_encrypt(data, limit, before_record)
     }
 
     pub(crate) fn start_outgoing_traffic(&mut self) {
@@ -1115,6 +1676,19 @@ impl CommonState {
         self.start_outgoing_traffic();
     }
 
+    /// Call `on_handshake_complete` the first time we find the
+    /// handshake has completed.
+    fn notify_if_handshake_complete(&mut self) {
+        if self.notified_handshake_complete || self.is_handshaking() {
+            return;
+        }
+
+        self.notified_handshake_complete = true;
+        if let Some(callback) = &self.on_handshake_complete {
+            callback(self);
+        }
+    }
+
     /// Sets a limit on the internal buffers used to buffer
     /// unsent plaintext (prior to completing the TLS handshake)
     /// and unsent TLS records.  This limit acts only on application
@@ -1167,7 +1741,7 @@ impl CommonState {
         }
 
         while let Some(buf) = self.sendable_plaintext.pop() {
-            self.send_plain(&buf, Limit::No);
+            self.send_plain(&buf, Limit::No, &mut |_| {});
         }
     }
 
@@ -1272,8 +1846,17 @@ impl CommonState {
     /// Queues a close_notify warning alert to be sent in the next
     /// [`CommonState::write_tls`] call.  This informs the peer that the
     /// connection is being closed.
+    ///
+    /// This only closes our direction of the connection: the peer may
+    /// carry on sending data, which can be read as usual until it sends
+    /// its own close_notify (see [`CommonState::peer_has_closed`]).
+    /// Calling this more than once has no further effect.
     pub fn send_close_notify(&mut self) {
+        if self.has_sent_close_notify {
+            return;
+        }
         debug!("Sending warning alert {:?}", AlertDescription::CloseNotify);
+        self.has_sent_close_notify = true;
         self.send_warning_alert_no_log(AlertDescription::CloseNotify);
     }
 
@@ -1282,11 +1865,31 @@ impl CommonState {
         self.send_msg(m, self.record_layer.is_encrypting());
     }
 
-    pub(crate) fn set_max_fragment_size(&mut self, new: Option<usize>) -> Result<(), Error> {
+    /// Sets the largest TLS record we will send, including the 5 byte
+    /// record header, with the same meaning and range as
+    /// [`crate::ClientConfig::max_fragment_size`].  `None` means the
+    /// TLS maximum.
+    ///
+    /// This applies to records produced from now on, and is never more
+    /// than a limit the peer asked for using the max_fragment_length or
+    /// record_size_limit extensions.  Values outside the allowed range
+    /// are rejected with [`Error::BadMaxFragmentSize`], leaving the
+    /// current setting unchanged.
+    pub fn set_max_fragment_size(&mut self, new: Option<usize>) -> Result<(), Error> {
         self.message_fragmenter
             .set_max_fragment_size(new)
     }
 
+    pub(crate) fn limit_max_fragment_len(&mut self, len: usize) {
+        self.message_fragmenter
+            .limit_max_fragment_len(len);
+    }
+
+    pub(crate) fn limit_inbound_fragment_len(&mut self, len: usize) {
+        self.record_layer
+            .limit_inbound_fragment_len(len);
+    }
+
     pub(crate) fn get_alpn_protocol(&self) -> Option<&[u8]> {
         self.alpn_protocol
             .as_ref()
@@ -1313,8 +1916,8 @@ impl CommonState {
 
     fn current_io_state(&self) -> IoState {
         IoState {
-            tls_bytes_to_write: self.sendable_tls.len(),
-            plaintext_bytes_to_read: self.received_plaintext.len(),
+            tls_bytes_to_write: self.tls_bytes_to_write(),
+            plaintext_bytes_to_read: self.plaintext_bytes_to_read(),
             peer_has_closed: self.has_received_close_notify,
         }
     }
@@ -1345,7 +1948,19 @@ pub(crate) trait State<Data>: Send + Sync {
         Err(Error::HandshakeNotComplete)
     }
 
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        None
+    }
+
     fn perhaps_write_key_update(&mut self, _cx: &mut CommonState) {}
+
+    fn request_client_auth(&mut self, _common: &mut CommonState) -> Result<(), Error> {
+        Err(Error::HandshakeNotComplete)
+    }
+
+    fn refresh_traffic_keys(&mut self, _common: &mut CommonState) -> Result<(), Error> {
+        Err(Error::HandshakeNotComplete)
+    }
 }
 
 pub(crate) struct Context<'a, Data> {
@@ -1359,7 +1974,8 @@ pub(crate) struct Quic {
     pub(crate) params: Option<Vec<u8>>,
     pub(crate) alert: Option<AlertDescription>,
     pub(crate) hs_queue: VecDeque<(bool, Vec<u8>)>,
-    pub(crate) early_secret: Option<ring::hkdf::Prk>,
+    /// Boxed, since it is large and only used for 0-RTT.
+    pub(crate) early_secret: Option<Box<ring::hkdf::Prk>>,
     pub(crate) hs_secrets: Option<quic::Secrets>,
     pub(crate) traffic_secrets: Option<quic::Secrets>,
     /// Whether keys derived from traffic_secrets have been passed to the QUIC implementation
@@ -0,0 +1,187 @@
+use crate::error::Error;
+use crate::suites::BulkAlgorithm;
+
+use std::fmt;
+
+/// The default provider, backed by *ring*.
+pub mod ring;
+
+/// The length of the nonce used by the AEAD algorithms of all supported
+/// cipher suites.
+pub const NONCE_LEN: usize = 12;
+
+/// A source of the cryptographic primitives used by the TLS protocol.
+///
+/// rustls uses this for handshake hashing, HMAC, HKDF, the TLS1.2 PRF
+/// and record protection.  Install one with the `crypto_provider` field
+/// of [`ClientConfig`] or [`ServerConfig`]; the default is
+/// [`ring::Ring`].
+///
+/// Implementations must support every algorithm used by the configured
+/// cipher suites.
+///
+/// *ring* remains a dependency: key exchange, signing, ticket encryption
+/// and QUIC packet protection use it directly, as does webpki for
+/// certificate validation and, through the certificate verifiers, for
+/// checking handshake signatures.
+///
+/// [`ClientConfig`]: crate::ClientConfig
+/// [`ServerConfig`]: crate::ServerConfig
+pub trait CryptoProvider: Send + Sync + fmt::Debug {
+    /// Start an incremental hash computation using `alg`.
+    fn start_hash(&self, alg: HashAlgorithm) -> Box<dyn HashContext>;
+
+    /// Make an HMAC key from `key`, using hash function `alg`.
+    fn hmac_key(&self, alg: HashAlgorithm, key: &[u8]) -> Box<dyn HmacKey>;
+
+    /// HKDF-Extract from RFC5869, returning the pseudorandom key.
+    fn hkdf_extract(&self, alg: HashAlgorithm, salt: &[u8], ikm: &[u8]) -> Output;
+
+    /// HKDF-Expand from RFC5869, filling `out` from the pseudorandom key
+    /// `prk`.  `info` is the concatenation of the given slices.
+    ///
+    /// This fails if `out` is longer than HKDF allows for `alg`.
+    fn hkdf_expand(
+        &self,
+        alg: HashAlgorithm,
+        prk: &[u8],
+        info: &[&[u8]],
+        out: &mut [u8],
+    ) -> Result<(), Error>;
+
+    /// Make a key for the AEAD algorithm `alg` from `key`.
+    ///
+    /// This fails if `key` is the wrong length for `alg`.
+    fn aead_key(&self, alg: BulkAlgorithm, key: &[u8]) -> Result<Box<dyn AeadKey>, Error>;
+
+    /// Return true if [`CryptoProvider::aead_key`] supports `alg`.
+    ///
+    /// Cipher suites using other algorithms are neither offered nor
+    /// selected, even if they are configured.  The default implementation
+    /// supports everything but the AES-CCM algorithms.
+    fn supports_aead(&self, alg: BulkAlgorithm) -> bool {
+        !matches!(alg, BulkAlgorithm::Aes128Ccm | BulkAlgorithm::Aes128Ccm8)
+    }
+}
+
+/// A hash function.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashAlgorithm {
+    /// SHA-256 from FIPS 180-4.
+    Sha256,
+    /// SHA-384 from FIPS 180-4.
+    Sha384,
+    /// SHA-512 from FIPS 180-4.
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// The length of this hash function's output.
+    pub fn output_len(self) -> usize {
+        match self {
+            Self::Sha256 => 32,
+            Self::Sha384 => 48,
+            Self::Sha512 => 64,
+        }
+    }
+}
+
+/// An incremental hash computation.
+pub trait HashContext: Send + Sync {
+    /// The hash function in use.
+    fn algorithm(&self) -> HashAlgorithm;
+
+    /// Add `data` to the computation.
+    fn update(&mut self, data: &[u8]);
+
+    /// Copy the state of the computation so far.
+    fn fork(&self) -> Box<dyn HashContext>;
+
+    /// Complete the computation, returning the hash.
+    fn finish(self: Box<Self>) -> Output;
+}
+
+/// A key for computing HMACs.
+pub trait HmacKey: Send + Sync {
+    /// Compute the HMAC of the concatenation of `data`.
+    fn sign(&self, data: &[&[u8]]) -> Output;
+}
+
+/// A key for an AEAD algorithm.
+pub trait AeadKey: Send + Sync {
+    /// The length of the authentication tag this algorithm produces.
+    fn tag_len(&self) -> usize;
+
+    /// Encrypt `in_out` in place, returning the authentication tag.
+    fn seal_in_place_separate_tag(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<Output, Error>;
+
+    /// Authenticate and decrypt `in_out` in place, where `in_out` is the
+    /// ciphertext followed by the tag.  Returns the length of the plaintext,
+    /// which is left at the start of `in_out`.
+    fn open_in_place(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<usize, Error>;
+}
+
+/// The output of a hash or HMAC computation, or an AEAD tag.
+///
+/// This is also used to hold secrets, so it is zeroized when dropped.
+#[derive(Clone)]
+pub struct Output {
+    buf: [u8; Self::MAX_LEN],
+    used: usize,
+}
+
+impl Output {
+    /// The longest output we can hold.
+    pub const MAX_LEN: usize = 64;
+
+    /// Make an `Output` holding a copy of `bytes`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is longer than [`Output::MAX_LEN`].
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut output = Self {
+            buf: [0u8; Self::MAX_LEN],
+            used: bytes.len(),
+        };
+        output.buf[..bytes.len()].copy_from_slice(bytes);
+        output
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        crate::zeroize::zeroize(&mut self.buf);
+    }
+}
+
+impl AsRef<[u8]> for Output {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.used]
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        crate::bs_debug::BsDebug(self.as_ref()).fmt(f)
+    }
+}
+
+impl dyn CryptoProvider + '_ {
+    /// Hash `data` in one go.
+    pub(crate) fn hash(&self, alg: HashAlgorithm, data: &[u8]) -> Output {
+        let mut ctx = self.start_hash(alg);
+        ctx.update(data);
+        ctx.finish()
+    }
+}
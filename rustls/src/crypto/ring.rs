@@ -0,0 +1,270 @@
+use crate::crypto::{
+    AeadKey, CryptoProvider, HashAlgorithm, HashContext, HmacKey, Output, NONCE_LEN,
+};
+use crate::error::Error;
+use crate::suites::BulkAlgorithm;
+
+use ring::{aead, digest, hkdf, hmac};
+
+/// A [`CryptoProvider`] backed by *ring*.
+///
+/// This is the default provider.
+#[derive(Debug)]
+pub struct Ring;
+
+impl CryptoProvider for Ring {
+    fn start_hash(&self, alg: HashAlgorithm) -> Box<dyn HashContext> {
+        Box::new(RingHash(digest::Context::new(digest_algorithm(alg))))
+    }
+
+    fn hmac_key(&self, alg: HashAlgorithm, key: &[u8]) -> Box<dyn HmacKey> {
+        Box::new(RingHmacKey(hmac::Key::new(hmac_algorithm(alg), key)))
+    }
+
+    fn hkdf_extract(&self, alg: HashAlgorithm, salt: &[u8], ikm: &[u8]) -> Output {
+        // HKDF-Extract is defined as HMAC-Hash(salt, IKM); ring does
+        // not give out the resulting PRK, so compute it directly.
+        self.hmac_key(alg, salt).sign(&[ikm])
+    }
+
+    fn hkdf_expand(
+        &self,
+        alg: HashAlgorithm,
+        prk: &[u8],
+        info: &[&[u8]],
+        out: &mut [u8],
+    ) -> Result<(), Error> {
+        hkdf::Prk::new_less_safe(hkdf_algorithm(alg), prk)
+            .expand(info, Len(out.len()))
+            .and_then(|okm| okm.fill(out))
+            .map_err(|_| Error::General("HKDF output too long".to_string()))
+    }
+
+    fn aead_key(&self, alg: BulkAlgorithm, key: &[u8]) -> Result<Box<dyn AeadKey>, Error> {
+        let alg = match alg {
+            BulkAlgorithm::Aes128Gcm => &aead::AES_128_GCM,
+            BulkAlgorithm::Aes256Gcm => &aead::AES_256_GCM,
+            BulkAlgorithm::Chacha20Poly1305 => &aead::CHACHA20_POLY1305,
+            BulkAlgorithm::Aes128Ccm | BulkAlgorithm::Aes128Ccm8 => {
+                return Err(Error::General("AES-CCM is not supported".to_string()));
+            }
+        };
+        let key = aead::UnboundKey::new(alg, key)
+            .map_err(|_| Error::General("invalid AEAD key".to_string()))?;
+        Ok(Box::new(RingAeadKey(aead::LessSafeKey::new(key))))
+    }
+}
+
+/// The TLS1.2 pseudorandom function from [RFC5246 section 5], filling
+/// `out` with `PRF(secret, label, seed)`, using HMAC with the hash
+/// function `alg`.
+///
+/// This is the PRF TLS1.2 itself uses, for protocols which reuse it
+/// for their own key derivation.
+///
+/// # Panics
+///
+/// Panics if `alg` is not `digest::SHA256`, `digest::SHA384` or
+/// `digest::SHA512`.
+///
+/// [RFC5246 section 5]: https://datatracker.ietf.org/doc/html/rfc5246#section-5
+#[cfg(feature = "tls12")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tls12")))]
+pub fn prf(
+    out: &mut [u8],
+    alg: &'static digest::Algorithm,
+    secret: &[u8],
+    label: &[u8],
+    seed: &[u8],
+) {
+    let alg = [
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Sha384,
+        HashAlgorithm::Sha512,
+    ]
+    .iter()
+    .copied()
+    .find(|hash| digest_algorithm(*hash) == alg)
+    .expect("unsupported PRF hash function");
+    crate::tls12::prf::prf(out, &Ring, alg, secret, label, seed);
+}
+
+/// The *ring* digest algorithm corresponding to `alg`.
+pub(crate) fn digest_algorithm(alg: HashAlgorithm) -> &'static digest::Algorithm {
+    match alg {
+        HashAlgorithm::Sha256 => &digest::SHA256,
+        HashAlgorithm::Sha384 => &digest::SHA384,
+        HashAlgorithm::Sha512 => &digest::SHA512,
+    }
+}
+
+fn hmac_algorithm(alg: HashAlgorithm) -> hmac::Algorithm {
+    match alg {
+        HashAlgorithm::Sha256 => hmac::HMAC_SHA256,
+        HashAlgorithm::Sha384 => hmac::HMAC_SHA384,
+        HashAlgorithm::Sha512 => hmac::HMAC_SHA512,
+    }
+}
+
+/// The *ring* HKDF algorithm corresponding to `alg`.
+pub(crate) fn hkdf_algorithm(alg: HashAlgorithm) -> hkdf::Algorithm {
+    match alg {
+        HashAlgorithm::Sha256 => hkdf::HKDF_SHA256,
+        HashAlgorithm::Sha384 => hkdf::HKDF_SHA384,
+        HashAlgorithm::Sha512 => hkdf::HKDF_SHA512,
+    }
+}
+
+struct RingHash(digest::Context);
+
+impl HashContext for RingHash {
+    fn algorithm(&self) -> HashAlgorithm {
+        let alg = self.0.algorithm();
+        if alg == &digest::SHA384 {
+            HashAlgorithm::Sha384
+        } else if alg == &digest::SHA512 {
+            HashAlgorithm::Sha512
+        } else {
+            HashAlgorithm::Sha256
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn fork(&self) -> Box<dyn HashContext> {
+        Box::new(Self(self.0.clone()))
+    }
+
+    fn finish(self: Box<Self>) -> Output {
+        Output::new(self.0.finish().as_ref())
+    }
+}
+
+struct RingHmacKey(hmac::Key);
+
+impl HmacKey for RingHmacKey {
+    fn sign(&self, data: &[&[u8]]) -> Output {
+        let mut ctx = hmac::Context::with_key(&self.0);
+        for d in data {
+            ctx.update(d);
+        }
+        Output::new(ctx.sign().as_ref())
+    }
+}
+
+struct RingAeadKey(aead::LessSafeKey);
+
+impl AeadKey for RingAeadKey {
+    fn tag_len(&self) -> usize {
+        self.0.algorithm().tag_len()
+    }
+
+    fn seal_in_place_separate_tag(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<Output, Error> {
+        self.0
+            .seal_in_place_separate_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(aad),
+                in_out,
+            )
+            .map(|tag| Output::new(tag.as_ref()))
+            .map_err(|_| Error::General("encrypt failed".to_string()))
+    }
+
+    fn open_in_place(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<usize, Error> {
+        self.0
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(aad),
+                in_out,
+            )
+            .map(|plain| plain.len())
+            .map_err(|_| Error::DecryptError)
+    }
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+#[cfg(all(test, feature = "tls12"))]
+mod tests {
+    use super::prf;
+    use ring::digest;
+
+    // These are the widely-used TLS1.2 PRF test vectors posted to the
+    // IETF TLS working group list; RFC5246 itself has none.
+    #[test]
+    fn prf_sha256_known_answer() {
+        let secret = b"\x9b\xbe\x43\x6b\xa9\x40\xf0\x17\xb1\x76\x52\x84\x9a\x71\xdb\x35";
+        let seed = b"\xa0\xba\x9f\x93\x6c\xda\x31\x18\x27\xa6\xf7\x96\xff\xd5\x19\x8c";
+        let mut output = [0u8; 100];
+        prf(&mut output, &digest::SHA256, secret, b"test label", seed);
+        assert_eq!(
+            output.to_vec(),
+            include_bytes!("../testdata/prf-result.1.bin").to_vec()
+        );
+    }
+
+    #[test]
+    fn prf_sha512_known_answer() {
+        let secret = b"\xb0\x32\x35\x23\xc1\x85\x35\x99\x58\x4d\x88\x56\x8b\xbb\x05\xeb";
+        let seed = b"\xd4\x64\x0e\x12\xe4\xbc\xdb\xfb\x43\x7f\x03\xe6\xae\x41\x8e\xe5";
+        let mut output = [0u8; 196];
+        prf(&mut output, &digest::SHA512, secret, b"test label", seed);
+        assert_eq!(
+            output.to_vec(),
+            include_bytes!("../testdata/prf-result.2.bin").to_vec()
+        );
+    }
+
+    #[test]
+    fn prf_sha384_known_answer() {
+        /* This test vector generated with OpenSSL's TLS1-PRF. */
+        let secret = [
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+            0x0f, 0x10,
+        ];
+        let seed = [
+            0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xab, 0xac, 0xad,
+            0xae, 0xaf,
+        ];
+        let mut output = [0u8; 32];
+        prf(&mut output, &digest::SHA384, &secret, b"test label", &seed);
+        assert_eq!(
+            output,
+            [
+                0x75, 0xa9, 0x06, 0x6e, 0xf7, 0xcc, 0x4b, 0x8d, 0xab, 0x7e, 0x5f, 0x75, 0xa0, 0x70,
+                0x8c, 0x13, 0x12, 0x15, 0x9c, 0xb4, 0xa5, 0xc2, 0xcb, 0x2e, 0x26, 0xd6, 0x60, 0xd7,
+                0xc1, 0xd3, 0x8c, 0xb3,
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "unsupported PRF hash function")]
+    fn prf_rejects_other_hashes() {
+        prf(
+            &mut [0u8; 16],
+            &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            b"",
+            b"",
+            b"",
+        );
+    }
+}
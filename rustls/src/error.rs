@@ -3,6 +3,7 @@ use crate::rand;
 
 use std::error::Error as StdError;
 use std::fmt;
+use std::sync::Arc;
 use std::time::SystemTimeError;
 
 /// rustls reports protocol errors using this type.
@@ -37,6 +38,12 @@ pub enum Error {
     CorruptMessagePayload(ContentType),
 
     /// The peer didn't give us any certificates.
+    ///
+    /// A server reports this if a client declines to authenticate when
+    /// client authentication is mandatory, having sent it a
+    /// `certificate_required` alert (or `handshake_failure` in TLS1.2).
+    /// Certificates which are sent but fail verification are reported
+    /// with other errors.
     NoCertificatesPresented,
 
     /// The certificate verifier doesn't support the given type of name.
@@ -45,7 +52,8 @@ pub enum Error {
     /// We couldn't decrypt a message.  This is invariably fatal.
     DecryptError,
 
-    /// We couldn't encrypt a message because it was larger than the allowed message size.
+    /// We couldn't encrypt a message because it was larger than the allowed message size,
+    /// or because a TLS1.2 connection ran out of sequence numbers.
     /// This should never happen if the application is using valid record sizes.
     EncryptError,
 
@@ -72,12 +80,53 @@ pub enum Error {
     /// We received a certificate which includes invalid data.
     InvalidCertificateData(String),
 
+    /// We received a certificate whose validity period has ended.
+    CertExpired,
+
+    /// We received a certificate whose validity period has not yet begun.
+    CertNotValidYet,
+
+    /// We received a certificate which is not valid for the name
+    /// (or IP address) of the server we connected to.
+    CertNotValidForName,
+
+    /// We received a certificate chain which does not lead to any
+    /// of our trust anchors.
+    UnknownIssuer,
+
+    /// We received a certificate which has been revoked by its issuer,
+    /// according to a configured certificate revocation list or a
+    /// stapled OCSP response.
+    CertRevoked,
+
+    /// A configured certificate revocation list could not be used: either
+    /// it is invalid, or it has expired.
+    InvalidCrl(String),
+
+    /// A private key could not be used: for example, an encrypted
+    /// private key was malformed, used an unsupported scheme, or
+    /// could not be decrypted with the given passphrase.
+    InvalidPrivateKey(String),
+
+    /// The server certificate requires an OCSP response to be stapled
+    /// ("OCSP must-staple"), but the server did not provide a valid and
+    /// current one for it.
+    MissingOcspStaple,
+
     /// The presented SCT(s) were invalid.
     InvalidSct(sct::Error),
 
+    /// No certificate in the peer's chain has a public key matching
+    /// one of the configured pins.
+    PinMismatch,
+
     /// A catch-all error for unlikely errors.
     General(String),
 
+    /// An error of the application's own type, returned by one of its
+    /// callbacks (for example, a custom `ServerCertVerifier`).
+    Other(OtherError),
+
     /// We failed to figure out what time it currently is.
     FailedToGetCurrentTime,
 
@@ -95,7 +144,8 @@ pub enum Error {
     NoApplicationProtocol,
 
     /// The `max_fragment_size` value supplied in configuration was too small,
-    /// or too large.
+    /// or too large.  This is also used for invalid `max_fragment_length` and
+    /// `record_size_limit` values.
     BadMaxFragmentSize,
 }
 
@@ -146,6 +196,20 @@ impl fmt::Display for Error {
             Error::InvalidCertificateData(ref reason) => {
                 write!(f, "invalid peer certificate contents: {}", reason)
             }
+            Error::CertExpired => write!(f, "peer certificate has expired"),
+            Error::CertNotValidYet => write!(f, "peer certificate is not valid yet"),
+            Error::CertNotValidForName => {
+                write!(f, "peer certificate is not valid for the expected name")
+            }
+            Error::UnknownIssuer => write!(f, "peer certificate has an unknown issuer"),
+            Error::CertRevoked => write!(f, "peer certificate has been revoked"),
+            Error::InvalidCrl(ref reason) => {
+                write!(f, "invalid certificate revocation list: {}", reason)
+            }
+            Error::InvalidPrivateKey(ref reason) => write!(f, "invalid private key: {}", reason),
+            Error::MissingOcspStaple => {
+                write!(f, "peer certificate requires a stapled OCSP response")
+            }
             Error::CorruptMessage => write!(f, "received corrupt message"),
             Error::NoCertificatesPresented => write!(f, "peer sent no certificates"),
             Error::UnsupportedNameType => write!(f, "presented server name type wasn't supported"),
@@ -155,12 +219,14 @@ impl fmt::Display for Error {
             Error::HandshakeNotComplete => write!(f, "handshake not complete"),
             Error::NoApplicationProtocol => write!(f, "peer doesn't support any known protocol"),
             Error::InvalidSct(ref err) => write!(f, "invalid certificate timestamp: {:?}", err),
+            Error::PinMismatch => write!(f, "peer certificate chain does not match any pin"),
             Error::FailedToGetCurrentTime => write!(f, "failed to get current time"),
             Error::FailedToGetRandomBytes => write!(f, "failed to get random bytes"),
             Error::BadMaxFragmentSize => {
                 write!(f, "the supplied max_fragment_size was too small or large")
             }
             Error::General(ref err) => write!(f, "unexpected error: {}", err),
+            Error::Other(ref err) => write!(f, "other error: {}", err),
         }
     }
 }
@@ -172,7 +238,43 @@ impl From<SystemTimeError> for Error {
     }
 }
 
-impl StdError for Error {}
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            Self::Other(ref err) => Some(err.0.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// An application-defined error, carried through rustls unchanged
+/// in [`Error::Other`].
+///
+/// The application can recover its own type with
+/// `err.0.downcast_ref::<T>()`.
+///
+/// Two `OtherError`s never compare equal, since the errors inside
+/// cannot be compared.
+#[derive(Debug, Clone)]
+pub struct OtherError(pub Arc<dyn StdError + Send + Sync>);
+
+impl PartialEq for OtherError {
+    fn eq(&self, _other: &Self) -> bool {
+        false
+    }
+}
+
+impl fmt::Display for OtherError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<OtherError> for Error {
+    fn from(err: OtherError) -> Self {
+        Self::Other(err)
+    }
+}
 
 impl From<rand::GetRandomFailed> for Error {
     fn from(_: rand::GetRandomFailed) -> Self {
@@ -182,7 +284,8 @@ impl From<rand::GetRandomFailed> for Error {
 
 #[cfg(test)]
 mod tests {
-    use super::Error;
+    use super::{Error, OtherError};
+    use std::sync::Arc;
 
     #[test]
     fn smoke() {
@@ -209,8 +312,17 @@ mod tests {
             Error::InvalidCertificateSignatureType,
             Error::InvalidCertificateSignature,
             Error::InvalidCertificateData("Data".into()),
+            Error::CertExpired,
+            Error::CertNotValidYet,
+            Error::CertNotValidForName,
+            Error::UnknownIssuer,
+            Error::CertRevoked,
+            Error::InvalidCrl("expired".into()),
+            Error::InvalidPrivateKey("wrong passphrase".into()),
+            Error::MissingOcspStaple,
             Error::InvalidSct(sct::Error::MalformedSct),
             Error::General("undocumented error".to_string()),
+            Error::Other(OtherError(Arc::new(Error::CorruptMessage))),
             Error::FailedToGetCurrentTime,
             Error::FailedToGetRandomBytes,
             Error::HandshakeNotComplete,
@@ -225,6 +337,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn other_error_is_source() {
+        use std::error::Error as StdError;
+
+        let err = Error::Other(OtherError(Arc::new(Error::DecryptError)));
+        assert_eq!(
+            err.to_string(),
+            "other error: cannot decrypt peer's message"
+        );
+        assert_ne!(err, err.clone());
+
+        let source = err.source().unwrap();
+        assert_eq!(source.downcast_ref::<Error>(), Some(&Error::DecryptError));
+        assert!(Error::DecryptError.source().is_none());
+    }
+
     #[test]
     fn rand_error_mapping() {
         use super::rand;
@@ -1,14 +1,18 @@
+use crate::crypto::{CryptoProvider, HashAlgorithm, HashContext, Output};
 use crate::msgs::codec::Codec;
 use crate::msgs::handshake::HandshakeMessagePayload;
 use crate::msgs::message::{Message, MessagePayload};
-use ring::digest;
 use std::mem;
+use std::sync::Arc;
 
 /// Early stage buffering of handshake payloads.
 ///
 /// Before we know the hash algorithm to use to verify the handshake, we just buffer the messages.
 /// During the handshake, we may restart the transcript due to a HelloRetryRequest, reverting
 /// from the `HandshakeHash` to a `HandshakeHashBuffer` again.
+///
+/// Cloning this copies the buffered messages.
+#[derive(Clone)]
 pub(crate) struct HandshakeHashBuffer {
     buffer: Vec<u8>,
     client_auth_enabled: bool,
@@ -22,6 +26,15 @@ impl HandshakeHashBuffer {
         }
     }
 
+    /// Start again for the second ClientHello of a stateless retry,
+    /// where `client_hello_hash` is the hash of the first ClientHello.
+    pub(crate) fn for_stateless_retry(client_hello_hash: &[u8]) -> Self {
+        Self {
+            buffer: HandshakeMessagePayload::build_handshake_hash(client_hello_hash).get_encoding(),
+            client_auth_enabled: false,
+        }
+    }
+
     /// We might be doing client auth, so need to keep a full
     /// log of the handshake.
     pub(crate) fn set_client_auth_enabled(&mut self) {
@@ -45,20 +58,26 @@ impl HandshakeHashBuffer {
     /// Get the hash value if we were to hash `extra` too.
     pub(crate) fn get_hash_given(
         &self,
-        hash: &'static digest::Algorithm,
+        provider: &dyn CryptoProvider,
+        hash: HashAlgorithm,
         extra: &[u8],
-    ) -> digest::Digest {
-        let mut ctx = digest::Context::new(hash);
+    ) -> Output {
+        let mut ctx = provider.start_hash(hash);
         ctx.update(&self.buffer);
         ctx.update(extra);
         ctx.finish()
     }
 
     /// We now know what hash function the verify_data will use.
-    pub(crate) fn start_hash(self, alg: &'static digest::Algorithm) -> HandshakeHash {
-        let mut ctx = digest::Context::new(alg);
+    pub(crate) fn start_hash(
+        self,
+        provider: Arc<dyn CryptoProvider>,
+        alg: HashAlgorithm,
+    ) -> HandshakeHash {
+        let mut ctx = provider.start_hash(alg);
         ctx.update(&self.buffer);
         HandshakeHash {
+            provider,
             ctx,
             client_auth: match self.client_auth_enabled {
                 true => Some(self.buffer),
@@ -76,13 +95,28 @@ impl HandshakeHashBuffer {
 /// For client auth, we also need to buffer all the messages.
 /// This is disabled in cases where client auth is not possible.
 pub(crate) struct HandshakeHash {
+    provider: Arc<dyn CryptoProvider>,
+
     /// None before we know what hash function we're using
-    ctx: digest::Context,
+    ctx: Box<dyn HashContext>,
 
     /// buffer for client-auth.
     client_auth: Option<Vec<u8>>,
 }
 
+/// Cloning forks the running hash, so the copies can be advanced
+/// independently.  If client auth is enabled, this also duplicates
+/// the buffer of all handshake messages so far, which may be large.
+impl Clone for HandshakeHash {
+    fn clone(&self) -> Self {
+        Self {
+            provider: Arc::clone(&self.provider),
+            ctx: self.ctx.fork(),
+            client_auth: self.client_auth.clone(),
+        }
+    }
+}
+
 impl HandshakeHash {
     /// We decided not to do client auth after all, so discard
     /// the transcript.
@@ -112,8 +146,8 @@ impl HandshakeHash {
 
     /// Get the hash value if we were to hash `extra` too,
     /// using hash function `hash`.
-    pub(crate) fn get_hash_given(&self, extra: &[u8]) -> digest::Digest {
-        let mut ctx = self.ctx.clone();
+    pub(crate) fn get_hash_given(&self, extra: &[u8]) -> Output {
+        let mut ctx = self.ctx.fork();
         ctx.update(extra);
         ctx.finish()
     }
@@ -135,7 +169,11 @@ impl HandshakeHash {
     pub(crate) fn rollup_for_hrr(&mut self) {
         let ctx = &mut self.ctx;
 
-        let old_ctx = mem::replace(ctx, digest::Context::new(ctx.algorithm()));
+        let old_ctx = mem::replace(
+            ctx,
+            self.provider
+                .start_hash(ctx.algorithm()),
+        );
         let old_hash = old_ctx.finish();
         let old_handshake_hash_msg =
             HandshakeMessagePayload::build_handshake_hash(old_hash.as_ref());
@@ -144,8 +182,8 @@ impl HandshakeHash {
     }
 
     /// Get the current hash value.
-    pub(crate) fn get_current_hash(&self) -> digest::Digest {
-        self.ctx.clone().finish()
+    pub(crate) fn get_current_hash(&self) -> Output {
+        self.ctx.fork().finish()
     }
 
     /// Takes this object's buffer containing all handshake messages
@@ -156,8 +194,15 @@ impl HandshakeHash {
         self.client_auth.take()
     }
 
+    /// How much memory the client auth buffer holds, in bytes.
+    pub(crate) fn buffer_memory_usage(&self) -> usize {
+        self.client_auth
+            .as_ref()
+            .map_or(0, Vec::capacity)
+    }
+
     /// The digest algorithm
-    pub(crate) fn algorithm(&self) -> &'static digest::Algorithm {
+    pub(crate) fn algorithm(&self) -> HashAlgorithm {
         self.ctx.algorithm()
     }
 }
@@ -165,14 +210,16 @@ impl HandshakeHash {
 #[cfg(test)]
 mod test {
     use super::HandshakeHashBuffer;
-    use ring::digest;
+    use crate::crypto::ring::Ring;
+    use crate::crypto::HashAlgorithm;
+    use std::sync::Arc;
 
     #[test]
     fn hashes_correctly() {
         let mut hhb = HandshakeHashBuffer::new();
         hhb.update_raw(b"hello");
         assert_eq!(hhb.buffer.len(), 5);
-        let mut hh = hhb.start_hash(&digest::SHA256);
+        let mut hh = hhb.start_hash(Arc::new(Ring), HashAlgorithm::Sha256);
         assert!(hh.client_auth.is_none());
         hh.update_raw(b"world");
         let h = hh.get_current_hash();
@@ -190,7 +237,7 @@ mod test {
         hhb.set_client_auth_enabled();
         hhb.update_raw(b"hello");
         assert_eq!(hhb.buffer.len(), 5);
-        let mut hh = hhb.start_hash(&digest::SHA256);
+        let mut hh = hhb.start_hash(Arc::new(Ring), HashAlgorithm::Sha256);
         assert_eq!(
             hh.client_auth
                 .as_ref()
@@ -214,13 +261,49 @@ mod test {
         assert_eq!(Some(b"helloworld".to_vec()), buf);
     }
 
+    #[test]
+    fn clones_are_independent() {
+        let mut hhb = HandshakeHashBuffer::new();
+        hhb.set_client_auth_enabled();
+        hhb.update_raw(b"hello");
+        let buffer_copy = hhb.clone();
+        hhb.update_raw(b"world");
+        assert_eq!(buffer_copy.buffer, b"hello");
+        assert_eq!(hhb.buffer, b"helloworld");
+
+        let mut hh = buffer_copy.start_hash(Arc::new(Ring), HashAlgorithm::Sha256);
+        let copy = hh.clone();
+        hh.update_raw(b"world");
+        assert_eq!(
+            hh.get_current_hash().as_ref(),
+            hhb.start_hash(Arc::new(Ring), HashAlgorithm::Sha256)
+                .get_current_hash()
+                .as_ref()
+        );
+        assert_eq!(copy.client_auth.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(
+            copy.get_current_hash().as_ref(),
+            HandshakeHashBuffer {
+                buffer: b"hello".to_vec(),
+                client_auth_enabled: false,
+            }
+            .start_hash(Arc::new(Ring), HashAlgorithm::Sha256)
+            .get_current_hash()
+            .as_ref()
+        );
+        assert_ne!(
+            hh.get_current_hash().as_ref(),
+            copy.get_current_hash().as_ref()
+        );
+    }
+
     #[test]
     fn abandon() {
         let mut hhb = HandshakeHashBuffer::new();
         hhb.set_client_auth_enabled();
         hhb.update_raw(b"hello");
         assert_eq!(hhb.buffer.len(), 5);
-        let mut hh = hhb.start_hash(&digest::SHA256);
+        let mut hh = hhb.start_hash(Arc::new(Ring), HashAlgorithm::Sha256);
         assert_eq!(
             hh.client_auth
                 .as_ref()
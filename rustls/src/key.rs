@@ -1,3 +1,6 @@
+#[cfg(feature = "encrypted_pkcs8")]
+use crate::error::Error;
+
 use std::fmt;
 
 /// This type contains a private key by value.
@@ -10,6 +13,21 @@ use std::fmt;
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct PrivateKey(pub Vec<u8>);
 
+impl PrivateKey {
+    /// Decrypt a DER-encoded PKCS#8 `EncryptedPrivateKeyInfo`, as
+    /// produced by `openssl pkcs8 -topk8 -v2 ...`, using `passphrase`.
+    ///
+    /// Only PBES2 encryption is supported, with a PBKDF2 key derivation
+    /// (using HMAC with SHA-1, SHA-256, SHA-384 or SHA-512) and either
+    /// AES-CBC or AES-GCM.  A wrong passphrase, a malformed key or an
+    /// unsupported scheme is reported as [`Error::InvalidPrivateKey`].
+    #[cfg(feature = "encrypted_pkcs8")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "encrypted_pkcs8")))]
+    pub fn from_encrypted_pkcs8(der: &[u8], passphrase: &[u8]) -> Result<Self, Error> {
+        crate::pkcs8::decrypt(der, passphrase).map(Self)
+    }
+}
+
 /// This type contains a single certificate by value.
 ///
 /// The certificate must be DER-encoded X.509.
@@ -9,7 +9,8 @@
 /// implementation to make this useful.
 ///
 /// See [`KeyLogFile`](crate::KeyLogFile) that implements the standard
-/// `SSLKEYLOGFILE` environment variable behaviour.
+/// `SSLKEYLOGFILE` environment variable behaviour, and
+/// [`CallbackKeyLog`] for passing secrets to a closure.
 pub trait KeyLog: Send + Sync {
     /// Log the given `secret`.  `client_random` is provided for
     /// session identification.  `label` describes precisely what
@@ -53,3 +54,79 @@ impl KeyLog for NoKeyLog {
         false
     }
 }
+
+/// [`KeyLog`] implementation that calls a closure with each secret.
+///
+/// Each config has its own `key_log`, so this can route the secrets
+/// of different configs to different places.  Use
+/// [`nss_key_log_line`] to format them for Wireshark.
+///
+/// ```
+/// # use std::sync::Arc;
+/// # fn f(config: &mut rustls::ServerConfig) {
+/// config.key_log = Arc::new(rustls::CallbackKeyLog::new(
+///     |label: &str, client_random: &[u8], secret: &[u8]| {
+///         eprint!("{}", rustls::nss_key_log_line(label, client_random, secret));
+///     },
+/// ));
+/// # }
+/// ```
+pub struct CallbackKeyLog<F>(F);
+
+impl<F> CallbackKeyLog<F>
+where
+    F: Fn(&str, &[u8], &[u8]) + Send + Sync,
+{
+    /// Make a `CallbackKeyLog` which calls `f` with each secret's
+    /// label, client random and value, as for [`KeyLog::log`].
+    pub fn new(f: F) -> Self {
+        Self(f)
+    }
+}
+
+impl<F> KeyLog for CallbackKeyLog<F>
+where
+    F: Fn(&str, &[u8], &[u8]) + Send + Sync,
+{
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        (self.0)(label, client_random, secret)
+    }
+}
+
+/// Format a secret as a line of the NSS key log format, as read by
+/// Wireshark: the label, then the client random and secret in
+/// lowercase hex, separated by spaces and ending with a newline.
+///
+/// This is what [`KeyLogFile`](crate::KeyLogFile) writes.
+pub fn nss_key_log_line(label: &str, client_random: &[u8], secret: &[u8]) -> String {
+    let mut line =
+        String::with_capacity(label.len() + 2 * (client_random.len() + secret.len()) + 3);
+    line.push_str(label);
+    line.push(' ');
+    push_hex(&mut line, client_random);
+    line.push(' ');
+    push_hex(&mut line, secret);
+    line.push('\n');
+    line
+}
+
+fn push_hex(out: &mut String, bytes: &[u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for b in bytes {
+        out.push(char::from(DIGITS[usize::from(b >> 4)]));
+        out.push(char::from(DIGITS[usize::from(b & 0x0f)]));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nss_key_log_line() {
+        assert_eq!(
+            nss_key_log_line("CLIENT_RANDOM", &[0x00, 0x1f], &[0xab, 0xcd, 0xef]),
+            "CLIENT_RANDOM 001f abcdef\n"
+        );
+    }
+}
@@ -1,6 +1,6 @@
 #[cfg(feature = "logging")]
 use crate::log::warn;
-use crate::KeyLog;
+use crate::{nss_key_log_line, KeyLog};
 use std::env;
 use std::fs::{File, OpenOptions};
 use std::io;
@@ -11,7 +11,6 @@ use std::sync::Mutex;
 // Internal mutable state for KeyLogFile
 struct KeyLogFileInner {
     file: Option<File>,
-    buf: Vec<u8>,
 }
 
 impl KeyLogFileInner {
@@ -20,10 +19,7 @@ impl KeyLogFileInner {
             Ok(ref s) => Path::new(s),
             Err(env::VarError::NotUnicode(ref s)) => Path::new(s),
             Err(env::VarError::NotPresent) => {
-                return Self {
-                    file: None,
-                    buf: Vec::new(),
-                };
+                return Self { file: None };
             }
         };
 
@@ -40,10 +36,7 @@ impl KeyLogFileInner {
             }
         };
 
-        Self {
-            file,
-            buf: Vec::new(),
-        }
+        Self { file }
     }
 
     fn try_write(&mut self, label: &str, client_random: &[u8], secret: &[u8]) -> io::Result<()> {
@@ -54,17 +47,7 @@ impl KeyLogFileInner {
             Some(ref f) => f,
         };
 
-        self.buf.truncate(0);
-        write!(self.buf, "{} ", label)?;
-        for b in client_random.iter() {
-            write!(self.buf, "{:02x}", b)?;
-        }
-        write!(self.buf, " ")?;
-        for b in secret.iter() {
-            write!(self.buf, "{:02x}", b)?;
-        }
-        writeln!(self.buf)?;
-        file.write_all(&self.buf)
+        file.write_all(nss_key_log_line(label, client_random, secret).as_bytes())
     }
 }
 
@@ -1,14 +1,40 @@
 use crate::error::Error;
+#[cfg(feature = "kyber")]
+use crate::kyber;
 use crate::msgs::enums::NamedGroup;
+use crate::rand::SecureRandom;
+#[cfg(feature = "kyber")]
+use crate::zeroize::Zeroizing;
+
+#[cfg(feature = "kyber")]
+use std::convert::TryFrom;
 
 /// An in-progress key exchange.  This has the algorithm,
 /// our private key, and our public key.
+///
+/// For hybrid groups, `pubkey` is our X25519 public key followed by our
+/// Kyber768 public key or ciphertext.
 pub(crate) struct KeyExchange {
     skxg: &'static SupportedKxGroup,
     privkey: ring::agreement::EphemeralPrivateKey,
-    pub(crate) pubkey: ring::agreement::PublicKey,
+    pub(crate) pubkey: Vec<u8>,
+    #[cfg(feature = "kyber")]
+    kem: Option<KemState>,
 }
 
+/// The post-quantum half of a hybrid key exchange.
+#[cfg(feature = "kyber")]
+enum KemState {
+    /// We sent a public key, and will decapsulate the peer's ciphertext.
+    Decapsulate(kyber::SecretKey),
+    /// We already encapsulated a shared secret to the peer's public key.
+    Encapsulated(Zeroizing<[u8; kyber::SHARED_SECRET_LEN]>),
+}
+
+/// The length of an X25519 public key.
+#[cfg(feature = "kyber")]
+const X25519_LEN: usize = 32;
+
 impl KeyExchange {
     /// Choose a SupportedKxGroup by name, from a list of supported groups.
     pub(crate) fn choose(
@@ -24,7 +50,43 @@ impl KeyExchange {
     /// Start a key exchange, using the given SupportedKxGroup.
     ///
     /// This generates an ephemeral key pair and stores it in the returned KeyExchange object.
-    pub(crate) fn start(skxg: &'static SupportedKxGroup) -> Option<Self> {
+    /// `rng` is used for the Kyber768 key pair of hybrid groups; *ring*
+    /// generates the classical key pair with its own RNG.
+    #[cfg_attr(not(feature = "kyber"), allow(unused_variables))]
+    pub(crate) fn start(skxg: &'static SupportedKxGroup, rng: &dyn SecureRandom) -> Option<Self> {
+        let kx = Self::start_classical(skxg)?;
+
+        #[cfg(feature = "kyber")]
+        if skxg.hybrid_kyber768 {
+            return kx.with_kem_key_pair(rng);
+        }
+
+        Some(kx)
+    }
+
+    /// Start a key exchange in reply to the peer's share, `peer`, as a
+    /// TLS1.3 server does.
+    ///
+    /// This is the same as `start`, except for hybrid groups: then the
+    /// KEM shared secret is encapsulated to the peer's public key now, so
+    /// that our share can be sent before calling `complete`.
+    #[cfg_attr(not(feature = "kyber"), allow(unused_variables))]
+    pub(crate) fn start_in_reply(
+        skxg: &'static SupportedKxGroup,
+        peer: &[u8],
+        rng: &dyn SecureRandom,
+    ) -> Result<Self, Error> {
+        let kx = Self::start_classical(skxg).ok_or(Error::FailedToGetRandomBytes)?;
+
+        #[cfg(feature = "kyber")]
+        if skxg.hybrid_kyber768 {
+            return kx.with_kem_encapsulated(peer, rng);
+        }
+
+        Ok(kx)
+    }
+
+    fn start_classical(skxg: &'static SupportedKxGroup) -> Option<Self> {
         let rng = ring::rand::SystemRandom::new();
         let ours =
             ring::agreement::EphemeralPrivateKey::generate(skxg.agreement_algorithm, &rng).ok()?;
@@ -34,10 +96,35 @@ impl KeyExchange {
         Some(Self {
             skxg,
             privkey: ours,
-            pubkey,
+            pubkey: pubkey.as_ref().to_vec(),
+            #[cfg(feature = "kyber")]
+            kem: None,
         })
     }
 
+    #[cfg(feature = "kyber")]
+    fn with_kem_key_pair(mut self, rng: &dyn SecureRandom) -> Option<Self> {
+        let (kem_pubkey, secret_key) = kyber::generate_key_pair(rng).ok()?;
+        self.pubkey
+            .extend_from_slice(&kem_pubkey);
+        self.kem = Some(KemState::Decapsulate(secret_key));
+        Some(self)
+    }
+
+    #[cfg(feature = "kyber")]
+    fn with_kem_encapsulated(mut self, peer: &[u8], rng: &dyn SecureRandom) -> Result<Self, Error> {
+        let kem_peer = peer
+            .get(X25519_LEN..)
+            .and_then(|kem_peer| <&[u8; kyber::PUBLIC_KEY_LEN]>::try_from(kem_peer).ok())
+            .ok_or_else(agreement_failed)?;
+        let (ciphertext, shared_secret) = kyber::encapsulate(kem_peer, rng)?;
+
+        self.pubkey
+            .extend_from_slice(&ciphertext);
+        self.kem = Some(KemState::Encapsulated(Zeroizing::new(shared_secret)));
+        Ok(self)
+    }
+
     /// Return the group being used.
     pub(crate) fn group(&self) -> NamedGroup {
         self.skxg.name
@@ -47,17 +134,52 @@ impl KeyExchange {
     ///
     /// The shared secret is passed into the closure passed down in `f`, and the result of calling
     /// `f` is returned to the caller.
+    ///
+    /// For hybrid groups, the shared secret is the X25519 shared secret
+    /// followed by the Kyber768 shared secret.
     pub(crate) fn complete<T>(
         self,
         peer: &[u8],
         f: impl FnOnce(&[u8]) -> Result<T, ()>,
     ) -> Result<T, Error> {
-        let peer_key = ring::agreement::UnparsedPublicKey::new(self.skxg.agreement_algorithm, peer);
-        ring::agreement::agree_ephemeral(self.privkey, &peer_key, (), f)
-            .map_err(|()| Error::PeerMisbehavedError("key agreement failed".to_string()))
+        #[cfg(feature = "kyber")]
+        if let Some(kem) = self.kem {
+            if peer.len() < X25519_LEN {
+                return Err(agreement_failed());
+            }
+            let (peer, kem_peer) = peer.split_at(X25519_LEN);
+            let kem_secret = match kem {
+                KemState::Decapsulate(secret_key) => {
+                    let ciphertext = <&[u8; kyber::CIPHERTEXT_LEN]>::try_from(kem_peer)
+                        .map_err(|_| agreement_failed())?;
+                    Zeroizing::new(kyber::decapsulate(&secret_key, ciphertext))
+                }
+                KemState::Encapsulated(shared_secret) => shared_secret,
+            };
+
+            return agree(self.skxg, self.privkey, peer, |secret| {
+                f(&Zeroizing::new([secret, &kem_secret[..]].concat()))
+            });
+        }
+
+        agree(self.skxg, self.privkey, peer, f)
     }
 }
 
+fn agree<T>(
+    skxg: &'static SupportedKxGroup,
+    privkey: ring::agreement::EphemeralPrivateKey,
+    peer: &[u8],
+    f: impl FnOnce(&[u8]) -> Result<T, ()>,
+) -> Result<T, Error> {
+    let peer_key = ring::agreement::UnparsedPublicKey::new(skxg.agreement_algorithm, peer);
+    ring::agreement::agree_ephemeral(privkey, &peer_key, (), f).map_err(|()| agreement_failed())
+}
+
+fn agreement_failed() -> Error {
+    Error::PeerMisbehavedError("key agreement failed".to_string())
+}
+
 /// A key-exchange group supported by rustls.
 ///
 /// All possible instances of this class are provided by the library in
@@ -69,24 +191,61 @@ pub struct SupportedKxGroup {
 
     /// The corresponding ring agreement::Algorithm
     agreement_algorithm: &'static ring::agreement::Algorithm,
+
+    /// Whether Kyber768 is combined with the agreement, making
+    /// this a hybrid group.
+    #[cfg(feature = "kyber")]
+    hybrid_kyber768: bool,
+}
+
+impl SupportedKxGroup {
+    /// Can this group be used in TLS1.2?  Hybrid groups are only
+    /// defined for TLS1.3.
+    pub(crate) fn usable_for_tls12(&self) -> bool {
+        #[cfg(feature = "kyber")]
+        if self.hybrid_kyber768 {
+            return false;
+        }
+
+        true
+    }
 }
 
 /// Ephemeral ECDH on curve25519 (see RFC7748)
 pub static X25519: SupportedKxGroup = SupportedKxGroup {
     name: NamedGroup::X25519,
     agreement_algorithm: &ring::agreement::X25519,
+    #[cfg(feature = "kyber")]
+    hybrid_kyber768: false,
 };
 
 /// Ephemeral ECDH on secp256r1 (aka NIST-P256)
 pub static SECP256R1: SupportedKxGroup = SupportedKxGroup {
     name: NamedGroup::secp256r1,
     agreement_algorithm: &ring::agreement::ECDH_P256,
+    #[cfg(feature = "kyber")]
+    hybrid_kyber768: false,
 };
 
 /// Ephemeral ECDH on secp384r1 (aka NIST-P384)
 pub static SECP384R1: SupportedKxGroup = SupportedKxGroup {
     name: NamedGroup::secp384r1,
     agreement_algorithm: &ring::agreement::ECDH_P384,
+    #[cfg(feature = "kyber")]
+    hybrid_kyber768: false,
+};
+
+/// Hybrid post-quantum key exchange, combining ephemeral ECDH on
+/// curve25519 with Kyber768 (see draft-tls-westerbaan-xyber768d00).
+///
+/// This is only used in TLS1.3, and is not in [`ALL_KX_GROUPS`]: ask
+/// for it explicitly, preferably followed by [`X25519`] for peers
+/// that do not support it.
+#[cfg(feature = "kyber")]
+pub static X25519_KYBER768_DRAFT00: SupportedKxGroup = SupportedKxGroup {
+    name: NamedGroup::X25519Kyber768Draft00,
+    agreement_algorithm: &ring::agreement::X25519,
+    hybrid_kyber768: true,
 };
 
 /// A list of all the key exchange groups supported by rustls.
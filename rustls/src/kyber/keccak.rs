@@ -0,0 +1,330 @@
+//! The Keccak-f[1600] permutation, and the SHA-3 hash functions and
+//! extendable-output functions built on it (FIPS 202).
+//!
+//! Only what Kyber needs is provided.
+
+const ROUND_CONSTANTS: [u64; 24] = [
+    0x0000_0000_0000_0001,
+    0x0000_0000_0000_8082,
+    0x8000_0000_0000_808a,
+    0x8000_0000_8000_8000,
+    0x0000_0000_0000_808b,
+    0x0000_0000_8000_0001,
+    0x8000_0000_8000_8081,
+    0x8000_0000_0000_8009,
+    0x0000_0000_0000_008a,
+    0x0000_0000_0000_0088,
+    0x0000_0000_8000_8009,
+    0x0000_0000_8000_000a,
+    0x0000_0000_8000_808b,
+    0x8000_0000_0000_008b,
+    0x8000_0000_0000_8089,
+    0x8000_0000_0000_8003,
+    0x8000_0000_0000_8002,
+    0x8000_0000_0000_0080,
+    0x0000_0000_0000_800a,
+    0x8000_0000_8000_000a,
+    0x8000_0000_8000_8081,
+    0x8000_0000_0000_8080,
+    0x0000_0000_8000_0001,
+    0x8000_0000_8000_8008,
+];
+
+// The rho rotation of each lane, in the order the pi step visits them.
+const RHO: [u32; 24] = [
+    1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44,
+];
+const PI: [usize; 24] = [
+    10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1,
+];
+
+fn keccak_f1600(a: &mut [u64; 25]) {
+    for rc in ROUND_CONSTANTS.iter() {
+        // theta
+        let mut c = [0u64; 5];
+        for x in 0..5 {
+            c[x] = a[x] ^ a[x + 5] ^ a[x + 10] ^ a[x + 15] ^ a[x + 20];
+        }
+        for x in 0..5 {
+            let d = c[(x + 4) % 5] ^ c[(x + 1) % 5].rotate_left(1);
+            for y in 0..5 {
+                a[5 * y + x] ^= d;
+            }
+        }
+
+        // rho and pi
+        let mut last = a[1];
+        for (rho, pi) in RHO.iter().zip(PI.iter()) {
+            let next = a[*pi];
+            a[*pi] = last.rotate_left(*rho);
+            last = next;
+        }
+
+        // chi
+        for y in 0..5 {
+            let mut row = [0u64; 5];
+            row.copy_from_slice(&a[5 * y..5 * y + 5]);
+            for x in 0..5 {
+                a[5 * y + x] = row[x] ^ (!row[(x + 1) % 5] & row[(x + 2) % 5]);
+            }
+        }
+
+        // iota
+        a[0] ^= rc;
+    }
+}
+
+/// A Keccak sponge, absorbing input and then squeezing output.
+#[derive(Clone)]
+pub(super) struct Sponge {
+    state: [u64; 25],
+    rate: usize,
+    pos: usize,
+}
+
+impl Sponge {
+    fn new(rate: usize) -> Self {
+        Self {
+            state: [0; 25],
+            rate,
+            pos: 0,
+        }
+    }
+
+    fn xor_byte(&mut self, i: usize, byte: u8) {
+        self.state[i / 8] ^= u64::from(byte) << (8 * (i % 8));
+    }
+
+    fn absorb(&mut self, input: &[u8]) {
+        for byte in input {
+            self.xor_byte(self.pos, *byte);
+            self.pos += 1;
+            if self.pos == self.rate {
+                keccak_f1600(&mut self.state);
+                self.pos = 0;
+            }
+        }
+    }
+
+    /// Pad the input with `domain` and the final bit, and get ready
+    /// to squeeze.
+    fn finish(&mut self, domain: u8) {
+        self.xor_byte(self.pos, domain);
+        self.xor_byte(self.rate - 1, 0x80);
+        keccak_f1600(&mut self.state);
+        self.pos = 0;
+    }
+
+    /// Fill `out` with the next bytes of output.
+    pub(super) fn squeeze(&mut self, out: &mut [u8]) {
+        for byte in out {
+            if self.pos == self.rate {
+                keccak_f1600(&mut self.state);
+                self.pos = 0;
+            }
+            *byte = (self.state[self.pos / 8] >> (8 * (self.pos % 8))) as u8;
+            self.pos += 1;
+        }
+    }
+}
+
+const SHA3_DOMAIN: u8 = 0x06;
+const SHAKE_DOMAIN: u8 = 0x1f;
+
+fn sponge_for(rate: usize, domain: u8, input: &[&[u8]]) -> Sponge {
+    let mut sponge = Sponge::new(rate);
+    for part in input {
+        sponge.absorb(part);
+    }
+    sponge.finish(domain);
+    sponge
+}
+
+/// SHA3-256 of the concatenation of `input`.
+pub(super) fn sha3_256(input: &[&[u8]]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    sponge_for(136, SHA3_DOMAIN, input).squeeze(&mut out);
+    out
+}
+
+/// SHA3-512 of the concatenation of `input`.
+pub(super) fn sha3_512(input: &[&[u8]]) -> [u8; 64] {
+    let mut out = [0u8; 64];
+    sponge_for(72, SHA3_DOMAIN, input).squeeze(&mut out);
+    out
+}
+
+/// SHAKE128 of the concatenation of `input`, ready to squeeze.
+pub(super) fn shake128(input: &[&[u8]]) -> Sponge {
+    sponge_for(SHAKE128_RATE, SHAKE_DOMAIN, input)
+}
+
+/// SHAKE256 of the concatenation of `input`, ready to squeeze.
+pub(super) fn shake256(input: &[&[u8]]) -> Sponge {
+    sponge_for(136, SHAKE_DOMAIN, input)
+}
+
+/// How many bytes SHAKE128 produces per permutation.
+pub(super) const SHAKE128_RATE: usize = 168;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn squeeze(mut sponge: Sponge, len: usize) -> Vec<u8> {
+        let mut out = vec![0u8; len];
+        sponge.squeeze(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(
+            sha3_256(&[]).to_vec(),
+            hex("a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a")
+        );
+        assert_eq!(
+            sha3_512(&[]).to_vec(),
+            hex(
+                "a69f73cca23a9ac5c8b567dc185a756e97c982164fe25859e0d1dcc1475c80a6\
+                 15b2123af1f5f94c11e3e9402c3ac558f500199d95b6d3e301758586281dcd26"
+            )
+        );
+        assert_eq!(
+            squeeze(shake128(&[]), 32),
+            hex("7f9c2ba4e88f827d616045507605853ed73b8093f6efbc88eb1a6eacfa66ef26")
+        );
+        assert_eq!(
+            squeeze(shake256(&[]), 32),
+            hex("46b9dd2b0ba88d13233b3feb743eeb243fcd52ea62b81b82b50c27646ed5762f")
+        );
+    }
+
+    #[test]
+    fn test_multiple_blocks() {
+        let input: Vec<u8> = (0..200).collect();
+        let (first, second) = input.split_at(150);
+
+        assert_eq!(
+            sha3_256(&[first, second]).to_vec(),
+            hex("5f728f63bf5ee48c77f453c0490398fa645b8d4c4e56be9a41cfec344d6ca899")
+        );
+        assert_eq!(
+            sha3_512(&[first, second]).to_vec(),
+            hex(
+                "ea5d05f19348dd589793354793a15f37a73b4c0bb4e750b9a00757dfce2f8b65\
+                 a64191bb9b137de00feef6474cfd47abf7880efbc51614a5715df12cfe0caee3"
+            )
+        );
+        assert_eq!(
+            sha3_256(&[&squeeze(shake128(&[first, second]), 500)]).to_vec(),
+            hex("1e64f95e765840276902f9ee722f0d05dd8dd93d86441b7e178957e3a1eaae9e")
+        );
+        assert_eq!(
+            sha3_256(&[&squeeze(shake256(&[first, second]), 500)]).to_vec(),
+            hex("7a4e601cd1812698cd55a0eae62839075a03959ce4d8415f0ea428906c967e3f")
+        );
+    }
+
+    /// Squeeze `len` bytes in two calls, which do not end on a block
+    /// boundary.
+    fn squeeze_in_parts(mut sponge: Sponge, len: usize) -> Vec<u8> {
+        let mut out = vec![0u8; len];
+        let (first, second) = out.split_at_mut(100);
+        sponge.squeeze(first);
+        sponge.squeeze(second);
+        out
+    }
+
+    /// The SHA-3 and SHAKE examples for FIPS 202, from
+    /// <https://csrc.nist.gov/projects/cryptographic-standards-and-guidelines/example-values>.
+    #[test]
+    fn test_fips202_abc() {
+        assert_eq!(
+            sha3_256(&[b"abc"]).to_vec(),
+            hex("3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532")
+        );
+        assert_eq!(
+            sha3_512(&[b"abc"]).to_vec(),
+            hex(
+                "b751850b1a57168a5693cd924b6b096e08f621827444f70d884f5d0240d2712e\
+                 10e116e9192af3c91a7ec57647e3934057340b4cf408d5a56592f8274eec53f0"
+            )
+        );
+        assert_eq!(
+            squeeze_in_parts(shake128(&[b"abc"]), 200),
+            hex(
+                "5881092dd818bf5cf8a3ddb793fbcba74097d5c526a6d35f97b83351940f2cc8\
+                 44c50af32acd3f2cdd066568706f509bc1bdde58295dae3f891a9a0fca578378\
+                 9a41f8611214ce612394df286a62d1a2252aa94db9c538956c717dc2bed4f232\
+                 a0294c857c730aa16067ac1062f1201fb0d377cfb9cde4c63599b27f3462bba4\
+                 a0ed296c801f9ff7f57302bb3076ee145f97a32ae68e76ab66c48d51675bd49a\
+                 cc29082f5647584e6aa01b3f5af057805f973ff8ecb8b226ac32ada6f01c1fcd\
+                 4818cb006aa5b4cd"
+            )
+        );
+        assert_eq!(
+            squeeze_in_parts(shake256(&[b"abc"]), 200),
+            hex(
+                "483366601360a8771c6863080cc4114d8db44530f8f1e1ee4f94ea37e78b5739\
+                 d5a15bef186a5386c75744c0527e1faa9f8726e462a12a4feb06bd8801e751e4\
+                 1385141204f329979fd3047a13c5657724ada64d2470157b3cdc288620944d78\
+                 dbcddbd912993f0913f164fb2ce95131a2d09a3e6d51cbfc622720d7a75c6334\
+                 e8a2d7ec71a7cc29cf0ea610eeff1a588290a53000faa79932becec0bd3cd0b3\
+                 3a7e5d397fed1ada9442b99903f4dcfd8559ed3950faf40fe6f3b5d710ed3b67\
+                 7513771af6bfe119"
+            )
+        );
+    }
+
+    /// As above, for the 1600-bit message of repeated 0xa3 bytes,
+    /// absorbed in two parts.
+    #[test]
+    fn test_fips202_1600_bits() {
+        let input = [0xa3u8; 200];
+        let (first, second) = input.split_at(100);
+
+        assert_eq!(
+            sha3_256(&[first, second]).to_vec(),
+            hex("79f38adec5c20307a98ef76e8324afbfd46cfd81b22e3973c65fa1bd9de31787")
+        );
+        assert_eq!(
+            sha3_512(&[first, second]).to_vec(),
+            hex(
+                "e76dfad22084a8b1467fcf2ffa58361bec7628edf5f3fdc0e4805dc48caeeca8\
+                 1b7c13c30adf52a3659584739a2df46be589c51ca1a4a8416df6545a1ce8ba00"
+            )
+        );
+        assert_eq!(
+            squeeze_in_parts(shake128(&[first, second]), 200),
+            hex(
+                "131ab8d2b594946b9c81333f9bb6e0ce75c3b93104fa3469d3917457385da037\
+                 cf232ef7164a6d1eb448c8908186ad852d3f85a5cf28da1ab6fe343817197846\
+                 7f1c05d58c7ef38c284c41f6c2221a76f12ab1c04082660250802294fb871802\
+                 13fdef5b0ecb7df50ca1f8555be14d32e10f6edcde892c09424b29f597afc270\
+                 c904556bfcb47a7d40778d390923642b3cbd0579e60908d5a000c1d08b98ef93\
+                 3f806445bf87f8b009ba9e94f7266122ed7ac24e5e266c42a82fa1bbefb7b8db\
+                 0066e16a85e0493f"
+            )
+        );
+        assert_eq!(
+            squeeze_in_parts(shake256(&[first, second]), 200),
+            hex(
+                "cd8a920ed141aa0407a22d59288652e9d9f1a7ee0c1e7c1ca699424da84a904d\
+                 2d700caae7396ece96604440577da4f3aa22aeb8857f961c4cd8e06f0ae6610b\
+                 1048a7f64e1074cd629e85ad7566048efc4fb500b486a3309a8f26724c0ed628\
+                 001a1099422468de726f1061d99eb9e93604d5aa7467d4b1bd6484582a384317\
+                 d7f47d750b8f5499512bb85a226c4243556e696f6bd072c5aa2d9b69730244b5\
+                 6853d16970ad817e213e470618178001c9fb56c54fefa5fee67d2da524bb3b0b\
+                 61ef0e9114a92cdb"
+            )
+        );
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
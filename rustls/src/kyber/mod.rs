@@ -0,0 +1,666 @@
+//! Kyber768, as submitted to round 3 of the NIST post-quantum
+//! standardisation process (draft-cfrg-schwabe-kyber-00).
+//!
+//! This is the KEM used by the X25519Kyber768Draft00 hybrid key exchange
+//! (draft-tls-westerbaan-xyber768d00).  It follows the reference
+//! implementation, and does not branch on or index by secret data.
+
+mod keccak;
+
+use crate::error::Error;
+use crate::rand::SecureRandom;
+use crate::zeroize::{zeroize, Zeroizing};
+
+const N: usize = 256;
+const Q: i16 = 3329;
+const K: usize = 3;
+
+const SYMBYTES: usize = 32;
+const POLYBYTES: usize = 384;
+const POLYVECBYTES: usize = K * POLYBYTES;
+const POLYCOMPRESSEDBYTES: usize = 128;
+const POLYVECCOMPRESSEDBYTES: usize = K * 320;
+
+/// The length of an encoded public key.
+pub(crate) const PUBLIC_KEY_LEN: usize = POLYVECBYTES + SYMBYTES;
+
+/// The length of an encoded ciphertext.
+pub(crate) const CIPHERTEXT_LEN: usize = POLYVECCOMPRESSEDBYTES + POLYCOMPRESSEDBYTES;
+
+/// The length of a shared secret.
+pub(crate) const SHARED_SECRET_LEN: usize = 32;
+
+const SECRET_KEY_LEN: usize = POLYVECBYTES + PUBLIC_KEY_LEN + 2 * SYMBYTES;
+
+/// A Kyber768 secret key.
+///
+/// This is the IND-CPA secret key, the public key, the hash of the
+/// public key and the implicit rejection value, as in the reference
+/// implementation.
+pub(crate) struct SecretKey(Vec<u8>);
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+/// Generate a new key pair, returning the encoded public key and the
+/// secret key.
+pub(crate) fn generate_key_pair(rng: &dyn SecureRandom) -> Result<(Vec<u8>, SecretKey), Error> {
+    let mut seeds = Zeroizing::new([0u8; 2 * SYMBYTES]);
+    rng.fill(&mut seeds)?;
+    let (d, z) = seeds.split_at(SYMBYTES);
+    Ok(key_pair_from_seeds(d, z))
+}
+
+fn key_pair_from_seeds(d: &[u8], z: &[u8]) -> (Vec<u8>, SecretKey) {
+    let (public_key, indcpa_secret_key) = indcpa_key_pair(d);
+    let indcpa_secret_key = Zeroizing::new(indcpa_secret_key);
+
+    // Allocate this at its final size, so it's never copied.
+    let mut secret_key = Vec::with_capacity(SECRET_KEY_LEN);
+    secret_key.extend_from_slice(&indcpa_secret_key);
+    secret_key.extend_from_slice(&public_key);
+    secret_key.extend_from_slice(&keccak::sha3_256(&[&public_key]));
+    secret_key.extend_from_slice(z);
+    (public_key, SecretKey(secret_key))
+}
+
+/// Make a new shared secret for the holder of `public_key`, returning
+/// the encoded ciphertext and the shared secret.
+pub(crate) fn encapsulate(
+    public_key: &[u8; PUBLIC_KEY_LEN],
+    rng: &dyn SecureRandom,
+) -> Result<(Vec<u8>, [u8; SHARED_SECRET_LEN]), Error> {
+    let mut seed = Zeroizing::new([0u8; SYMBYTES]);
+    rng.fill(&mut seed)?;
+    Ok(encapsulate_with_seed(public_key, &seed))
+}
+
+fn encapsulate_with_seed(public_key: &[u8], seed: &[u8]) -> (Vec<u8>, [u8; SHARED_SECRET_LEN]) {
+    // Don't send the RNG output itself.
+    let m = Zeroizing::new(keccak::sha3_256(&[seed]));
+    let kr = Zeroizing::new(keccak::sha3_512(&[&m, &keccak::sha3_256(&[public_key])]));
+    let (k, coins) = kr.split_at(SYMBYTES);
+
+    let ciphertext = indcpa_encrypt(public_key, &m, coins);
+    let shared_secret = kdf(k, &ciphertext);
+    (ciphertext, shared_secret)
+}
+
+/// Recover the shared secret from `ciphertext`.
+///
+/// An invalid ciphertext gives an unpredictable shared secret, rather
+/// than an error ("implicit rejection").
+pub(crate) fn decapsulate(
+    secret_key: &SecretKey,
+    ciphertext: &[u8; CIPHERTEXT_LEN],
+) -> [u8; SHARED_SECRET_LEN] {
+    let (indcpa_secret_key, rest) = secret_key.0.split_at(POLYVECBYTES);
+    let (public_key, rest) = rest.split_at(PUBLIC_KEY_LEN);
+    let (public_key_hash, z) = rest.split_at(SYMBYTES);
+
+    let m = Zeroizing::new(indcpa_decrypt(indcpa_secret_key, ciphertext));
+    let mut kr = Zeroizing::new(keccak::sha3_512(&[&m, public_key_hash]));
+    let (k, coins) = kr.split_at_mut(SYMBYTES);
+    let expected = indcpa_encrypt(public_key, &m, coins);
+
+    // Replace k with z unless the ciphertext re-encrypts exactly.
+    let diff = ciphertext
+        .iter()
+        .zip(expected.iter())
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    let mask = (u16::from(diff).wrapping_neg() >> 8) as u8;
+    for (k, z) in k.iter_mut().zip(z) {
+        *k ^= mask & (*k ^ z);
+    }
+
+    kdf(k, ciphertext)
+}
+
+fn kdf(k: &[u8], ciphertext: &[u8]) -> [u8; SHARED_SECRET_LEN] {
+    let mut shared_secret = [0u8; SHARED_SECRET_LEN];
+    keccak::shake256(&[k, &keccak::sha3_256(&[ciphertext])]).squeeze(&mut shared_secret);
+    shared_secret
+}
+
+fn indcpa_key_pair(d: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let seeds = Zeroizing::new(keccak::sha3_512(&[d]));
+    let (public_seed, noise_seed) = seeds.split_at(SYMBYTES);
+
+    let a = gen_matrix(public_seed, false);
+    let mut s = [Poly::zero(); K];
+    let mut e = [Poly::zero(); K];
+    for (nonce, p) in s
+        .iter_mut()
+        .chain(e.iter_mut())
+        .enumerate()
+    {
+        *p = Poly::noise(noise_seed, nonce as u8);
+    }
+    polyvec_ntt(&mut s);
+    polyvec_ntt(&mut e);
+
+    let mut t = [Poly::zero(); K];
+    for (t, a) in t.iter_mut().zip(a.iter()) {
+        *t = polyvec_basemul_acc(a, &s);
+        t.convert_to_mont();
+    }
+    polyvec_add(&mut t, &e);
+    polyvec_reduce(&mut t);
+
+    let mut public_key = polyvec_to_bytes(&t);
+    public_key.extend_from_slice(public_seed);
+    (public_key, polyvec_to_bytes(&s))
+}
+
+fn indcpa_encrypt(public_key: &[u8], m: &[u8], coins: &[u8]) -> Vec<u8> {
+    let (t, public_seed) = public_key.split_at(POLYVECBYTES);
+    let t = polyvec_from_bytes(t);
+    let at = gen_matrix(public_seed, true);
+
+    let mut r = [Poly::zero(); K];
+    let mut e1 = [Poly::zero(); K];
+    for (nonce, p) in r
+        .iter_mut()
+        .chain(e1.iter_mut())
+        .enumerate()
+    {
+        *p = Poly::noise(coins, nonce as u8);
+    }
+    let e2 = Poly::noise(coins, 2 * K as u8);
+    polyvec_ntt(&mut r);
+
+    let mut u = [Poly::zero(); K];
+    for (u, at) in u.iter_mut().zip(at.iter()) {
+        *u = polyvec_basemul_acc(at, &r);
+    }
+    let mut v = polyvec_basemul_acc(&t, &r);
+
+    polyvec_invntt_to_mont(&mut u);
+    v.invntt_to_mont();
+
+    polyvec_add(&mut u, &e1);
+    v.add(&e2);
+    v.add(&Poly::from_msg(m));
+    polyvec_reduce(&mut u);
+    v.reduce();
+
+    let mut ciphertext = polyvec_compress(&u);
+    ciphertext.extend_from_slice(&v.compress());
+    ciphertext
+}
+
+fn indcpa_decrypt(secret_key: &[u8], ciphertext: &[u8]) -> [u8; SYMBYTES] {
+    let (u, v) = ciphertext.split_at(POLYVECCOMPRESSEDBYTES);
+    let mut u = polyvec_decompress(u);
+    let v = Poly::decompress(v);
+    let s = polyvec_from_bytes(secret_key);
+
+    polyvec_ntt(&mut u);
+    let mut w = polyvec_basemul_acc(&s, &u);
+    w.invntt_to_mont();
+    for (w, v) in w.0.iter_mut().zip(v.0.iter()) {
+        *w = v - *w;
+    }
+    w.reduce();
+    w.to_msg()
+}
+
+/// Generate the matrix A, or its transpose, from `seed`.
+///
+/// The entries are uniformly random, and taken to be in the NTT domain.
+fn gen_matrix(seed: &[u8], transposed: bool) -> [PolyVec; K] {
+    let mut a = [[Poly::zero(); K]; K];
+    for (i, row) in a.iter_mut().enumerate() {
+        for (j, p) in row.iter_mut().enumerate() {
+            *p = if transposed {
+                Poly::uniform(seed, i as u8, j as u8)
+            } else {
+                Poly::uniform(seed, j as u8, i as u8)
+            };
+        }
+    }
+    a
+}
+
+/// A polynomial in Z_q[X]/(X^256 + 1).
+#[derive(Clone, Copy)]
+struct Poly([i16; N]);
+
+type PolyVec = [Poly; K];
+
+impl Poly {
+    fn zero() -> Self {
+        Self([0; N])
+    }
+
+    /// Sample a polynomial with uniformly random coefficients, using
+    /// SHAKE128 on `seed` and the matrix position.
+    fn uniform(seed: &[u8], x: u8, y: u8) -> Self {
+        let mut xof = keccak::shake128(&[seed, &[x, y]]);
+        let mut buf = [0u8; keccak::SHAKE128_RATE];
+        let mut r = Self::zero();
+        let mut count = 0;
+
+        while count < N {
+            xof.squeeze(&mut buf);
+            for b in buf.chunks_exact(3) {
+                let vals = [
+                    (u16::from(b[0]) | u16::from(b[1]) << 8) & 0xfff,
+                    (u16::from(b[1]) >> 4 | u16::from(b[2]) << 4) & 0xfff,
+                ];
+                for val in vals.iter() {
+                    if count < N && *val < Q as u16 {
+                        r.0[count] = *val as i16;
+                        count += 1;
+                    }
+                }
+            }
+        }
+
+        r
+    }
+
+    /// Sample a polynomial with coefficients from the centred binomial
+    /// distribution with eta = 2, using SHAKE256 on `seed` and `nonce`.
+    fn noise(seed: &[u8], nonce: u8) -> Self {
+        let mut buf = [0u8; 2 * N / 4];
+        keccak::shake256(&[seed, &[nonce]]).squeeze(&mut buf);
+
+        let mut r = Self::zero();
+        for (coeffs, b) in
+            r.0.chunks_exact_mut(8)
+                .zip(buf.chunks_exact(4))
+        {
+            let t = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+            let d = (t & 0x5555_5555) + ((t >> 1) & 0x5555_5555);
+            for (j, c) in coeffs.iter_mut().enumerate() {
+                let a = ((d >> (4 * j)) & 0x3) as i16;
+                let b = ((d >> (4 * j + 2)) & 0x3) as i16;
+                *c = a - b;
+            }
+        }
+        r
+    }
+
+    fn from_msg(msg: &[u8]) -> Self {
+        let mut r = Self::zero();
+        for (coeffs, byte) in r.0.chunks_exact_mut(8).zip(msg) {
+            for (j, c) in coeffs.iter_mut().enumerate() {
+                let mask = -(((byte >> j) & 1) as i16);
+                *c = mask & ((Q + 1) / 2);
+            }
+        }
+        r
+    }
+
+    fn to_msg(self) -> [u8; SYMBYTES] {
+        let mut msg = [0u8; SYMBYTES];
+        for (byte, coeffs) in msg
+            .iter_mut()
+            .zip(self.0.chunks_exact(8))
+        {
+            for (j, c) in coeffs.iter().enumerate() {
+                *byte |= (compress(*c, 1) as u8) << j;
+            }
+        }
+        msg
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut r = Self::zero();
+        for (coeffs, b) in
+            r.0.chunks_exact_mut(2)
+                .zip(bytes.chunks_exact(3))
+        {
+            coeffs[0] = ((u16::from(b[0]) | u16::from(b[1]) << 8) & 0xfff) as i16;
+            coeffs[1] = ((u16::from(b[1]) >> 4 | u16::from(b[2]) << 4) & 0xfff) as i16;
+        }
+        r
+    }
+
+    fn to_bytes(self, out: &mut [u8]) {
+        for (b, coeffs) in out
+            .chunks_exact_mut(3)
+            .zip(self.0.chunks_exact(2))
+        {
+            let t0 = to_positive(coeffs[0]) as u16;
+            let t1 = to_positive(coeffs[1]) as u16;
+            b[0] = t0 as u8;
+            b[1] = (t0 >> 8 | t1 << 4) as u8;
+            b[2] = (t1 >> 4) as u8;
+        }
+    }
+
+    fn compress(self) -> [u8; POLYCOMPRESSEDBYTES] {
+        let mut out = [0u8; POLYCOMPRESSEDBYTES];
+        for (b, coeffs) in out
+            .iter_mut()
+            .zip(self.0.chunks_exact(2))
+        {
+            *b = (compress(coeffs[0], 4) | compress(coeffs[1], 4) << 4) as u8;
+        }
+        out
+    }
+
+    fn decompress(bytes: &[u8]) -> Self {
+        let mut r = Self::zero();
+        for (coeffs, b) in r.0.chunks_exact_mut(2).zip(bytes) {
+            coeffs[0] = decompress(u32::from(b & 0xf), 4);
+            coeffs[1] = decompress(u32::from(b >> 4), 4);
+        }
+        r
+    }
+
+    fn add(&mut self, other: &Self) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a += b;
+        }
+    }
+
+    fn reduce(&mut self) {
+        for c in self.0.iter_mut() {
+            *c = barrett_reduce(*c);
+        }
+    }
+
+    /// Multiply by the Montgomery factor 2^16.
+    fn convert_to_mont(&mut self) {
+        const F: i32 = ((1u64 << 32) % Q as u64) as i32;
+        for c in self.0.iter_mut() {
+            *c = montgomery_reduce(i32::from(*c) * F);
+        }
+    }
+
+    fn ntt(&mut self) {
+        let mut k = 1;
+        let mut len = 128;
+        while len >= 2 {
+            for start in (0..N).step_by(2 * len) {
+                let zeta = ZETAS[k];
+                k += 1;
+                for j in start..start + len {
+                    let t = fqmul(zeta, self.0[j + len]);
+                    self.0[j + len] = self.0[j] - t;
+                    self.0[j] += t;
+                }
+            }
+            len >>= 1;
+        }
+        self.reduce();
+    }
+
+    /// Inverse NTT, also multiplying by the Montgomery factor 2^16.
+    fn invntt_to_mont(&mut self) {
+        // mont^2 / 128
+        const F: i16 = 1441;
+
+        let mut k = 127;
+        let mut len = 2;
+        while len <= 128 {
+            for start in (0..N).step_by(2 * len) {
+                let zeta = ZETAS[k];
+                k -= 1;
+                for j in start..start + len {
+                    let t = self.0[j];
+                    self.0[j] = barrett_reduce(t + self.0[j + len]);
+                    self.0[j + len] = fqmul(zeta, self.0[j + len] - t);
+                }
+            }
+            len <<= 1;
+        }
+
+        for c in self.0.iter_mut() {
+            *c = fqmul(*c, F);
+        }
+    }
+
+    /// Multiply two polynomials in the NTT domain, adding the result to
+    /// `self`.  The result carries a factor of 2^-16.
+    fn basemul_acc(&mut self, a: &Self, b: &Self) {
+        let quads = self
+            .0
+            .chunks_exact_mut(4)
+            .zip(a.0.chunks_exact(4))
+            .zip(b.0.chunks_exact(4));
+        for (i, ((r, a), b)) in quads.enumerate() {
+            let zeta = ZETAS[64 + i];
+            basemul_acc(&mut r[..2], &a[..2], &b[..2], zeta);
+            basemul_acc(&mut r[2..], &a[2..], &b[2..], -zeta);
+        }
+    }
+}
+
+/// Multiply in Z_q[X]/(X^2 - zeta), adding the result to `r`.
+fn basemul_acc(r: &mut [i16], a: &[i16], b: &[i16], zeta: i16) {
+    r[0] += fqmul(fqmul(a[1], b[1]), zeta) + fqmul(a[0], b[0]);
+    r[1] += fqmul(a[0], b[1]) + fqmul(a[1], b[0]);
+}
+
+fn polyvec_basemul_acc(a: &PolyVec, b: &PolyVec) -> Poly {
+    let mut r = Poly::zero();
+    for (a, b) in a.iter().zip(b.iter()) {
+        r.basemul_acc(a, b);
+    }
+    r.reduce();
+    r
+}
+
+fn polyvec_ntt(v: &mut PolyVec) {
+    for p in v.iter_mut() {
+        p.ntt();
+    }
+}
+
+fn polyvec_invntt_to_mont(v: &mut PolyVec) {
+    for p in v.iter_mut() {
+        p.invntt_to_mont();
+    }
+}
+
+fn polyvec_add(v: &mut PolyVec, other: &PolyVec) {
+    for (a, b) in v.iter_mut().zip(other.iter()) {
+        a.add(b);
+    }
+}
+
+fn polyvec_reduce(v: &mut PolyVec) {
+    for p in v.iter_mut() {
+        p.reduce();
+    }
+}
+
+fn polyvec_to_bytes(v: &PolyVec) -> Vec<u8> {
+    let mut out = vec![0u8; POLYVECBYTES];
+    for (b, p) in out
+        .chunks_exact_mut(POLYBYTES)
+        .zip(v.iter())
+    {
+        p.to_bytes(b);
+    }
+    out
+}
+
+fn polyvec_from_bytes(bytes: &[u8]) -> PolyVec {
+    let mut v = [Poly::zero(); K];
+    for (p, b) in v
+        .iter_mut()
+        .zip(bytes.chunks_exact(POLYBYTES))
+    {
+        *p = Poly::from_bytes(b);
+    }
+    v
+}
+
+/// Compress each coefficient to 10 bits.
+fn polyvec_compress(v: &PolyVec) -> Vec<u8> {
+    let mut out = Vec::with_capacity(POLYVECCOMPRESSEDBYTES);
+    for p in v.iter() {
+        for coeffs in p.0.chunks_exact(4) {
+            let mut t = [0u32; 4];
+            for (t, c) in t.iter_mut().zip(coeffs) {
+                *t = compress(*c, 10);
+            }
+            out.extend_from_slice(&[
+                t[0] as u8,
+                (t[0] >> 8 | t[1] << 2) as u8,
+                (t[1] >> 6 | t[2] << 4) as u8,
+                (t[2] >> 4 | t[3] << 6) as u8,
+                (t[3] >> 2) as u8,
+            ]);
+        }
+    }
+    out
+}
+
+fn polyvec_decompress(bytes: &[u8]) -> PolyVec {
+    let mut v = [Poly::zero(); K];
+    for (p, bytes) in v
+        .iter_mut()
+        .zip(bytes.chunks_exact(POLYVECCOMPRESSEDBYTES / K))
+    {
+        for (coeffs, b) in
+            p.0.chunks_exact_mut(4)
+                .zip(bytes.chunks_exact(5))
+        {
+            let b = [
+                u32::from(b[0]),
+                u32::from(b[1]),
+                u32::from(b[2]),
+                u32::from(b[3]),
+                u32::from(b[4]),
+            ];
+            let t = [
+                b[0] | b[1] << 8,
+                b[1] >> 2 | b[2] << 6,
+                b[2] >> 4 | b[3] << 4,
+                b[3] >> 6 | b[4] << 2,
+            ];
+            for (c, t) in coeffs.iter_mut().zip(t.iter()) {
+                *c = decompress(t & 0x3ff, 10);
+            }
+        }
+    }
+    v
+}
+
+/// Map a coefficient in (-q, q) to [0, q).
+fn to_positive(c: i16) -> i16 {
+    c + ((c >> 15) & Q)
+}
+
+/// Round `c` * 2^`d` / q to a `d`-bit value.
+fn compress(c: i16, d: u32) -> u32 {
+    let c = to_positive(c) as u32;
+    (((c << d) + Q as u32 / 2) / Q as u32) & ((1 << d) - 1)
+}
+
+/// Round `x` * q / 2^`d` to a coefficient.
+fn decompress(x: u32, d: u32) -> i16 {
+    ((x * Q as u32 + (1 << (d - 1))) >> d) as i16
+}
+
+/// Return a value congruent to `a` * 2^-16 mod q, in (-q, q).
+///
+/// `a` must be in [-q * 2^15, q * 2^15).
+fn montgomery_reduce(a: i32) -> i16 {
+    // q^-1 mod 2^16
+    const QINV: i16 = -3327;
+    let t = (a as i16).wrapping_mul(QINV);
+    ((a - i32::from(t) * i32::from(Q)) >> 16) as i16
+}
+
+/// Return the value congruent to `a` mod q, in [-q/2, q/2].
+fn barrett_reduce(a: i16) -> i16 {
+    const V: i32 = ((1 << 26) + Q as i32 / 2) / Q as i32;
+    let t = ((V * i32::from(a) + (1 << 25)) >> 26) as i16;
+    a.wrapping_sub(t.wrapping_mul(Q))
+}
+
+fn fqmul(a: i16, b: i16) -> i16 {
+    montgomery_reduce(i32::from(a) * i32::from(b))
+}
+
+/// Powers of the 256th root of unity 17, in Montgomery form and
+/// bit-reversed order.
+const ZETAS: [i16; 128] = [
+    -1044, -758, -359, -1517, 1493, 1422, 287, 202, -171, 622, 1577, 182, 962, -1202, -1474, 1468,
+    573, -1325, 264, 383, -829, 1458, -1602, -130, -681, 1017, 732, 608, -1542, 411, -205, -1571,
+    1223, 652, -552, 1015, -1293, 1491, -282, -1544, 516, -8, -320, -666, -1618, -1162, 126, 1469,
+    -853, -90, -271, 830, 107, -1421, -247, -951, -398, 961, -1508, -725, 448, -1065, 677, -1275,
+    -1103, 430, 555, 843, -1251, 871, 1550, 105, 422, 587, 177, -235, -291, -460, 1574, 1653, -246,
+    778, 1159, -147, -777, 1483, -602, 1119, -1590, 644, -872, 349, 418, 329, -156, -75, 817, 1097,
+    603, 610, 1322, -1285, -1465, 384, -1215, -136, 1218, -1335, -874, 220, -1187, -1659, -1185,
+    -1530, -1278, 794, -1510, -854, -870, 478, -108, -308, 996, 991, 958, -1460, 1522, 1628,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand::DefaultSecureRandom;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_round_trip() {
+        let (public_key, secret_key) = generate_key_pair(&DefaultSecureRandom).unwrap();
+        assert_eq!(public_key.len(), PUBLIC_KEY_LEN);
+
+        let (ciphertext, shared_secret) =
+            encapsulate(public_key[..].try_into().unwrap(), &DefaultSecureRandom).unwrap();
+        assert_eq!(ciphertext.len(), CIPHERTEXT_LEN);
+        assert_eq!(
+            decapsulate(&secret_key, ciphertext[..].try_into().unwrap()),
+            shared_secret
+        );
+    }
+
+    #[test]
+    fn test_implicit_rejection() {
+        let (public_key, secret_key) = key_pair_from_seeds(&[1; 32], &[2; 32]);
+        let (mut ciphertext, shared_secret) = encapsulate_with_seed(&public_key, &[3; 32]);
+
+        ciphertext[0] ^= 1;
+        let rejected = decapsulate(&secret_key, ciphertext[..].try_into().unwrap());
+        assert_ne!(rejected, shared_secret);
+        assert_eq!(rejected, kdf(&[2; 32], &ciphertext));
+    }
+
+    /// The first entry (count = 0) of PQCkemKAT_2400.rsp from the round 3
+    /// reference implementation.  The seeds are the first three outputs
+    /// of the KAT generator's AES-256 CTR DRBG, seeded with that entry's
+    /// `seed`:
+    ///
+    /// 061550234D158C5EC95595FE04EF7A25767F2E24CC2BC479D09D86DC9ABCFDE7
+    /// 056A8C266F9EF97ED08541DBD2E1FFA1
+    ///
+    /// The shared secret depends on the whole public key and ciphertext,
+    /// so matching `ss` checks those too.
+    #[test]
+    fn test_round3_kat() {
+        let d = hex("7c9935a0b07694aa0c6d10e4db6b1add2fd81a25ccb148032dcd739936737f2d");
+        let z = hex("8626ed79d451140800e03b59b956f8210e556067407d13dc90fa9e8b872bfb8f");
+        let seed = hex("147c03f7a5bebba406c8fae1874d7f13c80efe79a3a9a874cc09fe76f6997615");
+        let ss = hex("914cb67fe5c38e73bf74181c0ac50428dedf7750a98058f7d536708774535b29");
+
+        let (public_key, secret_key) = key_pair_from_seeds(&d, &z);
+        assert_eq!(public_key.len(), PUBLIC_KEY_LEN);
+        assert_eq!(secret_key.0.len(), SECRET_KEY_LEN);
+
+        let (ciphertext, shared_secret) = encapsulate_with_seed(&public_key, &seed);
+        assert_eq!(ciphertext.len(), CIPHERTEXT_LEN);
+        assert_eq!(&shared_secret[..], &ss[..]);
+        assert_eq!(
+            &decapsulate(&secret_key, ciphertext[..].try_into().unwrap())[..],
+            &ss[..]
+        );
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
@@ -252,9 +252,22 @@
 //!   it for your application. If you want to disable TLS 1.2 for security reasons,
 //!   consider explicitly enabling TLS 1.3 only in the config builder API.
 //!
+//! - `kyber`: this feature adds the hybrid post-quantum key exchange group
+//!   `kx_group::X25519_KYBER768_DRAFT00`, combining X25519 with Kyber768.
+//!   It is not used unless configured with `with_kx_groups`, and only in TLS1.3.
+//!
+//! - `pemfile`: this feature exposes the `pemfile` module, for reading
+//!   certificates and private keys from PEM files without depending on
+//!   another crate.
+//!
+//! - `encrypted_pkcs8`: this feature adds `PrivateKey::from_encrypted_pkcs8`,
+//!   for decrypting password-protected PKCS#8 private keys.  It brings in
+//!   the `aes` and `cbc` crates.
+//!
 //! - `read_buf`: When building with Rust Nightly, adds support for the unstable
-//!   `std::io::ReadBuf` and related APIs. This reduces costs from initializing
-//!   buffers. Will do nothing on non-Nightly releases.
+//!   `std::io::Read::read_buf` method, which reads into a `std::io::BorrowedBuf`.
+//!   This reduces costs from initializing buffers. Will do nothing on
+//!   non-Nightly releases.
 
 // Require docs for public APIs, deny unsafe code, etc.
 #![forbid(unsafe_code, unused_must_use)]
@@ -298,6 +311,7 @@
 // is used to avoid needing `rustversion` to be compiled twice during
 // cross-compiling.
 #![cfg_attr(read_buf, feature(read_buf))]
+#![cfg_attr(read_buf, feature(core_io_borrowed_buf))]
 
 // log for logging (optional).
 #[cfg(feature = "logging")]
@@ -332,6 +346,7 @@ mod verify;
 #[cfg(test)]
 mod verifybench;
 mod x509;
+mod zeroize;
 #[macro_use]
 mod check;
 mod bs_debug;
@@ -340,8 +355,13 @@ mod key;
 mod key_log;
 mod key_log_file;
 mod kx;
+#[cfg(feature = "kyber")]
+mod kyber;
+#[cfg(feature = "encrypted_pkcs8")]
+mod pkcs8;
 mod suites;
 mod ticketer;
+mod time_provider;
 mod versions;
 
 /// Internal classes which may be useful outside the library.
@@ -362,26 +382,35 @@ pub use crate::anchors::{OwnedTrustAnchor, RootCertStore};
 pub use crate::builder::{
     ConfigBuilder, ConfigSide, WantsCipherSuites, WantsKxGroups, WantsVerifier, WantsVersions,
 };
+pub use crate::cipher::PaddingPolicy;
 pub use crate::conn::{
-    CommonState, Connection, ConnectionCommon, IoState, Reader, SideData, Writer,
+    ChannelBindingKind, CommonState, Connection, ConnectionCommon, HandshakeCompleteCallback,
+    IoState, Reader, SideData, Writer,
 };
-pub use crate::error::Error;
+pub use crate::error::{Error, OtherError};
 pub use crate::key::{Certificate, PrivateKey};
-pub use crate::key_log::{KeyLog, NoKeyLog};
+pub use crate::key_log::{nss_key_log_line, CallbackKeyLog, KeyLog, NoKeyLog};
 pub use crate::key_log_file::KeyLogFile;
 pub use crate::kx::{SupportedKxGroup, ALL_KX_GROUPS};
+pub use crate::msgs::enums::AlertDescription;
 pub use crate::msgs::enums::CipherSuite;
+pub use crate::msgs::enums::NamedGroup;
 pub use crate::msgs::enums::ProtocolVersion;
 pub use crate::msgs::enums::SignatureScheme;
 pub use crate::msgs::handshake::DistinguishedNames;
+pub use crate::msgs::persist::ResumptionPsk;
+pub use crate::rand::{DefaultSecureRandom, SecureRandom};
+pub use crate::record_layer::RecordCounters;
 pub use crate::stream::{Stream, StreamOwned};
 pub use crate::suites::{
     BulkAlgorithm, SupportedCipherSuite, ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES,
 };
-pub use crate::ticketer::Ticketer;
+pub use crate::ticketer::{TicketAlgorithm, TicketSwitcher, Ticketer};
+pub use crate::time_provider::{DefaultTimeProvider, TimeProvider};
 #[cfg(feature = "tls12")]
 pub use crate::tls12::Tls12CipherSuite;
 pub use crate::tls13::Tls13CipherSuite;
+pub use crate::verify::{verify_cert_chain, RawPublicKeyVerified, RawPublicKeyVerifier};
 pub use crate::versions::{SupportedProtocolVersion, ALL_VERSIONS, DEFAULT_VERSIONS};
 
 /// Items for use in a client.
@@ -391,10 +420,12 @@ pub mod client {
     mod common;
     pub(super) mod handy;
     mod hs;
+    mod idna;
     #[cfg(feature = "tls12")]
     mod tls12;
     mod tls13;
 
+    pub use crate::verify::CrlExpiryPolicy;
     pub use builder::{WantsClientCert, WantsTransparencyPolicyOrClientCert};
     #[cfg(feature = "quic")]
     #[cfg_attr(docsrs, doc(cfg(feature = "quic")))]
@@ -404,13 +435,14 @@ pub mod client {
     pub use client_conn::ServerName;
     pub use client_conn::StoresClientSessions;
     pub use client_conn::{ClientConfig, ClientConnection, ClientConnectionData, WriteEarlyData};
+    pub use handy::ResolvesClientCertUsingCaHints;
     pub use handy::{ClientSessionMemoryCache, NoClientSessionStorage};
 
     #[cfg(feature = "dangerous_configuration")]
     #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
     pub use crate::verify::{
-        CertificateTransparencyPolicy, HandshakeSignatureValid, ServerCertVerified,
-        ServerCertVerifier, WebPkiVerifier,
+        AndVerifier, CertificateTransparencyPolicy, HandshakeSignatureValid, ServerCertVerified,
+        ServerCertVerifier, SpkiPinVerifier, WebPkiVerifier,
     };
     #[cfg(feature = "dangerous_configuration")]
     #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
@@ -431,7 +463,8 @@ pub mod server {
     mod tls13;
 
     pub use crate::verify::{
-        AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth,
+        AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, CrlExpiryPolicy,
+        NoClientAuth,
     };
     pub use builder::WantsServerCert;
     pub use handy::ResolvesServerCertUsingSni;
@@ -443,7 +476,10 @@ pub mod server {
     pub use server_conn::{
         Accepted, Acceptor, ReadEarlyData, ServerConfig, ServerConnection, ServerConnectionData,
     };
-    pub use server_conn::{ClientHello, ProducesTickets, ResolvesServerCert};
+    pub use server_conn::{
+        ClientHello, ClientHelloDecision, DetectsEarlyDataReplay, InspectsClientHello,
+        ProducesTickets, ResolvesServerCert,
+    };
 
     #[cfg(feature = "dangerous_configuration")]
     #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
@@ -458,6 +494,10 @@ pub use server::{ServerConfig, ServerConnection};
 pub mod cipher_suite {
     pub use crate::suites::CipherSuiteCommon;
     #[cfg(feature = "tls12")]
+    pub use crate::tls12::TLS_ECDHE_ECDSA_WITH_AES_128_CCM;
+    #[cfg(feature = "tls12")]
+    pub use crate::tls12::TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8;
+    #[cfg(feature = "tls12")]
     pub use crate::tls12::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256;
     #[cfg(feature = "tls12")]
     pub use crate::tls12::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384;
@@ -490,16 +530,31 @@ pub mod kx_group {
     pub use crate::kx::SECP256R1;
     pub use crate::kx::SECP384R1;
     pub use crate::kx::X25519;
+    #[cfg(feature = "kyber")]
+    pub use crate::kx::X25519_KYBER768_DRAFT00;
 }
 
 /// Message signing interfaces and implementations.
 pub mod sign;
 
+/// Certificate compression (RFC8879) interfaces.
+pub mod compress;
+
+/// Pluggable cryptography interfaces, and the default *ring*-backed
+/// implementation.
+pub mod crypto;
+
 #[cfg(feature = "quic")]
 #[cfg_attr(docsrs, doc(cfg(feature = "quic")))]
 /// APIs for implementing QUIC TLS
 pub mod quic;
 
+#[cfg(feature = "pemfile")]
+#[cfg_attr(docsrs, doc(cfg(feature = "pemfile")))]
+/// Functions for reading certificates and private keys from PEM files.
+#[allow(clippy::result_unit_err)]
+pub mod pemfile;
+
 /// This is the rustls manual.
 pub mod manual;
 
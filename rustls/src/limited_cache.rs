@@ -62,6 +62,30 @@ where
         self.map.get(k)
     }
 
+    /// Like `get`, but also marks the item as the most recently used,
+    /// so it is evicted last.
+    pub(crate) fn get_and_freshen<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        if !self.map.contains_key(k) {
+            return None;
+        }
+
+        // O(N) search, followed by O(N) move
+        if let Some(index) = self
+            .oldest
+            .iter()
+            .position(|item| item.borrow() == k)
+        {
+            if let Some(item) = self.oldest.remove(index) {
+                self.oldest.push_back(item);
+            }
+        }
+        self.map.get(k)
+    }
+
     pub(crate) fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
     where
         K: Borrow<Q>,
@@ -107,6 +131,22 @@ mod test {
         assert_eq!(t.get("ghi"), Some(&3));
     }
 
+    #[test]
+    fn test_evicts_least_recently_used_item() {
+        let mut t = Test::new(3);
+        t.insert("abc".into(), 1);
+        t.insert("def".into(), 2);
+
+        assert_eq!(t.get_and_freshen("abc"), Some(&1));
+        assert_eq!(t.get_and_freshen("xyz"), None);
+
+        t.insert("ghi".into(), 3);
+
+        assert_eq!(t.get("abc"), Some(&1));
+        assert_eq!(t.get("def"), None);
+        assert_eq!(t.get("ghi"), Some(&3));
+    }
+
     #[test]
     fn test_evicts_second_oldest_item_if_first_removed() {
         let mut t = Test::new(3);
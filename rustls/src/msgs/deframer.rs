@@ -1,41 +1,32 @@
-use std::collections::VecDeque;
 use std::io;
 
 use crate::msgs::codec;
-use crate::msgs::message::{MessageError, OpaqueMessage};
+use crate::msgs::message::{BorrowedOpaqueMessage, MessageError, OpaqueMessage};
 
 /// This deframer works to reconstruct TLS messages
 /// from arbitrary-sized reads, buffering as necessary.
-/// The input is `read()`, the output is the `frames` deque.
+/// The input is `read()`, and complete messages are
+/// taken with `pop()`.
 pub struct MessageDeframer {
-    /// Completed frames for output.
-    pub frames: VecDeque<OpaqueMessage>,
-
     /// Set to true if the peer is not talking TLS, but some other
     /// protocol.  The caller should abort the connection, because
-    /// the deframer cannot recover.
+    /// the deframer cannot recover, once it has taken any complete
+    /// messages received before the bad data.
     pub desynced: bool,
 
-    /// A fixed-size buffer containing the currently-accumulating
-    /// TLS message.
-    buf: Box<[u8; OpaqueMessage::MAX_WIRE_SIZE]>,
+    /// Received bytes: complete messages not yet popped, then
+    /// perhaps a partial message.  Messages are decrypted in
+    /// place here, and the buffer is reused between reads, so it
+    /// is only reallocated if it needs to grow.
+    buf: Vec<u8>,
+
+    /// Bytes before this offset in `buf` have been popped.
+    start: usize,
 
     /// What size prefix of `buf` is used.
     used: usize,
 }
 
-enum BufferContents {
-    /// Contains an invalid message as a header.
-    Invalid,
-
-    /// Might contain a valid message if we receive more.
-    /// Perhaps totally empty!
-    Partial,
-
-    /// Contains a valid frame as a prefix.
-    Valid,
-}
-
 impl Default for MessageDeframer {
     fn default() -> Self {
         Self::new()
@@ -45,96 +36,107 @@ impl Default for MessageDeframer {
 impl MessageDeframer {
     pub fn new() -> Self {
         Self {
-            frames: VecDeque::new(),
             desynced: false,
-            buf: Box::new([0u8; OpaqueMessage::MAX_WIRE_SIZE]),
+            buf: Vec::new(),
+            start: 0,
             used: 0,
         }
     }
 
     /// Read some bytes from `rd`, and add them to our internal
     /// buffer.  If this means our internal buffer contains
-    /// full messages, decode them all.
+    /// full messages, they become available from `pop()`.
     pub fn read(&mut self, rd: &mut dyn io::Read) -> io::Result<usize> {
-        // Try to do the largest reads possible.  Note that if
-        // we get a message with a length field out of range here,
-        // we do a zero length read.  That looks like an EOF to
-        // the next layer up, which is fine.
-        debug_assert!(self.used <= OpaqueMessage::MAX_WIRE_SIZE);
+        // Reclaim the space used by messages which have already
+        // been popped.
+        if self.start > 0 {
+            self.buf
+                .copy_within(self.start..self.used, 0);
+            self.used -= self.start;
+            self.start = 0;
+        }
+
+        // Always leave room for a whole message, so that a single
+        // read can complete whatever is pending.
+        let want = self.used + OpaqueMessage::MAX_WIRE_SIZE;
+        if self.buf.len() < want {
+            self.buf.resize(want, 0u8);
+        }
+
         let new_bytes = rd.read(&mut self.buf[self.used..])?;
 
+        // Nothing after bad data can be framed, so discard it.
+        if self.desynced {
+            return Ok(new_bytes);
+        }
         self.used += new_bytes;
 
-        loop {
-            match self.try_deframe_one() {
-                BufferContents::Invalid => {
+        // Check the headers of everything we now have, so that a peer
+        // not talking TLS is noticed as soon as possible.
+        let mut offs = self.start;
+        while offs < self.used {
+            match frame_len(&self.buf[offs..self.used]) {
+                Ok(len) => offs += len,
+                Err(MessageError::TooShortForHeader) | Err(MessageError::TooShortForLength) => {
+                    break
+                }
+                Err(_) => {
+                    // Keep the messages before the bad data, so
+                    // they can still be popped.
                     self.desynced = true;
+                    self.used = offs;
                     break;
                 }
-                BufferContents::Valid => continue,
-                BufferContents::Partial => break,
             }
         }
 
         Ok(new_bytes)
     }
 
+    /// Take the next complete message, if there is one.
+    ///
+    /// The message's payload is borrowed from our buffer, so
+    /// it may be decrypted in place.  If we are `desynced`, this
+    /// still returns the messages received before the bad data.
+    pub fn pop(&mut self) -> Option<BorrowedOpaqueMessage<'_>> {
+        let len = frame_len(&self.buf[self.start..self.used]).ok()?;
+        let frame = &mut self.buf[self.start..self.start + len];
+        self.start += len;
+        if self.start == self.used {
+            self.start = 0;
+            self.used = 0;
+        }
+
+        BorrowedOpaqueMessage::read(frame).ok()
+    }
+
     /// Returns true if we have messages for the caller
-    /// to process, either whole messages in our output
-    /// queue or partial messages in our buffer.
+    /// to process, either whole messages waiting to be
+    /// popped or partial messages in our buffer.
     pub fn has_pending(&self) -> bool {
-        !self.frames.is_empty() || self.used > 0
-    }
-
-    /// Does our `buf` contain a full message?  It does if it is big enough to
-    /// contain a header, and that header has a length which falls within `buf`.
-    /// If so, deframe it and place the message onto the frames output queue.
-    fn try_deframe_one(&mut self) -> BufferContents {
-        // Try to decode a message off the front of buf.
-        let mut rd = codec::Reader::init(&self.buf[..self.used]);
-
-        match OpaqueMessage::read(&mut rd) {
-            Ok(m) => {
-                let used = rd.used();
-                self.frames.push_back(m);
-                self.buf_consume(used);
-                BufferContents::Valid
-            }
-            Err(MessageError::TooShortForHeader) | Err(MessageError::TooShortForLength) => {
-                BufferContents::Partial
-            }
-            Err(_) => BufferContents::Invalid,
-        }
+        self.used > self.start
     }
 
-    #[allow(clippy::comparison_chain)]
-    fn buf_consume(&mut self, taken: usize) {
-        if taken < self.used {
-            /* Before:
-             * +----------+----------+----------+
-             * | taken    | pending  |xxxxxxxxxx|
-             * +----------+----------+----------+
-             * 0          ^ taken    ^ self.used
-             *
-             * After:
-             * +----------+----------+----------+
-             * | pending  |xxxxxxxxxxxxxxxxxxxxx|
-             * +----------+----------+----------+
-             * 0          ^ self.used
-             */
+    /// How much memory our buffer holds, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.buf.capacity()
+    }
+}
 
-            self.buf
-                .copy_within(taken..self.used, 0);
-            self.used -= taken;
-        } else if taken == self.used {
-            self.used = 0;
-        }
+/// If `buf` starts with a complete and valid message, return
+/// its length including the header.
+fn frame_len(buf: &[u8]) -> Result<usize, MessageError> {
+    let (_, _, len) = OpaqueMessage::read_header(&mut codec::Reader::init(buf))?;
+    let len = OpaqueMessage::HEADER_SIZE as usize + len as usize;
+    if len > buf.len() {
+        return Err(MessageError::TooShortForLength);
     }
+    Ok(len)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::MessageDeframer;
+    use super::{frame_len, MessageDeframer};
     use crate::msgs;
     use crate::msgs::message::Message;
     use std::convert::TryFrom;
@@ -227,19 +229,29 @@ mod tests {
             .expect_err("error not propagated");
     }
 
+    fn frames_ready(d: &MessageDeframer) -> usize {
+        let mut count = 0;
+        let mut offs = d.start;
+        while let Ok(len) = frame_len(&d.buf[offs..d.used]) {
+            offs += len;
+            count += 1;
+        }
+        count
+    }
+
     fn input_whole_incremental(d: &mut MessageDeframer, bytes: &[u8]) {
-        let frames_before = d.frames.len();
+        let frames_before = frames_ready(d);
 
         for i in 0..bytes.len() {
             assert_len(1, input_bytes(d, &bytes[i..i + 1]));
             assert!(d.has_pending());
 
             if i < bytes.len() - 1 {
-                assert_eq!(frames_before, d.frames.len());
+                assert_eq!(frames_before, frames_ready(d));
             }
         }
 
-        assert_eq!(frames_before + 1, d.frames.len());
+        assert_eq!(frames_before + 1, frames_ready(d));
     }
 
     fn assert_len(want: usize, got: io::Result<usize>) {
@@ -251,15 +263,23 @@ mod tests {
     }
 
     fn pop_first(d: &mut MessageDeframer) {
-        let m = d.frames.pop_front().unwrap();
+        let m = d.pop().unwrap();
         assert_eq!(m.typ, msgs::enums::ContentType::Handshake);
-        Message::try_from(m.into_plain_message()).unwrap();
+        Message::try_from(
+            m.into_plain_message()
+                .to_plain_message(),
+        )
+        .unwrap();
     }
 
     fn pop_second(d: &mut MessageDeframer) {
-        let m = d.frames.pop_front().unwrap();
+        let m = d.pop().unwrap();
         assert_eq!(m.typ, msgs::enums::ContentType::Alert);
-        Message::try_from(m.into_plain_message()).unwrap();
+        Message::try_from(
+            m.into_plain_message()
+                .to_plain_message(),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -268,7 +288,7 @@ mod tests {
         assert!(!d.has_pending());
         input_whole_incremental(&mut d, FIRST_MESSAGE);
         assert!(d.has_pending());
-        assert_eq!(1, d.frames.len());
+        assert_eq!(1, frames_ready(&d));
         pop_first(&mut d);
         assert!(!d.has_pending());
         assert!(!d.desynced);
@@ -282,7 +302,7 @@ mod tests {
         assert!(d.has_pending());
         input_whole_incremental(&mut d, SECOND_MESSAGE);
         assert!(d.has_pending());
-        assert_eq!(2, d.frames.len());
+        assert_eq!(2, frames_ready(&d));
         pop_first(&mut d);
         assert!(d.has_pending());
         pop_second(&mut d);
@@ -296,7 +316,7 @@ mod tests {
         assert!(!d.has_pending());
         assert_len(FIRST_MESSAGE.len(), input_bytes(&mut d, FIRST_MESSAGE));
         assert!(d.has_pending());
-        assert_eq!(d.frames.len(), 1);
+        assert_eq!(frames_ready(&d), 1);
         pop_first(&mut d);
         assert!(!d.has_pending());
         assert!(!d.desynced);
@@ -308,7 +328,7 @@ mod tests {
         assert!(!d.has_pending());
         assert_len(FIRST_MESSAGE.len(), input_bytes(&mut d, FIRST_MESSAGE));
         assert_len(SECOND_MESSAGE.len(), input_bytes(&mut d, SECOND_MESSAGE));
-        assert_eq!(d.frames.len(), 2);
+        assert_eq!(frames_ready(&d), 2);
         pop_first(&mut d);
         pop_second(&mut d);
         assert!(!d.has_pending());
@@ -323,7 +343,7 @@ mod tests {
             FIRST_MESSAGE.len() + SECOND_MESSAGE.len(),
             input_bytes_concat(&mut d, FIRST_MESSAGE, SECOND_MESSAGE),
         );
-        assert_eq!(d.frames.len(), 2);
+        assert_eq!(frames_ready(&d), 2);
         pop_first(&mut d);
         pop_second(&mut d);
         assert!(!d.has_pending());
@@ -338,13 +358,93 @@ mod tests {
             FIRST_MESSAGE.len() + SECOND_MESSAGE.len(),
             input_bytes_concat(&mut d, SECOND_MESSAGE, FIRST_MESSAGE),
         );
-        assert_eq!(d.frames.len(), 2);
+        assert_eq!(frames_ready(&d), 2);
         pop_second(&mut d);
         pop_first(&mut d);
         assert!(!d.has_pending());
         assert!(!d.desynced);
     }
 
+    #[test]
+    fn test_split_across_reads_after_pop() {
+        let mut d = MessageDeframer::new();
+        let split = SECOND_MESSAGE.len() / 2;
+        assert_len(
+            FIRST_MESSAGE.len() + split,
+            input_bytes_concat(&mut d, FIRST_MESSAGE, &SECOND_MESSAGE[..split]),
+        );
+        assert_eq!(frames_ready(&d), 1);
+        pop_first(&mut d);
+        assert!(d.has_pending());
+        assert!(d.pop().is_none());
+
+        assert_len(
+            SECOND_MESSAGE.len() - split,
+            input_bytes(&mut d, &SECOND_MESSAGE[split..]),
+        );
+        pop_second(&mut d);
+        assert!(!d.has_pending());
+        assert!(!d.desynced);
+    }
+
+    #[test]
+    fn test_many_in_one_read() {
+        let mut bytes = Vec::new();
+        for _ in 0..8 {
+            bytes.extend_from_slice(FIRST_MESSAGE);
+            bytes.extend_from_slice(SECOND_MESSAGE);
+        }
+
+        let mut d = MessageDeframer::new();
+        assert_len(bytes.len(), input_bytes(&mut d, &bytes));
+        assert_eq!(frames_ready(&d), 16);
+        for _ in 0..8 {
+            pop_first(&mut d);
+            pop_second(&mut d);
+        }
+        assert!(d.pop().is_none());
+        assert!(!d.has_pending());
+        assert!(!d.desynced);
+    }
+
+    #[test]
+    fn test_popped_payload_is_writable_in_place() {
+        let mut d = MessageDeframer::new();
+        assert_len(
+            FIRST_MESSAGE.len() + SECOND_MESSAGE.len(),
+            input_bytes_concat(&mut d, FIRST_MESSAGE, SECOND_MESSAGE),
+        );
+
+        // scribbling over one message must not disturb the next
+        let m = d.pop().unwrap();
+        for b in m.payload.iter_mut() {
+            *b = 0xff;
+        }
+        pop_second(&mut d);
+        assert!(!d.has_pending());
+    }
+
+    #[test]
+    fn test_buffer_is_reused() {
+        let mut d = MessageDeframer::new();
+        assert_len(FIRST_MESSAGE.len(), input_bytes(&mut d, FIRST_MESSAGE));
+        pop_first(&mut d);
+        let buf = d.buf.as_ptr();
+        let capacity = d.buf.capacity();
+
+        for _ in 0..16 {
+            assert_len(
+                FIRST_MESSAGE.len() + SECOND_MESSAGE.len(),
+                input_bytes_concat(&mut d, FIRST_MESSAGE, SECOND_MESSAGE),
+            );
+            pop_first(&mut d);
+            pop_second(&mut d);
+        }
+
+        assert_eq!(buf, d.buf.as_ptr());
+        assert_eq!(capacity, d.buf.capacity());
+    }
+
     #[test]
     fn test_incremental_with_nonfatal_read_error() {
         let mut d = MessageDeframer::new();
@@ -354,7 +454,7 @@ mod tests {
             FIRST_MESSAGE.len() - 3,
             input_bytes(&mut d, &FIRST_MESSAGE[3..]),
         );
-        assert_eq!(d.frames.len(), 1);
+        assert_eq!(frames_ready(&d), 1);
         pop_first(&mut d);
         assert!(!d.has_pending());
         assert!(!d.desynced);
@@ -390,6 +490,24 @@ mod tests {
         assert!(d.desynced);
     }
 
+    #[test]
+    fn test_messages_before_invalid_data_are_kept() {
+        let mut d = MessageDeframer::new();
+        assert_len(
+            FIRST_MESSAGE.len() + INVALID_CONTENTTYPE_MESSAGE.len(),
+            input_bytes_concat(&mut d, FIRST_MESSAGE, INVALID_CONTENTTYPE_MESSAGE),
+        );
+        assert!(d.desynced);
+        pop_first(&mut d);
+        assert!(d.pop().is_none());
+        assert!(!d.has_pending());
+
+        // Later bytes are discarded.
+        assert_len(SECOND_MESSAGE.len(), input_bytes(&mut d, SECOND_MESSAGE));
+        assert!(d.pop().is_none());
+        assert!(!d.has_pending());
+    }
+
     #[test]
     fn test_empty_applicationdata() {
         let mut d = MessageDeframer::new();
@@ -397,9 +515,9 @@ mod tests {
             EMPTY_APPLICATIONDATA_MESSAGE.len(),
             input_bytes(&mut d, EMPTY_APPLICATIONDATA_MESSAGE),
         );
-        let m = d.frames.pop_front().unwrap();
+        let m = d.pop().unwrap();
         assert_eq!(m.typ, msgs::enums::ContentType::ApplicationData);
-        assert_eq!(m.payload.0.len(), 0);
+        assert_eq!(m.payload.len(), 0);
         assert!(!d.has_pending());
         assert!(!d.desynced);
     }
@@ -52,6 +52,7 @@ fn test_enums() {
         ClientCertificateType::RSASign,
         ClientCertificateType::ECDSAFixedECDH,
     );
+    test_enum8::<CertificateType>(CertificateType::X509, CertificateType::RawPublicKey);
     test_enum8::<Compression>(Compression::Null, Compression::LSZ);
     test_enum8::<ContentType>(ContentType::ChangeCipherSpec, ContentType::Heartbeat);
     test_enum8::<HandshakeType>(HandshakeType::HelloRequest, HandshakeType::MessageHash);
@@ -91,6 +92,11 @@ fn test_enums() {
         KeyUpdateRequest::UpdateRequested,
     );
     test_enum8::<CertificateStatusType>(CertificateStatusType::OCSP, CertificateStatusType::OCSP);
+    test_enum8::<MaxFragmentLength>(MaxFragmentLength::Len512, MaxFragmentLength::Len4096);
+    test_enum16::<CertCompressionAlgorithm>(
+        CertCompressionAlgorithm::Zlib,
+        CertCompressionAlgorithm::Zstd,
+    );
 }
 
 #[test]
@@ -1,15 +1,46 @@
 use crate::msgs::base::Payload;
-use crate::msgs::enums::{ContentType, ProtocolVersion};
+use crate::msgs::enums::{ContentType, MaxFragmentLength, ProtocolVersion};
 use crate::msgs::message::{BorrowedPlainMessage, PlainMessage};
 use crate::Error;
+use std::cmp;
 use std::collections::VecDeque;
 
 pub const MAX_FRAGMENT_LEN: usize = 16384;
 pub const PACKET_OVERHEAD: usize = 1 + 2 + 2;
 pub const MAX_FRAGMENT_SIZE: usize = MAX_FRAGMENT_LEN + PACKET_OVERHEAD;
 
+/// The smallest value allowed in the record_size_limit extension (RFC8449).
+pub const MIN_RECORD_SIZE_LIMIT: u16 = 64;
+
+/// The largest useful value for the record_size_limit extension: the
+/// TLS1.3 maximum, which counts the inner content type byte.
+pub const MAX_RECORD_SIZE_LIMIT: u16 = MAX_FRAGMENT_LEN as u16 + 1;
+
+/// Check a configured record_size_limit is one we may send.
+pub fn check_record_size_limit(limit: Option<u16>) -> Result<(), Error> {
+    match limit {
+        Some(MIN_RECORD_SIZE_LIMIT..=MAX_RECORD_SIZE_LIMIT) | None => Ok(()),
+        Some(_) => Err(Error::BadMaxFragmentSize),
+    }
+}
+
+/// Returns the largest fragment payload we may send to a peer that
+/// advertised `limit` in its record_size_limit extension.
+///
+/// In TLS1.3 the limit includes the inner content type byte.
+pub fn record_size_limit_to_fragment_len(limit: u16, is_tls13: bool) -> usize {
+    let limit = limit as usize;
+    match is_tls13 {
+        true => limit - 1,
+        false => limit,
+    }
+}
+
 pub struct MessageFragmenter {
     max_frag: usize,
+
+    /// The largest fragment payload the peer will accept.
+    peer_max_frag: usize,
 }
 
 impl MessageFragmenter {
@@ -19,7 +50,10 @@ impl MessageFragmenter {
     /// this includes overhead. A `max_fragment_size` of 10 will produce TLS fragments
     /// up to 10 bytes.
     pub fn new(max_fragment_size: Option<usize>) -> Result<Self, Error> {
-        let mut new = Self { max_frag: 0 };
+        let mut new = Self {
+            max_frag: 0,
+            peer_max_frag: MAX_FRAGMENT_LEN,
+        };
         new.set_max_fragment_size(max_fragment_size)?;
         Ok(new)
     }
@@ -63,21 +97,63 @@ impl MessageFragmenter {
         }
     }
 
+    /// Set the maximum fragment size that will be produced, including
+    /// overhead.  This is never more than any limit the peer negotiated.
     pub fn set_max_fragment_size(&mut self, new: Option<usize>) -> Result<(), Error> {
-        self.max_frag = match new {
+        let max_frag = match new {
             Some(sz @ 32..=MAX_FRAGMENT_SIZE) => sz - PACKET_OVERHEAD,
             None => MAX_FRAGMENT_LEN,
             _ => return Err(Error::BadMaxFragmentSize),
         };
+        self.max_frag = cmp::min(max_frag, self.peer_max_frag);
         Ok(())
     }
+
+    /// The largest fragment payload we currently produce.
+    pub fn max_fragment_len(&self) -> usize {
+        self.max_frag
+    }
+
+    /// Further limit fragments to `len` bytes of payload, as negotiated
+    /// with the peer's max_fragment_length extension.  This never
+    /// increases the limit.
+    pub fn limit_max_fragment_len(&mut self, len: usize) {
+        self.peer_max_frag = cmp::min(self.peer_max_frag, len);
+        self.max_frag = cmp::min(self.max_frag, len);
+    }
+}
+
+impl MaxFragmentLength {
+    /// The fragment payload length this value represents, or `None` if
+    /// it isn't one defined by RFC6066.
+    pub fn fragment_len(self) -> Option<usize> {
+        match self {
+            Self::Len512 => Some(512),
+            Self::Len1024 => Some(1024),
+            Self::Len2048 => Some(2048),
+            Self::Len4096 => Some(4096),
+            Self::Unknown(_) => None,
+        }
+    }
+
+    /// The inverse of `fragment_len`.
+    pub fn from_fragment_len(len: usize) -> Option<Self> {
+        match len {
+            512 => Some(Self::Len512),
+            1024 => Some(Self::Len1024),
+            2048 => Some(Self::Len2048),
+            4096 => Some(Self::Len4096),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{MessageFragmenter, PACKET_OVERHEAD};
+    use super::{check_record_size_limit, record_size_limit_to_fragment_len};
+    use super::{MessageFragmenter, MAX_FRAGMENT_SIZE, PACKET_OVERHEAD};
     use crate::msgs::base::Payload;
-    use crate::msgs::enums::{ContentType, ProtocolVersion};
+    use crate::msgs::enums::{ContentType, MaxFragmentLength, ProtocolVersion};
     use crate::msgs::message::PlainMessage;
     use std::collections::VecDeque;
 
@@ -165,4 +241,70 @@ mod tests {
         );
         assert_eq!(q.len(), 0);
     }
+
+    #[test]
+    fn limit_max_fragment_len() {
+        let data: Vec<u8> = (1..70u8).collect();
+        let m = PlainMessage {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: Payload::new(data),
+        };
+
+        let mut frag = MessageFragmenter::new(Some(64)).unwrap();
+        frag.limit_max_fragment_len(32);
+        frag.limit_max_fragment_len(512);
+        let mut q = VecDeque::new();
+        frag.fragment(m, &mut q);
+        assert_eq!(q.len(), 3);
+        assert!(q
+            .iter()
+            .all(|m| m.payload.0.len() <= 32));
+    }
+
+    #[test]
+    fn set_max_fragment_size_keeps_peer_limit() {
+        let mut frag = MessageFragmenter::new(None).unwrap();
+        frag.limit_max_fragment_len(512);
+        frag.set_max_fragment_size(Some(64))
+            .unwrap();
+        assert_eq!(frag.max_fragment_len(), 64 - PACKET_OVERHEAD);
+        frag.set_max_fragment_size(Some(4096))
+            .unwrap();
+        assert_eq!(frag.max_fragment_len(), 512);
+        frag.set_max_fragment_size(None)
+            .unwrap();
+        assert_eq!(frag.max_fragment_len(), 512);
+
+        assert!(frag
+            .set_max_fragment_size(Some(31))
+            .is_err());
+        assert!(frag
+            .set_max_fragment_size(Some(MAX_FRAGMENT_SIZE + 1))
+            .is_err());
+        assert_eq!(frag.max_fragment_len(), 512);
+    }
+
+    #[test]
+    fn record_size_limits() {
+        assert!(check_record_size_limit(None).is_ok());
+        assert!(check_record_size_limit(Some(63)).is_err());
+        assert!(check_record_size_limit(Some(64)).is_ok());
+        assert!(check_record_size_limit(Some(16385)).is_ok());
+        assert!(check_record_size_limit(Some(16386)).is_err());
+
+        assert_eq!(record_size_limit_to_fragment_len(64, false), 64);
+        assert_eq!(record_size_limit_to_fragment_len(64, true), 63);
+        assert_eq!(record_size_limit_to_fragment_len(16385, true), 16384);
+    }
+
+    #[test]
+    fn max_fragment_length_values() {
+        for len in &[512, 1024, 2048, 4096] {
+            let mfl = MaxFragmentLength::from_fragment_len(*len).unwrap();
+            assert_eq!(mfl.fragment_len(), Some(*len));
+        }
+        assert_eq!(MaxFragmentLength::from_fragment_len(1000), None);
+        assert_eq!(MaxFragmentLength::Unknown(5).fragment_len(), None);
+    }
 }
@@ -1,14 +1,16 @@
+use crate::error::Error;
 use crate::key;
 use crate::msgs::base::{Payload, PayloadU16, PayloadU24, PayloadU8};
 use crate::msgs::codec;
 use crate::msgs::codec::{Codec, Reader};
+use crate::msgs::enums::ClientCertificateType;
 use crate::msgs::enums::ECCurveType;
 use crate::msgs::enums::PSKKeyExchangeMode;
-use crate::msgs::enums::{CertificateStatusType, ClientCertificateType};
+use crate::msgs::enums::{CertCompressionAlgorithm, CertificateStatusType, CertificateType};
 use crate::msgs::enums::{CipherSuite, Compression, ECPointFormat, ExtensionType};
 use crate::msgs::enums::{HandshakeType, ProtocolVersion};
 use crate::msgs::enums::{HashAlgorithm, ServerNameType, SignatureAlgorithm};
-use crate::msgs::enums::{KeyUpdateRequest, NamedGroup, SignatureScheme};
+use crate::msgs::enums::{KeyUpdateRequest, MaxFragmentLength, NamedGroup, SignatureScheme};
 use crate::rand;
 
 #[cfg(feature = "logging")]
@@ -77,9 +79,9 @@ impl Codec for Random {
 }
 
 impl Random {
-    pub fn new() -> Result<Self, rand::GetRandomFailed> {
+    pub fn new(rng: &dyn rand::SecureRandom) -> Result<Self, Error> {
         let mut data = [0u8; 32];
-        rand::fill_random(&mut data)?;
+        rng.fill(&mut data)?;
         Ok(Self(data))
     }
 
@@ -110,6 +112,12 @@ impl fmt::Debug for SessionID {
     }
 }
 
+impl AsRef<[u8]> for SessionID {
+    fn as_ref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
 impl PartialEq for SessionID {
     fn eq(&self, other: &Self) -> bool {
         if self.len != other.len {
@@ -147,9 +155,9 @@ impl Codec for SessionID {
 }
 
 impl SessionID {
-    pub fn random() -> Result<Self, rand::GetRandomFailed> {
+    pub fn random(rng: &dyn rand::SecureRandom) -> Result<Self, Error> {
         let mut data = [0u8; 32];
-        rand::fill_random(&mut data)?;
+        rng.fill(&mut data)?;
         Ok(Self { data, len: 32 })
     }
 
@@ -220,6 +228,8 @@ impl DecomposedSignatureScheme for SignatureScheme {
             Self::ECDSA_NISTP256_SHA256
             | Self::ECDSA_NISTP384_SHA384
             | Self::ECDSA_NISTP521_SHA512 => SignatureAlgorithm::ECDSA,
+            Self::ED25519 => SignatureAlgorithm::ED25519,
+            Self::ED448 => SignatureAlgorithm::ED448,
             _ => SignatureAlgorithm::Unknown(0),
         }
     }
@@ -545,12 +555,15 @@ pub type SCTList = VecU16OfPayloadU16;
 declare_u8_vec!(PSKKeyExchangeModes, PSKKeyExchangeMode);
 declare_u16_vec!(KeyShareEntries, KeyShareEntry);
 declare_u8_vec!(ProtocolVersions, ProtocolVersion);
+declare_u8_vec!(CertCompressionAlgorithms, CertCompressionAlgorithm);
+declare_u8_vec!(CertificateTypes, CertificateType);
 
 #[derive(Clone, Debug)]
 pub enum ClientExtension {
     ECPointFormats(ECPointFormatList),
     NamedGroups(NamedGroups),
     SignatureAlgorithms(SupportedSignatureSchemes),
+    SignatureAlgorithmsCert(SupportedSignatureSchemes),
     ServerName(ServerNameRequest),
     SessionTicket(ClientSessionTicket),
     Protocols(ProtocolNameList),
@@ -565,6 +578,13 @@ pub enum ClientExtension {
     TransportParameters(Vec<u8>),
     TransportParametersDraft(Vec<u8>),
     EarlyData,
+    CertificateCompressionAlgorithms(CertCompressionAlgorithms),
+    MaxFragmentLength(MaxFragmentLength),
+    RecordSizeLimit(u16),
+    PostHandshakeAuth,
+    ClientCertificateType(CertificateTypes),
+    ServerCertificateType(CertificateTypes),
+    DelegatedCredential(SupportedSignatureSchemes),
     Unknown(UnknownExtension),
 }
 
@@ -574,6 +594,7 @@ impl ClientExtension {
             Self::ECPointFormats(_) => ExtensionType::ECPointFormats,
             Self::NamedGroups(_) => ExtensionType::EllipticCurves,
             Self::SignatureAlgorithms(_) => ExtensionType::SignatureAlgorithms,
+            Self::SignatureAlgorithmsCert(_) => ExtensionType::SignatureAlgorithmsCert,
             Self::ServerName(_) => ExtensionType::ServerName,
             Self::SessionTicket(_) => ExtensionType::SessionTicket,
             Self::Protocols(_) => ExtensionType::ALProtocolNegotiation,
@@ -588,6 +609,13 @@ impl ClientExtension {
             Self::TransportParameters(_) => ExtensionType::TransportParameters,
             Self::TransportParametersDraft(_) => ExtensionType::TransportParametersDraft,
             Self::EarlyData => ExtensionType::EarlyData,
+            Self::CertificateCompressionAlgorithms(_) => ExtensionType::CompressCertificate,
+            Self::MaxFragmentLength(_) => ExtensionType::MaxFragmentLength,
+            Self::RecordSizeLimit(_) => ExtensionType::RecordSizeLimit,
+            Self::PostHandshakeAuth => ExtensionType::PostHandshakeAuth,
+            Self::ClientCertificateType(_) => ExtensionType::ClientCertificateType,
+            Self::ServerCertificateType(_) => ExtensionType::ServerCertificateType,
+            Self::DelegatedCredential(_) => ExtensionType::DelegatedCredential,
             Self::Unknown(ref r) => r.typ,
         }
     }
@@ -601,12 +629,15 @@ impl Codec for ClientExtension {
         match *self {
             Self::ECPointFormats(ref r) => r.encode(&mut sub),
             Self::NamedGroups(ref r) => r.encode(&mut sub),
-            Self::SignatureAlgorithms(ref r) => r.encode(&mut sub),
+            Self::SignatureAlgorithms(ref r) | Self::SignatureAlgorithmsCert(ref r) => {
+                r.encode(&mut sub)
+            }
             Self::ServerName(ref r) => r.encode(&mut sub),
             Self::SessionTicket(ClientSessionTicket::Request)
             | Self::ExtendedMasterSecretRequest
             | Self::SignedCertificateTimestampRequest
-            | Self::EarlyData => {}
+            | Self::EarlyData
+            | Self::PostHandshakeAuth => {}
             Self::SessionTicket(ClientSessionTicket::Offer(ref r)) => r.encode(&mut sub),
             Self::Protocols(ref r) => r.encode(&mut sub),
             Self::SupportedVersions(ref r) => r.encode(&mut sub),
@@ -618,6 +649,13 @@ impl Codec for ClientExtension {
             Self::TransportParameters(ref r) | Self::TransportParametersDraft(ref r) => {
                 sub.extend_from_slice(r)
             }
+            Self::CertificateCompressionAlgorithms(ref r) => r.encode(&mut sub),
+            Self::MaxFragmentLength(ref r) => r.encode(&mut sub),
+            Self::RecordSizeLimit(r) => r.encode(&mut sub),
+            Self::ClientCertificateType(ref r) | Self::ServerCertificateType(ref r) => {
+                r.encode(&mut sub)
+            }
+            Self::DelegatedCredential(ref r) => r.encode(&mut sub),
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -639,6 +677,10 @@ impl Codec for ClientExtension {
                 let schemes = SupportedSignatureSchemes::read(&mut sub)?;
                 Self::SignatureAlgorithms(schemes)
             }
+            ExtensionType::SignatureAlgorithmsCert => {
+                let schemes = SupportedSignatureSchemes::read(&mut sub)?;
+                Self::SignatureAlgorithmsCert(schemes)
+            }
             ExtensionType::ServerName => Self::ServerName(ServerNameRequest::read(&mut sub)?),
             ExtensionType::SessionTicket => {
                 if sub.any_left() {
@@ -673,6 +715,23 @@ impl Codec for ClientExtension {
                 Self::TransportParametersDraft(sub.rest().to_vec())
             }
             ExtensionType::EarlyData if !sub.any_left() => Self::EarlyData,
+            ExtensionType::CompressCertificate => {
+                Self::CertificateCompressionAlgorithms(CertCompressionAlgorithms::read(&mut sub)?)
+            }
+            ExtensionType::MaxFragmentLength => {
+                Self::MaxFragmentLength(MaxFragmentLength::read(&mut sub)?)
+            }
+            ExtensionType::RecordSizeLimit => Self::RecordSizeLimit(u16::read(&mut sub)?),
+            ExtensionType::PostHandshakeAuth if !sub.any_left() => Self::PostHandshakeAuth,
+            ExtensionType::ClientCertificateType => {
+                Self::ClientCertificateType(CertificateTypes::read(&mut sub)?)
+            }
+            ExtensionType::ServerCertificateType => {
+                Self::ServerCertificateType(CertificateTypes::read(&mut sub)?)
+            }
+            ExtensionType::DelegatedCredential => {
+                Self::DelegatedCredential(SupportedSignatureSchemes::read(&mut sub)?)
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -733,6 +792,10 @@ pub enum ServerExtension {
     TransportParameters(Vec<u8>),
     TransportParametersDraft(Vec<u8>),
     EarlyData,
+    MaxFragmentLength(MaxFragmentLength),
+    RecordSizeLimit(u16),
+    ClientCertificateType(CertificateType),
+    ServerCertificateType(CertificateType),
     Unknown(UnknownExtension),
 }
 
@@ -753,6 +816,10 @@ impl ServerExtension {
             Self::TransportParameters(_) => ExtensionType::TransportParameters,
             Self::TransportParametersDraft(_) => ExtensionType::TransportParametersDraft,
             Self::EarlyData => ExtensionType::EarlyData,
+            Self::MaxFragmentLength(_) => ExtensionType::MaxFragmentLength,
+            Self::RecordSizeLimit(_) => ExtensionType::RecordSizeLimit,
+            Self::ClientCertificateType(_) => ExtensionType::ClientCertificateType,
+            Self::ServerCertificateType(_) => ExtensionType::ServerCertificateType,
             Self::Unknown(ref r) => r.typ,
         }
     }
@@ -779,6 +846,11 @@ impl Codec for ServerExtension {
             Self::TransportParameters(ref r) | Self::TransportParametersDraft(ref r) => {
                 sub.extend_from_slice(r)
             }
+            Self::MaxFragmentLength(ref r) => r.encode(&mut sub),
+            Self::RecordSizeLimit(r) => r.encode(&mut sub),
+            Self::ClientCertificateType(ref r) | Self::ServerCertificateType(ref r) => {
+                r.encode(&mut sub)
+            }
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -817,6 +889,16 @@ impl Codec for ServerExtension {
                 Self::TransportParametersDraft(sub.rest().to_vec())
             }
             ExtensionType::EarlyData => Self::EarlyData,
+            ExtensionType::MaxFragmentLength => {
+                Self::MaxFragmentLength(MaxFragmentLength::read(&mut sub)?)
+            }
+            ExtensionType::RecordSizeLimit => Self::RecordSizeLimit(u16::read(&mut sub)?),
+            ExtensionType::ClientCertificateType => {
+                Self::ClientCertificateType(CertificateType::read(&mut sub)?)
+            }
+            ExtensionType::ServerCertificateType => {
+                Self::ServerCertificateType(CertificateType::read(&mut sub)?)
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -929,6 +1011,14 @@ impl ClientHelloPayload {
         }
     }
 
+    pub fn get_sigalgs_cert_extension(&self) -> Option<&SupportedSignatureSchemes> {
+        let ext = self.find_extension(ExtensionType::SignatureAlgorithmsCert)?;
+        match *ext {
+            ClientExtension::SignatureAlgorithmsCert(ref req) => Some(req),
+            _ => None,
+        }
+    }
+
     pub fn get_namedgroups_extension(&self) -> Option<&NamedGroups> {
         let ext = self.find_extension(ExtensionType::EllipticCurves)?;
         match *ext {
@@ -1044,6 +1134,67 @@ impl ClientHelloPayload {
         self.find_extension(ExtensionType::EarlyData)
             .is_some()
     }
+
+    pub fn post_handshake_auth_offered(&self) -> bool {
+        self.find_extension(ExtensionType::PostHandshakeAuth)
+            .is_some()
+    }
+
+    pub fn get_cert_compression_extension(&self) -> Option<&CertCompressionAlgorithms> {
+        let ext = self.find_extension(ExtensionType::CompressCertificate)?;
+        match *ext {
+            ClientExtension::CertificateCompressionAlgorithms(ref algs) => Some(algs),
+            _ => None,
+        }
+    }
+
+    pub fn get_max_fragment_length(&self) -> Option<MaxFragmentLength> {
+        let ext = self.find_extension(ExtensionType::MaxFragmentLength)?;
+        match *ext {
+            ClientExtension::MaxFragmentLength(mfl) => Some(mfl),
+            _ => None,
+        }
+    }
+
+    pub fn get_record_size_limit(&self) -> Option<u16> {
+        let ext = self.find_extension(ExtensionType::RecordSizeLimit)?;
+        match *ext {
+            ClientExtension::RecordSizeLimit(limit) => Some(limit),
+            _ => None,
+        }
+    }
+
+    pub fn get_client_certificate_types(&self) -> Option<&CertificateTypes> {
+        let ext = self.find_extension(ExtensionType::ClientCertificateType)?;
+        match *ext {
+            ClientExtension::ClientCertificateType(ref types) => Some(types),
+            _ => None,
+        }
+    }
+
+    pub fn get_server_certificate_types(&self) -> Option<&CertificateTypes> {
+        let ext = self.find_extension(ExtensionType::ServerCertificateType)?;
+        match *ext {
+            ClientExtension::ServerCertificateType(ref types) => Some(types),
+            _ => None,
+        }
+    }
+
+    pub fn get_delegated_credential_schemes(&self) -> Option<&SupportedSignatureSchemes> {
+        let ext = self.find_extension(ExtensionType::DelegatedCredential)?;
+        match *ext {
+            ClientExtension::DelegatedCredential(ref schemes) => Some(schemes),
+            _ => None,
+        }
+    }
+
+    pub fn get_cookie(&self) -> Option<&PayloadU16> {
+        let ext = self.find_extension(ExtensionType::Cookie)?;
+        match *ext {
+            ClientExtension::Cookie(ref ck) => Some(ck),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -1302,8 +1453,9 @@ impl Codec for CertificatePayload {
     }
 
     fn read(r: &mut Reader) -> Option<Self> {
-        // 64KB of certificates is plenty, 16MB is obviously silly
-        codec::read_vec_u24_limited(r, 0x10000)
+        // The size of the whole message is already limited by
+        // HandshakeJoiner, according to max_handshake_message_size.
+        codec::read_vec_u24_limited(r, 0xff_ffff)
     }
 }
 
@@ -1311,10 +1463,58 @@ impl Codec for CertificatePayload {
 // That's annoying. It means the parsing is not
 // context-free any more.
 
+// -- RFC9345 delegated credentials --
+#[derive(Clone, Debug)]
+pub struct Credential {
+    pub valid_time: u32,
+    pub scheme: SignatureScheme,
+    pub public_key: PayloadU24,
+}
+
+impl Codec for Credential {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.valid_time.encode(bytes);
+        self.scheme.encode(bytes);
+        self.public_key.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            valid_time: u32::read(r)?,
+            scheme: SignatureScheme::read(r)?,
+            public_key: PayloadU24::read(r)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DelegatedCredentialPayload {
+    pub credential: Credential,
+    pub algorithm: SignatureScheme,
+    pub signature: PayloadU16,
+}
+
+impl Codec for DelegatedCredentialPayload {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.credential.encode(bytes);
+        self.algorithm.encode(bytes);
+        self.signature.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            credential: Credential::read(r)?,
+            algorithm: SignatureScheme::read(r)?,
+            signature: PayloadU16::read(r)?,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub enum CertificateExtension {
     CertificateStatus(CertificateStatus),
     SignedCertificateTimestamp(SCTList),
+    DelegatedCredential(DelegatedCredentialPayload),
     Unknown(UnknownExtension),
 }
 
@@ -1323,6 +1523,7 @@ impl CertificateExtension {
         match *self {
             CertificateExtension::CertificateStatus(_) => ExtensionType::StatusRequest,
             CertificateExtension::SignedCertificateTimestamp(_) => ExtensionType::SCT,
+            CertificateExtension::DelegatedCredential(_) => ExtensionType::DelegatedCredential,
             CertificateExtension::Unknown(ref r) => r.typ,
         }
     }
@@ -1345,6 +1546,13 @@ impl CertificateExtension {
             _ => None,
         }
     }
+
+    pub fn get_delegated_credential(&self) -> Option<&DelegatedCredentialPayload> {
+        match *self {
+            CertificateExtension::DelegatedCredential(ref dc) => Some(dc),
+            _ => None,
+        }
+    }
 }
 
 impl Codec for CertificateExtension {
@@ -1355,6 +1563,7 @@ impl Codec for CertificateExtension {
         match *self {
             CertificateExtension::CertificateStatus(ref r) => r.encode(&mut sub),
             CertificateExtension::SignedCertificateTimestamp(ref r) => r.encode(&mut sub),
+            CertificateExtension::DelegatedCredential(ref r) => r.encode(&mut sub),
             CertificateExtension::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -1376,6 +1585,9 @@ impl Codec for CertificateExtension {
                 let scts = SCTList::read(&mut sub)?;
                 Self::SignedCertificateTimestamp(scts)
             }
+            ExtensionType::DelegatedCredential => {
+                Self::DelegatedCredential(DelegatedCredentialPayload::read(&mut sub)?)
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -1434,7 +1646,9 @@ impl CertificateEntry {
 
     pub fn has_unknown_extension(&self) -> bool {
         self.exts.iter().any(|ext| {
-            ext.get_type() != ExtensionType::StatusRequest && ext.get_type() != ExtensionType::SCT
+            ext.get_type() != ExtensionType::StatusRequest
+                && ext.get_type() != ExtensionType::SCT
+                && ext.get_type() != ExtensionType::DelegatedCredential
         })
     }
 
@@ -1451,6 +1665,13 @@ impl CertificateEntry {
             .find(|ext| ext.get_type() == ExtensionType::SCT)
             .and_then(CertificateExtension::get_sct_list)
     }
+
+    pub fn get_delegated_credential(&self) -> Option<&DelegatedCredentialPayload> {
+        self.exts
+            .iter()
+            .find(|ext| ext.get_type() == ExtensionType::DelegatedCredential)
+            .and_then(CertificateExtension::get_delegated_credential)
+    }
 }
 
 #[derive(Debug)]
@@ -1468,7 +1689,7 @@ impl Codec for CertificatePayloadTLS13 {
     fn read(r: &mut Reader) -> Option<Self> {
         Some(Self {
             context: PayloadU8::read(r)?,
-            entries: codec::read_vec_u24_limited::<CertificateEntry>(r, 0x10000)?,
+            entries: codec::read_vec_u24_limited::<CertificateEntry>(r, 0xff_ffff)?,
         })
     }
 }
@@ -1526,6 +1747,24 @@ impl CertificatePayloadTLS13 {
             .cloned()
     }
 
+    pub fn get_end_entity_delegated_credential(&self) -> Option<DelegatedCredentialPayload> {
+        self.entries
+            .first()
+            .and_then(CertificateEntry::get_delegated_credential)
+            .cloned()
+    }
+
+    pub fn any_intermediate_has_delegated_credential(&self) -> bool {
+        self.entries
+            .iter()
+            .skip(1)
+            .any(|entry| {
+                entry
+                    .get_delegated_credential()
+                    .is_some()
+            })
+    }
+
     pub fn convert(&self) -> CertificatePayload {
         let mut ret = Vec::new();
         for entry in &self.entries {
@@ -1535,6 +1774,30 @@ impl CertificatePayloadTLS13 {
     }
 }
 
+// -- RFC8879 certificate compression --
+#[derive(Debug)]
+pub struct CompressedCertificatePayload {
+    pub alg: CertCompressionAlgorithm,
+    pub uncompressed_len: u32,
+    pub compressed: PayloadU24,
+}
+
+impl Codec for CompressedCertificatePayload {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.alg.encode(bytes);
+        codec::u24(self.uncompressed_len).encode(bytes);
+        self.compressed.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            alg: CertCompressionAlgorithm::read(r)?,
+            uncompressed_len: codec::u24::read(r)?.0,
+            compressed: PayloadU24::read(r)?,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub enum KeyExchangeAlgorithm {
     BulkOnly,
@@ -1768,6 +2031,38 @@ pub trait HasServerExtensions {
         self.find_extension(ExtensionType::EarlyData)
             .is_some()
     }
+
+    fn get_max_fragment_length(&self) -> Option<MaxFragmentLength> {
+        let ext = self.find_extension(ExtensionType::MaxFragmentLength)?;
+        match *ext {
+            ServerExtension::MaxFragmentLength(mfl) => Some(mfl),
+            _ => None,
+        }
+    }
+
+    fn get_record_size_limit(&self) -> Option<u16> {
+        let ext = self.find_extension(ExtensionType::RecordSizeLimit)?;
+        match *ext {
+            ServerExtension::RecordSizeLimit(limit) => Some(limit),
+            _ => None,
+        }
+    }
+
+    fn get_client_certificate_type(&self) -> Option<CertificateType> {
+        let ext = self.find_extension(ExtensionType::ClientCertificateType)?;
+        match *ext {
+            ServerExtension::ClientCertificateType(typ) => Some(typ),
+            _ => None,
+        }
+    }
+
+    fn get_server_certificate_type(&self) -> Option<CertificateType> {
+        let ext = self.find_extension(ExtensionType::ServerCertificateType)?;
+        match *ext {
+            ServerExtension::ServerCertificateType(typ) => Some(typ),
+            _ => None,
+        }
+    }
 }
 
 impl HasServerExtensions for EncryptedExtensions {
@@ -1817,6 +2112,7 @@ impl Codec for CertificateRequestPayload {
 pub enum CertReqExtension {
     SignatureAlgorithms(SupportedSignatureSchemes),
     AuthorityNames(DistinguishedNames),
+    CertificateCompressionAlgorithms(CertCompressionAlgorithms),
     Unknown(UnknownExtension),
 }
 
@@ -1825,6 +2121,9 @@ impl CertReqExtension {
         match *self {
             CertReqExtension::SignatureAlgorithms(_) => ExtensionType::SignatureAlgorithms,
             CertReqExtension::AuthorityNames(_) => ExtensionType::CertificateAuthorities,
+            CertReqExtension::CertificateCompressionAlgorithms(_) => {
+                ExtensionType::CompressCertificate
+            }
             CertReqExtension::Unknown(ref r) => r.typ,
         }
     }
@@ -1838,6 +2137,7 @@ impl Codec for CertReqExtension {
         match *self {
             CertReqExtension::SignatureAlgorithms(ref r) => r.encode(&mut sub),
             CertReqExtension::AuthorityNames(ref r) => r.encode(&mut sub),
+            CertReqExtension::CertificateCompressionAlgorithms(ref r) => r.encode(&mut sub),
             CertReqExtension::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -1862,6 +2162,9 @@ impl Codec for CertReqExtension {
                 let cas = DistinguishedNames::read(&mut sub)?;
                 Self::AuthorityNames(cas)
             }
+            ExtensionType::CompressCertificate => {
+                Self::CertificateCompressionAlgorithms(CertCompressionAlgorithms::read(&mut sub)?)
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -1920,6 +2223,14 @@ impl CertificateRequestPayloadTLS13 {
             _ => None,
         }
     }
+
+    pub fn get_cert_compression_extension(&self) -> Option<&CertCompressionAlgorithms> {
+        let ext = self.find_extension(ExtensionType::CompressCertificate)?;
+        match *ext {
+            CertReqExtension::CertificateCompressionAlgorithms(ref algs) => Some(algs),
+            _ => None,
+        }
+    }
 }
 
 // -- NewSessionTicket --
@@ -2140,6 +2451,7 @@ pub enum HandshakePayload {
     KeyUpdate(KeyUpdateRequest),
     Finished(Payload),
     CertificateStatus(CertificateStatus),
+    CompressedCertificate(CompressedCertificatePayload),
     MessageHash(Payload),
     Unknown(Payload),
 }
@@ -2166,6 +2478,7 @@ impl HandshakePayload {
             HandshakePayload::KeyUpdate(ref x) => x.encode(bytes),
             HandshakePayload::Finished(ref x) => x.encode(bytes),
             HandshakePayload::CertificateStatus(ref x) => x.encode(bytes),
+            HandshakePayload::CompressedCertificate(ref x) => x.encode(bytes),
             HandshakePayload::MessageHash(ref x) => x.encode(bytes),
             HandshakePayload::Unknown(ref x) => x.encode(bytes),
         }
@@ -2281,6 +2594,10 @@ impl HandshakeMessagePayload {
             HandshakeType::CertificateStatus => {
                 HandshakePayload::CertificateStatus(CertificateStatus::read(&mut sub)?)
             }
+            HandshakeType::CompressedCertificate if vers == ProtocolVersion::TLSv1_3 => {
+                let p = CompressedCertificatePayload::read(&mut sub)?;
+                HandshakePayload::CompressedCertificate(p)
+            }
             HandshakeType::MessageHash => {
                 // does not appear on the wire
                 return None;
@@ -2306,6 +2623,13 @@ impl HandshakeMessagePayload {
         }
     }
 
+    pub fn build_key_update_request() -> Self {
+        Self {
+            typ: HandshakeType::KeyUpdate,
+            payload: HandshakePayload::KeyUpdate(KeyUpdateRequest::UpdateRequested),
+        }
+    }
+
     pub fn get_encoding_for_binder_signing(&self) -> Vec<u8> {
         let mut ret = self.get_encoding();
 
@@ -364,6 +364,7 @@ fn get_sample_clienthellopayload() -> ClientHelloPayload {
             ClientExtension::ECPointFormats(ECPointFormatList::supported()),
             ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
             ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ECDSA_NISTP256_SHA256]),
+            ClientExtension::SignatureAlgorithmsCert(vec![SignatureScheme::RSA_PKCS1_SHA256]),
             ClientExtension::make_sni(DnsNameRef::try_from_ascii_str("hello").unwrap()),
             ClientExtension::SessionTicket(ClientSessionTicket::Request),
             ClientExtension::SessionTicket(ClientSessionTicket::Offer(Payload(vec![]))),
@@ -386,6 +387,19 @@ fn get_sample_clienthellopayload() -> ClientHelloPayload {
             ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()),
             ClientExtension::SignedCertificateTimestampRequest,
             ClientExtension::TransportParameters(vec![1, 2, 3]),
+            ClientExtension::CertificateCompressionAlgorithms(vec![
+                CertCompressionAlgorithm::Zlib,
+                CertCompressionAlgorithm::Brotli,
+            ]),
+            ClientExtension::MaxFragmentLength(MaxFragmentLength::Len512),
+            ClientExtension::RecordSizeLimit(1024),
+            ClientExtension::PostHandshakeAuth,
+            ClientExtension::ClientCertificateType(vec![CertificateType::RawPublicKey]),
+            ClientExtension::ServerCertificateType(vec![
+                CertificateType::RawPublicKey,
+                CertificateType::X509,
+            ]),
+            ClientExtension::DelegatedCredential(vec![SignatureScheme::ED25519]),
             ClientExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![1, 2, 3]),
@@ -513,6 +527,14 @@ fn client_get_sigalgs_extension() {
     });
 }
 
+#[test]
+fn client_get_sigalgs_cert_extension() {
+    test_client_extension_getter(ExtensionType::SignatureAlgorithmsCert, |chp| {
+        chp.get_sigalgs_cert_extension()
+            .is_some()
+    });
+}
+
 #[test]
 fn client_get_namedgroups_extension() {
     test_client_extension_getter(ExtensionType::EllipticCurves, |chp| {
@@ -569,6 +591,26 @@ fn client_get_psk_modes() {
     });
 }
 
+#[test]
+fn client_get_certificate_types() {
+    test_client_extension_getter(ExtensionType::ClientCertificateType, |chp| {
+        chp.get_client_certificate_types()
+            .is_some()
+    });
+    test_client_extension_getter(ExtensionType::ServerCertificateType, |chp| {
+        chp.get_server_certificate_types()
+            .is_some()
+    });
+}
+
+#[test]
+fn client_get_delegated_credential_schemes() {
+    test_client_extension_getter(ExtensionType::DelegatedCredential, |chp| {
+        chp.get_delegated_credential_schemes()
+            .is_some()
+    });
+}
+
 #[test]
 fn test_truncated_helloretry_extension_is_detected() {
     let hrr = get_sample_helloretryrequest();
@@ -708,6 +750,18 @@ fn server_get_sct_list() {
     test_server_extension_getter(ExtensionType::SCT, |shp| shp.get_sct_list().is_some());
 }
 
+#[test]
+fn server_get_certificate_types() {
+    test_server_extension_getter(ExtensionType::ClientCertificateType, |shp| {
+        shp.get_client_certificate_type()
+            .is_some()
+    });
+    test_server_extension_getter(ExtensionType::ServerCertificateType, |shp| {
+        shp.get_server_certificate_type()
+            .is_some()
+    });
+}
+
 #[test]
 fn server_get_supported_versions() {
     test_server_extension_getter(ExtensionType::SupportedVersions, |shp| {
@@ -746,6 +800,13 @@ fn certentry_get_scts() {
     test_cert_extension_getter(ExtensionType::SCT, |ce| ce.get_scts().is_some());
 }
 
+#[test]
+fn certentry_get_delegated_credential() {
+    test_cert_extension_getter(ExtensionType::DelegatedCredential, |ce| {
+        ce.get_delegated_credential().is_some()
+    });
+}
+
 fn get_sample_serverhellopayload() -> ServerHelloPayload {
     ServerHelloPayload {
         legacy_version: ProtocolVersion::TLSv1_2,
@@ -766,6 +827,10 @@ fn get_sample_serverhellopayload() -> ServerHelloPayload {
             ServerExtension::SignedCertificateTimestamp(vec![PayloadU16(vec![0])]),
             ServerExtension::SupportedVersions(ProtocolVersion::TLSv1_2),
             ServerExtension::TransportParameters(vec![1, 2, 3]),
+            ServerExtension::MaxFragmentLength(MaxFragmentLength::Len1024),
+            ServerExtension::RecordSizeLimit(16385),
+            ServerExtension::ClientCertificateType(CertificateType::RawPublicKey),
+            ServerExtension::ServerCertificateType(CertificateType::X509),
             ServerExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![1, 2, 3]),
@@ -811,6 +876,15 @@ fn get_sample_certificatepayloadtls13() -> CertificatePayloadTLS13 {
                     ocsp_response: PayloadU24(vec![1, 2, 3]),
                 }),
                 CertificateExtension::SignedCertificateTimestamp(vec![PayloadU16(vec![0])]),
+                CertificateExtension::DelegatedCredential(DelegatedCredentialPayload {
+                    credential: Credential {
+                        valid_time: 3600,
+                        scheme: SignatureScheme::ED25519,
+                        public_key: PayloadU24(vec![1, 2, 3]),
+                    },
+                    algorithm: SignatureScheme::ECDSA_NISTP256_SHA256,
+                    signature: PayloadU16(vec![4, 5, 6]),
+                }),
                 CertificateExtension::Unknown(UnknownExtension {
                     typ: ExtensionType::Unknown(12345),
                     payload: Payload(vec![1, 2, 3]),
@@ -854,6 +928,9 @@ fn get_sample_certificaterequestpayloadtls13() -> CertificateRequestPayloadTLS13
         extensions: vec![
             CertReqExtension::SignatureAlgorithms(vec![SignatureScheme::ECDSA_NISTP256_SHA256]),
             CertReqExtension::AuthorityNames(vec![PayloadU16(vec![1, 2, 3])]),
+            CertReqExtension::CertificateCompressionAlgorithms(vec![
+                CertCompressionAlgorithm::Zstd,
+            ]),
             CertReqExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![1, 2, 3]),
@@ -1101,6 +1178,14 @@ fn get_all_tls13_handshake_payloads() -> Vec<HandshakeMessagePayload> {
             typ: HandshakeType::CertificateStatus,
             payload: HandshakePayload::CertificateStatus(get_sample_certificatestatus()),
         },
+        HandshakeMessagePayload {
+            typ: HandshakeType::CompressedCertificate,
+            payload: HandshakePayload::CompressedCertificate(CompressedCertificatePayload {
+                alg: CertCompressionAlgorithm::Zlib,
+                uncompressed_len: 123,
+                compressed: PayloadU24(vec![1, 2, 3]),
+            }),
+        },
         HandshakeMessagePayload {
             typ: HandshakeType::Unknown(99),
             payload: HandshakePayload::Unknown(Payload(vec![1, 2, 3])),
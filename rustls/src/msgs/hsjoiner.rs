@@ -7,10 +7,21 @@ use crate::msgs::message::{Message, MessagePayload, PlainMessage};
 
 const HEADER_SIZE: usize = 1 + 3;
 
-/// TLS allows for handshake messages of up to 16MB.  We
-/// restrict that to 64KB to limit potential for denial-of-
+/// TLS allows for handshake messages of up to 16MB.  By default
+/// we restrict that to 64KB to limit potential for denial-of-
 /// service.
-const MAX_HANDSHAKE_SIZE: u32 = 0xffff;
+pub const DEFAULT_MAX_HANDSHAKE_SIZE: usize = 0xffff;
+
+/// The most records one handshake message may be spread across.  This
+/// is enough for a maximum-size message in 64 byte records, the
+/// smallest a peer can be asked to send; without a limit, a peer could
+/// keep us reassembling one message indefinitely.
+const MAX_FRAGMENTS_PER_MESSAGE: usize = 1024;
+
+/// The most complete handshake messages we hold before they are
+/// processed.  A single record can otherwise carry thousands of empty
+/// messages, each of which costs far more to hold than to send.
+const MAX_PENDING_MESSAGES: usize = 64;
 
 /// This works to reconstruct TLS handshake messages
 /// from individual TLS messages.  It's guaranteed that
@@ -22,6 +33,12 @@ pub struct HandshakeJoiner {
 
     /// The message payload we're currently accumulating.
     buf: Vec<u8>,
+
+    /// The largest handshake message body we'll accept.
+    max_message_size: usize,
+
+    /// How many records contributed to the message at the start of `buf`.
+    fragments: usize,
 }
 
 impl Default for HandshakeJoiner {
@@ -47,9 +64,18 @@ impl HandshakeJoiner {
         Self {
             frames: VecDeque::new(),
             buf: Vec::new(),
+            max_message_size: DEFAULT_MAX_HANDSHAKE_SIZE,
+            fragments: 0,
         }
     }
 
+    /// Set the largest handshake message body we'll accept.  Larger
+    /// messages are rejected as soon as their header arrives, before
+    /// their body is buffered.
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
     /// Do we want to process this message?
     pub fn want_message(&self, msg: &PlainMessage) -> bool {
         msg.typ == ContentType::Handshake
@@ -60,22 +86,34 @@ impl HandshakeJoiner {
         self.buf.is_empty()
     }
 
+    /// How much memory our buffer holds, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.buf.capacity()
+    }
+
     /// Take the message, and join/split it as needed.
     /// Return the number of new messages added to the
     /// output deque as a result of this message.
     ///
-    /// Returns None if msg or a preceding message was corrupt.
-    /// You cannot recover from this situation.  Otherwise returns
-    /// a count of how many messages we queued.
+    /// Returns None if msg or a preceding message was corrupt, or if
+    /// the peer exceeded our limits on fragmentation or on pending
+    /// messages.  You cannot recover from this situation.  Otherwise
+    /// returns a count of how many messages we queued.
     pub fn take_message(&mut self, msg: PlainMessage) -> Option<usize> {
         // The vast majority of the time `self.buf` will be empty since most
         // handshake messages arrive in a single fragment. Avoid allocating and
         // copying in that common case.
         if self.buf.is_empty() {
             self.buf = msg.payload.0;
+            self.fragments = 1;
         } else {
             self.buf
                 .extend_from_slice(&msg.payload.0[..]);
+            self.fragments += 1;
+        }
+
+        if self.fragments > MAX_FRAGMENTS_PER_MESSAGE {
+            return None;
         }
 
         let mut count = 0;
@@ -88,11 +126,17 @@ impl HandshakeJoiner {
                         return None;
                     }
 
+                    // Anything left over started in this record.
+                    self.fragments = 1;
                     count += 1;
                 }
             }
         }
 
+        if self.frames.len() > MAX_PENDING_MESSAGES {
+            return None;
+        }
+
         Some(count)
     }
 
@@ -106,7 +150,7 @@ impl HandshakeJoiner {
 
         let (header, rest) = self.buf.split_at(HEADER_SIZE);
         match codec::u24::decode(&header[1..]) {
-            Some(len) if len.0 > MAX_HANDSHAKE_SIZE => BufferState::MessageTooLarge,
+            Some(len) if usize::from(len) > self.max_message_size => BufferState::MessageTooLarge,
             Some(len) if rest.get(..len.into()).is_some() => BufferState::OneMessage,
             _ => BufferState::NeedsMoreData,
         }
@@ -139,7 +183,7 @@ impl HandshakeJoiner {
 
 #[cfg(test)]
 mod tests {
-    use super::HandshakeJoiner;
+    use super::{HandshakeJoiner, MAX_FRAGMENTS_PER_MESSAGE, MAX_PENDING_MESSAGES};
     use crate::msgs::base::Payload;
     use crate::msgs::codec::Codec;
     use crate::msgs::enums::{ContentType, HandshakeType, ProtocolVersion};
@@ -289,4 +333,77 @@ mod tests {
         assert_eq!(hj.take_message(msg), None);
         assert!(!hj.is_empty());
     }
+
+    #[test]
+    fn test_max_message_size_is_configurable() {
+        // A 0x10000 byte certificate message, just over the default limit.
+        let header = b"\x0b\x01\x00\x00".to_vec();
+        let msg = |payload: Vec<u8>| PlainMessage {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: Payload::new(payload),
+        };
+
+        let mut hj = HandshakeJoiner::new();
+        assert_eq!(hj.take_message(msg(header.clone())), None);
+
+        let mut hj = HandshakeJoiner::new();
+        hj.set_max_message_size(0x10000);
+        assert_eq!(hj.take_message(msg(header)), Some(0));
+
+        let mut hj = HandshakeJoiner::new();
+        hj.set_max_message_size(16);
+        assert_eq!(hj.take_message(msg(b"\x0b\x00\x00\x11".to_vec())), None);
+    }
+
+    fn handshake(payload: &[u8]) -> PlainMessage {
+        PlainMessage {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: Payload::new(payload.to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_rejects_message_in_too_many_fragments() {
+        // A 0x1000 byte certificate message, one byte per record.
+        let mut message = b"\x0b\x00\x10\x00".to_vec();
+        message.resize(4 + 0x1000, 0);
+
+        let mut hj = HandshakeJoiner::new();
+        for byte in &message[..MAX_FRAGMENTS_PER_MESSAGE] {
+            assert_eq!(hj.take_message(handshake(&[*byte])), Some(0));
+        }
+        let next = MAX_FRAGMENTS_PER_MESSAGE;
+        assert_eq!(hj.take_message(handshake(&message[next..next + 1])), None);
+    }
+
+    #[test]
+    fn test_fragment_limit_is_per_message() {
+        // Many HelloRequests, each split over two records, with each
+        // record finishing one message and starting the next.
+        let mut hj = HandshakeJoiner::new();
+        assert_eq!(hj.take_message(handshake(b"\x00\x00")), Some(0));
+        for _ in 0..MAX_FRAGMENTS_PER_MESSAGE * 2 {
+            assert_eq!(hj.take_message(handshake(b"\x00\x00\x00\x00")), Some(1));
+            hj.frames.pop_front().unwrap();
+        }
+        assert!(!hj.is_empty());
+    }
+
+    #[test]
+    fn test_rejects_too_many_pending_messages() {
+        // HelloRequests are empty, so many fit in one record.
+        let mut hj = HandshakeJoiner::new();
+        let hello_requests = vec![0u8; 4 * MAX_PENDING_MESSAGES];
+        assert_eq!(
+            hj.take_message(handshake(&hello_requests)),
+            Some(MAX_PENDING_MESSAGES)
+        );
+        assert_eq!(hj.take_message(handshake(&[0u8; 4])), None);
+
+        let mut hj = HandshakeJoiner::new();
+        let hello_requests = vec![0u8; 4 * (MAX_PENDING_MESSAGES + 1)];
+        assert_eq!(hj.take_message(handshake(&hello_requests)), None);
+    }
 }
@@ -73,6 +73,25 @@ impl OpaqueMessage {
     /// `MessageError` allows callers to distinguish between valid prefixes (might
     /// become valid if we read more data) and invalid data.
     pub fn read(r: &mut Reader) -> Result<Self, MessageError> {
+        let (typ, version, len) = Self::read_header(r)?;
+
+        let mut sub = r
+            .sub(len as usize)
+            .ok_or(MessageError::TooShortForLength)?;
+        let payload = Payload::read(&mut sub);
+
+        Ok(Self {
+            typ,
+            version,
+            payload,
+        })
+    }
+
+    /// Read and check the header of a message, returning its content
+    /// type, version and payload length.
+    pub(crate) fn read_header(
+        r: &mut Reader,
+    ) -> Result<(ContentType, ProtocolVersion, u16), MessageError> {
         let typ = ContentType::read(r).ok_or(MessageError::TooShortForHeader)?;
         let version = ProtocolVersion::read(r).ok_or(MessageError::TooShortForHeader)?;
         let len = u16::read(r).ok_or(MessageError::TooShortForHeader)?;
@@ -102,16 +121,7 @@ impl OpaqueMessage {
             _ => {}
         };
 
-        let mut sub = r
-            .sub(len as usize)
-            .ok_or(MessageError::TooShortForLength)?;
-        let payload = Payload::read(&mut sub);
-
-        Ok(Self {
-            typ,
-            version,
-            payload,
-        })
+        Ok((typ, version, len))
     }
 
     pub fn encode(self) -> Vec<u8> {
@@ -123,6 +133,15 @@ impl OpaqueMessage {
         buf
     }
 
+    /// Borrow this message, to decrypt it in place.
+    pub fn as_borrowed(&mut self) -> BorrowedOpaqueMessage<'_> {
+        BorrowedOpaqueMessage {
+            typ: self.typ,
+            version: self.version,
+            payload: &mut self.payload.0,
+        }
+    }
+
     /// Force conversion into a plaintext message.
     ///
     /// This should only be used for messages that are known to be in plaintext. Otherwise, the
@@ -141,7 +160,7 @@ impl OpaqueMessage {
     const MAX_PAYLOAD: u16 = 16384 + 2048;
 
     /// Content type, version and size.
-    const HEADER_SIZE: u16 = 1 + 2 + 2;
+    pub(crate) const HEADER_SIZE: u16 = 1 + 2 + 2;
 
     /// Maximum on-wire message size.
     pub const MAX_WIRE_SIZE: usize = (Self::MAX_PAYLOAD + Self::HEADER_SIZE) as usize;
@@ -229,6 +248,13 @@ impl Message {
             payload: MessagePayload::Handshake(HandshakeMessagePayload::build_key_update_notify()),
         }
     }
+
+    pub fn build_key_update_request() -> Self {
+        Self {
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::Handshake(HandshakeMessagePayload::build_key_update_request()),
+        }
+    }
 }
 
 /// Parses a plaintext message into a well-typed [`Message`].
@@ -260,6 +286,67 @@ pub struct BorrowedPlainMessage<'a> {
     pub payload: &'a [u8],
 }
 
+impl<'a> BorrowedPlainMessage<'a> {
+    pub fn to_plain_message(&self) -> PlainMessage {
+        PlainMessage {
+            typ: self.typ,
+            version: self.version,
+            payload: Payload::new(self.payload),
+        }
+    }
+}
+
+/// A TLS frame, named TLSCiphertext in the standard.
+///
+/// This type differs from `OpaqueMessage` because it borrows its
+/// payload mutably, so that it can be decrypted in place in the
+/// buffer it was received into.
+pub struct BorrowedOpaqueMessage<'a> {
+    pub typ: ContentType,
+    pub version: ProtocolVersion,
+    pub payload: &'a mut [u8],
+}
+
+impl<'a> BorrowedOpaqueMessage<'a> {
+    /// Read a message from the front of `buf`, borrowing its payload.
+    /// The message occupies the first `HEADER_SIZE + payload.len()`
+    /// bytes of `buf`.
+    ///
+    /// Errors are as for [`OpaqueMessage::read`].
+    pub fn read(buf: &'a mut [u8]) -> Result<Self, MessageError> {
+        let (typ, version, len) = OpaqueMessage::read_header(&mut Reader::init(buf))?;
+        let start = OpaqueMessage::HEADER_SIZE as usize;
+        let payload = buf
+            .get_mut(start..start + len as usize)
+            .ok_or(MessageError::TooShortForLength)?;
+
+        Ok(Self {
+            typ,
+            version,
+            payload,
+        })
+    }
+
+    /// Copy this message into an `OpaqueMessage`.
+    pub fn to_opaque_message(&self) -> OpaqueMessage {
+        OpaqueMessage {
+            typ: self.typ,
+            version: self.version,
+            payload: Payload::new(&*self.payload),
+        }
+    }
+
+    /// Force conversion into a plaintext message, as
+    /// [`OpaqueMessage::into_plain_message`] does.
+    pub fn into_plain_message(self) -> BorrowedPlainMessage<'a> {
+        BorrowedPlainMessage {
+            typ: self.typ,
+            version: self.version,
+            payload: self.payload,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum MessageError {
     TooShortForHeader,
@@ -2,7 +2,7 @@ use crate::client::ServerName;
 use crate::key;
 use crate::msgs::base::{PayloadU16, PayloadU8};
 use crate::msgs::codec::{Codec, Reader};
-use crate::msgs::enums::{CipherSuite, ProtocolVersion};
+use crate::msgs::enums::{CipherSuite, NamedGroup, ProtocolVersion};
 use crate::msgs::handshake::CertificatePayload;
 use crate::msgs::handshake::SessionID;
 use crate::suites::SupportedCipherSuite;
@@ -10,12 +10,64 @@ use crate::ticketer::TimeBase;
 #[cfg(feature = "tls12")]
 use crate::tls12::Tls12CipherSuite;
 use crate::tls13::Tls13CipherSuite;
+use crate::zeroize::zeroize;
 
 use std::cmp;
 #[cfg(feature = "tls12")]
 use std::mem;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // These are the keys and values we store in session storage.
+//
+// The values are stored by applications, possibly outside this process
+// (see `StoresClientSessions` and `StoresServerSessions`), so their
+// encoding must stay the same for every release with the same minor
+// version.  Each value starts with `SESSION_VALUE_FORMAT`; change it
+// whenever the encoding of any value changes, so that values written
+// by other versions are ignored rather than misread.
+pub const SESSION_VALUE_FORMAT: u8 = 1;
+
+/// A TLS1.3 pre-shared key made for resumption, as offered to
+/// [`StoresClientSessions::put_tls13_session`] and
+/// [`StoresServerSessions::put_tls13_session`].
+///
+/// The identity is the ticket the server issued, and is the same on
+/// both sides, so a cache shared between processes can use it as a
+/// key.  The secret is borrowed from a session value which rustls
+/// zeroizes when it is dropped; copy it only into storage which is
+/// treated as sensitive.
+///
+/// [`StoresClientSessions::put_tls13_session`]: crate::client::StoresClientSessions::put_tls13_session
+/// [`StoresServerSessions::put_tls13_session`]: crate::server::StoresServerSessions::put_tls13_session
+pub struct ResumptionPsk<'a> {
+    identity: &'a [u8],
+    secret: &'a [u8],
+}
+
+impl<'a> ResumptionPsk<'a> {
+    pub(crate) fn new(identity: &'a [u8], secret: &'a [u8]) -> Self {
+        Self { identity, secret }
+    }
+
+    /// The PSK identity: the ticket sent in NewSessionTicket, and
+    /// offered back in the `pre_shared_key` extension.
+    pub fn identity(&self) -> &'a [u8] {
+        self.identity
+    }
+
+    /// The resumption secret derived for this ticket.
+    pub fn secret(&self) -> &'a [u8] {
+        self.secret
+    }
+}
+
+impl std::fmt::Debug for ResumptionPsk<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResumptionPsk")
+            .field("identity", &crate::bs_debug::BsDebug(self.identity))
+            .finish()
+    }
+}
 
 // --- Client types ---
 /// Keys for session resumption and tickets.
@@ -62,11 +114,17 @@ pub enum ClientSessionValue {
 }
 
 impl ClientSessionValue {
-    pub fn read(
-        reader: &mut Reader<'_>,
-        suite: CipherSuite,
-        supported: &[SupportedCipherSuite],
-    ) -> Option<Self> {
+    /// Reads a value written by the `get_encoding()` method of
+    /// [`Tls13ClientSessionValue`] or [`Tls12ClientSessionValue`].
+    ///
+    /// Returns `None` for values in another format, or for a suite
+    /// not in `supported`.
+    pub fn read(reader: &mut Reader<'_>, supported: &[SupportedCipherSuite]) -> Option<Self> {
+        if u8::read(reader)? != SESSION_VALUE_FORMAT {
+            return None;
+        }
+
+        let suite = CipherSuite::read(reader)?;
         match supported
             .iter()
             .find(|s| s.suite() == suite)?
@@ -203,6 +261,7 @@ impl Tls13ClientSessionValue {
     /// (See `read()` for why this is inherent here.)
     pub fn get_encoding(&self) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(16);
+        SESSION_VALUE_FORMAT.encode(&mut bytes);
         self.suite
             .common
             .suite
@@ -284,6 +343,7 @@ impl Tls12ClientSessionValue {
     /// (See `read()` for why this is inherent here.)
     pub fn get_encoding(&self) -> Vec<u8> {
         let mut bytes = Vec::with_capacity(16);
+        SESSION_VALUE_FORMAT.encode(&mut bytes);
         self.suite
             .common
             .suite
@@ -342,6 +402,15 @@ impl ClientSessionCommon {
         }
     }
 
+    /// When this session stops being useful, or `None` if the server
+    /// didn't say.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        match self.lifetime_secs {
+            0 => None,
+            lifetime => Some(UNIX_EPOCH + Duration::from_secs(self.epoch + u64::from(lifetime))),
+        }
+    }
+
     /// [`Codec::read()`] is inherent here to avoid leaking the [`Codec`]
     /// implementation through [`Deref`] implementations on
     /// [`Tls12ClientSessionValue`] and [`Tls13ClientSessionValue`].
@@ -384,7 +453,13 @@ impl ClientSessionCommon {
     }
 }
 
-static MAX_TICKET_LIFETIME: u32 = 7 * 24 * 60 * 60;
+impl Drop for ClientSessionCommon {
+    fn drop(&mut self) {
+        zeroize(&mut self.secret.0);
+    }
+}
+
+pub(crate) static MAX_TICKET_LIFETIME: u32 = 7 * 24 * 60 * 60;
 
 /// This is the maximum allowed skew between server and client clocks, over
 /// the maximum ticket lifetime period.  This encompasses TCP retransmission
@@ -412,6 +487,7 @@ pub struct ServerSessionValue {
 
 impl Codec for ServerSessionValue {
     fn encode(&self, bytes: &mut Vec<u8>) {
+        SESSION_VALUE_FORMAT.encode(bytes);
         if let Some(ref sni) = self.sni {
             1u8.encode(bytes);
             let sni_bytes: &str = sni.as_ref().into();
@@ -442,6 +518,10 @@ impl Codec for ServerSessionValue {
     }
 
     fn read(r: &mut Reader) -> Option<Self> {
+        if u8::read(r)? != SESSION_VALUE_FORMAT {
+            return None;
+        }
+
         let has_sni = u8::read(r)?;
         let sni = if has_sni == 1 {
             let dns_name = PayloadU8::read(r)?;
@@ -538,3 +618,68 @@ impl ServerSessionValue {
         self.freshness.unwrap_or_default()
     }
 }
+
+impl Drop for ServerSessionValue {
+    fn drop(&mut self) {
+        zeroize(&mut self.master_secret.0);
+    }
+}
+
+/// The server state carried by the cookie of a stateless
+/// HelloRetryRequest, and recovered from the second ClientHello.
+#[derive(Debug)]
+pub struct RetryCookie {
+    pub creation_time_sec: u64,
+    pub cipher_suite: CipherSuite,
+    pub retry_group: Option<NamedGroup>,
+    pub client_hello_hash: PayloadU8,
+}
+
+impl Codec for RetryCookie {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.creation_time_sec.encode(bytes);
+        self.cipher_suite.encode(bytes);
+        if let Some(group) = self.retry_group {
+            1u8.encode(bytes);
+            group.encode(bytes);
+        } else {
+            0u8.encode(bytes);
+        }
+        self.client_hello_hash.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        let creation_time_sec = u64::read(r)?;
+        let cipher_suite = CipherSuite::read(r)?;
+        let has_group = u8::read(r)? == 1;
+        let retry_group = if has_group {
+            Some(NamedGroup::read(r)?)
+        } else {
+            None
+        };
+        let client_hello_hash = PayloadU8::read(r)?;
+
+        Some(Self {
+            creation_time_sec,
+            cipher_suite,
+            retry_group,
+            client_hello_hash,
+        })
+    }
+}
+
+impl RetryCookie {
+    pub fn new(
+        creation_time_sec: u64,
+        cs: CipherSuite,
+        retry_group: Option<NamedGroup>,
+        client_hello_hash: Vec<u8>,
+    ) -> Self {
+        Self {
+            creation_time_sec,
+            cipher_suite: cs,
+            retry_group,
+            client_hello_hash: PayloadU8::new(client_hello_hash),
+        }
+    }
+}
@@ -4,7 +4,7 @@ use super::persist::*;
 
 use crate::key::Certificate;
 use crate::ticketer::TimeBase;
-use crate::tls13::TLS13_AES_128_GCM_SHA256;
+use crate::tls13::{TLS13_AES_128_GCM_SHA256, TLS13_CHACHA20_POLY1305_SHA256};
 
 use std::convert::TryInto;
 
@@ -58,8 +58,8 @@ fn serversessionvalue_is_debug() {
 #[test]
 fn serversessionvalue_no_sni() {
     let bytes = [
-        0x00, 0x03, 0x03, 0xc0, 0x23, 0x03, 0x01, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12,
-        0x23, 0x34, 0x45, 0x56, 0x67, 0x78, 0x89, 0xfe, 0xed, 0xf0, 0x0d,
+        0x01, 0x00, 0x03, 0x03, 0xc0, 0x23, 0x03, 0x01, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x12, 0x23, 0x34, 0x45, 0x56, 0x67, 0x78, 0x89, 0xfe, 0xed, 0xf0, 0x0d,
     ];
     let mut rd = Reader::init(&bytes);
     let ssv = ServerSessionValue::read(&mut rd).unwrap();
@@ -69,10 +69,82 @@ fn serversessionvalue_no_sni() {
 #[test]
 fn serversessionvalue_with_cert() {
     let bytes = [
-        0x00, 0x03, 0x03, 0xc0, 0x23, 0x03, 0x01, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x12,
-        0x23, 0x34, 0x45, 0x56, 0x67, 0x78, 0x89, 0xfe, 0xed, 0xf0, 0x0d,
+        0x01, 0x00, 0x03, 0x03, 0xc0, 0x23, 0x03, 0x01, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x12, 0x23, 0x34, 0x45, 0x56, 0x67, 0x78, 0x89, 0xfe, 0xed, 0xf0, 0x0d,
     ];
     let mut rd = Reader::init(&bytes);
     let ssv = ServerSessionValue::read(&mut rd).unwrap();
     assert_eq!(ssv.get_encoding(), bytes);
 }
+
+#[test]
+fn serversessionvalue_in_other_format_is_not_read() {
+    let mut bytes = vec![
+        0x01, 0x00, 0x03, 0x03, 0xc0, 0x23, 0x03, 0x01, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x12, 0x23, 0x34, 0x45, 0x56, 0x67, 0x78, 0x89, 0xfe, 0xed, 0xf0, 0x0d,
+    ];
+    assert!(ServerSessionValue::read_bytes(&bytes).is_some());
+    bytes[0] = 0x02;
+    assert!(ServerSessionValue::read_bytes(&bytes).is_none());
+}
+
+#[test]
+fn clientsessionvalue_roundtrip() {
+    let suite = TLS13_AES_128_GCM_SHA256
+        .tls13()
+        .unwrap();
+    let value = Tls13ClientSessionValue::new(
+        suite,
+        vec![4, 5, 6],
+        vec![1, 2, 3],
+        vec![Certificate(b"abc".to_vec())],
+        TimeBase::now().unwrap(),
+        15,
+        10,
+        128,
+    );
+    let bytes = value.get_encoding();
+    assert_eq!(bytes[0], SESSION_VALUE_FORMAT);
+
+    let mut rd = Reader::init(&bytes);
+    let read = ClientSessionValue::read(&mut rd, &[TLS13_AES_128_GCM_SHA256]).unwrap();
+    assert!(!rd.any_left());
+    match read {
+        ClientSessionValue::Tls13(read) => assert_eq!(read.get_encoding(), bytes),
+        #[cfg(feature = "tls12")]
+        ClientSessionValue::Tls12(_) => panic!("read TLS1.2 session value"),
+    }
+
+    // unsupported suite
+    let mut rd = Reader::init(&bytes);
+    assert!(ClientSessionValue::read(&mut rd, &[TLS13_CHACHA20_POLY1305_SHA256]).is_none());
+
+    // other format
+    let mut other = bytes.clone();
+    other[0] = SESSION_VALUE_FORMAT + 1;
+    let mut rd = Reader::init(&other);
+    assert!(ClientSessionValue::read(&mut rd, &[TLS13_AES_128_GCM_SHA256]).is_none());
+
+    // truncated
+    let mut rd = Reader::init(&bytes[..2]);
+    assert!(ClientSessionValue::read(&mut rd, &[TLS13_AES_128_GCM_SHA256]).is_none());
+}
+
+#[test]
+fn retrycookie_roundtrip() {
+    let cookie = RetryCookie::new(
+        0x1234_5678,
+        CipherSuite::TLS13_AES_128_GCM_SHA256,
+        Some(NamedGroup::X25519),
+        vec![1, 2, 3],
+    );
+    let bytes = cookie.get_encoding();
+    let mut rd = Reader::init(&bytes);
+    let read = RetryCookie::read(&mut rd).unwrap();
+    assert!(!rd.any_left());
+    assert_eq!(read.creation_time_sec, 0x1234_5678);
+    assert_eq!(read.cipher_suite, CipherSuite::TLS13_AES_128_GCM_SHA256);
+    assert_eq!(read.retry_group, Some(NamedGroup::X25519));
+    assert_eq!(read.client_hello_hash.0, vec![1, 2, 3]);
+    assert_eq!(read.get_encoding(), bytes);
+}
@@ -0,0 +1,259 @@
+//! Decryption of PBES2-encrypted PKCS#8 private keys (RFC8018).
+
+use crate::error::Error;
+use crate::x509::{read_tlv, TAG_INTEGER, TAG_OCTET_STRING, TAG_OID};
+use crate::zeroize::Zeroizing;
+
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockDecryptMut, KeyIvInit};
+use ring::io::der;
+use ring::{aead, pbkdf2};
+
+use std::num::NonZeroU32;
+
+// DER-encoded object identifier contents for the algorithms we know about.
+const OID_PBES2: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05, 0x0d];
+const OID_PBKDF2: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05, 0x0c];
+const OID_HMAC_WITH_SHA1: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x07];
+const OID_HMAC_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x09];
+const OID_HMAC_WITH_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x0a];
+const OID_HMAC_WITH_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x0b];
+const OID_AES128_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x02];
+const OID_AES192_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x16];
+const OID_AES256_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a];
+const OID_AES128_GCM: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x06];
+const OID_AES256_GCM: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2e];
+
+enum Scheme<'a> {
+    Cbc { key_len: usize, iv: &'a [u8] },
+    Gcm(&'static aead::Algorithm, &'a [u8]),
+}
+
+impl Scheme<'_> {
+    fn key_len(&self) -> usize {
+        match self {
+            Self::Cbc { key_len, .. } => *key_len,
+            Self::Gcm(alg, _) => alg.key_len(),
+        }
+    }
+}
+
+/// Decrypt the DER-encoded `EncryptedPrivateKeyInfo` in `der`, using
+/// `passphrase`, and return the DER-encoded PKCS#8 `PrivateKeyInfo`
+/// inside it.
+pub(crate) fn decrypt(der: &[u8], passphrase: &[u8]) -> Result<Vec<u8>, Error> {
+    let malformed = || invalid("malformed encrypted private key");
+
+    let (info, _) = read_tlv(der, der::Tag::Sequence as u8).ok_or_else(malformed)?;
+    let (algorithm, rest) = read_tlv(info, der::Tag::Sequence as u8).ok_or_else(malformed)?;
+    let (encrypted, _) = read_tlv(rest, TAG_OCTET_STRING).ok_or_else(malformed)?;
+
+    let (oid, params) = read_tlv(algorithm, TAG_OID).ok_or_else(malformed)?;
+    if oid != OID_PBES2 {
+        return Err(invalid("unsupported private key encryption scheme"));
+    }
+    let (params, _) = read_tlv(params, der::Tag::Sequence as u8).ok_or_else(malformed)?;
+    let (kdf, rest) = read_tlv(params, der::Tag::Sequence as u8).ok_or_else(malformed)?;
+    let (scheme, _) = read_tlv(rest, der::Tag::Sequence as u8).ok_or_else(malformed)?;
+
+    let scheme = parse_scheme(scheme)?;
+    let mut key = Zeroizing::new(vec![0u8; scheme.key_len()]);
+    derive_key(kdf, passphrase, &mut key)?;
+
+    let mut plain = Zeroizing::new(encrypted.to_vec());
+    let plain_len = match scheme {
+        Scheme::Cbc { key_len, iv } => decrypt_cbc(key_len, &key[..], iv, &mut plain[..])?,
+        Scheme::Gcm(alg, nonce) => {
+            let key = aead::LessSafeKey::new(aead::UnboundKey::new(alg, &key[..]).unwrap());
+            let nonce = aead::Nonce::try_assume_unique_for_key(nonce).unwrap();
+            key.open_in_place(nonce, aead::Aad::empty(), &mut plain[..])
+                .map_err(|_| wrong_passphrase())?
+                .len()
+        }
+    };
+
+    // CBC padding alone will sometimes let a wrong passphrase through,
+    // so check we got something that looks like a PrivateKeyInfo.
+    let plain = &plain[..plain_len];
+    match read_tlv(plain, der::Tag::Sequence as u8) {
+        Some((contents, rest)) if rest.is_empty() && read_tlv(contents, TAG_INTEGER).is_some() => {
+            Ok(plain.to_vec())
+        }
+        _ => Err(wrong_passphrase()),
+    }
+}
+
+fn parse_scheme(scheme: &[u8]) -> Result<Scheme<'_>, Error> {
+    let malformed = || invalid("malformed encrypted private key");
+    let (oid, params) = read_tlv(scheme, TAG_OID).ok_or_else(malformed)?;
+
+    let key_len = match oid {
+        OID_AES128_CBC => 16,
+        OID_AES192_CBC => 24,
+        OID_AES256_CBC => 32,
+        OID_AES128_GCM | OID_AES256_GCM => {
+            let alg = match oid {
+                OID_AES128_GCM => &aead::AES_128_GCM,
+                _ => &aead::AES_256_GCM,
+            };
+
+            // We can only check 16 byte tags, so the (12 byte)
+            // default for aes-ICVlen is not supported.
+            let (params, _) = read_tlv(params, der::Tag::Sequence as u8).ok_or_else(malformed)?;
+            let (nonce, rest) = read_tlv(params, TAG_OCTET_STRING).ok_or_else(malformed)?;
+            match read_tlv(rest, TAG_INTEGER) {
+                Some(([16], _)) if nonce.len() == aead::NONCE_LEN => {}
+                _ => return Err(invalid("unsupported AES-GCM parameters")),
+            }
+            return Ok(Scheme::Gcm(alg, nonce));
+        }
+        _ => return Err(invalid("unsupported private key cipher")),
+    };
+
+    match read_tlv(params, TAG_OCTET_STRING) {
+        Some((iv, _)) if iv.len() == 16 => Ok(Scheme::Cbc { key_len, iv }),
+        _ => Err(malformed()),
+    }
+}
+
+/// Fill `key` from `passphrase` using the PBKDF2 parameters in `kdf`.
+fn derive_key(kdf: &[u8], passphrase: &[u8], key: &mut [u8]) -> Result<(), Error> {
+    let malformed = || invalid("malformed encrypted private key");
+
+    let (oid, params) = read_tlv(kdf, TAG_OID).ok_or_else(malformed)?;
+    if oid != OID_PBKDF2 {
+        return Err(invalid("unsupported private key derivation function"));
+    }
+
+    let (params, _) = read_tlv(params, der::Tag::Sequence as u8).ok_or_else(malformed)?;
+    let (salt, rest) = read_tlv(params, TAG_OCTET_STRING).ok_or_else(malformed)?;
+    let (iterations, rest) = read_tlv(rest, TAG_INTEGER).ok_or_else(malformed)?;
+    let iterations = read_u32(iterations)
+        .and_then(NonZeroU32::new)
+        .ok_or_else(malformed)?;
+
+    // keyLength is optional, but must match the cipher if present.
+    let rest = match read_tlv(rest, TAG_INTEGER) {
+        Some((key_len, rest)) if read_u32(key_len) == Some(key.len() as u32) => rest,
+        Some(_) => return Err(malformed()),
+        None => rest,
+    };
+
+    // The PRF defaults to hmacWithSHA1.
+    let prf = match read_tlv(rest, der::Tag::Sequence as u8) {
+        Some((prf, _)) => {
+            read_tlv(prf, TAG_OID)
+                .ok_or_else(malformed)?
+                .0
+        }
+        None => OID_HMAC_WITH_SHA1,
+    };
+    let prf = match prf {
+        OID_HMAC_WITH_SHA1 => pbkdf2::PBKDF2_HMAC_SHA1,
+        OID_HMAC_WITH_SHA256 => pbkdf2::PBKDF2_HMAC_SHA256,
+        OID_HMAC_WITH_SHA384 => pbkdf2::PBKDF2_HMAC_SHA384,
+        OID_HMAC_WITH_SHA512 => pbkdf2::PBKDF2_HMAC_SHA512,
+        _ => return Err(invalid("unsupported PBKDF2 pseudorandom function")),
+    };
+
+    pbkdf2::derive(prf, iterations, salt, passphrase, key);
+    Ok(())
+}
+
+/// Decrypt and unpad `buf` in place, returning the plaintext length.
+fn decrypt_cbc(key_len: usize, key: &[u8], iv: &[u8], buf: &mut [u8]) -> Result<usize, Error> {
+    let plain = match key_len {
+        16 => cbc::Decryptor::<aes::Aes128>::new_from_slices(key, iv)
+            .unwrap()
+            .decrypt_padded_mut::<Pkcs7>(buf),
+        24 => cbc::Decryptor::<aes::Aes192>::new_from_slices(key, iv)
+            .unwrap()
+            .decrypt_padded_mut::<Pkcs7>(buf),
+        _ => cbc::Decryptor::<aes::Aes256>::new_from_slices(key, iv)
+            .unwrap()
+            .decrypt_padded_mut::<Pkcs7>(buf),
+    };
+    plain
+        .map(|plain| plain.len())
+        .map_err(|_| wrong_passphrase())
+}
+
+/// Read a non-negative DER INTEGER's contents, if it fits in a `u32`.
+fn read_u32(bytes: &[u8]) -> Option<u32> {
+    let bytes = match bytes {
+        [0, rest @ ..] if !rest.is_empty() => rest,
+        [first, ..] if first & 0x80 == 0 => bytes,
+        _ => return None,
+    };
+    if bytes.len() > 4 {
+        return None;
+    }
+    Some(
+        bytes
+            .iter()
+            .fold(0u32, |acc, b| (acc << 8) | *b as u32),
+    )
+}
+
+fn invalid(why: &str) -> Error {
+    Error::InvalidPrivateKey(why.to_string())
+}
+
+fn wrong_passphrase() -> Error {
+    invalid("wrong passphrase, or corrupt encrypted private key")
+}
+
+#[cfg(test)]
+mod test {
+    use super::decrypt;
+    use crate::Error;
+
+    const PLAIN: &[u8] = include_bytes!("testdata/ecdsa-p521.pk8");
+    const AES128_CBC_SHA1: &[u8] = include_bytes!("testdata/ecdsa-p521.aes128cbc-sha1.pk8");
+    const AES256_CBC_SHA256: &[u8] = include_bytes!("testdata/ecdsa-p521.aes256cbc-sha256.pk8");
+    const AES256_GCM_SHA256: &[u8] = include_bytes!("testdata/ecdsa-p521.aes256gcm-sha256.pk8");
+
+    #[test]
+    fn test_decrypt_aes128_cbc() {
+        assert_eq!(decrypt(AES128_CBC_SHA1, b"rustls").unwrap(), PLAIN);
+    }
+
+    #[test]
+    fn test_decrypt_aes256_cbc() {
+        assert_eq!(decrypt(AES256_CBC_SHA256, b"rustls").unwrap(), PLAIN);
+    }
+
+    #[test]
+    fn test_decrypt_aes256_gcm() {
+        assert_eq!(decrypt(AES256_GCM_SHA256, b"rustls").unwrap(), PLAIN);
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        for der in &[AES128_CBC_SHA1, AES256_CBC_SHA256, AES256_GCM_SHA256] {
+            assert_eq!(
+                decrypt(der, b"not rustls"),
+                Err(Error::InvalidPrivateKey(
+                    "wrong passphrase, or corrupt encrypted private key".into()
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn test_unencrypted_key() {
+        assert_eq!(
+            decrypt(PLAIN, b"rustls"),
+            Err(Error::InvalidPrivateKey(
+                "malformed encrypted private key".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_truncated_key() {
+        for len in 0..AES256_CBC_SHA256.len() {
+            assert!(decrypt(&AES256_CBC_SHA256[..len], b"rustls").is_err());
+        }
+    }
+}
@@ -1,12 +1,13 @@
 /// This module contains optional APIs for implementing QUIC TLS.
-use crate::cipher::{Iv, IvLen};
+use crate::cipher::Iv;
 pub use crate::client::ClientQuicExt;
 use crate::conn::CommonState;
+use crate::crypto;
 use crate::error::Error;
 use crate::msgs::enums::AlertDescription;
 pub use crate::server::ServerQuicExt;
-use crate::suites::BulkAlgorithm;
-use crate::tls13::key_schedule::hkdf_expand;
+use crate::suites::{BulkAlgorithm, SupportedCipherSuite};
+use crate::tls13::key_schedule::Secret;
 use crate::tls13::{Tls13CipherSuite, TLS13_AES_128_GCM_SHA256_INTERNAL};
 
 use ring::{aead, hkdf};
@@ -25,19 +26,40 @@ pub struct Secrets {
 
 impl Secrets {
     pub(crate) fn new(
-        client: hkdf::Prk,
-        server: hkdf::Prk,
+        client: Secret,
+        server: Secret,
         suite: &'static Tls13CipherSuite,
         is_client: bool,
     ) -> Self {
         Self {
-            client,
-            server,
+            client: ring_secret(&client),
+            server: ring_secret(&server),
             suite,
             is_client,
         }
     }
 
+    /// Derive the secrets for initial packets, as specified in RFC9001
+    /// section 5.2.
+    pub fn initial(version: Version, client_dst_connection_id: &[u8], is_client: bool) -> Self {
+        const CLIENT_LABEL: &[u8] = b"client in";
+        const SERVER_LABEL: &[u8] = b"server in";
+        let salt = version.initial_salt();
+        let hs_secret = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(client_dst_connection_id);
+
+        Self {
+            client: hkdf_expand(&hs_secret, hkdf::HKDF_SHA256, CLIENT_LABEL, &[]),
+            server: hkdf_expand(&hs_secret, hkdf::HKDF_SHA256, SERVER_LABEL, &[]),
+            suite: TLS13_AES_128_GCM_SHA256_INTERNAL,
+            is_client,
+        }
+    }
+
+    /// Derive the header protection and packet keys for these secrets.
+    pub fn keys(&self) -> Keys {
+        Keys::new(self)
+    }
+
     /// Derive the next set of packet keys
     pub fn next_packet_keys(&mut self) -> PacketKeySet {
         let keys = PacketKeySet::new(self);
@@ -46,7 +68,7 @@ impl Secrets {
     }
 
     fn update(&mut self) {
-        let hkdf_alg = self.suite.hkdf_algorithm;
+        let hkdf_alg = crypto::ring::hkdf_algorithm(self.suite.hash);
         self.client = hkdf_expand(&self.client, hkdf_alg, b"quic ku", &[]);
         self.server = hkdf_expand(&self.server, hkdf_alg, b"quic ku", &[]);
     }
@@ -71,9 +93,30 @@ pub trait QuicExt {
     /// to fail.
     fn quic_transport_parameters(&self) -> Option<&[u8]>;
 
+    /// Return the peer's raw transport parameters, exactly as they were
+    /// received in the ClientHello or EncryptedExtensions.
+    ///
+    /// This is the same as [`QuicExt::quic_transport_parameters()`]: rustls
+    /// carries the parameters as an opaque blob and leaves encoding and
+    /// decoding them to the QUIC implementation.
+    fn get_quic_transport_parameters(&self) -> Option<&[u8]> {
+        self.quic_transport_parameters()
+    }
+
     /// Compute the keys for encrypting/decrypting 0-RTT packets, if available
+    ///
+    /// A client stops returning keys once the server has rejected 0-RTT.
     fn zero_rtt_keys(&self) -> Option<DirectionalKeys>;
 
+    /// Whether the server accepted 0-RTT data.
+    ///
+    /// This is `None` until it is known: for a client, once the server's
+    /// EncryptedExtensions have been read; for a server, once the ClientHello
+    /// has been read.  If this is `Some(false)` after a client has sent 0-RTT
+    /// packets, they were discarded and their data must be sent again with
+    /// the 1-RTT keys.
+    fn zero_rtt_accepted(&self) -> Option<bool>;
+
     /// Consume unencrypted TLS handshake data.
     ///
     /// Handshake data obtained from separate encryption levels should be supplied in separate calls.
@@ -112,12 +155,7 @@ pub struct HeaderProtectionKey(aead::quic::HeaderProtectionKey);
 
 impl HeaderProtectionKey {
     fn new(suite: &'static Tls13CipherSuite, secret: &hkdf::Prk) -> Self {
-        let alg = match suite.common.bulk {
-            BulkAlgorithm::Aes128Gcm => &aead::quic::AES_128,
-            BulkAlgorithm::Aes256Gcm => &aead::quic::AES_256,
-            BulkAlgorithm::Chacha20Poly1305 => &aead::quic::CHACHA20,
-        };
-
+        let (alg, _) = algorithms(suite.common.bulk).expect("suite checked for QUIC support");
         Self(hkdf_expand(secret, alg, b"quic hp", &[]))
     }
 
@@ -257,7 +295,9 @@ impl PacketKey {
         Self {
             key: aead::LessSafeKey::new(hkdf_expand(
                 secret,
-                suite.common.aead_algorithm,
+                algorithms(suite.common.bulk)
+                    .expect("suite checked for QUIC support")
+                    .1,
                 b"quic key",
                 &[],
             )),
@@ -374,18 +414,11 @@ pub struct Keys {
 impl Keys {
     /// Construct keys for use with initial packets
     pub fn initial(version: Version, client_dst_connection_id: &[u8], is_client: bool) -> Self {
-        const CLIENT_LABEL: &[u8] = b"client in";
-        const SERVER_LABEL: &[u8] = b"server in";
-        let salt = version.initial_salt();
-        let hs_secret = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(client_dst_connection_id);
-
-        let secrets = Secrets {
-            client: hkdf_expand(&hs_secret, hkdf::HKDF_SHA256, CLIENT_LABEL, &[]),
-            server: hkdf_expand(&hs_secret, hkdf::HKDF_SHA256, SERVER_LABEL, &[]),
-            suite: TLS13_AES_128_GCM_SHA256_INTERNAL,
+        Self::new(&Secrets::initial(
+            version,
+            client_dst_connection_id,
             is_client,
-        };
-        Self::new(&secrets)
+        ))
     }
 
     fn new(secrets: &Secrets) -> Self {
@@ -411,6 +444,7 @@ pub(crate) fn write_hs(this: &mut CommonState, buf: &mut Vec<u8>) -> Option<KeyC
     if let Some(secrets) = this.quic.hs_secrets.take() {
         return Some(KeyChange::Handshake {
             keys: Keys::new(&secrets),
+            secrets,
         });
     }
 
@@ -442,12 +476,17 @@ pub(crate) fn write_hs(this: &mut CommonState, buf: &mut Vec<u8>) -> Option<KeyC
 /// Once the 1-RTT keys have been exchanged, either side may initiate a key update. Progressive
 /// update keys can be obtained from the [`Secrets`] returned in [`KeyChange::OneRtt`]. Note that
 /// only packet keys are updated by key updates; header protection keys remain the same.
+///
+/// Callers that derive packet protection keys themselves can use the [`Secrets`] carried
+/// by each change, and [`Secrets::initial()`] for the initial space.
 #[allow(clippy::large_enum_variant)]
 pub enum KeyChange {
     /// Keys for the handshake space
     Handshake {
         /// Header and packet keys for the handshake space
         keys: Keys,
+        /// Secrets the handshake keys were derived from
+        secrets: Secrets,
     },
     /// Keys for 1-RTT data
     OneRtt {
@@ -458,6 +497,109 @@ pub enum KeyChange {
     },
 }
 
+impl KeyChange {
+    /// The encryption level these keys are for.
+    pub fn level(&self) -> Level {
+        match self {
+            Self::Handshake { .. } => Level::Handshake,
+            Self::OneRtt { .. } => Level::OneRtt,
+        }
+    }
+}
+
+/// QUIC encryption levels, in the order their keys become available.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Initial packets, protected with keys from [`Keys::initial()`]
+    Initial,
+    /// 0-RTT packets, protected with keys from [`QuicExt::zero_rtt_keys()`]
+    ZeroRtt,
+    /// Handshake packets, protected with keys from [`KeyChange::Handshake`]
+    Handshake,
+    /// 1-RTT packets, protected with keys from [`KeyChange::OneRtt`]
+    OneRtt,
+}
+
+/// Packet protection is done with *ring* regardless of the configured
+/// `CryptoProvider`, so traffic secrets are moved across here.
+pub(crate) fn ring_secret(secret: &Secret) -> hkdf::Prk {
+    hkdf::Prk::new_less_safe(
+        crypto::ring::hkdf_algorithm(secret.algorithm()),
+        secret.value(),
+    )
+}
+
+/// The header and packet protection algorithms for `bulk`, or `None`
+/// if *ring* cannot protect QUIC packets with it.
+fn algorithms(
+    bulk: BulkAlgorithm,
+) -> Option<(&'static aead::quic::Algorithm, &'static aead::Algorithm)> {
+    match bulk {
+        BulkAlgorithm::Aes128Gcm => Some((&aead::quic::AES_128, &aead::AES_128_GCM)),
+        BulkAlgorithm::Aes256Gcm => Some((&aead::quic::AES_256, &aead::AES_256_GCM)),
+        BulkAlgorithm::Chacha20Poly1305 => Some((&aead::quic::CHACHA20, &aead::CHACHA20_POLY1305)),
+        BulkAlgorithm::Aes128Ccm | BulkAlgorithm::Aes128Ccm8 => None,
+    }
+}
+
+/// Fail if any TLS1.3 suite in `suites` could be negotiated but not
+/// used to protect QUIC packets.  This is checked when a QUIC connection
+/// is made, so key derivation never meets such a suite.
+pub(crate) fn check_cipher_suites(suites: &[SupportedCipherSuite]) -> Result<(), Error> {
+    match suites
+        .iter()
+        .filter_map(SupportedCipherSuite::tls13)
+        .find(|suite| algorithms(suite.common.bulk).is_none())
+    {
+        Some(suite) => Err(Error::General(format!(
+            "{:?} is not supported for QUIC",
+            suite.common.suite
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn hkdf_expand<T, L>(secret: &hkdf::Prk, key_type: L, label: &[u8], context: &[u8]) -> T
+where
+    T: for<'a> From<hkdf::Okm<'a, L>>,
+    L: hkdf::KeyType,
+{
+    const LABEL_PREFIX: &[u8] = b"tls13 ";
+
+    let output_len = u16::to_be_bytes(key_type.len() as u16);
+    let label_len = u8::to_be_bytes((LABEL_PREFIX.len() + label.len()) as u8);
+    let context_len = u8::to_be_bytes(context.len() as u8);
+
+    let info = &[
+        &output_len[..],
+        &label_len[..],
+        LABEL_PREFIX,
+        label,
+        &context_len[..],
+        context,
+    ];
+    secret
+        .expand(info, key_type)
+        .unwrap()
+        .into()
+}
+
+struct IvLen;
+
+impl hkdf::KeyType for IvLen {
+    fn len(&self) -> usize {
+        aead::NONCE_LEN
+    }
+}
+
+impl From<hkdf::Okm<'_, IvLen>> for Iv {
+    fn from(okm: hkdf::Okm<IvLen>) -> Self {
+        let mut r = Self(Default::default());
+        okm.fill(&mut r.0[..]).unwrap();
+        r
+    }
+}
+
 /// Compute the nonce to use for encrypting or decrypting `packet_number`
 fn nonce_for(packet_number: u64, iv: &Iv) -> ring::aead::Nonce {
     let mut out = [0; aead::NONCE_LEN];
@@ -554,6 +696,32 @@ mod test {
         assert_eq!(plain, &PLAIN[4..]);
     }
 
+    #[test]
+    fn ccm_suites_are_rejected() {
+        use crate::msgs::enums::CipherSuite;
+        use crate::suites::CipherSuiteCommon;
+        use crate::tls13::TLS13_AES_128_GCM_SHA256;
+
+        static TLS13_AES_128_CCM_SHA256: SupportedCipherSuite =
+            SupportedCipherSuite::Tls13(&Tls13CipherSuite {
+                common: CipherSuiteCommon {
+                    suite: CipherSuite::TLS13_AES_128_CCM_SHA256,
+                    bulk: BulkAlgorithm::Aes128Ccm,
+                },
+                hash: crate::crypto::HashAlgorithm::Sha256,
+                confidentiality_limit: 1 << 23,
+                integrity_limit: 1 << 52,
+            });
+
+        assert_eq!(check_cipher_suites(&[TLS13_AES_128_GCM_SHA256]), Ok(()));
+        assert_eq!(
+            check_cipher_suites(&[TLS13_AES_128_GCM_SHA256, TLS13_AES_128_CCM_SHA256]),
+            Err(Error::General(
+                "TLS13_AES_128_CCM_SHA256 is not supported for QUIC".into()
+            ))
+        );
+    }
+
     #[test]
     fn key_update_test_vector() {
         fn equal_prk(x: &hkdf::Prk, y: &hkdf::Prk) -> bool {
@@ -1,29 +1,55 @@
+use crate::error::Error;
 use crate::msgs::codec;
 /// The single place where we generate random material
-/// for our own use.  These functions never fail,
-/// they panic on error.
-use ring::rand::{SecureRandom, SystemRandom};
+/// for our own use.
+use ring::rand::SystemRandom;
 
-/// Fill the whole slice with random material.
+/// A source of cryptographically secure random bytes.
+///
+/// rustls uses this for the random values in hello messages, session
+/// IDs, TLS1.3 ticket nonces, and the Kyber768 half of hybrid key
+/// exchanges.  The default, [`DefaultSecureRandom`], uses *ring*'s
+/// `SystemRandom`.  Tests might provide a deterministic one, to make
+/// handshakes reproducible.
+///
+/// *ring* generates ephemeral ECDH keys and signatures using its own
+/// system RNG, which cannot be replaced, so handshakes using those are
+/// still not byte-for-byte reproducible.
+pub trait SecureRandom: Send + Sync {
+    /// Fill the whole of `buf` with random bytes.
+    ///
+    /// On failure, return [`Error::FailedToGetRandomBytes`]: the
+    /// handshake then fails with this error.
+    fn fill(&self, buf: &mut [u8]) -> Result<(), Error>;
+}
+
+/// SecureRandom that uses the operating system's RNG, through *ring*.
+pub struct DefaultSecureRandom;
+
+impl SecureRandom for DefaultSecureRandom {
+    fn fill(&self, buf: &mut [u8]) -> Result<(), Error> {
+        fill_random(buf).map_err(|_| Error::FailedToGetRandomBytes)
+    }
+}
+
+/// Fill the whole slice with random material from the system RNG.
 pub(crate) fn fill_random(bytes: &mut [u8]) -> Result<(), GetRandomFailed> {
-    SystemRandom::new()
-        .fill(bytes)
-        .map_err(|_| GetRandomFailed)
+    ring::rand::SecureRandom::fill(&SystemRandom::new(), bytes).map_err(|_| GetRandomFailed)
 }
 
 /// Make a Vec<u8> of the given size
 /// containing random material.
-pub(crate) fn random_vec(len: usize) -> Result<Vec<u8>, GetRandomFailed> {
+pub(crate) fn random_vec(rng: &dyn SecureRandom, len: usize) -> Result<Vec<u8>, Error> {
     let mut v = vec![0; len];
-    fill_random(&mut v)?;
+    rng.fill(&mut v)?;
     Ok(v)
 }
 
 /// Return a uniformly random u32.
-pub(crate) fn random_u32() -> Result<u32, GetRandomFailed> {
+pub(crate) fn random_u32(rng: &dyn SecureRandom) -> Result<u32, Error> {
     let mut buf = [0u8; 4];
-    fill_random(&mut buf)?;
-    codec::decode_u32(&buf).ok_or(GetRandomFailed)
+    rng.fill(&mut buf)?;
+    codec::decode_u32(&buf).ok_or(Error::FailedToGetRandomBytes)
 }
 
 #[derive(Debug)]
@@ -1,10 +1,52 @@
-use crate::cipher::{MessageDecrypter, MessageEncrypter};
+use crate::cipher::{MessageDecrypter, MessageEncrypter, PaddingPolicy};
 use crate::error::Error;
-use crate::msgs::message::{BorrowedPlainMessage, OpaqueMessage, PlainMessage};
+use crate::msgs::fragmenter::MAX_FRAGMENT_LEN;
+use crate::msgs::message::{BorrowedOpaqueMessage, BorrowedPlainMessage, OpaqueMessage};
 
+use std::cmp;
+
+/// We send a close_notify alert once our sequence number reaches this.
 static SEQ_SOFT_LIMIT: u64 = 0xffff_ffff_ffff_0000u64;
+
+/// We refuse to encrypt any record with a sequence number this large,
+/// so it never wraps and a nonce is never reused.  This only matters
+/// for TLS1.2, which has no way to change keys: TLS1.3 connections
+/// update their keys long before.
 static SEQ_HARD_LIMIT: u64 = 0xffff_ffff_ffff_fffeu64;
 
+/// We change TLS1.3 keys before sending this many messages with one,
+/// which is within the limits for all AEADs in RFC8446 section 5.5.
+static SEQ_KEY_UPDATE_LIMIT: u64 = 1 << 24;
+
+/// How many records a connection has encrypted and decrypted, and how
+/// much data they carried, as returned by
+/// [`CommonState::get_record_counters`](crate::CommonState::get_record_counters).
+///
+/// These count every protected record over the life of the connection,
+/// including those carrying handshake messages and alerts, but not the
+/// unprotected records which start the handshake.  Byte counts are of
+/// the data in each record, not counting its header, padding or
+/// authentication tag.
+///
+/// In TLS1.2 a connection never changes keys, so the record counts are
+/// also the sequence numbers: a connection is closed before either
+/// reaches 2^64.  In TLS1.3 sequence numbers start again with each
+/// KeyUpdate.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RecordCounters {
+    /// The number of records we have encrypted.
+    pub records_encrypted: u64,
+
+    /// The number of bytes of data in the records we have encrypted.
+    pub bytes_encrypted: u64,
+
+    /// The number of records we have decrypted.
+    pub records_decrypted: u64,
+
+    /// The number of bytes of data in the records we have decrypted.
+    pub bytes_decrypted: u64,
+}
+
 #[derive(PartialEq)]
 enum DirectionState {
     /// No keying material.
@@ -29,6 +71,51 @@ pub(crate) struct RecordLayer {
     // should be swallowed by the caller.  This struct tracks the amount
     // of message size this is allowed for.
     trial_decryption_len: Option<usize>,
+
+    // The largest plaintext we accept in one record, which is less
+    // than the TLS maximum if we asked the peer for smaller records.
+    max_inbound_fragment_len: usize,
+
+    // Whether we are decrypting TLS1.3 0-RTT data, whose records
+    // aren't subject to limits negotiated in this handshake.
+    decrypting_early_data: bool,
+
+    // How to pad outgoing records, where the protocol allows it.
+    padding: RecordPadding,
+
+    counters: RecordCounters,
+}
+
+/// A [`PaddingPolicy`], kept small since every connection has one.
+///
+/// Records are much shorter than `u16::MAX` bytes, so padding them to
+/// a larger length has the same effect as padding to `u16::MAX`.
+#[derive(Clone, Copy)]
+enum RecordPadding {
+    None,
+    Multiple(u16),
+    Fixed(u16),
+}
+
+impl From<PaddingPolicy> for RecordPadding {
+    fn from(policy: PaddingPolicy) -> Self {
+        let clamp = |len: usize| cmp::min(len, usize::from(u16::MAX)) as u16;
+        match policy {
+            PaddingPolicy::None => Self::None,
+            PaddingPolicy::PadToMultiple(n) => Self::Multiple(clamp(n)),
+            PaddingPolicy::PadToFixed(n) => Self::Fixed(clamp(n)),
+        }
+    }
+}
+
+impl From<RecordPadding> for PaddingPolicy {
+    fn from(padding: RecordPadding) -> Self {
+        match padding {
+            RecordPadding::None => Self::None,
+            RecordPadding::Multiple(n) => Self::PadToMultiple(n.into()),
+            RecordPadding::Fixed(n) => Self::PadToFixed(n.into()),
+        }
+    }
 }
 
 impl RecordLayer {
@@ -41,9 +128,27 @@ impl RecordLayer {
             encrypt_state: DirectionState::Invalid,
             decrypt_state: DirectionState::Invalid,
             trial_decryption_len: None,
+            max_inbound_fragment_len: MAX_FRAGMENT_LEN,
+            decrypting_early_data: false,
+            padding: RecordPadding::None,
+            counters: RecordCounters::default(),
         }
     }
 
+    /// Pad future outgoing records according to `padding`, where
+    /// the protocol version allows it.
+    pub(crate) fn set_padding(&mut self, padding: Option<PaddingPolicy>) {
+        self.padding = padding
+            .unwrap_or(PaddingPolicy::None)
+            .into();
+    }
+
+    /// Refuse incoming records with more than `len` bytes of plaintext,
+    /// other than TLS1.3 0-RTT data.
+    pub(crate) fn limit_inbound_fragment_len(&mut self, len: usize) {
+        self.max_inbound_fragment_len = cmp::min(len, MAX_FRAGMENT_LEN);
+    }
+
     pub(crate) fn is_encrypting(&self) -> bool {
         self.encrypt_state == DirectionState::Active
     }
@@ -79,6 +184,7 @@ impl RecordLayer {
         self.message_decrypter = cipher;
         self.read_seq = 0;
         self.decrypt_state = DirectionState::Prepared;
+        self.decrypting_early_data = false;
     }
 
     /// Start using the `MessageEncrypter` previously provided to the previous
@@ -110,6 +216,13 @@ impl RecordLayer {
         self.trial_decryption_len = None;
     }
 
+    /// Set and start using the given `MessageDecrypter` for TLS1.3 0-RTT
+    /// data.  Any inbound limit applies again from the next decrypter.
+    pub(crate) fn set_early_data_decrypter(&mut self, cipher: Box<dyn MessageDecrypter>) {
+        self.set_message_decrypter(cipher);
+        self.decrypting_early_data = true;
+    }
+
     /// Set and start using the given `MessageDecrypter` for future incoming
     /// message decryption, and enable "trial decryption" mode for when TLS1.3
     /// 0-RTT is attempted but rejected by the server.
@@ -145,38 +258,200 @@ impl RecordLayer {
         self.write_seq == SEQ_SOFT_LIMIT
     }
 
+    /// Return true if we have encrypted `threshold` messages with our
+    /// current encryption key, or are getting close to the limit for
+    /// any one TLS1.3 key.
+    pub(crate) fn wants_key_update(&self, threshold: Option<u64>) -> bool {
+        let limit = threshold.map_or(SEQ_KEY_UPDATE_LIMIT, |threshold| {
+            threshold.clamp(1, SEQ_KEY_UPDATE_LIMIT)
+        });
+        self.is_encrypting() && self.write_seq >= limit
+    }
+
     /// Return true if we outright refuse to do anything with the
     /// encryption key.
     pub(crate) fn encrypt_exhausted(&self) -> bool {
         self.write_seq >= SEQ_HARD_LIMIT
     }
 
+    pub(crate) fn counters(&self) -> RecordCounters {
+        self.counters
+    }
+
     /// Decrypt a TLS message.
     ///
     /// `encr` is a decoded message allegedly received from the peer.
-    /// If it can be decrypted, its decryption is returned.  Otherwise,
-    /// an error is returned.
-    pub(crate) fn decrypt_incoming(&mut self, encr: OpaqueMessage) -> Result<PlainMessage, Error> {
+    /// If it can be decrypted, it is decrypted in place and its
+    /// plaintext returned.  Otherwise, an error is returned.
+    pub(crate) fn decrypt_incoming<'a>(
+        &mut self,
+        encr: BorrowedOpaqueMessage<'a>,
+    ) -> Result<BorrowedPlainMessage<'a>, Error> {
         debug_assert!(self.is_decrypting());
         let seq = self.read_seq;
         let msg = self
             .message_decrypter
-            .decrypt(encr, seq)?;
+            .decrypt_in_place(encr, seq)?;
+        if !self.decrypting_early_data && msg.payload.len() > self.max_inbound_fragment_len {
+            return Err(Error::PeerSentOversizedRecord);
+        }
         self.read_seq += 1;
+        self.counters.records_decrypted += 1;
+        self.counters.bytes_decrypted += msg.payload.len() as u64;
         Ok(msg)
     }
 
     /// Encrypt a TLS message.
     ///
-    /// `plain` is a TLS message we'd like to send.  This function
+    /// `plain` is a TLS message we'd like to send.  Padding may make
+    /// its fragment up to `max_fragment_len` bytes long.  This function
     /// panics if the requisite keying material hasn't been established yet.
-    pub(crate) fn encrypt_outgoing(&mut self, plain: BorrowedPlainMessage) -> OpaqueMessage {
+    ///
+    /// Once the sequence number reaches its limit, this fails with
+    /// `Error::EncryptError` rather than reuse a nonce.
+    pub(crate) fn encrypt_outgoing(
+        &mut self,
+        plain: BorrowedPlainMessage,
+        max_fragment_len: usize,
+    ) -> Result<OpaqueMessage, Error> {
         debug_assert!(self.encrypt_state == DirectionState::Active);
-        assert!(!self.encrypt_exhausted());
+        if self.encrypt_exhausted() {
+            return Err(Error::EncryptError);
+        }
         let seq = self.write_seq;
         self.write_seq += 1;
-        self.message_encrypter
-            .encrypt(plain, seq)
-            .unwrap()
+        self.counters.records_encrypted += 1;
+        self.counters.bytes_encrypted += plain.payload.len() as u64;
+        Ok(self
+            .message_encrypter
+            .encrypt_padded(plain, seq, self.padding.into(), max_fragment_len)
+            .unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msgs::base::Payload;
+    use crate::msgs::enums::{ContentType, ProtocolVersion};
+
+    #[test]
+    fn key_update_threshold() {
+        let mut record_layer = RecordLayer::new();
+        assert!(!record_layer.wants_key_update(Some(1)));
+
+        record_layer.set_message_encrypter(<dyn MessageEncrypter>::invalid());
+        assert!(!record_layer.wants_key_update(Some(1)));
+        record_layer.write_seq = 1;
+        assert!(record_layer.wants_key_update(Some(1)));
+        assert!(!record_layer.wants_key_update(Some(2)));
+        assert!(!record_layer.wants_key_update(None));
+    }
+
+    #[test]
+    fn key_update_forced_near_sequence_limit() {
+        let mut record_layer = RecordLayer::new();
+        record_layer.set_message_encrypter(<dyn MessageEncrypter>::invalid());
+
+        record_layer.write_seq = SEQ_KEY_UPDATE_LIMIT - 1;
+        assert!(!record_layer.wants_key_update(None));
+        assert!(!record_layer.wants_key_update(Some(u64::MAX)));
+
+        record_layer.write_seq = SEQ_KEY_UPDATE_LIMIT;
+        assert!(record_layer.wants_key_update(None));
+        assert!(record_layer.wants_key_update(Some(u64::MAX)));
+    }
+
+    /// Encrypts a record by replacing its contents with the sequence number.
+    struct SequenceEncrypter;
+
+    impl MessageEncrypter for SequenceEncrypter {
+        fn encrypt(&self, m: BorrowedPlainMessage, seq: u64) -> Result<OpaqueMessage, Error> {
+            Ok(OpaqueMessage {
+                typ: m.typ,
+                version: m.version,
+                payload: Payload::new(seq.to_be_bytes().to_vec()),
+            })
+        }
+    }
+
+    #[test]
+    fn encryption_stops_before_sequence_number_wraps() {
+        let mut record_layer = RecordLayer::new();
+        record_layer.set_message_encrypter(Box::new(SequenceEncrypter));
+        let plain = || BorrowedPlainMessage {
+            typ: ContentType::ApplicationData,
+            version: ProtocolVersion::TLSv1_2,
+            payload: b"hello",
+        };
+
+        record_layer.write_seq = SEQ_SOFT_LIMIT;
+        assert!(record_layer.wants_close_before_encrypt());
+
+        record_layer.write_seq = SEQ_HARD_LIMIT - 1;
+        assert!(!record_layer.encrypt_exhausted());
+        let last = record_layer
+            .encrypt_outgoing(plain(), 16384)
+            .unwrap();
+        assert_eq!(last.payload.0, (SEQ_HARD_LIMIT - 1).to_be_bytes());
+
+        assert!(record_layer.encrypt_exhausted());
+        for _ in 0..3 {
+            assert_eq!(
+                record_layer
+                    .encrypt_outgoing(plain(), 16384)
+                    .unwrap_err(),
+                Error::EncryptError
+            );
+            assert_eq!(record_layer.write_seq, SEQ_HARD_LIMIT);
+        }
+    }
+
+    #[test]
+    fn record_padding_pads_like_its_policy() {
+        let max_len = 16384 + 1 + 16;
+        for policy in &[
+            PaddingPolicy::None,
+            PaddingPolicy::PadToMultiple(0),
+            PaddingPolicy::PadToMultiple(1),
+            PaddingPolicy::PadToMultiple(256),
+            PaddingPolicy::PadToMultiple(100_000),
+            PaddingPolicy::PadToFixed(1024),
+            PaddingPolicy::PadToFixed(usize::MAX),
+        ] {
+            let padding = PaddingPolicy::from(RecordPadding::from(*policy));
+            for &len in &[17, 200, 1024, 5000, max_len] {
+                assert_eq!(
+                    padding.padding_len(len, max_len),
+                    policy.padding_len(len, max_len)
+                );
+            }
+        }
+
+        assert_eq!(PaddingPolicy::None.padding_len(17, max_len), 0);
+        assert_eq!(
+            PaddingPolicy::PadToMultiple(256).padding_len(17, max_len),
+            239
+        );
+        assert_eq!(
+            PaddingPolicy::PadToMultiple(256).padding_len(256, max_len),
+            0
+        );
+        assert_eq!(
+            PaddingPolicy::PadToMultiple(256).padding_len(16390, max_len),
+            11
+        );
+        assert_eq!(
+            PaddingPolicy::PadToFixed(1024).padding_len(17, max_len),
+            1007
+        );
+        assert_eq!(
+            PaddingPolicy::PadToFixed(1024).padding_len(2000, max_len),
+            0
+        );
+        assert_eq!(
+            PaddingPolicy::PadToFixed(usize::MAX).padding_len(17, 64),
+            47
+        );
     }
 }
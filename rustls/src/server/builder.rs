@@ -1,13 +1,17 @@
 use crate::builder::{ConfigBuilder, WantsVerifier};
+use crate::check;
+use crate::crypto::ring::Ring;
 use crate::error::Error;
 use crate::key;
 use crate::kx::SupportedKxGroup;
+use crate::msgs::hsjoiner::DEFAULT_MAX_HANDSHAKE_SIZE;
+use crate::rand::DefaultSecureRandom;
 use crate::server::handy;
 use crate::server::{ResolvesServerCert, ServerConfig};
 use crate::suites::SupportedCipherSuite;
 use crate::verify;
 use crate::versions;
-use crate::NoKeyLog;
+use crate::{DefaultTimeProvider, NoKeyLog};
 
 use std::marker::PhantomData;
 use std::sync::Arc;
@@ -59,7 +63,8 @@ impl ConfigBuilder<ServerConfig, WantsServerCert> {
     /// `cert_chain` is a vector of DER-encoded certificates.
     /// `key_der` is a DER-encoded RSA, ECDSA, or Ed25519 private key.
     ///
-    /// This function fails if `key_der` is invalid.
+    /// This function fails if `key_der` is invalid, or is not the key
+    /// for the end-entity certificate.
     pub fn with_single_cert(
         self,
         cert_chain: Vec<key::Certificate>,
@@ -79,7 +84,8 @@ impl ConfigBuilder<ServerConfig, WantsServerCert> {
     /// `scts` is an `SignedCertificateTimestampList` encoding (see RFC6962)
     /// and is ignored if empty.
     ///
-    /// This function fails if `key_der` is invalid.
+    /// This function fails if `key_der` is invalid, or is not the key
+    /// for the end-entity certificate.
     pub fn with_single_cert_with_ocsp_and_sct(
         self,
         cert_chain: Vec<key::Certificate>,
@@ -95,18 +101,40 @@ impl ConfigBuilder<ServerConfig, WantsServerCert> {
     /// Sets a custom [`ResolvesServerCert`].
     pub fn with_cert_resolver(self, cert_resolver: Arc<dyn ResolvesServerCert>) -> ServerConfig {
         ServerConfig {
-            cipher_suites: self.state.cipher_suites,
+            cipher_suites: self.state.cipher_suites.into(),
+            signature_schemes: None,
             kx_groups: self.state.kx_groups,
             verifier: self.state.verifier,
+            raw_public_key_verifier: None,
+            time_provider: Arc::new(DefaultTimeProvider),
+            secure_random: Arc::new(DefaultSecureRandom),
+            crypto_provider: Arc::new(Ring),
             cert_resolver,
+            client_hello_inspector: None,
             ignore_client_order: false,
             max_fragment_size: None,
+            record_size_limit: None,
+            max_handshake_message_size: DEFAULT_MAX_HANDSHAKE_SIZE,
+            max_cert_chain_length: check::DEFAULT_MAX_CERT_CHAIN_LENGTH,
+            max_cert_size: check::DEFAULT_MAX_CERT_SIZE,
+            require_ems: false,
+            record_padding: None,
+            key_update_threshold: None,
+            limit_outgoing_plaintext: None,
+            on_handshake_complete: None,
             session_storage: handy::ServerSessionMemoryCache::new(256),
             ticketer: Arc::new(handy::NeverProducesTickets {}),
+            send_tls13_tickets: 1,
+            stateless_retry: Arc::new(handy::NeverProducesTickets {}),
             alpn_protocols: Vec::new(),
+            alpn_required: false,
             versions: self.state.versions,
             key_log: Arc::new(NoKeyLog {}),
             max_early_data_size: 0,
+            early_data_replay_detector: None,
+            cert_compressors: Vec::new(),
+            cert_decompressors: Vec::new(),
+            defer_client_auth: false,
         }
     }
 }
@@ -7,6 +7,7 @@ use crate::sign;
 
 use std::collections;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 /// Something which never stores sessions.
 pub struct NoServerSessionStorage {}
@@ -27,42 +28,76 @@ impl server::StoresServerSessions for NoServerSessionStorage {
 }
 
 /// An implementer of `StoresServerSessions` that stores everything
-/// in memory.  If enforces a limit on the number of stored sessions
-/// to bound memory usage.
+/// in memory.  It enforces a limit on the number of stored sessions
+/// to bound memory usage, evicting the least recently used session
+/// first.  Sessions older than the cache's TTL are never returned.
+///
+/// This is the default [`ServerConfig::session_storage`](crate::ServerConfig::session_storage),
+/// and is safe to share between connections on many threads.
 pub struct ServerSessionMemoryCache {
-    cache: Mutex<limited_cache::LimitedCache<Vec<u8>, Vec<u8>>>,
+    cache: Mutex<limited_cache::LimitedCache<Vec<u8>, CacheEntry>>,
+    ttl: Duration,
+}
+
+struct CacheEntry {
+    value: Vec<u8>,
+    expires: SystemTime,
 }
 
 impl ServerSessionMemoryCache {
+    /// How long sessions are kept by [`ServerSessionMemoryCache::new`].
+    ///
+    /// This matches the lifetime we advertise for TLS1.3 tickets
+    /// that refer to stored sessions.
+    pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
     /// Make a new ServerSessionMemoryCache.  `size` is the maximum
-    /// number of stored sessions, and may be rounded-up for
-    /// efficiency.
+    /// number of stored sessions.  Sessions expire after
+    /// [`ServerSessionMemoryCache::DEFAULT_TTL`].
     pub fn new(size: usize) -> Arc<Self> {
+        Self::with_ttl(size, Self::DEFAULT_TTL)
+    }
+
+    /// Like [`ServerSessionMemoryCache::new`], but sessions
+    /// expire `ttl` after they are stored.
+    pub fn with_ttl(size: usize, ttl: Duration) -> Arc<Self> {
+        debug_assert!(size > 0);
         Arc::new(Self {
             cache: Mutex::new(limited_cache::LimitedCache::new(size)),
+            ttl,
         })
     }
 }
 
 impl server::StoresServerSessions for ServerSessionMemoryCache {
     fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        let expires = SystemTime::now() + self.ttl;
         self.cache
             .lock()
             .unwrap()
-            .insert(key, value);
+            .insert(key, CacheEntry { value, expires });
         true
     }
 
     fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.cache
-            .lock()
-            .unwrap()
+        let mut cache = self.cache.lock().unwrap();
+        if cache.get_and_freshen(key)?.expires <= SystemTime::now() {
+            cache.remove(key);
+            return None;
+        }
+
+        cache
             .get(key)
-            .cloned()
+            .map(|entry| entry.value.clone())
     }
 
     fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.cache.lock().unwrap().remove(key)
+        let entry = self.cache.lock().unwrap().remove(key)?;
+        if entry.expires <= SystemTime::now() {
+            return None;
+        }
+
+        Some(entry.value)
     }
 
     fn can_cache(&self) -> bool {
@@ -100,7 +135,9 @@ impl AlwaysResolvesChain {
     ) -> Result<Self, Error> {
         let key = sign::any_supported_type(priv_key)
             .map_err(|_| Error::General("invalid private key".into()))?;
-        Ok(Self(Arc::new(sign::CertifiedKey::new(chain, key))))
+        let certified_key = sign::CertifiedKey::new(chain, key);
+        certified_key.keys_match()?;
+        Ok(Self(Arc::new(certified_key)))
     }
 
     /// Creates an `AlwaysResolvesChain`, auto-detecting the underlying private
@@ -153,12 +190,14 @@ impl ResolvesServerCertUsingSni {
     ///
     /// This function fails if `name` is not a valid DNS name, or if
     /// it's not valid for the supplied certificate, or if the certificate
-    /// chain is syntactically faulty.
+    /// chain is syntactically faulty, or if the key doesn't match the
+    /// certificate.
     pub fn add(&mut self, name: &str, ck: sign::CertifiedKey) -> Result<(), Error> {
         let checked_name = webpki::DnsNameRef::try_from_ascii_str(name)
             .map_err(|_| Error::General("Bad DNS name".into()))?;
 
         ck.cross_check_end_entity_cert(Some(checked_name))?;
+        ck.keys_match()?;
         self.by_name
             .insert(name.into(), Arc::new(ck));
         Ok(())
@@ -246,6 +285,62 @@ mod test {
         assert!(count < 5);
     }
 
+    #[test]
+    fn test_serversessionmemorycache_evicts_least_recently_used() {
+        let c = ServerSessionMemoryCache::new(3);
+        assert!(c.put(vec![0x01], vec![0x02]));
+        assert!(c.put(vec![0x03], vec![0x04]));
+        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+        assert!(c.put(vec![0x05], vec![0x06]));
+
+        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+        assert_eq!(c.get(&[0x03]), None);
+        assert_eq!(c.get(&[0x05]), Some(vec![0x06]));
+    }
+
+    #[test]
+    fn test_serversessionmemorycache_does_not_return_expired_sessions() {
+        let c = ServerSessionMemoryCache::with_ttl(4, Duration::from_secs(0));
+        assert!(c.put(vec![0x01], vec![0x02]));
+        assert!(c.put(vec![0x03], vec![0x04]));
+        assert_eq!(c.get(&[0x01]), None);
+        assert_eq!(c.take(&[0x03]), None);
+
+        let c = ServerSessionMemoryCache::with_ttl(4, Duration::from_secs(3600));
+        assert!(c.put(vec![0x01], vec![0x02]));
+        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+        assert_eq!(c.take(&[0x01]), Some(vec![0x02]));
+        assert_eq!(c.take(&[0x01]), None);
+    }
+
+    #[test]
+    fn test_serversessionmemorycache_is_usable_from_many_threads() {
+        let c = ServerSessionMemoryCache::new(16);
+        let threads = (0..4u8)
+            .map(|i| {
+                let c = Arc::clone(&c);
+                std::thread::spawn(move || {
+                    for j in 0..100u8 {
+                        assert!(c.put(vec![i, j], vec![j]));
+                        // Other threads' puts may already have evicted it.
+                        if let Some(value) = c.get(&[i, j]) {
+                            assert_eq!(value, vec![j]);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let stored = (0..4u8)
+            .flat_map(|i| (0..100u8).map(move |j| vec![i, j]))
+            .filter(|key| c.get(key).is_some())
+            .count();
+        assert!(stored <= 16);
+    }
+
     #[test]
     fn test_neverproducestickets_does_nothing() {
         let npt = NeverProducesTickets {};
@@ -259,7 +354,7 @@ mod test {
     fn test_resolvesservercertusingsni_requires_sni() {
         let rscsni = ResolvesServerCertUsingSni::new();
         assert!(rscsni
-            .resolve(ClientHello::new(&None, &[], None))
+            .resolve(ClientHello::new(&None, &[], None, None, &[]))
             .is_none());
     }
 
@@ -270,7 +365,7 @@ mod test {
             .unwrap()
             .to_owned();
         assert!(rscsni
-            .resolve(ClientHello::new(&Some(name), &[], None))
+            .resolve(ClientHello::new(&Some(name), &[], None, None, &[]))
             .is_none());
     }
 }
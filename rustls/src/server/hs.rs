@@ -7,13 +7,14 @@ use crate::log::{debug, trace};
 use crate::msgs::enums::CipherSuite;
 use crate::msgs::enums::{AlertDescription, Compression, ExtensionType};
 use crate::msgs::enums::{HandshakeType, ProtocolVersion, SignatureScheme};
+use crate::msgs::fragmenter;
 #[cfg(feature = "tls12")]
 use crate::msgs::handshake::SessionID;
 use crate::msgs::handshake::{ClientHelloPayload, Random, ServerExtension};
 use crate::msgs::handshake::{ConvertProtocolNameList, ConvertServerNameList, HandshakePayload};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
-use crate::server::{ClientHello, ServerConfig};
+use crate::server::{ClientHello, ClientHelloDecision, ServerConfig};
 use crate::suites;
 use crate::SupportedCipherSuite;
 
@@ -86,7 +87,10 @@ impl ExtensionProcessing {
         extra_exts: Vec<ServerExtension>,
     ) -> Result<(), Error> {
         // ALPN
-        let our_protocols = &config.alpn_protocols;
+        let our_protocols = match &cx.data.alpn_protocols {
+            Some(protocols) => &protocols[..],
+            None => &config.alpn_protocols[..],
+        };
         let maybe_their_protocols = hello.get_alpn_extension();
         if let Some(their_protocols) = maybe_their_protocols {
             let their_protocols = their_protocols.to_slices();
@@ -108,7 +112,7 @@ impl ExtensionProcessing {
                 debug!("Chosen ALPN protocol {:?}", selected_protocol);
                 self.exts
                     .push(ServerExtension::make_alpn(&[selected_protocol]));
-            } else if !our_protocols.is_empty() {
+            } else if config.alpn_required && !our_protocols.is_empty() {
                 cx.common
                     .send_fatal_alert(AlertDescription::NoApplicationProtocol);
                 return Err(Error::NoApplicationProtocol);
@@ -144,6 +148,57 @@ impl ExtensionProcessing {
             }
         }
 
+        // RFC8449 record_size_limit.  This replaces max_fragment_length,
+        // which we must ignore if both are offered.
+        let record_size_limit = hello.get_record_size_limit();
+        if let Some(limit) = record_size_limit {
+            if limit < fragmenter::MIN_RECORD_SIZE_LIMIT {
+                return Err(cx
+                    .common
+                    .illegal_param("client sent too-small record_size_limit"));
+            }
+
+            let is_tls13 = cx.common.is_tls13();
+            let len = fragmenter::record_size_limit_to_fragment_len(limit, is_tls13);
+            cx.common.limit_max_fragment_len(len);
+
+            let max_ours = match is_tls13 {
+                true => fragmenter::MAX_RECORD_SIZE_LIMIT,
+                false => fragmenter::MAX_RECORD_SIZE_LIMIT - 1,
+            };
+            let ours = config
+                .record_size_limit
+                .map_or(max_ours, |ours| ours.min(max_ours));
+            debug!("Client record_size_limit is {}, ours is {}", limit, ours);
+            cx.common
+                .limit_inbound_fragment_len(fragmenter::record_size_limit_to_fragment_len(
+                    ours, is_tls13,
+                ));
+            self.exts
+                .push(ServerExtension::RecordSizeLimit(ours));
+        }
+
+        // RFC6066 max_fragment_length.  Once we've replied, we must limit
+        // every record we send, starting with this flight, and refuse
+        // larger ones from the client.
+        if let (Some(mfl), None) = (hello.get_max_fragment_length(), record_size_limit) {
+            match mfl.fragment_len() {
+                Some(len) => {
+                    debug!("Negotiated max_fragment_length {}", len);
+                    cx.common.limit_max_fragment_len(len);
+                    cx.common
+                        .limit_inbound_fragment_len(len);
+                    self.exts
+                        .push(ServerExtension::MaxFragmentLength(mfl));
+                }
+                None => {
+                    return Err(cx
+                        .common
+                        .illegal_param("client sent invalid max_fragment_length"));
+                }
+            }
+        }
+
         let for_resume = resumedata.is_some();
         // SNI
         if !for_resume && hello.get_sni_extension().is_some() {
@@ -270,11 +325,66 @@ impl ExpectClientHello {
     /// Continues handling of a `ClientHello` message once config and certificate are available.
     pub(super) fn with_certified_key(
         self,
-        sig_schemes: Vec<SignatureScheme>,
+        mut sig_schemes: Vec<SignatureScheme>,
         client_hello: &ClientHelloPayload,
         m: &Message,
         cx: &mut ServerContext<'_>,
     ) -> NextStateOrError {
+        sig_schemes.retain(|scheme| {
+            self.config
+                .allows_signature_scheme(*scheme)
+        });
+
+        let decision = match &self.config.client_hello_inspector {
+            Some(inspector) => inspector.on_client_hello(&ClientHello::new(
+                &cx.data.sni,
+                &sig_schemes,
+                client_hello
+                    .get_sigalgs_cert_extension()
+                    .map(Vec::as_slice),
+                client_hello.get_alpn_extension(),
+                &client_hello.cipher_suites,
+            )),
+            None => ClientHelloDecision::Continue,
+        };
+
+        let mut selected_certified_key = None;
+        match decision {
+            ClientHelloDecision::Continue => {}
+            ClientHelloDecision::Reject(desc) => {
+                cx.common.send_fatal_alert(desc);
+                return Err(Error::General(
+                    "ClientHello rejected by client_hello_inspector".to_string(),
+                ));
+            }
+            ClientHelloDecision::Select {
+                alpn_protocol,
+                certified_key,
+            } => {
+                if let Some(protocol) = &alpn_protocol {
+                    let offered = client_hello
+                        .get_alpn_extension()
+                        .map_or(false, |protocols| {
+                            protocols
+                                .to_slices()
+                                .contains(&protocol.as_slice())
+                        });
+                    if !offered {
+                        cx.common
+                            .send_fatal_alert(AlertDescription::NoApplicationProtocol);
+                        return Err(Error::General(
+                            "selected ALPN protocol was not offered by client".to_string(),
+                        ));
+                    }
+                }
+                cx.data.alpn_protocols = alpn_protocol.map(|protocol| vec![protocol]);
+                selected_certified_key = certified_key;
+            }
+            ClientHelloDecision::UseAlpnProtocols(protocols) => {
+                cx.data.alpn_protocols = Some(protocols);
+            }
+        }
+
         let tls13_enabled = self
             .config
             .supports_version(ProtocolVersion::TLSv1_3);
@@ -313,6 +423,19 @@ impl ExpectClientHello {
             ProtocolVersion::TLSv1_2
         };
 
+        if version != ProtocolVersion::TLSv1_3
+            && self
+                .config
+                .cert_resolver
+                .only_raw_public_keys()
+        {
+            cx.common
+                .send_fatal_alert(AlertDescription::UnsupportedCertificate);
+            return Err(Error::PeerIncompatibleError(
+                "raw public keys are only supported in TLS1.3".to_string(),
+            ));
+        }
+
         cx.common.negotiated_version = Some(version);
 
         // Choose a certificate.
@@ -320,13 +443,18 @@ impl ExpectClientHello {
             let client_hello = ClientHello::new(
                 &cx.data.sni,
                 &sig_schemes,
+                client_hello
+                    .get_sigalgs_cert_extension()
+                    .map(Vec::as_slice),
                 client_hello.get_alpn_extension(),
+                &client_hello.cipher_suites,
             );
 
-            let certkey = self
-                .config
-                .cert_resolver
-                .resolve(client_hello);
+            let certkey = selected_certified_key.or_else(|| {
+                self.config
+                    .cert_resolver
+                    .resolve(client_hello)
+            });
 
             certkey.ok_or_else(|| {
                 cx.common
@@ -334,6 +462,29 @@ impl ExpectClientHello {
                 Error::General("no server certificate chain resolved".to_string())
             })?
         };
+        if certkey
+            .ticket_lifetime
+            .map_or(false, |lifetime| lifetime > persist::MAX_TICKET_LIFETIME)
+        {
+            cx.common
+                .send_fatal_alert(AlertDescription::InternalError);
+            return Err(Error::General(
+                "resolved certificate has a ticket lifetime over 7 days".to_string(),
+            ));
+        }
+        #[cfg(feature = "quic")]
+        if cx.common.is_quic() {
+            if let Some(sz) = certkey.max_early_data_size {
+                if sz != 0 && sz != 0xffff_ffff {
+                    cx.common
+                        .send_fatal_alert(AlertDescription::InternalError);
+                    return Err(Error::General(
+                        "QUIC sessions must set a max early data of 0 or 2^32-1".to_string(),
+                    ));
+                }
+            }
+        }
+        cx.common.server_certified_key = Some(Arc::clone(&certkey));
         let certkey = ActiveCertifiedKey::from_certified_key(&certkey);
 
         // Reduce our supported ciphersuites by the certificate.
@@ -344,6 +495,10 @@ impl ExpectClientHello {
         // And version
         let suitable_suites = suites::reduce_given_version(&suitable_suites, version);
 
+        // And what our crypto provider can do
+        let suitable_suites =
+            suites::reduce_given_provider(&suitable_suites, &*self.config.crypto_provider);
+
         let suite = if self.config.ignore_client_order {
             suites::choose_ciphersuite_preferring_server(
                 &client_hello.cipher_suites,
@@ -361,9 +516,11 @@ impl ExpectClientHello {
         cx.common.suite = Some(suite);
 
         // Start handshake hash.
-        let starting_hash = suite.hash_algorithm();
+        let starting_hash = suite.hash();
         let transcript = match self.transcript {
-            HandshakeHashOrBuffer::Buffer(inner) => inner.start_hash(starting_hash),
+            HandshakeHashOrBuffer::Buffer(inner) => {
+                inner.start_hash(Arc::clone(&self.config.crypto_provider), starting_hash)
+            }
             HandshakeHashOrBuffer::Hash(inner) if inner.algorithm() == starting_hash => inner,
             _ => {
                 return Err(cx
@@ -373,7 +530,10 @@ impl ExpectClientHello {
         };
 
         // Save their Random.
-        let randoms = ConnectionRandoms::new(client_hello.random, Random::new()?);
+        let randoms = ConnectionRandoms::new(
+            client_hello.random,
+            Random::new(&*self.config.secure_random)?,
+        );
         match suite {
             SupportedCipherSuite::Tls13(suite) => tls13::CompleteClientHelloHandling {
                 config: self.config,
@@ -419,6 +579,13 @@ impl State<ServerConnectionData> for ExpectClientHello {
         )?;
         self.with_certified_key(sig_schemes, client_hello, &m, cx)
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        match &self.transcript {
+            HandshakeHashOrBuffer::Hash(hash) => Some(hash),
+            HandshakeHashOrBuffer::Buffer(_) => None,
+        }
+    }
 }
 
 /// Configuration-independent validation of a `ClientHello` message.
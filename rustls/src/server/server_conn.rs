@@ -1,21 +1,29 @@
 use crate::builder::{ConfigBuilder, WantsCipherSuites};
-use crate::conn::{CommonState, ConnectionCommon, Side, State};
+use crate::cipher::PaddingPolicy;
+use crate::compress;
+use crate::conn::{CommonState, ConnectionCommon, HandshakeCompleteCallback, Side, State};
+use crate::crypto::CryptoProvider;
 use crate::error::Error;
+use crate::key;
 use crate::kx::SupportedKxGroup;
 #[cfg(feature = "logging")]
 use crate::log::trace;
 use crate::msgs::base::{Payload, PayloadU8};
-#[cfg(feature = "quic")]
 use crate::msgs::enums::AlertDescription;
-use crate::msgs::enums::ProtocolVersion;
 use crate::msgs::enums::SignatureScheme;
+use crate::msgs::enums::{CipherSuite, ProtocolVersion};
+use crate::msgs::fragmenter;
 use crate::msgs::handshake::{ClientHelloPayload, ServerExtension};
 use crate::msgs::message::Message;
+use crate::msgs::persist::ResumptionPsk;
+use crate::rand::SecureRandom;
 use crate::sign;
-use crate::suites::SupportedCipherSuite;
+use crate::suites::{self, SupportedCipherSuite};
+use crate::tls13::key_schedule::Secret;
 use crate::vecbuf::ChunkVecBuffer;
 use crate::verify;
 use crate::KeyLog;
+use crate::TimeProvider;
 #[cfg(feature = "quic")]
 use crate::{conn::Protocol, quic};
 
@@ -30,6 +38,19 @@ use std::{fmt, io};
 ///
 /// The keys and values are opaque.
 ///
+/// The keys and values are byte strings, so they can be kept outside
+/// this process: for example, in a cache shared by several server
+/// processes, so that a session established with one can be resumed
+/// with another.  Their encoding is the same for all releases with the
+/// same minor version (all 0.20.x releases, say).  Each value starts
+/// with a format tag, and a value written by a release with a different
+/// encoding is ignored: we do a full handshake instead.
+///
+/// Tickets produced by [`ServerConfig::ticketer`] are encrypted with
+/// keys private to the [`ProducesTickets`] implementation, so resuming
+/// them with another process needs an implementation sharing its keys
+/// between processes.
+///
 /// Both the keys and values should be treated as
 /// **highly sensitive data**, containing enough key material
 /// to break all security of the corresponding sessions.
@@ -62,6 +83,20 @@ pub trait StoresServerSessions: Send + Sync {
     /// whether their session can be resumed; the implementation is not required to remember
     /// a session even if it returns `true` here.
     fn can_cache(&self) -> bool;
+
+    /// Store a TLS1.3 session encoded in `value` against the identity
+    /// of `psk`.  Returns `true` if the value was stored.
+    ///
+    /// This is only called when the server issues stateful tickets,
+    /// that is, when [`ServerConfig::ticketer`] is not enabled.  The
+    /// ticket sent to the client is then `psk.identity()`, which is
+    /// later given to `take` to resume.  `value` contains
+    /// `psk.secret()`, so treat it as sensitive.
+    ///
+    /// The default implementation calls `put` with `psk.identity()`.
+    fn put_tls13_session(&self, psk: &ResumptionPsk<'_>, value: Vec<u8>) -> bool {
+        self.put(psk.identity().to_vec(), value)
+    }
 }
 
 /// A trait for the ability to encrypt and decrypt tickets.
@@ -105,13 +140,85 @@ pub trait ResolvesServerCert: Send + Sync {
     ///
     /// Return `None` to abort the handshake.
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>>;
+
+    /// Return true if this resolver only provides raw public keys
+    /// (RFC7250): that is, every [`sign::CertifiedKey`] it returns has
+    /// a `cert` of one DER-encoded SubjectPublicKeyInfo, rather than a
+    /// certificate chain.
+    ///
+    /// Handshakes then fail unless the client negotiates TLS1.3 and
+    /// offers to accept a raw public key.
+    ///
+    /// The default implementation returns false.
+    fn only_raw_public_keys(&self) -> bool {
+        false
+    }
+}
+
+/// Inspects each ClientHello before we choose a certificate, to
+/// decide whether and how to continue the handshake.
+pub trait InspectsClientHello: Send + Sync {
+    /// Decide what to do with `client_hello`.
+    ///
+    /// This is called for every ClientHello, including the second
+    /// one sent in response to a HelloRetryRequest.
+    fn on_client_hello(&self, client_hello: &ClientHello) -> ClientHelloDecision;
+}
+
+/// Detects replayed early data, which we would otherwise accept from
+/// anyone who captured and resent a client's first flight.
+///
+/// Sessions are only resumed once, so this matters most for servers
+/// sharing a session store between many instances.  See RFC8446
+/// section 8.
+pub trait DetectsEarlyDataReplay: Send + Sync {
+    /// Has a ClientHello with `client_random` had its early data
+    /// accepted before?  If so, we reject the early data and continue
+    /// the handshake without it.
+    ///
+    /// This is called only when we would otherwise accept early data,
+    /// and should remember `client_random` for at least as long as the
+    /// tickets we issue live.
+    fn is_replay(&self, client_random: &[u8]) -> bool;
+}
+
+/// What to do with a ClientHello, as decided by [`InspectsClientHello`].
+#[derive(Clone)]
+pub enum ClientHelloDecision {
+    /// Continue the handshake as usual.
+    Continue,
+
+    /// Abort the handshake, sending this fatal alert.
+    Reject(AlertDescription),
+
+    /// Continue the handshake, overriding the usual choices.
+    Select {
+        /// Use this ALPN protocol, instead of choosing from
+        /// [`ServerConfig::alpn_protocols`].  The client must
+        /// have offered it.
+        alpn_protocol: Option<Vec<u8>>,
+
+        /// Use this certificate chain and key, instead of asking
+        /// [`ServerConfig::cert_resolver`].
+        certified_key: Option<Arc<sign::CertifiedKey>>,
+    },
+
+    /// Continue the handshake, choosing an ALPN protocol from this
+    /// list instead of [`ServerConfig::alpn_protocols`].
+    ///
+    /// This lets the server support different protocols for each
+    /// server name.  The list is used in the same way: we choose the
+    /// first protocol the client offered.
+    UseAlpnProtocols(Vec<Vec<u8>>),
 }
 
 /// A struct representing the received Client Hello
 pub struct ClientHello<'a> {
     server_name: &'a Option<webpki::DnsName>,
     signature_schemes: &'a [SignatureScheme],
+    certificate_signature_schemes: Option<&'a [SignatureScheme]>,
     alpn: Option<&'a Vec<PayloadU8>>,
+    cipher_suites: &'a [CipherSuite],
 }
 
 impl<'a> ClientHello<'a> {
@@ -119,16 +226,22 @@ impl<'a> ClientHello<'a> {
     pub(super) fn new(
         server_name: &'a Option<webpki::DnsName>,
         signature_schemes: &'a [SignatureScheme],
+        certificate_signature_schemes: Option<&'a [SignatureScheme]>,
         alpn: Option<&'a Vec<PayloadU8>>,
+        cipher_suites: &'a [CipherSuite],
     ) -> Self {
         trace!("sni {:?}", server_name);
         trace!("sig schemes {:?}", signature_schemes);
+        trace!("cert sig schemes {:?}", certificate_signature_schemes);
         trace!("alpn protocols {:?}", alpn);
+        trace!("cipher suites {:?}", cipher_suites);
 
         ClientHello {
             server_name,
             signature_schemes,
+            certificate_signature_schemes,
             alpn,
+            cipher_suites,
         }
     }
 
@@ -148,6 +261,17 @@ impl<'a> ClientHello<'a> {
         self.signature_schemes
     }
 
+    /// Get the signature schemes the client accepts in our certificate
+    /// chain, from the `signature_algorithms_cert` extension.  A resolver
+    /// with several certificates should prefer one signed with one of these.
+    ///
+    /// Returns [`ClientHello::signature_schemes`] if the client omitted
+    /// this extension.
+    pub fn certificate_signature_schemes(&self) -> &[SignatureScheme] {
+        self.certificate_signature_schemes
+            .unwrap_or(self.signature_schemes)
+    }
+
     /// Get the alpn.
     ///
     /// Returns `None` if the client did not include an ALPN extension
@@ -158,6 +282,12 @@ impl<'a> ClientHello<'a> {
                 .map(|proto| proto.0.as_slice())
         })
     }
+
+    /// Get the cipher suites offered by the client, in its preference
+    /// order.  This includes suites we don't support.
+    pub fn cipher_suites(&self) -> &[CipherSuite] {
+        self.cipher_suites
+    }
 }
 
 /// Common configuration for a set of server sessions.
@@ -165,18 +295,29 @@ impl<'a> ClientHello<'a> {
 /// Making one of these can be expensive, and should be
 /// once per process rather than once per connection.
 ///
+/// One `Arc<ServerConfig>` is meant to be shared by all the connections
+/// using it.  Cloning a `ServerConfig` to adjust it is also cheap: the
+/// certificate resolver, verifiers, session storage, ticketer and cipher
+/// suites are behind `Arc`s, and are shared with the clone.
+///
 /// These must be created via the [`ServerConfig::builder()`] function.
 ///
 /// # Defaults
 ///
 /// * [`ServerConfig::max_fragment_size`]: the default is `None`: TLS packets are not fragmented to a specific size.
-/// * [`ServerConfig::session_storage`]: the default stores 256 sessions in memory.
+/// * [`ServerConfig::session_storage`]: the default is a [`ServerSessionMemoryCache`](crate::server::ServerSessionMemoryCache)
+///   storing up to 256 sessions for up to 24 hours, evicting the least recently used first.
 /// * [`ServerConfig::alpn_protocols`]: the default is empty -- no ALPN protocol is negotiated.
+/// * [`ServerConfig::alpn_required`]: the default is false -- a client offering no protocol we support still connects.
 /// * [`ServerConfig::key_log`]: key material is not logged.
 #[derive(Clone)]
 pub struct ServerConfig {
     /// List of ciphersuites, in preference order.
-    pub(super) cipher_suites: Vec<SupportedCipherSuite>,
+    pub(super) cipher_suites: Arc<[SupportedCipherSuite]>,
+
+    /// The signature schemes we may sign with, or None to allow
+    /// all those we support.
+    pub(super) signature_schemes: Option<Vec<SignatureScheme>>,
 
     /// List of supported key exchange groups.
     ///
@@ -186,7 +327,8 @@ pub struct ServerConfig {
 
     /// Ignore the client's ciphersuite order. Instead,
     /// choose the top ciphersuite in the server list
-    /// which is supported by the client.
+    /// which is supported by the client.  The server list
+    /// can be reordered with [`ServerConfig::set_cipher_suite_order`].
     pub ignore_client_order: bool,
 
     /// The maximum size of TLS message we'll emit.  If None, we don't limit TLS
@@ -198,19 +340,151 @@ pub struct ServerConfig {
     /// Setting this value to the TCP MSS may improve latency for stream-y workloads.
     pub max_fragment_size: Option<usize>,
 
+    /// The largest record we will accept, advertised using the RFC8449
+    /// record_size_limit extension to clients that offer it.  We always
+    /// respect a client's limit when we send.
+    ///
+    /// This must be between 64 and 16385.  Out of range values are
+    /// reported as errors from ServerConnection::new.
+    ///
+    /// The default is None: we advertise the largest size the protocol
+    /// version allows.
+    pub record_size_limit: Option<u16>,
+
+    /// The largest handshake message we will accept from the peer, in
+    /// bytes.  Larger messages are rejected with a `decode_error` alert
+    /// as soon as their header arrives, so a peer cannot make us buffer
+    /// up to the 16MB the protocol allows.
+    ///
+    /// When using an [`Acceptor`], this applies once the connection
+    /// has been made from the [`Accepted`] ClientHello.
+    ///
+    /// The default is 64KB less one byte.  Raise this if you expect to
+    /// receive unusually large certificate chains.
+    pub max_handshake_message_size: usize,
+
+    /// The most certificates we will accept in a chain from the peer.
+    /// Longer chains are rejected with a `bad_certificate` alert before
+    /// any attempt is made to verify them.
+    ///
+    /// The default is 10.
+    pub max_cert_chain_length: usize,
+
+    /// The largest certificate we will accept from the peer, in bytes.
+    /// A chain including a larger certificate is rejected with a
+    /// `bad_certificate` alert before any attempt is made to verify it.
+    ///
+    /// The default is 32KB.
+    pub max_cert_size: usize,
+
+    /// Whether to refuse TLS1.2 connections with clients which do not
+    /// support the extended master secret (RFC7627).  Without it, TLS1.2
+    /// sessions are open to the triple handshake attack.
+    ///
+    /// This has no effect on TLS1.3 connections, whose keys always
+    /// depend on the whole handshake.
+    ///
+    /// The default is false, for interoperability with older clients.
+    pub require_ems: bool,
+
+    /// How to pad TLS1.3 records we send, to hide the length of the
+    /// data in them.  This has no effect on TLS1.2 or QUIC connections.
+    ///
+    /// The default is None: records are not padded.
+    pub record_padding: Option<PaddingPolicy>,
+
+    /// The number of records to send under one TLS1.3 traffic key.  Once
+    /// this many have been sent, we send a KeyUpdate and change to the next
+    /// key before writing more data.
+    ///
+    /// Whatever this is set to, we change keys before sending 2^24 records
+    /// under any one key.  This has no effect on TLS1.2 or QUIC connections.
+    ///
+    /// The default is None: we only change keys to stay within that limit.
+    pub key_update_threshold: Option<u64>,
+
+    /// The most application data we will buffer for sending, either
+    /// before the handshake completes or while waiting for
+    /// `write_tls` to drain earlier records.  Once this much is
+    /// buffered, writes to [`Connection::writer`](crate::Connection::writer)
+    /// are short, and return `Ok(0)` if nothing more can be accepted.
+    ///
+    /// This sets the starting value of
+    /// [`CommonState::set_buffer_limit`](crate::CommonState::set_buffer_limit)
+    /// for each connection, which can still be changed afterwards.
+    ///
+    /// The default is None: connections start with a 64KB limit.
+    pub limit_outgoing_plaintext: Option<usize>,
+
+    /// Called once, when the handshake completes.  By then all the
+    /// details of the connection, such as the negotiated cipher suite
+    /// and the client's certificates, are available from the
+    /// [`CommonState`](crate::CommonState) passed in.  This is useful to
+    /// wake a task waiting for the handshake, without polling
+    /// [`CommonState::is_handshaking`](crate::CommonState::is_handshaking).
+    ///
+    /// The callback is made from within
+    /// [`Connection::process_new_packets`](crate::Connection::process_new_packets),
+    /// so it must not block.
+    ///
+    /// The default is None.
+    pub on_handshake_complete: Option<Arc<HandshakeCompleteCallback>>,
+
     /// How to store client sessions.
     pub session_storage: Arc<dyn StoresServerSessions + Send + Sync>,
 
     /// How to produce tickets.
     pub ticketer: Arc<dyn ProducesTickets>,
 
+    /// How many TLS1.3 tickets to send after each handshake in which
+    /// the client said it can resume.  Clients making several
+    /// connections at once can use a ticket for each; 0 disables
+    /// TLS1.3 resumption.
+    ///
+    /// Each ticket is stored in [`ServerConfig::session_storage`] unless
+    /// [`ServerConfig::ticketer`] is enabled.
+    ///
+    /// The default is 1.
+    pub send_tls13_tickets: usize,
+
+    /// How to protect the cookie in a stateless HelloRetryRequest.
+    ///
+    /// If this is enabled, we answer the first TLS1.3 ClientHello of every
+    /// handshake with a HelloRetryRequest, and keep no state until the client
+    /// returns its cookie in a second ClientHello.  This costs a round trip,
+    /// but a client must then show it receives our messages before we do any
+    /// further work.  Cookies are accepted for 60 seconds, measured with
+    /// `time_provider`.
+    ///
+    /// [`Ticketer::new`] makes a suitable implementation with a rotating key.
+    /// The default is disabled.
+    ///
+    /// [`Ticketer::new`]: crate::Ticketer::new
+    pub stateless_retry: Arc<dyn ProducesTickets>,
+
     /// How to choose a server cert and key.
     pub cert_resolver: Arc<dyn ResolvesServerCert>,
 
+    /// Called with each ClientHello before we choose a certificate,
+    /// to reject it or override our choice of certificate and ALPN
+    /// protocol.
+    ///
+    /// The default is None: every ClientHello continues as usual.
+    pub client_hello_inspector: Option<Arc<dyn InspectsClientHello>>,
+
     /// Protocol names we support, most preferred first.
     /// If empty we don't do ALPN at all.
     pub alpn_protocols: Vec<Vec<u8>>,
 
+    /// Whether to abort the handshake with a `no_application_protocol`
+    /// alert when the client offers ALPN protocols but none of them
+    /// are in [`ServerConfig::alpn_protocols`].  This has no effect
+    /// if `alpn_protocols` is empty.
+    ///
+    /// The default is false: the handshake completes without an
+    /// ALPN protocol, leaving the application to decide what to do.
+    pub alpn_required: bool,
+
     /// Supported protocol versions, in no particular order.
     /// The default is all supported versions.
     pub(super) versions: crate::versions::EnabledVersions,
@@ -218,6 +492,33 @@ pub struct ServerConfig {
     /// How to verify client certificates.
     pub(super) verifier: Arc<dyn verify::ClientCertVerifier>,
 
+    /// If set, when we request client authentication we offer to accept
+    /// a raw public key (RFC7250) from the client in place of a
+    /// certificate chain, and verify it with this.  Whether client
+    /// authentication is requested, and whether it is mandatory, is
+    /// still decided by the client certificate verifier.
+    ///
+    /// This only has an effect on TLS1.3 connections.
+    ///
+    /// The default is None: we only accept certificate chains.
+    pub raw_public_key_verifier: Option<Arc<dyn verify::RawPublicKeyVerifier>>,
+
+    /// How to find the current time, which is passed to `verifier`
+    /// and used to expire `stateless_retry` cookies.
+    /// The default uses the system clock.
+    pub time_provider: Arc<dyn TimeProvider>,
+
+    /// The source of random bytes for the values rustls generates
+    /// itself: the random value in our hello message, session IDs,
+    /// and so on.  See [`SecureRandom`] for what this does not cover.
+    /// The default uses the operating system's RNG.
+    pub secure_random: Arc<dyn SecureRandom>,
+
+    /// The implementation of the cryptography used by the protocol:
+    /// hashing, HMAC, HKDF and record protection.  The default is
+    /// backed by *ring*.
+    pub crypto_provider: Arc<dyn CryptoProvider>,
+
     /// How to output key material for debugging.  The default
     /// does nothing.
     pub key_log: Arc<dyn KeyLog>,
@@ -234,6 +535,34 @@ pub struct ServerConfig {
     /// this value to account for the unknown amount of ciphertext
     /// expansion in the latter case.
     pub max_early_data_size: u32,
+
+    /// Called before accepting early data, to reject replays of it.
+    ///
+    /// The default is None: early data is accepted whenever possible.
+    pub early_data_replay_detector: Option<Arc<dyn DetectsEarlyDataReplay>>,
+
+    /// Certificate compression algorithms we can use to compress our
+    /// certificate, in preference order.  We use the first one the
+    /// client offers.
+    ///
+    /// The default is empty: we never compress.
+    pub cert_compressors: Vec<Arc<dyn compress::CertCompressor>>,
+
+    /// Certificate compression algorithms we offer to decompress client
+    /// certificates with, in preference order.  If empty, we don't offer
+    /// certificate compression in our CertificateRequest.
+    ///
+    /// The default is empty.
+    pub cert_decompressors: Vec<Arc<dyn compress::CertDecompressor>>,
+
+    /// Whether to skip client authentication during TLS1.3 handshakes,
+    /// leaving it to [`ServerConnection::request_client_authentication`].
+    ///
+    /// Clients that don't offer post-handshake authentication can then
+    /// never authenticate over TLS1.3.  TLS1.2 handshakes are unaffected.
+    ///
+    /// The default is false.
+    pub defer_client_auth: bool,
 }
 
 impl ServerConfig {
@@ -258,6 +587,40 @@ impl ServerConfig {
                 .iter()
                 .any(|cs| cs.version().version == v)
     }
+
+    /// Change the server's cipher suite preference order.
+    ///
+    /// The suites in `order` are preferred, in that order, over the rest of
+    /// the configured suites, which keep their existing order.  This only
+    /// affects suite selection if [`ServerConfig::ignore_client_order`] is
+    /// set; otherwise the client's preference is followed.
+    ///
+    /// This fails, leaving the order unchanged, if `order` contains a suite
+    /// which is not configured.
+    pub fn set_cipher_suite_order(&mut self, order: &[SupportedCipherSuite]) -> Result<(), Error> {
+        self.cipher_suites = suites::reorder_suites(&self.cipher_suites, order)?.into();
+        Ok(())
+    }
+
+    /// Restrict the signature schemes we sign the handshake with.  Only
+    /// schemes the client offers are used, so this can leave no scheme
+    /// usable with a client: the handshake then fails.
+    ///
+    /// This fails, leaving the schemes unchanged, if `schemes` is empty,
+    /// repeats a scheme, or contains a scheme we can't sign with.
+    pub fn set_signature_schemes(&mut self, schemes: &[SignatureScheme]) -> Result<(), Error> {
+        sign::check_signature_schemes(schemes, sign::supports_scheme)?;
+        self.signature_schemes = Some(schemes.to_vec());
+        Ok(())
+    }
+
+    /// Do we allow signing with `scheme`?
+    pub(super) fn allows_signature_scheme(&self, scheme: SignatureScheme) -> bool {
+        match &self.signature_schemes {
+            Some(schemes) => schemes.contains(&scheme),
+            None => true,
+        }
+    }
 }
 
 /// Allows reading of early data in resumed TLS1.3 connections.
@@ -281,8 +644,8 @@ impl<'a> std::io::Read for ReadEarlyData<'a> {
     }
 
     #[cfg(read_buf)]
-    fn read_buf(&mut self, buf: &mut io::ReadBuf<'_>) -> io::Result<()> {
-        self.early_data.read_buf(buf)
+    fn read_buf(&mut self, cursor: io::BorrowedCursor<'_>) -> io::Result<()> {
+        self.early_data.read_buf(cursor)
     }
 }
 
@@ -305,14 +668,24 @@ impl ServerConnection {
         config: Arc<ServerConfig>,
         extra_exts: Vec<ServerExtension>,
     ) -> Result<Self, Error> {
-        let common = CommonState::new(config.max_fragment_size, Side::Server)?;
-        Ok(Self {
-            inner: ConnectionCommon::new(
-                Box::new(hs::ExpectClientHello::new(config, extra_exts)),
-                ServerConnectionData::default(),
-                common,
-            ),
-        })
+        fragmenter::check_record_size_limit(config.record_size_limit)?;
+        let mut common = CommonState::new(config.max_fragment_size, Side::Server)?;
+        if let Some(limit) = config.limit_outgoing_plaintext {
+            common.set_buffer_limit(Some(limit));
+        }
+        common.on_handshake_complete = config.on_handshake_complete.clone();
+        common
+            .record_layer
+            .set_padding(config.record_padding);
+        common.allow_ccs_before_client_hello = config.stateless_retry.enabled();
+        let max_handshake_message_size = config.max_handshake_message_size;
+        let mut inner = ConnectionCommon::new(
+            Box::new(hs::ExpectClientHello::new(config, extra_exts)),
+            ServerConnectionData::default(),
+            common,
+        );
+        inner.set_max_handshake_message_size(max_handshake_message_size);
+        Ok(Self { inner })
     }
 
     /// Retrieves the SNI hostname, if any, used to select the certificate and
@@ -335,6 +708,22 @@ impl ServerConnection {
         self.inner.data.get_sni_str()
     }
 
+    /// The certificate chain chosen for this handshake, end-entity
+    /// certificate first.  This is the chain returned by
+    /// [`ServerConfig::cert_resolver`] (or chosen by the
+    /// [`ServerConfig::client_hello_inspector`]).
+    ///
+    /// Returns `None` until the client's hello has been processed and a
+    /// chain selected.  A chain is still selected for resumed sessions,
+    /// although it is not sent to the client.
+    pub fn get_selected_certificate(&self) -> Option<&[key::Certificate]> {
+        self.inner
+            .common_state
+            .server_certified_key
+            .as_ref()
+            .map(|certkey| certkey.cert.as_slice())
+    }
+
     /// Application-controlled portion of the resumption ticket supplied by the client, if any.
     ///
     /// Recovered from the prior session's `set_resumption_data`. Integrity is guaranteed by rustls.
@@ -374,6 +763,36 @@ impl ServerConnection {
         self.inner.data.early_data.reject();
     }
 
+    /// Ask the client to authenticate itself after the handshake, using
+    /// TLS1.3 post-handshake authentication (RFC8446 section 4.6.2).
+    ///
+    /// This queues a CertificateRequest for the client.  Its response is
+    /// verified by [`ServerConfig::verifier`] as it arrives; once it has
+    /// been, [`CommonState::peer_certificates`] returns the client's
+    /// certificate chain.
+    ///
+    /// This fails if the handshake is not yet complete, if the client
+    /// did not offer post-handshake authentication, if the verifier does
+    /// not offer client authentication, or if a previous request is
+    /// still outstanding.
+    pub fn request_client_authentication(&mut self) -> Result<(), Error> {
+        if self.inner.common_state.is_handshaking() {
+            return Err(Error::HandshakeNotComplete);
+        }
+
+        if !self
+            .inner
+            .data
+            .post_handshake_auth_offered
+        {
+            return Err(Error::PeerIncompatibleError(
+                "client did not offer post-handshake authentication".into(),
+            ));
+        }
+
+        self.inner.request_client_auth()
+    }
+
     /// Returns an `io::Read` implementer you can read bytes from that are
     /// received from a client as TLS1.3 0RTT/"early" data, during the handshake.
     ///
@@ -396,6 +815,30 @@ impl ServerConnection {
             None
         }
     }
+
+    /// Derives key material from the TLS1.3 early exporter secret.
+    ///
+    /// This works like [`ConnectionCommon::export_keying_material()`], but
+    /// uses the `early_exporter_master_secret` from RFC8446.  It matches
+    /// [`ClientConnection::export_keying_material_early()`] on the client.
+    ///
+    /// This fails unless early data from the client was accepted.
+    ///
+    /// [`ClientConnection::export_keying_material_early()`]: crate::ClientConnection::export_keying_material_early
+    pub fn export_keying_material_early(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        let data = &self.inner.data;
+        match &data.early_exporter_secret {
+            Some(secret) if data.early_data.was_accepted() => {
+                secret.export_keying_material(output, label, context)
+            }
+            _ => Err(Error::General("early data was not used".into())),
+        }
+    }
 }
 
 impl fmt::Debug for ServerConnection {
@@ -530,10 +973,15 @@ pub struct Accepted {
 impl Accepted {
     /// Get the [`ClientHello`] for this connection.
     pub fn client_hello(&self) -> ClientHello<'_> {
+        let payload = Self::client_hello_payload(&self.message);
         ClientHello::new(
             &self.connection.data.sni,
             &self.sig_schemes,
-            Self::client_hello_payload(&self.message).get_alpn_extension(),
+            payload
+                .get_sigalgs_cert_extension()
+                .map(Vec::as_slice),
+            payload.get_alpn_extension(),
+            &payload.cipher_suites,
         )
     }
 
@@ -543,9 +991,24 @@ impl Accepted {
     /// [`sign::CertifiedKey`] that should be used for the session. Returns an error if
     /// configuration-dependent validation of the received `ClientHello` message fails.
     pub fn into_connection(mut self, config: Arc<ServerConfig>) -> Result<ServerConnection, Error> {
+        fragmenter::check_record_size_limit(config.record_size_limit)?;
         self.connection
             .common_state
             .set_max_fragment_size(config.max_fragment_size)?;
+        if let Some(limit) = config.limit_outgoing_plaintext {
+            self.connection
+                .common_state
+                .set_buffer_limit(Some(limit));
+        }
+        self.connection
+            .common_state
+            .on_handshake_complete = config.on_handshake_complete.clone();
+        self.connection
+            .common_state
+            .record_layer
+            .set_padding(config.record_padding);
+        self.connection
+            .set_max_handshake_message_size(config.max_handshake_message_size);
         let state = hs::ExpectClientHello::new(config, Vec::new());
         let mut cx = hs::ServerContext {
             common: &mut self.connection.common_state,
@@ -625,9 +1088,9 @@ impl EarlyDataState {
     }
 
     #[cfg(read_buf)]
-    fn read_buf(&mut self, buf: &mut io::ReadBuf<'_>) -> io::Result<()> {
+    fn read_buf(&mut self, cursor: io::BorrowedCursor<'_>) -> io::Result<()> {
         match self {
-            Self::Accepted(ref mut received) => received.read_buf(buf),
+            Self::Accepted(ref mut received) => received.read_buf(cursor),
             _ => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
         }
     }
@@ -659,7 +1122,7 @@ fn test_read_buf_in_new_state() {
     assert_eq!(
         format!(
             "{:?}",
-            EarlyDataState::default().read_buf(&mut io::ReadBuf::new(&mut [0u8; 5]))
+            EarlyDataState::default().read_buf(io::BorrowedBuf::from(&mut [0u8; 5][..]).unfilled())
         ),
         "Err(Kind(BrokenPipe))"
     );
@@ -672,6 +1135,11 @@ pub struct ServerConnectionData {
     pub(super) received_resumption_data: Option<Vec<u8>>,
     pub(super) resumption_data: Vec<u8>,
     pub(super) early_data: EarlyDataState,
+    pub(super) early_exporter_secret: Option<Box<Secret>>,
+    pub(super) post_handshake_auth_offered: bool,
+    /// ALPN protocols chosen by the `client_hello_inspector`, which
+    /// replace `ServerConfig::alpn_protocols`.
+    pub(super) alpn_protocols: Option<Vec<Vec<u8>>>,
 }
 
 impl ServerConnectionData {
@@ -703,10 +1171,18 @@ impl quic::QuicExt for ServerConnection {
                 .common_state
                 .quic
                 .early_secret
-                .as_ref()?,
+                .as_deref()?,
         ))
     }
 
+    fn zero_rtt_accepted(&self) -> Option<bool> {
+        match self.inner.data.early_data {
+            EarlyDataState::New => None,
+            EarlyDataState::Accepted(_) => Some(true),
+            EarlyDataState::Rejected => Some(false),
+        }
+    }
+
     fn read_hs(&mut self, plaintext: &[u8]) -> Result<(), Error> {
         self.inner.read_quic_hs(plaintext)
     }
@@ -726,6 +1202,9 @@ pub trait ServerQuicExt {
     /// Make a new QUIC ServerConnection. This differs from `ServerConnection::new()`
     /// in that it takes an extra argument, `params`, which contains the
     /// TLS-encoded transport parameters to send.
+    ///
+    /// `params` is sent unmodified in EncryptedExtensions; the client's parameters
+    /// are available from [`quic::QuicExt::get_quic_transport_parameters()`].
     fn new_quic(
         config: Arc<ServerConfig>,
         quic_version: quic::Version,
@@ -736,6 +1215,7 @@ pub trait ServerQuicExt {
                 "TLS 1.3 support is required for QUIC".into(),
             ));
         }
+        quic::check_cipher_suites(&config.cipher_suites)?;
 
         if config.max_early_data_size != 0 && config.max_early_data_size != 0xffff_ffff {
             return Err(Error::General(
@@ -1,4 +1,4 @@
-use crate::check::inappropriate_message;
+use crate::check::{check_cert_chain_limits, inappropriate_message};
 use crate::conn::{CommonState, ConnectionRandoms, Side, State};
 use crate::error::Error;
 use crate::hash_hs::HandshakeHash;
@@ -13,6 +13,7 @@ use crate::msgs::handshake::{ClientECDHParams, HandshakeMessagePayload, Handshak
 use crate::msgs::handshake::{NewSessionTicketPayload, SessionID};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
+use crate::rand::SecureRandom;
 use crate::tls12::{self, ConnectionSecrets, Tls12CipherSuite};
 use crate::{kx, ticketer, verify};
 
@@ -67,6 +68,11 @@ mod client_hello {
 
             if client_hello.ems_support_offered() {
                 self.using_ems = true;
+            } else if self.config.require_ems {
+                return Err(hs::incompatible(
+                    cx.common,
+                    "client does not support extended master secret",
+                ));
             }
 
             let groups_ext = client_hello
@@ -155,7 +161,7 @@ mod client_hello {
                 .config
                 .kx_groups
                 .iter()
-                .find(|skxg| groups_ext.contains(&skxg.name))
+                .find(|skxg| skxg.usable_for_tls12() && groups_ext.contains(&skxg.name))
                 .cloned()
                 .ok_or_else(|| hs::incompatible(cx.common, "no supported group"))?;
 
@@ -174,7 +180,7 @@ mod client_hello {
             if !self.config.session_storage.can_cache() {
                 self.session_id = SessionID::empty();
             } else if self.session_id.is_empty() && !ticket_received {
-                self.session_id = SessionID::random()?;
+                self.session_id = SessionID::random(&*self.config.secure_random)?;
             }
 
             self.send_ticket = emit_server_hello(
@@ -202,6 +208,7 @@ mod client_hello {
                 group,
                 server_key.get_key(),
                 &self.randoms,
+                &*self.config.secure_random,
             )?;
             let doing_client_auth = emit_certificate_req(&self.config, &mut self.transcript, cx)?;
             emit_server_hello_done(&mut self.transcript, cx.common);
@@ -237,7 +244,7 @@ mod client_hello {
             cx: &mut ServerContext<'_>,
             client_hello: &ClientHelloPayload,
             id: &SessionID,
-            resumedata: persist::ServerSessionValue,
+            mut resumedata: persist::ServerSessionValue,
         ) -> hs::NextStateOrError {
             debug!("Resuming connection");
 
@@ -265,6 +272,7 @@ mod client_hello {
 
             let secrets = ConnectionSecrets::new_resume(
                 self.randoms,
+                Arc::clone(&self.config.crypto_provider),
                 self.suite,
                 &resumedata.master_secret.0,
             );
@@ -275,7 +283,8 @@ mod client_hello {
             );
             cx.common
                 .start_encryption_tls12(&secrets, Side::Server);
-            cx.common.peer_certificates = resumedata.client_cert_chain;
+            cx.common.peer_certificates = resumedata.client_cert_chain.take();
+            cx.common.resumed = true;
 
             if self.send_ticket {
                 emit_ticket(
@@ -345,6 +354,10 @@ mod client_hello {
             }),
         };
 
+        if !session_id.is_empty() {
+            cx.common.session_id = Some(session_id);
+        }
+
         trace!("sending server hello {:?}", sh);
         transcript.add_message(&sh);
         cx.common.send_msg(sh, false);
@@ -390,8 +403,10 @@ mod client_hello {
         skxg: &'static kx::SupportedKxGroup,
         signing_key: &dyn sign::SigningKey,
         randoms: &ConnectionRandoms,
+        rng: &dyn SecureRandom,
     ) -> Result<kx::KeyExchange, Error> {
-        let kx = kx::KeyExchange::start(skxg).ok_or(Error::FailedToGetRandomBytes)?;
+        let kx = kx::KeyExchange::start(skxg, rng).ok_or(Error::FailedToGetRandomBytes)?;
+        common.kx_group = Some(skxg.name);
         let secdh = ServerECDHParams::new(skxg.name, kx.pubkey.as_ref());
 
         let mut msg = Vec::new();
@@ -502,12 +517,18 @@ impl State<ServerConnectionData> for ExpectCertificate {
             HandshakeType::Certificate,
             HandshakePayload::Certificate
         )?;
+        check_cert_chain_limits(
+            cx.common,
+            &cert_chain,
+            self.config.max_cert_chain_length,
+            self.config.max_cert_size,
+        )?;
 
         // If we can't determine if the auth is mandatory, abort
         let mandatory = self
             .config
             .verifier
-            .client_auth_mandatory()
+            .client_auth_mandatory(cx.data.get_sni_str())
             .ok_or_else(|| {
                 debug!("could not determine if client auth is mandatory based on SNI");
                 cx.common
@@ -519,8 +540,10 @@ impl State<ServerConnectionData> for ExpectCertificate {
 
         let client_cert = match cert_chain.split_first() {
             None if mandatory => {
+                // certificate_required is new in TLS1.3: RFC5246 says
+                // to send handshake_failure instead.
                 cx.common
-                    .send_fatal_alert(AlertDescription::CertificateRequired);
+                    .send_fatal_alert(AlertDescription::HandshakeFailure);
                 return Err(Error::NoCertificatesPresented);
             }
             None => {
@@ -529,7 +552,7 @@ impl State<ServerConnectionData> for ExpectCertificate {
                 None
             }
             Some((end_entity, intermediates)) => {
-                let now = std::time::SystemTime::now();
+                let now = self.config.time_provider.now()?;
                 self.config
                     .verifier
                     .verify_client_cert(end_entity, intermediates, now)
@@ -554,6 +577,10 @@ impl State<ServerConnectionData> for ExpectCertificate {
             send_ticket: self.send_ticket,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 // --- Process client's KeyExchange ---
@@ -590,6 +617,7 @@ impl State<ServerConnectionData> for ExpectClientKx {
             &peer_kx_params.public.0,
             ems_seed,
             self.randoms,
+            Arc::clone(&self.config.crypto_provider),
             self.suite,
         )?;
 
@@ -623,6 +651,10 @@ impl State<ServerConnectionData> for ExpectClientKx {
             }))
         }
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 // --- Process client's certificate proof ---
@@ -685,6 +717,10 @@ impl State<ServerConnectionData> for ExpectCertificateVerify {
             send_ticket: self.send_ticket,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 // --- Process client's ChangeCipherSpec ---
@@ -727,6 +763,10 @@ impl State<ServerConnectionData> for ExpectCcs {
             send_ticket: self.send_ticket,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 // --- Process client's Finished ---
@@ -889,14 +929,20 @@ impl State<ServerConnectionData> for ExpectFinished {
         cx.common.start_traffic();
         Ok(Box::new(ExpectTraffic {
             secrets: self.secrets,
+            transcript: self.transcript,
             _fin_verified,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 // --- Process traffic ---
 struct ExpectTraffic {
     secrets: ConnectionSecrets,
+    transcript: HandshakeHash,
     _fin_verified: verify::FinishedMessageVerified,
 }
 
@@ -918,6 +964,10 @@ impl State<ServerConnectionData> for ExpectTraffic {
         Ok(self)
     }
 
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
+
     fn export_keying_material(
         &self,
         output: &mut [u8],
@@ -925,7 +975,6 @@ impl State<ServerConnectionData> for ExpectTraffic {
         context: Option<&[u8]>,
     ) -> Result<(), Error> {
         self.secrets
-            .export_keying_material(output, label, context);
-        Ok(())
+            .export_keying_material(output, label, context)
     }
 }
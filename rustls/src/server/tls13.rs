@@ -1,13 +1,17 @@
-use crate::check::inappropriate_handshake_message;
+use crate::check::{check_cert_chain_limits, inappropriate_handshake_message};
+use crate::compress;
 use crate::conn::{CommonState, ConnectionRandoms, State};
 use crate::error::Error;
-use crate::hash_hs::HandshakeHash;
+use crate::hash_hs::{HandshakeHash, HandshakeHashBuffer};
 use crate::key::Certificate;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace, warn};
+use crate::msgs::base::PayloadU8;
 use crate::msgs::codec::Codec;
-use crate::msgs::enums::{AlertDescription, KeyUpdateRequest};
+use crate::msgs::enums::{AlertDescription, CertificateType, KeyUpdateRequest};
 use crate::msgs::enums::{ContentType, HandshakeType, ProtocolVersion};
+use crate::msgs::handshake::CertReqExtension;
+use crate::msgs::handshake::CertificateRequestPayloadTLS13;
 use crate::msgs::handshake::HandshakeMessagePayload;
 use crate::msgs::handshake::HandshakePayload;
 use crate::msgs::handshake::{NewSessionTicketExtension, NewSessionTicketPayloadTLS13};
@@ -19,6 +23,7 @@ use crate::ticketer;
 use crate::tls13::key_schedule::{KeyScheduleTraffic, KeyScheduleTrafficWithClientFinishedPending};
 use crate::tls13::Tls13CipherSuite;
 use crate::verify;
+use crate::zeroize::Zeroizing;
 #[cfg(feature = "quic")]
 use crate::{check::inappropriate_message, conn::Protocol};
 
@@ -26,24 +31,77 @@ use super::hs::{self, HandshakeHashOrBuffer, ServerContext};
 use super::server_conn::ServerConnectionData;
 
 use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 
 use ring::constant_time;
 
 pub(super) use client_hello::CompleteClientHelloHandling;
 
+fn make_certificate_request(
+    config: &ServerConfig,
+    context: Vec<u8>,
+) -> Result<CertificateRequestPayloadTLS13, Error> {
+    let mut cr = CertificateRequestPayloadTLS13 {
+        context: PayloadU8::new(context),
+        extensions: Vec::new(),
+    };
+
+    let schemes = config
+        .verifier
+        .supported_verify_schemes();
+    cr.extensions
+        .push(CertReqExtension::SignatureAlgorithms(schemes.to_vec()));
+
+    let names = config
+        .verifier
+        .client_auth_root_subjects()
+        .ok_or_else(|| {
+            debug!("could not determine root subjects based on SNI");
+            Error::General("client rejected by client_auth_root_subjects".into())
+        })?;
+
+    if !names.is_empty() {
+        cr.extensions
+            .push(CertReqExtension::AuthorityNames(names));
+    }
+
+    if !config.cert_decompressors.is_empty() {
+        cr.extensions
+            .push(CertReqExtension::CertificateCompressionAlgorithms(
+                config
+                    .cert_decompressors
+                    .iter()
+                    .map(|d| d.algorithm())
+                    .collect(),
+            ));
+    }
+
+    Ok(cr)
+}
+
+/// The max_early_data_size for this connection: that of the chosen
+/// certified key if it has one, otherwise the config's.
+fn max_early_data_size_for(config: &ServerConfig, common: &CommonState) -> u32 {
+    common
+        .server_certified_key
+        .as_ref()
+        .and_then(|key| key.max_early_data_size)
+        .unwrap_or(config.max_early_data_size)
+}
+
 mod client_hello {
+    use crate::compress::CertCompressor;
     use crate::kx;
-    use crate::msgs::base::{Payload, PayloadU8};
+    use crate::msgs::base::{Payload, PayloadU16};
     use crate::msgs::ccs::ChangeCipherSpecPayload;
     use crate::msgs::enums::{Compression, PSKKeyExchangeMode};
     use crate::msgs::enums::{NamedGroup, SignatureScheme};
-    use crate::msgs::handshake::CertReqExtension;
     use crate::msgs::handshake::CertificateEntry;
     use crate::msgs::handshake::CertificateExtension;
     use crate::msgs::handshake::CertificatePayloadTLS13;
-    use crate::msgs::handshake::CertificateRequestPayloadTLS13;
     use crate::msgs::handshake::CertificateStatus;
     use crate::msgs::handshake::ClientHelloPayload;
+    use crate::msgs::handshake::DelegatedCredentialPayload;
     use crate::msgs::handshake::DigitallySignedStruct;
     use crate::msgs::handshake::HelloRetryExtension;
     use crate::msgs::handshake::HelloRetryRequest;
@@ -111,7 +169,8 @@ mod client_hello {
                 .transcript
                 .get_hash_given(&binder_plaintext);
 
-            let key_schedule = KeyScheduleEarly::new(suite.hkdf_algorithm, psk);
+            let key_schedule =
+                KeyScheduleEarly::new(Arc::clone(&self.config.crypto_provider), suite.hash, psk);
             let real_binder =
                 key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash);
 
@@ -126,13 +185,141 @@ mod client_hello {
                 self.config
                     .ticketer
                     .decrypt(ticket)
-                    .and_then(|plain| persist::ServerSessionValue::read_bytes(&plain))
+                    .and_then(|plain| {
+                        persist::ServerSessionValue::read_bytes(&Zeroizing::new(plain))
+                    })
             } else {
                 self.config
                     .session_storage
                     .take(ticket)
-                    .and_then(|plain| persist::ServerSessionValue::read_bytes(&plain))
+                    .and_then(|plain| {
+                        persist::ServerSessionValue::read_bytes(&Zeroizing::new(plain))
+                    })
+            }
+        }
+
+        /// Answer a first ClientHello with a HelloRetryRequest whose cookie
+        /// holds everything we need to continue, and then forget it.
+        fn emit_stateless_retry(
+            mut self,
+            cx: &mut ServerContext<'_>,
+            chm: &Message,
+            groups_ext: &[NamedGroup],
+            chosen_share: Option<&KeyShareEntry>,
+            early_data_requested: bool,
+        ) -> hs::NextStateOrError {
+            let retry_group = match chosen_share {
+                Some(_) => None,
+                None => {
+                    let group = self
+                        .config
+                        .kx_groups
+                        .iter()
+                        .find(|group| groups_ext.contains(&group.name))
+                        .ok_or_else(|| {
+                            hs::incompatible(cx.common, "no kx group overlap with client")
+                        })?;
+                    Some(group.name)
+                }
+            };
+
+            self.transcript.add_message(chm);
+            let cookie = persist::RetryCookie::new(
+                unix_time_secs(&self.config)?,
+                self.suite.common.suite,
+                retry_group,
+                self.transcript
+                    .get_current_hash()
+                    .as_ref()
+                    .to_vec(),
+            );
+            let cookie = self
+                .config
+                .stateless_retry
+                .encrypt(&cookie.get_encoding())
+                .ok_or_else(|| Error::General("cannot encrypt retry cookie".into()))?;
+
+            let m = build_hello_retry_request(self.suite, retry_group, Some(cookie));
+            trace!("Requesting stateless retry {:?}", m);
+            cx.common.send_msg(m, false);
+            emit_fake_ccs(cx.common);
+
+            // The second ClientHello is handled afresh.
+            cx.data.sni = None;
+
+            let skip_early_data =
+                max_early_data_size(max_early_data_size_for(&self.config, cx.common));
+            let next = Box::new(hs::ExpectClientHello::new(self.config, self.extra_exts));
+
+            if early_data_requested {
+                Ok(Box::new(ExpectAndSkipRejectedEarlyData {
+                    skip_data_left: skip_early_data,
+                    next,
+                }))
+            } else {
+                Ok(next)
+            }
+        }
+
+        /// Recover the state we put in the cookie of a stateless
+        /// HelloRetryRequest, and continue as if we'd kept it.
+        fn resume_stateless_retry(
+            &mut self,
+            cx: &mut ServerContext<'_>,
+            cookie: &[u8],
+            chosen_share: Option<&KeyShareEntry>,
+        ) -> Result<(), Error> {
+            let retry = match self
+                .config
+                .stateless_retry
+                .decrypt(cookie)
+                .and_then(|plain| persist::RetryCookie::read_bytes(&plain))
+            {
+                Some(retry) => retry,
+                None => {
+                    return Err(cx
+                        .common
+                        .illegal_param("client sent invalid retry cookie"));
+                }
+            };
+
+            let age = unix_time_secs(&self.config)?.saturating_sub(retry.creation_time_sec);
+            if age > RETRY_COOKIE_LIFETIME {
+                return Err(cx
+                    .common
+                    .illegal_param("client sent stale retry cookie"));
+            }
+
+            if retry.cipher_suite != self.suite.common.suite {
+                return Err(cx
+                    .common
+                    .illegal_param("client changed cipher suite after retry"));
+            }
+
+            if let Some(group) = retry.retry_group {
+                if chosen_share.map(|share| share.group) != Some(group) {
+                    return Err(cx
+                        .common
+                        .illegal_param("did not follow retry request"));
+                }
             }
+
+            let mut transcript_buffer =
+                HandshakeHashBuffer::for_stateless_retry(&retry.client_hello_hash.0);
+            if self.config.verifier.offer_client_auth() {
+                transcript_buffer.set_client_auth_enabled();
+            }
+            let mut transcript = transcript_buffer
+                .start_hash(Arc::clone(&self.config.crypto_provider), self.suite.hash);
+            transcript.add_message(&build_hello_retry_request(
+                self.suite,
+                retry.retry_group,
+                Some(cookie.to_vec()),
+            ));
+
+            self.transcript = transcript;
+            self.done_retry = true;
+            Ok(())
         }
 
         pub(in crate::server) fn handle_client_hello(
@@ -168,12 +355,9 @@ mod client_hello {
 
             let early_data_requested = client_hello.early_data_extension_offered();
 
-            // EarlyData extension is illegal in second ClientHello
-            if self.done_retry && early_data_requested {
-                return Err(cx
-                    .common
-                    .illegal_param("client sent EarlyData in second ClientHello"));
-            }
+            // Post-handshake authentication is not used with QUIC (RFC9001 section 4.4).
+            cx.data.post_handshake_auth_offered =
+                client_hello.post_handshake_auth_offered() && !cx.common.is_quic();
 
             // choose a share that we support
             let chosen_share = self
@@ -186,6 +370,28 @@ mod client_hello {
                         .find(|share| share.group == group.name)
                 });
 
+            if self.config.stateless_retry.enabled() && !self.done_retry {
+                match client_hello.get_cookie() {
+                    Some(cookie) => self.resume_stateless_retry(cx, &cookie.0, chosen_share)?,
+                    None => {
+                        return self.emit_stateless_retry(
+                            cx,
+                            chm,
+                            groups_ext,
+                            chosen_share,
+                            early_data_requested,
+                        );
+                    }
+                }
+            }
+
+            // EarlyData extension is illegal in second ClientHello
+            if self.done_retry && early_data_requested {
+                return Err(cx
+                    .common
+                    .illegal_param("client sent EarlyData in second ClientHello"));
+            }
+
             let chosen_share = match chosen_share {
                 Some(s) => s,
                 None => {
@@ -215,7 +421,8 @@ mod client_hello {
                         );
                         emit_fake_ccs(cx.common);
 
-                        let skip_early_data = max_early_data_size(self.config.max_early_data_size);
+                        let skip_early_data =
+                            max_early_data_size(max_early_data_size_for(&self.config, cx.common));
 
                         let next = Box::new(hs::ExpectClientHello {
                             config: self.config,
@@ -311,9 +518,24 @@ mod client_hello {
             if let Some(ref resume) = resumedata {
                 cx.data.received_resumption_data = Some(resume.application_data.0.clone());
                 cx.common.peer_certificates = resume.client_cert_chain.clone();
+                cx.common.resumed = true;
             }
 
             let full_handshake = resumedata.is_none();
+            let (server_cert_type, client_cert_type) = if full_handshake {
+                let client_auth =
+                    self.config.verifier.offer_client_auth() && !self.config.defer_client_auth;
+                choose_certificate_types(
+                    &self.config,
+                    cx.common,
+                    client_hello,
+                    client_auth,
+                    &mut self.extra_exts,
+                )?
+            } else {
+                (CertificateType::X509, CertificateType::X509)
+            };
+
             self.transcript.add_message(chm);
             let key_schedule = emit_server_hello(
                 &mut self.transcript,
@@ -326,14 +548,29 @@ mod client_hello {
                 resumedata
                     .as_ref()
                     .map(|x| &x.master_secret.0[..]),
+                early_data_requested,
                 &self.config,
             )?;
             if !self.done_retry {
                 emit_fake_ccs(cx.common);
             }
 
-            let (mut ocsp_response, mut sct_list) =
-                (server_key.get_ocsp(), server_key.get_sct_list());
+            let (mut ocsp_response, mut sct_list) = match server_cert_type {
+                CertificateType::RawPublicKey => (None, None),
+                _ => (server_key.get_ocsp(), server_key.get_sct_list()),
+            };
+
+            // Sign with our delegated credential if the client accepts it.
+            let delegated_credential = server_key
+                .get_delegated_credential()
+                .filter(|dc| {
+                    server_cert_type == CertificateType::X509
+                        && sigschemes_ext.contains(&dc.algorithm())
+                        && client_hello
+                            .get_delegated_credential_schemes()
+                            .map_or(false, |schemes| schemes.contains(&dc.scheme()))
+                });
+
             let doing_early_data = emit_encrypted_extensions(
                 &mut self.transcript,
                 self.suite,
@@ -349,18 +586,35 @@ mod client_hello {
             let doing_client_auth = if full_handshake {
                 let client_auth =
                     emit_certificate_req_tls13(&mut self.transcript, cx, &self.config)?;
+                let config = &self.config;
+                let cert_compressor = client_hello
+                    .get_cert_compression_extension()
+                    .and_then(|offered| {
+                        compress::choose_compressor(&config.cert_compressors, offered)
+                    });
                 emit_certificate_tls13(
                     &mut self.transcript,
                     cx.common,
                     server_key.get_cert(),
                     ocsp_response,
                     sct_list,
+                    delegated_credential.map(sign::DelegatedCredential::payload),
+                    cert_compressor.as_deref(),
                 );
+
+                let delegated_scheme;
+                let (signing_key, schemes) = match delegated_credential {
+                    Some(dc) => {
+                        delegated_scheme = [dc.scheme()];
+                        (dc.key(), &delegated_scheme[..])
+                    }
+                    None => (server_key.get_key(), &sigschemes_ext[..]),
+                };
                 emit_certificate_verify_tls13(
                     &mut self.transcript,
                     cx.common,
-                    server_key.get_key(),
-                    &sigschemes_ext,
+                    signing_key,
+                    schemes,
                 )?;
                 client_auth
             } else {
@@ -386,14 +640,14 @@ mod client_hello {
                         .set_message_decrypter_with_trial_decryption(
                             self.suite
                                 .derive_decrypter(key_schedule.client_key()),
-                            max_early_data_size(self.config.max_early_data_size),
+                            max_early_data_size(max_early_data_size_for(&self.config, cx.common)),
                         );
                     cx.data.early_data.reject();
                 }
                 EarlyDataDecision::Accepted => {
                     cx.data
                         .early_data
-                        .accept(self.config.max_early_data_size as usize);
+                        .accept(max_early_data_size_for(&self.config, cx.common) as usize);
                 }
             }
 
@@ -420,9 +674,11 @@ mod client_hello {
                     transcript: self.transcript,
                     suite: self.suite,
                     key_schedule: key_schedule_traffic,
+                    client_cert_type,
                     send_ticket: self.send_ticket,
                 }))
-            } else if doing_early_data == EarlyDataDecision::Accepted {
+            } else if doing_early_data == EarlyDataDecision::Accepted && !cx.common.is_quic() {
+                // QUIC clients don't send EndOfEarlyData (RFC9001 section 8.3).
                 Ok(Box::new(ExpectEarlyData {
                     config: self.config,
                     transcript: self.transcript,
@@ -451,14 +707,18 @@ mod client_hello {
         share: &KeyShareEntry,
         chosen_psk_idx: Option<usize>,
         resuming_psk: Option<&[u8]>,
+        early_data_requested: bool,
         config: &ServerConfig,
     ) -> Result<KeyScheduleHandshake, Error> {
         let mut extensions = Vec::new();
 
         // Prepare key exchange
         let kx = kx::KeyExchange::choose(share.group, &config.kx_groups)
-            .and_then(kx::KeyExchange::start)
-            .ok_or(Error::FailedToGetRandomBytes)?;
+            .ok_or(Error::FailedToGetRandomBytes)
+            .and_then(|skxg| {
+                kx::KeyExchange::start_in_reply(skxg, &share.payload.0, &*config.secure_random)
+            })?;
+        cx.common.kx_group = Some(share.group);
 
         let kse = KeyShareEntry::new(share.group, kx.pubkey.as_ref());
         extensions.push(ServerExtension::KeyShare(kse));
@@ -493,19 +753,31 @@ mod client_hello {
 
         // Start key schedule
         let (key_schedule_pre_handshake, early_data_client_key) = if let Some(psk) = resuming_psk {
-            let early_key_schedule = KeyScheduleEarly::new(suite.hkdf_algorithm, psk);
+            let early_key_schedule =
+                KeyScheduleEarly::new(Arc::clone(&config.crypto_provider), suite.hash, psk);
             let client_early_traffic_secret = early_key_schedule.client_early_traffic_secret(
                 &client_hello_hash,
                 &*config.key_log,
                 &randoms.client,
             );
+            if early_data_requested {
+                cx.data.early_exporter_secret =
+                    Some(Box::new(early_key_schedule.early_exporter_master_secret(
+                        &client_hello_hash,
+                        &*config.key_log,
+                        &randoms.client,
+                    )));
+            }
 
             (
                 KeySchedulePreHandshake::from(early_key_schedule),
                 Some(client_early_traffic_secret),
             )
         } else {
-            (KeySchedulePreHandshake::new(suite.hkdf_algorithm), None)
+            (
+                KeySchedulePreHandshake::new(Arc::clone(&config.crypto_provider), suite.hash),
+                None,
+            )
         };
 
         // Do key exchange
@@ -530,14 +802,16 @@ mod client_hello {
         if let Some(key) = &early_data_client_key {
             cx.common
                 .record_layer
-                .set_message_decrypter(suite.derive_decrypter(key));
+                .set_early_data_decrypter(suite.derive_decrypter(key));
         }
 
         #[cfg(feature = "quic")]
         {
             // If 0-RTT should be rejected, this will be clobbered by ExtensionProcessing
             // before the application can see.
-            cx.common.quic.early_secret = early_data_client_key;
+            cx.common.quic.early_secret = early_data_client_key
+                .as_ref()
+                .map(|secret| Box::new(quic::ring_secret(secret)));
             cx.common.quic.hs_secrets =
                 Some(quic::Secrets::new(_client_key, server_key, suite, false));
         }
@@ -556,12 +830,23 @@ mod client_hello {
         common.send_msg(m, false);
     }
 
-    fn emit_hello_retry_request(
-        transcript: &mut HandshakeHash,
+    /// The number of seconds for which we accept a stateless retry cookie.
+    const RETRY_COOKIE_LIFETIME: u64 = 60;
+
+    fn unix_time_secs(config: &ServerConfig) -> Result<u64, Error> {
+        config
+            .time_provider
+            .now()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .map_err(|_| Error::FailedToGetCurrentTime)
+    }
+
+    fn build_hello_retry_request(
         suite: &'static Tls13CipherSuite,
-        common: &mut CommonState,
-        group: NamedGroup,
-    ) {
+        group: Option<NamedGroup>,
+        cookie: Option<Vec<u8>>,
+    ) -> Message {
         let mut req = HelloRetryRequest {
             legacy_version: ProtocolVersion::TLSv1_2,
             session_id: SessionID::empty(),
@@ -569,20 +854,35 @@ mod client_hello {
             extensions: Vec::new(),
         };
 
-        req.extensions
-            .push(HelloRetryExtension::KeyShare(group));
+        if let Some(group) = group {
+            req.extensions
+                .push(HelloRetryExtension::KeyShare(group));
+        }
+        if let Some(cookie) = cookie {
+            req.extensions
+                .push(HelloRetryExtension::Cookie(PayloadU16::new(cookie)));
+        }
         req.extensions
             .push(HelloRetryExtension::SupportedVersions(
                 ProtocolVersion::TLSv1_3,
             ));
 
-        let m = Message {
+        Message {
             version: ProtocolVersion::TLSv1_2,
             payload: MessagePayload::Handshake(HandshakeMessagePayload {
                 typ: HandshakeType::HelloRetryRequest,
                 payload: HandshakePayload::HelloRetryRequest(req),
             }),
-        };
+        }
+    }
+
+    fn emit_hello_retry_request(
+        transcript: &mut HandshakeHash,
+        suite: &'static Tls13CipherSuite,
+        common: &mut CommonState,
+        group: NamedGroup,
+    ) {
+        let m = build_hello_retry_request(suite, Some(group), None);
 
         trace!("Requesting retry {:?}", m);
         transcript.rollup_for_hrr();
@@ -613,7 +913,8 @@ mod client_hello {
 
         /* Non-zero max_early_data_size controls whether early_data is allowed at all.
          * We also require stateful resumption. */
-        let early_data_configured = config.max_early_data_size > 0 && !config.ticketer.enabled();
+        let early_data_configured =
+            max_early_data_size_for(config, cx.common) > 0 && !config.ticketer.enabled();
 
         /* "For PSKs provisioned via NewSessionTicket, a server MUST validate
          *  that the ticket age for the selected PSK identity (computed by
@@ -637,7 +938,16 @@ mod client_hello {
             && resume.cipher_suite == suite.common.suite
             && resume.alpn.as_ref().map(|x| &x.0) == cx.common.alpn_protocol.as_ref();
 
-        if early_data_configured && early_data_possible && !cx.data.early_data.was_rejected() {
+        let replayed = || match &config.early_data_replay_detector {
+            Some(detector) => detector.is_replay(&client_hello.random.0),
+            None => false,
+        };
+
+        if early_data_configured
+            && early_data_possible
+            && !cx.data.early_data.was_rejected()
+            && !replayed()
+        {
             EarlyDataDecision::Accepted
         } else {
             #[cfg(feature = "quic")]
@@ -650,6 +960,61 @@ mod client_hello {
         }
     }
 
+    /// Choose the certificate types (RFC7250) for a full handshake,
+    /// adding extensions confirming them to `exts`.  Returns the types
+    /// we and the client will authenticate with.
+    fn choose_certificate_types(
+        config: &ServerConfig,
+        common: &mut CommonState,
+        hello: &ClientHelloPayload,
+        client_auth: bool,
+        exts: &mut Vec<ServerExtension>,
+    ) -> Result<(CertificateType, CertificateType), Error> {
+        let server_cert_type = match config
+            .cert_resolver
+            .only_raw_public_keys()
+        {
+            true => CertificateType::RawPublicKey,
+            false => CertificateType::X509,
+        };
+        match hello.get_server_certificate_types() {
+            Some(offered) if offered.contains(&server_cert_type) => {
+                exts.push(ServerExtension::ServerCertificateType(server_cert_type));
+            }
+            None if server_cert_type == CertificateType::X509 => {}
+            _ => {
+                common.send_fatal_alert(AlertDescription::UnsupportedCertificate);
+                return Err(Error::PeerIncompatibleError(format!(
+                    "client does not accept {:?} server certificates",
+                    server_cert_type
+                )));
+            }
+        }
+
+        // We only say which type the client should use if we ask it
+        // to authenticate.
+        let offered = match hello.get_client_certificate_types() {
+            Some(offered) if client_auth => offered,
+            _ => return Ok((server_cert_type, CertificateType::X509)),
+        };
+
+        let client_cert_type = if config.raw_public_key_verifier.is_some()
+            && offered.contains(&CertificateType::RawPublicKey)
+        {
+            CertificateType::RawPublicKey
+        } else if offered.contains(&CertificateType::X509) {
+            CertificateType::X509
+        } else {
+            common.send_fatal_alert(AlertDescription::UnsupportedCertificate);
+            return Err(Error::PeerIncompatibleError(
+                "client offered no usable client certificate types".to_string(),
+            ));
+        };
+        exts.push(ServerExtension::ClientCertificateType(client_cert_type));
+
+        Ok((server_cert_type, client_cert_type))
+    }
+
     fn emit_encrypted_extensions(
         transcript: &mut HandshakeHash,
         suite: &'static Tls13CipherSuite,
@@ -696,35 +1061,15 @@ mod client_hello {
         cx: &mut ServerContext<'_>,
         config: &ServerConfig,
     ) -> Result<bool, Error> {
-        if !config.verifier.offer_client_auth() {
+        if !config.verifier.offer_client_auth() || config.defer_client_auth {
             return Ok(false);
         }
 
-        let mut cr = CertificateRequestPayloadTLS13 {
-            context: PayloadU8::empty(),
-            extensions: Vec::new(),
-        };
-
-        let schemes = config
-            .verifier
-            .supported_verify_schemes();
-        cr.extensions
-            .push(CertReqExtension::SignatureAlgorithms(schemes.to_vec()));
-
-        let names = config
-            .verifier
-            .client_auth_root_subjects()
-            .ok_or_else(|| {
-                debug!("could not determine root subjects based on SNI");
-                cx.common
-                    .send_fatal_alert(AlertDescription::AccessDenied);
-                Error::General("client rejected by client_auth_root_subjects".into())
-            })?;
-
-        if !names.is_empty() {
-            cr.extensions
-                .push(CertReqExtension::AuthorityNames(names));
-        }
+        let cr = make_certificate_request(config, Vec::new()).map_err(|err| {
+            cx.common
+                .send_fatal_alert(AlertDescription::AccessDenied);
+            err
+        })?;
 
         let m = Message {
             version: ProtocolVersion::TLSv1_3,
@@ -746,6 +1091,8 @@ mod client_hello {
         cert_chain: &[Certificate],
         ocsp_response: Option<&[u8]>,
         sct_list: Option<&[u8]>,
+        delegated_credential: Option<&DelegatedCredentialPayload>,
+        compressor: Option<&dyn CertCompressor>,
     ) {
         let mut cert_entries = vec![];
         for cert in cert_chain {
@@ -773,15 +1120,28 @@ mod client_hello {
                     .exts
                     .push(CertificateExtension::make_sct(sct_list.to_owned()));
             }
+
+            if let Some(dc) = delegated_credential {
+                end_entity_cert
+                    .exts
+                    .push(CertificateExtension::DelegatedCredential(dc.clone()));
+            }
         }
 
         let cert_body = CertificatePayloadTLS13::new(cert_entries);
-        let c = Message {
-            version: ProtocolVersion::TLSv1_3,
-            payload: MessagePayload::Handshake(HandshakeMessagePayload {
+        let payload = match compressor.and_then(|c| compress::compress_certificate(c, &cert_body)) {
+            Some(compressed) => HandshakeMessagePayload {
+                typ: HandshakeType::CompressedCertificate,
+                payload: HandshakePayload::CompressedCertificate(compressed),
+            },
+            None => HandshakeMessagePayload {
                 typ: HandshakeType::Certificate,
                 payload: HandshakePayload::CertificateTLS13(cert_body),
-            }),
+            },
+        };
+        let c = Message {
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::Handshake(payload),
         };
 
         trace!("sending certificate {:?}", c);
@@ -894,16 +1254,32 @@ struct ExpectCertificate {
     transcript: HandshakeHash,
     suite: &'static Tls13CipherSuite,
     key_schedule: KeyScheduleTrafficWithClientFinishedPending,
+    client_cert_type: CertificateType,
     send_ticket: bool,
 }
 
 impl State<ServerConnectionData> for ExpectCertificate {
     fn handle(mut self: Box<Self>, cx: &mut ServerContext<'_>, m: Message) -> hs::NextStateOrError {
-        let certp = require_handshake_msg!(
-            m,
-            HandshakeType::Certificate,
-            HandshakePayload::CertificateTLS13
-        )?;
+        let decompressed;
+        let certp = match &m.payload {
+            MessagePayload::Handshake(HandshakeMessagePayload {
+                payload: HandshakePayload::CompressedCertificate(compressed),
+                ..
+            }) => {
+                decompressed = compress::decompress_certificate(
+                    &self.config.cert_decompressors,
+                    self.config.max_handshake_message_size,
+                    compressed,
+                    cx.common,
+                )?;
+                &decompressed
+            }
+            _ => require_handshake_msg!(
+                m,
+                HandshakeType::Certificate,
+                HandshakePayload::CertificateTLS13
+            )?,
+        };
         self.transcript.add_message(&m);
 
         // We don't send any CertificateRequest extensions, so any extensions
@@ -915,11 +1291,17 @@ impl State<ServerConnectionData> for ExpectCertificate {
         }
 
         let client_cert = certp.convert();
+        check_cert_chain_limits(
+            cx.common,
+            &client_cert,
+            self.config.max_cert_chain_length,
+            self.config.max_cert_size,
+        )?;
 
         let mandatory = self
             .config
             .verifier
-            .client_auth_mandatory()
+            .client_auth_mandatory(cx.data.get_sni_str())
             .ok_or_else(|| {
                 debug!("could not determine if client auth is mandatory based on SNI");
                 cx.common
@@ -948,14 +1330,33 @@ impl State<ServerConnectionData> for ExpectCertificate {
             Some(chain) => chain,
         };
 
-        let now = std::time::SystemTime::now();
-        self.config
-            .verifier
-            .verify_client_cert(end_entity, intermediates, now)
-            .map_err(|err| {
-                hs::incompatible(cx.common, "certificate invalid");
-                err
-            })?;
+        let now = self.config.time_provider.now()?;
+        match (self.client_cert_type, &self.config.raw_public_key_verifier) {
+            (CertificateType::RawPublicKey, Some(verifier)) => {
+                if !intermediates.is_empty() {
+                    cx.common
+                        .send_fatal_alert(AlertDescription::BadCertificate);
+                    return Err(Error::PeerMisbehavedError(
+                        "client sent more than one raw public key".to_string(),
+                    ));
+                }
+                verifier
+                    .verify_raw_public_key(&end_entity.0, now)
+                    .map_err(|err| {
+                        hs::incompatible(cx.common, "raw public key invalid");
+                        err
+                    })?;
+            }
+            _ => {
+                self.config
+                    .verifier
+                    .verify_client_cert(end_entity, intermediates, now)
+                    .map_err(|err| {
+                        hs::incompatible(cx.common, "certificate invalid");
+                        err
+                    })?;
+            }
+        }
 
         Ok(Box::new(ExpectCertificateVerify {
             config: self.config,
@@ -963,9 +1364,14 @@ impl State<ServerConnectionData> for ExpectCertificate {
             transcript: self.transcript,
             key_schedule: self.key_schedule,
             client_cert,
+            client_cert_type: self.client_cert_type,
             send_ticket: self.send_ticket,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 struct ExpectCertificateVerify {
@@ -974,6 +1380,7 @@ struct ExpectCertificateVerify {
     suite: &'static Tls13CipherSuite,
     key_schedule: KeyScheduleTrafficWithClientFinishedPending,
     client_cert: Vec<Certificate>,
+    client_cert_type: CertificateType,
     send_ticket: bool,
 }
 
@@ -990,9 +1397,15 @@ impl State<ServerConnectionData> for ExpectCertificateVerify {
             let certs = &self.client_cert;
             let msg = verify::construct_tls13_client_verify_message(&handshake_hash);
 
-            self.config
-                .verifier
-                .verify_tls13_signature(&msg, &certs[0], sig)
+            match (self.client_cert_type, &self.config.raw_public_key_verifier) {
+                (CertificateType::RawPublicKey, Some(verifier)) => {
+                    verifier.verify_tls13_signature(&msg, &certs[0].0, sig)
+                }
+                _ => self
+                    .config
+                    .verifier
+                    .verify_tls13_signature(&msg, &certs[0], sig),
+            }
         };
 
         if let Err(e) = rc {
@@ -1013,6 +1426,10 @@ impl State<ServerConnectionData> for ExpectCertificateVerify {
             send_ticket: self.send_ticket,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 // --- Process (any number of) early ApplicationData messages,
@@ -1072,11 +1489,15 @@ impl State<ServerConnectionData> for ExpectEarlyData {
             )),
         }
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 // --- Process client's Finished ---
 fn get_server_session_value(
-    transcript: &mut HandshakeHash,
+    transcript: &HandshakeHash,
     suite: &'static Tls13CipherSuite,
     key_schedule: &KeyScheduleTraffic,
     cx: &ServerContext<'_>,
@@ -1113,48 +1534,58 @@ struct ExpectFinished {
 
 impl ExpectFinished {
     fn emit_ticket(
-        transcript: &mut HandshakeHash,
+        transcript: &HandshakeHash,
         suite: &'static Tls13CipherSuite,
         cx: &mut ServerContext<'_>,
         key_schedule: &KeyScheduleTraffic,
         config: &ServerConfig,
     ) -> Result<(), Error> {
-        let nonce = rand::random_vec(32)?;
+        let nonce = rand::random_vec(&*config.secure_random, 32)?;
         let now = ticketer::TimeBase::now()?;
-        let age_add = rand::random_u32()?;
-        let plain =
-            get_server_session_value(transcript, suite, key_schedule, cx, &nonce, now, age_add)
-                .get_encoding();
-
+        let age_add = rand::random_u32(&*config.secure_random)?;
+        let value =
+            get_server_session_value(transcript, suite, key_schedule, cx, &nonce, now, age_add);
+        let plain = value.get_encoding();
+
+        let lifetime_override = cx
+            .common
+            .server_certified_key
+            .as_ref()
+            .and_then(|key| key.ticket_lifetime);
         let stateless = config.ticketer.enabled();
         let (ticket, lifetime) = if stateless {
+            let plain = Zeroizing::new(plain);
             let ticket = match config.ticketer.encrypt(&plain) {
                 Some(t) => t,
                 None => return Ok(()),
             };
-            (ticket, config.ticketer.lifetime())
+            let lifetime = config.ticketer.lifetime();
+            (
+                ticket,
+                lifetime_override.map_or(lifetime, |l| l.min(lifetime)),
+            )
         } else {
-            let id = rand::random_vec(32)?;
+            let id = rand::random_vec(&*config.secure_random, 32)?;
+            let psk = persist::ResumptionPsk::new(&id, &value.master_secret.0);
             let stored = config
                 .session_storage
-                .put(id.clone(), plain);
+                .put_tls13_session(&psk, plain);
             if !stored {
                 trace!("resumption not available; not issuing ticket");
                 return Ok(());
             }
             let stateful_lifetime = 24 * 60 * 60; // this is a bit of a punt
-            (id, stateful_lifetime)
+            (id, lifetime_override.unwrap_or(stateful_lifetime))
         };
 
         let mut payload = NewSessionTicketPayloadTLS13::new(lifetime, age_add, nonce, ticket);
 
-        if config.max_early_data_size > 0 {
+        let max_early_data_size = max_early_data_size_for(config, cx.common);
+        if max_early_data_size > 0 {
             if !stateless {
                 payload
                     .exts
-                    .push(NewSessionTicketExtension::EarlyData(
-                        config.max_early_data_size,
-                    ));
+                    .push(NewSessionTicketExtension::EarlyData(max_early_data_size));
             } else {
                 // We implement RFC8446 section 8.1: by enforcing that 0-RTT is
                 // only possible if using stateful resumption
@@ -1171,7 +1602,6 @@ impl ExpectFinished {
         };
 
         trace!("sending new ticket {:?} (stateless: {})", m, stateless);
-        transcript.add_message(&m);
         cx.common.send_msg(m, true);
         Ok(())
     }
@@ -1199,6 +1629,8 @@ impl State<ServerConnectionData> for ExpectFinished {
         // nb. future derivations include Client Finished, but not the
         // main application data keying.
         self.transcript.add_message(&m);
+        let mut transcript_after_handshake = self.transcript.clone();
+        transcript_after_handshake.abandon_client_auth();
 
         cx.common.check_aligned_handshake()?;
 
@@ -1208,13 +1640,15 @@ impl State<ServerConnectionData> for ExpectFinished {
             .set_message_decrypter(self.suite.derive_decrypter(&client_key));
 
         if self.send_ticket {
-            Self::emit_ticket(
-                &mut self.transcript,
-                self.suite,
-                cx,
-                &key_schedule_traffic,
-                &self.config,
-            )?;
+            for _ in 0..self.config.send_tls13_tickets {
+                Self::emit_ticket(
+                    &self.transcript,
+                    self.suite,
+                    cx,
+                    &key_schedule_traffic,
+                    &self.config,
+                )?;
+            }
         }
 
         // Application data may now flow, even if we have client auth enabled.
@@ -1224,6 +1658,7 @@ impl State<ServerConnectionData> for ExpectFinished {
         {
             if cx.common.protocol == Protocol::Quic {
                 return Ok(Box::new(ExpectQuicTraffic {
+                    transcript: transcript_after_handshake,
                     key_schedule: key_schedule_traffic,
                     _fin_verified: fin,
                 }));
@@ -1231,23 +1666,198 @@ impl State<ServerConnectionData> for ExpectFinished {
         }
 
         Ok(Box::new(ExpectTraffic {
+            config: self.config,
             suite: self.suite,
+            transcript: transcript_after_handshake,
             key_schedule: key_schedule_traffic,
             want_write_key_update: false,
+            client_auth: None,
             _fin_verified: fin,
         }))
     }
+
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
 }
 
 // --- Process traffic ---
 struct ExpectTraffic {
+    config: Arc<ServerConfig>,
     suite: &'static Tls13CipherSuite,
+    transcript: HandshakeHash,
     key_schedule: KeyScheduleTraffic,
     want_write_key_update: bool,
+    client_auth: Option<PostHandshakeClientAuth>,
     _fin_verified: verify::FinishedMessageVerified,
 }
 
+/// An outstanding post-handshake client authentication exchange.
+struct PostHandshakeClientAuth {
+    context: Vec<u8>,
+    transcript: HandshakeHash,
+    expect: PostHandshakeClientAuthExpect,
+}
+
+enum PostHandshakeClientAuthExpect {
+    Certificate,
+    CertificateVerify(Vec<Certificate>),
+    Finished(Option<Vec<Certificate>>),
+}
+
 impl ExpectTraffic {
+    fn handle_client_auth(&mut self, cx: &mut ServerContext<'_>, m: &Message) -> Result<(), Error> {
+        let PostHandshakeClientAuth {
+            context,
+            mut transcript,
+            expect,
+        } = match self.client_auth.take() {
+            Some(pending) => pending,
+            None => {
+                return Err(inappropriate_handshake_message(
+                    &m.payload,
+                    &[ContentType::ApplicationData, ContentType::Handshake],
+                    &[HandshakeType::KeyUpdate],
+                ));
+            }
+        };
+
+        let expect = match expect {
+            PostHandshakeClientAuthExpect::Certificate => {
+                let decompressed;
+                let certp = match &m.payload {
+                    MessagePayload::Handshake(HandshakeMessagePayload {
+                        payload: HandshakePayload::CompressedCertificate(compressed),
+                        ..
+                    }) => {
+                        decompressed = compress::decompress_certificate(
+                            &self.config.cert_decompressors,
+                            self.config.max_handshake_message_size,
+                            compressed,
+                            cx.common,
+                        )?;
+                        &decompressed
+                    }
+                    _ => require_handshake_msg!(
+                        m,
+                        HandshakeType::Certificate,
+                        HandshakePayload::CertificateTLS13
+                    )?,
+                };
+
+                if certp.context.0 != context {
+                    cx.common
+                        .send_fatal_alert(AlertDescription::IllegalParameter);
+                    return Err(Error::PeerMisbehavedError(
+                        "client sent wrong certificate request context".to_string(),
+                    ));
+                }
+
+                if certp.any_entry_has_extension() {
+                    return Err(Error::PeerMisbehavedError(
+                        "client sent unsolicited cert extension".to_string(),
+                    ));
+                }
+
+                transcript.add_message(m);
+                let client_cert = certp.convert();
+                check_cert_chain_limits(
+                    cx.common,
+                    &client_cert,
+                    self.config.max_cert_chain_length,
+                    self.config.max_cert_size,
+                )?;
+
+                let mandatory = self
+                    .config
+                    .verifier
+                    .client_auth_mandatory(cx.data.get_sni_str())
+                    .ok_or_else(|| {
+                        debug!("could not determine if client auth is mandatory based on SNI");
+                        cx.common
+                            .send_fatal_alert(AlertDescription::AccessDenied);
+                        Error::General("client rejected by client_auth_mandatory".into())
+                    })?;
+
+                match client_cert.split_first() {
+                    None if !mandatory => {
+                        debug!("client auth requested but no certificate supplied");
+                        PostHandshakeClientAuthExpect::Finished(None)
+                    }
+                    None => {
+                        cx.common
+                            .send_fatal_alert(AlertDescription::CertificateRequired);
+                        return Err(Error::NoCertificatesPresented);
+                    }
+                    Some((end_entity, intermediates)) => {
+                        let now = self.config.time_provider.now()?;
+                        self.config
+                            .verifier
+                            .verify_client_cert(end_entity, intermediates, now)
+                            .map_err(|err| {
+                                hs::incompatible(cx.common, "certificate invalid");
+                                err
+                            })?;
+                        PostHandshakeClientAuthExpect::CertificateVerify(client_cert)
+                    }
+                }
+            }
+            PostHandshakeClientAuthExpect::CertificateVerify(client_cert) => {
+                let sig = require_handshake_msg!(
+                    m,
+                    HandshakeType::CertificateVerify,
+                    HandshakePayload::CertificateVerify
+                )?;
+                let msg =
+                    verify::construct_tls13_client_verify_message(&transcript.get_current_hash());
+
+                if let Err(e) = self
+                    .config
+                    .verifier
+                    .verify_tls13_signature(&msg, &client_cert[0], sig)
+                {
+                    cx.common
+                        .send_fatal_alert(AlertDescription::AccessDenied);
+                    return Err(e);
+                }
+
+                trace!("client CertificateVerify OK");
+                transcript.add_message(m);
+                PostHandshakeClientAuthExpect::Finished(Some(client_cert))
+            }
+            PostHandshakeClientAuthExpect::Finished(client_cert) => {
+                let finished =
+                    require_handshake_msg!(m, HandshakeType::Finished, HandshakePayload::Finished)?;
+                let expect_verify_data = self
+                    .key_schedule
+                    .sign_post_handshake_client_finish(&transcript.get_current_hash());
+
+                constant_time::verify_slices_are_equal(expect_verify_data.as_ref(), &finished.0)
+                    .map_err(|_| {
+                        cx.common
+                            .send_fatal_alert(AlertDescription::DecryptError);
+                        warn!("Finished wrong");
+                        Error::DecryptError
+                    })?;
+
+                cx.common.check_aligned_handshake()?;
+
+                debug!("post-handshake client authentication complete");
+                if let Some(client_cert) = client_cert {
+                    cx.common.peer_certificates = Some(client_cert);
+                }
+                return Ok(());
+            }
+        };
+
+        self.client_auth = Some(PostHandshakeClientAuth {
+            context,
+            transcript,
+            expect,
+        });
+        Ok(())
+    }
+
     fn handle_key_update(
         &mut self,
         common: &mut CommonState,
@@ -1289,6 +1899,18 @@ impl ExpectTraffic {
 
         Ok(())
     }
+
+    /// Send `key_update` and start using the next write key.
+    fn write_key_update(&mut self, common: &mut CommonState, key_update: Message) {
+        common.send_msg_encrypt(key_update.into());
+
+        let write_key = self
+            .key_schedule
+            .next_server_application_traffic_secret();
+        common
+            .record_layer
+            .set_message_encrypter(self.suite.derive_encrypter(&write_key));
+    }
 }
 
 impl State<ServerConnectionData> for ExpectTraffic {
@@ -1301,6 +1923,7 @@ impl State<ServerConnectionData> for ExpectTraffic {
                 payload: HandshakePayload::KeyUpdate(key_update),
                 ..
             }) => self.handle_key_update(cx.common, &key_update)?,
+            MessagePayload::Handshake(_) => self.handle_client_auth(cx, &m)?,
             payload => {
                 return Err(inappropriate_handshake_message(
                     &payload,
@@ -1313,6 +1936,10 @@ impl State<ServerConnectionData> for ExpectTraffic {
         Ok(self)
     }
 
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
+
     fn export_keying_material(
         &self,
         output: &mut [u8],
@@ -1324,22 +1951,65 @@ impl State<ServerConnectionData> for ExpectTraffic {
     }
 
     fn perhaps_write_key_update(&mut self, common: &mut CommonState) {
-        if self.want_write_key_update {
+        if self.want_write_key_update
+            || common
+                .record_layer
+                .wants_key_update(self.config.key_update_threshold)
+        {
             self.want_write_key_update = false;
-            common.send_msg_encrypt(Message::build_key_update_notify().into());
+            self.write_key_update(common, Message::build_key_update_notify());
+        }
+    }
 
-            let write_key = self
-                .key_schedule
-                .next_server_application_traffic_secret();
-            common
-                .record_layer
-                .set_message_encrypter(self.suite.derive_encrypter(&write_key));
+    fn refresh_traffic_keys(&mut self, common: &mut CommonState) -> Result<(), Error> {
+        // This also answers any update the peer requested.
+        self.want_write_key_update = false;
+        self.write_key_update(common, Message::build_key_update_request());
+        Ok(())
+    }
+
+    fn request_client_auth(&mut self, common: &mut CommonState) -> Result<(), Error> {
+        if self.client_auth.is_some() {
+            return Err(Error::General(
+                "client authentication already requested".into(),
+            ));
         }
+
+        if !self.config.verifier.offer_client_auth() {
+            return Err(Error::General(
+                "client authentication is not configured".into(),
+            ));
+        }
+
+        // The context must be unique within the connection.
+        let context = rand::random_vec(&*self.config.secure_random, 32)?;
+        let cr = make_certificate_request(&self.config, context.clone())?;
+
+        let m = Message {
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::Handshake(HandshakeMessagePayload {
+                typ: HandshakeType::CertificateRequest,
+                payload: HandshakePayload::CertificateRequestTLS13(cr),
+            }),
+        };
+
+        trace!("Sending post-handshake CertificateRequest {:?}", m);
+        let mut transcript = self.transcript.clone();
+        transcript.add_message(&m);
+        common.send_msg(m, true);
+
+        self.client_auth = Some(PostHandshakeClientAuth {
+            context,
+            transcript,
+            expect: PostHandshakeClientAuthExpect::Certificate,
+        });
+        Ok(())
     }
 }
 
 #[cfg(feature = "quic")]
 struct ExpectQuicTraffic {
+    transcript: HandshakeHash,
     key_schedule: KeyScheduleTraffic,
     _fin_verified: verify::FinishedMessageVerified,
 }
@@ -1351,6 +2021,10 @@ impl State<ServerConnectionData> for ExpectQuicTraffic {
         Err(inappropriate_message(&m.payload, &[]))
     }
 
+    fn handshake_transcript(&self) -> Option<&HandshakeHash> {
+        Some(&self.transcript)
+    }
+
     fn export_keying_material(
         &self,
         output: &mut [u8],
@@ -1,13 +1,18 @@
 use crate::error::Error;
 use crate::key;
+use crate::msgs::base::{PayloadU16, PayloadU24};
 use crate::msgs::enums::{SignatureAlgorithm, SignatureScheme};
+use crate::msgs::handshake::{Credential, DelegatedCredentialPayload};
+use crate::verify;
+use crate::x509;
 
-use ring::signature::{self, EcdsaKeyPair, Ed25519KeyPair, RsaKeyPair};
+use ring::signature::{self, EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair};
 
 use std::convert::TryFrom;
 use std::error::Error as StdError;
 use std::fmt;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// An abstract signing key.
 pub trait SigningKey: Send + Sync {
@@ -19,6 +24,15 @@ pub trait SigningKey: Send + Sync {
 
     /// What kind of key we have.
     fn algorithm(&self) -> SignatureAlgorithm;
+
+    /// The public half of this key, encoded as in the subjectPublicKey
+    /// of a certificate.
+    ///
+    /// The default implementation returns None, so we can't check
+    /// this key matches its certificate.
+    fn public_key(&self) -> Option<&[u8]> {
+        None
+    }
 }
 
 /// A thing that can sign a message.
@@ -48,6 +62,27 @@ pub struct CertifiedKey {
     /// certificate is included on those logs.  This must be
     /// a `SignedCertificateTimestampList` encoding; see RFC6962.
     pub sct_list: Option<Vec<u8>>,
+
+    /// An optional delegated credential, used in place of `key` to
+    /// sign TLS1.3 handshakes with clients that accept it.
+    pub delegated_credential: Option<DelegatedCredential>,
+
+    /// The lifetime in seconds of TLS1.3 tickets issued to clients
+    /// this key is chosen for, overriding the server's default.  With
+    /// a [`ServerConfig::ticketer`], this is capped to its lifetime.
+    ///
+    /// RFC8446 allows at most seven days (604800 seconds); handshakes
+    /// choosing a key with a longer lifetime fail.
+    ///
+    /// [`ServerConfig::ticketer`]: crate::ServerConfig::ticketer
+    pub ticket_lifetime: Option<u32>,
+
+    /// Overrides [`ServerConfig::max_early_data_size`] for clients
+    /// this key is chosen for, both in the tickets they are issued and
+    /// when they resume.
+    ///
+    /// [`ServerConfig::max_early_data_size`]: crate::ServerConfig::max_early_data_size
+    pub max_early_data_size: Option<u32>,
 }
 
 impl CertifiedKey {
@@ -61,6 +96,9 @@ impl CertifiedKey {
             key,
             ocsp: None,
             sct_list: None,
+            delegated_credential: None,
+            ticket_lifetime: None,
+            max_early_data_size: None,
         }
     }
 
@@ -69,6 +107,42 @@ impl CertifiedKey {
         self.cert.get(0).ok_or(SignError(()))
     }
 
+    /// Check `key` is the private key for the end-entity certificate,
+    /// to detect a certificate configured with the wrong key.
+    ///
+    /// Keys whose [`SigningKey::public_key`] is None are not checked.
+    pub fn keys_match(&self) -> Result<(), Error> {
+        let public_key = match self.key.public_key() {
+            Some(public_key) => public_key,
+            None => return Ok(()),
+        };
+
+        let end_entity_cert = self
+            .end_entity_cert()
+            .map_err(|SignError(())| {
+                Error::General("No end-entity certificate in certificate chain".to_string())
+            })?;
+        let (algorithm, cert_public_key) = x509::certified_public_key(&end_entity_cert.0)
+            .ok_or_else(|| {
+                Error::General("End-entity certificate has an unsupported public key".to_string())
+            })?;
+
+        if algorithm != self.key.algorithm() {
+            return Err(Error::General(format!(
+                "Private key is {:?} but end-entity certificate has an {:?} public key",
+                self.key.algorithm(),
+                algorithm
+            )));
+        }
+        if cert_public_key != public_key {
+            return Err(Error::General(
+                "Private key does not match end-entity certificate".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
     /// Check the certificate chain for validity:
     /// - it should be non-empty list
     /// - the first certificate should be parsable as a x509v3,
@@ -120,6 +194,108 @@ impl CertifiedKey {
     }
 }
 
+/// A delegated credential (RFC9345): a short-lived key which the
+/// holder of a certificate's key has authorised to sign TLS1.3
+/// handshakes in its place.
+///
+/// Attach one to a [`CertifiedKey`] to use it.  Since a delegated
+/// credential is valid for at most seven days, you'll typically
+/// make new ones from a [`ResolvesServerCert`].
+///
+/// [`ResolvesServerCert`]: crate::server::ResolvesServerCert
+#[derive(Clone)]
+pub struct DelegatedCredential {
+    key: Arc<dyn SigningKey>,
+    payload: DelegatedCredentialPayload,
+}
+
+impl DelegatedCredential {
+    /// Delegate to `key` until `not_after`, using the end-entity
+    /// certificate and key of `certified_key`.
+    ///
+    /// `spki` is the DER-encoded SubjectPublicKeyInfo of `key`, and
+    /// `scheme` is the TLS1.3 signature scheme `key` signs handshakes
+    /// with.  Clients reject credentials valid for more than seven
+    /// days, or made with a certificate that lacks the DelegationUsage
+    /// extension or the digitalSignature key usage.
+    pub fn new(
+        certified_key: &CertifiedKey,
+        key: Arc<dyn SigningKey>,
+        spki: Vec<u8>,
+        scheme: SignatureScheme,
+        not_after: SystemTime,
+    ) -> Result<Self, Error> {
+        let end_entity = certified_key
+            .end_entity_cert()
+            .map_err(|SignError(())| {
+                Error::General("No end-entity certificate in certificate chain".to_string())
+            })?;
+        let not_before = x509::not_before(&end_entity.0).ok_or_else(|| {
+            Error::General("End-entity certificate has invalid validity".to_string())
+        })?;
+        let valid_time = not_after
+            .duration_since(UNIX_EPOCH + Duration::from_secs(not_before))
+            .ok()
+            .and_then(|valid_time| u32::try_from(valid_time.as_secs()).ok())
+            .ok_or_else(|| {
+                Error::General("Delegated credential expiry is out of range".to_string())
+            })?;
+
+        if !supported_sign_tls13().contains(&scheme) || key.choose_scheme(&[scheme]).is_none() {
+            return Err(Error::General(format!(
+                "Delegated key cannot sign with {:?}",
+                scheme
+            )));
+        }
+
+        let credential = Credential {
+            valid_time,
+            scheme,
+            public_key: PayloadU24::new(spki),
+        };
+
+        let signer = certified_key
+            .key
+            .choose_scheme(supported_sign_tls13())
+            .ok_or_else(|| {
+                Error::General("Certified key cannot sign delegated credentials".to_string())
+            })?;
+        let algorithm = signer.scheme();
+        let signature = signer.sign(&verify::construct_delegated_credential_message(
+            end_entity,
+            &credential,
+            algorithm,
+        ))?;
+
+        Ok(Self {
+            key,
+            payload: DelegatedCredentialPayload {
+                credential,
+                algorithm,
+                signature: PayloadU16::new(signature),
+            },
+        })
+    }
+
+    /// The signature scheme the delegated key signs handshakes with.
+    pub fn scheme(&self) -> SignatureScheme {
+        self.payload.credential.scheme
+    }
+
+    /// The signature scheme the certified key signed this credential with.
+    pub fn algorithm(&self) -> SignatureScheme {
+        self.payload.algorithm
+    }
+
+    pub(crate) fn key(&self) -> &dyn SigningKey {
+        &*self.key
+    }
+
+    pub(crate) fn payload(&self) -> &DelegatedCredentialPayload {
+        &self.payload
+    }
+}
+
 /// Parse `der` as any supported key encoding/type, returning
 /// the first which works.
 pub fn any_supported_type(der: &key::PrivateKey) -> Result<Arc<dyn SigningKey>, SignError> {
@@ -133,6 +309,10 @@ pub fn any_supported_type(der: &key::PrivateKey) -> Result<Arc<dyn SigningKey>,
 }
 
 /// Parse `der` as any ECDSA key type, returning the first which works.
+///
+/// NIST P-256 and P-384 keys are supported, and sign using
+/// `ecdsa_secp256r1_sha256` and `ecdsa_secp384r1_sha384` respectively.
+/// P-521 keys are rejected: *ring* has no implementation of that curve.
 pub fn any_ecdsa_type(der: &key::PrivateKey) -> Result<Arc<dyn SigningKey>, SignError> {
     if let Ok(ecdsa_p256) = EcdsaSigningKey::new(
         der,
@@ -204,6 +384,10 @@ impl SigningKey for RsaSigningKey {
     fn algorithm(&self) -> SignatureAlgorithm {
         SignatureAlgorithm::RSA
     }
+
+    fn public_key(&self) -> Option<&[u8]> {
+        Some(self.key.public_key().as_ref())
+    }
 }
 
 #[allow(clippy::upper_case_acronyms)]
@@ -302,6 +486,10 @@ impl SigningKey for EcdsaSigningKey {
         use crate::msgs::handshake::DecomposedSignatureScheme;
         self.scheme.sign()
     }
+
+    fn public_key(&self) -> Option<&[u8]> {
+        Some(self.key.public_key().as_ref())
+    }
 }
 
 struct EcdsaSigner {
@@ -368,6 +556,10 @@ impl SigningKey for Ed25519SigningKey {
         use crate::msgs::handshake::DecomposedSignatureScheme;
         self.scheme.sign()
     }
+
+    fn public_key(&self) -> Option<&[u8]> {
+        Some(self.key.public_key().as_ref())
+    }
 }
 
 struct Ed25519Signer {
@@ -385,6 +577,39 @@ impl Signer for Ed25519Signer {
     }
 }
 
+/// Can we sign with `scheme`, in any TLS version?
+pub(crate) fn supports_scheme(scheme: SignatureScheme) -> bool {
+    ALL_RSA_SCHEMES.contains(&scheme) || supported_sign_tls13().contains(&scheme)
+}
+
+/// Check `schemes` is a non-empty list of distinct schemes, each of
+/// which is `supported`.
+pub(crate) fn check_signature_schemes(
+    schemes: &[SignatureScheme],
+    supported: impl Fn(SignatureScheme) -> bool,
+) -> Result<(), Error> {
+    if schemes.is_empty() {
+        return Err(Error::General("no signature schemes given".to_string()));
+    }
+
+    for (i, scheme) in schemes.iter().enumerate() {
+        if schemes[..i].contains(scheme) {
+            return Err(Error::General(format!(
+                "signature scheme {:?} is repeated",
+                scheme
+            )));
+        }
+        if !supported(*scheme) {
+            return Err(Error::General(format!(
+                "signature scheme {:?} is not supported",
+                scheme
+            )));
+        }
+    }
+
+    Ok(())
+}
+
 /// The set of schemes we support for signatures and
 /// that are allowed for TLS1.3.
 pub fn supported_sign_tls13() -> &'static [SignatureScheme] {
@@ -409,3 +634,79 @@ impl fmt::Display for SignError {
 }
 
 impl StdError for SignError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+
+    fn generate_ecdsa_key(alg: &'static signature::EcdsaSigningAlgorithm) -> key::PrivateKey {
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &SystemRandom::new()).unwrap();
+        key::PrivateKey(pkcs8.as_ref().to_vec())
+    }
+
+    fn check_ecdsa_round_trip(
+        alg: &'static signature::EcdsaSigningAlgorithm,
+        verify_alg: &'static signature::EcdsaVerificationAlgorithm,
+        scheme: SignatureScheme,
+    ) {
+        let key = any_ecdsa_type(&generate_ecdsa_key(alg)).unwrap();
+        assert_eq!(key.algorithm(), SignatureAlgorithm::ECDSA);
+
+        // only the scheme matching the key's curve is acceptable
+        let other_schemes = [
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ECDSA_NISTP521_SHA512,
+        ];
+        let other_schemes = other_schemes
+            .iter()
+            .filter(|s| **s != scheme)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert!(key
+            .choose_scheme(&other_schemes)
+            .is_none());
+
+        let signer = key
+            .choose_scheme(supported_sign_tls13())
+            .unwrap();
+        assert_eq!(signer.scheme(), scheme);
+
+        let message = b"hello world";
+        let sig = signer.sign(message).unwrap();
+        let public_key = key.public_key().unwrap();
+        let public_key = signature::UnparsedPublicKey::new(verify_alg, public_key);
+        public_key
+            .verify(message, &sig)
+            .unwrap();
+        assert!(public_key
+            .verify(b"goodbye world", &sig)
+            .is_err());
+    }
+
+    #[test]
+    fn ecdsa_p256_signs_and_verifies() {
+        check_ecdsa_round_trip(
+            &signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+            &signature::ECDSA_P256_SHA256_ASN1,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+        );
+    }
+
+    #[test]
+    fn ecdsa_p384_signs_and_verifies() {
+        check_ecdsa_round_trip(
+            &signature::ECDSA_P384_SHA384_ASN1_SIGNING,
+            &signature::ECDSA_P384_SHA384_ASN1,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+        );
+    }
+
+    #[test]
+    fn ecdsa_p521_is_rejected() {
+        let key = key::PrivateKey(include_bytes!("testdata/ecdsa-p521.pk8").to_vec());
+        assert!(any_ecdsa_type(&key).is_err());
+        assert!(any_supported_type(&key).is_err());
+    }
+}
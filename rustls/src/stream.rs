@@ -1,6 +1,6 @@
 use crate::conn::{ConnectionCommon, SideData};
 
-use std::io::{IoSlice, Read, Result, Write};
+use std::io::{BufRead, IoSlice, Read, Result, Write};
 use std::ops::{Deref, DerefMut};
 
 /// This type implements `io::Read` and `io::Write`, encapsulating
@@ -41,15 +41,10 @@ where
 
         Ok(())
     }
-}
 
-impl<'a, C, T, S> Read for Stream<'a, C, T>
-where
-    C: 'a + DerefMut + Deref<Target = ConnectionCommon<S>>,
-    T: 'a + Read + Write,
-    S: SideData,
-{
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+    /// Do the IO needed before reading plaintext.  Returns `true` if
+    /// the transport reached EOF with no plaintext left to read.
+    fn prepare_read(&mut self) -> Result<bool> {
         self.complete_prior_io()?;
 
         // We call complete_io() in a loop since a single call may read only
@@ -63,39 +58,60 @@ where
             if at_eof {
                 if let Ok(io_state) = self.conn.process_new_packets() {
                     if at_eof && io_state.plaintext_bytes_to_read() == 0 {
-                        return Ok(0);
+                        return Ok(true);
                     }
                 }
                 break;
             }
         }
 
+        Ok(false)
+    }
+}
+
+impl<'a, C, T, S> Read for Stream<'a, C, T>
+where
+    C: 'a + DerefMut + Deref<Target = ConnectionCommon<S>>,
+    T: 'a + Read + Write,
+    S: SideData,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.prepare_read()? {
+            return Ok(0);
+        }
+
         self.conn.reader().read(buf)
     }
 
     #[cfg(read_buf)]
-    fn read_buf(&mut self, buf: &mut std::io::ReadBuf<'_>) -> Result<()> {
-        self.complete_prior_io()?;
+    fn read_buf(&mut self, cursor: std::io::BorrowedCursor<'_>) -> Result<()> {
+        if self.prepare_read()? {
+            return Ok(());
+        }
 
-        // We call complete_io() in a loop since a single call may read only
-        // a partial packet from the underlying transport. A full packet is
-        // needed to get more plaintext, which we must do if EOF has not been
-        // hit. Otherwise, we will prematurely signal EOF by returning without
-        // writing anything. We determine if EOF has actually been hit by
-        // checking if 0 bytes were read from the underlying transport.
-        while self.conn.wants_read() {
-            let at_eof = self.conn.complete_io(self.sock)?.0 == 0;
-            if at_eof {
-                if let Ok(io_state) = self.conn.process_new_packets() {
-                    if at_eof && io_state.plaintext_bytes_to_read() == 0 {
-                        return Ok(());
-                    }
-                }
-                break;
-            }
+        self.conn.reader().read_buf(cursor)
+    }
+}
+
+impl<'a, C, T, S> BufRead for Stream<'a, C, T>
+where
+    C: 'a + DerefMut + Deref<Target = ConnectionCommon<S>>,
+    T: 'a + Read + Write,
+    S: 'static + SideData,
+{
+    /// Borrow received plaintext without copying it, doing IO first
+    /// only if none is buffered.  See [`Reader`](crate::Reader)'s
+    /// `fill_buf` for how much is returned at once.
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if self.prepare_read()? {
+            return Ok(&[]);
         }
 
-        self.conn.reader().read_buf(buf)
+        self.conn.reader().into_first_chunk()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.conn.reader().consume(amt)
     }
 }
 
@@ -210,8 +226,27 @@ where
     }
 
     #[cfg(read_buf)]
-    fn read_buf(&mut self, buf: &mut std::io::ReadBuf<'_>) -> Result<()> {
-        self.as_stream().read_buf(buf)
+    fn read_buf(&mut self, cursor: std::io::BorrowedCursor<'_>) -> Result<()> {
+        self.as_stream().read_buf(cursor)
+    }
+}
+
+impl<C, T, S> BufRead for StreamOwned<C, T>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    T: Read + Write,
+    S: 'static + SideData,
+{
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if self.as_stream().prepare_read()? {
+            return Ok(&[]);
+        }
+
+        self.conn.reader().into_first_chunk()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.conn.reader().consume(amt)
     }
 }
 
@@ -225,6 +260,10 @@ where
         self.as_stream().write(buf)
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        self.as_stream().write_vectored(bufs)
+    }
+
     fn flush(&mut self) -> Result<()> {
         self.as_stream().flush()
     }
@@ -1,3 +1,5 @@
+use crate::crypto::{CryptoProvider, HashAlgorithm};
+use crate::error::Error;
 use crate::msgs::enums::ProtocolVersion;
 use crate::msgs::enums::{CipherSuite, SignatureAlgorithm, SignatureScheme};
 use crate::msgs::handshake::DecomposedSignatureScheme;
@@ -5,6 +7,8 @@ use crate::msgs::handshake::DecomposedSignatureScheme;
 use crate::tls12::Tls12CipherSuite;
 #[cfg(feature = "tls12")]
 use crate::tls12::{
+    TLS_ECDHE_ECDSA_WITH_AES_128_CCM,
+    TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8,
     TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
     // TLS1.2 suites
     TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
@@ -23,7 +27,8 @@ use crate::versions::{SupportedProtocolVersion, TLS13};
 
 /// Bulk symmetric encryption scheme used by a cipher suite.
 #[allow(non_camel_case_types)]
-#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BulkAlgorithm {
     /// AES with 128-bit keys in Galois counter mode.
     Aes128Gcm,
@@ -33,6 +38,24 @@ pub enum BulkAlgorithm {
 
     /// Chacha20 for confidentiality with poly1305 for authenticity.
     Chacha20Poly1305,
+
+    /// AES with 128-bit keys in counter with CBC-MAC mode, with a
+    /// 16-byte tag.
+    Aes128Ccm,
+
+    /// AES with 128-bit keys in counter with CBC-MAC mode, with an
+    /// 8-byte tag.
+    Aes128Ccm8,
+}
+
+impl BulkAlgorithm {
+    /// The length of this algorithm's key.
+    pub fn key_len(self) -> usize {
+        match self {
+            Self::Aes128Gcm | Self::Aes128Ccm | Self::Aes128Ccm8 => 16,
+            Self::Aes256Gcm | Self::Chacha20Poly1305 => 32,
+        }
+    }
 }
 
 /// Common state for cipher suites (both for TLS 1.2 and TLS 1.3)
@@ -42,8 +65,6 @@ pub struct CipherSuiteCommon {
 
     /// How to do bulk encryption.
     pub bulk: BulkAlgorithm,
-
-    pub(crate) aead_algorithm: &'static ring::aead::Algorithm,
 }
 
 /// A cipher suite supported by rustls.
@@ -69,11 +90,31 @@ impl SupportedCipherSuite {
         }
     }
 
+    /// Which hash function this suite uses, without reference
+    /// to a particular implementation.
+    pub fn hash(&self) -> HashAlgorithm {
+        match self {
+            #[cfg(feature = "tls12")]
+            SupportedCipherSuite::Tls12(inner) => inner.hash,
+            SupportedCipherSuite::Tls13(inner) => inner.hash,
+        }
+    }
+
     /// The cipher suite's identifier
     pub fn suite(&self) -> CipherSuite {
         self.common().suite
     }
 
+    /// Which bulk encryption algorithm this suite uses.
+    pub fn bulk_algorithm(&self) -> BulkAlgorithm {
+        self.common().bulk
+    }
+
+    /// Return true if this is a TLS1.3 suite.
+    pub fn is_tls13(&self) -> bool {
+        self.tls13().is_some()
+    }
+
     pub(crate) fn common(&self) -> &CipherSuiteCommon {
         match self {
             #[cfg(feature = "tls12")]
@@ -114,6 +155,26 @@ impl SupportedCipherSuite {
 }
 
 /// A list of all the cipher suites supported by rustls.
+///
+/// Iterate over it to list or filter suites by their properties:
+///
+/// ```
+/// for suite in rustls::ALL_CIPHER_SUITES {
+///     println!(
+///         "{:?}: {:?}, {:?}, {:?}",
+///         suite.suite(),
+///         suite.version().version,
+///         suite.bulk_algorithm(),
+///         suite.hash()
+///     );
+/// }
+///
+/// let tls13_only = rustls::ALL_CIPHER_SUITES
+///     .iter()
+///     .filter(|suite| suite.is_tls13())
+///     .count();
+/// assert_eq!(tls13_only, 3);
+/// ```
 pub static ALL_CIPHER_SUITES: &[SupportedCipherSuite] = &[
     // TLS1.3 suites
     TLS13_AES_256_GCM_SHA384,
@@ -132,13 +193,37 @@ pub static ALL_CIPHER_SUITES: &[SupportedCipherSuite] = &[
     TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
     #[cfg(feature = "tls12")]
     TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_ECDSA_WITH_AES_128_CCM,
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8,
 ];
 
 /// The cipher suite configuration that an application should use by default.
 ///
 /// This will be [`ALL_CIPHER_SUITES`] sans any supported cipher suites that
-/// shouldn't be enabled by most applications.
-pub static DEFAULT_CIPHER_SUITES: &[SupportedCipherSuite] = ALL_CIPHER_SUITES;
+/// shouldn't be enabled by most applications.  The AES-CCM suites are
+/// excluded: they are intended for constrained peers, and need a
+/// [`CryptoProvider`] other than the default.
+pub static DEFAULT_CIPHER_SUITES: &[SupportedCipherSuite] = &[
+    // TLS1.3 suites
+    TLS13_AES_256_GCM_SHA384,
+    TLS13_AES_128_GCM_SHA256,
+    TLS13_CHACHA20_POLY1305_SHA256,
+    // TLS1.2 suites
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+];
 
 // These both O(N^2)!
 pub(crate) fn choose_ciphersuite_preferring_client(
@@ -171,6 +256,33 @@ pub(crate) fn choose_ciphersuite_preferring_server(
     None
 }
 
+/// Return `suites` reordered so that those in `order` come first, in
+/// that order, followed by the rest in their existing order.
+///
+/// Fails if `order` contains a suite which is not in `suites`.
+pub(crate) fn reorder_suites(
+    suites: &[SupportedCipherSuite],
+    order: &[SupportedCipherSuite],
+) -> Result<Vec<SupportedCipherSuite>, Error> {
+    if let Some(unknown) = order
+        .iter()
+        .find(|suite| !suites.contains(suite))
+    {
+        return Err(Error::General(format!(
+            "cipher suite {:?} is not configured",
+            unknown.suite()
+        )));
+    }
+
+    let mut reordered = Vec::with_capacity(suites.len());
+    for suite in order.iter().chain(suites.iter()) {
+        if !reordered.contains(suite) {
+            reordered.push(*suite);
+        }
+    }
+    Ok(reordered)
+}
+
 /// Return a list of the ciphersuites in `all` with the suites
 /// incompatible with `SignatureAlgorithm` `sigalg` removed.
 pub(crate) fn reduce_given_sigalg(
@@ -195,6 +307,18 @@ pub(crate) fn reduce_given_version(
         .collect()
 }
 
+/// Return a list of the ciphersuites in `all` with the suites
+/// whose bulk encryption `provider` does not support removed.
+pub(crate) fn reduce_given_provider(
+    all: &[SupportedCipherSuite],
+    provider: &dyn CryptoProvider,
+) -> Vec<SupportedCipherSuite> {
+    all.iter()
+        .filter(|&&suite| provider.supports_aead(suite.common().bulk))
+        .copied()
+        .collect()
+}
+
 /// Return true if `sigscheme` is usable by any of the given suites.
 pub(crate) fn compatible_sigscheme_for_suites(
     sigscheme: SignatureScheme,
@@ -254,6 +378,48 @@ mod test {
         println!("{:?}", ALL_CIPHER_SUITES);
     }
 
+    #[test]
+    fn test_tls13_suites_report_properties() {
+        for (suite, hash, bulk) in &[
+            (
+                TLS13_AES_128_GCM_SHA256,
+                HashAlgorithm::Sha256,
+                BulkAlgorithm::Aes128Gcm,
+            ),
+            (
+                TLS13_AES_256_GCM_SHA384,
+                HashAlgorithm::Sha384,
+                BulkAlgorithm::Aes256Gcm,
+            ),
+            (
+                TLS13_CHACHA20_POLY1305_SHA256,
+                HashAlgorithm::Sha256,
+                BulkAlgorithm::Chacha20Poly1305,
+            ),
+        ] {
+            assert!(suite.is_tls13());
+            assert_eq!(suite.version(), &TLS13);
+            assert_eq!(suite.hash(), *hash);
+            assert_eq!(suite.bulk_algorithm(), *bulk);
+            assert_eq!(suite.hash_algorithm().output_len, hash.output_len());
+        }
+    }
+
+    #[cfg(feature = "tls12")]
+    #[test]
+    fn test_tls12_suites_are_not_tls13() {
+        for suite in ALL_CIPHER_SUITES
+            .iter()
+            .filter(|suite| suite.version() == &TLS12)
+        {
+            assert!(!suite.is_tls13());
+        }
+        assert_eq!(
+            TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384.hash(),
+            HashAlgorithm::Sha384
+        );
+    }
+
     #[test]
     fn test_can_resume_to() {
         assert!(TLS13_AES_128_GCM_SHA256
@@ -1,8 +1,10 @@
-use crate::rand;
+use crate::rand::{self, SecureRandom};
 use crate::server::ProducesTickets;
 use crate::Error;
 
 use ring::aead;
+#[cfg(test)]
+use std::collections::HashSet;
 use std::mem;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::time;
@@ -28,29 +30,70 @@ impl TimeBase {
     }
 }
 
+/// The AEAD algorithm a [`Ticketer`] encrypts tickets with.
+///
+/// Each ticket records the algorithm it was made with, and a ticketer
+/// rejects tickets made with any other algorithm.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TicketAlgorithm {
+    /// ChaCha20-Poly1305, with a 256-bit key.  This is the default.
+    #[default]
+    ChaCha20Poly1305,
+    /// AES-128-GCM.
+    Aes128Gcm,
+    /// AES-256-GCM.
+    Aes256Gcm,
+}
+
+impl TicketAlgorithm {
+    fn aead_algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Self::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+            Self::Aes128Gcm => &aead::AES_128_GCM,
+            Self::Aes256Gcm => &aead::AES_256_GCM,
+        }
+    }
+
+    /// The byte which starts each ticket made with this algorithm.
+    fn tag(self) -> u8 {
+        match self {
+            Self::ChaCha20Poly1305 => 1,
+            Self::Aes128Gcm => 2,
+            Self::Aes256Gcm => 3,
+        }
+    }
+}
+
 /// This is a `ProducesTickets` implementation which uses
 /// any *ring* `aead::Algorithm` to encrypt and authentication
 /// the ticket payload.  It does not enforce any lifetime
 /// constraint.
 struct AeadTicketer {
-    alg: &'static aead::Algorithm,
+    alg: TicketAlgorithm,
     key: aead::LessSafeKey,
     lifetime: u32,
+    rng: Arc<dyn SecureRandom>,
 }
 
 impl AeadTicketer {
-    /// Make a ticketer with recommended configuration and a random key.
-    fn new() -> Result<Self, rand::GetRandomFailed> {
+    /// Make a ticketer for `alg` with recommended configuration and
+    /// a key from `rng`, which also provides its nonces.
+    fn new(
+        rng: Arc<dyn SecureRandom>,
+        alg: TicketAlgorithm,
+    ) -> Result<Self, rand::GetRandomFailed> {
         let mut key = [0u8; 32];
-        rand::fill_random(&mut key)?;
+        let key = &mut key[..alg.aead_algorithm().key_len()];
+        rng.fill(key)
+            .map_err(|_| rand::GetRandomFailed)?;
 
-        let alg = &aead::CHACHA20_POLY1305;
-        let key = aead::UnboundKey::new(alg, &key).unwrap();
+        let key = aead::UnboundKey::new(alg.aead_algorithm(), key).unwrap();
 
         Ok(Self {
             alg,
             key: aead::LessSafeKey::new(key),
             lifetime: 60 * 60 * 12,
+            rng,
         })
     }
 }
@@ -63,20 +106,23 @@ impl ProducesTickets for AeadTicketer {
         self.lifetime
     }
 
-    /// Encrypt `message` and return the ciphertext.
+    /// Encrypt `message` and return the ciphertext, preceded by
+    /// the algorithm's tag and the nonce.
     fn encrypt(&self, message: &[u8]) -> Option<Vec<u8>> {
         // Random nonce, because a counter is a privacy leak.
         let mut nonce_buf = [0u8; 12];
-        rand::fill_random(&mut nonce_buf).ok()?;
+        self.rng.fill(&mut nonce_buf).ok()?;
         let nonce = ring::aead::Nonce::assume_unique_for_key(nonce_buf);
         let aad = ring::aead::Aad::empty();
 
+        let header_len = 1 + nonce_buf.len();
         let mut ciphertext =
-            Vec::with_capacity(nonce_buf.len() + message.len() + self.key.algorithm().tag_len());
+            Vec::with_capacity(header_len + message.len() + self.key.algorithm().tag_len());
+        ciphertext.push(self.alg.tag());
         ciphertext.extend(&nonce_buf);
         ciphertext.extend(message);
         self.key
-            .seal_in_place_separate_tag(nonce, aad, &mut ciphertext[nonce_buf.len()..])
+            .seal_in_place_separate_tag(nonce, aad, &mut ciphertext[header_len..])
             .map(|tag| {
                 ciphertext.extend(tag.as_ref());
                 ciphertext
@@ -86,8 +132,14 @@ impl ProducesTickets for AeadTicketer {
 
     /// Decrypt `ciphertext` and recover the original message.
     fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        // Refuse tickets made with a different algorithm.
+        let (&tag, ciphertext) = ciphertext.split_first()?;
+        if tag != self.alg.tag() {
+            return None;
+        }
+
         // Non-panicking `let (nonce, ciphertext) = ciphertext.split_at(...)`.
-        let nonce = ciphertext.get(..self.alg.nonce_len())?;
+        let nonce = ciphertext.get(..self.alg.aead_algorithm().nonce_len())?;
         let ciphertext = ciphertext.get(nonce.len()..)?;
 
         // This won't fail since `nonce` has the required length.
@@ -106,6 +158,11 @@ impl ProducesTickets for AeadTicketer {
     }
 }
 
+/// The longest rotation interval a [`TicketSwitcher`] accepts, in
+/// seconds.  Tickets live for two intervals, and RFC 8446 caps ticket
+/// lifetimes at seven days.
+const MAX_ROTATION_INTERVAL: u32 = 7 * 24 * 60 * 60 / 2;
+
 struct TicketSwitcherState {
     next: Option<Box<dyn ProducesTickets>>,
     current: Box<dyn ProducesTickets>,
@@ -116,8 +173,14 @@ struct TicketSwitcherState {
 /// A ticketer that has a 'current' sub-ticketer and a single
 /// 'previous' ticketer.  It creates a new ticketer every so
 /// often, demoting the current ticketer.
-struct TicketSwitcher {
-    generator: fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>,
+///
+/// New tickets are encrypted by the current ticketer, and tickets
+/// are decrypted by either.  So a ticket survives one rotation, but
+/// not two.  Make one with [`Ticketer::with_rotation_interval`].
+pub struct TicketSwitcher {
+    generator: Generator,
+    rng: Arc<dyn SecureRandom>,
+    algorithm: TicketAlgorithm,
     lifetime: u32,
     state: Mutex<TicketSwitcherState>,
 }
@@ -126,21 +189,35 @@ impl TicketSwitcher {
     /// `lifetime` is in seconds, and is how long the current ticketer
     /// is used to generate new tickets.  Tickets are accepted for no
     /// longer than twice this duration.  `generator` produces a new
-    /// `ProducesTickets` implementation.
+    /// `ProducesTickets` implementation for `algorithm`, using
+    /// randomness from `rng`.
+    ///
+    /// `lifetime` may not exceed [`MAX_ROTATION_INTERVAL`].
     fn new(
         lifetime: u32,
-        generator: fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>,
+        generator: Generator,
+        rng: Arc<dyn SecureRandom>,
+        algorithm: TicketAlgorithm,
     ) -> Result<Self, Error> {
+        if lifetime > MAX_ROTATION_INTERVAL {
+            return Err(Error::General(format!(
+                "ticket rotation interval of {} seconds exceeds maximum of {}",
+                lifetime, MAX_ROTATION_INTERVAL
+            )));
+        }
+
         let now = TimeBase::now()?;
         Ok(Self {
             generator,
             lifetime,
+            algorithm,
             state: Mutex::new(TicketSwitcherState {
-                next: Some(generator()?),
-                current: generator()?,
+                next: Some(generator(&rng, algorithm)?),
+                current: generator(&rng, algorithm)?,
                 previous: None,
                 next_switch_time: now.as_secs() + u64::from(lifetime),
             }),
+            rng,
         })
     }
 
@@ -203,7 +280,7 @@ impl TicketSwitcher {
         }
 
         // We always need a next, so generate it now
-        let next = (self.generator)().ok()?;
+        let next = (self.generator)(&self.rng, self.algorithm).ok()?;
         if !are_recovering {
             // Normal path, generate new next and place it in the state
             let mut state = self.state.lock().ok()?;
@@ -213,7 +290,7 @@ impl TicketSwitcher {
             // Recovering, generate also a new current ticketer, and modify state
             // as needed. (we need to redo the time check, otherwise this might
             // result in very rapid switching of ticketers)
-            let new_current = (self.generator)().ok()?;
+            let new_current = (self.generator)(&self.rng, self.algorithm).ok()?;
             let mut state = self.state.lock().ok()?;
             state.next = Some(next);
             if now > state.next_switch_time {
@@ -223,6 +300,20 @@ impl TicketSwitcher {
             Some(state)
         }
     }
+
+    /// Rotate now, rather than waiting for the rotation interval to
+    /// pass: demote the current ticketer and erase the previous one.
+    ///
+    /// Tickets made before the last rotation no longer decrypt after
+    /// this, and the next rotation is due one interval from now.
+    pub fn rotate(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.next_switch_time = 0;
+        }
+        if let Ok(now) = TimeBase::now() {
+            self.maybe_roll(now);
+        }
+    }
 }
 
 impl ProducesTickets for TicketSwitcher {
@@ -259,8 +350,16 @@ impl ProducesTickets for TicketSwitcher {
 /// A concrete, safe ticket creation mechanism.
 pub struct Ticketer {}
 
-fn generate_inner() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed> {
-    Ok(Box::new(AeadTicketer::new()?))
+type Generator = fn(
+    &Arc<dyn SecureRandom>,
+    TicketAlgorithm,
+) -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>;
+
+fn generate_inner(
+    rng: &Arc<dyn SecureRandom>,
+    alg: TicketAlgorithm,
+) -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed> {
+    Ok(Box::new(AeadTicketer::new(Arc::clone(rng), alg)?))
 }
 
 impl Ticketer {
@@ -269,7 +368,52 @@ impl Ticketer {
     ///
     /// The encryption mechanism used in Chacha20Poly1305.
     pub fn new() -> Result<Arc<dyn ProducesTickets>, Error> {
-        Ok(Arc::new(TicketSwitcher::new(6 * 60 * 60, generate_inner)?))
+        Ok(Self::with_rotation_interval(6 * 60 * 60)?)
+    }
+
+    /// Make a Ticketer which switches to a new random key every
+    /// `interval` seconds, or when [`TicketSwitcher::rotate`] is
+    /// called.  Tickets are accepted until the second rotation after
+    /// they were made, and have a life of twice `interval`.
+    ///
+    /// RFC 8446 limits ticket lifetimes to seven days, so this fails
+    /// if `interval` is longer than three and a half days.
+    ///
+    /// The encryption mechanism used in Chacha20Poly1305.
+    pub fn with_rotation_interval(interval: u32) -> Result<Arc<TicketSwitcher>, Error> {
+        Self::with_secure_random(interval, Arc::new(rand::DefaultSecureRandom))
+    }
+
+    /// Make a Ticketer like [`Ticketer::with_rotation_interval`], whose
+    /// keys and nonces come from `secure_random` rather than the
+    /// operating system's RNG.  This might be the same as
+    /// [`ServerConfig::secure_random`](crate::ServerConfig::secure_random).
+    ///
+    /// Each ticket has a random 96-bit nonce, so `secure_random` must
+    /// never repeat itself.
+    pub fn with_secure_random(
+        interval: u32,
+        secure_random: Arc<dyn SecureRandom>,
+    ) -> Result<Arc<TicketSwitcher>, Error> {
+        Self::with_algorithm(interval, TicketAlgorithm::default(), secure_random)
+    }
+
+    /// Make a Ticketer like [`Ticketer::with_secure_random`], which
+    /// encrypts tickets with `algorithm` rather than Chacha20Poly1305.
+    ///
+    /// Tickets made with one algorithm are not accepted by a Ticketer
+    /// using another.
+    pub fn with_algorithm(
+        interval: u32,
+        algorithm: TicketAlgorithm,
+        secure_random: Arc<dyn SecureRandom>,
+    ) -> Result<Arc<TicketSwitcher>, Error> {
+        Ok(Arc::new(TicketSwitcher::new(
+            interval,
+            generate_inner,
+            secure_random,
+            algorithm,
+        )?))
     }
 }
 
@@ -284,7 +428,15 @@ fn basic_pairwise_test() {
 
 #[test]
 fn ticketswitcher_switching_test() {
-    let t = Arc::new(TicketSwitcher::new(1, generate_inner).unwrap());
+    let t = Arc::new(
+        TicketSwitcher::new(
+            1,
+            generate_inner,
+            Arc::new(rand::DefaultSecureRandom),
+            TicketAlgorithm::default(),
+        )
+        .unwrap(),
+    );
     let now = TimeBase::now().unwrap();
     let cipher1 = t.encrypt(b"ticket 1").unwrap();
     assert_eq!(t.decrypt(&cipher1).unwrap(), b"ticket 1");
@@ -305,14 +457,45 @@ fn ticketswitcher_switching_test() {
     assert_eq!(t.decrypt(&cipher3).unwrap(), b"ticket 3");
 }
 
+#[test]
+fn ticketswitcher_rotate_test() {
+    let t = Ticketer::with_rotation_interval(60 * 60).unwrap();
+    let cipher1 = t.encrypt(b"ticket 1").unwrap();
+    t.rotate();
+    let cipher2 = t.encrypt(b"ticket 2").unwrap();
+    assert_eq!(t.decrypt(&cipher1).unwrap(), b"ticket 1");
+    assert_eq!(t.decrypt(&cipher2).unwrap(), b"ticket 2");
+    t.rotate();
+    assert!(t.decrypt(&cipher1).is_none());
+    assert_eq!(t.decrypt(&cipher2).unwrap(), b"ticket 2");
+    assert_eq!(t.lifetime(), 2 * 60 * 60);
+}
+
+#[test]
+fn ticketswitcher_rejects_long_interval_test() {
+    let max = Ticketer::with_rotation_interval(MAX_ROTATION_INTERVAL).unwrap();
+    assert_eq!(max.lifetime(), 7 * 24 * 60 * 60);
+    assert!(Ticketer::with_rotation_interval(MAX_ROTATION_INTERVAL + 1).is_err());
+    assert!(Ticketer::with_rotation_interval(u32::MAX).is_err());
+}
+
 #[cfg(test)]
-fn fail_generator() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed> {
+fn fail_generator(
+    _rng: &Arc<dyn SecureRandom>,
+    _alg: TicketAlgorithm,
+) -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed> {
     Err(rand::GetRandomFailed)
 }
 
 #[test]
 fn ticketswitcher_recover_test() {
-    let mut t = TicketSwitcher::new(1, generate_inner).unwrap();
+    let mut t = TicketSwitcher::new(
+        1,
+        generate_inner,
+        Arc::new(rand::DefaultSecureRandom),
+        TicketAlgorithm::default(),
+    )
+    .unwrap();
     let now = TimeBase::now().unwrap();
     let cipher1 = t.encrypt(b"ticket 1").unwrap();
     assert_eq!(t.decrypt(&cipher1).unwrap(), b"ticket 1");
@@ -334,3 +517,81 @@ fn ticketswitcher_recover_test() {
     assert_eq!(t.decrypt(&cipher2).unwrap(), b"ticket 2");
     assert_eq!(t.decrypt(&cipher3).unwrap(), b"ticket 3");
 }
+
+#[cfg(test)]
+struct FixedRandom(u8);
+
+#[cfg(test)]
+impl SecureRandom for FixedRandom {
+    fn fill(&self, buf: &mut [u8]) -> Result<(), Error> {
+        buf.fill(self.0);
+        Ok(())
+    }
+}
+
+#[test]
+fn ticketer_with_secure_random_test() {
+    let t = Ticketer::with_secure_random(60 * 60, Arc::new(FixedRandom(0x55))).unwrap();
+    let cipher = t.encrypt(b"ticket").unwrap();
+    assert_eq!(&cipher[1..13], &[0x55; 12]);
+    assert_eq!(t.decrypt(&cipher).unwrap(), b"ticket");
+}
+
+#[test]
+fn aeadticketer_unique_nonces_test() {
+    for alg in ALL_TICKET_ALGORITHMS {
+        let t = AeadTicketer::new(Arc::new(rand::DefaultSecureRandom), alg).unwrap();
+        let mut nonces = HashSet::new();
+        for _ in 0..1000 {
+            let cipher = t.encrypt(b"ticket").unwrap();
+            assert!(
+                nonces.insert(cipher[1..13].to_vec()),
+                "ticket nonce repeated under one key"
+            );
+            assert_eq!(t.decrypt(&cipher).unwrap(), b"ticket");
+        }
+    }
+}
+
+#[cfg(test)]
+const ALL_TICKET_ALGORITHMS: [TicketAlgorithm; 3] = [
+    TicketAlgorithm::ChaCha20Poly1305,
+    TicketAlgorithm::Aes128Gcm,
+    TicketAlgorithm::Aes256Gcm,
+];
+
+#[test]
+fn ticketer_with_algorithm_test() {
+    for alg in ALL_TICKET_ALGORITHMS {
+        let t =
+            Ticketer::with_algorithm(60 * 60, alg, Arc::new(rand::DefaultSecureRandom)).unwrap();
+        let cipher = t.encrypt(b"ticket").unwrap();
+        assert_eq!(cipher[0], alg.tag());
+        assert_eq!(
+            cipher.len(),
+            1 + 12 + b"ticket".len() + alg.aead_algorithm().tag_len()
+        );
+        assert_eq!(t.decrypt(&cipher).unwrap(), b"ticket");
+    }
+}
+
+#[test]
+fn aeadticketer_rejects_other_algorithms_test() {
+    // Same key bytes throughout, so only the algorithm differs.
+    for alg in ALL_TICKET_ALGORITHMS {
+        let cipher = AeadTicketer::new(Arc::new(FixedRandom(0x55)), alg)
+            .unwrap()
+            .encrypt(b"ticket")
+            .unwrap();
+
+        for other in ALL_TICKET_ALGORITHMS {
+            let t = AeadTicketer::new(Arc::new(FixedRandom(0x55)), other).unwrap();
+            assert_eq!(t.decrypt(&cipher).is_some(), alg == other);
+
+            // Relabelling the ticket does not help.
+            let mut relabelled = cipher.clone();
+            relabelled[0] = other.tag();
+            assert_eq!(t.decrypt(&relabelled).is_some(), alg == other);
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use crate::error::Error;
+
+use std::time::SystemTime;
+
+/// This trait represents a source of the current time, which is used
+/// when validating certificates.
+///
+/// The default, [`DefaultTimeProvider`], uses the system clock.  You
+/// might provide your own if the system clock cannot be trusted: for
+/// example, on a device which learns the time over the network after
+/// starting up.  It is also useful for testing at a fixed time.
+pub trait TimeProvider: Send + Sync {
+    /// Return the current time.
+    ///
+    /// If the time is not known, return [`Error::FailedToGetCurrentTime`]:
+    /// the handshake then fails with this error.
+    fn now(&self) -> Result<SystemTime, Error>;
+}
+
+/// TimeProvider that uses the system clock.
+pub struct DefaultTimeProvider;
+
+impl TimeProvider for DefaultTimeProvider {
+    fn now(&self) -> Result<SystemTime, Error> {
+        Ok(SystemTime::now())
+    }
+}
@@ -1,12 +1,13 @@
 use crate::cipher::{make_nonce, Iv, MessageDecrypter, MessageEncrypter};
+use crate::crypto::AeadKey;
 use crate::error::Error;
 use crate::msgs::base::Payload;
 use crate::msgs::codec;
 use crate::msgs::enums::{ContentType, ProtocolVersion};
 use crate::msgs::fragmenter::MAX_FRAGMENT_LEN;
-use crate::msgs::message::{BorrowedPlainMessage, OpaqueMessage, PlainMessage};
-
-use ring::aead;
+use crate::msgs::message::{
+    BorrowedOpaqueMessage, BorrowedPlainMessage, OpaqueMessage, PlainMessage,
+};
 
 const TLS12_AAD_SIZE: usize = 8 + 1 + 2 + 2;
 
@@ -15,19 +16,19 @@ fn make_tls12_aad(
     typ: ContentType,
     vers: ProtocolVersion,
     len: usize,
-) -> ring::aead::Aad<[u8; TLS12_AAD_SIZE]> {
+) -> [u8; TLS12_AAD_SIZE] {
     let mut out = [0; TLS12_AAD_SIZE];
     codec::put_u64(seq, &mut out[0..]);
     out[8] = typ.get_u8();
     codec::put_u16(vers.get_u16(), &mut out[9..]);
     codec::put_u16(len as u16, &mut out[11..]);
-    ring::aead::Aad::from(out)
+    out
 }
 
 pub(crate) struct AesGcm;
 
 impl Tls12AeadAlgorithm for AesGcm {
-    fn decrypter(&self, dec_key: aead::LessSafeKey, dec_iv: &[u8]) -> Box<dyn MessageDecrypter> {
+    fn decrypter(&self, dec_key: Box<dyn AeadKey>, dec_iv: &[u8]) -> Box<dyn MessageDecrypter> {
         let mut ret = GcmMessageDecrypter {
             dec_key,
             dec_salt: [0u8; 4],
@@ -40,7 +41,7 @@ impl Tls12AeadAlgorithm for AesGcm {
 
     fn encrypter(
         &self,
-        enc_key: aead::LessSafeKey,
+        enc_key: Box<dyn AeadKey>,
         write_iv: &[u8],
         explicit: &[u8],
     ) -> Box<dyn MessageEncrypter> {
@@ -62,10 +63,30 @@ impl Tls12AeadAlgorithm for AesGcm {
     }
 }
 
+/// AES-CCM from RFC6655, which uses the same nonce and additional data
+/// construction as AES-GCM.  The tag is 16 or 8 bytes, depending on the
+/// `AeadKey`.
+pub(crate) struct AesCcm;
+
+impl Tls12AeadAlgorithm for AesCcm {
+    fn decrypter(&self, dec_key: Box<dyn AeadKey>, dec_iv: &[u8]) -> Box<dyn MessageDecrypter> {
+        AesGcm.decrypter(dec_key, dec_iv)
+    }
+
+    fn encrypter(
+        &self,
+        enc_key: Box<dyn AeadKey>,
+        write_iv: &[u8],
+        explicit: &[u8],
+    ) -> Box<dyn MessageEncrypter> {
+        AesGcm.encrypter(enc_key, write_iv, explicit)
+    }
+}
+
 pub(crate) struct ChaCha20Poly1305;
 
 impl Tls12AeadAlgorithm for ChaCha20Poly1305 {
-    fn decrypter(&self, dec_key: aead::LessSafeKey, iv: &[u8]) -> Box<dyn MessageDecrypter> {
+    fn decrypter(&self, dec_key: Box<dyn AeadKey>, iv: &[u8]) -> Box<dyn MessageDecrypter> {
         Box::new(ChaCha20Poly1305MessageDecrypter {
             dec_key,
             dec_offset: Iv::copy(iv),
@@ -74,7 +95,7 @@ impl Tls12AeadAlgorithm for ChaCha20Poly1305 {
 
     fn encrypter(
         &self,
-        enc_key: aead::LessSafeKey,
+        enc_key: Box<dyn AeadKey>,
         enc_iv: &[u8],
         _: &[u8],
     ) -> Box<dyn MessageEncrypter> {
@@ -86,34 +107,45 @@ impl Tls12AeadAlgorithm for ChaCha20Poly1305 {
 }
 
 pub(crate) trait Tls12AeadAlgorithm: Send + Sync + 'static {
-    fn decrypter(&self, key: aead::LessSafeKey, iv: &[u8]) -> Box<dyn MessageDecrypter>;
+    fn decrypter(&self, key: Box<dyn AeadKey>, iv: &[u8]) -> Box<dyn MessageDecrypter>;
     fn encrypter(
         &self,
-        key: aead::LessSafeKey,
+        key: Box<dyn AeadKey>,
         iv: &[u8],
         extra: &[u8],
     ) -> Box<dyn MessageEncrypter>;
 }
 
-/// A `MessageEncrypter` for AES-GCM AEAD ciphersuites. TLS 1.2 only.
+/// A `MessageEncrypter` for AES-GCM and AES-CCM AEAD ciphersuites. TLS 1.2 only.
 struct GcmMessageEncrypter {
-    enc_key: aead::LessSafeKey,
+    enc_key: Box<dyn AeadKey>,
     iv: Iv,
 }
 
-/// A `MessageDecrypter` for AES-GCM AEAD ciphersuites.  TLS1.2 only.
+/// A `MessageDecrypter` for AES-GCM and AES-CCM AEAD ciphersuites.  TLS1.2 only.
 struct GcmMessageDecrypter {
-    dec_key: aead::LessSafeKey,
+    dec_key: Box<dyn AeadKey>,
     dec_salt: [u8; 4],
 }
 
 const GCM_EXPLICIT_NONCE_LEN: usize = 8;
-const GCM_OVERHEAD: usize = GCM_EXPLICIT_NONCE_LEN + 16;
 
 impl MessageDecrypter for GcmMessageDecrypter {
     fn decrypt(&self, mut msg: OpaqueMessage, seq: u64) -> Result<PlainMessage, Error> {
-        let payload = &mut msg.payload.0;
-        if payload.len() < GCM_OVERHEAD {
+        Ok(self
+            .decrypt_in_place(msg.as_borrowed(), seq)?
+            .to_plain_message())
+    }
+
+    fn decrypt_in_place<'a>(
+        &self,
+        msg: BorrowedOpaqueMessage<'a>,
+        seq: u64,
+    ) -> Result<BorrowedPlainMessage<'a>, Error> {
+        let payload = msg.payload;
+        // The tag is shorter for AES-CCM-8.
+        let overhead = GCM_EXPLICIT_NONCE_LEN + self.dec_key.tag_len();
+        if payload.len() < overhead {
             return Err(Error::DecryptError);
         }
 
@@ -121,23 +153,24 @@ impl MessageDecrypter for GcmMessageDecrypter {
             let mut nonce = [0u8; 12];
             nonce[..4].copy_from_slice(&self.dec_salt);
             nonce[4..].copy_from_slice(&payload[..8]);
-            aead::Nonce::assume_unique_for_key(nonce)
+            nonce
         };
 
-        let aad = make_tls12_aad(seq, msg.typ, msg.version, payload.len() - GCM_OVERHEAD);
+        let aad = make_tls12_aad(seq, msg.typ, msg.version, payload.len() - overhead);
 
-        let plain_len = self
-            .dec_key
-            .open_within(nonce, aad, payload, GCM_EXPLICIT_NONCE_LEN..)
-            .map_err(|_| Error::DecryptError)?
-            .len();
+        let plain_len =
+            self.dec_key
+                .open_in_place(nonce, &aad, &mut payload[GCM_EXPLICIT_NONCE_LEN..])?;
 
         if plain_len > MAX_FRAGMENT_LEN {
             return Err(Error::PeerSentOversizedRecord);
         }
 
-        payload.truncate(plain_len);
-        Ok(msg.into_plain_message())
+        Ok(BorrowedPlainMessage {
+            typ: msg.typ,
+            version: msg.version,
+            payload: &payload[GCM_EXPLICIT_NONCE_LEN..GCM_EXPLICIT_NONCE_LEN + plain_len],
+        })
     }
 }
 
@@ -146,15 +179,15 @@ impl MessageEncrypter for GcmMessageEncrypter {
         let nonce = make_nonce(&self.iv, seq);
         let aad = make_tls12_aad(seq, msg.typ, msg.version, msg.payload.len());
 
-        let total_len = msg.payload.len() + self.enc_key.algorithm().tag_len();
+        let total_len = msg.payload.len() + self.enc_key.tag_len();
         let mut payload = Vec::with_capacity(GCM_EXPLICIT_NONCE_LEN + total_len);
-        payload.extend_from_slice(&nonce.as_ref()[4..]);
+        payload.extend_from_slice(&nonce[4..]);
         payload.extend_from_slice(msg.payload);
 
-        self.enc_key
-            .seal_in_place_separate_tag(nonce, aad, &mut payload[GCM_EXPLICIT_NONCE_LEN..])
-            .map(|tag| payload.extend(tag.as_ref()))
-            .map_err(|_| Error::General("encrypt failed".to_string()))?;
+        let tag = self
+            .enc_key
+            .seal_in_place_separate_tag(nonce, &aad, &mut payload[GCM_EXPLICIT_NONCE_LEN..])?;
+        payload.extend_from_slice(tag.as_ref());
 
         Ok(OpaqueMessage {
             typ: msg.typ,
@@ -168,7 +201,7 @@ impl MessageEncrypter for GcmMessageEncrypter {
 /// This implementation does the AAD construction required in TLS1.2.
 /// TLS1.3 uses `TLS13MessageEncrypter`.
 struct ChaCha20Poly1305MessageEncrypter {
-    enc_key: aead::LessSafeKey,
+    enc_key: Box<dyn AeadKey>,
     enc_offset: Iv,
 }
 
@@ -176,7 +209,7 @@ struct ChaCha20Poly1305MessageEncrypter {
 /// This implementation does the AAD construction required in TLS1.2.
 /// TLS1.3 uses `TLS13MessageDecrypter`.
 struct ChaCha20Poly1305MessageDecrypter {
-    dec_key: aead::LessSafeKey,
+    dec_key: Box<dyn AeadKey>,
     dec_offset: Iv,
 }
 
@@ -184,7 +217,17 @@ const CHACHAPOLY1305_OVERHEAD: usize = 16;
 
 impl MessageDecrypter for ChaCha20Poly1305MessageDecrypter {
     fn decrypt(&self, mut msg: OpaqueMessage, seq: u64) -> Result<PlainMessage, Error> {
-        let payload = &mut msg.payload.0;
+        Ok(self
+            .decrypt_in_place(msg.as_borrowed(), seq)?
+            .to_plain_message())
+    }
+
+    fn decrypt_in_place<'a>(
+        &self,
+        msg: BorrowedOpaqueMessage<'a>,
+        seq: u64,
+    ) -> Result<BorrowedPlainMessage<'a>, Error> {
+        let payload = msg.payload;
 
         if payload.len() < CHACHAPOLY1305_OVERHEAD {
             return Err(Error::DecryptError);
@@ -200,16 +243,17 @@ impl MessageDecrypter for ChaCha20Poly1305MessageDecrypter {
 
         let plain_len = self
             .dec_key
-            .open_in_place(nonce, aad, payload)
-            .map_err(|_| Error::DecryptError)?
-            .len();
+            .open_in_place(nonce, &aad, payload)?;
 
         if plain_len > MAX_FRAGMENT_LEN {
             return Err(Error::PeerSentOversizedRecord);
         }
 
-        payload.truncate(plain_len);
-        Ok(msg.into_plain_message())
+        Ok(BorrowedPlainMessage {
+            typ: msg.typ,
+            version: msg.version,
+            payload: &payload[..plain_len],
+        })
     }
 }
 
@@ -218,13 +262,14 @@ impl MessageEncrypter for ChaCha20Poly1305MessageEncrypter {
         let nonce = make_nonce(&self.enc_offset, seq);
         let aad = make_tls12_aad(seq, msg.typ, msg.version, msg.payload.len());
 
-        let total_len = msg.payload.len() + self.enc_key.algorithm().tag_len();
+        let total_len = msg.payload.len() + self.enc_key.tag_len();
         let mut buf = Vec::with_capacity(total_len);
         buf.extend_from_slice(msg.payload);
 
-        self.enc_key
-            .seal_in_place_append_tag(nonce, aad, &mut buf)
-            .map_err(|_| Error::General("encrypt failed".to_string()))?;
+        let tag = self
+            .enc_key
+            .seal_in_place_separate_tag(nonce, &aad, &mut buf)?;
+        buf.extend_from_slice(tag.as_ref());
 
         Ok(OpaqueMessage {
             typ: msg.typ,
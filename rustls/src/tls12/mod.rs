@@ -1,22 +1,23 @@
 use crate::cipher::{MessageDecrypter, MessageEncrypter};
 use crate::conn::{CommonState, ConnectionRandoms, Side};
+use crate::crypto::{AeadKey, CryptoProvider, HashAlgorithm, Output};
 use crate::kx;
 use crate::msgs::codec::{Codec, Reader};
 use crate::msgs::enums::{AlertDescription, ContentType};
 use crate::msgs::enums::{CipherSuite, SignatureScheme};
 use crate::msgs::handshake::KeyExchangeAlgorithm;
 use crate::suites::{BulkAlgorithm, CipherSuiteCommon, SupportedCipherSuite};
+use crate::zeroize::Zeroizing;
 use crate::Error;
 
-use ring::aead;
-use ring::digest::Digest;
-
+use std::convert::TryFrom;
 use std::fmt;
+use std::sync::Arc;
 
 mod cipher;
-pub(crate) use cipher::{AesGcm, ChaCha20Poly1305, Tls12AeadAlgorithm};
+pub(crate) use cipher::{AesCcm, AesGcm, ChaCha20Poly1305, Tls12AeadAlgorithm};
 
-mod prf;
+pub(crate) mod prf;
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256.
 #[cfg(feature = "tls12")]
@@ -25,14 +26,13 @@ pub static TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
             bulk: BulkAlgorithm::Chacha20Poly1305,
-            aead_algorithm: &ring::aead::CHACHA20_POLY1305,
         },
         kx: KeyExchangeAlgorithm::ECDHE,
         sign: TLS12_ECDSA_SCHEMES,
         fixed_iv_len: 12,
         explicit_nonce_len: 0,
         aead_alg: &ChaCha20Poly1305,
-        hmac_algorithm: ring::hmac::HMAC_SHA256,
+        hash: HashAlgorithm::Sha256,
     });
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256
@@ -42,14 +42,13 @@ pub static TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
             bulk: BulkAlgorithm::Chacha20Poly1305,
-            aead_algorithm: &ring::aead::CHACHA20_POLY1305,
         },
         kx: KeyExchangeAlgorithm::ECDHE,
         sign: TLS12_RSA_SCHEMES,
         fixed_iv_len: 12,
         explicit_nonce_len: 0,
         aead_alg: &ChaCha20Poly1305,
-        hmac_algorithm: ring::hmac::HMAC_SHA256,
+        hash: HashAlgorithm::Sha256,
     });
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
@@ -59,14 +58,13 @@ pub static TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
             bulk: BulkAlgorithm::Aes128Gcm,
-            aead_algorithm: &ring::aead::AES_128_GCM,
         },
         kx: KeyExchangeAlgorithm::ECDHE,
         sign: TLS12_RSA_SCHEMES,
         fixed_iv_len: 4,
         explicit_nonce_len: 8,
         aead_alg: &AesGcm,
-        hmac_algorithm: ring::hmac::HMAC_SHA256,
+        hash: HashAlgorithm::Sha256,
     });
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384
@@ -76,14 +74,13 @@ pub static TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
             bulk: BulkAlgorithm::Aes256Gcm,
-            aead_algorithm: &ring::aead::AES_256_GCM,
         },
         kx: KeyExchangeAlgorithm::ECDHE,
         sign: TLS12_RSA_SCHEMES,
         fixed_iv_len: 4,
         explicit_nonce_len: 8,
         aead_alg: &AesGcm,
-        hmac_algorithm: ring::hmac::HMAC_SHA384,
+        hash: HashAlgorithm::Sha384,
     });
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
@@ -93,14 +90,13 @@ pub static TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
             bulk: BulkAlgorithm::Aes128Gcm,
-            aead_algorithm: &ring::aead::AES_128_GCM,
         },
         kx: KeyExchangeAlgorithm::ECDHE,
         sign: TLS12_ECDSA_SCHEMES,
         fixed_iv_len: 4,
         explicit_nonce_len: 8,
         aead_alg: &AesGcm,
-        hmac_algorithm: ring::hmac::HMAC_SHA256,
+        hash: HashAlgorithm::Sha256,
     });
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384
@@ -110,14 +106,55 @@ pub static TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
             bulk: BulkAlgorithm::Aes256Gcm,
-            aead_algorithm: &ring::aead::AES_256_GCM,
         },
         kx: KeyExchangeAlgorithm::ECDHE,
         sign: TLS12_ECDSA_SCHEMES,
         fixed_iv_len: 4,
         explicit_nonce_len: 8,
         aead_alg: &AesGcm,
-        hmac_algorithm: ring::hmac::HMAC_SHA384,
+        hash: HashAlgorithm::Sha384,
+    });
+
+/// The TLS1.2 ciphersuite TLS_ECDHE_ECDSA_WITH_AES_128_CCM
+///
+/// This is not in [`DEFAULT_CIPHER_SUITES`], and needs a
+/// [`CryptoProvider`] which supports [`BulkAlgorithm::Aes128Ccm`].
+///
+/// [`DEFAULT_CIPHER_SUITES`]: crate::DEFAULT_CIPHER_SUITES
+#[cfg(feature = "tls12")]
+pub static TLS_ECDHE_ECDSA_WITH_AES_128_CCM: SupportedCipherSuite =
+    SupportedCipherSuite::Tls12(&Tls12CipherSuite {
+        common: CipherSuiteCommon {
+            suite: CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_CCM,
+            bulk: BulkAlgorithm::Aes128Ccm,
+        },
+        kx: KeyExchangeAlgorithm::ECDHE,
+        sign: TLS12_ECDSA_SCHEMES,
+        fixed_iv_len: 4,
+        explicit_nonce_len: 8,
+        aead_alg: &AesCcm,
+        hash: HashAlgorithm::Sha256,
+    });
+
+/// The TLS1.2 ciphersuite TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8
+///
+/// This is not in [`DEFAULT_CIPHER_SUITES`], and needs a
+/// [`CryptoProvider`] which supports [`BulkAlgorithm::Aes128Ccm8`].
+///
+/// [`DEFAULT_CIPHER_SUITES`]: crate::DEFAULT_CIPHER_SUITES
+#[cfg(feature = "tls12")]
+pub static TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8: SupportedCipherSuite =
+    SupportedCipherSuite::Tls12(&Tls12CipherSuite {
+        common: CipherSuiteCommon {
+            suite: CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8,
+            bulk: BulkAlgorithm::Aes128Ccm8,
+        },
+        kx: KeyExchangeAlgorithm::ECDHE,
+        sign: TLS12_ECDSA_SCHEMES,
+        fixed_iv_len: 4,
+        explicit_nonce_len: 8,
+        aead_alg: &AesCcm,
+        hash: HashAlgorithm::Sha256,
     });
 
 #[cfg(feature = "tls12")]
@@ -143,7 +180,7 @@ static TLS12_RSA_SCHEMES: &[SignatureScheme] = &[
 pub struct Tls12CipherSuite {
     /// Common cipher suite fields.
     pub common: CipherSuiteCommon,
-    pub(crate) hmac_algorithm: ring::hmac::Algorithm,
+    pub(crate) hash: HashAlgorithm,
     /// How to exchange/agree keys.
     pub kx: KeyExchangeAlgorithm,
 
@@ -158,7 +195,7 @@ pub struct Tls12CipherSuite {
 
     /// This is a non-standard extension which extends the
     /// key block to provide an initial explicit nonce offset,
-    /// in a deterministic and safe way.  GCM needs this,
+    /// in a deterministic and safe way.  GCM and CCM need this,
     /// chacha20poly1305 works this way by design.
     pub explicit_nonce_len: usize,
 
@@ -180,7 +217,7 @@ impl Tls12CipherSuite {
 
     /// Which hash function to use with this suite.
     pub fn hash_algorithm(&self) -> &'static ring::digest::Algorithm {
-        self.hmac_algorithm.digest_algorithm()
+        crate::crypto::ring::digest_algorithm(self.hash)
     }
 }
 
@@ -211,22 +248,25 @@ impl fmt::Debug for Tls12CipherSuite {
 /// TLS1.2 per-connection keying material
 pub(crate) struct ConnectionSecrets {
     pub(crate) randoms: ConnectionRandoms,
+    provider: Arc<dyn CryptoProvider>,
     suite: &'static Tls12CipherSuite,
-    pub(crate) master_secret: [u8; 48],
+    pub(crate) master_secret: Zeroizing<[u8; 48]>,
 }
 
 impl ConnectionSecrets {
     pub(crate) fn from_key_exchange(
         kx: kx::KeyExchange,
         peer_pub_key: &[u8],
-        ems_seed: Option<Digest>,
+        ems_seed: Option<Output>,
         randoms: ConnectionRandoms,
+        provider: Arc<dyn CryptoProvider>,
         suite: &'static Tls12CipherSuite,
     ) -> Result<Self, Error> {
         let mut ret = Self {
             randoms,
+            provider,
             suite,
-            master_secret: [0u8; 48],
+            master_secret: Zeroizing::new([0u8; 48]),
         };
 
         let (label, seed) = match ems_seed {
@@ -240,7 +280,8 @@ impl ConnectionSecrets {
         kx.complete(peer_pub_key, |secret| {
             prf::prf(
                 &mut ret.master_secret,
-                suite.hmac_algorithm,
+                ret.provider.as_ref(),
+                suite.hash,
                 secret,
                 label.as_bytes(),
                 seed.as_ref(),
@@ -253,13 +294,15 @@ impl ConnectionSecrets {
 
     pub(crate) fn new_resume(
         randoms: ConnectionRandoms,
+        provider: Arc<dyn CryptoProvider>,
         suite: &'static Tls12CipherSuite,
         master_secret: &[u8],
     ) -> Self {
         let mut ret = Self {
             randoms,
+            provider,
             suite,
-            master_secret: [0u8; 48],
+            master_secret: Zeroizing::new([0u8; 48]),
         };
         ret.master_secret
             .copy_from_slice(master_secret);
@@ -271,13 +314,14 @@ impl ConnectionSecrets {
     pub(crate) fn make_cipher_pair(&self, side: Side) -> MessageCipherPair {
         fn split_key<'a>(
             key_block: &'a [u8],
-            alg: &'static aead::Algorithm,
-        ) -> (aead::LessSafeKey, &'a [u8]) {
+            provider: &dyn CryptoProvider,
+            alg: BulkAlgorithm,
+        ) -> (Box<dyn AeadKey>, &'a [u8]) {
             // Might panic if the key block is too small.
             let (key, rest) = key_block.split_at(alg.key_len());
             // Won't panic because its only prerequisite is that `key` is `alg.key_len()` bytes long.
-            let key = aead::UnboundKey::new(alg, key).unwrap();
-            (aead::LessSafeKey::new(key), rest)
+            let key = provider.aead_key(alg, key).unwrap();
+            (key, rest)
         }
 
         // Make a key block, and chop it up.
@@ -287,8 +331,8 @@ impl ConnectionSecrets {
         let suite = self.suite;
         let scs = &suite.common;
 
-        let (client_write_key, key_block) = split_key(&key_block, scs.aead_algorithm);
-        let (server_write_key, key_block) = split_key(key_block, scs.aead_algorithm);
+        let (client_write_key, key_block) = split_key(&key_block, self.provider.as_ref(), scs.bulk);
+        let (server_write_key, key_block) = split_key(key_block, self.provider.as_ref(), scs.bulk);
         let (client_write_iv, key_block) = key_block.split_at(suite.fixed_iv_len);
         let (server_write_iv, extra) = key_block.split_at(suite.fixed_iv_len);
 
@@ -317,22 +361,21 @@ impl ConnectionSecrets {
         )
     }
 
-    fn make_key_block(&self) -> Vec<u8> {
+    fn make_key_block(&self) -> Zeroizing<Vec<u8>> {
         let suite = &self.suite;
         let common = &self.suite.common;
 
-        let len =
-            (common.aead_algorithm.key_len() + suite.fixed_iv_len) * 2 + suite.explicit_nonce_len;
+        let len = (common.bulk.key_len() + suite.fixed_iv_len) * 2 + suite.explicit_nonce_len;
 
-        let mut out = Vec::new();
-        out.resize(len, 0u8);
+        let mut out = Zeroizing::new(vec![0u8; len]);
 
         // NOTE: opposite order to above for no good reason.
         // Don't design security protocols on drugs, kids.
         let randoms = join_randoms(&self.randoms.server, &self.randoms.client);
         prf::prf(
             &mut out,
-            self.suite.hmac_algorithm,
+            self.provider.as_ref(),
+            self.suite.hash,
             &self.master_secret,
             b"key expansion",
             &randoms,
@@ -351,13 +394,14 @@ impl ConnectionSecrets {
         ret
     }
 
-    fn make_verify_data(&self, handshake_hash: &Digest, label: &[u8]) -> Vec<u8> {
+    fn make_verify_data(&self, handshake_hash: &Output, label: &[u8]) -> Vec<u8> {
         let mut out = Vec::new();
         out.resize(12, 0u8);
 
         prf::prf(
             &mut out,
-            self.suite.hmac_algorithm,
+            self.provider.as_ref(),
+            self.suite.hash,
             &self.master_secret,
             label,
             handshake_hash.as_ref(),
@@ -365,11 +409,11 @@ impl ConnectionSecrets {
         out
     }
 
-    pub(crate) fn client_verify_data(&self, handshake_hash: &Digest) -> Vec<u8> {
+    pub(crate) fn client_verify_data(&self, handshake_hash: &Output) -> Vec<u8> {
         self.make_verify_data(handshake_hash, b"client finished")
     }
 
-    pub(crate) fn server_verify_data(&self, handshake_hash: &Digest) -> Vec<u8> {
+    pub(crate) fn server_verify_data(&self, handshake_hash: &Output) -> Vec<u8> {
         self.make_verify_data(handshake_hash, b"server finished")
     }
 
@@ -378,28 +422,32 @@ impl ConnectionSecrets {
         output: &mut [u8],
         label: &[u8],
         context: Option<&[u8]>,
-    ) {
+    ) -> Result<(), Error> {
         let mut randoms = Vec::new();
         randoms.extend_from_slice(&self.randoms.client);
         randoms.extend_from_slice(&self.randoms.server);
         if let Some(context) = context {
-            assert!(context.len() <= 0xffff);
-            (context.len() as u16).encode(&mut randoms);
+            // RFC5705 prefixes the context with its length as a u16.
+            let len = u16::try_from(context.len())
+                .map_err(|_| Error::General("exporter context too long".to_string()))?;
+            len.encode(&mut randoms);
             randoms.extend_from_slice(context);
         }
 
         prf::prf(
             output,
-            self.suite.hmac_algorithm,
+            self.provider.as_ref(),
+            self.suite.hash,
             &self.master_secret,
             label,
             &randoms,
-        )
+        );
+        Ok(())
     }
 }
 
 enum Seed {
-    Ems(Digest),
+    Ems(Output),
     Randoms([u8; 64]),
 }
 
@@ -445,11 +493,13 @@ pub(crate) const DOWNGRADE_SENTINEL: [u8; 8] = [0x44, 0x4f, 0x57, 0x4e, 0x47, 0x
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypto::ring::Ring;
     use crate::msgs::handshake::{ClientECDHParams, ServerECDHParams};
+    use crate::rand::DefaultSecureRandom;
 
     #[test]
     fn server_ecdhe_remaining_bytes() {
-        let key = kx::KeyExchange::start(&kx::X25519).unwrap();
+        let key = kx::KeyExchange::start(&kx::X25519, &DefaultSecureRandom).unwrap();
         let server_params = ServerECDHParams::new(key.group(), key.pubkey.as_ref());
         let mut server_buf = Vec::new();
         server_params.encode(&mut server_buf);
@@ -461,4 +511,107 @@ mod tests {
     fn client_ecdhe_invalid() {
         assert!(decode_ecdh_params_::<ClientECDHParams>(&[34]).is_none());
     }
+
+    #[test]
+    fn exporter_known_answer() {
+        /* These test vectors generated with OpenSSL's TLS1-PRF. */
+        fn secrets_for(suite: SupportedCipherSuite) -> ConnectionSecrets {
+            let suite = match suite {
+                SupportedCipherSuite::Tls12(suite) => suite,
+                _ => unreachable!(),
+            };
+            let randoms = ConnectionRandoms {
+                client: [0x22; 32],
+                server: [0x33; 32],
+            };
+            ConnectionSecrets::new_resume(randoms, Arc::new(Ring), suite, &[0x11; 48])
+        }
+
+        let secrets = secrets_for(TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256);
+        let mut output = [0u8; 32];
+        secrets
+            .export_keying_material(&mut output, b"EXPORTER-test", Some(b"context"))
+            .unwrap();
+        assert_eq!(
+            output,
+            [
+                0x48, 0x98, 0x20, 0x23, 0x8b, 0x07, 0x0e, 0x8b, 0x6f, 0xdb, 0xd8, 0x45, 0x8f, 0x4b,
+                0x0f, 0x2a, 0xd7, 0xaa, 0x3c, 0x20, 0xc6, 0x79, 0xbb, 0xba, 0x0a, 0x5f, 0xdb, 0xf2,
+                0x20, 0xd5, 0x64, 0x17,
+            ]
+        );
+
+        secrets
+            .export_keying_material(&mut output, b"EXPORTER-test", None)
+            .unwrap();
+        assert_eq!(
+            output,
+            [
+                0xb7, 0x0f, 0xc8, 0x82, 0x43, 0x08, 0x38, 0x7e, 0x36, 0xc8, 0x6a, 0xd9, 0x54, 0xbc,
+                0xbe, 0x34, 0x2a, 0x2d, 0x47, 0x7c, 0xbb, 0xc4, 0x7d, 0xa9, 0xbf, 0x55, 0x0d, 0xfc,
+                0x31, 0xb5, 0xe7, 0xef,
+            ]
+        );
+
+        let secrets = secrets_for(TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384);
+        secrets
+            .export_keying_material(&mut output, b"EXPORTER-test", Some(b"context"))
+            .unwrap();
+        assert_eq!(
+            output,
+            [
+                0x0f, 0x0b, 0xfa, 0xf3, 0x00, 0xea, 0xbf, 0xe2, 0x42, 0x90, 0x5f, 0x09, 0x4f, 0x00,
+                0x54, 0x90, 0xe3, 0x46, 0xa3, 0x1e, 0xc1, 0x00, 0x0b, 0x70, 0x9e, 0x79, 0x17, 0x1c,
+                0x0c, 0x51, 0xf6, 0xe1,
+            ]
+        );
+    }
+
+    #[test]
+    fn exporter_supports_long_outputs_and_contexts() {
+        let suite = match TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 {
+            SupportedCipherSuite::Tls12(suite) => suite,
+            _ => unreachable!(),
+        };
+        let randoms = ConnectionRandoms {
+            client: [0x22; 32],
+            server: [0x33; 32],
+        };
+        let secrets = ConnectionSecrets::new_resume(randoms, Arc::new(Ring), suite, &[0x11; 48]);
+
+        let context = (0..500)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        let mut output = [0u8; 200];
+        secrets
+            .export_keying_material(&mut output, b"EXPORTER-test", Some(&context))
+            .unwrap();
+        assert_eq!(
+            &output[..],
+            &include_bytes!("../testdata/exporter-tls12-long.bin")[..]
+        );
+
+        // The context's length must fit in a u16.
+        assert_eq!(
+            secrets.export_keying_material(&mut output, b"EXPORTER-test", Some(&[0u8; 0x10000])),
+            Err(Error::General("exporter context too long".to_string()))
+        );
+    }
+
+    #[test]
+    fn master_secret_is_zeroizing() {
+        fn assert_zeroizing(_: &Zeroizing<[u8; 48]>) {}
+
+        let suite = match TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 {
+            SupportedCipherSuite::Tls12(suite) => suite,
+            _ => unreachable!(),
+        };
+        let randoms = ConnectionRandoms {
+            client: [0x22; 32],
+            server: [0x33; 32],
+        };
+        let secrets = ConnectionSecrets::new_resume(randoms, Arc::new(Ring), suite, &[0x11; 48]);
+        assert_zeroizing(&secrets.master_secret);
+        assert_eq!(&secrets.master_secret[..], &[0x11; 48][..]);
+    }
 }
@@ -1,21 +1,20 @@
-use crate::cipher::{Iv, IvLen};
+//! Key schedule maintenance for TLS1.3
+
+use crate::cipher::Iv;
+use crate::crypto::{AeadKey, CryptoProvider, HashAlgorithm, Output};
 use crate::error::Error;
-use crate::msgs::base::PayloadU8;
+use crate::suites::BulkAlgorithm;
+use crate::zeroize::Zeroizing;
 use crate::KeyLog;
 
-/// Key schedule maintenance for TLS1.3
-use ring::{
-    aead,
-    digest::{self, Digest},
-    hkdf::{self, KeyType as _},
-    hmac,
-};
+use std::sync::Arc;
 
 /// The kinds of secret we can extract from `KeySchedule`.
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum SecretKind {
     ResumptionPskBinderKey,
     ClientEarlyTrafficSecret,
+    EarlyExporterMasterSecret,
     ClientHandshakeTrafficSecret,
     ServerHandshakeTrafficSecret,
     ClientApplicationTrafficSecret,
@@ -30,6 +29,7 @@ impl SecretKind {
         match self {
             SecretKind::ResumptionPskBinderKey => b"res binder",
             SecretKind::ClientEarlyTrafficSecret => b"c e traffic",
+            SecretKind::EarlyExporterMasterSecret => b"e exp master",
             SecretKind::ClientHandshakeTrafficSecret => b"c hs traffic",
             SecretKind::ServerHandshakeTrafficSecret => b"s hs traffic",
             SecretKind::ClientApplicationTrafficSecret => b"c ap traffic",
@@ -44,6 +44,7 @@ impl SecretKind {
         use self::SecretKind::*;
         Some(match self {
             ClientEarlyTrafficSecret => "CLIENT_EARLY_TRAFFIC_SECRET",
+            EarlyExporterMasterSecret => "EARLY_EXPORTER_SECRET",
             ClientHandshakeTrafficSecret => "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
             ServerHandshakeTrafficSecret => "SERVER_HANDSHAKE_TRAFFIC_SECRET",
             ClientApplicationTrafficSecret => "CLIENT_TRAFFIC_SECRET_0",
@@ -56,12 +57,95 @@ impl SecretKind {
     }
 }
 
+/// The longest exporter label that fits in an HkdfLabel.
+const MAX_EXPORTER_LABEL_LEN: usize = 255 - 6;
+
+/// A secret from the key schedule.  This carries the crypto provider
+/// and hash function needed to expand it further.
+#[derive(Clone)]
+pub(crate) struct Secret {
+    provider: Arc<dyn CryptoProvider>,
+    algorithm: HashAlgorithm,
+    value: Output,
+}
+
+impl Secret {
+    #[cfg(feature = "quic")]
+    pub(crate) fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    #[cfg(feature = "quic")]
+    pub(crate) fn value(&self) -> &[u8] {
+        self.value.as_ref()
+    }
+
+    /// Derive a new secret of the hash length from this one.
+    fn derive(&self, label: &[u8], context: &[u8]) -> Self {
+        let mut buf = Zeroizing::new([0u8; Output::MAX_LEN]);
+        let value = &mut buf[..self.algorithm.output_len()];
+        self.expand_label(value, label, context)
+            .unwrap();
+        Self {
+            provider: Arc::clone(&self.provider),
+            algorithm: self.algorithm,
+            value: Output::new(value),
+        }
+    }
+
+    /// Fill `out` with keying material exported from this exporter secret,
+    /// as described in RFC8446 section 7.5.
+    pub(crate) fn export_keying_material(
+        &self,
+        out: &mut [u8],
+        label: &[u8],
+        context: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        // The label must fit in the HkdfLabel structure alongside "tls13 ".
+        if label.len() > MAX_EXPORTER_LABEL_LEN {
+            return Err(Error::General("exporter label too long".to_string()));
+        }
+
+        let h_empty = self.provider.hash(self.algorithm, &[]);
+        let secret = self.derive(label, h_empty.as_ref());
+
+        let h_context = self
+            .provider
+            .hash(self.algorithm, context.unwrap_or(&[]));
+
+        // The context is hashed, so may be any length.  HKDF limits
+        // the output to 255 times the hash length.
+        secret
+            .expand_label(out, b"exporter", h_context.as_ref())
+            .map_err(|_| Error::General("exporting too much".to_string()))
+    }
+
+    /// HKDF-Expand-Label from RFC8446, filling `out`.
+    fn expand_label(&self, out: &mut [u8], label: &[u8], context: &[u8]) -> Result<(), Error> {
+        const LABEL_PREFIX: &[u8] = b"tls13 ";
+
+        let output_len = u16::to_be_bytes(out.len() as u16);
+        let label_len = u8::to_be_bytes((LABEL_PREFIX.len() + label.len()) as u8);
+        let context_len = u8::to_be_bytes(context.len() as u8);
+
+        let info = &[
+            &output_len[..],
+            &label_len[..],
+            LABEL_PREFIX,
+            label,
+            &context_len[..],
+            context,
+        ];
+        self.provider
+            .hkdf_expand(self.algorithm, self.value.as_ref(), info, out)
+    }
+}
+
 /// This is the TLS1.3 key schedule.  It stores the current secret and
 /// the type of hash.  This isn't used directly; but only through the
 /// typestates.
 struct KeySchedule {
-    current: hkdf::Prk,
-    algorithm: ring::hkdf::Algorithm,
+    current: Secret,
 }
 
 // We express the state of a contained KeySchedule using these
@@ -76,18 +160,22 @@ pub(crate) struct KeyScheduleEarly {
 }
 
 impl KeyScheduleEarly {
-    pub(crate) fn new(algorithm: hkdf::Algorithm, secret: &[u8]) -> Self {
+    pub(crate) fn new(
+        provider: Arc<dyn CryptoProvider>,
+        algorithm: HashAlgorithm,
+        secret: &[u8],
+    ) -> Self {
         Self {
-            ks: KeySchedule::new(algorithm, secret),
+            ks: KeySchedule::new(provider, algorithm, secret),
         }
     }
 
     pub(crate) fn client_early_traffic_secret(
         &self,
-        hs_hash: &Digest,
+        hs_hash: &Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
-    ) -> hkdf::Prk {
+    ) -> Secret {
         self.ks.derive_logged_secret(
             SecretKind::ClientEarlyTrafficSecret,
             hs_hash.as_ref(),
@@ -96,10 +184,24 @@ impl KeyScheduleEarly {
         )
     }
 
+    pub(crate) fn early_exporter_master_secret(
+        &self,
+        hs_hash: &Output,
+        key_log: &dyn KeyLog,
+        client_random: &[u8; 32],
+    ) -> Secret {
+        self.ks.derive_logged_secret(
+            SecretKind::EarlyExporterMasterSecret,
+            hs_hash.as_ref(),
+            key_log,
+            client_random,
+        )
+    }
+
     pub(crate) fn resumption_psk_binder_key_and_sign_verify_data(
         &self,
-        hs_hash: &Digest,
-    ) -> hmac::Tag {
+        hs_hash: &Output,
+    ) -> Output {
         let resumption_psk_binder_key = self
             .ks
             .derive_for_empty_hash(SecretKind::ResumptionPskBinderKey);
@@ -117,12 +219,17 @@ pub(crate) struct KeySchedulePreHandshake {
 }
 
 impl KeySchedulePreHandshake {
-    pub(crate) fn new(algorithm: hkdf::Algorithm) -> Self {
+    pub(crate) fn new(provider: Arc<dyn CryptoProvider>, algorithm: HashAlgorithm) -> Self {
         Self {
-            ks: KeySchedule::new_with_empty_secret(algorithm),
+            ks: KeySchedule::new_with_empty_secret(provider, algorithm),
         }
     }
 
+    /// Input the key exchange's shared secret.
+    ///
+    /// For hybrid key exchange groups, `secret` is the classical shared secret
+    /// followed by the post-quantum one; together they form the input keying
+    /// material.
     pub(crate) fn into_handshake(mut self, secret: &[u8]) -> KeyScheduleHandshakeStart {
         self.ks.input_secret(secret);
         KeyScheduleHandshakeStart { ks: self.ks }
@@ -143,10 +250,10 @@ pub(crate) struct KeyScheduleHandshakeStart {
 impl KeyScheduleHandshakeStart {
     pub(crate) fn derive_handshake_secrets(
         self,
-        hs_hash: Digest,
+        hs_hash: Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
-    ) -> (KeyScheduleHandshake, hkdf::Prk, hkdf::Prk) {
+    ) -> (KeyScheduleHandshake, Secret, Secret) {
         // Use an empty handshake hash for the initial handshake.
         let client_secret = self.ks.derive_logged_secret(
             SecretKind::ClientHandshakeTrafficSecret,
@@ -174,30 +281,26 @@ impl KeyScheduleHandshakeStart {
 
 pub(crate) struct KeyScheduleHandshake {
     ks: KeySchedule,
-    client_handshake_traffic_secret: hkdf::Prk,
-    server_handshake_traffic_secret: hkdf::Prk,
+    client_handshake_traffic_secret: Secret,
+    server_handshake_traffic_secret: Secret,
 }
 
 impl KeyScheduleHandshake {
-    pub(crate) fn sign_server_finish(&self, hs_hash: &Digest) -> hmac::Tag {
+    pub(crate) fn sign_server_finish(&self, hs_hash: &Output) -> Output {
         self.ks
             .sign_finish(&self.server_handshake_traffic_secret, hs_hash)
     }
 
-    pub(crate) fn client_key(&self) -> &hkdf::Prk {
+    pub(crate) fn client_key(&self) -> &Secret {
         &self.client_handshake_traffic_secret
     }
 
     pub(crate) fn into_traffic_with_client_finished_pending(
         self,
-        hs_hash: Digest,
+        hs_hash: Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
-    ) -> (
-        KeyScheduleTrafficWithClientFinishedPending,
-        hkdf::Prk,
-        hkdf::Prk,
-    ) {
+    ) -> (KeyScheduleTrafficWithClientFinishedPending, Secret, Secret) {
         let traffic = KeyScheduleTraffic::new(self.ks, hs_hash, key_log, client_random);
 
         let client_secret = traffic
@@ -220,19 +323,19 @@ impl KeyScheduleHandshake {
 /// finished verify_data. The traffic stage key schedule can be extracted from it
 /// through signing the client finished hash.
 pub(crate) struct KeyScheduleTrafficWithClientFinishedPending {
-    handshake_client_traffic_secret: hkdf::Prk,
+    handshake_client_traffic_secret: Secret,
     traffic: KeyScheduleTraffic,
 }
 
 impl KeyScheduleTrafficWithClientFinishedPending {
-    pub(crate) fn client_key(&self) -> &hkdf::Prk {
+    pub(crate) fn client_key(&self) -> &Secret {
         &self.handshake_client_traffic_secret
     }
 
     pub(crate) fn sign_client_finish(
         self,
-        hs_hash: &Digest,
-    ) -> (KeyScheduleTraffic, hmac::Tag, hkdf::Prk) {
+        hs_hash: &Output,
+    ) -> (KeyScheduleTraffic, Output, Secret) {
         let tag = self
             .traffic
             .ks
@@ -251,15 +354,15 @@ impl KeyScheduleTrafficWithClientFinishedPending {
 /// to be available.
 pub(crate) struct KeyScheduleTraffic {
     ks: KeySchedule,
-    current_client_traffic_secret: hkdf::Prk,
-    current_server_traffic_secret: hkdf::Prk,
-    current_exporter_secret: hkdf::Prk,
+    current_client_traffic_secret: Secret,
+    current_server_traffic_secret: Secret,
+    current_exporter_secret: Secret,
 }
 
 impl KeyScheduleTraffic {
     fn new(
         mut ks: KeySchedule,
-        hs_hash: Digest,
+        hs_hash: Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
     ) -> Self {
@@ -294,7 +397,7 @@ impl KeyScheduleTraffic {
         }
     }
 
-    pub(crate) fn next_server_application_traffic_secret(&mut self) -> hkdf::Prk {
+    pub(crate) fn next_server_application_traffic_secret(&mut self) -> Secret {
         let secret = self
             .ks
             .derive_next(&self.current_server_traffic_secret);
@@ -302,7 +405,7 @@ impl KeyScheduleTraffic {
         secret
     }
 
-    pub(crate) fn next_client_application_traffic_secret(&mut self) -> hkdf::Prk {
+    pub(crate) fn next_client_application_traffic_secret(&mut self) -> Secret {
         let secret = self
             .ks
             .derive_next(&self.current_client_traffic_secret);
@@ -310,16 +413,22 @@ impl KeyScheduleTraffic {
         secret
     }
 
+    /// Sign the client's Finished message for post-handshake
+    /// authentication.  This is keyed from the current client
+    /// application traffic secret.
+    pub(crate) fn sign_post_handshake_client_finish(&self, hs_hash: &Output) -> Output {
+        self.ks
+            .sign_finish(&self.current_client_traffic_secret, hs_hash)
+    }
+
     pub(crate) fn resumption_master_secret_and_derive_ticket_psk(
         &self,
-        hs_hash: &Digest,
+        hs_hash: &Output,
         nonce: &[u8],
     ) -> Vec<u8> {
-        let resumption_master_secret = self.ks.derive(
-            self.ks.algorithm(),
-            SecretKind::ResumptionMasterSecret,
-            hs_hash.as_ref(),
-        );
+        let resumption_master_secret = self
+            .ks
+            .derive(SecretKind::ResumptionMasterSecret, hs_hash.as_ref());
         self.ks
             .derive_ticket_psk(&resumption_master_secret, nonce)
     }
@@ -330,50 +439,58 @@ impl KeyScheduleTraffic {
         label: &[u8],
         context: Option<&[u8]>,
     ) -> Result<(), Error> {
-        self.ks
-            .export_keying_material(&self.current_exporter_secret, out, label, context)
+        self.current_exporter_secret
+            .export_keying_material(out, label, context)
     }
 }
 
 impl KeySchedule {
-    fn new(algorithm: hkdf::Algorithm, secret: &[u8]) -> Self {
-        let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
-        let salt = hkdf::Salt::new(algorithm, &zeroes[..algorithm.len()]);
+    fn new(provider: Arc<dyn CryptoProvider>, algorithm: HashAlgorithm, secret: &[u8]) -> Self {
+        let zeroes = [0u8; Output::MAX_LEN];
+        let salt = &zeroes[..algorithm.output_len()];
+        let value = provider.hkdf_extract(algorithm, salt, secret);
         Self {
-            current: salt.extract(secret),
-            algorithm,
+            current: Secret {
+                provider,
+                algorithm,
+                value,
+            },
         }
     }
 
     #[inline]
-    fn algorithm(&self) -> hkdf::Algorithm {
-        self.algorithm
+    fn algorithm(&self) -> HashAlgorithm {
+        self.current.algorithm
+    }
+
+    #[inline]
+    fn provider(&self) -> &dyn CryptoProvider {
+        self.current.provider.as_ref()
     }
 
-    fn new_with_empty_secret(algorithm: hkdf::Algorithm) -> Self {
-        let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
-        Self::new(algorithm, &zeroes[..algorithm.len()])
+    fn new_with_empty_secret(provider: Arc<dyn CryptoProvider>, algorithm: HashAlgorithm) -> Self {
+        let zeroes = [0u8; Output::MAX_LEN];
+        Self::new(provider, algorithm, &zeroes[..algorithm.output_len()])
     }
 
     /// Input the empty secret.
     fn input_empty(&mut self) {
-        let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
-        self.input_secret(&zeroes[..self.algorithm.len()]);
+        let zeroes = [0u8; Output::MAX_LEN];
+        self.input_secret(&zeroes[..self.algorithm().output_len()]);
     }
 
     /// Input the given secret.
     fn input_secret(&mut self, secret: &[u8]) {
-        let salt: hkdf::Salt = self.derive_for_empty_hash(SecretKind::DerivedSecret);
-        self.current = salt.extract(secret);
+        let salt = self.derive_for_empty_hash(SecretKind::DerivedSecret);
+        self.current.value =
+            self.provider()
+                .hkdf_extract(self.algorithm(), salt.value.as_ref(), secret);
     }
 
     /// Derive a secret of given `kind`, using current handshake hash `hs_hash`.
-    fn derive<T, L>(&self, key_type: L, kind: SecretKind, hs_hash: &[u8]) -> T
-    where
-        T: for<'a> From<hkdf::Okm<'a, L>>,
-        L: hkdf::KeyType,
-    {
-        hkdf_expand(&self.current, key_type, kind.to_bytes(), hs_hash)
+    fn derive(&self, kind: SecretKind, hs_hash: &[u8]) -> Secret {
+        self.current
+            .derive(kind.to_bytes(), hs_hash)
     }
 
     fn derive_logged_secret(
@@ -382,169 +499,88 @@ impl KeySchedule {
         hs_hash: &[u8],
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
-    ) -> hkdf::Prk {
+    ) -> Secret {
         let log_label = kind
             .log_label()
             .expect("not a loggable secret");
+        let secret = self.derive(kind, hs_hash);
         if key_log.will_log(log_label) {
-            let secret = self
-                .derive::<PayloadU8, _>(PayloadU8Len(self.algorithm.len()), kind, hs_hash)
-                .into_inner();
-            key_log.log(log_label, client_random, &secret);
+            key_log.log(log_label, client_random, secret.value.as_ref());
         }
-        self.derive(self.algorithm, kind, hs_hash)
+        secret
     }
 
     /// Derive a secret of given `kind` using the hash of the empty string
     /// for the handshake hash.  Useful only for
     /// `SecretKind::ResumptionPSKBinderKey` and
     /// `SecretKind::DerivedSecret`.
-    fn derive_for_empty_hash<T>(&self, kind: SecretKind) -> T
-    where
-        T: for<'a> From<hkdf::Okm<'a, hkdf::Algorithm>>,
-    {
-        let digest_alg = self
-            .algorithm
-            .hmac_algorithm()
-            .digest_algorithm();
-        let empty_hash = digest::digest(digest_alg, &[]);
-        self.derive(self.algorithm, kind, empty_hash.as_ref())
+    fn derive_for_empty_hash(&self, kind: SecretKind) -> Secret {
+        let empty_hash = self
+            .provider()
+            .hash(self.algorithm(), &[]);
+        self.derive(kind, empty_hash.as_ref())
     }
 
     /// Sign the finished message consisting of `hs_hash` using a current
     /// traffic secret.
-    fn sign_finish(&self, base_key: &hkdf::Prk, hs_hash: &Digest) -> hmac::Tag {
+    fn sign_finish(&self, base_key: &Secret, hs_hash: &Output) -> Output {
         self.sign_verify_data(base_key, hs_hash)
     }
 
     /// Sign the finished message consisting of `hs_hash` using the key material
     /// `base_key`.
-    fn sign_verify_data(&self, base_key: &hkdf::Prk, hs_hash: &Digest) -> hmac::Tag {
-        let hmac_alg = self.algorithm.hmac_algorithm();
-        let hmac_key = hkdf_expand(base_key, hmac_alg, b"finished", &[]);
-        hmac::sign(&hmac_key, hs_hash.as_ref())
+    fn sign_verify_data(&self, base_key: &Secret, hs_hash: &Output) -> Output {
+        let hmac_key = base_key.derive(b"finished", &[]);
+        self.provider()
+            .hmac_key(self.algorithm(), hmac_key.value.as_ref())
+            .sign(&[hs_hash.as_ref()])
     }
 
     /// Derive the next application traffic secret, returning it.
-    fn derive_next(&self, base_key: &hkdf::Prk) -> hkdf::Prk {
-        hkdf_expand(base_key, self.algorithm, b"traffic upd", &[])
+    fn derive_next(&self, base_key: &Secret) -> Secret {
+        base_key.derive(b"traffic upd", &[])
     }
 
     /// Derive the PSK to use given a resumption_master_secret and
     /// ticket_nonce.
-    fn derive_ticket_psk(&self, rms: &hkdf::Prk, nonce: &[u8]) -> Vec<u8> {
-        let payload: PayloadU8 = hkdf_expand(
-            rms,
-            PayloadU8Len(self.algorithm.len()),
-            b"resumption",
-            nonce,
-        );
-        payload.into_inner()
-    }
-
-    fn export_keying_material(
-        &self,
-        current_exporter_secret: &hkdf::Prk,
-        out: &mut [u8],
-        label: &[u8],
-        context: Option<&[u8]>,
-    ) -> Result<(), Error> {
-        let digest_alg = self
-            .algorithm
-            .hmac_algorithm()
-            .digest_algorithm();
-
-        let h_empty = digest::digest(digest_alg, &[]);
-        let secret: hkdf::Prk = hkdf_expand(
-            current_exporter_secret,
-            self.algorithm,
-            label,
-            h_empty.as_ref(),
-        );
-
-        let h_context = digest::digest(digest_alg, context.unwrap_or(&[]));
-
-        // TODO: Test what happens when this fails
-        hkdf_expand_info(
-            &secret,
-            PayloadU8Len(out.len()),
-            b"exporter",
-            h_context.as_ref(),
-            |okm| okm.fill(out),
-        )
-        .map_err(|_| Error::General("exporting too much".to_string()))
-    }
-}
-
-pub(crate) fn hkdf_expand<T, L>(secret: &hkdf::Prk, key_type: L, label: &[u8], context: &[u8]) -> T
-where
-    T: for<'a> From<hkdf::Okm<'a, L>>,
-    L: hkdf::KeyType,
-{
-    hkdf_expand_info(secret, key_type, label, context, |okm| okm.into())
-}
-
-fn hkdf_expand_info<F, T, L>(
-    secret: &hkdf::Prk,
-    key_type: L,
-    label: &[u8],
-    context: &[u8],
-    f: F,
-) -> T
-where
-    F: for<'b> FnOnce(hkdf::Okm<'b, L>) -> T,
-    L: hkdf::KeyType,
-{
-    const LABEL_PREFIX: &[u8] = b"tls13 ";
-
-    let output_len = u16::to_be_bytes(key_type.len() as u16);
-    let label_len = u8::to_be_bytes((LABEL_PREFIX.len() + label.len()) as u8);
-    let context_len = u8::to_be_bytes(context.len() as u8);
-
-    let info = &[
-        &output_len[..],
-        &label_len[..],
-        LABEL_PREFIX,
-        label,
-        &context_len[..],
-        context,
-    ];
-    let okm = secret.expand(info, key_type).unwrap();
-
-    f(okm)
-}
-
-pub(crate) struct PayloadU8Len(pub(crate) usize);
-impl hkdf::KeyType for PayloadU8Len {
-    fn len(&self) -> usize {
-        self.0
-    }
-}
-
-impl From<hkdf::Okm<'_, PayloadU8Len>> for PayloadU8 {
-    fn from(okm: hkdf::Okm<PayloadU8Len>) -> Self {
-        let mut r = vec![0u8; okm.len().0];
-        okm.fill(&mut r[..]).unwrap();
-        Self::new(r)
+    fn derive_ticket_psk(&self, rms: &Secret, nonce: &[u8]) -> Vec<u8> {
+        let mut psk = vec![0u8; self.algorithm().output_len()];
+        rms.expand_label(&mut psk, b"resumption", nonce)
+            .unwrap();
+        psk
     }
 }
 
-pub(crate) fn derive_traffic_key(
-    secret: &hkdf::Prk,
-    aead_algorithm: &'static aead::Algorithm,
-) -> aead::UnboundKey {
-    hkdf_expand(secret, aead_algorithm, b"key", &[])
+pub(crate) fn derive_traffic_key(secret: &Secret, alg: BulkAlgorithm) -> Box<dyn AeadKey> {
+    let mut buf = Zeroizing::new([0u8; 32]);
+    let key = &mut buf[..alg.key_len()];
+    secret
+        .expand_label(key, b"key", &[])
+        .unwrap();
+    // Won't fail unless the provider doesn't support `alg`.
+    secret
+        .provider
+        .aead_key(alg, key)
+        .unwrap()
 }
 
-pub(crate) fn derive_traffic_iv(secret: &hkdf::Prk) -> Iv {
-    hkdf_expand(secret, IvLen, b"iv", &[])
+pub(crate) fn derive_traffic_iv(secret: &Secret) -> Iv {
+    let mut iv = Iv::default();
+    secret
+        .expand_label(&mut iv.0, b"iv", &[])
+        .unwrap();
+    iv
 }
 
 #[cfg(test)]
 mod test {
-    use super::{derive_traffic_iv, derive_traffic_key, KeySchedule, SecretKind};
+    use super::{derive_traffic_iv, derive_traffic_key, KeySchedule, Secret, SecretKind};
+    use crate::crypto::ring::Ring;
+    use crate::crypto::{AeadKey, CryptoProvider, HashAlgorithm, Output};
+    use crate::error::Error;
+    use crate::suites::BulkAlgorithm;
     use crate::KeyLog;
-    use ring::{aead, hkdf};
+    use std::sync::Arc;
 
     #[test]
     fn test_vectors() {
@@ -627,8 +663,7 @@ mod test {
             0x0d, 0xb2, 0x8f, 0x98, 0x85, 0x86, 0xa1, 0xb7, 0xe4, 0xd5, 0xc6, 0x9c,
         ];
 
-        let hkdf = hkdf::HKDF_SHA256;
-        let mut ks = KeySchedule::new_with_empty_secret(hkdf);
+        let mut ks = KeySchedule::new_with_empty_secret(Arc::new(Ring), HashAlgorithm::Sha256);
         ks.input_secret(&ecdhe_secret);
 
         assert_traffic_secret(
@@ -670,6 +705,78 @@ mod test {
         );
     }
 
+    #[test]
+    fn exporter_known_answer() {
+        /* These test vectors generated with OpenSSL's HKDF. */
+        let exporter_secret = Secret {
+            provider: Arc::new(Ring),
+            algorithm: HashAlgorithm::Sha256,
+            value: Output::new(&[0x44; 32]),
+        };
+
+        let mut output = [0u8; 32];
+        exporter_secret
+            .export_keying_material(&mut output, b"EXPORTER-test", Some(b"context"))
+            .unwrap();
+        assert_eq!(
+            output,
+            [
+                0x3e, 0x50, 0xb1, 0x9f, 0x75, 0x53, 0x69, 0xe3, 0x20, 0xd9, 0xc2, 0x96, 0x69, 0xea,
+                0xc7, 0x58, 0xdf, 0x6c, 0x05, 0xe8, 0x76, 0xe6, 0x20, 0xe2, 0x69, 0xe0, 0xc8, 0x1a,
+                0x6d, 0xa9, 0xda, 0xd7,
+            ]
+        );
+
+        // In TLS1.3, an absent context is equivalent to an empty one.
+        exporter_secret
+            .export_keying_material(&mut output, b"EXPORTER-test", None)
+            .unwrap();
+        assert_eq!(
+            output,
+            [
+                0xbc, 0xbf, 0x0d, 0xc4, 0x1c, 0xed, 0xe1, 0xd7, 0x49, 0x06, 0x8a, 0x21, 0x90, 0x3c,
+                0x9c, 0xc8, 0x21, 0xe2, 0x9e, 0x80, 0xcd, 0x8e, 0xa5, 0x57, 0x1c, 0x28, 0x34, 0xe8,
+                0x95, 0xe1, 0x42, 0x0d,
+            ]
+        );
+    }
+
+    #[test]
+    fn exporter_supports_long_outputs_and_contexts() {
+        let exporter_secret = Secret {
+            provider: Arc::new(Ring),
+            algorithm: HashAlgorithm::Sha256,
+            value: Output::new(&[0x44; 32]),
+        };
+
+        let context = (0..500)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        let mut output = [0u8; 200];
+        exporter_secret
+            .export_keying_material(&mut output, b"EXPORTER-test", Some(&context))
+            .unwrap();
+        assert_eq!(
+            &output[..],
+            &include_bytes!("../testdata/exporter-tls13-long.bin")[..]
+        );
+
+        let mut longest = vec![0u8; 255 * 32];
+        assert!(exporter_secret
+            .export_keying_material(&mut longest, b"EXPORTER-test", None)
+            .is_ok());
+        longest.push(0);
+        assert_eq!(
+            exporter_secret.export_keying_material(&mut longest, b"EXPORTER-test", None),
+            Err(Error::General("exporting too much".to_string()))
+        );
+
+        assert_eq!(
+            exporter_secret.export_keying_material(&mut output, &[b'x'; 250], None),
+            Err(Error::General("exporter label too long".to_string()))
+        );
+    }
+
     fn assert_traffic_secret(
         ks: &KeySchedule,
         kind: SecretKind,
@@ -688,11 +795,13 @@ mod test {
         let traffic_secret = ks.derive_logged_secret(kind, hash, &log, &[0; 32]);
 
         // Since we can't test key equality, we test the output of sealing with the key instead.
-        let aead_alg = &aead::AES_128_GCM;
+        let aead_alg = BulkAlgorithm::Aes128Gcm;
         let key = derive_traffic_key(&traffic_secret, aead_alg);
-        let seal_output = seal_zeroes(key);
-        let expected_key = aead::UnboundKey::new(aead_alg, expected_key).unwrap();
-        let expected_seal_output = seal_zeroes(expected_key);
+        let seal_output = seal_zeroes(key.as_ref());
+        let expected_key = Ring
+            .aead_key(aead_alg, expected_key)
+            .unwrap();
+        let expected_seal_output = seal_zeroes(expected_key.as_ref());
         assert_eq!(seal_output, expected_seal_output);
         assert!(seal_output.len() >= 48); // Sanity check.
 
@@ -700,15 +809,12 @@ mod test {
         assert_eq!(iv.value(), expected_iv);
     }
 
-    fn seal_zeroes(key: aead::UnboundKey) -> Vec<u8> {
-        let key = aead::LessSafeKey::new(key);
+    fn seal_zeroes(key: &dyn AeadKey) -> Vec<u8> {
         let mut seal_output = vec![0; 32];
-        key.seal_in_place_append_tag(
-            aead::Nonce::assume_unique_for_key([0; aead::NONCE_LEN]),
-            aead::Aad::empty(),
-            &mut seal_output,
-        )
-        .unwrap();
+        let tag = key
+            .seal_in_place_separate_tag([0; 12], &[], &mut seal_output)
+            .unwrap();
+        seal_output.extend_from_slice(tag.as_ref());
         seal_output
     }
 }
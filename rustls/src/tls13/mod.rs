@@ -1,18 +1,19 @@
-use crate::cipher::{make_nonce, Iv, MessageDecrypter, MessageEncrypter};
+use crate::cipher::{make_nonce, Iv, MessageDecrypter, MessageEncrypter, PaddingPolicy};
+use crate::crypto::{AeadKey, HashAlgorithm};
 use crate::error::Error;
 use crate::msgs::base::Payload;
 use crate::msgs::codec::Codec;
 use crate::msgs::enums::{CipherSuite, ContentType, ProtocolVersion};
 use crate::msgs::fragmenter::MAX_FRAGMENT_LEN;
-use crate::msgs::message::{BorrowedPlainMessage, OpaqueMessage, PlainMessage};
+use crate::msgs::message::{
+    BorrowedOpaqueMessage, BorrowedPlainMessage, OpaqueMessage, PlainMessage,
+};
 use crate::suites::{BulkAlgorithm, CipherSuiteCommon, SupportedCipherSuite};
 
-use ring::{aead, hkdf};
-
 use std::fmt;
 
 pub(crate) mod key_schedule;
-use key_schedule::{derive_traffic_iv, derive_traffic_key};
+use key_schedule::{derive_traffic_iv, derive_traffic_key, Secret};
 
 /// The TLS1.3 ciphersuite TLS_CHACHA20_POLY1305_SHA256
 pub static TLS13_CHACHA20_POLY1305_SHA256: SupportedCipherSuite =
@@ -22,9 +23,8 @@ pub(crate) static TLS13_CHACHA20_POLY1305_SHA256_INTERNAL: &Tls13CipherSuite = &
     common: CipherSuiteCommon {
         suite: CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
         bulk: BulkAlgorithm::Chacha20Poly1305,
-        aead_algorithm: &ring::aead::CHACHA20_POLY1305,
     },
-    hkdf_algorithm: ring::hkdf::HKDF_SHA256,
+    hash: HashAlgorithm::Sha256,
     #[cfg(feature = "quic")]
     confidentiality_limit: u64::MAX,
     #[cfg(feature = "quic")]
@@ -37,9 +37,8 @@ pub static TLS13_AES_256_GCM_SHA384: SupportedCipherSuite =
         common: CipherSuiteCommon {
             suite: CipherSuite::TLS13_AES_256_GCM_SHA384,
             bulk: BulkAlgorithm::Aes256Gcm,
-            aead_algorithm: &ring::aead::AES_256_GCM,
         },
-        hkdf_algorithm: ring::hkdf::HKDF_SHA384,
+        hash: HashAlgorithm::Sha384,
         #[cfg(feature = "quic")]
         confidentiality_limit: 1 << 23,
         #[cfg(feature = "quic")]
@@ -54,9 +53,8 @@ pub(crate) static TLS13_AES_128_GCM_SHA256_INTERNAL: &Tls13CipherSuite = &Tls13C
     common: CipherSuiteCommon {
         suite: CipherSuite::TLS13_AES_128_GCM_SHA256,
         bulk: BulkAlgorithm::Aes128Gcm,
-        aead_algorithm: &ring::aead::AES_128_GCM,
     },
-    hkdf_algorithm: ring::hkdf::HKDF_SHA256,
+    hash: HashAlgorithm::Sha256,
     #[cfg(feature = "quic")]
     confidentiality_limit: 1 << 23,
     #[cfg(feature = "quic")]
@@ -67,7 +65,7 @@ pub(crate) static TLS13_AES_128_GCM_SHA256_INTERNAL: &Tls13CipherSuite = &Tls13C
 pub struct Tls13CipherSuite {
     /// Common cipher suite fields.
     pub common: CipherSuiteCommon,
-    pub(crate) hkdf_algorithm: ring::hkdf::Algorithm,
+    pub(crate) hash: HashAlgorithm,
     #[cfg(feature = "quic")]
     pub(crate) confidentiality_limit: u64,
     #[cfg(feature = "quic")]
@@ -75,38 +73,28 @@ pub struct Tls13CipherSuite {
 }
 
 impl Tls13CipherSuite {
-    pub(crate) fn derive_encrypter(&self, secret: &hkdf::Prk) -> Box<dyn MessageEncrypter> {
-        let key = derive_traffic_key(secret, self.common.aead_algorithm);
+    pub(crate) fn derive_encrypter(&self, secret: &Secret) -> Box<dyn MessageEncrypter> {
+        let key = derive_traffic_key(secret, self.common.bulk);
         let iv = derive_traffic_iv(secret);
 
-        Box::new(Tls13MessageEncrypter {
-            enc_key: aead::LessSafeKey::new(key),
-            iv,
-        })
+        Box::new(Tls13MessageEncrypter { enc_key: key, iv })
     }
 
-    /// Derive a `MessageDecrypter` object from the concerned TLS 1.3
-    /// cipher suite.
-    pub fn derive_decrypter(&self, secret: &hkdf::Prk) -> Box<dyn MessageDecrypter> {
-        let key = derive_traffic_key(secret, self.common.aead_algorithm);
+    pub(crate) fn derive_decrypter(&self, secret: &Secret) -> Box<dyn MessageDecrypter> {
+        let key = derive_traffic_key(secret, self.common.bulk);
         let iv = derive_traffic_iv(secret);
 
-        Box::new(Tls13MessageDecrypter {
-            dec_key: aead::LessSafeKey::new(key),
-            iv,
-        })
+        Box::new(Tls13MessageDecrypter { dec_key: key, iv })
     }
 
     /// Which hash function to use with this suite.
     pub fn hash_algorithm(&self) -> &'static ring::digest::Algorithm {
-        self.hkdf_algorithm
-            .hmac_algorithm()
-            .digest_algorithm()
+        crate::crypto::ring::digest_algorithm(self.hash)
     }
 
     /// Can a session using suite self resume from suite prev?
     pub fn can_resume_from(&self, prev: &'static Self) -> Option<&'static Self> {
-        (prev.hash_algorithm() == self.hash_algorithm()).then(|| prev)
+        (prev.hash == self.hash).then(|| prev)
     }
 }
 
@@ -132,33 +120,32 @@ impl fmt::Debug for Tls13CipherSuite {
 }
 
 struct Tls13MessageEncrypter {
-    enc_key: aead::LessSafeKey,
+    enc_key: Box<dyn AeadKey>,
     iv: Iv,
 }
 
 struct Tls13MessageDecrypter {
-    dec_key: aead::LessSafeKey,
+    dec_key: Box<dyn AeadKey>,
     iv: Iv,
 }
 
-fn unpad_tls13(v: &mut Vec<u8>) -> ContentType {
-    loop {
-        match v.pop() {
-            Some(0) => {}
-            Some(content_type) => return ContentType::from(content_type),
-            None => return ContentType::Unknown(0),
-        }
+/// Find the content type of the TLSInnerPlaintext `v`, after any zero
+/// padding.  Returns it, and the length of the content before it.
+fn unpad_tls13(v: &[u8]) -> (ContentType, usize) {
+    match v.iter().rposition(|&b| b != 0) {
+        Some(pos) => (ContentType::from(v[pos]), pos),
+        None => (ContentType::Unknown(0), 0),
     }
 }
 
-fn make_tls13_aad(len: usize) -> ring::aead::Aad<[u8; TLS13_AAD_SIZE]> {
-    ring::aead::Aad::from([
+fn make_tls13_aad(len: usize) -> [u8; TLS13_AAD_SIZE] {
+    [
         0x17, // ContentType::ApplicationData
         0x3,  // ProtocolVersion (major)
         0x3,  // ProtocolVersion (minor)
         (len >> 8) as u8,
         len as u8,
-    ])
+    ]
 }
 
 // https://datatracker.ietf.org/doc/html/rfc8446#section-5.2
@@ -166,17 +153,32 @@ const TLS13_AAD_SIZE: usize = 1 + 2 + 2;
 
 impl MessageEncrypter for Tls13MessageEncrypter {
     fn encrypt(&self, msg: BorrowedPlainMessage, seq: u64) -> Result<OpaqueMessage, Error> {
-        let total_len = msg.payload.len() + 1 + self.enc_key.algorithm().tag_len();
+        self.encrypt_padded(msg, seq, PaddingPolicy::None, MAX_FRAGMENT_LEN)
+    }
+
+    fn encrypt_padded(
+        &self,
+        msg: BorrowedPlainMessage,
+        seq: u64,
+        padding: PaddingPolicy,
+        max_fragment_len: usize,
+    ) -> Result<OpaqueMessage, Error> {
+        let overhead = 1 + self.enc_key.tag_len();
+        let padding_len =
+            padding.padding_len(msg.payload.len() + overhead, max_fragment_len + overhead);
+        let total_len = msg.payload.len() + padding_len + overhead;
         let mut payload = Vec::with_capacity(total_len);
         payload.extend_from_slice(msg.payload);
         msg.typ.encode(&mut payload);
+        payload.resize(payload.len() + padding_len, 0);
 
         let nonce = make_nonce(&self.iv, seq);
         let aad = make_tls13_aad(total_len);
 
-        self.enc_key
-            .seal_in_place_append_tag(nonce, aad, &mut payload)
-            .map_err(|_| Error::General("encrypt failed".to_string()))?;
+        let tag = self
+            .enc_key
+            .seal_in_place_separate_tag(nonce, &aad, &mut payload)?;
+        payload.extend_from_slice(tag.as_ref());
 
         Ok(OpaqueMessage {
             typ: ContentType::ApplicationData,
@@ -188,8 +190,18 @@ impl MessageEncrypter for Tls13MessageEncrypter {
 
 impl MessageDecrypter for Tls13MessageDecrypter {
     fn decrypt(&self, mut msg: OpaqueMessage, seq: u64) -> Result<PlainMessage, Error> {
-        let payload = &mut msg.payload.0;
-        if payload.len() < self.dec_key.algorithm().tag_len() {
+        Ok(self
+            .decrypt_in_place(msg.as_borrowed(), seq)?
+            .to_plain_message())
+    }
+
+    fn decrypt_in_place<'a>(
+        &self,
+        msg: BorrowedOpaqueMessage<'a>,
+        seq: u64,
+    ) -> Result<BorrowedPlainMessage<'a>, Error> {
+        let payload = msg.payload;
+        if payload.len() < self.dec_key.tag_len() {
             return Err(Error::DecryptError);
         }
 
@@ -197,27 +209,26 @@ impl MessageDecrypter for Tls13MessageDecrypter {
         let aad = make_tls13_aad(payload.len());
         let plain_len = self
             .dec_key
-            .open_in_place(nonce, aad, payload)
-            .map_err(|_| Error::DecryptError)?
-            .len();
+            .open_in_place(nonce, &aad, payload)?;
 
-        payload.truncate(plain_len);
-
-        if payload.len() > MAX_FRAGMENT_LEN + 1 {
+        if plain_len > MAX_FRAGMENT_LEN + 1 {
             return Err(Error::PeerSentOversizedRecord);
         }
 
-        msg.typ = unpad_tls13(payload);
-        if msg.typ == ContentType::Unknown(0) {
+        let (typ, content_len) = unpad_tls13(&payload[..plain_len]);
+        if typ == ContentType::Unknown(0) {
             let msg = "peer sent bad TLSInnerPlaintext".to_string();
             return Err(Error::PeerMisbehavedError(msg));
         }
 
-        if payload.len() > MAX_FRAGMENT_LEN {
+        if content_len > MAX_FRAGMENT_LEN {
             return Err(Error::PeerSentOversizedRecord);
         }
 
-        msg.version = ProtocolVersion::TLSv1_3;
-        Ok(msg.into_plain_message())
+        Ok(BorrowedPlainMessage {
+            typ,
+            version: ProtocolVersion::TLSv1_3,
+            payload: &payload[..content_len],
+        })
     }
 }
@@ -45,6 +45,15 @@ impl ChunkVecBuffer {
         len
     }
 
+    /// How much memory we're holding, including spare
+    /// capacity.
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.chunks
+            .iter()
+            .map(Vec::capacity)
+            .sum()
+    }
+
     /// For a proposed append of `len` bytes, how many
     /// bytes should we actually append to adhere to the
     /// currently set `limit`?
@@ -61,10 +70,19 @@ impl ChunkVecBuffer {
     /// we're near the limit.
     pub(crate) fn append_limited_copy(&mut self, bytes: &[u8]) -> usize {
         let take = self.apply_limit(bytes.len());
-        self.append(bytes[..take].to_vec());
+        self.append_copy(&bytes[..take]);
         take
     }
 
+    /// Append a copy of `bytes`.
+    pub(crate) fn append_copy(&mut self, bytes: &[u8]) -> usize {
+        if bytes.is_empty() {
+            return 0;
+        }
+
+        self.append(bytes.to_vec())
+    }
+
     /// Take and append the given `bytes`.
     pub(crate) fn append(&mut self, bytes: Vec<u8>) -> usize {
         let len = bytes.len();
@@ -100,23 +118,33 @@ impl ChunkVecBuffer {
     }
 
     #[cfg(read_buf)]
-    /// Read data out of this object, writing it into `buf`.
-    pub(crate) fn read_buf(&mut self, buf: &mut io::ReadBuf<'_>) -> io::Result<()> {
-        while !self.is_empty() && buf.remaining() > 0 {
+    /// Read data out of this object, writing it into `cursor`.
+    pub(crate) fn read_buf(&mut self, mut cursor: io::BorrowedCursor<'_>) -> io::Result<()> {
+        while !self.is_empty() && cursor.capacity() > 0 {
             let chunk = self.chunks[0].as_slice();
-            let used = std::cmp::min(chunk.len(), buf.remaining());
-            buf.append(&chunk[..used]);
+            let used = std::cmp::min(chunk.len(), cursor.capacity());
+            cursor.append(&chunk[..used]);
             self.consume(used);
         }
 
         Ok(())
     }
 
-    fn consume(&mut self, mut used: usize) {
+    /// Returns the first chunk of data, without consuming
+    /// it.  This is empty if the object `is_empty`.
+    pub(crate) fn peek(&self) -> &[u8] {
+        match self.chunks.front() {
+            Some(chunk) => chunk,
+            None => &[],
+        }
+    }
+
+    /// Discard the first `used` bytes of data.
+    pub(crate) fn consume(&mut self, mut used: usize) {
         while let Some(mut buf) = self.chunks.pop_front() {
             if used < buf.len() {
-                self.chunks
-                    .push_front(buf.split_off(used));
+                buf.drain(..used);
+                self.chunks.push_front(buf);
                 break;
             } else {
                 used -= buf.len();
@@ -158,10 +186,28 @@ mod test {
         assert_eq!(buf.to_vec(), b"helloworldhe".to_vec());
     }
 
+    #[test]
+    fn peek_and_consume() {
+        let mut cvb = ChunkVecBuffer::new(None);
+        assert_eq!(cvb.peek(), b"");
+        cvb.append(b"hello".to_vec());
+        cvb.append(b"world".to_vec());
+
+        assert_eq!(cvb.peek(), b"hello");
+        assert_eq!(cvb.peek(), b"hello");
+        cvb.consume(2);
+        assert_eq!(cvb.peek(), b"llo");
+        cvb.consume(3);
+        assert_eq!(cvb.peek(), b"world");
+        cvb.consume(7);
+        assert!(cvb.is_empty());
+        assert_eq!(cvb.peek(), b"");
+    }
+
     #[cfg(read_buf)]
     #[test]
     fn read_buf() {
-        use std::{io::ReadBuf, mem::MaybeUninit};
+        use std::{io::BorrowedBuf, mem::MaybeUninit};
 
         {
             let mut cvb = ChunkVecBuffer::new(None);
@@ -170,14 +216,14 @@ mod test {
             cvb.append(b"data".to_vec());
 
             let mut buf = [MaybeUninit::<u8>::uninit(); 8];
-            let mut buf = ReadBuf::uninit(&mut buf);
-            cvb.read_buf(&mut buf).unwrap();
+            let mut buf = BorrowedBuf::from(&mut buf[..]);
+            cvb.read_buf(buf.unfilled()).unwrap();
             assert_eq!(buf.filled(), b"test fix");
             buf.clear();
-            cvb.read_buf(&mut buf).unwrap();
+            cvb.read_buf(buf.unfilled()).unwrap();
             assert_eq!(buf.filled(), b"ture dat");
             buf.clear();
-            cvb.read_buf(&mut buf).unwrap();
+            cvb.read_buf(buf.unfilled()).unwrap();
             assert_eq!(buf.filled(), b"a");
         }
 
@@ -186,8 +232,8 @@ mod test {
             cvb.append(b"short message".to_vec());
 
             let mut buf = [MaybeUninit::<u8>::uninit(); 1024];
-            let mut buf = ReadBuf::uninit(&mut buf);
-            cvb.read_buf(&mut buf).unwrap();
+            let mut buf = BorrowedBuf::from(&mut buf[..]);
+            cvb.read_buf(buf.unfilled()).unwrap();
             assert_eq!(buf.filled(), b"short message");
         }
     }
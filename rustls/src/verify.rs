@@ -1,17 +1,21 @@
 use crate::anchors::{OwnedTrustAnchor, RootCertStore};
 use crate::client::ServerName;
+use crate::crypto::Output;
 use crate::error::Error;
 use crate::key::Certificate;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace, warn};
+use crate::msgs::codec::Codec;
 use crate::msgs::enums::SignatureScheme;
-use crate::msgs::handshake::{DigitallySignedStruct, DistinguishedNames};
-
-use ring::digest::Digest;
+use crate::msgs::handshake::{
+    Credential, DelegatedCredentialPayload, DigitallySignedStruct, DistinguishedNames,
+};
+use crate::x509;
 
 use std::convert::TryFrom;
+use std::net::IpAddr;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 type SignatureAlgorithms = &'static [&'static webpki::SignatureAlgorithm];
 
@@ -62,16 +66,40 @@ impl FinishedMessageVerified {
     }
 }
 
-/// Zero-sized marker type representing verification of a server cert chain.
+/// Marker type representing verification of a server cert chain.
+///
+/// This optionally carries the chain that was validated, which is
+/// made available afterwards via [`CommonState::get_verified_cert_chain`].
+///
+/// [`CommonState::get_verified_cert_chain`]: crate::CommonState::get_verified_cert_chain
 #[allow(unreachable_pub)]
 #[derive(Debug)]
-pub struct ServerCertVerified(());
+pub struct ServerCertVerified {
+    verified_chain: Option<Vec<Certificate>>,
+}
 
 #[allow(unreachable_pub)]
 impl ServerCertVerified {
-    /// Make a `ServerCertVerified`
+    /// Make a `ServerCertVerified`, without recording the validated chain.
     pub fn assertion() -> Self {
-        Self(())
+        Self {
+            verified_chain: None,
+        }
+    }
+
+    /// Make a `ServerCertVerified`, recording the chain that was validated.
+    ///
+    /// `chain` starts with the end-entity certificate, followed by the
+    /// intermediates used to reach a trust anchor, in issuing order.
+    /// It does not include the trust anchor itself.
+    pub fn assertion_with_verified_chain(chain: Vec<Certificate>) -> Self {
+        Self {
+            verified_chain: Some(chain),
+        }
+    }
+
+    pub(crate) fn take_verified_chain(&mut self) -> Option<Vec<Certificate>> {
+        self.verified_chain.take()
     }
 }
 
@@ -86,6 +114,56 @@ impl ClientCertVerified {
     }
 }
 
+/// Zero-sized marker type representing verification of a raw public key.
+#[derive(Debug)]
+pub struct RawPublicKeyVerified(());
+
+impl RawPublicKeyVerified {
+    /// Make a `RawPublicKeyVerified`
+    pub fn assertion() -> Self {
+        Self(())
+    }
+}
+
+/// Something that can verify a peer's raw public key (RFC7250), and
+/// verify signatures made by it.
+///
+/// A raw public key is a DER-encoded SubjectPublicKeyInfo sent in place
+/// of a certificate chain.  Since there is nothing to chain to a trust
+/// anchor, implementations typically compare it against a set of keys
+/// known in advance.  Once accepted, the key is available as the only
+/// entry of [`CommonState::peer_certificates`].
+///
+/// Raw public keys are only supported in TLS1.3.
+///
+/// [`CommonState::peer_certificates`]: crate::CommonState::peer_certificates
+pub trait RawPublicKeyVerifier: Send + Sync {
+    /// Verify that `spki`, the DER-encoded SubjectPublicKeyInfo sent
+    /// by the peer, is one we trust.
+    fn verify_raw_public_key(
+        &self,
+        spki: &[u8],
+        now: SystemTime,
+    ) -> Result<RawPublicKeyVerified, Error>;
+
+    /// Verify a TLS1.3 signature allegedly made by the key in `spki`.
+    ///
+    /// `message` is not hashed, and needs hashing during the verification.
+    /// The signature and algorithm are within `dss`.  `spki` is the same
+    /// key that was previously accepted by a call to `verify_raw_public_key`.
+    ///
+    /// This trait method has a default implementation that uses ring to
+    /// verify the signature.
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        spki: &[u8],
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_with_spki(message, spki, dss)
+    }
+}
+
 /// Something that can verify a server certificate chain, and verify
 /// signatures made by certificates.
 #[allow(unreachable_pub)]
@@ -201,15 +279,24 @@ pub trait ClientCertVerifier: Send + Sync {
     /// Return `Some(true)` to require a client certificate and `Some(false)` to make
     /// client authentication optional. Return `None` to abort the connection.
     /// Defaults to `Some(self.offer_client_auth())`.
-    fn client_auth_mandatory(&self) -> Option<bool> {
+    ///
+    /// `server_name` is the name the client sent in its SNI extension, if any;
+    /// this allows the decision to be made per server name.  When client
+    /// authentication is optional, a client which sends no certificate is
+    /// accepted without calling `verify_client_cert`.
+    fn client_auth_mandatory(&self, _server_name: Option<&str>) -> Option<bool> {
         Some(self.offer_client_auth())
     }
 
     /// Returns the subject names of the client authentication trust anchors to
     /// share with the client when requesting client authentication.
     ///
+    /// These are sent in the `certificate_authorities` field of a TLS1.2
+    /// CertificateRequest, or its `certificate_authorities` extension in TLS1.3.
+    ///
     /// Return `None` to abort the connection. Return an empty `Vec` to continue
-    /// the handshake without passing a list of CA DNs.
+    /// the handshake without passing a list of CA DNs, which tells the client
+    /// any issuer is acceptable; the TLS1.3 extension is then omitted.
     fn client_auth_root_subjects(&self) -> Option<DistinguishedNames>;
 
     /// Verify the end-entity certificate `end_entity` is valid for the
@@ -218,6 +305,10 @@ pub trait ClientCertVerifier: Send + Sync {
     /// `intermediates` contains the intermediate certificates the
     /// client sent along with the end-entity certificate; it is in the same
     /// order that the peer sent them and may be empty.
+    ///
+    /// This is called with every certificate chain a client presents, so
+    /// implementations can also reject clients here based on the subject
+    /// of `end_entity`.
     fn verify_client_cert(
         &self,
         end_entity: &Certificate,
@@ -289,7 +380,9 @@ impl ServerCertVerifier for WebPkiVerifier {
     /// Will verify the certificate is valid in the following ways:
     /// - Signed by a  trusted `RootCertStore` CA
     /// - Not Expired
-    /// - Valid for DNS entry
+    /// - Not revoked, according to any configured CRLs
+    /// - Valid for DNS entry or IP address, unless
+    ///   `set_verify_is_valid_cert_ignoring_name` was used
     fn verify_server_cert(
         &self,
         end_entity: &Certificate,
@@ -299,32 +392,398 @@ impl ServerCertVerifier for WebPkiVerifier {
         ocsp_response: &[u8],
         now: SystemTime,
     ) -> Result<ServerCertVerified, Error> {
+        if self.reject_weak_signature_algorithms {
+            reject_weak_signatures(end_entity, intermediates)?;
+        }
+
         let (cert, chain, trustroots) = prepare(end_entity, intermediates, &self.roots)?;
-        let webpki_now = webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)?;
 
-        let ServerName::DnsName(dns_name) = server_name;
+        let webpki_now = self.verify_with_skew(&cert, &chain, &trustroots, now)?;
 
-        let cert = cert
-            .verify_is_valid_tls_server_cert(
+        let verified_chain = verified_chain(end_entity, intermediates, &trustroots, |chain| {
+            cert.verify_is_valid_tls_server_cert(
                 SUPPORTED_SIG_ALGS,
                 &webpki::TlsServerTrustAnchors(&trustroots),
-                &chain,
+                chain,
                 webpki_now,
             )
-            .map_err(pki_error)
-            .map(|_| cert)?;
+            .is_ok()
+        });
+
+        self.crls
+            .check(verified_chain.as_ref(), now)?;
 
         if let Some(policy) = &self.ct_policy {
             policy.verify(end_entity, now, scts)?;
         }
 
-        if !ocsp_response.is_empty() {
+        if self.enforce_ocsp_must_staple {
+            verify_must_staple(end_entity, intermediates, ocsp_response, now)?;
+        } else if !ocsp_response.is_empty() {
             trace!("Unvalidated OCSP response: {:?}", ocsp_response.to_vec());
         }
 
-        cert.verify_is_valid_for_dns_name(dns_name.0.as_ref())
-            .map_err(pki_error)
-            .map(|_| ServerCertVerified::assertion())
+        if !self.ignore_server_name {
+            verify_server_name(&cert, end_entity, server_name)?;
+        }
+
+        Ok(match verified_chain {
+            Some((chain, _)) => ServerCertVerified::assertion_with_verified_chain(chain),
+            None => ServerCertVerified::assertion(),
+        })
+    }
+}
+
+/// Verify that `chain` is a valid server certificate chain for
+/// `server_name` at time `now`, issued by one of `roots`.
+///
+/// `chain` is the end-entity certificate followed by any intermediates,
+/// in the order a server would send them.  This makes the same checks
+/// as a [`ClientConfig`](crate::ClientConfig) with the default verifier,
+/// but without a handshake: so there are no SCTs, stapled OCSP
+/// response or CRLs to check.
+pub fn verify_cert_chain(
+    roots: &RootCertStore,
+    chain: &[Certificate],
+    server_name: &ServerName,
+    now: SystemTime,
+) -> Result<(), Error> {
+    let (end_entity, intermediates) = chain
+        .split_first()
+        .ok_or(Error::NoCertificatesPresented)?;
+    let (cert, intermediates, trustroots) = prepare(end_entity, intermediates, roots)?;
+    let now = webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)?;
+
+    cert.verify_is_valid_tls_server_cert(
+        SUPPORTED_SIG_ALGS,
+        &webpki::TlsServerTrustAnchors(&trustroots),
+        &intermediates,
+        now,
+    )
+    .map_err(pki_error)?;
+
+    verify_server_name(&cert, end_entity, server_name)
+}
+
+/// Check that `cert`, the parsed form of `end_entity`, is valid for
+/// `server_name`.
+fn verify_server_name(
+    cert: &webpki::EndEntityCert,
+    end_entity: &Certificate,
+    server_name: &ServerName,
+) -> Result<(), Error> {
+    match server_name {
+        ServerName::DnsName(dns_name) => cert
+            .verify_is_valid_for_dns_name(dns_name.0.as_ref())
+            .map_err(pki_error),
+        ServerName::IpAddress(ip_address) => verify_ip_address(end_entity, ip_address),
+    }
+}
+
+/// Check that none of `end_entity` and `intermediates` is signed using
+/// MD5 or SHA-1.
+fn reject_weak_signatures(
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+) -> Result<(), Error> {
+    for cert in std::iter::once(end_entity).chain(intermediates) {
+        match x509::has_weak_signature_algorithm(&cert.0) {
+            Some(false) => {}
+            Some(true) => return Err(Error::InvalidCertificateSignatureType),
+            None => return Err(Error::InvalidCertificateEncoding),
+        }
+    }
+    Ok(())
+}
+
+/// Check that `end_entity` is valid for `ip_address`, by matching it
+/// against the certificate's iPAddress subject alternative names.
+///
+/// As RFC6125 requires, the subject common name is never used for this.
+fn verify_ip_address(end_entity: &Certificate, ip_address: &IpAddr) -> Result<(), Error> {
+    let octets = match ip_address {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+
+    match x509::has_ip_address(&end_entity.0, &octets) {
+        Some(true) => Ok(()),
+        Some(false) => Err(pki_error(webpki::Error::CertNotValidForName)),
+        None => Err(Error::InvalidCertificateEncoding),
+    }
+}
+
+/// Check that `end_entity` does not require OCSP stapling, or that
+/// `ocsp_response` shows it has not been revoked if it does.
+///
+/// The response must be signed by the certificate's issuer, or by a
+/// responder it delegated to (RFC6960 section 4.2.2.2), and be current
+/// at `now`.  The issuer must be one of the `intermediates`.
+///
+/// Certificates we cannot parse are left alone: webpki has accepted
+/// them, and we cannot tell if they require a staple.
+fn verify_must_staple(
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    ocsp_response: &[u8],
+    now: SystemTime,
+) -> Result<(), Error> {
+    let cert = match x509::parse_tbs_certificate(&end_entity.0) {
+        Some(cert) if x509::has_must_staple(&end_entity.0) == Some(true) => cert,
+        _ => return Ok(()),
+    };
+    let issuer = intermediates
+        .iter()
+        .filter_map(|inter| x509::parse_tbs_certificate(&inter.0))
+        .find(|inter| inter.subject == cert.issuer && x509::is_signed_by(&end_entity.0, inter.spki))
+        .ok_or_else(|| Error::InvalidCertificateData("cannot find issuer for OCSP check".into()))?;
+    let now = unix_time_millis(now)? / 1000;
+
+    let response = x509::parse_ocsp_response(ocsp_response).ok_or(Error::MissingOcspStaple)?;
+    if !is_ocsp_response_signed(&response, &issuer, now) {
+        warn!("Ignoring OCSP response which is not signed by certificate issuer");
+        return Err(Error::MissingOcspStaple);
+    }
+
+    let single = response
+        .find_response(cert.serial_number, &issuer)
+        .ok_or(Error::MissingOcspStaple)?;
+    match single.cert_status {
+        x509::OcspCertStatus::Good => {}
+        x509::OcspCertStatus::Revoked => return Err(Error::CertRevoked),
+        x509::OcspCertStatus::Unknown => return Err(Error::MissingOcspStaple),
+    }
+
+    match single.next_update {
+        Some(next_update) if single.this_update <= now && now <= next_update => Ok(()),
+        _ => Err(Error::MissingOcspStaple),
+    }
+}
+
+/// Is `response` signed by `issuer`, or by a certificate in the
+/// response which `issuer` authorised to sign OCSP responses and
+/// which is valid at `now`?
+fn is_ocsp_response_signed(
+    response: &x509::BasicOcspResponse,
+    issuer: &x509::TbsCertificate,
+    now: u64,
+) -> bool {
+    let signed_by = |spki| {
+        x509::verify_signed_data(
+            spki,
+            response.signature_algorithm,
+            response.signed_data,
+            response.signature,
+        )
+    };
+
+    if signed_by(issuer.spki) {
+        return true;
+    }
+
+    response
+        .certs()
+        .into_iter()
+        .any(|cert| {
+            let responder = match x509::parse_tbs_certificate(cert) {
+                Some(responder) => responder,
+                None => return false,
+            };
+            let valid = matches!(
+                (x509::not_before(cert), x509::not_after(cert)),
+                (Some(not_before), Some(not_after)) if not_before <= now && now <= not_after
+            );
+
+            valid
+                && responder.issuer == issuer.subject
+                && x509::is_signed_by(cert, issuer.spki)
+                && x509::has_ocsp_signing_usage(cert) == Some(true)
+                && signed_by(responder.spki)
+        })
+}
+
+/// Work out which of `intermediates` webpki used to validate `end_entity`,
+/// and the trust anchor it reached.
+///
+/// webpki does not report the path it built, so we rebuild it with
+/// [`issuer_path`].  Unless the path uses all the presented intermediates,
+/// it is checked by passing only those intermediates to `validate`, which
+/// should validate again with webpki.
+///
+/// Returns None if no such path could be found.
+fn verified_chain<'a, 'b>(
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    trustroots: &'a [webpki::TrustAnchor<'b>],
+    validate: impl Fn(&[&[u8]]) -> bool,
+) -> Option<(Vec<Certificate>, &'a webpki::TrustAnchor<'b>)> {
+    let (used, anchor) = issuer_path(end_entity, intermediates, trustroots)?;
+
+    if used.len() != intermediates.len() {
+        let chain: Vec<&[u8]> = used
+            .iter()
+            .map(|i| intermediates[*i].0.as_ref())
+            .collect();
+        if !validate(&chain) {
+            return None;
+        }
+    }
+
+    let mut verified = vec![end_entity.clone()];
+    verified.extend(
+        used.into_iter()
+            .map(|i| intermediates[i].clone()),
+    );
+    Some((verified, anchor))
+}
+
+/// Find a path from `end_entity` to one of `trustroots`.
+///
+/// This matches issuer names to subject names: trust anchors first, then
+/// intermediates in the order presented, as webpki does.  Signatures are
+/// not checked.
+///
+/// Returns the indices of the intermediates used, in issuing order,
+/// and the trust anchor reached; or None if there is no such path.
+fn issuer_path<'a, 'b>(
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    trustroots: &'a [webpki::TrustAnchor<'b>],
+) -> Option<(Vec<usize>, &'a webpki::TrustAnchor<'b>)> {
+    let (mut issuer, _) = x509::issuer_and_subject(&end_entity.0)?;
+    let mut used = Vec::new();
+
+    loop {
+        if let Some(anchor) = trustroots
+            .iter()
+            .find(|anchor| anchor.subject == issuer)
+        {
+            return Some((used, anchor));
+        }
+
+        if used.len() == intermediates.len() {
+            return None;
+        }
+
+        let (i, inter_issuer) = intermediates
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !used.contains(i))
+            .find_map(|(i, inter)| match x509::issuer_and_subject(&inter.0) {
+                Some((inter_issuer, subject)) if subject == issuer => Some((i, inter_issuer)),
+                _ => None,
+            })?;
+        used.push(i);
+        issuer = inter_issuer;
+    }
+}
+
+/// What to do if a certificate revocation list has passed its
+/// nextUpdate time.
+#[allow(unreachable_pub)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrlExpiryPolicy {
+    /// Fail certificate verification with [`Error::InvalidCrl`].
+    Reject,
+    /// Log a warning, and use the CRL anyway.
+    Warn,
+}
+
+/// A set of DER-encoded certificate revocation lists, and the policy
+/// for using them.
+struct CrlStore {
+    crls: Vec<Vec<u8>>,
+    expiry_policy: CrlExpiryPolicy,
+}
+
+impl CrlStore {
+    fn new() -> Self {
+        Self {
+            crls: Vec::new(),
+            expiry_policy: CrlExpiryPolicy::Reject,
+        }
+    }
+
+    fn add(&mut self, crls: Vec<Vec<u8>>) -> Result<(), Error> {
+        if !crls.iter().all(|crl| {
+            matches!(
+                x509::parse_crl(crl),
+                Some(crl) if x509::is_supported_signature_algorithm(crl.signature_algorithm)
+            )
+        }) {
+            return Err(Error::InvalidCrl("unsupported or malformed CRL".into()));
+        }
+
+        self.crls.extend(crls);
+        Ok(())
+    }
+
+    /// Check that no certificate in `verified`, a validated chain and
+    /// the trust anchor it reached, has been revoked.
+    ///
+    /// Only CRLs naming the issuer of each certificate are used; if there
+    /// is no such CRL, that certificate is assumed not revoked.  But such
+    /// a CRL which is not signed by the issuer fails the check, as does
+    /// a missing chain.
+    fn check(
+        &self,
+        verified: Option<&(Vec<Certificate>, &webpki::TrustAnchor)>,
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        if self.crls.is_empty() {
+            return Ok(());
+        }
+
+        let (chain, anchor) = verified.ok_or_else(|| {
+            Error::InvalidCertificateData("cannot find issuer for revocation check".into())
+        })?;
+        let now = unix_time_millis(now)? / 1000;
+
+        let path = chain
+            .iter()
+            .map(|cert| x509::parse_tbs_certificate(&cert.0))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Error::InvalidCertificateEncoding)?;
+
+        for (i, cert) in path.iter().enumerate() {
+            let issuer_spki = match path.get(i + 1) {
+                Some(issuer) => issuer.spki,
+                None => anchor.spki,
+            };
+
+            for crl in self
+                .crls
+                .iter()
+                .filter_map(|crl| x509::parse_crl(crl))
+                .filter(|crl| crl.issuer == cert.issuer)
+            {
+                if !x509::verify_signed_data(
+                    issuer_spki,
+                    crl.signature_algorithm,
+                    crl.signed_data,
+                    crl.signature,
+                ) {
+                    return Err(Error::InvalidCrl(
+                        "CRL is not signed by certificate issuer".into(),
+                    ));
+                }
+
+                if matches!(crl.next_update, Some(next_update) if next_update < now) {
+                    match self.expiry_policy {
+                        CrlExpiryPolicy::Reject => {
+                            return Err(Error::InvalidCrl("CRL has expired".into()));
+                        }
+                        CrlExpiryPolicy::Warn => warn!("Using expired CRL"),
+                    }
+                }
+
+                if crl.is_revoked(cert.serial_number) {
+                    return Err(Error::CertRevoked);
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -332,7 +791,12 @@ impl ServerCertVerifier for WebPkiVerifier {
 #[allow(unreachable_pub)]
 pub struct WebPkiVerifier {
     roots: RootCertStore,
-    ct_policy: Option<CertificateTransparencyPolicy>,
+    pub(crate) ct_policy: Option<CertificateTransparencyPolicy>,
+    crls: CrlStore,
+    clock_skew: Duration,
+    reject_weak_signature_algorithms: bool,
+    enforce_ocsp_must_staple: bool,
+    ignore_server_name: bool,
 }
 
 #[allow(unreachable_pub)]
@@ -345,7 +809,119 @@ impl WebPkiVerifier {
     /// Transparency. Currently CT log enforcement is opportunistic; see
     /// <https://github.com/rustls/rustls/issues/479>.
     pub fn new(roots: RootCertStore, ct_policy: Option<CertificateTransparencyPolicy>) -> Self {
-        Self { roots, ct_policy }
+        Self {
+            roots,
+            ct_policy,
+            crls: CrlStore::new(),
+            clock_skew: Duration::from_secs(0),
+            reject_weak_signature_algorithms: false,
+            enforce_ocsp_must_staple: false,
+            ignore_server_name: false,
+        }
+    }
+
+    /// Adds DER-encoded certificate revocation lists to check server
+    /// certificates against.
+    ///
+    /// A server certificate chain is rejected with [`Error::CertRevoked`]
+    /// if any certificate in it appears in a CRL signed by its issuer,
+    /// and with [`Error::InvalidCrl`] if a CRL naming its issuer is not
+    /// signed by it.  CRLs with critical extensions (such as delta CRLs)
+    /// are not supported, and neither are CRLs signed with RSASSA-PSS:
+    /// this fails if any of `crls` is one of those.
+    pub fn with_crls(&mut self, crls: Vec<Vec<u8>>) -> Result<(), Error> {
+        self.crls.add(crls)
+    }
+
+    /// Sets what to do if a CRL has expired.  The default is
+    /// [`CrlExpiryPolicy::Reject`].
+    pub fn set_crl_expiry_policy(&mut self, policy: CrlExpiryPolicy) {
+        self.crls.expiry_policy = policy;
+    }
+
+    /// Sets how far our clock may be wrong.  A certificate chain which
+    /// has expired, or is not yet valid, is accepted if it would be
+    /// valid within `clock_skew` of the current time.
+    ///
+    /// The default is zero.
+    pub fn set_clock_skew(&mut self, clock_skew: Duration) {
+        self.clock_skew = clock_skew;
+    }
+
+    /// Sets whether to reject a certificate chain if any certificate the
+    /// server sent is signed using MD5 or SHA-1, with
+    /// [`Error::InvalidCertificateSignatureType`].
+    ///
+    /// Such signatures are never accepted when building a path to a
+    /// trust anchor.  This additionally rejects them on certificates
+    /// which are not needed for the path.  The default is false.
+    pub fn set_reject_weak_signature_algorithms(&mut self, reject: bool) {
+        self.reject_weak_signature_algorithms = reject;
+    }
+
+    /// Sets whether to reject server certificates with the RFC7633 TLS
+    /// feature extension requiring `status_request` ("OCSP must-staple"),
+    /// unless the server staples a valid and current OCSP response.
+    ///
+    /// The response must come from the certificate's issuer, or a
+    /// responder it delegated to, and the issuer must be among the
+    /// certificates the server sent.  A response saying the certificate
+    /// is revoked fails with [`Error::CertRevoked`]; otherwise a missing
+    /// or invalid one fails with [`Error::MissingOcspStaple`].  The
+    /// default is false.
+    pub fn set_enforce_ocsp_must_staple(&mut self, enforce: bool) {
+        self.enforce_ocsp_must_staple = enforce;
+    }
+
+    /// Sets whether to skip checking that the server's certificate is
+    /// valid for the name given to [`ClientConnection::new`](crate::ClientConnection::new).
+    /// The chain is still validated to a trust anchor, but **any** server
+    /// with a certificate from those anchors is then accepted.
+    ///
+    /// This is only safe if the caller checks the certificate's names
+    /// itself, for example with [`CommonState::peer_certificates`](crate::CommonState::peer_certificates),
+    /// before trusting the connection.  The default is false.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn set_verify_is_valid_cert_ignoring_name(&mut self, ignore: bool) {
+        self.ignore_server_name = ignore;
+    }
+
+    /// Validate the chain from `cert` at `now`, allowing for
+    /// `clock_skew`.  Returns the time at which the chain is valid.
+    fn verify_with_skew(
+        &self,
+        cert: &webpki::EndEntityCert,
+        chain: &[&[u8]],
+        trustroots: &[webpki::TrustAnchor],
+        now: SystemTime,
+    ) -> Result<webpki::Time, Error> {
+        let to_webpki_time = |now: SystemTime| {
+            webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)
+        };
+        let verify = |webpki_now: webpki::Time| {
+            cert.verify_is_valid_tls_server_cert(
+                SUPPORTED_SIG_ALGS,
+                &webpki::TlsServerTrustAnchors(trustroots),
+                chain,
+                webpki_now,
+            )
+        };
+
+        let webpki_now = to_webpki_time(now)?;
+        let skewed_now = match verify(webpki_now) {
+            Ok(_) => return Ok(webpki_now),
+            Err(webpki::Error::CertExpired) if self.clock_skew != Duration::from_secs(0) => {
+                now.checked_sub(self.clock_skew)
+            }
+            Err(webpki::Error::CertNotValidYet) if self.clock_skew != Duration::from_secs(0) => {
+                now.checked_add(self.clock_skew)
+            }
+            Err(err) => return Err(pki_error(err)),
+        };
+
+        let webpki_now = to_webpki_time(skewed_now.ok_or(Error::FailedToGetCurrentTime)?)?;
+        verify(webpki_now).map_err(pki_error)?;
+        Ok(webpki_now)
     }
 
     /// Returns the signature verification methods supported by
@@ -365,6 +941,227 @@ impl WebPkiVerifier {
     }
 }
 
+/// A `ServerCertVerifier` which pins the server's public key.
+///
+/// After validating the server's certificate with a [`WebPkiVerifier`],
+/// this requires a certificate in the validated chain to have a
+/// SubjectPublicKeyInfo whose SHA-256 hash is one of the pins.  Pins
+/// work across certificate renewals so long as the key is kept, and
+/// pinning an intermediate's key trusts any certificate it issues.
+#[cfg(feature = "dangerous_configuration")]
+#[allow(unreachable_pub)]
+pub struct SpkiPinVerifier {
+    inner: WebPkiVerifier,
+    pins: Vec<[u8; 32]>,
+    leaf_only: bool,
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[allow(unreachable_pub)]
+impl SpkiPinVerifier {
+    /// Constructs a new `SpkiPinVerifier`, checking certificates with
+    /// `inner` and then requiring one of `pins`: SHA-256 hashes of
+    /// DER-encoded SubjectPublicKeyInfos.
+    ///
+    /// By default, any certificate in the validated chain may match.
+    pub fn new(inner: WebPkiVerifier, pins: Vec<[u8; 32]>) -> Self {
+        Self {
+            inner,
+            pins,
+            leaf_only: false,
+        }
+    }
+
+    /// Sets whether only the end-entity certificate may match a pin,
+    /// rather than any certificate in the validated chain.
+    pub fn set_leaf_only(&mut self, leaf_only: bool) {
+        self.leaf_only = leaf_only;
+    }
+
+    fn is_pinned(&self, cert: &Certificate) -> bool {
+        let tbs = match x509::parse_tbs_certificate(&cert.0) {
+            Some(tbs) => tbs,
+            None => return false,
+        };
+        let mut spki = tbs.spki.to_vec();
+        x509::wrap_in_sequence(&mut spki);
+        let hash = ring::digest::digest(&ring::digest::SHA256, &spki);
+        self.pins
+            .iter()
+            .any(|pin| pin[..] == *hash.as_ref())
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+impl ServerCertVerifier for SpkiPinVerifier {
+    /// Verifies the certificate as a [`WebPkiVerifier`] does, then checks
+    /// it against the pins.
+    ///
+    /// The chain searched is the one validated, which excludes the trust
+    /// anchor.  If that chain can't be worked out, only the end-entity
+    /// certificate is checked.
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let mut verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+
+        let pinned = match verified.take_verified_chain() {
+            Some(chain) => {
+                let pinned = match self.leaf_only {
+                    true => self.is_pinned(end_entity),
+                    false => chain
+                        .iter()
+                        .any(|cert| self.is_pinned(cert)),
+                };
+                verified = ServerCertVerified::assertion_with_verified_chain(chain);
+                pinned
+            }
+            None => self.is_pinned(end_entity),
+        };
+
+        match pinned {
+            true => Ok(verified),
+            false => Err(Error::PinMismatch),
+        }
+    }
+}
+
+/// A `ServerCertVerifier` which requires two other verifiers to accept
+/// the server's certificate.
+///
+/// Handshake signatures are verified by the first verifier, and the
+/// chain it validated is the one reported afterwards.  Use
+/// [`AndVerifier::with_check`] to add a check to, say, a
+/// [`WebPkiVerifier`] without implementing `ServerCertVerifier`.
+#[cfg(feature = "dangerous_configuration")]
+#[allow(unreachable_pub)]
+pub struct AndVerifier {
+    first: Arc<dyn ServerCertVerifier>,
+    second: Arc<dyn ServerCertVerifier>,
+}
+
+#[cfg(feature = "dangerous_configuration")]
+#[allow(unreachable_pub)]
+impl AndVerifier {
+    /// Constructs a new `AndVerifier`, accepting certificates which
+    /// `first` and then `second` accept.
+    pub fn new(first: Arc<dyn ServerCertVerifier>, second: Arc<dyn ServerCertVerifier>) -> Self {
+        Self { first, second }
+    }
+
+    /// Constructs a new `AndVerifier`, accepting certificates which
+    /// `inner` accepts and then `check` returns `Ok` for.
+    ///
+    /// `check` is given the end-entity certificate and the intermediates,
+    /// as the server sent them.
+    pub fn with_check<F>(inner: Arc<dyn ServerCertVerifier>, check: F) -> Self
+    where
+        F: Fn(&Certificate, &[Certificate]) -> Result<(), Error> + Send + Sync + 'static,
+    {
+        Self::new(inner, Arc::new(CheckVerifier(check)))
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+impl ServerCertVerifier for AndVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let scts: Vec<&[u8]> = scts.collect();
+        let verified = self.first.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            &mut scts.iter().copied(),
+            ocsp_response,
+            now,
+        )?;
+        self.second.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            &mut scts.iter().copied(),
+            ocsp_response,
+            now,
+        )?;
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.first
+            .verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.first
+            .verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.first.supported_verify_schemes()
+    }
+
+    fn request_scts(&self) -> bool {
+        self.first.request_scts() || self.second.request_scts()
+    }
+}
+
+/// Adapts a closure for [`AndVerifier::with_check`].
+#[cfg(feature = "dangerous_configuration")]
+struct CheckVerifier<F>(F);
+
+#[cfg(feature = "dangerous_configuration")]
+impl<F> ServerCertVerifier for CheckVerifier<F>
+where
+    F: Fn(&Certificate, &[Certificate]) -> Result<(), Error> + Send + Sync,
+{
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        (self.0)(end_entity, intermediates)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn request_scts(&self) -> bool {
+        false
+    }
+}
+
 /// Policy for enforcing Certificate Transparency.
 ///
 /// Because Certificate Transparency logs are sharded on a per-year basis and can be trusted or
@@ -473,6 +1270,7 @@ fn prepare<'a, 'b>(
 /// certificate, without any name checking.
 pub struct AllowAnyAuthenticatedClient {
     roots: RootCertStore,
+    crls: CrlStore,
 }
 
 impl AllowAnyAuthenticatedClient {
@@ -480,7 +1278,33 @@ impl AllowAnyAuthenticatedClient {
     ///
     /// `roots` is the list of trust anchors to use for certificate validation.
     pub fn new(roots: RootCertStore) -> Arc<dyn ClientCertVerifier> {
-        Arc::new(Self { roots })
+        Arc::new(Self {
+            roots,
+            crls: CrlStore::new(),
+        })
+    }
+
+    /// Construct a new `AllowAnyAuthenticatedClient` which also checks
+    /// client certificates against certificate revocation lists.
+    ///
+    /// `roots` is the list of trust anchors to use for certificate validation.
+    /// `crls` is a list of DER-encoded CRLs: a client certificate chain is
+    /// rejected with [`Error::CertRevoked`] if any certificate in it appears
+    /// in a CRL signed by its issuer, and with [`Error::InvalidCrl`] if a CRL
+    /// naming its issuer is not signed by it.  `expiry_policy` says what to
+    /// do if one of them has expired.
+    ///
+    /// This fails if any of `crls` cannot be parsed, or is signed with an
+    /// unsupported algorithm such as RSASSA-PSS.
+    pub fn new_with_crls(
+        roots: RootCertStore,
+        crls: Vec<Vec<u8>>,
+        expiry_policy: CrlExpiryPolicy,
+    ) -> Result<Arc<dyn ClientCertVerifier>, Error> {
+        let mut store = CrlStore::new();
+        store.add(crls)?;
+        store.expiry_policy = expiry_policy;
+        Ok(Arc::new(Self { roots, crls: store }))
     }
 }
 
@@ -500,15 +1324,28 @@ impl ClientCertVerifier for AllowAnyAuthenticatedClient {
         now: SystemTime,
     ) -> Result<ClientCertVerified, Error> {
         let (cert, chain, trustroots) = prepare(end_entity, intermediates, &self.roots)?;
-        let now = webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)?;
+        let webpki_now = webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)?;
         cert.verify_is_valid_tls_client_cert(
             SUPPORTED_SIG_ALGS,
             &webpki::TlsClientTrustAnchors(&trustroots),
             &chain,
-            now,
+            webpki_now,
         )
-        .map_err(pki_error)
-        .map(|_| ClientCertVerified::assertion())
+        .map_err(pki_error)?;
+
+        let verified_chain = verified_chain(end_entity, intermediates, &trustroots, |chain| {
+            cert.verify_is_valid_tls_client_cert(
+                SUPPORTED_SIG_ALGS,
+                &webpki::TlsClientTrustAnchors(&trustroots),
+                chain,
+                webpki_now,
+            )
+            .is_ok()
+        });
+
+        self.crls
+            .check(verified_chain.as_ref(), now)
+            .map(|_| ClientCertVerified::assertion())
     }
 }
 
@@ -528,7 +1365,10 @@ impl AllowAnyAnonymousOrAuthenticatedClient {
     /// `roots` is the list of trust anchors to use for certificate validation.
     pub fn new(roots: RootCertStore) -> Arc<dyn ClientCertVerifier> {
         Arc::new(Self {
-            inner: AllowAnyAuthenticatedClient { roots },
+            inner: AllowAnyAuthenticatedClient {
+                roots,
+                crls: CrlStore::new(),
+            },
         })
     }
 }
@@ -538,7 +1378,7 @@ impl ClientCertVerifier for AllowAnyAnonymousOrAuthenticatedClient {
         self.inner.offer_client_auth()
     }
 
-    fn client_auth_mandatory(&self) -> Option<bool> {
+    fn client_auth_mandatory(&self, _server_name: Option<&str>) -> Option<bool> {
         Some(false)
     }
 
@@ -561,6 +1401,10 @@ fn pki_error(error: webpki::Error) -> Error {
     use webpki::Error::*;
     match error {
         BadDer | BadDerTime => Error::InvalidCertificateEncoding,
+        CertExpired => Error::CertExpired,
+        CertNotValidYet => Error::CertNotValidYet,
+        CertNotValidForName => Error::CertNotValidForName,
+        UnknownIssuer => Error::UnknownIssuer,
         InvalidSignatureForPublicKey => Error::InvalidCertificateSignature,
         UnsupportedSignatureAlgorithm | UnsupportedSignatureAlgorithmForPublicKey => {
             Error::InvalidCertificateSignatureType
@@ -687,17 +1531,17 @@ fn convert_alg_tls13(
 }
 
 /// Constructs the signature message specified in section 4.4.3 of RFC8446.
-pub(crate) fn construct_tls13_client_verify_message(handshake_hash: &Digest) -> Vec<u8> {
+pub(crate) fn construct_tls13_client_verify_message(handshake_hash: &Output) -> Vec<u8> {
     construct_tls13_verify_message(handshake_hash, b"TLS 1.3, client CertificateVerify\x00")
 }
 
 /// Constructs the signature message specified in section 4.4.3 of RFC8446.
-pub(crate) fn construct_tls13_server_verify_message(handshake_hash: &Digest) -> Vec<u8> {
+pub(crate) fn construct_tls13_server_verify_message(handshake_hash: &Output) -> Vec<u8> {
     construct_tls13_verify_message(handshake_hash, b"TLS 1.3, server CertificateVerify\x00")
 }
 
 fn construct_tls13_verify_message(
-    handshake_hash: &Digest,
+    handshake_hash: &Output,
     context_string_with_0: &[u8],
 ) -> Vec<u8> {
     let mut msg = Vec::new();
@@ -707,6 +1551,90 @@ fn construct_tls13_verify_message(
     msg
 }
 
+/// Constructs the message signed by the issuer of a delegated credential,
+/// as specified in section 4 of RFC9345.
+pub(crate) fn construct_delegated_credential_message(
+    end_entity: &Certificate,
+    credential: &Credential,
+    algorithm: SignatureScheme,
+) -> Vec<u8> {
+    let mut msg = Vec::new();
+    msg.resize(64, 0x20u8);
+    msg.extend_from_slice(b"TLS, server delegated credentials\x00");
+    msg.extend_from_slice(&end_entity.0);
+    credential.encode(&mut msg);
+    algorithm.encode(&mut msg);
+    msg
+}
+
+/// The longest a delegated credential may be valid for: see section
+/// 4.1.3 of RFC9345.
+const MAX_DELEGATED_CREDENTIAL_VALIDITY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Verify `dss`, a TLS1.3 signature over `msg` made by the key in
+/// `dc`, a delegated credential the server sent with `end_entity`.
+///
+/// `end_entity` must already be verified by `verifier`, which we
+/// then use to check its key signed `dc`; and it must allow delegation
+/// (RFC9345 section 4.2).  `offered` are the schemes we accept for
+/// delegated credentials.
+pub(crate) fn verify_tls13_delegated(
+    verifier: &dyn ServerCertVerifier,
+    msg: &[u8],
+    end_entity: &Certificate,
+    dc: &DelegatedCredentialPayload,
+    offered: &[SignatureScheme],
+    dss: &DigitallySignedStruct,
+    now: SystemTime,
+) -> Result<HandshakeSignatureValid, Error> {
+    let credential = &dc.credential;
+    if !offered.contains(&credential.scheme)
+        || !verifier
+            .supported_verify_schemes()
+            .contains(&dc.algorithm)
+    {
+        return Err(Error::PeerMisbehavedError(
+            "delegated credential uses unoffered signature scheme".to_string(),
+        ));
+    }
+
+    if dss.scheme != credential.scheme {
+        return Err(Error::PeerMisbehavedError(
+            "handshake signature scheme does not match delegated credential".to_string(),
+        ));
+    }
+
+    match x509::allows_delegated_credentials(&end_entity.0) {
+        Some(true) => {}
+        Some(false) => {
+            return Err(Error::InvalidCertificateData(
+                "certificate does not allow delegated credentials".to_string(),
+            ));
+        }
+        None => return Err(Error::InvalidCertificateEncoding),
+    }
+
+    let not_before = x509::not_before(&end_entity.0).ok_or(Error::InvalidCertificateEncoding)?;
+    let expiry = UNIX_EPOCH + Duration::from_secs(not_before + u64::from(credential.valid_time));
+    if now >= expiry {
+        return Err(Error::InvalidCertificateData(
+            "delegated credential expired".to_string(),
+        ));
+    }
+    if expiry > now + MAX_DELEGATED_CREDENTIAL_VALIDITY {
+        return Err(Error::InvalidCertificateData(
+            "delegated credential valid for too long".to_string(),
+        ));
+    }
+
+    verifier.verify_tls13_signature(
+        &construct_delegated_credential_message(end_entity, credential, dc.algorithm),
+        end_entity,
+        &DigitallySignedStruct::new(dc.algorithm, dc.signature.0.clone()),
+    )?;
+    verify_tls13_with_spki(msg, &credential.public_key.0, dss)
+}
+
 fn verify_tls13(
     msg: &[u8],
     cert: &Certificate,
@@ -721,8 +1649,22 @@ fn verify_tls13(
         .map(|_| HandshakeSignatureValid::assertion())
 }
 
+fn verify_tls13_with_spki(
+    msg: &[u8],
+    spki: &[u8],
+    dss: &DigitallySignedStruct,
+) -> Result<HandshakeSignatureValid, Error> {
+    convert_alg_tls13(dss.scheme)?;
+
+    match x509::verify_tls13_signature_with_spki(spki, dss.scheme, msg, &dss.sig.0) {
+        Some(true) => Ok(HandshakeSignatureValid::assertion()),
+        Some(false) => Err(Error::InvalidCertificateSignature),
+        None => Err(Error::InvalidCertificateSignatureType),
+    }
+}
+
 fn unix_time_millis(now: SystemTime) -> Result<u64, Error> {
-    now.duration_since(std::time::UNIX_EPOCH)
+    now.duration_since(UNIX_EPOCH)
         .map(|dur| dur.as_secs())
         .map_err(|_| Error::FailedToGetCurrentTime)
         .and_then(|secs| {
@@ -751,7 +1693,7 @@ mod tests {
         );
         assert_eq!(
             format!("{:?}", ServerCertVerified::assertion()),
-            "ServerCertVerified(())"
+            "ServerCertVerified { verified_chain: None }"
         );
     }
 }
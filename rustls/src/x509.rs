@@ -1,6 +1,10 @@
 // Additional x509/asn1 functions to those provided in webpki/ring.
 
+use crate::msgs::enums::{SignatureAlgorithm, SignatureScheme};
+
+use ring::digest;
 use ring::io::der;
+use ring::signature;
 
 fn wrap_in_asn1_len(bytes: &mut Vec<u8>) {
     let len = bytes.len();
@@ -25,6 +29,970 @@ pub(crate) fn wrap_in_sequence(bytes: &mut Vec<u8>) {
     bytes.insert(0, der::Tag::Sequence as u8);
 }
 
+/// Split one DER TLV with the given `tag` from the front of `input`,
+/// returning its contents and the remainder of `input`.
+pub(crate) fn read_tlv(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&actual_tag, rest) = input.split_first()?;
+    if actual_tag != tag {
+        return None;
+    }
+
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        0x00..=0x7f => (first as usize, rest),
+        0x81..=0x84 => {
+            let octets = (first & 0x7f) as usize;
+            if rest.len() < octets {
+                return None;
+            }
+            let (len_bytes, rest) = rest.split_at(octets);
+            let len = len_bytes
+                .iter()
+                .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+            (len, rest)
+        }
+        _ => return None,
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+const TAG_BOOLEAN: u8 = 0x01;
+pub(crate) const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+pub(crate) const TAG_OCTET_STRING: u8 = 0x04;
+pub(crate) const TAG_OID: u8 = 0x06;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_CONTEXT_0: u8 = 0xa0;
+const TAG_CONTEXT_0_PRIMITIVE: u8 = 0x80;
+const TAG_CONTEXT_1: u8 = 0xa1;
+const TAG_CONTEXT_1_PRIMITIVE: u8 = 0x81;
+const TAG_CONTEXT_2: u8 = 0xa2;
+const TAG_CONTEXT_2_PRIMITIVE: u8 = 0x82;
+const TAG_CONTEXT_3: u8 = 0xa3;
+const TAG_CONTEXT_7_PRIMITIVE: u8 = 0x87;
+
+// DER-encoded object identifier contents for the signature and
+// hash algorithms we know about.
+const OID_MD5_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x04];
+const OID_SHA1_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x05];
+const OID_RSASSA_PSS: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0a];
+const OID_SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+const OID_SHA384_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+const OID_SHA512_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+const OID_ECDSA_WITH_SHA1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x01];
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_ECDSA_WITH_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const OID_ECDSA_WITH_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04];
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+const OID_SHA384: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+const OID_SHA512: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+
+// ... and for the public key types and curves.
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_SECP256R1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_SECP384R1: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+
+// ... and for the TLS feature extension (RFC7633).
+const OID_TLS_FEATURE: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x18];
+
+// ... and for embedded SCTs (RFC6962 section 3.3).
+const OID_SCT_LIST: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xd6, 0x79, 0x02, 0x04, 0x02];
+
+// ... and for the subject alternative name extension (RFC5280 section 4.2.1.6).
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+// ... and for the extended key usage extension (RFC5280 section 4.2.1.12),
+// and the OCSP signing purpose.
+const OID_EXTENDED_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+const OID_KP_OCSP_SIGNING: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x09];
+
+// ... and for the key usage extension (RFC5280 section 4.2.1.3), and
+// the DelegationUsage extension (RFC9345 section 4.2).
+const OID_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x0f];
+const OID_DELEGATION_USAGE: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0xda, 0x4b, 0x2c];
+
+// ... and for basic OCSP responses (RFC6960 section 4.2.1).
+const OID_PKIX_OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+
+/// Return the hash function used for the `tls-server-end-point`
+/// channel binding of the DER-encoded certificate `cert`.
+///
+/// This is the hash function of the certificate's signature algorithm,
+/// except that MD5 and SHA-1 are replaced with SHA-256 (RFC5929 section 4.1).
+/// Returns None if the certificate cannot be parsed, or its signature
+/// algorithm does not have a single hash function (eg. Ed25519).
+pub(crate) fn server_end_point_hash(cert: &[u8]) -> Option<&'static digest::Algorithm> {
+    let (cert, _) = read_tlv(cert, der::Tag::Sequence as u8)?;
+    let (_tbs_certificate, rest) = read_tlv(cert, der::Tag::Sequence as u8)?;
+    let (signature_algorithm, _) = read_tlv(rest, der::Tag::Sequence as u8)?;
+    let (oid, params) = read_tlv(signature_algorithm, TAG_OID)?;
+
+    match oid {
+        OID_MD5_WITH_RSA | OID_SHA1_WITH_RSA | OID_ECDSA_WITH_SHA1 => Some(&digest::SHA256),
+        OID_SHA256_WITH_RSA | OID_ECDSA_WITH_SHA256 => Some(&digest::SHA256),
+        OID_SHA384_WITH_RSA | OID_ECDSA_WITH_SHA384 => Some(&digest::SHA384),
+        OID_SHA512_WITH_RSA | OID_ECDSA_WITH_SHA512 => Some(&digest::SHA512),
+        OID_RSASSA_PSS => pss_hash(params),
+        _ => None,
+    }
+}
+
+/// Return whether the DER-encoded certificate `cert` is signed using
+/// MD5 or SHA-1, or None if it cannot be parsed.
+pub(crate) fn has_weak_signature_algorithm(cert: &[u8]) -> Option<bool> {
+    let (cert, _) = read_tlv(cert, der::Tag::Sequence as u8)?;
+    let (_tbs_certificate, rest) = read_tlv(cert, der::Tag::Sequence as u8)?;
+    let (signature_algorithm, _) = read_tlv(rest, der::Tag::Sequence as u8)?;
+    let (oid, _) = read_tlv(signature_algorithm, TAG_OID)?;
+
+    Some(matches!(
+        oid,
+        OID_MD5_WITH_RSA | OID_SHA1_WITH_RSA | OID_ECDSA_WITH_SHA1
+    ))
+}
+
+/// The fields of a TBSCertificate (RFC5280 section 4.1) that we need
+/// for revocation checking, must-staple enforcement and delegated
+/// credentials.
+///
+/// Each is the contents of the field, without its tag and length.  The
+/// names are in the same form webpki uses for trust anchor subjects.
+pub(crate) struct TbsCertificate<'a> {
+    pub(crate) serial_number: &'a [u8],
+    pub(crate) issuer: &'a [u8],
+    pub(crate) validity: &'a [u8],
+    pub(crate) subject: &'a [u8],
+    pub(crate) spki: &'a [u8],
+    /// The Extensions, or empty if there are none.
+    pub(crate) extensions: &'a [u8],
+}
+
+/// Parse the TBSCertificate of the DER-encoded certificate `cert`.
+pub(crate) fn parse_tbs_certificate(cert: &[u8]) -> Option<TbsCertificate<'_>> {
+    let (cert, _) = read_tlv(cert, der::Tag::Sequence as u8)?;
+    let (tbs_certificate, _) = read_tlv(cert, der::Tag::Sequence as u8)?;
+
+    // The version field is optional.
+    let rest = match read_tlv(tbs_certificate, TAG_CONTEXT_0) {
+        Some((_version, rest)) => rest,
+        None => tbs_certificate,
+    };
+    let (serial_number, rest) = read_tlv(rest, TAG_INTEGER)?;
+    let (_signature, rest) = read_tlv(rest, der::Tag::Sequence as u8)?;
+    let (issuer, rest) = read_tlv(rest, der::Tag::Sequence as u8)?;
+    let (validity, rest) = read_tlv(rest, der::Tag::Sequence as u8)?;
+    let (subject, rest) = read_tlv(rest, der::Tag::Sequence as u8)?;
+    let (spki, rest) = read_tlv(rest, der::Tag::Sequence as u8)?;
+
+    // issuerUniqueID and subjectUniqueID are optional, and unused.
+    let rest = read_tlv(rest, TAG_CONTEXT_1_PRIMITIVE).map_or(rest, |(_, rest)| rest);
+    let rest = read_tlv(rest, TAG_CONTEXT_2_PRIMITIVE).map_or(rest, |(_, rest)| rest);
+    let extensions = match read_tlv(rest, TAG_CONTEXT_3) {
+        Some((extensions, _)) => read_tlv(extensions, der::Tag::Sequence as u8)?.0,
+        None => &[],
+    };
+
+    Some(TbsCertificate {
+        serial_number,
+        issuer,
+        validity,
+        subject,
+        spki,
+        extensions,
+    })
+}
+
+/// Return the kind of key certified by the DER-encoded certificate
+/// `cert`, and the key itself as found in its subjectPublicKey.
+pub(crate) fn certified_public_key(cert: &[u8]) -> Option<(SignatureAlgorithm, &[u8])> {
+    let info = parse_spki(parse_tbs_certificate(cert)?.spki)?;
+    let algorithm = match info.key_type {
+        OID_RSA_ENCRYPTION => SignatureAlgorithm::RSA,
+        OID_EC_PUBLIC_KEY => SignatureAlgorithm::ECDSA,
+        OID_ED25519 => SignatureAlgorithm::ED25519,
+        _ => return None,
+    };
+    Some((algorithm, info.public_key))
+}
+
+/// Return the notBefore time of the DER-encoded certificate `cert`, in
+/// seconds since the UNIX epoch.
+pub(crate) fn not_before(cert: &[u8]) -> Option<u64> {
+    let validity = parse_tbs_certificate(cert)?.validity;
+    read_time(validity).map(|(not_before, _)| not_before)
+}
+
+/// Return the notAfter time of the DER-encoded certificate `cert`, in
+/// seconds since the UNIX epoch.
+pub(crate) fn not_after(cert: &[u8]) -> Option<u64> {
+    let validity = parse_tbs_certificate(cert)?.validity;
+    let (_not_before, rest) = read_time(validity)?;
+    read_time(rest).map(|(not_after, _)| not_after)
+}
+
+/// Is the DER-encoded certificate `cert` signed by the key in `spki`?
+///
+/// `spki` is the contents of the issuer's SubjectPublicKeyInfo.
+pub(crate) fn is_signed_by(cert: &[u8], spki: &[u8]) -> bool {
+    let check = || -> Option<bool> {
+        let (cert, _) = read_tlv(cert, der::Tag::Sequence as u8)?;
+        let (_tbs_certificate, rest) = read_tlv(cert, der::Tag::Sequence as u8)?;
+        let signed_data = &cert[..cert.len() - rest.len()];
+        let (signature_algorithm, rest) = read_tlv(rest, der::Tag::Sequence as u8)?;
+        let (signature, _) = read_tlv(rest, TAG_BIT_STRING)?;
+        let signature = match signature.split_first() {
+            Some((0, signature)) => signature,
+            _ => return None,
+        };
+        Some(verify_signed_data(
+            spki,
+            signature_algorithm,
+            signed_data,
+            signature,
+        ))
+    };
+
+    check() == Some(true)
+}
+
+/// Does the DER-encoded certificate `cert` have the OCSP signing
+/// extended key usage?  This is required of delegated OCSP responders
+/// (RFC6960 section 4.2.2.2).
+///
+/// Returns None if the certificate cannot be parsed.
+pub(crate) fn has_ocsp_signing_usage(cert: &[u8]) -> Option<bool> {
+    let extn_value = match find_extension(cert, OID_EXTENDED_KEY_USAGE)? {
+        Some(extn_value) => extn_value,
+        None => return Some(false),
+    };
+    let (mut purposes, _) = read_tlv(extn_value, der::Tag::Sequence as u8)?;
+
+    while !purposes.is_empty() {
+        let (purpose, next) = read_tlv(purposes, TAG_OID)?;
+        if purpose == OID_KP_OCSP_SIGNING {
+            return Some(true);
+        }
+        purposes = next;
+    }
+
+    Some(false)
+}
+
+/// May the key in the DER-encoded certificate `cert` sign delegated
+/// credentials?  This needs the DelegationUsage extension, and the
+/// digitalSignature key usage (RFC9345 section 4.2).
+///
+/// Returns None if the certificate cannot be parsed.
+pub(crate) fn allows_delegated_credentials(cert: &[u8]) -> Option<bool> {
+    if find_extension(cert, OID_DELEGATION_USAGE)?.is_none() {
+        return Some(false);
+    }
+    let extn_value = match find_extension(cert, OID_KEY_USAGE)? {
+        Some(extn_value) => extn_value,
+        None => return Some(false),
+    };
+    let (key_usage, _) = read_tlv(extn_value, TAG_BIT_STRING)?;
+
+    // digitalSignature is the first bit, after the count of unused bits.
+    Some(matches!(key_usage, [_, first, ..] if first & 0x80 != 0))
+}
+
+/// Does the DER-encoded certificate `cert` have the TLS feature
+/// extension (RFC7633), requiring the `status_request` feature?
+///
+/// This is known as "OCSP must-staple".  Returns None if the certificate
+/// cannot be parsed.
+pub(crate) fn has_must_staple(cert: &[u8]) -> Option<bool> {
+    let extn_value = match find_extension(cert, OID_TLS_FEATURE)? {
+        Some(extn_value) => extn_value,
+        None => return Some(false),
+    };
+    let (mut features, _) = read_tlv(extn_value, der::Tag::Sequence as u8)?;
+
+    while !features.is_empty() {
+        let (feature, next) = read_tlv(features, TAG_INTEGER)?;
+        // status_request, from the TLS ExtensionType registry.
+        if feature == [0x05] {
+            return Some(true);
+        }
+        features = next;
+    }
+
+    Some(false)
+}
+
+/// Return the SignedCertificateTimestampList (RFC6962 section 3.3)
+/// embedded in the DER-encoded certificate `cert`, if it has one.
+pub(crate) fn embedded_sct_list(cert: &[u8]) -> Option<&[u8]> {
+    let extn_value = find_extension(cert, OID_SCT_LIST)??;
+    read_tlv(extn_value, TAG_OCTET_STRING).map(|(sct_list, _)| sct_list)
+}
+
+/// Does the DER-encoded certificate `cert` have an iPAddress entry
+/// in its subject alternative names equal to `ip_address`?
+///
+/// `ip_address` is in network byte order: four octets for IPv4, or
+/// sixteen for IPv6.  Returns None if the certificate cannot be parsed.
+pub(crate) fn has_ip_address(cert: &[u8], ip_address: &[u8]) -> Option<bool> {
+    let extn_value = match find_extension(cert, OID_SUBJECT_ALT_NAME)? {
+        Some(extn_value) => extn_value,
+        None => return Some(false),
+    };
+    let (mut names, _) = read_tlv(extn_value, der::Tag::Sequence as u8)?;
+
+    while let Some(&tag) = names.first() {
+        // GeneralName is a CHOICE, so each entry has its own tag.
+        let (name, next) = read_tlv(names, tag)?;
+        if tag == TAG_CONTEXT_7_PRIMITIVE && name == ip_address {
+            return Some(true);
+        }
+        names = next;
+    }
+
+    Some(false)
+}
+
+/// Find the extension with id `oid` in the DER-encoded certificate
+/// `cert`, returning the contents of its extnValue.
+///
+/// Returns None if the certificate cannot be parsed, and Some(None) if
+/// it has no such extension.
+fn find_extension<'a>(cert: &'a [u8], oid: &[u8]) -> Option<Option<&'a [u8]>> {
+    let mut extensions = parse_tbs_certificate(cert)?.extensions;
+
+    while !extensions.is_empty() {
+        let (extension, next) = read_tlv(extensions, der::Tag::Sequence as u8)?;
+        let (extn_id, rest) = read_tlv(extension, TAG_OID)?;
+        if extn_id == oid {
+            let rest = read_tlv(rest, TAG_BOOLEAN).map_or(rest, |(_, rest)| rest);
+            let (extn_value, _) = read_tlv(rest, TAG_OCTET_STRING)?;
+            return Some(Some(extn_value));
+        }
+        extensions = next;
+    }
+
+    Some(None)
+}
+
+/// The status of a certificate, according to an OCSP SingleResponse.
+#[derive(Debug, PartialEq)]
+pub(crate) enum OcspCertStatus {
+    Good,
+    Revoked,
+    Unknown,
+}
+
+/// The fields of a SingleResponse (RFC6960 section 4.2.1) that we need
+/// for must-staple enforcement.
+pub(crate) struct SingleResponse<'a> {
+    /// The contents of the CertID hashAlgorithm AlgorithmIdentifier.
+    hash_algorithm: &'a [u8],
+    issuer_name_hash: &'a [u8],
+    issuer_key_hash: &'a [u8],
+    serial_number: &'a [u8],
+    pub(crate) cert_status: OcspCertStatus,
+    /// The thisUpdate time, in seconds since the UNIX epoch.
+    pub(crate) this_update: u64,
+    /// The nextUpdate time, in seconds since the UNIX epoch.
+    pub(crate) next_update: Option<u64>,
+}
+
+impl<'a> SingleResponse<'a> {
+    /// Is this a response about the certificate with `serial_number`,
+    /// issued by `issuer`?
+    ///
+    /// `serial_number` is the contents of the certificate's serialNumber.
+    /// Returns false if the CertID uses a hash algorithm we do not support.
+    pub(crate) fn is_for(&self, serial_number: &[u8], issuer: &TbsCertificate) -> bool {
+        let check = || -> Option<bool> {
+            let (oid, _) = read_tlv(self.hash_algorithm, TAG_OID)?;
+            let algorithm = match oid {
+                OID_SHA1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+                OID_SHA256 => &digest::SHA256,
+                OID_SHA384 => &digest::SHA384,
+                OID_SHA512 => &digest::SHA512,
+                _ => return None,
+            };
+
+            // The name hash covers the whole DER-encoded Name.
+            let mut name = issuer.subject.to_vec();
+            wrap_in_sequence(&mut name);
+            let key = parse_spki(issuer.spki)?.public_key;
+
+            Some(
+                self.serial_number == serial_number
+                    && self.issuer_name_hash == digest::digest(algorithm, &name).as_ref()
+                    && self.issuer_key_hash == digest::digest(algorithm, key).as_ref(),
+            )
+        };
+
+        check() == Some(true)
+    }
+}
+
+/// The fields of a BasicOCSPResponse (RFC6960 section 4.2.1) that we
+/// need for must-staple enforcement.
+pub(crate) struct BasicOcspResponse<'a> {
+    /// The whole DER-encoded ResponseData, which is what is signed.
+    pub(crate) signed_data: &'a [u8],
+    /// The contents of the signatureAlgorithm AlgorithmIdentifier.
+    pub(crate) signature_algorithm: &'a [u8],
+    pub(crate) signature: &'a [u8],
+    certs: &'a [u8],
+    responses: &'a [u8],
+}
+
+impl<'a> BasicOcspResponse<'a> {
+    /// Return the DER-encoded certificates included in the response to
+    /// help verify its signature.
+    pub(crate) fn certs(&self) -> Vec<&'a [u8]> {
+        let mut certs = Vec::new();
+        let mut rest = self.certs;
+        while let Some((_, next)) = read_tlv(rest, der::Tag::Sequence as u8) {
+            certs.push(&rest[..rest.len() - next.len()]);
+            rest = next;
+        }
+        certs
+    }
+
+    /// Find the response about the certificate with `serial_number`,
+    /// issued by `issuer`.
+    ///
+    /// Returns None if there is no such response, or if a response cannot
+    /// be parsed.
+    pub(crate) fn find_response(
+        &self,
+        serial_number: &[u8],
+        issuer: &TbsCertificate,
+    ) -> Option<SingleResponse<'a>> {
+        let mut rest = self.responses;
+        while !rest.is_empty() {
+            let (response, next) = read_tlv(rest, der::Tag::Sequence as u8)?;
+            let response = parse_single_response(response)?;
+            if response.is_for(serial_number, issuer) {
+                return Some(response);
+            }
+            rest = next;
+        }
+        None
+    }
+}
+
+/// Parse the DER-encoded OCSPResponse `response`.
+///
+/// Returns None unless `response` has a successful status, and holds a
+/// basic response (RFC6960 section 4.2.1).  The response is not validated.
+pub(crate) fn parse_ocsp_response(response: &[u8]) -> Option<BasicOcspResponse<'_>> {
+    let (response, rest) = read_tlv(response, der::Tag::Sequence as u8)?;
+    if !rest.is_empty() {
+        return None;
+    }
+    let (response_status, rest) = read_tlv(response, TAG_ENUMERATED)?;
+    if response_status != [0x00] {
+        return None;
+    }
+    let (response_bytes, _) = read_tlv(rest, TAG_CONTEXT_0)?;
+    let (response_bytes, _) = read_tlv(response_bytes, der::Tag::Sequence as u8)?;
+    let (response_type, rest) = read_tlv(response_bytes, TAG_OID)?;
+    if response_type != OID_PKIX_OCSP_BASIC {
+        return None;
+    }
+    let (response, _) = read_tlv(rest, TAG_OCTET_STRING)?;
+
+    let (basic_response, _) = read_tlv(response, der::Tag::Sequence as u8)?;
+    let (tbs_response_data, rest) = read_tlv(basic_response, der::Tag::Sequence as u8)?;
+    let signed_data = &basic_response[..basic_response.len() - rest.len()];
+    let (signature_algorithm, rest) = read_tlv(rest, der::Tag::Sequence as u8)?;
+    let (signature, rest) = read_tlv(rest, TAG_BIT_STRING)?;
+    let signature = match signature.split_first() {
+        Some((0, signature)) => signature,
+        _ => return None,
+    };
+    let certs = match read_tlv(rest, TAG_CONTEXT_0) {
+        Some((certs, _)) => read_tlv(certs, der::Tag::Sequence as u8)?.0,
+        None => &[],
+    };
+
+    // The version field is optional, and if present must be v1.
+    let rest = match read_tlv(tbs_response_data, TAG_CONTEXT_0) {
+        Some((version, rest)) if version == [TAG_INTEGER, 0x01, 0x00] => rest,
+        Some(_) => return None,
+        None => tbs_response_data,
+    };
+    let (_responder_id, rest) =
+        read_tlv(rest, TAG_CONTEXT_1).or_else(|| read_tlv(rest, TAG_CONTEXT_2))?;
+    let (_produced_at, rest) = read_tlv(rest, TAG_GENERALIZED_TIME)?;
+    let (responses, _) = read_tlv(rest, der::Tag::Sequence as u8)?;
+
+    Some(BasicOcspResponse {
+        signed_data,
+        signature_algorithm,
+        signature,
+        certs,
+        responses,
+    })
+}
+
+/// Parse the contents of a SingleResponse.
+fn parse_single_response(response: &[u8]) -> Option<SingleResponse<'_>> {
+    let (cert_id, rest) = read_tlv(response, der::Tag::Sequence as u8)?;
+    let (hash_algorithm, cert_id) = read_tlv(cert_id, der::Tag::Sequence as u8)?;
+    let (issuer_name_hash, cert_id) = read_tlv(cert_id, TAG_OCTET_STRING)?;
+    let (issuer_key_hash, cert_id) = read_tlv(cert_id, TAG_OCTET_STRING)?;
+    let (serial_number, _) = read_tlv(cert_id, TAG_INTEGER)?;
+
+    // CertStatus is a CHOICE, so each alternative has its own tag.
+    let &tag = rest.first()?;
+    let (_, rest) = read_tlv(rest, tag)?;
+    let cert_status = match tag {
+        TAG_CONTEXT_0_PRIMITIVE => OcspCertStatus::Good,
+        TAG_CONTEXT_1 => OcspCertStatus::Revoked,
+        TAG_CONTEXT_2_PRIMITIVE => OcspCertStatus::Unknown,
+        _ => return None,
+    };
+    let (this_update, rest) = read_time(rest)?;
+    let next_update = match read_tlv(rest, TAG_CONTEXT_0) {
+        Some((next_update, _)) => Some(read_time(next_update)?.0),
+        None => None,
+    };
+
+    Some(SingleResponse {
+        hash_algorithm,
+        issuer_name_hash,
+        issuer_key_hash,
+        serial_number,
+        cert_status,
+        this_update,
+        next_update,
+    })
+}
+
+/// Return the issuer and subject names of the DER-encoded certificate
+/// `cert`.
+pub(crate) fn issuer_and_subject(cert: &[u8]) -> Option<(&[u8], &[u8])> {
+    parse_tbs_certificate(cert).map(|tbs| (tbs.issuer, tbs.subject))
+}
+
+/// The fields of a CertificateList (RFC5280 section 5.1) that we need
+/// for revocation checking.
+pub(crate) struct CertificateList<'a> {
+    /// The whole DER-encoded TBSCertList, which is what is signed.
+    pub(crate) signed_data: &'a [u8],
+    /// The contents of the signatureAlgorithm AlgorithmIdentifier.
+    pub(crate) signature_algorithm: &'a [u8],
+    pub(crate) signature: &'a [u8],
+    pub(crate) issuer: &'a [u8],
+    /// The nextUpdate time, in seconds since the UNIX epoch.
+    pub(crate) next_update: Option<u64>,
+    revoked_certificates: &'a [u8],
+}
+
+impl<'a> CertificateList<'a> {
+    /// Does this list include a certificate with the given serial number?
+    ///
+    /// `serial_number` is the contents of the certificate's serialNumber.
+    pub(crate) fn is_revoked(&self, serial_number: &[u8]) -> bool {
+        let mut rest = self.revoked_certificates;
+        while let Some((entry, next)) = read_tlv(rest, der::Tag::Sequence as u8) {
+            match read_tlv(entry, TAG_INTEGER) {
+                Some((user_certificate, _)) if user_certificate == serial_number => return true,
+                _ => {}
+            }
+            rest = next;
+        }
+        false
+    }
+}
+
+/// Parse the DER-encoded certificate revocation list `crl`.
+///
+/// Returns None if `crl` is not a version 1 or 2 CRL, or if it has
+/// critical extensions (we do not support any of them: for example,
+/// delta CRLs or issuing distribution points).
+pub(crate) fn parse_crl(crl: &[u8]) -> Option<CertificateList<'_>> {
+    let (certificate_list, rest) = read_tlv(crl, der::Tag::Sequence as u8)?;
+    if !rest.is_empty() {
+        return None;
+    }
+
+    let (tbs_cert_list, rest) = read_tlv(certificate_list, der::Tag::Sequence as u8)?;
+    let signed_data = &certificate_list[..certificate_list.len() - rest.len()];
+    let (signature_algorithm, rest) = read_tlv(rest, der::Tag::Sequence as u8)?;
+    let (signature, _) = read_tlv(rest, TAG_BIT_STRING)?;
+    let signature = match signature.split_first() {
+        Some((0, signature)) => signature,
+        _ => return None,
+    };
+
+    // The version field is optional, and if present must be v2.
+    let rest = match read_tlv(tbs_cert_list, TAG_INTEGER) {
+        Some((&[1], rest)) => rest,
+        Some(_) => return None,
+        None => tbs_cert_list,
+    };
+    let (_signature, rest) = read_tlv(rest, der::Tag::Sequence as u8)?;
+    let (issuer, rest) = read_tlv(rest, der::Tag::Sequence as u8)?;
+    let (_this_update, rest) = read_time(rest)?;
+    let (next_update, rest) = match read_time(rest) {
+        Some((next_update, rest)) => (Some(next_update), rest),
+        None => (None, rest),
+    };
+    let (revoked_certificates, rest) = match read_tlv(rest, der::Tag::Sequence as u8) {
+        Some((revoked_certificates, rest)) => (revoked_certificates, rest),
+        None => (&[][..], rest),
+    };
+
+    if let Some((extensions, _)) = read_tlv(rest, TAG_CONTEXT_0) {
+        let (mut extensions, _) = read_tlv(extensions, der::Tag::Sequence as u8)?;
+        while !extensions.is_empty() {
+            let (extension, next) = read_tlv(extensions, der::Tag::Sequence as u8)?;
+            let (_extn_id, rest) = read_tlv(extension, TAG_OID)?;
+            if let Some((critical, _)) = read_tlv(rest, TAG_BOOLEAN) {
+                if critical != [0x00] {
+                    return None;
+                }
+            }
+            extensions = next;
+        }
+    }
+
+    Some(CertificateList {
+        signed_data,
+        signature_algorithm,
+        signature,
+        issuer,
+        next_update,
+        revoked_certificates,
+    })
+}
+
+/// Can [`verify_signed_data`] check signatures made with
+/// `signature_algorithm`, the contents of an AlgorithmIdentifier?
+pub(crate) fn is_supported_signature_algorithm(signature_algorithm: &[u8]) -> bool {
+    matches!(
+        read_tlv(signature_algorithm, TAG_OID),
+        Some((
+            OID_SHA256_WITH_RSA
+                | OID_SHA384_WITH_RSA
+                | OID_SHA512_WITH_RSA
+                | OID_ECDSA_WITH_SHA256
+                | OID_ECDSA_WITH_SHA384
+                | OID_ED25519,
+            _
+        ))
+    )
+}
+
+/// Verify `signature` over `msg`, made with `signature_algorithm` by
+/// the key in `spki`.
+///
+/// `signature_algorithm` and `spki` are the contents of the respective
+/// AlgorithmIdentifier and SubjectPublicKeyInfo.  RSASSA-PSS signatures
+/// are not supported.
+pub(crate) fn verify_signed_data(
+    spki: &[u8],
+    signature_algorithm: &[u8],
+    msg: &[u8],
+    signature: &[u8],
+) -> bool {
+    let verify = || -> Option<()> {
+        let PublicKeyInfo {
+            key_type,
+            curve,
+            public_key,
+        } = parse_spki(spki)?;
+        let (signature_type, _) = read_tlv(signature_algorithm, TAG_OID)?;
+
+        let algorithm: &dyn signature::VerificationAlgorithm =
+            match (key_type, curve, signature_type) {
+                (OID_RSA_ENCRYPTION, _, OID_SHA256_WITH_RSA) => {
+                    &signature::RSA_PKCS1_2048_8192_SHA256
+                }
+                (OID_RSA_ENCRYPTION, _, OID_SHA384_WITH_RSA) => {
+                    &signature::RSA_PKCS1_2048_8192_SHA384
+                }
+                (OID_RSA_ENCRYPTION, _, OID_SHA512_WITH_RSA) => {
+                    &signature::RSA_PKCS1_2048_8192_SHA512
+                }
+                (OID_EC_PUBLIC_KEY, Some(OID_SECP256R1), OID_ECDSA_WITH_SHA256) => {
+                    &signature::ECDSA_P256_SHA256_ASN1
+                }
+                (OID_EC_PUBLIC_KEY, Some(OID_SECP256R1), OID_ECDSA_WITH_SHA384) => {
+                    &signature::ECDSA_P256_SHA384_ASN1
+                }
+                (OID_EC_PUBLIC_KEY, Some(OID_SECP384R1), OID_ECDSA_WITH_SHA256) => {
+                    &signature::ECDSA_P384_SHA256_ASN1
+                }
+                (OID_EC_PUBLIC_KEY, Some(OID_SECP384R1), OID_ECDSA_WITH_SHA384) => {
+                    &signature::ECDSA_P384_SHA384_ASN1
+                }
+                (OID_ED25519, _, OID_ED25519) => &signature::ED25519,
+                _ => return None,
+            };
+
+        signature::UnparsedPublicKey::new(algorithm, public_key)
+            .verify(msg, signature)
+            .ok()
+    };
+
+    verify().is_some()
+}
+
+/// Verify a TLS1.3 `signature` over `msg`, made using `scheme` by the
+/// key in `spki`.
+///
+/// `spki` is a whole DER-encoded SubjectPublicKeyInfo, as used for
+/// RFC7250 raw public keys.  Returns None if `spki` cannot be parsed,
+/// or if `scheme` is not a TLS1.3 scheme usable with its key.
+pub(crate) fn verify_tls13_signature_with_spki(
+    spki: &[u8],
+    scheme: SignatureScheme,
+    msg: &[u8],
+    signature: &[u8],
+) -> Option<bool> {
+    let (spki, rest) = read_tlv(spki, der::Tag::Sequence as u8)?;
+    if !rest.is_empty() {
+        return None;
+    }
+    let PublicKeyInfo {
+        key_type,
+        curve,
+        public_key,
+    } = parse_spki(spki)?;
+
+    let algorithm: &dyn signature::VerificationAlgorithm = match (key_type, curve, scheme) {
+        (OID_EC_PUBLIC_KEY, Some(OID_SECP256R1), SignatureScheme::ECDSA_NISTP256_SHA256) => {
+            &signature::ECDSA_P256_SHA256_ASN1
+        }
+        (OID_EC_PUBLIC_KEY, Some(OID_SECP384R1), SignatureScheme::ECDSA_NISTP384_SHA384) => {
+            &signature::ECDSA_P384_SHA384_ASN1
+        }
+        (OID_ED25519, _, SignatureScheme::ED25519) => &signature::ED25519,
+        (OID_RSA_ENCRYPTION, _, SignatureScheme::RSA_PSS_SHA256) => {
+            &signature::RSA_PSS_2048_8192_SHA256
+        }
+        (OID_RSA_ENCRYPTION, _, SignatureScheme::RSA_PSS_SHA384) => {
+            &signature::RSA_PSS_2048_8192_SHA384
+        }
+        (OID_RSA_ENCRYPTION, _, SignatureScheme::RSA_PSS_SHA512) => {
+            &signature::RSA_PSS_2048_8192_SHA512
+        }
+        _ => return None,
+    };
+
+    Some(
+        signature::UnparsedPublicKey::new(algorithm, public_key)
+            .verify(msg, signature)
+            .is_ok(),
+    )
+}
+
+/// The parts of a SubjectPublicKeyInfo we use to verify signatures.
+struct PublicKeyInfo<'a> {
+    key_type: &'a [u8],
+    curve: Option<&'a [u8]>,
+    public_key: &'a [u8],
+}
+
+/// Split the contents of a SubjectPublicKeyInfo into its key type, its
+/// named curve (if any), and the public key itself.
+fn parse_spki(spki: &[u8]) -> Option<PublicKeyInfo<'_>> {
+    let (key_algorithm, rest) = read_tlv(spki, der::Tag::Sequence as u8)?;
+    let (key_type, key_params) = read_tlv(key_algorithm, TAG_OID)?;
+    let (public_key, _) = read_tlv(rest, TAG_BIT_STRING)?;
+    let public_key = match public_key.split_first() {
+        Some((0, public_key)) => public_key,
+        _ => return None,
+    };
+    let curve = read_tlv(key_params, TAG_OID).map(|(curve, _)| curve);
+    Some(PublicKeyInfo {
+        key_type,
+        curve,
+        public_key,
+    })
+}
+
+/// Read a UTCTime or GeneralizedTime from the front of `input`,
+/// returning it in seconds since the UNIX epoch.
+fn read_time(input: &[u8]) -> Option<(u64, &[u8])> {
+    let (time, rest, year) = match read_tlv(input, TAG_UTC_TIME) {
+        Some((time, rest)) if time.len() == 13 => {
+            let year = match read_digits(&time[..2])? {
+                year @ 0..=49 => 2000 + year,
+                year => 1900 + year,
+            };
+            (&time[2..], rest, year)
+        }
+        Some(_) => return None,
+        None => match read_tlv(input, TAG_GENERALIZED_TIME)? {
+            (time, rest) if time.len() == 15 => (&time[4..], rest, read_digits(&time[..4])?),
+            _ => return None,
+        },
+    };
+
+    if time[10] != b'Z' {
+        return None;
+    }
+    let month = read_digits(&time[0..2])?;
+    let day = read_digits(&time[2..4])?;
+    let hours = read_digits(&time[4..6])?;
+    let minutes = read_digits(&time[6..8])?;
+    let seconds = read_digits(&time[8..10])?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hours > 23 || minutes > 59 {
+        return None;
+    }
+
+    // Days since the epoch of the given civil date; see
+    // <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146_097 + day_of_era).checked_sub(719_468)?;
+
+    Some((((days * 24 + hours) * 60 + minutes) * 60 + seconds, rest))
+}
+
+fn read_digits(digits: &[u8]) -> Option<u64> {
+    digits
+        .iter()
+        .try_fold(0, |acc, d| match d {
+            b'0'..=b'9' => Some(acc * 10 + u64::from(d - b'0')),
+            _ => None,
+        })
+}
+
+/// Find the hash algorithm in RSASSA-PSS-params (RFC4055 section 3.1).
+fn pss_hash(params: &[u8]) -> Option<&'static digest::Algorithm> {
+    let (params, _) = read_tlv(params, der::Tag::Sequence as u8)?;
+
+    // The hashAlgorithm field is optional, and defaults to SHA-1.
+    let hash_algorithm = match read_tlv(params, TAG_CONTEXT_0) {
+        Some((hash_algorithm, _)) => hash_algorithm,
+        None => return Some(&digest::SHA256),
+    };
+    let (hash_algorithm, _) = read_tlv(hash_algorithm, der::Tag::Sequence as u8)?;
+    let (oid, _) = read_tlv(hash_algorithm, TAG_OID)?;
+
+    match oid {
+        OID_SHA1 | OID_SHA256 => Some(&digest::SHA256),
+        OID_SHA384 => Some(&digest::SHA384),
+        OID_SHA512 => Some(&digest::SHA512),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_server_end_point_hash() {
+    let ecdsa_sha384 = include_bytes!("testdata/cert-wikipedia.0.der");
+    assert_eq!(server_end_point_hash(ecdsa_sha384), Some(&digest::SHA384));
+    assert_eq!(
+        digest::digest(server_end_point_hash(ecdsa_sha384).unwrap(), ecdsa_sha384).as_ref(),
+        &[
+            0x03, 0x5c, 0x84, 0xb3, 0x17, 0x0e, 0x73, 0x2b, 0x54, 0x53, 0x7a, 0x75, 0x3e, 0x7b,
+            0xee, 0x53, 0x6f, 0x45, 0xe6, 0xac, 0x8e, 0xfe, 0x53, 0xed, 0x07, 0x79, 0x12, 0xd3,
+            0xa0, 0x46, 0xa9, 0x44, 0x8a, 0xb3, 0xb6, 0xc0, 0x16, 0x6b, 0x48, 0xc6, 0xe8, 0x3e,
+            0x92, 0x59, 0x5a, 0x1f, 0xf3, 0xf4
+        ][..]
+    );
+
+    assert_eq!(
+        server_end_point_hash(include_bytes!("testdata/cert-wikipedia.1.der")),
+        Some(&digest::SHA384)
+    );
+    assert_eq!(
+        server_end_point_hash(include_bytes!("testdata/cert-github.0.der")),
+        Some(&digest::SHA256)
+    );
+    assert_eq!(
+        server_end_point_hash(include_bytes!("testdata/cert-google.0.der")),
+        Some(&digest::SHA256)
+    );
+    assert_eq!(server_end_point_hash(&[0x30, 0x00]), None);
+}
+
+#[test]
+fn test_has_weak_signature_algorithm() {
+    assert_eq!(
+        has_weak_signature_algorithm(include_bytes!("testdata/cert-wikipedia.0.der")),
+        Some(false)
+    );
+    assert_eq!(
+        has_weak_signature_algorithm(include_bytes!("testdata/cert-github.0.der")),
+        Some(false)
+    );
+    assert_eq!(has_weak_signature_algorithm(&[0x30, 0x00]), None);
+}
+
+#[test]
+fn test_read_time() {
+    assert_eq!(read_time(b"\x17\x0d700101000000Z"), Some((0, &b""[..])));
+    assert_eq!(
+        read_time(b"\x17\x0d190609171512Zrest"),
+        Some((1_560_100_512, &b"rest"[..]))
+    );
+    assert_eq!(
+        read_time(b"\x17\x0d491231235959Z"),
+        Some((2_524_607_999, &b""[..]))
+    );
+    assert_eq!(
+        read_time(b"\x18\x0f20500101000000Z"),
+        Some((2_524_608_000, &b""[..]))
+    );
+    assert_eq!(read_time(b"\x17\x0d190609171512+"), None);
+    assert_eq!(read_time(b"\x17\x0d191309171512Z"), None);
+    assert_eq!(read_time(b"\x18\x0d190609171512Z"), None);
+}
+
+#[test]
+fn test_parse_ocsp_response() {
+    // tryLater status, and a successful status with no BasicOCSPResponse.
+    assert!(parse_ocsp_response(&[0x30, 0x03, 0x0a, 0x01, 0x03]).is_none());
+    assert!(parse_ocsp_response(&[
+        0x30, 0x14, 0x0a, 0x01, 0x00, 0xa0, 0x0f, 0x30, 0x0d, 0x06, 0x09, 0x2b, 0x06, 0x01, 0x05,
+        0x05, 0x07, 0x30, 0x01, 0x01, 0x04, 0x00,
+    ])
+    .is_none());
+
+    let response = parse_ocsp_response(include_bytes!(
+        "../../test-ca/rsa/muststaple.revoked.ocsp.der"
+    ))
+    .unwrap();
+    // openssl includes the signer's certificate, here the issuer itself.
+    assert_eq!(response.certs().len(), 1);
+    assert_eq!(has_ocsp_signing_usage(response.certs()[0]), Some(false));
+    let single = parse_single_response(
+        read_tlv(response.responses, der::Tag::Sequence as u8)
+            .unwrap()
+            .0,
+    )
+    .unwrap();
+    assert_eq!(single.serial_number, [0x01, 0xc9]);
+    assert_eq!(single.cert_status, OcspCertStatus::Revoked);
+    assert!(single.this_update < single.next_update.unwrap());
+
+    let response = parse_ocsp_response(include_bytes!(
+        "../../test-ca/rsa/muststaple.delegated.ocsp.der"
+    ))
+    .unwrap();
+    assert_eq!(response.certs().len(), 1);
+    assert_eq!(has_ocsp_signing_usage(response.certs()[0]), Some(true));
+}
+
 #[test]
 fn test_empty() {
     let mut val = Vec::new();
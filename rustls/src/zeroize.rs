@@ -0,0 +1,80 @@
+//! Wiping secrets from memory once we're done with them.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{self, Ordering};
+
+/// Overwrite `buf` with zeroes.
+///
+/// This crate has no unsafe code, so we can't use volatile writes.
+/// Instead the compiler fence stops the writes being removed as dead
+/// stores before `buf` is freed.  This is best-effort: copies the
+/// compiler made in registers or elsewhere on the stack are untouched.
+pub(crate) fn zeroize(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        *byte = 0;
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+/// A buffer of secret bytes, which is zeroized when dropped.
+///
+/// This only gives access to the contents as a slice, so a
+/// `Zeroizing<Vec<u8>>` can never reallocate and leave a copy of its
+/// contents behind: make the `Vec` at its final length.
+pub(crate) struct Zeroizing<T: AsMut<[u8]>>(T);
+
+impl<T: AsMut<[u8]>> Zeroizing<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        Self(inner)
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Deref for Zeroizing<T> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> DerefMut for Zeroizing<T> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.0.as_mut()
+    }
+}
+
+impl<T: AsMut<[u8]>> Drop for Zeroizing<T> {
+    fn drop(&mut self) {
+        zeroize(self.0.as_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::Iv;
+    use crate::crypto::Output;
+    use std::mem;
+
+    #[test]
+    fn test_zeroize() {
+        let mut buf = [0xa5u8; 48];
+        zeroize(&mut buf[..]);
+        assert_eq!(buf, [0u8; 48]);
+    }
+
+    #[test]
+    fn test_secret_holders_have_drop_glue() {
+        // These are plain bytes, so the only drop glue is our zeroizing.
+        assert!(mem::needs_drop::<Zeroizing<[u8; 48]>>());
+        assert!(mem::needs_drop::<Output>());
+        assert!(mem::needs_drop::<Iv>());
+    }
+
+    #[test]
+    fn test_zeroizing_vec_derefs_to_contents() {
+        let mut secret = Zeroizing::new(vec![0u8; 4]);
+        secret[1] = 1;
+        assert_eq!(&secret[..], &[0, 1, 0, 0]);
+    }
+}
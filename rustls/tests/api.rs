@@ -1,23 +1,32 @@
 //! Assorted public API tests.
+#![cfg_attr(read_buf, feature(read_buf, core_io_borrowed_buf, borrowed_buf_init))]
 use std::convert::TryFrom;
 #[cfg(feature = "tls12")]
 use std::convert::TryInto;
 use std::fmt;
-use std::io::{self, IoSlice, Read, Write};
+use std::io::{self, BufRead, IoSlice, Read, Write};
 use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
 use rustls::client::ResolvesClientCert;
+use rustls::compress::{self, CertCompressionAlgorithm};
+use rustls::internal::msgs::enums::{AlertDescription, SignatureAlgorithm};
 #[cfg(feature = "quic")]
 use rustls::quic::{self, ClientQuicExt, QuicExt, ServerQuicExt};
-use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert};
-use rustls::{sign, ConnectionCommon, Error, KeyLog, SideData};
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+    ClientHelloDecision, CrlExpiryPolicy, DetectsEarlyDataReplay, InspectsClientHello,
+    ResolvesServerCert,
+};
+use rustls::{sign, CallbackKeyLog, ChannelBindingKind, ConnectionCommon, Error, KeyLog, SideData};
+use rustls::{Certificate, RawPublicKeyVerified, RawPublicKeyVerifier, RootCertStore};
 use rustls::{CipherSuite, ProtocolVersion, SignatureScheme};
 use rustls::{ClientConfig, ClientConnection};
-use rustls::{ServerConfig, ServerConnection};
+use rustls::{ServerConfig, ServerConnection, ServerName};
 use rustls::{Stream, StreamOwned};
 use rustls::{SupportedCipherSuite, ALL_CIPHER_SUITES};
 
@@ -27,11 +36,13 @@ use crate::common::*;
 fn alpn_test_error(
     server_protos: Vec<Vec<u8>>,
     client_protos: Vec<Vec<u8>>,
+    alpn_required: bool,
     agreed: Option<&[u8]>,
     expected_error: Option<ErrorFromPeer>,
 ) {
     let mut server_config = make_server_config(KeyType::Rsa);
     server_config.alpn_protocols = server_protos;
+    server_config.alpn_required = alpn_required;
 
     let server_config = Arc::new(server_config);
 
@@ -52,7 +63,7 @@ fn alpn_test_error(
 }
 
 fn alpn_test(server_protos: Vec<Vec<u8>>, client_protos: Vec<Vec<u8>>, agreed: Option<&[u8]>) {
-    alpn_test_error(server_protos, client_protos, agreed, None)
+    alpn_test_error(server_protos, client_protos, false, agreed, None)
 }
 
 #[test]
@@ -67,11 +78,10 @@ fn alpn() {
     alpn_test(vec![], vec![b"client-proto".to_vec()], None);
 
     // no overlap
-    alpn_test_error(
+    alpn_test(
         vec![b"server-proto".to_vec()],
         vec![b"client-proto".to_vec()],
         None,
-        Some(ErrorFromPeer::Server(Error::NoApplicationProtocol)),
     );
 
     // server chooses preference
@@ -81,15 +91,102 @@ fn alpn() {
         Some(b"server-proto"),
     );
 
+    // case sensitive
+    alpn_test(vec![b"PROTO".to_vec()], vec![b"proto".to_vec()], None);
+}
+
+#[test]
+fn alpn_h2_is_available_when_handshake_completes() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.alpn_protocols = vec![b"h2".to_vec()];
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(client.get_alpn_protocol_owned(), None);
+        assert_eq!(server.get_alpn_protocol_owned(), None);
+
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer(&mut client, &mut server);
+            server.process_new_packets().unwrap();
+            if !server.is_handshaking() {
+                assert_eq!(server.get_alpn_protocol_owned(), Some(b"h2".to_vec()));
+            }
+
+            transfer(&mut server, &mut client);
+            client.process_new_packets().unwrap();
+            if !client.is_handshaking() {
+                assert_eq!(client.get_alpn_protocol_owned(), Some(b"h2".to_vec()));
+            }
+        }
+
+        assert_eq!(client.alpn_protocol(), Some(&b"h2"[..]));
+        assert_eq!(server.alpn_protocol(), Some(&b"h2"[..]));
+    }
+}
+
+#[test]
+fn alpn_required() {
+    // overlap
+    alpn_test_error(
+        vec![b"server-proto".to_vec(), b"client-proto".to_vec()],
+        vec![b"client-proto".to_vec()],
+        true,
+        Some(b"client-proto"),
+        None,
+    );
+
+    // client support
+    alpn_test_error(vec![], vec![b"client-proto".to_vec()], true, None, None);
+
+    // no overlap
+    alpn_test_error(
+        vec![b"server-proto".to_vec()],
+        vec![b"client-proto".to_vec()],
+        true,
+        None,
+        Some(ErrorFromPeer::Server(Error::NoApplicationProtocol)),
+    );
+
     // case sensitive
     alpn_test_error(
         vec![b"PROTO".to_vec()],
         vec![b"proto".to_vec()],
+        true,
         None,
         Some(ErrorFromPeer::Server(Error::NoApplicationProtocol)),
     );
 }
 
+#[test]
+fn alpn_required_sends_no_application_protocol_alert() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.alpn_protocols = vec![b"server-proto".to_vec()];
+    server_config.alpn_required = true;
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.alpn_protocols = vec![b"client-proto".to_vec()];
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Server(Error::NoApplicationProtocol),
+                ErrorFromPeer::Client(Error::AlertReceived(
+                    rustls::AlertDescription::NoApplicationProtocol
+                )),
+            ])
+        );
+    }
+}
+
 fn version_test(
     client_versions: &[&'static rustls::SupportedProtocolVersion],
     server_versions: &[&'static rustls::SupportedProtocolVersion],
@@ -174,6 +271,30 @@ fn versions() {
     );
 }
 
+#[test]
+fn get_protocol_version_is_available_after_server_hello() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        assert_eq!(client.get_protocol_version(), None);
+        assert_eq!(server.get_protocol_version(), None);
+
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        assert_eq!(client.get_protocol_version(), None);
+        assert_eq!(server.get_protocol_version(), Some(version.version));
+
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        assert_eq!(client.get_protocol_version(), Some(version.version));
+
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.get_protocol_version(), Some(version.version));
+        assert_eq!(server.get_protocol_version(), Some(version.version));
+    }
+}
+
 fn check_read(reader: &mut dyn io::Read, bytes: &[u8]) {
     let mut buf = vec![0u8; bytes.len() + 1];
     assert_eq!(bytes.len(), reader.read(&mut buf).unwrap());
@@ -254,6 +375,50 @@ fn config_builder_for_server_rejects_incompatible_cipher_suites() {
     );
 }
 
+#[test]
+fn config_builder_for_server_accepts_matching_key() {
+    for kt in ALL_KEY_TYPES.iter() {
+        assert!(ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(kt.get_chain(), kt.get_key())
+            .is_ok());
+    }
+}
+
+#[test]
+fn config_builder_for_server_rejects_mismatched_key() {
+    for kt in ALL_KEY_TYPES.iter() {
+        assert_eq!(
+            ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(kt.get_chain(), kt.get_client_key())
+                .err(),
+            Some(Error::General(
+                "Private key does not match end-entity certificate".into()
+            ))
+        );
+    }
+}
+
+#[test]
+fn config_builder_for_client_rejects_key_of_wrong_type() {
+    assert_eq!(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(get_client_root_store(KeyType::Rsa))
+            .with_single_cert(
+                KeyType::Rsa.get_client_chain(),
+                KeyType::Ecdsa.get_client_key()
+            )
+            .err(),
+        Some(Error::General(
+            "Private key is ECDSA but end-entity certificate has an RSA public key".into()
+        ))
+    );
+}
+
 #[test]
 fn buffered_client_data_sent() {
     let server_config = Arc::new(make_server_config(KeyType::Rsa));
@@ -366,6 +531,79 @@ fn client_can_get_server_cert_after_resumption() {
     }
 }
 
+#[test]
+fn client_can_get_verified_cert_chain() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, make_server_config(*kt));
+            assert_eq!(client.get_verified_cert_chain(), None);
+            do_handshake(&mut client, &mut server);
+
+            // The server sends end, intermediate and root: the root is
+            // our trust anchor so is not part of the verified chain.
+            let chain = kt.get_chain();
+            assert_eq!(client.get_verified_cert_chain(), Some(&chain[..2]));
+            assert_eq!(server.get_verified_cert_chain(), None);
+        }
+    }
+}
+
+#[test]
+fn verified_cert_chain_omits_unused_certs() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let chain = kt.get_chain();
+        let other_kt = match kt {
+            KeyType::Rsa => KeyType::Ecdsa,
+            _ => KeyType::Rsa,
+        };
+        let sent_chain = vec![
+            chain[0].clone(),
+            chain[2].clone(),
+            other_kt.get_chain()[1].clone(),
+            chain[1].clone(),
+        ];
+
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(sent_chain.clone(), kt.get_key())
+            .unwrap();
+
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, server_config.clone());
+            do_handshake(&mut client, &mut server);
+
+            assert_eq!(client.peer_certificates(), Some(&sent_chain[..]));
+            assert_eq!(client.get_verified_cert_chain(), Some(&chain[..2]));
+        }
+    }
+}
+
+#[test]
+fn verified_cert_chain_is_not_available_after_resumption() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = make_server_config(*kt);
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config.clone(), server_config.clone());
+            do_handshake(&mut client, &mut server);
+            assert!(client
+                .get_verified_cert_chain()
+                .is_some());
+
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config.clone(), server_config.clone());
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.get_verified_cert_chain(), None);
+        }
+    }
+}
+
 #[test]
 fn server_can_get_client_cert() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -440,6 +678,107 @@ fn server_allow_any_anonymous_or_authenticated_client() {
     }
 }
 
+fn make_pair_for_post_handshake_auth(
+    kt: KeyType,
+    client_config: ClientConfig,
+) -> (ClientConnection, ServerConnection) {
+    let mut server_config = make_server_config_with_mandatory_client_auth(kt);
+    server_config.defer_client_auth = true;
+    let mut client_config = client_config;
+    client_config.enable_post_handshake_auth = true;
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    (client, server)
+}
+
+#[test]
+fn server_can_request_client_cert_after_handshake() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config =
+            make_client_config_with_versions_with_auth(*kt, &[&rustls::version::TLS13]);
+        let (mut client, mut server) = make_pair_for_post_handshake_auth(*kt, client_config);
+        assert_eq!(server.peer_certificates(), None);
+
+        server
+            .request_client_authentication()
+            .unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+
+        let certs = server.peer_certificates();
+        assert_eq!(certs, Some(kt.get_client_chain().as_slice()));
+
+        // application data still flows afterwards
+        client
+            .writer()
+            .write_all(b"hello")
+            .unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), b"hello");
+    }
+}
+
+#[test]
+fn server_rejects_missing_client_cert_after_handshake() {
+    let kt = KeyType::Rsa;
+    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    let (mut client, mut server) = make_pair_for_post_handshake_auth(kt, client_config);
+
+    server
+        .request_client_authentication()
+        .unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::NoCertificatesPresented)
+    );
+}
+
+#[test]
+fn server_cannot_request_client_cert_after_handshake_without_client_offer() {
+    let kt = KeyType::Rsa;
+    let mut server_config = make_server_config_with_mandatory_client_auth(kt);
+    server_config.defer_client_auth = true;
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions_with_auth(kt, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(
+            server.request_client_authentication(),
+            Err(Error::HandshakeNotComplete)
+        );
+
+        do_handshake(&mut client, &mut server);
+        assert!(matches!(
+            server.request_client_authentication(),
+            Err(Error::PeerIncompatibleError(_))
+        ));
+    }
+
+    // A TLS1.2 client's offer is meaningless.
+    #[cfg(feature = "tls12")]
+    {
+        let mut client_config =
+            make_client_config_with_versions_with_auth(kt, &[&rustls::version::TLS12]);
+        client_config.enable_post_handshake_auth = true;
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(matches!(
+            server.request_client_authentication(),
+            Err(Error::PeerIncompatibleError(_))
+        ));
+    }
+}
+
 fn check_read_and_close(reader: &mut dyn io::Read, expect: &[u8]) {
     check_read(reader, expect);
     assert!(matches!(reader.read(&mut [0u8; 5]), Ok(0)));
@@ -523,6 +862,75 @@ fn client_close_notify() {
     }
 }
 
+#[test]
+fn half_closed_connection_still_receives_data() {
+    let kt = KeyType::Rsa;
+    let server_config = Arc::new(make_server_config(kt));
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+
+        // client closes its direction; repeating that changes nothing
+        client.send_close_notify();
+        let sent = transfer(&mut client, &mut server);
+        client.send_close_notify();
+        assert!(!client.wants_write());
+        assert!(sent > 0);
+
+        let io_state = server.process_new_packets().unwrap();
+        assert!(io_state.peer_has_closed());
+        assert!(server.peer_has_closed());
+        assert!(!client.peer_has_closed());
+
+        // the server can still send, and the client still receives
+        server
+            .writer()
+            .write_all(b"from-server!")
+            .unwrap();
+        transfer(&mut server, &mut client);
+        let io_state = client.process_new_packets().unwrap();
+        assert!(!io_state.peer_has_closed());
+        assert!(!client.peer_has_closed());
+        check_read(&mut client.reader(), b"from-server!");
+
+        // then the server closes too
+        server.send_close_notify();
+        transfer(&mut server, &mut client);
+        let io_state = client.process_new_packets().unwrap();
+        assert!(io_state.peer_has_closed());
+        assert!(client.peer_has_closed());
+        check_read_and_close(&mut client.reader(), b"");
+    }
+}
+
+#[test]
+fn peer_has_closed_is_reported_with_pending_data() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    server
+        .writer()
+        .write_all(b"last words")
+        .unwrap();
+    server.send_close_notify();
+    transfer(&mut server, &mut client);
+
+    let io_state = client.process_new_packets().unwrap();
+    assert!(io_state.peer_has_closed());
+    assert_eq!(io_state.plaintext_bytes_to_read(), 10);
+
+    // still reported by later calls, before and after reading
+    let io_state = client.process_new_packets().unwrap();
+    assert!(io_state.peer_has_closed());
+    check_read_and_close(&mut client.reader(), b"last words");
+    let io_state = client.process_new_packets().unwrap();
+    assert!(io_state.peer_has_closed());
+    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
+}
+
 #[test]
 fn server_closes_uncleanly() {
     let kt = KeyType::Rsa;
@@ -613,6 +1021,7 @@ fn client_closes_uncleanly() {
 struct ServerCheckCertResolve {
     expected_sni: Option<String>,
     expected_sigalgs: Option<Vec<SignatureScheme>>,
+    expected_cert_sigalgs: Option<Vec<SignatureScheme>>,
     expected_alpn: Option<Vec<Vec<u8>>>,
 }
 
@@ -642,7 +1051,14 @@ impl ResolvesServerCert for ServerCheckCertResolve {
             }
         }
 
-        if let Some(expected_alpn) = &self.expected_alpn {
+        if let Some(expected_cert_sigalgs) = &self.expected_cert_sigalgs {
+            assert_eq!(
+                expected_cert_sigalgs,
+                client_hello.certificate_signature_schemes()
+            );
+        }
+
+        if let Some(expected_alpn) = &self.expected_alpn {
             let alpn = client_hello
                 .alpn()
                 .expect("alpn unexpectedly absent")
@@ -811,2931 +1227,8261 @@ fn client_with_sni_disabled_does_not_send_sni() {
 }
 
 #[test]
-fn client_checks_server_certificate_with_given_name() {
+fn client_with_sni_disabled_still_verifies_given_name() {
     for kt in ALL_KEY_TYPES.iter() {
         let server_config = Arc::new(make_server_config(*kt));
 
         for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions(*kt, &[version]);
-            let mut client = ClientConnection::new(
-                Arc::new(client_config),
-                dns_name("not-the-right-hostname.com"),
-            )
-            .unwrap();
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.enable_sni = false;
+            let client_config = Arc::new(client_config);
+
+            let mut client =
+                ClientConnection::new(Arc::clone(&client_config), dns_name("testserver.com"))
+                    .unwrap();
             let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+            do_handshake(&mut client, &mut server);
+            assert_eq!(server.sni_hostname(), None);
 
+            let mut client =
+                ClientConnection::new(client_config, dns_name("not-the-right-hostname.com"))
+                    .unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
             let err = do_handshake_until_error(&mut client, &mut server);
-            assert_eq!(
-                err,
-                Err(ErrorFromPeer::Client(Error::InvalidCertificateData(
-                    "invalid peer certificate: CertNotValidForName".into(),
-                )))
-            );
+            assert_eq!(err, Err(ErrorFromPeer::Client(Error::CertNotValidForName)));
+            assert_eq!(server.sni_hostname(), None);
         }
     }
 }
 
-struct ClientCheckCertResolve {
-    query_count: AtomicUsize,
-    expect_queries: usize,
+/// Rejects ClientHellos for one server name, logging the rest.
+struct RejectServerName {
+    rejected: &'static str,
+    seen: Mutex<Vec<(Option<String>, Vec<CipherSuite>)>>,
 }
 
-impl ClientCheckCertResolve {
-    fn new(expect_queries: usize) -> Self {
-        ClientCheckCertResolve {
-            query_count: AtomicUsize::new(0),
-            expect_queries,
-        }
-    }
-}
+impl InspectsClientHello for RejectServerName {
+    fn on_client_hello(&self, client_hello: &ClientHello) -> ClientHelloDecision {
+        let server_name = client_hello.server_name();
+        self.seen.lock().unwrap().push((
+            server_name.map(str::to_string),
+            client_hello.cipher_suites().to_vec(),
+        ));
 
-impl Drop for ClientCheckCertResolve {
-    fn drop(&mut self) {
-        if !std::thread::panicking() {
-            let count = self.query_count.load(Ordering::SeqCst);
-            assert_eq!(count, self.expect_queries);
+        if server_name == Some(self.rejected) {
+            ClientHelloDecision::Reject(rustls::AlertDescription::UnrecognisedName)
+        } else {
+            ClientHelloDecision::Continue
         }
     }
 }
 
-impl ResolvesClientCert for ClientCheckCertResolve {
-    fn resolve(
-        &self,
-        acceptable_issuers: &[&[u8]],
-        sigschemes: &[SignatureScheme],
-    ) -> Option<Arc<sign::CertifiedKey>> {
-        self.query_count
-            .fetch_add(1, Ordering::SeqCst);
+#[test]
+fn server_client_hello_inspector_rejects_server_name() {
+    let inspector = Arc::new(RejectServerName {
+        rejected: "unwanted.example.com",
+        seen: Mutex::new(Vec::new()),
+    });
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.client_hello_inspector = Some(inspector.clone());
+    let server_config = Arc::new(server_config);
+    let client_config = Arc::new(make_client_config(KeyType::Rsa));
 
-        if acceptable_issuers.is_empty() {
-            panic!("no issuers offered by server");
-        }
+    let mut client =
+        ClientConnection::new(Arc::clone(&client_config), dns_name("unwanted.example.com"))
+            .unwrap();
+    let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    assert_eq!(
+        do_handshake_until_both_error(&mut client, &mut server),
+        Err(vec![
+            ErrorFromPeer::Server(Error::General(
+                "ClientHello rejected by client_hello_inspector".into()
+            )),
+            ErrorFromPeer::Client(Error::AlertReceived(
+                rustls::AlertDescription::UnrecognisedName
+            )),
+        ])
+    );
 
-        if sigschemes.is_empty() {
-            panic!("no signature schemes shared by server");
-        }
+    let mut client = ClientConnection::new(client_config, dns_name("localhost")).unwrap();
+    let mut server = ServerConnection::new(server_config).unwrap();
+    do_handshake(&mut client, &mut server);
 
-        None
-    }
+    let seen = inspector.seen.lock().unwrap();
+    assert_eq!(seen.len(), 2);
+    assert_eq!(seen[0].0.as_deref(), Some("unwanted.example.com"));
+    assert_eq!(seen[1].0.as_deref(), Some("localhost"));
+    let offered = rustls::DEFAULT_CIPHER_SUITES
+        .iter()
+        .map(|suite| suite.suite())
+        .collect::<Vec<_>>();
+    assert!(offered
+        .iter()
+        .all(|suite| seen[1].1.contains(suite)));
+}
 
-    fn has_certs(&self) -> bool {
-        true
+/// Logs the ALPN protocols offered in each ClientHello.
+struct RecordAlpn {
+    seen: Mutex<Vec<Option<Vec<Vec<u8>>>>>,
+}
+
+impl InspectsClientHello for RecordAlpn {
+    fn on_client_hello(&self, client_hello: &ClientHello) -> ClientHelloDecision {
+        self.seen.lock().unwrap().push(
+            client_hello
+                .alpn()
+                .map(|protocols| protocols.map(|p| p.to_vec()).collect()),
+        );
+        ClientHelloDecision::Continue
     }
 }
 
 #[test]
-fn client_cert_resolve() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
+fn server_client_hello_inspector_sees_offered_alpn_protocols() {
+    let inspector = Arc::new(RecordAlpn {
+        seen: Mutex::new(Vec::new()),
+    });
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.alpn_protocols = vec![b"server-proto".to_vec()];
+    server_config.alpn_required = true;
+    server_config.client_hello_inspector = Some(inspector.clone());
+    let server_config = Arc::new(server_config);
 
-        for version in rustls::ALL_VERSIONS {
-            let mut client_config = make_client_config_with_versions(*kt, &[version]);
-            client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(1));
+    let client_config = Arc::new(make_client_config(KeyType::Rsa));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
 
-            let (mut client, mut server) =
-                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.alpn_protocols = vec![b"foo".to_vec(), b"bar".to_vec()];
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::NoApplicationProtocol))
+    );
 
-            assert_eq!(
-                do_handshake_until_error(&mut client, &mut server),
-                Err(ErrorFromPeer::Server(Error::NoCertificatesPresented))
-            );
-        }
+    let seen = inspector.seen.lock().unwrap();
+    assert_eq!(
+        *seen,
+        vec![None, Some(vec![b"foo".to_vec(), b"bar".to_vec()])]
+    );
+}
+
+struct SelectAlpnAndCert(ClientHelloDecision);
+
+impl InspectsClientHello for SelectAlpnAndCert {
+    fn on_client_hello(&self, _client_hello: &ClientHello) -> ClientHelloDecision {
+        self.0.clone()
     }
 }
 
 #[test]
-fn client_auth_works() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
+fn server_client_hello_inspector_selects_alpn_and_cert() {
+    let kt = KeyType::Rsa;
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let certified_key = sign::CertifiedKey::new(kt.get_chain(), Arc::new(signing_key));
+
+    for (alpn_protocol, expect_err) in [
+        (b"bar".to_vec(), None),
+        (
+            b"baz".to_vec(),
+            Some("selected ALPN protocol was not offered by client"),
+        ),
+    ]
+    .iter()
+    {
+        let mut client_config = make_client_config(kt);
+        client_config.alpn_protocols = vec![b"foo".to_vec(), b"bar".to_vec()];
 
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
-            let (mut client, mut server) =
-                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-            do_handshake(&mut client, &mut server);
+        let mut server_config = make_server_config(kt);
+        server_config.alpn_protocols = vec![b"foo".to_vec()];
+        // panics if consulted, since the client sends SNI
+        server_config.cert_resolver = Arc::new(ServerCheckNoSNI {});
+        server_config.client_hello_inspector =
+            Some(Arc::new(SelectAlpnAndCert(ClientHelloDecision::Select {
+                alpn_protocol: Some(alpn_protocol.clone()),
+                certified_key: Some(Arc::new(certified_key.clone())),
+            })));
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        match expect_err {
+            None => {
+                assert_eq!(do_handshake_until_error(&mut client, &mut server), Ok(()));
+                assert_eq!(client.alpn_protocol(), Some(&b"bar"[..]));
+                assert_eq!(server.alpn_protocol(), Some(&b"bar"[..]));
+            }
+            Some(err) => assert_eq!(
+                do_handshake_until_both_error(&mut client, &mut server),
+                Err(vec![
+                    ErrorFromPeer::Server(Error::General(err.to_string())),
+                    ErrorFromPeer::Client(Error::AlertReceived(
+                        rustls::internal::msgs::enums::AlertDescription::NoApplicationProtocol
+                    )),
+                ])
+            ),
         }
     }
 }
 
-#[test]
-fn client_error_is_sticky() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    client
-        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
-        .unwrap();
-    let mut err = client.process_new_packets();
-    assert!(err.is_err());
-    err = client.process_new_packets();
-    assert!(err.is_err());
-}
+/// Supports only HTTP/1.1 on one server name, and the configured
+/// protocols on others.
+struct AlpnPerServerName;
 
-#[test]
-fn server_error_is_sticky() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    server
-        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
-        .unwrap();
-    let mut err = server.process_new_packets();
-    assert!(err.is_err());
-    err = server.process_new_packets();
-    assert!(err.is_err());
+impl InspectsClientHello for AlpnPerServerName {
+    fn on_client_hello(&self, client_hello: &ClientHello) -> ClientHelloDecision {
+        match client_hello.server_name() {
+            Some("second.testserver.com") => {
+                ClientHelloDecision::UseAlpnProtocols(vec![b"http/1.1".to_vec()])
+            }
+            Some("localhost") => ClientHelloDecision::UseAlpnProtocols(vec![b"h3".to_vec()]),
+            _ => ClientHelloDecision::Continue,
+        }
+    }
 }
 
 #[test]
-fn server_flush_does_nothing() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    assert!(matches!(server.writer().flush(), Ok(())));
-}
+fn server_client_hello_inspector_chooses_alpn_protocols_per_server_name() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    server_config.client_hello_inspector = Some(Arc::new(AlpnPerServerName));
+    let server_config = Arc::new(server_config);
 
-#[test]
-fn client_flush_does_nothing() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    assert!(matches!(client.writer().flush(), Ok(())));
-}
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let client_config = Arc::new(client_config);
 
-#[test]
-fn server_is_send_and_sync() {
-    let (_, server) = make_pair(KeyType::Rsa);
-    &server as &dyn Send;
-    &server as &dyn Sync;
-}
+    for (name, expected) in [
+        ("testserver.com", Some(&b"h2"[..])),
+        ("second.testserver.com", Some(&b"http/1.1"[..])),
+        // the client did not offer h3
+        ("localhost", None),
+    ]
+    .iter()
+    {
+        let mut client = ClientConnection::new(Arc::clone(&client_config), dns_name(name)).unwrap();
+        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.alpn_protocol(), *expected);
+        assert_eq!(server.alpn_protocol(), *expected);
+    }
 
-#[test]
-fn client_is_send_and_sync() {
-    let (client, _) = make_pair(KeyType::Rsa);
-    &client as &dyn Send;
-    &client as &dyn Sync;
+    let mut server_config = (*server_config).clone();
+    server_config.alpn_required = true;
+    let mut client = ClientConnection::new(client_config, dns_name("localhost")).unwrap();
+    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::NoApplicationProtocol))
+    );
 }
 
 #[test]
-fn server_respects_buffer_limit_pre_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn client_checks_server_certificate_with_given_name() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config(*kt));
 
-    server.set_buffer_limit(Some(32));
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let mut client = ClientConnection::new(
+                Arc::new(client_config),
+                dns_name("not-the-right-hostname.com"),
+            )
+            .unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(err, Err(ErrorFromPeer::Client(Error::CertNotValidForName)));
+        }
+    }
+}
 
+#[test]
+fn server_name_parses_ip_addresses() {
     assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
+        ServerName::try_from("198.51.100.1").ok(),
+        Some(ServerName::IpAddress("198.51.100.1".parse().unwrap()))
     );
     assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        12
+        ServerName::try_from("2001:db8::1").ok(),
+        Some(ServerName::IpAddress("2001:db8::1".parse().unwrap()))
     );
+    assert!(ServerName::try_from("198.51.100.1:443").is_err());
+}
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
-
-    check_read(&mut client.reader(), b"01234567890123456789012345678901");
+fn make_server_config_with_ip_cert(kt: KeyType) -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(kt.get_ip_chain(), kt.get_key())
+        .unwrap()
 }
 
 #[test]
-fn server_respects_buffer_limit_pre_handshake_with_vectored_write() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-
-    server.set_buffer_limit(Some(32));
-
-    assert_eq!(
-        server
-            .writer()
-            .write_vectored(&[
-                IoSlice::new(b"01234567890123456789"),
-                IoSlice::new(b"01234567890123456789")
-            ])
-            .unwrap(),
-        32
-    );
+fn client_checks_server_certificate_with_given_ip_address() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config_with_ip_cert(*kt));
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+        for version in rustls::ALL_VERSIONS {
+            for ip_address in &["198.51.100.1", "2001:db8::1"] {
+                let client_config = make_client_config_with_versions(*kt, &[version]);
+                let mut client = ClientConnection::new(
+                    Arc::new(client_config),
+                    ServerName::try_from(*ip_address).unwrap(),
+                )
+                .unwrap();
+                let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
 
-    check_read(&mut client.reader(), b"01234567890123456789012345678901");
+                do_handshake(&mut client, &mut server);
+                assert_eq!(server.sni_hostname(), None);
+            }
+        }
+    }
 }
 
 #[test]
-fn server_respects_buffer_limit_post_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-
-    // this test will vary in behaviour depending on the default suites
-    do_handshake(&mut client, &mut server);
-    server.set_buffer_limit(Some(48));
-
-    assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
-    );
+fn server_name_converts_idn_to_a_labels() {
     assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        6
+        ServerName::try_from("Bücher.example").ok(),
+        ServerName::try_from("xn--bcher-kva.example").ok()
     );
-
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
-
-    check_read(&mut client.reader(), b"01234567890123456789012345");
+    assert!(ServerName::try_from("bü_cher.example").is_err());
+    assert!(ServerName::try_from("xn--bcher-kv!.example").is_err());
+    assert!(ServerName::try_from("xn--abc-.example").is_err());
 }
 
 #[test]
-fn client_respects_buffer_limit_pre_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn client_checks_server_certificate_with_given_idn() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(
+            ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(kt.get_idn_chain(), kt.get_key())
+                .unwrap(),
+        );
 
-    client.set_buffer_limit(Some(32));
+        for version in rustls::ALL_VERSIONS {
+            let client_config = Arc::new(make_client_config_with_versions(*kt, &[version]));
+            let mut client =
+                ClientConnection::new(Arc::clone(&client_config), dns_name("bücher.example"))
+                    .unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
 
-    assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
-    );
-    assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        12
-    );
+            do_handshake(&mut client, &mut server);
+            assert_eq!(server.sni_hostname(), Some("xn--bcher-kva.example"));
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+            let mut client =
+                ClientConnection::new(client_config, dns_name("bucher.example")).unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
 
-    check_read(&mut server.reader(), b"01234567890123456789012345678901");
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(err, Err(ErrorFromPeer::Client(Error::CertNotValidForName)));
+        }
+    }
 }
 
 #[test]
-fn client_respects_buffer_limit_pre_handshake_with_vectored_write() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-
-    client.set_buffer_limit(Some(32));
-
-    assert_eq!(
-        client
-            .writer()
-            .write_vectored(&[
-                IoSlice::new(b"01234567890123456789"),
-                IoSlice::new(b"01234567890123456789")
-            ])
-            .unwrap(),
-        32
-    );
+fn client_rejects_server_certificate_for_other_ip_address() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config_with_ip_cert(*kt));
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let mut client = ClientConnection::new(
+                Arc::new(client_config),
+                ServerName::try_from("198.51.100.2").unwrap(),
+            )
+            .unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
 
-    check_read(&mut server.reader(), b"01234567890123456789012345678901");
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(err, Err(ErrorFromPeer::Client(Error::CertNotValidForName)));
+        }
+    }
 }
 
-#[test]
-fn client_respects_buffer_limit_post_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-
-    do_handshake(&mut client, &mut server);
-    client.set_buffer_limit(Some(48));
-
-    assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
-    );
-    assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        6
-    );
-
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-
-    check_read(&mut server.reader(), b"01234567890123456789012345");
+fn make_server_config_with_must_staple(kt: KeyType, ocsp: &[u8]) -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert_with_ocsp_and_sct(
+            kt.get_must_staple_chain(),
+            kt.get_key(),
+            ocsp.to_vec(),
+            vec![],
+        )
+        .unwrap()
 }
 
-struct OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    sess: &'a mut C,
-    pub reads: usize,
-    pub writevs: Vec<Vec<usize>>,
-    fail_ok: bool,
-    pub short_writes: bool,
-    pub last_error: Option<rustls::Error>,
-}
+// An OCSPResponse with successful status, and empty BasicOCSPResponse.
+const OCSP_SUCCESSFUL: &[u8] = &[
+    0x30, 0x14, 0x0a, 0x01, 0x00, 0xa0, 0x0f, 0x30, 0x0d, 0x06, 0x09, 0x2b, 0x06, 0x01, 0x05, 0x05,
+    0x07, 0x30, 0x01, 0x01, 0x04, 0x00,
+];
 
-impl<'a, C, S> OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    fn new(sess: &'a mut C) -> OtherSession<'a, C, S> {
-        OtherSession {
-            sess,
-            reads: 0,
-            writevs: vec![],
-            fail_ok: false,
-            short_writes: false,
-            last_error: None,
-        }
-    }
+// An OCSPResponse with tryLater status.
+const OCSP_TRY_LATER: &[u8] = &[0x30, 0x03, 0x0a, 0x01, 0x03];
 
-    fn new_fails(sess: &'a mut C) -> OtherSession<'a, C, S> {
-        let mut os = OtherSession::new(sess);
-        os.fail_ok = true;
-        os
-    }
-}
+/// A client config enforcing must-staple, which checks the server
+/// certificate at a time when the OCSP responses in test-ca are current,
+/// except the expired one.
+///
+/// Those responses were produced at 2026-10-16T06:56Z; this time needs
+/// moving if they are regenerated.
+fn make_client_config_for_must_staple(
+    kt: KeyType,
+    version: &'static rustls::SupportedProtocolVersion,
+) -> ClientConfig {
+    let mut root_store = RootCertStore::empty();
+    root_store
+        .add(&kt.get_chain()[2])
+        .unwrap();
 
-impl<'a, C, S> io::Read for OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    fn read(&mut self, mut b: &mut [u8]) -> io::Result<usize> {
-        self.reads += 1;
-        self.sess.write_tls(b.by_ref())
-    }
+    let mut client_config = ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[version])
+        .unwrap()
+        .with_root_certificates(root_store)
+        .with_ocsp_must_staple(true)
+        .with_no_client_auth();
+    client_config.time_provider = Arc::new(FixedTime(
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_792_152_000),
+    ));
+    client_config
 }
 
-impl<'a, C, S> io::Write for OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
-        unreachable!()
-    }
+fn check_must_staple_rejected(kt: KeyType, ocsp: &[u8], expect: Error) {
+    let alert = match expect {
+        Error::CertRevoked => AlertDescription::CertificateRevoked,
+        _ => AlertDescription::BadCertificateStatusResponse,
+    };
+    let server_config = Arc::new(make_server_config_with_must_staple(kt, ocsp));
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_for_must_staple(kt, version);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        let errs = do_handshake_until_both_error(&mut client, &mut server);
+        assert_eq!(
+            errs,
+            Err(vec![
+                ErrorFromPeer::Client(expect.clone()),
+                ErrorFromPeer::Server(Error::AlertReceived(alert)),
+            ])
+        );
     }
+}
 
-    fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
-        let mut total = 0;
-        let mut lengths = vec![];
-        for bytes in b {
-            let write_len = if self.short_writes {
-                if bytes.len() > 5 {
-                    bytes.len() / 2
-                } else {
-                    bytes.len()
-                }
-            } else {
-                bytes.len()
-            };
-
-            let l = self
-                .sess
-                .read_tls(&mut io::Cursor::new(&bytes[..write_len]))?;
-            lengths.push(l);
-            total += l;
-            if bytes.len() != l {
-                break;
+#[test]
+fn client_accepts_must_staple_cert_with_staple() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for name in &[
+            "muststaple.good",
+            "muststaple.good-sha256",
+            "muststaple.delegated",
+        ] {
+            let server_config =
+                Arc::new(make_server_config_with_must_staple(*kt, &kt.get_ocsp(name)));
+
+            for version in rustls::ALL_VERSIONS {
+                let client_config = make_client_config_for_must_staple(*kt, version);
+                let (mut client, mut server) =
+                    make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+                do_handshake(&mut client, &mut server);
             }
         }
-
-        let rc = self.sess.process_new_packets();
-        if !self.fail_ok {
-            rc.unwrap();
-        } else if rc.is_err() {
-            self.last_error = rc.err();
-        }
-
-        self.writevs.push(lengths);
-        Ok(total)
     }
 }
 
 #[test]
-fn server_read_returns_wouldblock_when_no_data() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    assert!(matches!(server.reader().read(&mut [0u8; 1]),
-                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+fn client_rejects_must_staple_cert_without_staple() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for ocsp in &[&[][..], OCSP_TRY_LATER, OCSP_SUCCESSFUL] {
+            check_must_staple_rejected(*kt, ocsp, Error::MissingOcspStaple);
+        }
+    }
 }
 
 #[test]
-fn client_read_returns_wouldblock_when_no_data() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    assert!(matches!(client.reader().read(&mut [0u8; 1]),
-                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+fn client_rejects_must_staple_cert_with_revoked_staple() {
+    for kt in ALL_KEY_TYPES.iter() {
+        check_must_staple_rejected(*kt, &kt.get_ocsp("muststaple.revoked"), Error::CertRevoked);
+    }
 }
 
 #[test]
-fn new_server_returns_initial_io_state() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    let io_state = server.process_new_packets().unwrap();
-    println!("IoState is Debug {:?}", io_state);
-    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
-    assert!(!io_state.peer_has_closed());
-    assert_eq!(io_state.tls_bytes_to_write(), 0);
+fn client_rejects_must_staple_cert_with_staple_for_other_serial() {
+    for kt in ALL_KEY_TYPES.iter() {
+        check_must_staple_rejected(
+            *kt,
+            &kt.get_ocsp("other-serial.good"),
+            Error::MissingOcspStaple,
+        );
+    }
 }
 
 #[test]
-fn new_client_returns_initial_io_state() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    let io_state = client.process_new_packets().unwrap();
-    println!("IoState is Debug {:?}", io_state);
-    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
-    assert!(!io_state.peer_has_closed());
-    assert!(io_state.tls_bytes_to_write() > 200);
+fn client_rejects_must_staple_cert_with_expired_staple() {
+    for kt in ALL_KEY_TYPES.iter() {
+        check_must_staple_rejected(
+            *kt,
+            &kt.get_ocsp("muststaple.expired"),
+            Error::MissingOcspStaple,
+        );
+    }
 }
 
 #[test]
-fn client_complete_io_for_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-
-    assert!(client.is_handshaking());
-    let (rdlen, wrlen) = client
-        .complete_io(&mut OtherSession::new(&mut server))
-        .unwrap();
-    assert!(rdlen > 0 && wrlen > 0);
-    assert!(!client.is_handshaking());
+fn client_rejects_must_staple_cert_with_staple_from_wrong_signer() {
+    for kt in ALL_KEY_TYPES.iter() {
+        // Signed by the root rather than the issuing intermediate, and by
+        // a certificate from the issuer without the OCSP signing usage.
+        for name in &["muststaple.wrong-signer", "muststaple.not-responder"] {
+            check_must_staple_rejected(*kt, &kt.get_ocsp(name), Error::MissingOcspStaple);
+        }
+    }
 }
 
 #[test]
-fn client_complete_io_for_handshake_eof() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    let mut input = io::Cursor::new(Vec::new());
+fn client_does_not_enforce_must_staple_by_default() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config_with_must_staple(*kt, &[]));
 
-    assert!(client.is_handshaking());
-    let err = client
-        .complete_io(&mut input)
-        .unwrap_err();
-    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.time_provider = Arc::new(FixedTime(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1_792_152_000),
+            ));
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+        }
+    }
 }
 
 #[test]
-fn client_complete_io_for_write() {
+fn client_gets_stapled_ocsp() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
-
-        do_handshake(&mut client, &mut server);
+        let ocsp = kt.get_ocsp("muststaple.good");
+        let server_config = Arc::new(make_server_config_with_must_staple(*kt, &ocsp));
 
-        client
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        client
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
-            assert!(rdlen == 0 && wrlen > 0);
-            println!("{:?}", pipe.writevs);
-            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_for_must_staple(*kt, version);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            assert_eq!(client.get_peer_stapled_ocsp(), None);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.get_peer_stapled_ocsp(), Some(&ocsp[..]));
         }
-        check_read(
-            &mut server.reader(),
-            b"0123456789012345678901234567890123456789",
-        );
     }
 }
 
 #[test]
-fn client_complete_io_for_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+fn client_gets_signed_cert_timestamps() {
+    // A SignedCertificateTimestampList holding two (meaningless) SCTs.
+    let sct_list = vec![0x00, 0x08, 0x00, 0x02, 0x01, 0x02, 0x00, 0x02, 0x03, 0x04];
 
-        do_handshake(&mut client, &mut server);
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(
+            ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert_with_ocsp_and_sct(
+                    kt.get_chain(),
+                    kt.get_key(),
+                    vec![],
+                    sct_list.clone(),
+                )
+                .unwrap(),
+        );
 
-        server
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
-            assert!(rdlen > 0 && wrlen == 0);
-            assert_eq!(pipe.reads, 1);
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            assert_eq!(client.get_peer_signed_cert_timestamps(), None);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(
+                client.get_peer_signed_cert_timestamps(),
+                Some(&sct_list[..])
+            );
         }
-        check_read(&mut client.reader(), b"01234567890123456789");
     }
 }
 
 #[test]
-fn server_complete_io_for_handshake() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
-
-        assert!(server.is_handshaking());
-        let (rdlen, wrlen) = server
-            .complete_io(&mut OtherSession::new(&mut client))
-            .unwrap();
-        assert!(rdlen > 0 && wrlen > 0);
-        assert!(!server.is_handshaking());
+fn client_gets_no_stapled_ocsp_without_staple() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let server_config = make_server_config(KeyType::Rsa);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.get_peer_stapled_ocsp(), None);
+        assert_eq!(client.get_peer_signed_cert_timestamps(), None);
     }
 }
 
-#[test]
-fn server_complete_io_for_handshake_eof() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    let mut input = io::Cursor::new(Vec::new());
-
-    assert!(server.is_handshaking());
-    let err = server
-        .complete_io(&mut input)
-        .unwrap_err();
-    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+struct ClientCheckCertResolve {
+    query_count: AtomicUsize,
+    expect_queries: usize,
 }
 
-#[test]
-fn server_complete_io_for_write() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
-
-        do_handshake(&mut client, &mut server);
+impl ClientCheckCertResolve {
+    fn new(expect_queries: usize) -> Self {
+        ClientCheckCertResolve {
+            query_count: AtomicUsize::new(0),
+            expect_queries,
+        }
+    }
+}
 
-        server
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        server
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
-            assert!(rdlen == 0 && wrlen > 0);
-            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+impl Drop for ClientCheckCertResolve {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            let count = self.query_count.load(Ordering::SeqCst);
+            assert_eq!(count, self.expect_queries);
         }
-        check_read(
-            &mut client.reader(),
-            b"0123456789012345678901234567890123456789",
-        );
     }
 }
 
-#[test]
-fn server_complete_io_for_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+impl ResolvesClientCert for ClientCheckCertResolve {
+    fn resolve(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        self.query_count
+            .fetch_add(1, Ordering::SeqCst);
 
-        do_handshake(&mut client, &mut server);
+        if acceptable_issuers.is_empty() {
+            panic!("no issuers offered by server");
+        }
 
-        client
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
-            assert!(rdlen > 0 && wrlen == 0);
-            assert_eq!(pipe.reads, 1);
+        if sigschemes.is_empty() {
+            panic!("no signature schemes shared by server");
         }
-        check_read(&mut server.reader(), b"01234567890123456789");
+
+        None
+    }
+
+    fn has_certs(&self) -> bool {
+        true
     }
 }
 
 #[test]
-fn client_stream_write() {
+fn client_cert_resolve() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
 
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let mut stream = Stream::new(&mut client, &mut pipe);
-            assert_eq!(stream.write(b"hello").unwrap(), 5);
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(1));
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+
+            assert_eq!(
+                do_handshake_until_error(&mut client, &mut server),
+                Err(ErrorFromPeer::Server(Error::NoCertificatesPresented))
+            );
         }
-        check_read(&mut server.reader(), b"hello");
     }
 }
 
-#[test]
-fn client_streamowned_write() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (client, mut server) = make_pair(*kt);
+fn client_certified_key(kt: KeyType) -> sign::CertifiedKey {
+    let key = sign::any_supported_type(&kt.get_client_key()).unwrap();
+    sign::CertifiedKey::new(kt.get_client_chain(), key)
+}
 
-        {
-            let pipe = OtherSession::new(&mut server);
-            let mut stream = StreamOwned::new(client, pipe);
-            assert_eq!(stream.write(b"hello").unwrap(), 5);
-        }
-        check_read(&mut server.reader(), b"hello");
-    }
+fn handshake_with_ca_hints_resolver(
+    resolver: rustls::client::ResolvesClientCertUsingCaHints,
+) -> (
+    ClientConnection,
+    ServerConnection,
+    Result<(), ErrorFromPeer>,
+) {
+    // The server names the ECDSA CAs, and nothing else.
+    let server_config = make_server_config_with_mandatory_client_auth(KeyType::Ecdsa);
+    let mut client_config = make_client_config(KeyType::Ecdsa);
+    client_config.client_auth_cert_resolver = Arc::new(resolver);
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    let result = do_handshake_until_error(&mut client, &mut server);
+    (client, server, result)
 }
 
 #[test]
-fn client_stream_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
-
-        server
-            .writer()
-            .write_all(b"world")
+fn client_cert_resolver_uses_ca_hints() {
+    let mut resolver = rustls::client::ResolvesClientCertUsingCaHints::new();
+    for kt in &[KeyType::Rsa, KeyType::Ecdsa, KeyType::Ed25519] {
+        resolver
+            .add(client_certified_key(*kt))
             .unwrap();
-
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let mut stream = Stream::new(&mut client, &mut pipe);
-            check_read(&mut stream, b"world");
-        }
     }
+
+    let (_, server, result) = handshake_with_ca_hints_resolver(resolver);
+    assert_eq!(result, Ok(()));
+    assert_eq!(
+        server.peer_certificates(),
+        Some(&KeyType::Ecdsa.get_client_chain()[..])
+    );
 }
 
 #[test]
-fn client_streamowned_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (client, mut server) = make_pair(*kt);
+fn client_cert_resolver_falls_back_to_default_without_matching_ca_hint() {
+    let mut resolver = rustls::client::ResolvesClientCertUsingCaHints::new();
+    resolver
+        .add(client_certified_key(KeyType::Rsa))
+        .unwrap();
+    resolver
+        .set_default(client_certified_key(KeyType::Ecdsa))
+        .unwrap();
 
-        server
-            .writer()
-            .write_all(b"world")
-            .unwrap();
+    let (_, server, result) = handshake_with_ca_hints_resolver(resolver);
+    assert_eq!(result, Ok(()));
+    assert_eq!(
+        server.peer_certificates(),
+        Some(&KeyType::Ecdsa.get_client_chain()[..])
+    );
 
-        {
-            let pipe = OtherSession::new(&mut server);
-            let mut stream = StreamOwned::new(client, pipe);
-            check_read(&mut stream, b"world");
-        }
-    }
+    // With no default, no certificate is sent.
+    let mut resolver = rustls::client::ResolvesClientCertUsingCaHints::new();
+    resolver
+        .add(client_certified_key(KeyType::Rsa))
+        .unwrap();
+
+    let (_, _, result) = handshake_with_ca_hints_resolver(resolver);
+    assert_eq!(
+        result,
+        Err(ErrorFromPeer::Server(Error::NoCertificatesPresented))
+    );
 }
 
 #[test]
-fn server_stream_write() {
+fn client_cert_resolver_with_ca_hints_rejects_mismatched_key() {
+    let mut resolver = rustls::client::ResolvesClientCertUsingCaHints::new();
+    let key = sign::any_supported_type(&KeyType::Ecdsa.get_client_key()).unwrap();
+    assert!(resolver
+        .add(sign::CertifiedKey::new(
+            KeyType::Rsa.get_client_chain(),
+            key
+        ))
+        .is_err());
+}
+
+#[test]
+fn server_rejects_client_declining_mandatory_auth() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
 
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let mut stream = Stream::new(&mut server, &mut pipe);
-            assert_eq!(stream.write(b"hello").unwrap(), 5);
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+
+            let alert = if version.version == ProtocolVersion::TLSv1_3 {
+                AlertDescription::CertificateRequired
+            } else {
+                AlertDescription::HandshakeFailure
+            };
+            assert_eq!(
+                do_handshake_until_both_error(&mut client, &mut server),
+                Err(vec![
+                    ErrorFromPeer::Server(Error::NoCertificatesPresented),
+                    ErrorFromPeer::Client(Error::AlertReceived(alert)),
+                ])
+            );
         }
-        check_read(&mut client.reader(), b"hello");
     }
 }
 
 #[test]
-fn server_streamowned_write() {
+fn client_auth_works() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, server) = make_pair(*kt);
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
 
-        {
-            let pipe = OtherSession::new(&mut client);
-            let mut stream = StreamOwned::new(server, pipe);
-            assert_eq!(stream.write(b"hello").unwrap(), 5);
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
         }
-        check_read(&mut client.reader(), b"hello");
     }
 }
 
-#[test]
-fn server_stream_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+/// Holds several client identities, and chooses the one issued by
+/// a CA the server says it accepts.
+struct ClientCertByIssuer {
+    identities: Vec<(Vec<Vec<u8>>, Arc<sign::CertifiedKey>)>,
+    chosen: Mutex<Option<usize>>,
+}
 
-        client
-            .writer()
-            .write_all(b"world")
-            .unwrap();
+impl ClientCertByIssuer {
+    fn new(key_types: &[KeyType]) -> Self {
+        let identities = key_types
+            .iter()
+            .map(|kt| {
+                // The names of the CAs above the end-entity certificate.
+                let chain = kt.get_client_chain();
+                let mut cas = RootCertStore::empty();
+                for cert in &chain[1..] {
+                    cas.add(cert).unwrap();
+                }
+                let issuers = cas
+                    .subjects()
+                    .iter()
+                    .map(|name| name.0.clone())
+                    .collect();
+                let certkey = sign::CertifiedKey::new(
+                    chain,
+                    sign::any_supported_type(&kt.get_client_key()).unwrap(),
+                );
+                (issuers, Arc::new(certkey))
+            })
+            .collect();
 
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let mut stream = Stream::new(&mut server, &mut pipe);
-            check_read(&mut stream, b"world");
+        Self {
+            identities,
+            chosen: Mutex::new(None),
         }
     }
 }
 
+impl ResolvesClientCert for ClientCertByIssuer {
+    fn resolve(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        assert!(!sigschemes.is_empty());
+
+        let (index, (_, certkey)) = self
+            .identities
+            .iter()
+            .enumerate()
+            .find(|(_, (issuers, _))| {
+                acceptable_issuers.iter().any(|hint| {
+                    issuers
+                        .iter()
+                        .any(|issuer| issuer == hint)
+                })
+            })?;
+
+        *self.chosen.lock().unwrap() = Some(index);
+        Some(Arc::clone(certkey))
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
 #[test]
-fn server_streamowned_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, server) = make_pair(*kt);
+fn client_cert_resolve_uses_ca_hints() {
+    let server_config = Arc::new(make_server_config_with_mandatory_client_auth(
+        KeyType::Ecdsa,
+    ));
 
-        client
-            .writer()
-            .write_all(b"world")
-            .unwrap();
+    for version in rustls::ALL_VERSIONS {
+        let resolver = Arc::new(ClientCertByIssuer::new(&[KeyType::Rsa, KeyType::Ecdsa]));
+        let mut client_config = make_client_config_with_versions(KeyType::Ecdsa, &[version]);
+        client_config.client_auth_cert_resolver = resolver.clone();
 
-        {
-            let pipe = OtherSession::new(&mut client);
-            let mut stream = StreamOwned::new(server, pipe);
-            check_read(&mut stream, b"world");
-        }
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(*resolver.chosen.lock().unwrap(), Some(1));
+        assert_eq!(
+            server.peer_certificates(),
+            Some(&KeyType::Ecdsa.get_client_chain()[..])
+        );
     }
 }
 
-struct FailsWrites {
-    errkind: io::ErrorKind,
-    after: usize,
+/// A `SigningKey` which remembers which scheme it was asked to sign with.
+struct SchemeRecordingKey {
+    key: Arc<dyn sign::SigningKey>,
+    chosen: Arc<Mutex<Option<SignatureScheme>>>,
 }
 
-impl io::Read for FailsWrites {
-    fn read(&mut self, _b: &mut [u8]) -> io::Result<usize> {
-        Ok(0)
+impl sign::SigningKey for SchemeRecordingKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn sign::Signer>> {
+        let signer = self.key.choose_scheme(offered)?;
+        *self.chosen.lock().unwrap() = Some(signer.scheme());
+        Some(signer)
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        self.key.algorithm()
     }
 }
 
-impl io::Write for FailsWrites {
-    fn write(&mut self, b: &[u8]) -> io::Result<usize> {
-        if self.after > 0 {
-            self.after -= 1;
-            Ok(b.len())
-        } else {
-            Err(io::Error::new(self.errkind, "oops"))
-        }
+/// Offers a single client identity, loaded from a PKCS#8 key.
+struct SingleClientCert(Arc<sign::CertifiedKey>);
+
+impl ResolvesClientCert for SingleClientCert {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        assert!(sigschemes.contains(&SignatureScheme::ED25519));
+        Some(Arc::clone(&self.0))
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+    fn has_certs(&self) -> bool {
+        true
     }
 }
 
 #[test]
-fn stream_write_reports_underlying_io_error_before_plaintext_processed() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-    do_handshake(&mut client, &mut server);
-
-    let mut pipe = FailsWrites {
-        errkind: io::ErrorKind::ConnectionAborted,
-        after: 0,
-    };
-    client
-        .writer()
-        .write_all(b"hello")
+fn client_auth_with_ed25519_cert_from_resolver() {
+    let client_kt = KeyType::Ed25519;
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(get_client_root_store(
+            client_kt,
+        )))
+        .with_single_cert(KeyType::Rsa.get_chain(), KeyType::Rsa.get_key())
         .unwrap();
-    let mut client_stream = Stream::new(&mut client, &mut pipe);
-    let rc = client_stream.write(b"world");
-    assert!(rc.is_err());
-    let err = rc.err().unwrap();
-    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let chosen = Arc::new(Mutex::new(None));
+        let key = sign::any_supported_type(&client_kt.get_client_key()).unwrap();
+        assert_eq!(key.algorithm(), SignatureAlgorithm::ED25519);
+        let key = SchemeRecordingKey {
+            key,
+            chosen: chosen.clone(),
+        };
+        let certified_key = sign::CertifiedKey::new(client_kt.get_client_chain(), Arc::new(key));
+
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.client_auth_cert_resolver =
+            Arc::new(SingleClientCert(Arc::new(certified_key)));
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(*chosen.lock().unwrap(), Some(SignatureScheme::ED25519));
+        assert_eq!(
+            server.peer_certificates(),
+            Some(&client_kt.get_client_chain()[..])
+        );
+    }
 }
 
-#[test]
-fn stream_write_swallows_underlying_io_error_after_plaintext_processed() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-    do_handshake(&mut client, &mut server);
+/// Authenticates with the public key of the end-entity certificate
+/// in `chain`, instead of the certificate chain itself.
+struct RawPublicKeyResolver(Arc<sign::CertifiedKey>);
 
-    let mut pipe = FailsWrites {
-        errkind: io::ErrorKind::ConnectionAborted,
-        after: 1,
-    };
-    client
-        .writer()
-        .write_all(b"hello")
-        .unwrap();
-    let mut client_stream = Stream::new(&mut client, &mut pipe);
-    let rc = client_stream.write(b"world");
-    assert_eq!(format!("{:?}", rc), "Ok(5)");
+impl RawPublicKeyResolver {
+    fn new(chain: &[rustls::Certificate], key: &rustls::PrivateKey) -> Self {
+        Self(Arc::new(sign::CertifiedKey::new(
+            vec![rustls::Certificate(cert_spki(&chain[0]))],
+            sign::any_supported_type(key).unwrap(),
+        )))
+    }
 }
 
-fn make_disjoint_suite_configs() -> (ClientConfig, ServerConfig) {
-    let kt = KeyType::Rsa;
-    let server_config = finish_server_config(
-        kt,
-        ServerConfig::builder()
-            .with_cipher_suites(&[rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256])
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap(),
-    );
+impl ResolvesServerCert for RawPublicKeyResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        Some(Arc::clone(&self.0))
+    }
 
-    let client_config = finish_client_config(
-        kt,
-        ClientConfig::builder()
-            .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384])
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap(),
-    );
+    fn only_raw_public_keys(&self) -> bool {
+        true
+    }
+}
 
-    (client_config, server_config)
+impl ResolvesClientCert for RawPublicKeyResolver {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        Some(Arc::clone(&self.0))
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+
+    fn only_raw_public_keys(&self) -> bool {
+        true
+    }
 }
 
-#[test]
-fn client_stream_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+struct PinnedRawPublicKey(Vec<u8>);
 
-    {
-        let mut pipe = OtherSession::new_fails(&mut server);
-        let mut client_stream = Stream::new(&mut client, &mut pipe);
-        let rc = client_stream.write(b"hello");
-        assert!(rc.is_err());
-        assert_eq!(
-            format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
-        );
-        let rc = client_stream.write(b"hello");
-        assert!(rc.is_err());
-        assert_eq!(
-            format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
-        );
+impl RawPublicKeyVerifier for PinnedRawPublicKey {
+    fn verify_raw_public_key(
+        &self,
+        spki: &[u8],
+        _now: SystemTime,
+    ) -> Result<RawPublicKeyVerified, Error> {
+        if spki == self.0 {
+            Ok(RawPublicKeyVerified::assertion())
+        } else {
+            Err(Error::InvalidCertificateData(
+                "unknown raw public key".to_string(),
+            ))
+        }
     }
 }
 
-#[test]
-fn client_streamowned_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (client, mut server) = make_pair_for_configs(client_config, server_config);
+fn make_raw_public_key_pair(kt: KeyType) -> (ClientConfig, ServerConfig) {
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver =
+        Arc::new(RawPublicKeyResolver::new(&kt.get_chain(), &kt.get_key()));
 
-    let pipe = OtherSession::new_fails(&mut server);
-    let mut client_stream = StreamOwned::new(client, pipe);
-    let rc = client_stream.write(b"hello");
-    assert!(rc.is_err());
-    assert_eq!(
-        format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
-    );
-    let rc = client_stream.write(b"hello");
-    assert!(rc.is_err());
-    assert_eq!(
-        format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
-    );
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.raw_public_key_verifier =
+        Some(Arc::new(PinnedRawPublicKey(cert_spki(&kt.get_chain()[0]))));
+
+    (client_config, server_config)
 }
 
 #[test]
-fn server_stream_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-
-    client
-        .writer()
-        .write_all(b"world")
-        .unwrap();
+fn server_raw_public_key_works() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (client_config, server_config) = make_raw_public_key_pair(*kt);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
 
-    {
-        let mut pipe = OtherSession::new_fails(&mut client);
-        let mut server_stream = Stream::new(&mut server, &mut pipe);
-        let mut bytes = [0u8; 5];
-        let rc = server_stream.read(&mut bytes);
-        assert!(rc.is_err());
-        assert_eq!(
-            format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: PeerIncompatibleError(\"no ciphersuites in common\") })"
-        );
+        let spki = rustls::Certificate(cert_spki(&kt.get_chain()[0]));
+        assert_eq!(client.peer_certificates(), Some(&[spki][..]));
     }
 }
 
 #[test]
-fn server_streamowned_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, server) = make_pair_for_configs(client_config, server_config);
+fn client_raw_public_key_works() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client_config, _) = make_raw_public_key_pair(*kt);
+        client_config.client_auth_cert_resolver = Arc::new(RawPublicKeyResolver::new(
+            &kt.get_client_chain(),
+            &kt.get_client_key(),
+        ));
+
+        let mut server_config = make_server_config_with_mandatory_client_auth(*kt);
+        server_config.raw_public_key_verifier = Some(Arc::new(PinnedRawPublicKey(cert_spki(
+            &kt.get_client_chain()[0],
+        ))));
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
 
-    client
-        .writer()
-        .write_all(b"world")
-        .unwrap();
+        let spki = rustls::Certificate(cert_spki(&kt.get_client_chain()[0]));
+        assert_eq!(server.peer_certificates(), Some(&[spki][..]));
+    }
+}
 
-    let pipe = OtherSession::new_fails(&mut client);
-    let mut server_stream = StreamOwned::new(server, pipe);
-    let mut bytes = [0u8; 5];
-    let rc = server_stream.read(&mut bytes);
-    assert!(rc.is_err());
+#[test]
+fn client_rejects_unknown_raw_public_key() {
+    let (mut client_config, server_config) = make_raw_public_key_pair(KeyType::Ed25519);
+    client_config.raw_public_key_verifier = Some(Arc::new(PinnedRawPublicKey(cert_spki(
+        &KeyType::Rsa.get_chain()[0],
+    ))));
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
     assert_eq!(
-        format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: PeerIncompatibleError(\"no ciphersuites in common\") })"
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Client(Error::InvalidCertificateData(
+            "unknown raw public key".to_string()
+        )))
     );
 }
 
 #[test]
-fn server_config_is_clone() {
-    let _ = make_server_config(KeyType::Rsa);
+fn server_raw_public_key_requires_client_support() {
+    for version in rustls::ALL_VERSIONS {
+        let (_, server_config) = make_raw_public_key_pair(KeyType::Ecdsa);
+        let client_config = make_client_config_with_versions(KeyType::Ecdsa, &[version]);
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        let err = do_handshake_until_error(&mut client, &mut server);
+        assert!(matches!(
+            err,
+            Err(ErrorFromPeer::Server(Error::PeerIncompatibleError(_)))
+        ));
+    }
 }
 
 #[test]
-fn client_config_is_clone() {
-    let _ = make_client_config(KeyType::Rsa);
+fn client_offering_raw_public_keys_accepts_certificates() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (client_config, _) = make_raw_public_key_pair(*kt);
+        let server_config = make_server_config(*kt);
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.peer_certificates(), Some(&kt.get_chain()[..]));
+    }
 }
 
-#[test]
-fn client_connection_is_debug() {
-    let (client, _) = make_pair(KeyType::Rsa);
-    println!("{:?}", client);
-}
+struct AlwaysResolves(Arc<sign::CertifiedKey>);
 
-#[test]
-fn server_connection_is_debug() {
-    let (_, server) = make_pair(KeyType::Rsa);
-    println!("{:?}", server);
+impl ResolvesServerCert for AlwaysResolves {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        Some(Arc::clone(&self.0))
+    }
 }
 
-#[test]
-fn server_complete_io_for_handshake_ending_with_alert() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-
-    assert!(server.is_handshaking());
-
-    let mut pipe = OtherSession::new_fails(&mut client);
-    let rc = server.complete_io(&mut pipe);
-    assert!(rc.is_err(), "server io failed due to handshake failure");
-    assert!(!server.wants_write(), "but server did send its alert");
-    assert_eq!(
-        format!("{:?}", pipe.last_error),
-        "Some(AlertReceived(HandshakeFailure))",
-        "which was received by client"
+/// Makes a server which delegates to an Ed25519 key until `not_after`,
+/// using the certificate chain `chain` from test-ca.
+fn make_server_config_with_delegated_credential_from(
+    kt: KeyType,
+    chain: &str,
+    not_after: SystemTime,
+) -> ServerConfig {
+    let mut certified_key = sign::CertifiedKey::new(
+        kt.get_dc_chain(chain),
+        sign::any_supported_type(&kt.get_key()).unwrap(),
     );
+    let delegated_credential = sign::DelegatedCredential::new(
+        &certified_key,
+        sign::any_supported_type(&KeyType::Ed25519.get_client_key()).unwrap(),
+        cert_spki(&KeyType::Ed25519.get_client_chain()[0]),
+        SignatureScheme::ED25519,
+        not_after,
+    )
+    .unwrap();
+    certified_key.delegated_credential = Some(delegated_credential);
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(AlwaysResolves(Arc::new(certified_key)));
+    server_config
 }
 
-#[test]
-fn server_exposes_offered_sni() {
-    let kt = KeyType::Rsa;
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("second.testserver.com"))
-                .unwrap();
-        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
+fn make_server_config_with_delegated_credential(
+    kt: KeyType,
+    not_after: SystemTime,
+) -> ServerConfig {
+    make_server_config_with_delegated_credential_from(kt, "end.dc", not_after)
+}
 
-        assert_eq!(None, server.sni_hostname());
-        do_handshake(&mut client, &mut server);
-        assert_eq!(Some("second.testserver.com"), server.sni_hostname());
-    }
+fn make_client_config_accepting_delegated_credentials(
+    kt: KeyType,
+    schemes: &[SignatureScheme],
+) -> ClientConfig {
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.delegated_credential_schemes = schemes.to_vec();
+    client_config
 }
 
 #[test]
-fn server_exposes_offered_sni_smashed_to_lowercase() {
-    // webpki actually does this for us in its DnsName type
-    let kt = KeyType::Rsa;
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("SECOND.TESTServer.com"))
-                .unwrap();
-        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
+fn server_delegated_credential_works() {
+    let not_after = SystemTime::now() + Duration::from_secs(24 * 60 * 60);
 
-        assert_eq!(None, server.sni_hostname());
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = make_server_config_with_delegated_credential(*kt, not_after);
+        let client_config =
+            make_client_config_accepting_delegated_credentials(*kt, &[SignatureScheme::ED25519]);
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
         do_handshake(&mut client, &mut server);
-        assert_eq!(Some("second.testserver.com"), server.sni_hostname());
+        assert_eq!(
+            client.peer_certificates(),
+            Some(&kt.get_dc_chain("end.dc")[..])
+        );
     }
 }
 
 #[test]
-fn server_exposes_offered_sni_even_if_resolver_fails() {
-    let kt = KeyType::Rsa;
-    let resolver = rustls::server::ResolvesServerCertUsingSni::new();
-
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
+fn client_rejects_delegated_credential_from_cert_not_allowing_delegation() {
+    let not_after = SystemTime::now() + Duration::from_secs(24 * 60 * 60);
 
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
-        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("thisdoesNOTexist.com"))
-                .unwrap();
+    for kt in ALL_KEY_TYPES.iter() {
+        for chain in &["end.dc-no-extension", "end.dc-no-key-usage"] {
+            let server_config =
+                make_server_config_with_delegated_credential_from(*kt, chain, not_after);
+            let client_config = make_client_config_accepting_delegated_credentials(
+                *kt,
+                &[SignatureScheme::ED25519],
+            );
 
-        assert_eq!(None, server.sni_hostname());
-        transfer(&mut client, &mut server);
-        assert_eq!(
-            server.process_new_packets(),
-            Err(Error::General(
-                "no server certificate chain resolved".to_string()
-            ))
-        );
-        assert_eq!(Some("thisdoesnotexist.com"), server.sni_hostname());
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+            assert_eq!(
+                do_handshake_until_both_error(&mut client, &mut server),
+                Err(vec![
+                    ErrorFromPeer::Client(Error::InvalidCertificateData(
+                        "certificate does not allow delegated credentials".to_string()
+                    )),
+                    ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::IllegalParameter)),
+                ])
+            );
+        }
     }
 }
 
 #[test]
-fn sni_resolver_works() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
-    resolver
-        .add(
-            "localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone()),
-        )
-        .unwrap();
-
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
-
-    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client1 =
-        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("localhost")).unwrap();
-    let err = do_handshake_until_error(&mut client1, &mut server1);
-    assert_eq!(err, Ok(()));
+fn client_rejects_expired_delegated_credential() {
+    let now = SystemTime::now();
+    let server_config =
+        make_server_config_with_delegated_credential(KeyType::Ecdsa, now + Duration::from_secs(60));
+    let mut client_config = make_client_config_accepting_delegated_credentials(
+        KeyType::Ecdsa,
+        &[SignatureScheme::ED25519],
+    );
+    client_config.time_provider = Arc::new(FixedTime(now + Duration::from_secs(120)));
 
-    let mut server2 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client2 =
-        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("notlocalhost")).unwrap();
-    let err = do_handshake_until_error(&mut client2, &mut server2);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
     assert_eq!(
-        err,
-        Err(ErrorFromPeer::Server(Error::General(
-            "no server certificate chain resolved".into()
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Client(Error::InvalidCertificateData(
+            "delegated credential expired".to_string()
         )))
     );
 }
 
 #[test]
-fn sni_resolver_rejects_wrong_names() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
-
-    assert_eq!(
-        Ok(()),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
-    );
-    assert_eq!(
-        Err(Error::General(
-            "The server certificate is not valid for the given name".into()
-        )),
-        resolver.add(
-            "not-localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
+fn client_rejects_long_lived_delegated_credential() {
+    let not_after = SystemTime::now() + Duration::from_secs(8 * 24 * 60 * 60);
+    let server_config = make_server_config_with_delegated_credential(KeyType::Rsa, not_after);
+    let client_config = make_client_config_accepting_delegated_credentials(
+        KeyType::Rsa,
+        &[SignatureScheme::ED25519],
     );
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
     assert_eq!(
-        Err(Error::General("Bad DNS name".into())),
-        resolver.add(
-            "not ascii 🦀",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Client(Error::InvalidCertificateData(
+            "delegated credential valid for too long".to_string()
+        )))
     );
 }
 
 #[test]
-fn sni_resolver_rejects_bad_certs() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+fn server_omits_delegated_credential_with_unoffered_scheme() {
+    // The credential has expired by the client's clock, so the handshake
+    // only works if the server doesn't send it.
+    let now = SystemTime::now();
+    let server_config =
+        make_server_config_with_delegated_credential(KeyType::Ecdsa, now + Duration::from_secs(60));
 
-    assert_eq!(
-        Err(Error::General(
-            "No end-entity certificate in certificate chain".into()
-        )),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(vec![], signing_key.clone())
-        )
-    );
+    for schemes in [&[][..], &[SignatureScheme::ECDSA_NISTP256_SHA256][..]].iter() {
+        let mut client_config =
+            make_client_config_accepting_delegated_credentials(KeyType::Ecdsa, schemes);
+        client_config.time_provider = Arc::new(FixedTime(now + Duration::from_secs(120)));
 
-    let bad_chain = vec![rustls::Certificate(vec![0xa0])];
-    assert_eq!(
-        Err(Error::General(
-            "End-entity certificate in certificate chain is syntactically invalid".into()
-        )),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(bad_chain, signing_key.clone())
-        )
-    );
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config.clone());
+        do_handshake(&mut client, &mut server);
+    }
 }
 
-fn do_exporter_test(client_config: ClientConfig, server_config: ServerConfig) {
-    let mut client_secret = [0u8; 64];
-    let mut server_secret = [0u8; 64];
+#[test]
+fn server_accepts_client_cert_not_in_crl() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config_with_client_crls(
+            *kt,
+            &["end.revoked"],
+            CrlExpiryPolicy::Reject,
+        ));
 
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+        }
+    }
+}
 
-    assert_eq!(
-        Err(Error::HandshakeNotComplete),
-        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
-    );
-    assert_eq!(
-        Err(Error::HandshakeNotComplete),
-        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
-    );
-    do_handshake(&mut client, &mut server);
+#[test]
+fn server_rejects_revoked_client_cert() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config_with_client_crls(
+            *kt,
+            &["end.revoked", "client.revoked"],
+            CrlExpiryPolicy::Reject,
+        ));
 
-    assert_eq!(
-        Ok(()),
-        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
-    );
-    assert_eq!(
-        Ok(()),
-        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
-    );
-    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(err, Err(ErrorFromPeer::Server(Error::CertRevoked)));
+        }
+    }
+}
 
-    assert_eq!(
-        Ok(()),
-        client.export_keying_material(&mut client_secret, b"label", None)
-    );
-    assert_ne!(client_secret.to_vec(), server_secret.to_vec());
-    assert_eq!(
-        Ok(()),
-        server.export_keying_material(&mut server_secret, b"label", None)
-    );
-    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+fn make_client_config_with_server_crls(
+    kt: KeyType,
+    crls: &[&str],
+    expiry_policy: CrlExpiryPolicy,
+) -> ClientConfig {
+    let crls = crls
+        .iter()
+        .map(|name| kt.get_crl(name))
+        .collect();
+
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(get_client_root_store(kt))
+        .with_crls(crls, expiry_policy)
+        .unwrap()
+        .with_no_client_auth()
 }
 
-#[cfg(feature = "tls12")]
 #[test]
-fn test_tls12_exporter() {
+fn client_builder_crls_accept_unrevoked_server_cert() {
     for kt in ALL_KEY_TYPES.iter() {
-        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS12]);
-        let server_config = make_server_config(*kt);
-
-        do_exporter_test(client_config, server_config);
+        let client_config =
+            make_client_config_with_server_crls(*kt, &["client.revoked"], CrlExpiryPolicy::Reject);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(*kt));
+        do_handshake(&mut client, &mut server);
     }
 }
 
 #[test]
-fn test_tls13_exporter() {
+fn client_builder_crls_reject_revoked_server_cert() {
     for kt in ALL_KEY_TYPES.iter() {
-        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
-        let server_config = make_server_config(*kt);
+        let client_config =
+            make_client_config_with_server_crls(*kt, &["end.revoked"], CrlExpiryPolicy::Reject);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(*kt));
+        let err = do_handshake_until_error(&mut client, &mut server);
+        assert_eq!(err, Err(ErrorFromPeer::Client(Error::CertRevoked)));
+    }
+}
 
-        do_exporter_test(client_config, server_config);
+#[test]
+fn client_builder_rejects_unparseable_crl() {
+    let result = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(get_client_root_store(KeyType::Rsa))
+        .with_crls(vec![b"not a crl".to_vec()], CrlExpiryPolicy::Reject);
+    assert!(result.is_err());
+}
+
+#[test]
+fn server_rejects_expired_crl() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for (policy, expect) in vec![
+            (
+                CrlExpiryPolicy::Reject,
+                Err(ErrorFromPeer::Server(Error::InvalidCrl(
+                    "CRL has expired".into(),
+                ))),
+            ),
+            (CrlExpiryPolicy::Warn, Ok(())),
+        ] {
+            let server_config = Arc::new(make_server_config_with_client_crls(
+                *kt,
+                &["inter.expired"],
+                policy,
+            ));
+            let client_config =
+                make_client_config_with_versions_with_auth(*kt, &[&rustls::version::TLS13]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            assert_eq!(do_handshake_until_error(&mut client, &mut server), expect);
+        }
     }
 }
 
-fn do_suite_test(
-    client_config: ClientConfig,
-    server_config: ServerConfig,
-    expect_suite: SupportedCipherSuite,
-    expect_version: ProtocolVersion,
-) {
-    println!(
-        "do_suite_test {:?} {:?}",
-        expect_version,
-        expect_suite.suite()
-    );
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-
-    assert_eq!(None, client.negotiated_cipher_suite());
-    assert_eq!(None, server.negotiated_cipher_suite());
-    assert_eq!(None, client.protocol_version());
-    assert_eq!(None, server.protocol_version());
-    assert!(client.is_handshaking());
-    assert!(server.is_handshaking());
+struct FixedTime(SystemTime);
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+impl rustls::TimeProvider for FixedTime {
+    fn now(&self) -> Result<SystemTime, Error> {
+        Ok(self.0)
+    }
+}
 
-    assert!(client.is_handshaking());
-    assert!(server.is_handshaking());
-    assert_eq!(None, client.protocol_version());
-    assert_eq!(Some(expect_version), server.protocol_version());
-    assert_eq!(None, client.negotiated_cipher_suite());
-    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+#[test]
+fn client_builder_clock_skew_accepts_recently_expired_cert() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let expired = cert_not_after(&kt.get_chain()[0]) + Duration::from_secs(30);
 
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+        for (clock_skew, expect_ok) in [(0, false), (60, true)] {
+            let mut client_config = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(get_client_root_store(*kt))
+                .with_clock_skew(Duration::from_secs(clock_skew))
+                .with_no_client_auth();
+            client_config.time_provider = Arc::new(FixedTime(expired));
 
-    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
-    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, make_server_config(*kt));
+            assert_eq!(
+                do_handshake_until_error(&mut client, &mut server).is_ok(),
+                expect_ok
+            );
+        }
+    }
+}
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+#[test]
+fn client_builder_can_reject_unused_sha1_intermediate() {
+    let kt = KeyType::Rsa;
+    let mut chain = kt.get_chain();
+    chain.push(kt.get_sha1_intermediate());
+    let server_config = Arc::new(
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(chain, kt.get_key())
+            .unwrap(),
+    );
+    let now = cert_not_after(&kt.get_chain()[0]) - Duration::from_secs(30);
+
+    for (reject, expect) in [
+        (false, Ok(())),
+        (
+            true,
+            Err(ErrorFromPeer::Client(
+                Error::InvalidCertificateSignatureType,
+            )),
+        ),
+    ] {
+        let mut client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(get_client_root_store(kt))
+            .with_reject_weak_signature_algorithms(reject)
+            .with_no_client_auth();
+        client_config.time_provider = Arc::new(FixedTime(now));
 
-    assert!(!client.is_handshaking());
-    assert!(!server.is_handshaking());
-    assert_eq!(Some(expect_version), client.protocol_version());
-    assert_eq!(Some(expect_version), server.protocol_version());
-    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
-    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(do_handshake_until_error(&mut client, &mut server), expect);
+    }
 }
 
-fn find_suite(suite: CipherSuite) -> SupportedCipherSuite {
-    for scs in ALL_CIPHER_SUITES.iter().copied() {
-        if scs.suite() == suite {
-            return scs;
-        }
-    }
+struct UnknownTime;
 
-    panic!("find_suite given unsupported suite");
+impl rustls::TimeProvider for UnknownTime {
+    fn now(&self) -> Result<SystemTime, Error> {
+        Err(Error::FailedToGetCurrentTime)
+    }
 }
 
-static TEST_CIPHERSUITES: &[(&rustls::SupportedProtocolVersion, KeyType, CipherSuite)] = &[
-    (
-        &rustls::version::TLS13,
-        KeyType::Rsa,
-        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
-    ),
-    (
-        &rustls::version::TLS13,
-        KeyType::Rsa,
-        CipherSuite::TLS13_AES_256_GCM_SHA384,
-    ),
-    (
-        &rustls::version::TLS13,
-        KeyType::Rsa,
-        CipherSuite::TLS13_AES_128_GCM_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
-    ),
-];
+fn expiry_boundary(cert: &rustls::Certificate) -> Vec<(SystemTime, Option<Error>)> {
+    let not_after = cert_not_after(cert);
+    vec![
+        (not_after - Duration::from_secs(1), None),
+        (not_after + Duration::from_secs(1), Some(Error::CertExpired)),
+    ]
+}
 
 #[test]
-fn negotiated_ciphersuite_default() {
+fn client_uses_time_provider_for_server_cert_expiry() {
     for kt in ALL_KEY_TYPES.iter() {
-        do_suite_test(
-            make_client_config(*kt),
-            make_server_config(*kt),
-            find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384),
-            ProtocolVersion::TLSv1_3,
-        );
+        let server_config = Arc::new(make_server_config(*kt));
+
+        for (now, expect_err) in expiry_boundary(&kt.get_chain()[0]) {
+            for version in rustls::ALL_VERSIONS {
+                let mut client_config = make_client_config_with_versions(*kt, &[version]);
+                client_config.time_provider = Arc::new(FixedTime(now));
+                let (mut client, mut server) =
+                    make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+                assert_eq!(
+                    do_handshake_until_error(&mut client, &mut server),
+                    expect_err
+                        .clone()
+                        .map_or(Ok(()), |err| Err(ErrorFromPeer::Client(err)))
+                );
+            }
+        }
     }
 }
 
 #[test]
-fn all_suites_covered() {
-    assert_eq!(ALL_CIPHER_SUITES.len(), TEST_CIPHERSUITES.len());
+fn client_sends_alert_matching_cert_error() {
+    use rustls::internal::msgs::enums::AlertDescription;
+
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+    let not_after = cert_not_after(&KeyType::Rsa.get_chain()[0]);
+    let valid = not_after - Duration::from_secs(1);
+    let cases = [
+        (
+            KeyType::Rsa,
+            "localhost",
+            not_after + Duration::from_secs(1),
+            Error::CertExpired,
+            AlertDescription::CertificateExpired,
+        ),
+        (
+            KeyType::Rsa,
+            "localhost",
+            SystemTime::UNIX_EPOCH + Duration::from_secs(86_400),
+            Error::CertNotValidYet,
+            AlertDescription::CertificateExpired,
+        ),
+        (
+            KeyType::Ecdsa,
+            "localhost",
+            valid,
+            Error::UnknownIssuer,
+            AlertDescription::UnknownCA,
+        ),
+        (
+            KeyType::Rsa,
+            "not-testserver.com",
+            valid,
+            Error::CertNotValidForName,
+            AlertDescription::BadCertificate,
+        ),
+    ];
+
+    for (roots_kt, name, now, err, alert) in cases.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*roots_kt, &[version]);
+            client_config.time_provider = Arc::new(FixedTime(*now));
+            let mut client =
+                ClientConnection::new(Arc::new(client_config), dns_name(name)).unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+            assert_eq!(
+                do_handshake_until_both_error(&mut client, &mut server),
+                Err(vec![
+                    ErrorFromPeer::Client(err.clone()),
+                    ErrorFromPeer::Server(Error::AlertReceived(*alert)),
+                ])
+            );
+        }
+    }
+}
+
+fn verify_chain_at(kt: KeyType, name: &str, now: SystemTime) -> Result<(), Error> {
+    let chain = kt.get_chain();
+    let mut roots = RootCertStore::empty();
+    roots.add(&chain[2]).unwrap();
+    rustls::verify_cert_chain(
+        &roots,
+        &chain[..2],
+        &ServerName::try_from(name).unwrap(),
+        now,
+    )
 }
 
 #[test]
-fn negotiated_ciphersuite_client() {
-    for item in TEST_CIPHERSUITES.iter() {
-        let (version, kt, suite) = *item;
-        let scs = find_suite(suite);
-        let client_config = finish_client_config(
-            kt,
-            ClientConfig::builder()
-                .with_cipher_suites(&[scs])
-                .with_safe_default_kx_groups()
-                .with_protocol_versions(&[version])
-                .unwrap(),
-        );
+fn verify_cert_chain_accepts_valid_chain() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let now = cert_not_after(&kt.get_chain()[0]) - Duration::from_secs(1);
+        assert_eq!(verify_chain_at(*kt, "testserver.com", now), Ok(()));
+    }
+}
 
-        do_suite_test(client_config, make_server_config(kt), scs, version.version);
+#[test]
+fn verify_cert_chain_checks_expiry_like_handshake() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for (now, expect_err) in expiry_boundary(&kt.get_chain()[0]) {
+            assert_eq!(
+                verify_chain_at(*kt, "testserver.com", now),
+                expect_err.map_or(Ok(()), Err)
+            );
+        }
     }
 }
 
 #[test]
-fn negotiated_ciphersuite_server() {
-    for item in TEST_CIPHERSUITES.iter() {
-        let (version, kt, suite) = *item;
-        let scs = find_suite(suite);
-        let server_config = finish_server_config(
-            kt,
-            ServerConfig::builder()
-                .with_cipher_suites(&[scs])
-                .with_safe_default_kx_groups()
-                .with_protocol_versions(&[version])
-                .unwrap(),
+fn verify_cert_chain_checks_name_like_handshake() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let now = cert_not_after(&kt.get_chain()[0]) - Duration::from_secs(1);
+        assert_eq!(
+            verify_chain_at(*kt, "not-testserver.com", now),
+            Err(Error::CertNotValidForName)
         );
 
-        do_suite_test(make_client_config(kt), server_config, scs, version.version);
+        let mut client_config = make_client_config(*kt);
+        client_config.time_provider = Arc::new(FixedTime(now));
+        let mut client = ClientConnection::new(
+            Arc::new(client_config),
+            ServerName::try_from("not-testserver.com").unwrap(),
+        )
+        .unwrap();
+        let mut server = ServerConnection::new(Arc::new(make_server_config(*kt))).unwrap();
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Client(Error::CertNotValidForName))
+        );
     }
 }
 
-#[derive(Debug, PartialEq)]
-struct KeyLogItem {
-    label: String,
-    client_random: Vec<u8>,
-    secret: Vec<u8>,
+#[test]
+fn verify_cert_chain_rejects_empty_chain() {
+    let roots = get_client_root_store(KeyType::Rsa);
+    assert_eq!(
+        rustls::verify_cert_chain(
+            &roots,
+            &[],
+            &ServerName::try_from("testserver.com").unwrap(),
+            SystemTime::now(),
+        ),
+        Err(Error::NoCertificatesPresented)
+    );
 }
 
-struct KeyLogToVec {
-    label: &'static str,
-    items: Mutex<Vec<KeyLogItem>>,
-}
+#[test]
+fn server_uses_time_provider_for_client_cert_expiry() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for (now, expect_err) in expiry_boundary(&kt.get_client_chain()[0]) {
+            let mut server_config = make_server_config_with_mandatory_client_auth(*kt);
+            server_config.time_provider = Arc::new(FixedTime(now));
+            let server_config = Arc::new(server_config);
 
-impl KeyLogToVec {
-    fn new(who: &'static str) -> Self {
-        KeyLogToVec {
-            label: who,
-            items: Mutex::new(vec![]),
+            for version in rustls::ALL_VERSIONS {
+                let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+                let (mut client, mut server) =
+                    make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+                assert_eq!(
+                    do_handshake_until_error(&mut client, &mut server),
+                    expect_err
+                        .clone()
+                        .map_or(Ok(()), |err| Err(ErrorFromPeer::Server(err)))
+                );
+            }
         }
     }
+}
 
-    fn take(&self) -> Vec<KeyLogItem> {
-        std::mem::take(&mut self.items.lock().unwrap())
+#[test]
+fn client_fails_if_time_unknown() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.time_provider = Arc::new(UnknownTime);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(*kt));
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Client(Error::FailedToGetCurrentTime))
+        );
     }
 }
 
-impl KeyLog for KeyLogToVec {
-    fn log(&self, label: &str, client: &[u8], secret: &[u8]) {
-        let value = KeyLogItem {
-            label: label.into(),
-            client_random: client.into(),
-            secret: secret.into(),
-        };
-
-        println!("key log {:?}: {:?}", self.label, value);
+#[test]
+fn client_error_is_sticky() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    client
+        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
+        .unwrap();
+    let mut err = client.process_new_packets();
+    assert!(err.is_err());
+    err = client.process_new_packets();
+    assert!(err.is_err());
+}
 
-        self.items.lock().unwrap().push(value);
+#[test]
+fn client_surfaces_received_alert_description() {
+    for desc in [
+        rustls::AlertDescription::HandshakeFailure,
+        rustls::AlertDescription::CertificateExpired,
+        rustls::AlertDescription::UnknownCA,
+    ]
+    .iter()
+    {
+        let (mut client, _) = make_pair(KeyType::Rsa);
+        let alert = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, desc.get_u8()];
+        client
+            .read_tls(&mut alert.as_ref())
+            .unwrap();
+        assert_eq!(
+            client.process_new_packets(),
+            Err(Error::AlertReceived(*desc))
+        );
     }
 }
 
 #[cfg(feature = "tls12")]
 #[test]
-fn key_log_for_tls12() {
-    let client_key_log = Arc::new(KeyLogToVec::new("client"));
-    let server_key_log = Arc::new(KeyLogToVec::new("server"));
+fn client_surfaces_alert_sent_by_server() {
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let server_config = make_server_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-    let kt = KeyType::Rsa;
-    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS12]);
-    client_config.key_log = client_key_log.clone();
-    let client_config = Arc::new(client_config);
+    assert_eq!(
+        do_handshake_until_both_error(&mut client, &mut server),
+        Err(vec![
+            ErrorFromPeer::Server(Error::PeerIncompatibleError(
+                "TLS1.2 not offered/enabled".into()
+            )),
+            ErrorFromPeer::Client(Error::AlertReceived(
+                rustls::AlertDescription::ProtocolVersion
+            )),
+        ])
+    );
+}
 
-    let mut server_config = make_server_config(kt);
-    server_config.key_log = server_key_log.clone();
-    let server_config = Arc::new(server_config);
+#[test]
+fn server_error_is_sticky() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    server
+        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
+        .unwrap();
+    let mut err = server.process_new_packets();
+    assert!(err.is_err());
+    err = server.process_new_packets();
+    assert!(err.is_err());
+}
 
-    // full handshake
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+#[test]
+fn server_flush_does_nothing() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    assert!(matches!(server.writer().flush(), Ok(())));
+}
 
-    let client_full_log = client_key_log.take();
-    let server_full_log = server_key_log.take();
-    assert_eq!(client_full_log, server_full_log);
-    assert_eq!(1, client_full_log.len());
-    assert_eq!("CLIENT_RANDOM", client_full_log[0].label);
+#[test]
+fn client_flush_does_nothing() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert!(matches!(client.writer().flush(), Ok(())));
+}
 
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+#[test]
+fn server_is_send_and_sync() {
+    let (_, server) = make_pair(KeyType::Rsa);
+    &server as &dyn Send;
+    &server as &dyn Sync;
+}
 
-    let client_resume_log = client_key_log.take();
-    let server_resume_log = server_key_log.take();
-    assert_eq!(client_resume_log, server_resume_log);
-    assert_eq!(1, client_resume_log.len());
-    assert_eq!("CLIENT_RANDOM", client_resume_log[0].label);
-    assert_eq!(client_full_log[0].secret, client_resume_log[0].secret);
+#[test]
+fn client_is_send_and_sync() {
+    let (client, _) = make_pair(KeyType::Rsa);
+    &client as &dyn Send;
+    &client as &dyn Sync;
 }
 
 #[test]
-fn key_log_for_tls13() {
-    let client_key_log = Arc::new(KeyLogToVec::new("client"));
-    let server_key_log = Arc::new(KeyLogToVec::new("server"));
+fn configs_are_send_and_sync() {
+    fn assert_send_and_sync<T: Send + Sync>(_: &T) {}
+    assert_send_and_sync(&make_client_config(KeyType::Rsa));
+    assert_send_and_sync(&make_server_config(KeyType::Rsa));
+}
 
-    let kt = KeyType::Rsa;
-    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-    client_config.key_log = client_key_log.clone();
-    let client_config = Arc::new(client_config);
+#[test]
+fn cloned_configs_share_heavy_state() {
+    let client_config = make_client_config(KeyType::Rsa);
+    let cloned = client_config.clone();
+    assert!(Arc::ptr_eq(
+        &client_config.client_auth_cert_resolver,
+        &cloned.client_auth_cert_resolver
+    ));
+    assert!(Arc::ptr_eq(
+        &client_config.session_storage,
+        &cloned.session_storage
+    ));
 
-    let mut server_config = make_server_config(kt);
-    server_config.key_log = server_key_log.clone();
-    let server_config = Arc::new(server_config);
+    let server_config = make_server_config(KeyType::Rsa);
+    let cloned = server_config.clone();
+    assert!(Arc::ptr_eq(
+        &server_config.cert_resolver,
+        &cloned.cert_resolver
+    ));
+    assert!(Arc::ptr_eq(
+        &server_config.session_storage,
+        &cloned.session_storage
+    ));
+    assert!(Arc::ptr_eq(&server_config.ticketer, &cloned.ticketer));
+}
 
-    // full handshake
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+#[test]
+fn handshake_complete_callback_is_called_once_per_handshake() {
+    fn counting_callback(
+        resumed: &Arc<Mutex<Vec<bool>>>,
+    ) -> Arc<rustls::HandshakeCompleteCallback> {
+        let resumed = Arc::clone(resumed);
+        Arc::new(move |common: &rustls::CommonState| {
+            assert!(!common.is_handshaking());
+            assert!(common
+                .negotiated_cipher_suite()
+                .is_some());
+            resumed
+                .lock()
+                .unwrap()
+                .push(common.is_session_resumed().unwrap());
+        })
+    }
 
-    let client_full_log = client_key_log.take();
-    let server_full_log = server_key_log.take();
+    for version in rustls::ALL_VERSIONS {
+        let client_resumed = Arc::new(Mutex::new(Vec::new()));
+        let server_resumed = Arc::new(Mutex::new(Vec::new()));
 
-    assert_eq!(5, client_full_log.len());
-    assert_eq!("CLIENT_HANDSHAKE_TRAFFIC_SECRET", client_full_log[0].label);
-    assert_eq!("SERVER_HANDSHAKE_TRAFFIC_SECRET", client_full_log[1].label);
-    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_full_log[2].label);
-    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_full_log[3].label);
-    assert_eq!("EXPORTER_SECRET", client_full_log[4].label);
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.on_handshake_complete = Some(counting_callback(&client_resumed));
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.on_handshake_complete = Some(counting_callback(&server_resumed));
+        let client_config = Arc::new(client_config);
+        let server_config = Arc::new(server_config);
 
-    assert_eq!(client_full_log[0], server_full_log[0]);
-    assert_eq!(client_full_log[1], server_full_log[1]);
-    assert_eq!(client_full_log[2], server_full_log[2]);
-    assert_eq!(client_full_log[3], server_full_log[3]);
-    assert_eq!(client_full_log[4], server_full_log[4]);
+        for _ in 0..2 {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            do_handshake(&mut client, &mut server);
 
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+            // Neither later records nor session tickets call it again.
+            server
+                .writer()
+                .write_all(b"hello")
+                .unwrap();
+            transfer(&mut server, &mut client);
+            client.process_new_packets().unwrap();
+            client
+                .writer()
+                .write_all(b"hello")
+                .unwrap();
+            transfer(&mut client, &mut server);
+            server.process_new_packets().unwrap();
+        }
 
-    let client_resume_log = client_key_log.take();
-    let server_resume_log = server_key_log.take();
+        assert_eq!(*client_resumed.lock().unwrap(), vec![false, true]);
+        assert_eq!(*server_resumed.lock().unwrap(), vec![false, true]);
+    }
+}
 
-    assert_eq!(5, client_resume_log.len());
-    assert_eq!(
-        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
-        client_resume_log[0].label
-    );
-    assert_eq!(
-        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
-        client_resume_log[1].label
-    );
-    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_resume_log[2].label);
-    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_resume_log[3].label);
-    assert_eq!("EXPORTER_SECRET", client_resume_log[4].label);
+#[test]
+fn server_respects_buffer_limit_pre_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    server.set_buffer_limit(Some(32));
 
-    assert_eq!(6, server_resume_log.len());
-    assert_eq!("CLIENT_EARLY_TRAFFIC_SECRET", server_resume_log[0].label);
     assert_eq!(
-        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
-        server_resume_log[1].label
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
     );
     assert_eq!(
-        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
-        server_resume_log[2].label
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        12
     );
-    assert_eq!("CLIENT_TRAFFIC_SECRET_0", server_resume_log[3].label);
-    assert_eq!("SERVER_TRAFFIC_SECRET_0", server_resume_log[4].label);
-    assert_eq!("EXPORTER_SECRET", server_resume_log[5].label);
 
-    assert_eq!(client_resume_log[0], server_resume_log[1]);
-    assert_eq!(client_resume_log[1], server_resume_log[2]);
-    assert_eq!(client_resume_log[2], server_resume_log[3]);
-    assert_eq!(client_resume_log[3], server_resume_log[4]);
-    assert_eq!(client_resume_log[4], server_resume_log[5]);
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    check_read(&mut client.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn vectored_write_for_server_appdata() {
+fn server_respects_buffer_limit_pre_handshake_with_vectored_write() {
     let (mut client, mut server) = make_pair(KeyType::Rsa);
-    do_handshake(&mut client, &mut server);
 
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert_eq!(84, wrlen);
-        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
-    }
-    check_read(
-        &mut client.reader(),
-        b"0123456789012345678901234567890123456789",
+    server.set_buffer_limit(Some(32));
+
+    assert_eq!(
+        server
+            .writer()
+            .write_vectored(&[
+                IoSlice::new(b"01234567890123456789"),
+                IoSlice::new(b"01234567890123456789")
+            ])
+            .unwrap(),
+        32
     );
+
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    check_read(&mut client.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn vectored_write_for_client_appdata() {
+fn server_respects_buffer_limit_post_handshake() {
     let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    // this test will vary in behaviour depending on the default suites
     do_handshake(&mut client, &mut server);
+    server.set_buffer_limit(Some(48));
 
-    client
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    client
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert_eq!(84, wrlen);
-        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
-    }
-    check_read(
-        &mut server.reader(),
-        b"0123456789012345678901234567890123456789",
+    assert_eq!(
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
+    );
+    assert_eq!(
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        6
     );
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    check_read(&mut client.reader(), b"01234567890123456789012345");
 }
 
 #[test]
-fn vectored_write_for_server_handshake_without_client_auth() {
+fn client_respects_buffer_limit_pre_handshake() {
     let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    server
-        .writer()
-        .write_all(b"0123456789")
-        .unwrap();
+    client.set_buffer_limit(Some(32));
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        // don't assert exact sizes here, to avoid a brittle test
-        assert!(wrlen > 4000); // its pretty big (contains cert chain)
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert_eq!(pipe.writevs[0].len(), 8); // at least a server hello/ccs/cert/serverkx/0.5rtt data
-    }
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
+    );
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        12
+    );
 
-    client.process_new_packets().unwrap();
+    do_handshake(&mut client, &mut server);
     transfer(&mut client, &mut server);
     server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert_eq!(wrlen, 103);
-        assert_eq!(pipe.writevs, vec![vec![103]]);
-    }
 
-    assert!(!server.is_handshaking());
-    assert!(!client.is_handshaking());
-    check_read(&mut client.reader(), b"012345678901234567890123456789");
+    check_read(&mut server.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn vectored_write_for_server_handshake_with_client_auth() {
-    let server_config = make_server_config_with_mandatory_client_auth(KeyType::Rsa);
-    let (mut client, mut server) =
-        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
+fn client_respects_buffer_limit_pre_handshake_with_vectored_write() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    server
-        .writer()
-        .write_all(b"0123456789")
-        .unwrap();
+    client.set_buffer_limit(Some(32));
+
+    assert_eq!(
+        client
+            .writer()
+            .write_vectored(&[
+                IoSlice::new(b"01234567890123456789"),
+                IoSlice::new(b"01234567890123456789")
+            ])
+            .unwrap(),
+        32
+    );
 
+    do_handshake(&mut client, &mut server);
     transfer(&mut client, &mut server);
     server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        // don't assert exact sizes here, to avoid a brittle test
-        assert!(wrlen > 4000); // its pretty big (contains cert chain)
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert_eq!(pipe.writevs[0].len(), 7); // at least a server hello/ccs/cert/serverkx data
-    }
 
-    // client second flight
-    client.process_new_packets().unwrap();
-    transfer(&mut client, &mut server);
+    check_read(&mut server.reader(), b"01234567890123456789012345678901");
+}
 
-    // when client auth is enabled, we don't sent 0.5-rtt data, as we'd be sending
-    // it to an unauthenticated peer. so it happens here, in the server's second
-    // flight (42 and 32 are lengths of appdata sent above).
+#[test]
+fn client_respects_buffer_limit_post_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    do_handshake(&mut client, &mut server);
+    client.set_buffer_limit(Some(48));
+
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
+    );
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        6
+    );
+
+    transfer(&mut client, &mut server);
     server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert_eq!(wrlen, 177);
-        assert_eq!(pipe.writevs, vec![vec![103, 42, 32]]);
-    }
 
-    assert!(!server.is_handshaking());
-    assert!(!client.is_handshaking());
-    check_read(&mut client.reader(), b"012345678901234567890123456789");
+    check_read(&mut server.reader(), b"01234567890123456789012345");
 }
 
 #[test]
-fn vectored_write_for_client_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn server_config_limits_outgoing_plaintext() {
+    let client_config = make_client_config(KeyType::Rsa);
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.limit_outgoing_plaintext = Some(48);
 
-    client
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    client
-        .writer()
-        .write_all(b"0123456789")
-        .unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        // don't assert exact sizes here, to avoid a brittle test
-        assert!(wrlen > 200); // just the client hello
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 1); // only a client hello
-    }
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    // this test will vary in behaviour depending on the default suites
+    assert_eq!(
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
+    );
+    assert_eq!(
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        6
+    );
+    assert_eq!(server.writer().write(b"0123").unwrap(), 0);
 
     transfer(&mut server, &mut client);
     client.process_new_packets().unwrap();
+    check_read(&mut client.reader(), b"01234567890123456789012345");
 
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert_eq!(wrlen, 154);
-        // CCS, finished, then two application datas
-        assert_eq!(pipe.writevs, vec![vec![6, 74, 42, 32]]);
-    }
-
-    assert!(!server.is_handshaking());
-    assert!(!client.is_handshaking());
-    check_read(&mut server.reader(), b"012345678901234567890123456789");
+    assert_eq!(server.writer().write(b"0123").unwrap(), 4);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client.reader(), b"0123");
 }
 
 #[test]
-fn vectored_write_with_slow_client() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn client_config_limits_outgoing_plaintext() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.limit_outgoing_plaintext = Some(32);
+    let server_config = make_server_config(KeyType::Rsa);
 
-    client.set_buffer_limit(Some(32));
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
+    );
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        12
+    );
+    assert_eq!(client.writer().write(b"0123").unwrap(), 0);
 
     do_handshake(&mut client, &mut server);
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"01234567890123456789012345678901");
 
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        pipe.short_writes = true;
-        let wrlen = server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap();
-        assert_eq!(42, wrlen);
-        assert_eq!(
-            pipe.writevs,
-            vec![vec![21], vec![10], vec![5], vec![3], vec![3]]
-        );
-    }
-    check_read(&mut client.reader(), b"01234567890123456789");
+    assert_eq!(client.writer().write(b"0123").unwrap(), 4);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"0123");
 }
 
-struct ServerStorage {
-    storage: Arc<dyn rustls::server::StoresServerSessions>,
-    put_count: AtomicUsize,
-    get_count: AtomicUsize,
-    take_count: AtomicUsize,
+struct OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    sess: &'a mut C,
+    pub reads: usize,
+    pub writevs: Vec<Vec<usize>>,
+    fail_ok: bool,
+    pub short_writes: bool,
+    pub last_error: Option<rustls::Error>,
 }
 
-impl ServerStorage {
-    fn new() -> Self {
-        ServerStorage {
-            storage: rustls::server::ServerSessionMemoryCache::new(1024),
-            put_count: AtomicUsize::new(0),
-            get_count: AtomicUsize::new(0),
-            take_count: AtomicUsize::new(0),
+impl<'a, C, S> OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn new(sess: &'a mut C) -> OtherSession<'a, C, S> {
+        OtherSession {
+            sess,
+            reads: 0,
+            writevs: vec![],
+            fail_ok: false,
+            short_writes: false,
+            last_error: None,
         }
     }
 
-    fn puts(&self) -> usize {
-        self.put_count.load(Ordering::SeqCst)
-    }
-    fn gets(&self) -> usize {
-        self.get_count.load(Ordering::SeqCst)
-    }
-    fn takes(&self) -> usize {
-        self.take_count.load(Ordering::SeqCst)
+    fn new_fails(sess: &'a mut C) -> OtherSession<'a, C, S> {
+        let mut os = OtherSession::new(sess);
+        os.fail_ok = true;
+        os
     }
 }
 
-impl fmt::Debug for ServerStorage {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "(put: {:?}, get: {:?}, take: {:?})",
-            self.put_count, self.get_count, self.take_count
-        )
+impl<'a, C, S> io::Read for OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn read(&mut self, mut b: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        self.sess.write_tls(b.by_ref())
     }
 }
 
-impl rustls::server::StoresServerSessions for ServerStorage {
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        self.put_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.put(key, value)
-    }
-
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.get_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.get(key)
+impl<'a, C, S> io::Write for OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        unreachable!()
     }
 
-    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.take_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.take(key)
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 
-    fn can_cache(&self) -> bool {
-        true
-    }
-}
+    fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
+        let mut total = 0;
+        let mut lengths = vec![];
+        for bytes in b {
+            let write_len = if self.short_writes {
+                if bytes.len() > 5 {
+                    bytes.len() / 2
+                } else {
+                    bytes.len()
+                }
+            } else {
+                bytes.len()
+            };
 
-struct ClientStorage {
-    storage: Arc<dyn rustls::client::StoresClientSessions>,
-    put_count: AtomicUsize,
-    get_count: AtomicUsize,
-    last_put_key: Mutex<Option<Vec<u8>>>,
-}
+            let l = self
+                .sess
+                .read_tls(&mut io::Cursor::new(&bytes[..write_len]))?;
+            lengths.push(l);
+            total += l;
+            if bytes.len() != l {
+                break;
+            }
+        }
 
-impl ClientStorage {
-    fn new() -> Self {
-        ClientStorage {
-            storage: rustls::client::ClientSessionMemoryCache::new(1024),
-            put_count: AtomicUsize::new(0),
-            get_count: AtomicUsize::new(0),
-            last_put_key: Mutex::new(None),
+        let rc = self.sess.process_new_packets();
+        if !self.fail_ok {
+            rc.unwrap();
+        } else if rc.is_err() {
+            self.last_error = rc.err();
         }
-    }
 
-    fn puts(&self) -> usize {
-        self.put_count.load(Ordering::SeqCst)
-    }
-    fn gets(&self) -> usize {
-        self.get_count.load(Ordering::SeqCst)
+        self.writevs.push(lengths);
+        Ok(total)
     }
 }
 
-impl fmt::Debug for ClientStorage {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "(puts: {:?}, gets: {:?} )",
-            self.put_count, self.get_count
-        )
-    }
+#[test]
+fn server_read_returns_wouldblock_when_no_data() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    assert!(matches!(server.reader().read(&mut [0u8; 1]),
+                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
 }
 
-impl rustls::client::StoresClientSessions for ClientStorage {
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        self.put_count
-            .fetch_add(1, Ordering::SeqCst);
-        *self.last_put_key.lock().unwrap() = Some(key.clone());
-        self.storage.put(key, value)
-    }
-
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.get_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.get(key)
-    }
+#[test]
+fn client_read_returns_wouldblock_when_no_data() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert!(matches!(client.reader().read(&mut [0u8; 1]),
+                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
 }
 
+#[cfg(read_buf)]
 #[test]
-fn tls13_stateful_resumption() {
-    let kt = KeyType::Rsa;
-    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-    let client_config = Arc::new(client_config);
+fn client_read_buf_initializes_only_what_it_writes() {
+    use std::io::BorrowedBuf;
+    use std::mem::MaybeUninit;
 
-    let mut server_config = make_server_config(kt);
-    let storage = Arc::new(ServerStorage::new());
-    server_config.session_storage = storage.clone();
-    let server_config = Arc::new(server_config);
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
 
-    // full handshake
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(storage.puts(), 1);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+    server
+        .writer()
+        .write_all(b"hello world")
+        .unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
 
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
-    assert!(resume_c2s > full_c2s);
-    assert!(resume_s2c < full_s2c);
-    assert_eq!(storage.puts(), 2);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 1);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+    let mut storage = [MaybeUninit::<u8>::uninit(); 32];
+    let mut buf = BorrowedBuf::from(&mut storage[..]);
+    client
+        .reader()
+        .read_buf(buf.unfilled())
+        .unwrap();
+    assert_eq!(buf.filled(), b"hello world");
+    assert!(!buf.is_init());
 
-    // resumed again
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(resume_s2c, resume2_s2c);
-    assert_eq!(resume_c2s, resume2_c2s);
-    assert_eq!(storage.puts(), 3);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 2);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+    assert!(matches!(client.reader().read_buf(buf.unfilled()),
+                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+    assert_eq!(buf.len(), 11);
 }
 
 #[test]
-fn tls13_stateless_resumption() {
-    let kt = KeyType::Rsa;
-    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-    let client_config = Arc::new(client_config);
+fn client_fill_buf_peeks_without_consuming() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
 
-    let mut server_config = make_server_config(kt);
-    server_config.ticketer = rustls::Ticketer::new().unwrap();
-    let storage = Arc::new(ServerStorage::new());
-    server_config.session_storage = storage.clone();
-    let server_config = Arc::new(server_config);
+    assert!(matches!(client.reader().fill_buf(),
+                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
 
-    // full handshake
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(storage.puts(), 0);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+    server
+        .writer()
+        .write_all(b"hello world")
+        .unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
 
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
-    assert!(resume_c2s > full_c2s);
-    assert!(resume_s2c < full_s2c);
-    assert_eq!(storage.puts(), 0);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+    assert_eq!(client.reader().fill_buf().unwrap(), b"hello world");
+    assert_eq!(client.reader().fill_buf().unwrap(), b"hello world");
 
-    // resumed again
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(resume_s2c, resume2_s2c);
-    assert_eq!(resume_c2s, resume2_c2s);
-    assert_eq!(storage.puts(), 0);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+    client.reader().consume(6);
+    assert_eq!(client.reader().fill_buf().unwrap(), b"world");
+    check_read(&mut client.reader(), b"world");
+
+    assert!(matches!(client.reader().fill_buf(),
+                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
 }
 
 #[test]
-fn early_data_not_available() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    assert!(client.early_data().is_none());
-}
+fn server_fill_buf_consumes_across_records() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
 
-fn early_data_configs() -> (Arc<ClientConfig>, Arc<ServerConfig>) {
-    let kt = KeyType::Rsa;
-    let mut client_config = make_client_config(kt);
-    client_config.enable_early_data = true;
-    client_config.session_storage = Arc::new(ClientStorage::new());
+    client
+        .writer()
+        .write_all(b"first")
+        .unwrap();
+    client
+        .writer()
+        .write_all(b"second")
+        .unwrap();
+    client.send_close_notify();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-    let mut server_config = make_server_config(kt);
-    server_config.max_early_data_size = 1234;
-    (Arc::new(client_config), Arc::new(server_config))
+    let mut reader = server.reader();
+    assert_eq!(reader.fill_buf().unwrap(), b"first");
+    reader.consume(2);
+    assert_eq!(reader.fill_buf().unwrap(), b"rst");
+    reader.consume(3);
+    assert_eq!(reader.fill_buf().unwrap(), b"second");
+    reader.consume(6);
+    assert_eq!(reader.fill_buf().unwrap(), b"");
 }
 
 #[test]
-fn early_data_is_available_on_resumption() {
-    let (client_config, server_config) = early_data_configs();
+fn new_server_returns_initial_io_state() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    let io_state = server.process_new_packets().unwrap();
+    println!("IoState is Debug {:?}", io_state);
+    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
+    assert!(!io_state.peer_has_closed());
+    assert_eq!(io_state.tls_bytes_to_write(), 0);
+}
 
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+#[test]
+fn new_client_returns_initial_io_state() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    let io_state = client.process_new_packets().unwrap();
+    println!("IoState is Debug {:?}", io_state);
+    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
+    assert!(!io_state.peer_has_closed());
+    assert!(io_state.tls_bytes_to_write() > 200);
+}
+
+#[test]
+fn connection_reports_pending_bytes_in_each_direction() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
     do_handshake(&mut client, &mut server);
+    assert_eq!(client.tls_bytes_to_write(), 0);
+    assert!(!client.wants_write());
 
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    assert!(client.early_data().is_some());
-    assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .bytes_left(),
-        1234
-    );
     client
-        .early_data()
-        .unwrap()
-        .flush()
+        .writer()
+        .write_all(b"hello")
         .unwrap();
-    assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .write(b"hello")
-            .unwrap(),
-        5
-    );
-    do_handshake(&mut client, &mut server);
+    assert!(client.tls_bytes_to_write() > 5);
+    assert!(client.wants_write());
+    assert_eq!(server.plaintext_bytes_to_read(), 0);
 
-    let mut received_early_data = [0u8; 5];
-    assert_eq!(
-        server
-            .early_data()
-            .expect("early_data didn't happen")
-            .read(&mut received_early_data)
-            .expect("early_data failed unexpectedly"),
-        5
-    );
-    assert_eq!(&received_early_data[..], b"hello");
-}
+    let written = transfer(&mut client, &mut server);
+    assert_eq!(client.tls_bytes_to_write(), 0);
+    let io_state = server.process_new_packets().unwrap();
+    assert!(written > 5);
+    assert_eq!(server.plaintext_bytes_to_read(), 5);
+    assert_eq!(io_state.plaintext_bytes_to_read(), 5);
 
-#[test]
-fn early_data_not_available_on_server_before_client_hello() {
-    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
-    assert!(server.early_data().is_none());
+    check_read(&mut server.reader(), b"hello");
+    assert_eq!(server.plaintext_bytes_to_read(), 0);
 }
 
 #[test]
-fn early_data_can_be_rejected_by_server() {
-    let (client_config, server_config) = early_data_configs();
-
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+fn connection_memory_usage_tracks_buffered_writes() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
     do_handshake(&mut client, &mut server);
+    let idle = client.approximate_memory_usage();
 
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    assert!(client.early_data().is_some());
-    assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .bytes_left(),
-        1234
-    );
     client
-        .early_data()
-        .unwrap()
-        .flush()
+        .writer()
+        .write_all(&[0u8; 65536])
         .unwrap();
-    assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .write(b"hello")
-            .unwrap(),
-        5
+    let buffered = client.approximate_memory_usage();
+    assert!(buffered >= idle + 65536);
+
+    transfer(&mut client, &mut server);
+    let flushed = client.approximate_memory_usage();
+    assert!(flushed < buffered);
+    assert!(flushed < idle + 65536);
+}
+
+#[test]
+fn is_handshaking_until_both_finished_messages_are_exchanged() {
+    let (mut client, mut server) = make_pair_for_configs(
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]),
+        make_server_config(KeyType::Rsa),
     );
-    server.reject_early_data();
-    do_handshake(&mut client, &mut server);
+    assert!(client.is_handshaking());
+    assert!(server.is_handshaking());
 
-    assert_eq!(client.is_early_data_accepted(), false);
+    // ClientHello
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(client.is_handshaking());
+    assert!(server.is_handshaking());
+
+    // ServerHello ... server Finished: the client has its Finished
+    // queued and is done, but the server is still waiting for it.
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert!(!client.is_handshaking());
+    assert!(server.is_handshaking());
+
+    // client Finished
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(!client.is_handshaking());
+    assert!(!server.is_handshaking());
 }
 
-#[cfg(feature = "quic")]
-mod test_quic {
-    use super::*;
-    use rustls::Connection;
+#[cfg(feature = "tls12")]
+#[test]
+fn is_handshaking_until_both_finished_messages_are_exchanged_tls12() {
+    let (mut client, mut server) = make_pair_for_configs(
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]),
+        make_server_config(KeyType::Rsa),
+    );
 
-    // Returns the sender's next secrets to use, or the receiver's error.
-    fn step(
-        send: &mut dyn QuicExt,
-        recv: &mut dyn QuicExt,
-    ) -> Result<Option<quic::KeyChange>, Error> {
-        let mut buf = Vec::new();
-        let change = loop {
-            let prev = buf.len();
-            if let Some(x) = send.write_hs(&mut buf) {
-                break Some(x);
-            }
-            if prev == buf.len() {
-                break None;
-            }
-        };
-        if let Err(e) = recv.read_hs(&buf) {
-            return Err(e);
-        } else {
-            assert_eq!(recv.alert(), None);
-        }
+    // ClientHello
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(client.is_handshaking());
+    assert!(server.is_handshaking());
 
-        Ok(change)
-    }
+    // ServerHello ... ServerHelloDone
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert!(client.is_handshaking());
+    assert!(server.is_handshaking());
 
-    #[test]
-    fn test_quic_handshake() {
-        fn equal_packet_keys(x: &quic::PacketKey, y: &quic::PacketKey) -> bool {
-            // Check that these two sets of keys are equal.
-            let mut buf = vec![0; 32];
-            let (header, payload_tag) = buf.split_at_mut(8);
-            let (payload, tag_buf) = payload_tag.split_at_mut(8);
-            let tag = x
-                .encrypt_in_place(42, &*header, payload)
-                .unwrap();
-            tag_buf.copy_from_slice(tag.as_ref());
+    // ClientKeyExchange ... client Finished: now the server is done.
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(client.is_handshaking());
+    assert!(!server.is_handshaking());
 
-            let result = y.decrypt_in_place(42, &*header, payload_tag);
-            match result {
-                Ok(payload) => payload == &[0; 8],
-                Err(_) => false,
-            }
-        }
+    // server Finished
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert!(!client.is_handshaking());
+    assert!(!server.is_handshaking());
+}
 
-        fn compatible_keys(x: &quic::KeyChange, y: &quic::KeyChange) -> bool {
-            fn keys(kc: &quic::KeyChange) -> &quic::Keys {
-                match kc {
-                    quic::KeyChange::Handshake { keys } => keys,
-                    quic::KeyChange::OneRtt { keys, .. } => keys,
-                }
-            }
+#[test]
+fn client_complete_io_for_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-            let (x, y) = (keys(x), keys(y));
-            equal_packet_keys(&x.local.packet, &y.remote.packet)
-                && equal_packet_keys(&x.remote.packet, &y.local.packet)
-        }
+    assert!(client.is_handshaking());
+    let (rdlen, wrlen) = client
+        .complete_io(&mut OtherSession::new(&mut server))
+        .unwrap();
+    assert!(rdlen > 0 && wrlen > 0);
+    assert!(!client.is_handshaking());
+}
 
-        let kt = KeyType::Rsa;
-        let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-        client_config.enable_early_data = true;
-        let client_config = Arc::new(client_config);
-        let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
-        server_config.max_early_data_size = 0xffffffff;
-        let server_config = Arc::new(server_config);
-        let client_params = &b"client params"[..];
-        let server_params = &b"server params"[..];
+#[test]
+fn client_complete_io_for_handshake_eof() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    let mut input = io::Cursor::new(Vec::new());
 
-        // full handshake
-        let mut client = Connection::from(
-            ClientConnection::new_quic(
-                Arc::clone(&client_config),
-                quic::Version::V1,
-                dns_name("localhost"),
-                client_params.into(),
-            )
-            .unwrap(),
-        );
+    assert!(client.is_handshaking());
+    let err = client
+        .complete_io(&mut input)
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+}
 
-        let mut server = Connection::from(
-            ServerConnection::new_quic(
-                Arc::clone(&server_config),
-                quic::Version::V1,
-                server_params.into(),
-            )
-            .unwrap(),
-        );
+#[test]
+fn client_complete_io_for_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-        let client_initial = step(&mut client, &mut server).unwrap();
-        assert!(client_initial.is_none());
-        assert!(client.zero_rtt_keys().is_none());
-        assert_eq!(server.quic_transport_parameters(), Some(client_params));
-        let server_hs = step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        assert!(server.zero_rtt_keys().is_none());
-        let client_hs = step(&mut client, &mut server)
-            .unwrap()
-            .unwrap();
-        assert!(compatible_keys(&server_hs, &client_hs));
-        assert!(client.is_handshaking());
-        let server_1rtt = step(&mut server, &mut client)
-            .unwrap()
+        do_handshake(&mut client, &mut server);
+
+        client
+            .writer()
+            .write_all(b"01234567890123456789")
             .unwrap();
-        assert!(!client.is_handshaking());
-        assert_eq!(client.quic_transport_parameters(), Some(server_params));
-        assert!(server.is_handshaking());
-        let client_1rtt = step(&mut client, &mut server)
-            .unwrap()
+        client
+            .writer()
+            .write_all(b"01234567890123456789")
             .unwrap();
-        assert!(!server.is_handshaking());
-        assert!(compatible_keys(&server_1rtt, &client_1rtt));
-        assert!(!compatible_keys(&server_hs, &server_1rtt));
-        assert!(step(&mut client, &mut server)
-            .unwrap()
-            .is_none());
-        assert!(step(&mut server, &mut client)
-            .unwrap()
-            .is_none());
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
+            assert!(rdlen == 0 && wrlen > 0);
+            println!("{:?}", pipe.writevs);
+            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+        }
+        check_read(
+            &mut server.reader(),
+            b"0123456789012345678901234567890123456789",
+        );
+    }
+}
 
-        // 0-RTT handshake
-        let mut client = ClientConnection::new_quic(
-            Arc::clone(&client_config),
-            quic::Version::V1,
-            dns_name("localhost"),
-            client_params.into(),
-        )
-        .unwrap();
-        assert!(client
-            .negotiated_cipher_suite()
-            .is_some());
+#[test]
+fn client_complete_io_for_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-        let mut server = ServerConnection::new_quic(
-            Arc::clone(&server_config),
-            quic::Version::V1,
-            server_params.into(),
-        )
-        .unwrap();
+        do_handshake(&mut client, &mut server);
 
-        step(&mut client, &mut server).unwrap();
-        assert_eq!(client.quic_transport_parameters(), Some(server_params));
+        server
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
         {
-            let client_early = client.zero_rtt_keys().unwrap();
-            let server_early = server.zero_rtt_keys().unwrap();
-            assert!(equal_packet_keys(
-                &client_early.packet,
-                &server_early.packet
-            ));
+            let mut pipe = OtherSession::new(&mut server);
+            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
+            assert!(rdlen > 0 && wrlen == 0);
+            assert_eq!(pipe.reads, 1);
         }
-        step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        step(&mut client, &mut server)
-            .unwrap()
-            .unwrap();
-        step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        assert!(client.is_early_data_accepted());
+        check_read(&mut client.reader(), b"01234567890123456789");
+    }
+}
 
-        // 0-RTT rejection
-        {
-            let client_config = (*client_config).clone();
-            let mut client = ClientConnection::new_quic(
-                Arc::new(client_config),
-                quic::Version::V1,
-                dns_name("localhost"),
-                client_params.into(),
-            )
-            .unwrap();
+#[test]
+fn server_complete_io_for_handshake() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-            let mut server = ServerConnection::new_quic(
-                Arc::clone(&server_config),
-                quic::Version::V1,
-                server_params.into(),
-            )
+        assert!(server.is_handshaking());
+        let (rdlen, wrlen) = server
+            .complete_io(&mut OtherSession::new(&mut client))
             .unwrap();
+        assert!(rdlen > 0 && wrlen > 0);
+        assert!(!server.is_handshaking());
+    }
+}
 
-            step(&mut client, &mut server).unwrap();
-            assert_eq!(client.quic_transport_parameters(), Some(server_params));
-            assert!(client.zero_rtt_keys().is_some());
-            assert!(server.zero_rtt_keys().is_none());
-            step(&mut server, &mut client)
-                .unwrap()
-                .unwrap();
-            step(&mut client, &mut server)
-                .unwrap()
-                .unwrap();
-            step(&mut server, &mut client)
-                .unwrap()
-                .unwrap();
-            assert!(!client.is_early_data_accepted());
-        }
+#[test]
+fn server_complete_io_for_handshake_eof() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    let mut input = io::Cursor::new(Vec::new());
 
-        // failed handshake
-        let mut client = ClientConnection::new_quic(
-            client_config,
-            quic::Version::V1,
-            dns_name("example.com"),
-            client_params.into(),
-        )
-        .unwrap();
+    assert!(server.is_handshaking());
+    let err = server
+        .complete_io(&mut input)
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+}
 
-        let mut server =
-            ServerConnection::new_quic(server_config, quic::Version::V1, server_params.into())
-                .unwrap();
+#[test]
+fn server_complete_io_for_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-        step(&mut client, &mut server).unwrap();
-        step(&mut server, &mut client)
-            .unwrap()
+        do_handshake(&mut client, &mut server);
+
+        server
+            .writer()
+            .write_all(b"01234567890123456789")
             .unwrap();
-        assert!(step(&mut server, &mut client).is_err());
-        assert_eq!(
-            client.alert(),
-            Some(rustls::internal::msgs::enums::AlertDescription::BadCertificate)
+        server
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
+            assert!(rdlen == 0 && wrlen > 0);
+            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+        }
+        check_read(
+            &mut client.reader(),
+            b"0123456789012345678901234567890123456789",
         );
-
-        // Key updates
-
-        let (mut client_secrets, mut server_secrets) = match (client_1rtt, server_1rtt) {
-            (quic::KeyChange::OneRtt { next: c, .. }, quic::KeyChange::OneRtt { next: s, .. }) => {
-                (c, s)
-            }
-            _ => unreachable!(),
-        };
-
-        let mut client_next = client_secrets.next_packet_keys();
-        let mut server_next = server_secrets.next_packet_keys();
-        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
-        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
-
-        client_next = client_secrets.next_packet_keys();
-        server_next = server_secrets.next_packet_keys();
-        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
-        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
     }
+}
 
-    #[test]
-    fn test_quic_rejects_missing_alpn() {
-        let client_params = &b"client params"[..];
-        let server_params = &b"server params"[..];
-
-        for &kt in ALL_KEY_TYPES.iter() {
-            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-            let client_config = Arc::new(client_config);
+#[test]
+fn server_complete_io_for_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-            let mut server_config =
-                make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
-            server_config.alpn_protocols = vec!["foo".into()];
-            let server_config = Arc::new(server_config);
+        do_handshake(&mut client, &mut server);
 
-            let mut client = ClientConnection::new_quic(
-                client_config,
-                quic::Version::V1,
-                dns_name("localhost"),
-                client_params.into(),
-            )
+        client
+            .writer()
+            .write_all(b"01234567890123456789")
             .unwrap();
-            let mut server =
-                ServerConnection::new_quic(server_config, quic::Version::V1, server_params.into())
-                    .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
+            assert!(rdlen > 0 && wrlen == 0);
+            assert_eq!(pipe.reads, 1);
+        }
+        check_read(&mut server.reader(), b"01234567890123456789");
+    }
+}
 
-            assert_eq!(
-                step(&mut client, &mut server)
-                    .err()
-                    .unwrap(),
-                Error::NoApplicationProtocol
-            );
+#[test]
+fn client_stream_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-            assert_eq!(
-                server.alert(),
-                Some(rustls::internal::msgs::enums::AlertDescription::NoApplicationProtocol)
-            );
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let mut stream = Stream::new(&mut client, &mut pipe);
+            assert_eq!(stream.write(b"hello").unwrap(), 5);
         }
+        check_read(&mut server.reader(), b"hello");
     }
+}
 
-    #[test]
-    fn test_quic_no_tls13_error() {
-        let mut client_config =
-            make_client_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
-        client_config.alpn_protocols = vec!["foo".into()];
-        let client_config = Arc::new(client_config);
-
-        assert!(ClientConnection::new_quic(
-            client_config,
-            quic::Version::V1,
-            dns_name("localhost"),
-            b"client params".to_vec(),
-        )
-        .is_err());
+#[test]
+fn client_streamowned_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (client, mut server) = make_pair(*kt);
 
-        let mut server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
-        server_config.alpn_protocols = vec!["foo".into()];
-        let server_config = Arc::new(server_config);
-
-        assert!(ServerConnection::new_quic(
-            server_config,
-            quic::Version::V1,
-            b"server params".to_vec(),
-        )
-        .is_err());
+        {
+            let pipe = OtherSession::new(&mut server);
+            let mut stream = StreamOwned::new(client, pipe);
+            assert_eq!(stream.write(b"hello").unwrap(), 5);
+        }
+        check_read(&mut server.reader(), b"hello");
     }
+}
 
-    #[test]
-    fn test_quic_invalid_early_data_size() {
-        let mut server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
-        server_config.alpn_protocols = vec!["foo".into()];
+#[test]
+fn client_write_vectored_coalesces_records() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+        do_handshake(&mut client, &mut server);
 
-        let cases = [
-            (None, true),
-            (Some(0u32), true),
-            (Some(5), false),
-            (Some(0xffff_ffff), true),
-        ];
+        assert_eq!(
+            client
+                .writer()
+                .write_vectored(&[
+                    IoSlice::new(b"01234"),
+                    IoSlice::new(b"56789"),
+                    IoSlice::new(b"01234"),
+                ])
+                .unwrap(),
+            15
+        );
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            client.complete_io(&mut pipe).unwrap();
+            assert_eq!(pipe.writevs, vec![vec![37]]);
+        }
+        check_read(&mut server.reader(), b"012345678901234");
+    }
+}
 
-        for &(size, ok) in cases.iter() {
-            println!("early data size case: {:?}", size);
-            if let Some(new) = size {
-                server_config.max_early_data_size = new;
-            }
+#[test]
+fn client_streamowned_write_vectored() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (client, mut server) = make_pair(*kt);
 
-            let wrapped = Arc::new(server_config.clone());
+        {
+            let pipe = OtherSession::new(&mut server);
+            let mut stream = StreamOwned::new(client, pipe);
             assert_eq!(
-                ServerConnection::new_quic(wrapped, quic::Version::V1, b"server params".to_vec(),)
-                    .is_ok(),
-                ok
+                stream
+                    .write_vectored(&[IoSlice::new(b"hel"), IoSlice::new(b"lo")])
+                    .unwrap(),
+                5
             );
         }
+        check_read(&mut server.reader(), b"hello");
     }
+}
 
-    #[test]
-    fn test_quic_server_no_params_received() {
-        let server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
-        let server_config = Arc::new(server_config);
+#[test]
+fn client_stream_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-        let mut server =
-            ServerConnection::new_quic(server_config, quic::Version::V1, b"server params".to_vec())
-                .unwrap();
+        server
+            .writer()
+            .write_all(b"world")
+            .unwrap();
 
-        use ring::rand::SecureRandom;
-        use rustls::internal::msgs::base::PayloadU16;
-        use rustls::internal::msgs::enums::{
-            CipherSuite, Compression, HandshakeType, NamedGroup, SignatureScheme,
-        };
-        use rustls::internal::msgs::handshake::{
-            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionID,
-        };
-        use rustls::internal::msgs::message::PlainMessage;
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let mut stream = Stream::new(&mut client, &mut pipe);
+            check_read(&mut stream, b"world");
+        }
+    }
+}
 
-        let rng = ring::rand::SystemRandom::new();
-        let mut random = [0; 32];
-        rng.fill(&mut random).unwrap();
-        let random = Random::from(random);
+#[test]
+fn client_streamowned_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (client, mut server) = make_pair(*kt);
 
-        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
-            .unwrap()
-            .compute_public_key()
+        server
+            .writer()
+            .write_all(b"world")
             .unwrap();
 
-        let client_hello = Message {
-            version: ProtocolVersion::TLSv1_3,
-            payload: MessagePayload::Handshake(HandshakeMessagePayload {
-                typ: HandshakeType::ClientHello,
-                payload: HandshakePayload::ClientHello(ClientHelloPayload {
-                    client_version: ProtocolVersion::TLSv1_3,
-                    random,
-                    session_id: SessionID::random().unwrap(),
-                    cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
-                    compression_methods: vec![Compression::Null],
-                    extensions: vec![
-                        ClientExtension::SupportedVersions(vec![ProtocolVersion::TLSv1_3]),
-                        ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
-                        ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
-                        ClientExtension::KeyShare(vec![KeyShareEntry {
-                            group: NamedGroup::X25519,
-                            payload: PayloadU16::new(kx.as_ref().to_vec()),
-                        }]),
-                    ],
-                }),
-            }),
-        };
+        {
+            let pipe = OtherSession::new(&mut server);
+            let mut stream = StreamOwned::new(client, pipe);
+            check_read(&mut stream, b"world");
+        }
+    }
+}
 
-        let buf = PlainMessage::from(client_hello)
-            .into_unencrypted_opaque()
-            .encode();
-        server
-            .read_tls(&mut buf.as_slice())
-            .unwrap();
-        assert_eq!(
-            server.process_new_packets().err(),
-            Some(Error::PeerMisbehavedError(
-                "QUIC transport parameters not found".into(),
-            )),
-        );
+#[test]
+fn server_stream_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let mut stream = Stream::new(&mut server, &mut pipe);
+            assert_eq!(stream.write(b"hello").unwrap(), 5);
+        }
+        check_read(&mut client.reader(), b"hello");
     }
+}
 
-    #[test]
-    fn test_quic_server_no_tls12() {
-        let mut server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
-        server_config.alpn_protocols = vec!["foo".into()];
-        let server_config = Arc::new(server_config);
+#[test]
+fn server_streamowned_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, server) = make_pair(*kt);
 
-        use ring::rand::SecureRandom;
-        use rustls::internal::msgs::base::PayloadU16;
-        use rustls::internal::msgs::enums::{
-            CipherSuite, Compression, HandshakeType, NamedGroup, SignatureScheme,
-        };
-        use rustls::internal::msgs::handshake::{
-            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionID,
-        };
-        use rustls::internal::msgs::message::PlainMessage;
+        {
+            let pipe = OtherSession::new(&mut client);
+            let mut stream = StreamOwned::new(server, pipe);
+            assert_eq!(stream.write(b"hello").unwrap(), 5);
+        }
+        check_read(&mut client.reader(), b"hello");
+    }
+}
 
-        let rng = ring::rand::SystemRandom::new();
-        let mut random = [0; 32];
-        rng.fill(&mut random).unwrap();
-        let random = Random::from(random);
+#[test]
+fn server_stream_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
-            .unwrap()
-            .compute_public_key()
+        client
+            .writer()
+            .write_all(b"world")
             .unwrap();
 
-        let mut server =
-            ServerConnection::new_quic(server_config, quic::Version::V1, b"server params".to_vec())
-                .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let mut stream = Stream::new(&mut server, &mut pipe);
+            check_read(&mut stream, b"world");
+        }
+    }
+}
 
-        let client_hello = Message {
-            version: ProtocolVersion::TLSv1_2,
-            payload: MessagePayload::Handshake(HandshakeMessagePayload {
-                typ: HandshakeType::ClientHello,
-                payload: HandshakePayload::ClientHello(ClientHelloPayload {
-                    client_version: ProtocolVersion::TLSv1_2,
-                    random: random.clone(),
-                    session_id: SessionID::random().unwrap(),
-                    cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
-                    compression_methods: vec![Compression::Null],
-                    extensions: vec![
-                        ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
-                        ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
-                        ClientExtension::KeyShare(vec![KeyShareEntry {
-                            group: NamedGroup::X25519,
-                            payload: PayloadU16::new(kx.as_ref().to_vec()),
-                        }]),
-                    ],
-                }),
-            }),
-        };
+#[test]
+fn server_streamowned_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, server) = make_pair(*kt);
 
-        let buf = PlainMessage::from(client_hello)
-            .into_unencrypted_opaque()
-            .encode();
-        server
-            .read_tls(&mut buf.as_slice())
+        client
+            .writer()
+            .write_all(b"world")
             .unwrap();
-        assert_eq!(
-            server.process_new_packets().err(),
-            Some(Error::PeerIncompatibleError(
-                "Server requires TLS1.3, but client omitted versions ext".into(),
-            )),
-        );
+
+        {
+            let pipe = OtherSession::new(&mut client);
+            let mut stream = StreamOwned::new(server, pipe);
+            check_read(&mut stream, b"world");
+        }
     }
+}
 
-    #[test]
-    fn packet_key_api() {
+#[test]
+fn client_stream_reads_lines() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        server
+            .writer()
+            .write_all(b"220 ready\r\n250-first")
+            .unwrap();
+        server
+            .writer()
+            .write_all(b"\r\n250 last\r\n")
+            .unwrap();
+        server.send_close_notify();
+
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let mut stream = Stream::new(&mut client, &mut pipe);
+            let mut line = String::new();
+            assert_eq!(stream.read_line(&mut line).unwrap(), 11);
+            assert_eq!(line, "220 ready\r\n");
+
+            let mut line = Vec::new();
+            stream
+                .read_until(b'\n', &mut line)
+                .unwrap();
+            assert_eq!(line, b"250-first\r\n");
+
+            let lines = stream
+                .lines()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(lines, vec!["250 last".to_string()]);
+        }
+    }
+}
+
+#[test]
+fn server_streamowned_reads_lines() {
+    let (mut client, server) = make_pair(KeyType::Rsa);
+
+    client
+        .writer()
+        .write_all(b"NICK rustls\r\nUSER rustls 0 * :rustls\r\n")
+        .unwrap();
+
+    let pipe = OtherSession::new(&mut client);
+    let mut stream = StreamOwned::new(server, pipe);
+    let mut line = String::new();
+    stream.read_line(&mut line).unwrap();
+    assert_eq!(line, "NICK rustls\r\n");
+    assert_eq!(
+        stream.conn.reader().fill_buf().unwrap(),
+        b"USER rustls 0 * :rustls\r\n"
+    );
+    line.clear();
+    stream.read_line(&mut line).unwrap();
+    assert_eq!(line, "USER rustls 0 * :rustls\r\n");
+}
+
+struct FailsWrites {
+    errkind: io::ErrorKind,
+    after: usize,
+}
+
+impl io::Read for FailsWrites {
+    fn read(&mut self, _b: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl io::Write for FailsWrites {
+    fn write(&mut self, b: &[u8]) -> io::Result<usize> {
+        if self.after > 0 {
+            self.after -= 1;
+            Ok(b.len())
+        } else {
+            Err(io::Error::new(self.errkind, "oops"))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn stream_write_reports_underlying_io_error_before_plaintext_processed() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    let mut pipe = FailsWrites {
+        errkind: io::ErrorKind::ConnectionAborted,
+        after: 0,
+    };
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    let mut client_stream = Stream::new(&mut client, &mut pipe);
+    let rc = client_stream.write(b"world");
+    assert!(rc.is_err());
+    let err = rc.err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn stream_write_swallows_underlying_io_error_after_plaintext_processed() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    let mut pipe = FailsWrites {
+        errkind: io::ErrorKind::ConnectionAborted,
+        after: 1,
+    };
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    let mut client_stream = Stream::new(&mut client, &mut pipe);
+    let rc = client_stream.write(b"world");
+    assert_eq!(format!("{:?}", rc), "Ok(5)");
+}
+
+#[test]
+fn stream_write_vectored_respects_backpressure() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+    client.set_buffer_limit(Some(32));
+
+    let mut pipe = FailsWrites {
+        errkind: io::ErrorKind::WouldBlock,
+        after: 0,
+    };
+    {
+        let mut client_stream = Stream::new(&mut client, &mut pipe);
+        let bufs = [
+            IoSlice::new(b"01234567890123456789"),
+            IoSlice::new(b"01234567890123456789"),
+        ];
+        assert_eq!(
+            client_stream
+                .write_vectored(&bufs)
+                .unwrap(),
+            32
+        );
+
+        let err = client_stream
+            .write_vectored(&bufs)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"01234567890123456789012345678901");
+}
+
+fn make_disjoint_suite_configs() -> (ClientConfig, ServerConfig) {
+    let kt = KeyType::Rsa;
+    let server_config = finish_server_config(
+        kt,
+        ServerConfig::builder()
+            .with_cipher_suites(&[rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+
+    let client_config = finish_client_config(
+        kt,
+        ClientConfig::builder()
+            .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+
+    (client_config, server_config)
+}
+
+#[test]
+fn client_stream_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    {
+        let mut pipe = OtherSession::new_fails(&mut server);
+        let mut client_stream = Stream::new(&mut client, &mut pipe);
+        let rc = client_stream.write(b"hello");
+        assert!(rc.is_err());
+        assert_eq!(
+            format!("{:?}", rc),
+            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+        );
+        let rc = client_stream.write(b"hello");
+        assert!(rc.is_err());
+        assert_eq!(
+            format!("{:?}", rc),
+            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+        );
+    }
+}
+
+#[test]
+fn client_streamowned_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    let pipe = OtherSession::new_fails(&mut server);
+    let mut client_stream = StreamOwned::new(client, pipe);
+    let rc = client_stream.write(b"hello");
+    assert!(rc.is_err());
+    assert_eq!(
+        format!("{:?}", rc),
+        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+    );
+    let rc = client_stream.write(b"hello");
+    assert!(rc.is_err());
+    assert_eq!(
+        format!("{:?}", rc),
+        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+    );
+}
+
+#[test]
+fn server_stream_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    client
+        .writer()
+        .write_all(b"world")
+        .unwrap();
+
+    {
+        let mut pipe = OtherSession::new_fails(&mut client);
+        let mut server_stream = Stream::new(&mut server, &mut pipe);
+        let mut bytes = [0u8; 5];
+        let rc = server_stream.read(&mut bytes);
+        assert!(rc.is_err());
+        assert_eq!(
+            format!("{:?}", rc),
+            "Err(Custom { kind: InvalidData, error: PeerIncompatibleError(\"no ciphersuites in common\") })"
+        );
+    }
+}
+
+#[test]
+fn server_streamowned_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, server) = make_pair_for_configs(client_config, server_config);
+
+    client
+        .writer()
+        .write_all(b"world")
+        .unwrap();
+
+    let pipe = OtherSession::new_fails(&mut client);
+    let mut server_stream = StreamOwned::new(server, pipe);
+    let mut bytes = [0u8; 5];
+    let rc = server_stream.read(&mut bytes);
+    assert!(rc.is_err());
+    assert_eq!(
+        format!("{:?}", rc),
+        "Err(Custom { kind: InvalidData, error: PeerIncompatibleError(\"no ciphersuites in common\") })"
+    );
+}
+
+#[test]
+fn server_config_is_clone() {
+    let _ = make_server_config(KeyType::Rsa);
+}
+
+#[test]
+fn client_config_is_clone() {
+    let _ = make_client_config(KeyType::Rsa);
+}
+
+#[test]
+fn client_connection_is_debug() {
+    let (client, _) = make_pair(KeyType::Rsa);
+    println!("{:?}", client);
+}
+
+#[test]
+fn server_connection_is_debug() {
+    let (_, server) = make_pair(KeyType::Rsa);
+    println!("{:?}", server);
+}
+
+#[test]
+fn server_complete_io_for_handshake_ending_with_alert() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    assert!(server.is_handshaking());
+
+    let mut pipe = OtherSession::new_fails(&mut client);
+    let rc = server.complete_io(&mut pipe);
+    assert!(rc.is_err(), "server io failed due to handshake failure");
+    assert!(!server.wants_write(), "but server did send its alert");
+    assert_eq!(
+        format!("{:?}", pipe.last_error),
+        "Some(AlertReceived(HandshakeFailure))",
+        "which was received by client"
+    );
+}
+
+#[test]
+fn server_exposes_offered_sni() {
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("second.testserver.com"))
+                .unwrap();
+        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
+
+        assert_eq!(None, server.sni_hostname());
+        do_handshake(&mut client, &mut server);
+        assert_eq!(Some("second.testserver.com"), server.sni_hostname());
+    }
+}
+
+#[test]
+fn server_exposes_offered_sni_smashed_to_lowercase() {
+    // webpki actually does this for us in its DnsName type
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("SECOND.TESTServer.com"))
+                .unwrap();
+        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
+
+        assert_eq!(None, server.sni_hostname());
+        do_handshake(&mut client, &mut server);
+        assert_eq!(Some("second.testserver.com"), server.sni_hostname());
+    }
+}
+
+#[test]
+fn server_exposes_offered_sni_even_if_resolver_fails() {
+    let kt = KeyType::Rsa;
+    let resolver = rustls::server::ResolvesServerCertUsingSni::new();
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("thisdoesNOTexist.com"))
+                .unwrap();
+
+        assert_eq!(None, server.sni_hostname());
+        transfer(&mut client, &mut server);
+        assert_eq!(
+            server.process_new_packets(),
+            Err(Error::General(
+                "no server certificate chain resolved".to_string()
+            ))
+        );
+        assert_eq!(Some("thisdoesnotexist.com"), server.sni_hostname());
+    }
+}
+
+#[test]
+fn sni_resolver_works() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+    resolver
+        .add(
+            "localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone()),
+        )
+        .unwrap();
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client1 =
+        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("localhost")).unwrap();
+    let err = do_handshake_until_error(&mut client1, &mut server1);
+    assert_eq!(err, Ok(()));
+
+    let mut server2 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client2 =
+        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("notlocalhost")).unwrap();
+    let err = do_handshake_until_error(&mut client2, &mut server2);
+    assert_eq!(
+        err,
+        Err(ErrorFromPeer::Server(Error::General(
+            "no server certificate chain resolved".into()
+        )))
+    );
+}
+
+#[test]
+fn server_reports_selected_certificate() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+    resolver
+        .add(
+            "localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone()),
+        )
+        .unwrap();
+    resolver
+        .add(
+            "xn--bcher-kva.example",
+            sign::CertifiedKey::new(kt.get_idn_chain(), signing_key.clone()),
+        )
+        .unwrap();
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    for (name, chain) in &[
+        ("localhost", kt.get_chain()),
+        ("xn--bcher-kva.example", kt.get_idn_chain()),
+    ] {
+        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+        let mut client =
+            ClientConnection::new(Arc::new(make_client_config(kt)), dns_name(name)).unwrap();
+        assert_eq!(server.get_selected_certificate(), None);
+
+        do_handshake(&mut client, &mut server);
+        assert_eq!(server.get_selected_certificate(), Some(&chain[..]));
+        assert_eq!(client.peer_certificates(), Some(&chain[..]));
+    }
+}
+
+#[test]
+fn sni_resolver_rejects_wrong_names() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+
+    assert_eq!(
+        Ok(()),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+    assert_eq!(
+        Err(Error::General(
+            "The server certificate is not valid for the given name".into()
+        )),
+        resolver.add(
+            "not-localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+    assert_eq!(
+        Err(Error::General("Bad DNS name".into())),
+        resolver.add(
+            "not ascii 🦀",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+}
+
+#[test]
+fn sni_resolver_rejects_bad_certs() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+
+    assert_eq!(
+        Err(Error::General(
+            "No end-entity certificate in certificate chain".into()
+        )),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(vec![], signing_key.clone())
+        )
+    );
+
+    let bad_chain = vec![rustls::Certificate(vec![0xa0])];
+    assert_eq!(
+        Err(Error::General(
+            "End-entity certificate in certificate chain is syntactically invalid".into()
+        )),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(bad_chain, signing_key.clone())
+        )
+    );
+}
+
+fn do_exporter_test(client_config: ClientConfig, server_config: ServerConfig) {
+    let mut client_secret = [0u8; 64];
+    let mut server_secret = [0u8; 64];
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    assert_eq!(
+        Err(Error::HandshakeNotComplete),
+        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
+    );
+    assert_eq!(
+        Err(Error::HandshakeNotComplete),
+        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
+    );
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(
+        Ok(()),
+        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
+    );
+    assert_eq!(
+        Ok(()),
+        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
+    );
+    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+
+    assert_eq!(
+        Ok(()),
+        client.export_keying_material(&mut client_secret, b"label", None)
+    );
+    assert_ne!(client_secret.to_vec(), server_secret.to_vec());
+    assert_eq!(
+        Ok(()),
+        server.export_keying_material(&mut server_secret, b"label", None)
+    );
+    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_tls12_exporter() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS12]);
+        let server_config = make_server_config(*kt);
+
+        do_exporter_test(client_config, server_config);
+    }
+}
+
+#[test]
+fn test_tls13_exporter() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+        let server_config = make_server_config(*kt);
+
+        do_exporter_test(client_config, server_config);
+    }
+}
+
+fn client_sends(
+    client: &mut ClientConnection,
+    server: &mut ServerConnection,
+    data: &[u8],
+) -> usize {
+    client.writer().write_all(data).unwrap();
+    let len = transfer(client, server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), data);
+    len
+}
+
+fn server_sends(
+    server: &mut ServerConnection,
+    client: &mut ClientConnection,
+    data: &[u8],
+) -> usize {
+    server.writer().write_all(data).unwrap();
+    let len = transfer(server, client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client.reader(), data);
+    len
+}
+
+#[test]
+fn test_tls13_refresh_traffic_keys() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for client_refreshes in [true, false].iter() {
+            let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+            let server_config = make_server_config(*kt);
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+            assert_eq!(
+                client.refresh_traffic_keys(),
+                Err(Error::HandshakeNotComplete)
+            );
+            do_handshake(&mut client, &mut server);
+
+            let client_len = client_sends(&mut client, &mut server, b"ping");
+            let server_len = server_sends(&mut server, &mut client, b"pong");
+
+            // Each side's next flight is preceded by a KeyUpdate: first the
+            // one we asked for, and then the peer's answer.
+            if *client_refreshes {
+                client.refresh_traffic_keys().unwrap();
+                assert!(client_sends(&mut client, &mut server, b"ping") > client_len);
+                assert!(server_sends(&mut server, &mut client, b"pong") > server_len);
+            } else {
+                server.refresh_traffic_keys().unwrap();
+                assert!(server_sends(&mut server, &mut client, b"pong") > server_len);
+                assert!(client_sends(&mut client, &mut server, b"ping") > client_len);
+            }
+
+            assert_eq!(client_sends(&mut client, &mut server, b"ping"), client_len);
+            assert_eq!(server_sends(&mut server, &mut client, b"pong"), server_len);
+        }
+    }
+}
+
+#[test]
+fn test_tls13_key_update_threshold() {
+    for client_has_threshold in [true, false].iter() {
+        let mut client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+        let mut server_config = make_server_config(KeyType::Rsa);
+        if *client_has_threshold {
+            client_config.key_update_threshold = Some(2);
+        } else {
+            server_config.key_update_threshold = Some(2);
+        }
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        // The server has already sent tickets under its traffic key, so
+        // start from a key update on both sides.
+        client.refresh_traffic_keys().unwrap();
+        client_sends(&mut client, &mut server, b"ping");
+        server_sends(&mut server, &mut client, b"pong");
+
+        // Two records under each key, so every other write starts with a
+        // KeyUpdate; the peer decrypting it shows the keys changed in step.
+        let client_len = client_sends(&mut client, &mut server, b"ping");
+        let server_len = server_sends(&mut server, &mut client, b"pong");
+        for _ in 0..3 {
+            if *client_has_threshold {
+                assert!(client_sends(&mut client, &mut server, b"ping") > client_len);
+                assert_eq!(client_sends(&mut client, &mut server, b"ping"), client_len);
+                assert_eq!(server_sends(&mut server, &mut client, b"pong"), server_len);
+            } else {
+                assert!(server_sends(&mut server, &mut client, b"pong") > server_len);
+                assert_eq!(server_sends(&mut server, &mut client, b"pong"), server_len);
+                assert_eq!(client_sends(&mut client, &mut server, b"ping"), client_len);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_tls13_key_update_threshold_within_one_write() {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.key_update_threshold = Some(2);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+    client.refresh_traffic_keys().unwrap();
+    client_sends(&mut client, &mut server, b"ping");
+
+    // One record has been sent under the current key.  A single write of
+    // five full records must still update the key after every two.
+    let data = vec![0x55u8; 5 * 16384];
+    client.set_buffer_limit(None);
+    assert_eq!(client.writer().write(&data).unwrap(), data.len());
+
+    let full = 5 + 16384 + 1 + 16;
+    let key_update = 5 + 4 + 1 + 1 + 16;
+    assert_eq!(
+        transfer_records(&mut client, &mut server),
+        vec![full, key_update, full, full, key_update, full, full]
+    );
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), &data);
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_tls12_refresh_traffic_keys_fails() {
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let server_config = make_server_config(KeyType::Rsa);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    let expect = Err(Error::General(
+        "key updates are not supported in TLS1.2".into(),
+    ));
+    assert_eq!(client.refresh_traffic_keys(), expect);
+    assert_eq!(server.refresh_traffic_keys(), expect);
+}
+
+#[test]
+fn test_tls13_channel_binding_tls_exporter() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+        let server_config = make_server_config(*kt);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+        let mut client_binding = Vec::new();
+        let mut server_binding = Vec::new();
+        assert_eq!(
+            Err(Error::HandshakeNotComplete),
+            client.get_channel_binding(ChannelBindingKind::TlsExporter, &mut client_binding)
+        );
+
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(
+            Ok(()),
+            client.get_channel_binding(ChannelBindingKind::TlsExporter, &mut client_binding)
+        );
+        assert_eq!(
+            Ok(()),
+            server.get_channel_binding(ChannelBindingKind::TlsExporter, &mut server_binding)
+        );
+        assert_eq!(client_binding.len(), 32);
+        assert_eq!(client_binding, server_binding);
+
+        let mut exported = [0u8; 32];
+        client
+            .export_keying_material(&mut exported, b"EXPORTER-Channel-Binding", Some(b""))
+            .unwrap();
+        assert_eq!(client_binding, exported.to_vec());
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_tls12_channel_binding_tls_exporter_is_refused() {
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let server_config = make_server_config(KeyType::Rsa);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    let mut binding = Vec::new();
+    assert!(client
+        .get_channel_binding(ChannelBindingKind::TlsExporter, &mut binding)
+        .is_err());
+    assert!(server
+        .get_channel_binding(ChannelBindingKind::TlsExporter, &mut binding)
+        .is_err());
+    assert!(binding.is_empty());
+}
+
+#[test]
+fn test_channel_binding_tls_server_end_point() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let server_config = make_server_config(*kt);
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+            let mut client_binding = Vec::new();
+            let mut server_binding = Vec::new();
+            assert_eq!(
+                Err(Error::HandshakeNotComplete),
+                client.get_channel_binding(
+                    ChannelBindingKind::TlsServerEndPoint,
+                    &mut client_binding
+                )
+            );
+
+            do_handshake(&mut client, &mut server);
+
+            let result = client
+                .get_channel_binding(ChannelBindingKind::TlsServerEndPoint, &mut client_binding);
+            if *kt == KeyType::Ed25519 {
+                // No hash function is defined for ed25519 certificates.
+                assert!(result.is_err());
+                continue;
+            }
+            assert_eq!(Ok(()), result);
+            assert_eq!(
+                Ok(()),
+                server.get_channel_binding(
+                    ChannelBindingKind::TlsServerEndPoint,
+                    &mut server_binding
+                )
+            );
+            assert_eq!(client_binding, server_binding);
+            assert_eq!(client_binding.len(), 32);
+        }
+    }
+}
+
+/// Moves all pending TLS data from `left` to `right`, and returns the
+/// payloads of the handshake records among it.
+fn transfer_recording_handshake(
+    left: &mut (impl DerefMut + Deref<Target = ConnectionCommon<impl SideData>>),
+    right: &mut (impl DerefMut + Deref<Target = ConnectionCommon<impl SideData>>),
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    while left.wants_write() {
+        left.write_tls(&mut buf).unwrap();
+    }
+
+    let mut handshake = Vec::new();
+    let mut offs = 0;
+    while offs < buf.len() {
+        let len = u16::from_be_bytes([buf[offs + 3], buf[offs + 4]]) as usize;
+        if buf[offs] == rustls::internal::msgs::enums::ContentType::Handshake.get_u8() {
+            handshake.extend_from_slice(&buf[offs + 5..offs + 5 + len]);
+        }
+        offs += 5 + len;
+    }
+
+    let mut rd = &buf[..];
+    while !rd.is_empty() {
+        right.read_tls(&mut rd).unwrap();
+    }
+    handshake
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_handshake_transcript_hash_matches_handshake_messages() {
+    use rustls::crypto::ring::Ring;
+    use rustls::crypto::{CryptoProvider, HashAlgorithm};
+
+    let client_config = finish_client_config(
+        KeyType::Rsa,
+        ClientConfig::builder()
+            .with_cipher_suites(&[find_suite(
+                CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            )])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+    let server_config = make_server_config(KeyType::Rsa);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    assert_eq!(client.get_handshake_transcript_hash(), None);
+    assert_eq!(server.get_handshake_transcript_hash(), None);
+
+    // In TLS1.2 the server's first flight is unencrypted, so the whole
+    // transcript so far is visible on the wire.
+    let client_hello = transfer_recording_handshake(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    let server_flight = transfer_recording_handshake(&mut server, &mut client);
+
+    let mut expected = Ring.start_hash(HashAlgorithm::Sha256);
+    expected.update(&client_hello);
+    expected.update(&server_flight);
+    let expected = Some((HashAlgorithm::Sha256, expected.finish().as_ref().to_vec()));
+
+    assert_eq!(server.get_handshake_transcript_hash(), expected);
+    // Reading the hash leaves the transcript alone.
+    assert_eq!(server.get_handshake_transcript_hash(), expected);
+
+    client.process_new_packets().unwrap();
+    do_handshake(&mut client, &mut server);
+    assert!(!client.is_handshaking() && !server.is_handshaking());
+}
+
+#[test]
+fn test_handshake_transcript_hash_agrees_after_handshake() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let server_config = make_server_config(*kt);
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+            do_handshake(&mut client, &mut server);
+
+            let client_hash = client.get_handshake_transcript_hash();
+            let (alg, hash) = client_hash.clone().unwrap();
+            assert_eq!(hash.len(), alg.output_len());
+            assert_eq!(client_hash, server.get_handshake_transcript_hash());
+        }
+    }
+}
+
+/// A stand-in for a real compression algorithm: "compresses" by xoring
+/// each byte with the algorithm number.  Counts how often it is used.
+struct MockCertCompression {
+    alg: CertCompressionAlgorithm,
+    extra_bytes: usize,
+    missing_bytes: usize,
+    used: AtomicUsize,
+}
+
+impl MockCertCompression {
+    fn new(alg: CertCompressionAlgorithm) -> Arc<Self> {
+        Self::with_length_error(alg, 0, 0)
+    }
+
+    /// Produces `extra_bytes` more (or `missing_bytes` fewer) bytes of
+    /// output than the uncompressed length it reports, simulating a peer
+    /// which lies about it.
+    fn with_length_error(
+        alg: CertCompressionAlgorithm,
+        extra_bytes: usize,
+        missing_bytes: usize,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            alg,
+            extra_bytes,
+            missing_bytes,
+            used: AtomicUsize::new(0),
+        })
+    }
+
+    fn key(&self) -> u8 {
+        self.alg.get_u16() as u8
+    }
+
+    fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+}
+
+impl compress::CertCompressor for MockCertCompression {
+    fn compress(&self, input: &[u8]) -> Result<Vec<u8>, compress::CompressionFailed> {
+        self.used.fetch_add(1, Ordering::SeqCst);
+        let mut output: Vec<u8> = input
+            .iter()
+            .map(|b| b ^ self.key())
+            .collect();
+        output.resize(output.len() + self.extra_bytes, 0);
+        output.truncate(output.len() - self.missing_bytes);
+        Ok(output)
+    }
+
+    fn algorithm(&self) -> CertCompressionAlgorithm {
+        self.alg
+    }
+}
+
+impl compress::CertDecompressor for MockCertCompression {
+    fn decompress(
+        &self,
+        input: &[u8],
+        output: &mut [u8],
+    ) -> Result<usize, compress::DecompressionFailed> {
+        self.used.fetch_add(1, Ordering::SeqCst);
+        if input.len() > output.len() {
+            return Err(compress::DecompressionFailed);
+        }
+        for (out, b) in output.iter_mut().zip(input) {
+            *out = b ^ self.key();
+        }
+        Ok(input.len())
+    }
+
+    fn algorithm(&self) -> CertCompressionAlgorithm {
+        self.alg
+    }
+}
+
+static ALL_CERT_COMPRESSION_ALGS: [CertCompressionAlgorithm; 3] = [
+    CertCompressionAlgorithm::Zlib,
+    CertCompressionAlgorithm::Brotli,
+    CertCompressionAlgorithm::Zstd,
+];
+
+#[test]
+fn test_server_cert_compression() {
+    for alg in ALL_CERT_COMPRESSION_ALGS.iter() {
+        let compressor = MockCertCompression::new(*alg);
+        let decompressor = MockCertCompression::new(*alg);
+
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.cert_compressors = vec![compressor.clone()];
+        let mut client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+        client_config.cert_decompressors = vec![decompressor.clone()];
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(compressor.used(), 1);
+        assert_eq!(decompressor.used(), 1);
+        assert_eq!(
+            client.peer_certificates(),
+            Some(&KeyType::Rsa.get_chain()[..])
+        );
+    }
+}
+
+#[test]
+fn test_client_cert_compression() {
+    for alg in ALL_CERT_COMPRESSION_ALGS.iter() {
+        let compressor = MockCertCompression::new(*alg);
+        let decompressor = MockCertCompression::new(*alg);
+
+        let mut server_config = make_server_config_with_mandatory_client_auth(KeyType::Rsa);
+        server_config.cert_decompressors = vec![decompressor.clone()];
+        let mut client_config =
+            make_client_config_with_versions_with_auth(KeyType::Rsa, &[&rustls::version::TLS13]);
+        client_config.cert_compressors = vec![compressor.clone()];
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(compressor.used(), 1);
+        assert_eq!(decompressor.used(), 1);
+        assert_eq!(
+            server.peer_certificates(),
+            Some(&KeyType::Rsa.get_client_chain()[..])
+        );
+    }
+}
+
+#[test]
+fn test_cert_compression_requires_common_algorithm() {
+    let compressor = MockCertCompression::new(CertCompressionAlgorithm::Zlib);
+    let decompressor = MockCertCompression::new(CertCompressionAlgorithm::Zstd);
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_compressors = vec![compressor.clone()];
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.cert_decompressors = vec![decompressor.clone()];
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(compressor.used(), 0);
+    assert_eq!(decompressor.used(), 0);
+}
+
+fn check_cert_compression_length_error(extra_bytes: usize, missing_bytes: usize) {
+    let compressor = MockCertCompression::with_length_error(
+        CertCompressionAlgorithm::Zlib,
+        extra_bytes,
+        missing_bytes,
+    );
+    let decompressor = MockCertCompression::new(CertCompressionAlgorithm::Zlib);
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_compressors = vec![compressor];
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.cert_decompressors = vec![decompressor.clone()];
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert_eq!(
+        do_handshake_until_both_error(&mut client, &mut server),
+        Err(vec![
+            ErrorFromPeer::Client(Error::CorruptMessagePayload(
+                rustls::internal::msgs::enums::ContentType::Handshake
+            )),
+            ErrorFromPeer::Server(Error::AlertReceived(
+                rustls::internal::msgs::enums::AlertDescription::DecodeError
+            )),
+        ])
+    );
+    assert_eq!(decompressor.used(), 1);
+}
+
+#[test]
+fn test_cert_compression_overlong_output_is_rejected() {
+    check_cert_compression_length_error(1, 0);
+}
+
+#[test]
+fn test_cert_compression_short_output_is_rejected() {
+    check_cert_compression_length_error(0, 1);
+}
+
+#[test]
+fn server_rejects_oversized_handshake_message() {
+    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
+
+    // A record holding the start of a ClientHello claiming to be 64KB long.
+    let mut record = vec![0x16, 0x03, 0x01, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00];
+    record.extend_from_slice(&[0u8; 4]);
+    server
+        .read_tls(&mut &record[..])
+        .unwrap();
+    assert_eq!(
+        server.process_new_packets().err(),
+        Some(Error::CorruptMessagePayload(
+            rustls::internal::msgs::enums::ContentType::Handshake
+        ))
+    );
+
+    // fatal decode_error alert
+    let mut sent = Vec::new();
+    server.write_tls(&mut sent).unwrap();
+    assert_eq!(&sent[sent.len() - 2..], &[0x02, 0x32]);
+}
+
+#[test]
+fn server_rejects_handshake_message_in_too_many_records() {
+    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
+
+    // A 4KB ClientHello, sent one byte per record.
+    let mut client_hello = vec![0x01, 0x00, 0x10, 0x00];
+    client_hello.resize(4 + 0x1000, 0);
+    let records = client_hello
+        .iter()
+        .map(|byte| vec![0x16, 0x03, 0x01, 0x00, 0x01, *byte])
+        .collect::<Vec<_>>();
+
+    // Up to 1024 records are accepted...
+    server
+        .read_tls(&mut &records[..1024].concat()[..])
+        .unwrap();
+    server.process_new_packets().unwrap();
+
+    // ...but no more.
+    server
+        .read_tls(&mut &records[1024][..])
+        .unwrap();
+    assert_eq!(
+        server.process_new_packets().err(),
+        Some(Error::CorruptMessagePayload(
+            rustls::internal::msgs::enums::ContentType::Handshake
+        ))
+    );
+
+    // fatal decode_error alert
+    let mut sent = Vec::new();
+    server.write_tls(&mut sent).unwrap();
+    assert_eq!(&sent[sent.len() - 2..], &[0x02, 0x32]);
+}
+
+#[test]
+fn client_processes_alert_received_before_garbage() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+
+    // A fatal handshake_failure alert, then bytes which are not TLS.
+    let mut data = vec![0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28];
+    data.extend_from_slice(b"HTTP/1.1 400 Bad Request\r\n");
+    client.read_tls(&mut &data[..]).unwrap();
+    assert_eq!(
+        client.process_new_packets().err(),
+        Some(Error::AlertReceived(
+            rustls::internal::msgs::enums::AlertDescription::HandshakeFailure
+        ))
+    );
+}
+
+#[test]
+fn client_reports_garbage_after_processing_earlier_records() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+
+    // A warning close_notify alert, then bytes which are not TLS.
+    let mut data = vec![0x15, 0x03, 0x03, 0x00, 0x02, 0x01, 0x00];
+    data.extend_from_slice(b"HTTP/1.1 400 Bad Request\r\n");
+    client.read_tls(&mut &data[..]).unwrap();
+    assert_eq!(
+        client.process_new_packets().err(),
+        Some(Error::CorruptMessage)
+    );
+    assert_eq!(
+        client.process_new_packets().err(),
+        Some(Error::CorruptMessage)
+    );
+}
+
+fn make_server_config_with_chain(kt: KeyType, chain: Vec<Certificate>) -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(chain, kt.get_key())
+        .unwrap()
+}
+
+fn make_server_config_with_large_chain(kt: KeyType) -> ServerConfig {
+    // Pad the chain with copies of the intermediate until its
+    // Certificate message is over the default 64KB limit.
+    let mut chain = kt.get_chain();
+    while chain
+        .iter()
+        .map(|cert| cert.0.len())
+        .sum::<usize>()
+        <= 0x10000
+    {
+        chain.insert(1, chain[1].clone());
+    }
+
+    make_server_config_with_chain(kt, chain)
+}
+
+#[test]
+fn client_rejects_large_cert_chain_by_default() {
+    let server_config = make_server_config_with_large_chain(KeyType::Rsa);
+    let client_config = make_client_config(KeyType::Rsa);
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert_eq!(
+        do_handshake_until_both_error(&mut client, &mut server),
+        Err(vec![
+            ErrorFromPeer::Client(Error::CorruptMessagePayload(
+                rustls::internal::msgs::enums::ContentType::Handshake
+            )),
+            ErrorFromPeer::Server(Error::AlertReceived(
+                rustls::internal::msgs::enums::AlertDescription::DecodeError
+            )),
+        ])
+    );
+}
+
+#[test]
+fn client_accepts_large_cert_chain_within_configured_limit() {
+    for version in rustls::ALL_VERSIONS {
+        let server_config = make_server_config_with_large_chain(KeyType::Rsa);
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.max_handshake_message_size = 0x40000;
+        client_config.max_cert_chain_length = 100;
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+    }
+}
+
+#[test]
+fn client_rejects_over_long_cert_chain() {
+    // The end-entity, ten intermediates and the root.
+    let mut chain = KeyType::Rsa.get_chain();
+    for _ in 0..9 {
+        chain.insert(1, chain[1].clone());
+    }
+    let server_config = Arc::new(make_server_config_with_chain(KeyType::Rsa, chain));
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Client(Error::PeerMisbehavedError(
+                    "certificate chain too long".to_string()
+                )),
+                ErrorFromPeer::Server(Error::AlertReceived(
+                    rustls::internal::msgs::enums::AlertDescription::BadCertificate
+                )),
+            ])
+        );
+    }
+}
+
+#[test]
+fn client_rejects_over_large_cert() {
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+    let end_entity_len = KeyType::Rsa.get_chain()[0].0.len();
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.max_cert_size = end_entity_len - 1;
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Client(Error::PeerMisbehavedError(
+                    "certificate too large".to_string()
+                )),
+                ErrorFromPeer::Server(Error::AlertReceived(
+                    rustls::internal::msgs::enums::AlertDescription::BadCertificate
+                )),
+            ])
+        );
+    }
+}
+
+#[test]
+fn server_rejects_over_long_client_cert_chain() {
+    for version in rustls::ALL_VERSIONS {
+        let mut server_config = make_server_config_with_mandatory_client_auth(KeyType::Rsa);
+        server_config.max_cert_chain_length = 1;
+        let client_config = make_client_config_with_versions_with_auth(KeyType::Rsa, &[version]);
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Server(Error::PeerMisbehavedError(
+                    "certificate chain too long".to_string()
+                )),
+                ErrorFromPeer::Client(Error::AlertReceived(
+                    rustls::internal::msgs::enums::AlertDescription::BadCertificate
+                )),
+            ])
+        );
+    }
+}
+
+/// A deterministic, and so very insecure, source of "random" bytes.
+struct SeededRandom(Mutex<u64>);
+
+impl SeededRandom {
+    fn new(seed: u64) -> Self {
+        Self(Mutex::new(seed))
+    }
+}
+
+impl rustls::SecureRandom for SeededRandom {
+    fn fill(&self, buf: &mut [u8]) -> Result<(), Error> {
+        let mut state = self.0.lock().unwrap();
+        for byte in buf.iter_mut() {
+            // xorshift64
+            *state ^= *state << 13;
+            *state ^= *state >> 7;
+            *state ^= *state << 17;
+            *byte = (*state >> 56) as u8;
+        }
+        Ok(())
+    }
+}
+
+struct FailingRandom;
+
+impl rustls::SecureRandom for FailingRandom {
+    fn fill(&self, _buf: &mut [u8]) -> Result<(), Error> {
+        Err(Error::FailedToGetRandomBytes)
+    }
+}
+
+#[cfg(feature = "tls12")]
+fn client_hello_with_seed(seed: u64) -> Vec<u8> {
+    // TLS1.2 only, so there's no key share: *ring* makes those
+    // with its own RNG.
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    client_config.secure_random = Arc::new(SeededRandom::new(seed));
+
+    let mut client = ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    hello
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn client_hello_is_reproducible_with_seeded_random() {
+    assert_eq!(client_hello_with_seed(1), client_hello_with_seed(1));
+    assert_ne!(client_hello_with_seed(1), client_hello_with_seed(2));
+}
+
+#[test]
+fn client_reports_secure_random_failure() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.secure_random = Arc::new(FailingRandom);
+
+    assert_eq!(
+        ClientConnection::new(Arc::new(client_config), dns_name("localhost")).err(),
+        Some(Error::FailedToGetRandomBytes)
+    );
+}
+
+#[test]
+fn server_reports_secure_random_failure() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.secure_random = Arc::new(FailingRandom);
+
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+    transfer(&mut client, &mut server);
+    assert_eq!(
+        server.process_new_packets().err(),
+        Some(Error::FailedToGetRandomBytes)
+    );
+}
+
+fn do_suite_test(
+    client_config: ClientConfig,
+    server_config: ServerConfig,
+    expect_suite: SupportedCipherSuite,
+    expect_version: ProtocolVersion,
+) {
+    println!(
+        "do_suite_test {:?} {:?}",
+        expect_version,
+        expect_suite.suite()
+    );
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    assert_eq!(None, client.negotiated_cipher_suite());
+    assert_eq!(None, server.negotiated_cipher_suite());
+    assert_eq!(None, client.protocol_version());
+    assert_eq!(None, server.protocol_version());
+    assert!(client.is_handshaking());
+    assert!(server.is_handshaking());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    assert!(client.is_handshaking());
+    assert!(server.is_handshaking());
+    assert_eq!(None, client.protocol_version());
+    assert_eq!(Some(expect_version), server.protocol_version());
+    assert_eq!(None, client.negotiated_cipher_suite());
+    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
+    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    assert!(!client.is_handshaking());
+    assert!(!server.is_handshaking());
+    assert_eq!(Some(expect_version), client.protocol_version());
+    assert_eq!(Some(expect_version), server.protocol_version());
+    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
+    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+}
+
+fn find_suite(suite: CipherSuite) -> SupportedCipherSuite {
+    for scs in ALL_CIPHER_SUITES.iter().copied() {
+        if scs.suite() == suite {
+            return scs;
+        }
+    }
+
+    panic!("find_suite given unsupported suite");
+}
+
+static TEST_CIPHERSUITES: &[(&rustls::SupportedProtocolVersion, KeyType, CipherSuite)] = &[
+    (
+        &rustls::version::TLS13,
+        KeyType::Rsa,
+        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+    ),
+    (
+        &rustls::version::TLS13,
+        KeyType::Rsa,
+        CipherSuite::TLS13_AES_256_GCM_SHA384,
+    ),
+    (
+        &rustls::version::TLS13,
+        KeyType::Rsa,
+        CipherSuite::TLS13_AES_128_GCM_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    ),
+];
+
+#[test]
+fn negotiated_ciphersuite_default() {
+    for kt in ALL_KEY_TYPES.iter() {
+        do_suite_test(
+            make_client_config(*kt),
+            make_server_config(*kt),
+            find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384),
+            ProtocolVersion::TLSv1_3,
+        );
+    }
+}
+
+/// These need a provider other than *ring*, so are tested in `tests/ccm.rs`.
+static CCM_CIPHERSUITES: &[CipherSuite] = &[
+    #[cfg(feature = "tls12")]
+    CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_CCM,
+    #[cfg(feature = "tls12")]
+    CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8,
+];
+
+#[test]
+fn all_suites_covered() {
+    assert_eq!(
+        ALL_CIPHER_SUITES.len(),
+        TEST_CIPHERSUITES.len() + CCM_CIPHERSUITES.len()
+    );
+}
+
+#[test]
+fn negotiated_ciphersuite_client() {
+    for item in TEST_CIPHERSUITES.iter() {
+        let (version, kt, suite) = *item;
+        let scs = find_suite(suite);
+        let client_config = finish_client_config(
+            kt,
+            ClientConfig::builder()
+                .with_cipher_suites(&[scs])
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[version])
+                .unwrap(),
+        );
+
+        do_suite_test(client_config, make_server_config(kt), scs, version.version);
+    }
+}
+
+#[test]
+fn negotiated_ciphersuite_server() {
+    for item in TEST_CIPHERSUITES.iter() {
+        let (version, kt, suite) = *item;
+        let scs = find_suite(suite);
+        let server_config = finish_server_config(
+            kt,
+            ServerConfig::builder()
+                .with_cipher_suites(&[scs])
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[version])
+                .unwrap(),
+        );
+
+        do_suite_test(make_client_config(kt), server_config, scs, version.version);
+    }
+}
+
+#[test]
+fn client_offers_configured_cipher_suite_order() {
+    use rustls::internal::msgs::{
+        codec::Reader, handshake::HandshakePayload, message::MessagePayload, message::OpaqueMessage,
+    };
+
+    let preferred = [
+        find_suite(CipherSuite::TLS13_CHACHA20_POLY1305_SHA256),
+        find_suite(CipherSuite::TLS13_AES_128_GCM_SHA256),
+    ];
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config
+        .set_cipher_suite_order(&preferred)
+        .unwrap();
+
+    let (mut client, _) = make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    let mut buf = [0u8; 262144];
+    let sz = client
+        .write_tls(&mut buf.as_mut())
+        .unwrap();
+    let msg = OpaqueMessage::read(&mut Reader::init(&buf[..sz])).unwrap();
+    let msg = Message::try_from(msg.into_plain_message()).unwrap();
+    let client_hello = match msg.payload {
+        MessagePayload::Handshake(hs) => match hs.payload {
+            HandshakePayload::ClientHello(ch) => ch,
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    };
+
+    // The preferred suites first, then the remaining defaults in order.
+    let mut expected = preferred
+        .iter()
+        .map(|scs| scs.suite())
+        .collect::<Vec<_>>();
+    expected.extend(
+        rustls::DEFAULT_CIPHER_SUITES
+            .iter()
+            .map(|scs| scs.suite())
+            .filter(|suite| !expected.contains(suite))
+            .collect::<Vec<_>>(),
+    );
+    expected.push(CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV);
+    assert_eq!(client_hello.cipher_suites, expected);
+}
+
+fn client_hello_versions(client: &mut ClientConnection) -> Vec<ProtocolVersion> {
+    use rustls::internal::msgs::{
+        codec::Reader, handshake::HandshakePayload, message::MessagePayload, message::OpaqueMessage,
+    };
+
+    let mut buf = [0u8; 262144];
+    let sz = client
+        .write_tls(&mut buf.as_mut())
+        .unwrap();
+    let msg = OpaqueMessage::read(&mut Reader::init(&buf[..sz])).unwrap();
+    let msg = Message::try_from(msg.into_plain_message()).unwrap();
+    match msg.payload {
+        MessagePayload::Handshake(hs) => match hs.payload {
+            HandshakePayload::ClientHello(ch) => ch
+                .get_versions_extension()
+                .unwrap()
+                .clone(),
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn client_connection_can_narrow_protocol_versions() {
+    let client_config = Arc::new(make_client_config(KeyType::Rsa));
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+
+    let mut client = ClientConnection::new(client_config.clone(), dns_name("localhost")).unwrap();
+    assert_eq!(
+        client_hello_versions(&mut client),
+        vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]
+    );
+
+    let mut client = ClientConnection::new_with_versions(
+        client_config.clone(),
+        dns_name("localhost"),
+        &[&rustls::version::TLS13],
+    )
+    .unwrap();
+    assert_eq!(
+        client_hello_versions(&mut client),
+        vec![ProtocolVersion::TLSv1_3]
+    );
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client = ClientConnection::new_with_versions(
+            client_config.clone(),
+            dns_name("localhost"),
+            &[version],
+        )
+        .unwrap();
+        let mut server = ServerConnection::new(server_config.clone()).unwrap();
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.protocol_version(), Some(version.version));
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn client_connection_versions_must_be_enabled_in_config() {
+    let client_config = Arc::new(make_client_config_with_versions(
+        KeyType::Rsa,
+        &[&rustls::version::TLS12],
+    ));
+
+    for versions in [&[&rustls::version::TLS13][..], &[]] {
+        assert!(matches!(
+            ClientConnection::new_with_versions(
+                client_config.clone(),
+                dns_name("localhost"),
+                versions
+            ),
+            Err(Error::General(_))
+        ));
+    }
+}
+
+#[test]
+fn set_cipher_suite_order_rejects_unconfigured_suites() {
+    let tls13_only = [
+        find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384),
+        find_suite(CipherSuite::TLS13_CHACHA20_POLY1305_SHA256),
+    ];
+    let mut client_config = finish_client_config(
+        KeyType::Rsa,
+        ClientConfig::builder()
+            .with_cipher_suites(&tls13_only)
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+
+    let err = client_config
+        .set_cipher_suite_order(&[find_suite(CipherSuite::TLS13_AES_128_GCM_SHA256)])
+        .unwrap_err();
+    assert_eq!(
+        err,
+        Error::General("cipher suite TLS13_AES_128_GCM_SHA256 is not configured".into())
+    );
+
+    // The order is unchanged, so the server picks the client's first choice.
+    do_suite_test(
+        client_config,
+        make_server_config(KeyType::Rsa),
+        tls13_only[0],
+        ProtocolVersion::TLSv1_3,
+    );
+}
+
+#[test]
+fn server_cipher_suite_order_respects_preference_flag() {
+    let server_choice = find_suite(CipherSuite::TLS13_CHACHA20_POLY1305_SHA256);
+    let client_choice = rustls::DEFAULT_CIPHER_SUITES[0];
+    assert_ne!(server_choice, client_choice);
+
+    for ignore_client_order in [false, true].iter() {
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config
+            .set_cipher_suite_order(&[server_choice])
+            .unwrap();
+        server_config.ignore_client_order = *ignore_client_order;
+
+        let expected = if *ignore_client_order {
+            server_choice
+        } else {
+            client_choice
+        };
+        do_suite_test(
+            make_client_config(KeyType::Rsa),
+            server_config,
+            expected,
+            ProtocolVersion::TLSv1_3,
+        );
+    }
+}
+
+#[test]
+fn client_offers_configured_signature_schemes() {
+    use rustls::internal::msgs::{
+        codec::Reader, handshake::HandshakePayload, message::MessagePayload, message::OpaqueMessage,
+    };
+
+    let preferred = [
+        SignatureScheme::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::RSA_PSS_SHA256,
+    ];
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config
+        .set_signature_schemes(&preferred)
+        .unwrap();
+    assert_eq!(client_config.signature_schemes(), preferred.to_vec());
+
+    let (mut client, _) = make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    let mut buf = [0u8; 262144];
+    let sz = client
+        .write_tls(&mut buf.as_mut())
+        .unwrap();
+    let msg = OpaqueMessage::read(&mut Reader::init(&buf[..sz])).unwrap();
+    let msg = Message::try_from(msg.into_plain_message()).unwrap();
+    let client_hello = match msg.payload {
+        MessagePayload::Handshake(hs) => match hs.payload {
+            HandshakePayload::ClientHello(ch) => ch,
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    };
+    assert_eq!(
+        client_hello.get_sigalgs_extension(),
+        Some(&preferred.to_vec())
+    );
+}
+
+#[test]
+fn set_signature_schemes_rejects_bad_lists() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    let defaults = client_config.signature_schemes();
+
+    assert_eq!(
+        client_config.set_signature_schemes(&[]),
+        Err(Error::General("no signature schemes given".into()))
+    );
+    assert_eq!(
+        client_config.set_signature_schemes(&[
+            SignatureScheme::RSA_PSS_SHA256,
+            SignatureScheme::RSA_PSS_SHA256
+        ]),
+        Err(Error::General(
+            "signature scheme RSA_PSS_SHA256 is repeated".into()
+        ))
+    );
+    assert_eq!(
+        client_config.set_signature_schemes(&[SignatureScheme::ED448]),
+        Err(Error::General(
+            "signature scheme ED448 is not supported".into()
+        ))
+    );
+    assert_eq!(client_config.signature_schemes(), defaults);
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    assert_eq!(
+        server_config.set_signature_schemes(&[SignatureScheme::ECDSA_SHA1_Legacy]),
+        Err(Error::General(
+            "signature scheme ECDSA_SHA1_Legacy is not supported".into()
+        ))
+    );
+}
+
+#[test]
+fn client_signature_schemes_restrict_server() {
+    // An RSA server can only sign with the schemes we offer.
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config
+        .set_signature_schemes(&[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ED25519,
+        ])
+        .unwrap();
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    assert!(matches!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::PeerIncompatibleError(_)))
+    ));
+
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config
+        .set_signature_schemes(&[SignatureScheme::RSA_PSS_SHA384])
+        .unwrap();
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+}
+
+#[test]
+fn client_sends_certificate_signature_schemes_only_if_different() {
+    use rustls::internal::msgs::{
+        codec::Reader, handshake::ClientHelloPayload, handshake::HandshakePayload,
+        message::MessagePayload, message::OpaqueMessage,
+    };
+
+    fn client_hello_for(client_config: ClientConfig) -> ClientHelloPayload {
+        let (mut client, _) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        let mut buf = [0u8; 262144];
+        let sz = client
+            .write_tls(&mut buf.as_mut())
+            .unwrap();
+        let msg = OpaqueMessage::read(&mut Reader::init(&buf[..sz])).unwrap();
+        let msg = Message::try_from(msg.into_plain_message()).unwrap();
+        match msg.payload {
+            MessagePayload::Handshake(hs) => match hs.payload {
+                HandshakePayload::ClientHello(ch) => ch,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    let client_config = make_client_config(KeyType::Rsa);
+    assert_eq!(
+        client_config.certificate_signature_schemes(),
+        client_config.signature_schemes()
+    );
+    assert_eq!(
+        client_hello_for(client_config).get_sigalgs_cert_extension(),
+        None
+    );
+
+    let mut client_config = make_client_config(KeyType::Rsa);
+    let same = client_config.signature_schemes();
+    client_config
+        .set_certificate_signature_schemes(&same)
+        .unwrap();
+    assert_eq!(
+        client_hello_for(client_config).get_sigalgs_cert_extension(),
+        None
+    );
+
+    let cert_schemes = [
+        SignatureScheme::RSA_PKCS1_SHA256,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+    ];
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config
+        .set_certificate_signature_schemes(&cert_schemes)
+        .unwrap();
+    let client_hello = client_hello_for(client_config);
+    assert_eq!(
+        client_hello.get_sigalgs_cert_extension(),
+        Some(&cert_schemes.to_vec())
+    );
+    assert_ne!(client_hello.get_sigalgs_extension(), None);
+}
+
+#[test]
+fn set_certificate_signature_schemes_rejects_bad_lists() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    let defaults = client_config.certificate_signature_schemes();
+
+    assert_eq!(
+        client_config.set_certificate_signature_schemes(&[]),
+        Err(Error::General("no signature schemes given".into()))
+    );
+    assert_eq!(
+        client_config.set_certificate_signature_schemes(&[SignatureScheme::ED448]),
+        Err(Error::General(
+            "signature scheme ED448 is not supported".into()
+        ))
+    );
+    assert_eq!(client_config.certificate_signature_schemes(), defaults);
+}
+
+#[test]
+fn server_cert_resolve_sees_certificate_signature_schemes() {
+    let cert_schemes = vec![SignatureScheme::RSA_PKCS1_SHA256, SignatureScheme::ED25519];
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config
+            .set_certificate_signature_schemes(&cert_schemes)
+            .unwrap();
+
+        let mut server_config = make_server_config(*kt);
+        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+            expected_cert_sigalgs: Some(cert_schemes.clone()),
+            ..Default::default()
+        });
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        let err = do_handshake_until_error(&mut client, &mut server);
+        assert!(err.is_err());
+    }
+
+    // Without the extension, the handshake signature schemes apply.
+    let client_config = make_client_config(KeyType::Rsa);
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+        expected_cert_sigalgs: Some(client_config.signature_schemes()),
+        ..Default::default()
+    });
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert!(err.is_err());
+}
+
+#[test]
+fn server_signature_schemes_restrict_signing() {
+    // PKCS#1 v1.5 signatures are usable in TLS1.2 but not TLS1.3.
+    for version in rustls::ALL_VERSIONS {
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config
+            .set_signature_schemes(&[SignatureScheme::RSA_PKCS1_SHA256])
+            .unwrap();
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[*version]);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        let result = do_handshake_until_error(&mut client, &mut server);
+
+        if version.version == ProtocolVersion::TLSv1_2 {
+            assert!(result.is_ok());
+        } else {
+            assert!(matches!(
+                result,
+                Err(ErrorFromPeer::Server(Error::PeerIncompatibleError(_)))
+            ));
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct KeyLogItem {
+    label: String,
+    client_random: Vec<u8>,
+    secret: Vec<u8>,
+}
+
+struct KeyLogToVec {
+    label: &'static str,
+    items: Mutex<Vec<KeyLogItem>>,
+}
+
+impl KeyLogToVec {
+    fn new(who: &'static str) -> Self {
+        KeyLogToVec {
+            label: who,
+            items: Mutex::new(vec![]),
+        }
+    }
+
+    fn take(&self) -> Vec<KeyLogItem> {
+        std::mem::take(&mut self.items.lock().unwrap())
+    }
+}
+
+impl KeyLog for KeyLogToVec {
+    fn log(&self, label: &str, client: &[u8], secret: &[u8]) {
+        let value = KeyLogItem {
+            label: label.into(),
+            client_random: client.into(),
+            secret: secret.into(),
+        };
+
+        println!("key log {:?}: {:?}", self.label, value);
+
+        self.items.lock().unwrap().push(value);
+    }
+}
+
+#[test]
+fn callback_key_log_writes_nss_format() {
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&lines);
+
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.key_log = Arc::new(CallbackKeyLog::new(
+        move |label: &str, client_random: &[u8], secret: &[u8]| {
+            sink.lock()
+                .unwrap()
+                .push(rustls::nss_key_log_line(label, client_random, secret));
+        },
+    ));
+
+    let server_key_log = Arc::new(KeyLogToVec::new("server"));
+    let mut server_config = make_server_config(kt);
+    server_config.key_log = server_key_log.clone();
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    let expected = server_key_log
+        .take()
+        .into_iter()
+        .find(|item| item.label == "CLIENT_TRAFFIC_SECRET_0")
+        .unwrap();
+    let hex = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    };
+
+    let lines = lines.lock().unwrap();
+    let line = lines
+        .iter()
+        .find(|line| line.starts_with("CLIENT_TRAFFIC_SECRET_0 "))
+        .unwrap();
+    assert_eq!(
+        line,
+        &format!(
+            "CLIENT_TRAFFIC_SECRET_0 {} {}\n",
+            hex(&expected.client_random),
+            hex(&expected.secret)
+        )
+    );
+    assert_eq!(expected.client_random.len(), 32);
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn key_log_for_tls12() {
+    let client_key_log = Arc::new(KeyLogToVec::new("client"));
+    let server_key_log = Arc::new(KeyLogToVec::new("server"));
+
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS12]);
+    client_config.key_log = client_key_log.clone();
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.key_log = server_key_log.clone();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_full_log = client_key_log.take();
+    let server_full_log = server_key_log.take();
+    assert_eq!(client_full_log, server_full_log);
+    assert_eq!(1, client_full_log.len());
+    assert_eq!("CLIENT_RANDOM", client_full_log[0].label);
+
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_resume_log = client_key_log.take();
+    let server_resume_log = server_key_log.take();
+    assert_eq!(client_resume_log, server_resume_log);
+    assert_eq!(1, client_resume_log.len());
+    assert_eq!("CLIENT_RANDOM", client_resume_log[0].label);
+    assert_eq!(client_full_log[0].secret, client_resume_log[0].secret);
+}
+
+#[test]
+fn key_log_for_tls13() {
+    let client_key_log = Arc::new(KeyLogToVec::new("client"));
+    let server_key_log = Arc::new(KeyLogToVec::new("server"));
+
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.key_log = client_key_log.clone();
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.key_log = server_key_log.clone();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_full_log = client_key_log.take();
+    let server_full_log = server_key_log.take();
+
+    assert_eq!(5, client_full_log.len());
+    assert_eq!("CLIENT_HANDSHAKE_TRAFFIC_SECRET", client_full_log[0].label);
+    assert_eq!("SERVER_HANDSHAKE_TRAFFIC_SECRET", client_full_log[1].label);
+    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_full_log[2].label);
+    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_full_log[3].label);
+    assert_eq!("EXPORTER_SECRET", client_full_log[4].label);
+
+    assert_eq!(client_full_log[0], server_full_log[0]);
+    assert_eq!(client_full_log[1], server_full_log[1]);
+    assert_eq!(client_full_log[2], server_full_log[2]);
+    assert_eq!(client_full_log[3], server_full_log[3]);
+    assert_eq!(client_full_log[4], server_full_log[4]);
+
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_resume_log = client_key_log.take();
+    let server_resume_log = server_key_log.take();
+
+    assert_eq!(5, client_resume_log.len());
+    assert_eq!(
+        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+        client_resume_log[0].label
+    );
+    assert_eq!(
+        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+        client_resume_log[1].label
+    );
+    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_resume_log[2].label);
+    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_resume_log[3].label);
+    assert_eq!("EXPORTER_SECRET", client_resume_log[4].label);
+
+    assert_eq!(6, server_resume_log.len());
+    assert_eq!("CLIENT_EARLY_TRAFFIC_SECRET", server_resume_log[0].label);
+    assert_eq!(
+        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+        server_resume_log[1].label
+    );
+    assert_eq!(
+        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+        server_resume_log[2].label
+    );
+    assert_eq!("CLIENT_TRAFFIC_SECRET_0", server_resume_log[3].label);
+    assert_eq!("SERVER_TRAFFIC_SECRET_0", server_resume_log[4].label);
+    assert_eq!("EXPORTER_SECRET", server_resume_log[5].label);
+
+    assert_eq!(client_resume_log[0], server_resume_log[1]);
+    assert_eq!(client_resume_log[1], server_resume_log[2]);
+    assert_eq!(client_resume_log[2], server_resume_log[3]);
+    assert_eq!(client_resume_log[3], server_resume_log[4]);
+    assert_eq!(client_resume_log[4], server_resume_log[5]);
+}
+
+#[test]
+fn vectored_write_for_server_appdata() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert_eq!(84, wrlen);
+        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+    }
+    check_read(
+        &mut client.reader(),
+        b"0123456789012345678901234567890123456789",
+    );
+}
+
+#[test]
+fn vectored_write_for_client_appdata() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    client
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    client
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert_eq!(84, wrlen);
+        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+    }
+    check_read(
+        &mut server.reader(),
+        b"0123456789012345678901234567890123456789",
+    );
+}
+
+#[test]
+fn vectored_write_for_server_handshake_without_client_auth() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        // don't assert exact sizes here, to avoid a brittle test
+        assert!(wrlen > 4000); // its pretty big (contains cert chain)
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert_eq!(pipe.writevs[0].len(), 8); // at least a server hello/ccs/cert/serverkx/0.5rtt data
+    }
+
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert_eq!(wrlen, 103);
+        assert_eq!(pipe.writevs, vec![vec![103]]);
+    }
+
+    assert!(!server.is_handshaking());
+    assert!(!client.is_handshaking());
+    check_read(&mut client.reader(), b"012345678901234567890123456789");
+}
+
+#[test]
+fn vectored_write_for_server_handshake_with_client_auth() {
+    let server_config = make_server_config_with_mandatory_client_auth(KeyType::Rsa);
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
+
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        // don't assert exact sizes here, to avoid a brittle test
+        assert!(wrlen > 4000); // its pretty big (contains cert chain)
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert_eq!(pipe.writevs[0].len(), 7); // at least a server hello/ccs/cert/serverkx data
+    }
+
+    // client second flight
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+
+    // when client auth is enabled, we don't sent 0.5-rtt data, as we'd be sending
+    // it to an unauthenticated peer. so it happens here, in the server's second
+    // flight (42 and 32 are lengths of appdata sent above).
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert_eq!(wrlen, 177);
+        assert_eq!(pipe.writevs, vec![vec![103, 42, 32]]);
+    }
+
+    assert!(!server.is_handshaking());
+    assert!(!client.is_handshaking());
+    check_read(&mut client.reader(), b"012345678901234567890123456789");
+}
+
+#[test]
+fn vectored_write_for_client_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    client
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    client
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        // don't assert exact sizes here, to avoid a brittle test
+        assert!(wrlen > 200); // just the client hello
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 1); // only a client hello
+    }
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert_eq!(wrlen, 154);
+        // CCS, finished, then two application datas
+        assert_eq!(pipe.writevs, vec![vec![6, 74, 42, 32]]);
+    }
+
+    assert!(!server.is_handshaking());
+    assert!(!client.is_handshaking());
+    check_read(&mut server.reader(), b"012345678901234567890123456789");
+}
+
+#[test]
+fn vectored_write_with_slow_client() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    client.set_buffer_limit(Some(32));
+
+    do_handshake(&mut client, &mut server);
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        pipe.short_writes = true;
+        let wrlen = server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap();
+        assert_eq!(42, wrlen);
+        assert_eq!(
+            pipe.writevs,
+            vec![vec![21], vec![10], vec![5], vec![3], vec![3]]
+        );
+    }
+    check_read(&mut client.reader(), b"01234567890123456789");
+}
+
+struct ServerStorage {
+    storage: Arc<dyn rustls::server::StoresServerSessions>,
+    put_count: AtomicUsize,
+    get_count: AtomicUsize,
+    take_count: AtomicUsize,
+}
+
+impl ServerStorage {
+    fn new() -> Self {
+        ServerStorage {
+            storage: rustls::server::ServerSessionMemoryCache::new(1024),
+            put_count: AtomicUsize::new(0),
+            get_count: AtomicUsize::new(0),
+            take_count: AtomicUsize::new(0),
+        }
+    }
+
+    fn puts(&self) -> usize {
+        self.put_count.load(Ordering::SeqCst)
+    }
+    fn gets(&self) -> usize {
+        self.get_count.load(Ordering::SeqCst)
+    }
+    fn takes(&self) -> usize {
+        self.take_count.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for ServerStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "(put: {:?}, get: {:?}, take: {:?})",
+            self.put_count, self.get_count, self.take_count
+        )
+    }
+}
+
+impl rustls::server::StoresServerSessions for ServerStorage {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.put_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.get(key)
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.take_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.take(key)
+    }
+
+    fn can_cache(&self) -> bool {
+        true
+    }
+}
+
+struct ClientStorage {
+    storage: Arc<dyn rustls::client::StoresClientSessions>,
+    put_count: AtomicUsize,
+    get_count: AtomicUsize,
+    last_put_key: Mutex<Option<Vec<u8>>>,
+    last_put_expiry: Mutex<Option<SystemTime>>,
+}
+
+impl ClientStorage {
+    fn new() -> Self {
+        ClientStorage {
+            storage: rustls::client::ClientSessionMemoryCache::new(1024),
+            put_count: AtomicUsize::new(0),
+            get_count: AtomicUsize::new(0),
+            last_put_key: Mutex::new(None),
+            last_put_expiry: Mutex::new(None),
+        }
+    }
+
+    fn puts(&self) -> usize {
+        self.put_count.load(Ordering::SeqCst)
+    }
+    fn gets(&self) -> usize {
+        self.get_count.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for ClientStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "(puts: {:?}, gets: {:?} )",
+            self.put_count, self.get_count
+        )
+    }
+}
+
+impl rustls::client::StoresClientSessions for ClientStorage {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.put_count
+            .fetch_add(1, Ordering::SeqCst);
+        *self.last_put_key.lock().unwrap() = Some(key.clone());
+        self.storage.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.get(key)
+    }
+
+    fn put_with_expiry(&self, key: Vec<u8>, value: Vec<u8>, expires: Option<SystemTime>) -> bool {
+        self.put_count
+            .fetch_add(1, Ordering::SeqCst);
+        *self.last_put_key.lock().unwrap() = Some(key.clone());
+        *self.last_put_expiry.lock().unwrap() = expires;
+        self.storage
+            .put_with_expiry(key, value, expires)
+    }
+}
+
+#[test]
+fn client_stores_sessions_with_expiry() {
+    for version in rustls::ALL_VERSIONS {
+        let storage = Arc::new(ClientStorage::new());
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.session_storage = storage.clone();
+
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.ticketer = rustls::Ticketer::new().unwrap();
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        let before = SystemTime::now();
+        do_handshake(&mut client, &mut server);
+
+        let expiry = storage
+            .last_put_expiry
+            .lock()
+            .unwrap()
+            .expect("session stored without expiry");
+        assert!(expiry > before);
+        assert!(expiry <= before + Duration::from_secs(7 * 24 * 60 * 60 + 1));
+    }
+}
+
+/// Keeps every stored session, so they can be copied elsewhere.
+struct SessionExport(Mutex<Vec<(Vec<u8>, Vec<u8>)>>);
+
+impl SessionExport {
+    fn new() -> Self {
+        SessionExport(Mutex::new(Vec::new()))
+    }
+
+    fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn find(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    }
+}
+
+impl rustls::client::StoresClientSessions for SessionExport {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .push((key, value));
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.find(key)
+    }
+}
+
+impl rustls::server::StoresServerSessions for SessionExport {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .push((key, value));
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.find(key)
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.find(key)
+    }
+
+    fn can_cache(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn sessions_resume_from_another_processs_cache() {
+    use rustls::client::StoresClientSessions;
+    use rustls::server::StoresServerSessions;
+
+    for version in rustls::ALL_VERSIONS {
+        let client_export = Arc::new(SessionExport::new());
+        let server_export = Arc::new(SessionExport::new());
+
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.session_storage = client_export.clone();
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.session_storage = server_export.clone();
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config.clone(), server_config.clone());
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.is_session_resumed(), Some(false));
+
+        // As if the caches were written out by one process and read in
+        // by another.
+        let client_cache = rustls::client::ClientSessionMemoryCache::new(32);
+        for (key, value) in client_export.entries() {
+            client_cache.put(key, value);
+        }
+        let server_cache = rustls::server::ServerSessionMemoryCache::new(32);
+        for (key, value) in server_export.entries() {
+            server_cache.put(key, value);
+        }
+        client_config.session_storage = client_cache;
+        server_config.session_storage = server_cache;
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.is_session_resumed(), Some(true));
+        assert_eq!(server.is_session_resumed(), Some(true));
+    }
+}
+
+#[test]
+fn client_ignores_session_in_other_format() {
+    use rustls::client::StoresClientSessions;
+
+    let client_export = Arc::new(SessionExport::new());
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.session_storage = client_export.clone();
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config.clone()), &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_cache = rustls::client::ClientSessionMemoryCache::new(32);
+    for (key, mut value) in client_export.entries() {
+        if key.starts_with(b"session") {
+            value[0] ^= 0xff;
+        }
+        client_cache.put(key, value);
+    }
+    client_config.session_storage = client_cache;
+
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.is_session_resumed(), Some(false));
+}
+
+/// A backend shared between processes, which keeps TLS1.3 sessions
+/// under their PSK identity.
+#[derive(Default)]
+struct PskBackend {
+    by_identity: Mutex<Vec<(Vec<u8>, Vec<u8>)>>,
+    identity_for_key: Mutex<Vec<(Vec<u8>, Vec<u8>)>>,
+}
+
+impl PskBackend {
+    fn find(entries: &Mutex<Vec<(Vec<u8>, Vec<u8>)>>, key: &[u8]) -> Option<Vec<u8>> {
+        entries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    }
+}
+
+struct ClientPskStore(Arc<PskBackend>);
+
+impl rustls::client::StoresClientSessions for ClientPskStore {
+    fn put(&self, _key: Vec<u8>, _value: Vec<u8>) -> bool {
+        false
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let identity = PskBackend::find(&self.0.identity_for_key, key)?;
+        PskBackend::find(&self.0.by_identity, &identity)
+    }
+
+    fn put_tls13_session(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        psk: &rustls::ResumptionPsk<'_>,
+        _expires: Option<SystemTime>,
+    ) -> bool {
+        assert!(!psk.secret().is_empty());
+        self.0
+            .identity_for_key
+            .lock()
+            .unwrap()
+            .push((key, psk.identity().to_vec()));
+        self.0
+            .by_identity
+            .lock()
+            .unwrap()
+            .push((psk.identity().to_vec(), value));
+        true
+    }
+}
+
+struct ServerPskStore(Arc<PskBackend>);
+
+impl rustls::server::StoresServerSessions for ServerPskStore {
+    fn put(&self, _key: Vec<u8>, _value: Vec<u8>) -> bool {
+        false
+    }
+
+    fn get(&self, _key: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn take(&self, _key: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn can_cache(&self) -> bool {
+        true
+    }
+
+    fn put_tls13_session(&self, psk: &rustls::ResumptionPsk<'_>, value: Vec<u8>) -> bool {
+        self.0
+            .by_identity
+            .lock()
+            .unwrap()
+            .push((psk.identity().to_vec(), value));
+        true
+    }
+}
+
+#[test]
+fn tls13_sessions_resume_from_cache_keyed_by_psk_identity() {
+    use rustls::client::StoresClientSessions;
+    use rustls::server::StoresServerSessions;
+
+    let client_backend = Arc::new(PskBackend::default());
+    let server_backend = Arc::new(PskBackend::default());
+
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.session_storage = Arc::new(ClientPskStore(client_backend.clone()));
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.session_storage = Arc::new(ServerPskStore(server_backend.clone()));
+
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config.clone(), server_config.clone());
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.is_session_resumed(), Some(false));
+
+    // Both sides saw the same identities.
+    let client_identities = client_backend
+        .by_identity
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(identity, _)| identity.clone())
+        .collect::<Vec<_>>();
+    let server_identities = server_backend
+        .by_identity
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(identity, _)| identity.clone())
+        .collect::<Vec<_>>();
+    assert!(!client_identities.is_empty());
+    assert_eq!(client_identities, server_identities);
+
+    // New caches in another process, filled from the shared backends.
+    let client_cache = rustls::client::ClientSessionMemoryCache::new(32);
+    for (key, identity) in client_backend
+        .identity_for_key
+        .lock()
+        .unwrap()
+        .iter()
+    {
+        let value = PskBackend::find(&client_backend.by_identity, identity).unwrap();
+        client_cache.put(key.clone(), value);
+    }
+    let server_cache = rustls::server::ServerSessionMemoryCache::new(32);
+    for (identity, value) in server_backend
+        .by_identity
+        .lock()
+        .unwrap()
+        .iter()
+    {
+        server_cache.put(identity.clone(), value.clone());
+    }
+    client_config.session_storage = client_cache;
+    server_config.session_storage = server_cache;
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.is_session_resumed(), Some(true));
+    assert_eq!(server.is_session_resumed(), Some(true));
+}
+
+#[test]
+fn tickets_survive_one_rotation() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let client_config = Arc::new(client_config);
+
+        let ticketer = rustls::Ticketer::with_rotation_interval(60 * 60).unwrap();
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.session_storage = Arc::new(rustls::server::NoServerSessionStorage {});
+        server_config.ticketer = ticketer.clone();
+        let server_config = Arc::new(server_config);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(server.is_session_resumed(), Some(false));
+
+        // The ticket was made with the previous key, which still decrypts.
+        ticketer.rotate();
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(server.is_session_resumed(), Some(true));
+
+        // Any ticket made since is now two rotations old.
+        ticketer.rotate();
+        ticketer.rotate();
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(server.is_session_resumed(), Some(false));
+    }
+}
+
+#[test]
+fn tickets_resume_with_each_ticket_algorithm() {
+    let algorithms = [
+        rustls::TicketAlgorithm::ChaCha20Poly1305,
+        rustls::TicketAlgorithm::Aes128Gcm,
+        rustls::TicketAlgorithm::Aes256Gcm,
+    ];
+
+    for version in rustls::ALL_VERSIONS {
+        for alg in algorithms {
+            let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+            let client_config = Arc::new(client_config);
+
+            let mut server_config = make_server_config(KeyType::Rsa);
+            server_config.session_storage = Arc::new(rustls::server::NoServerSessionStorage {});
+            server_config.ticketer = rustls::Ticketer::with_algorithm(
+                60 * 60,
+                alg,
+                Arc::new(rustls::DefaultSecureRandom),
+            )
+            .unwrap();
+            let server_config = Arc::new(server_config);
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(server.is_session_resumed(), Some(false));
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(server.is_session_resumed(), Some(true));
+        }
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn client_reports_session_id_chosen_by_server() {
+    let client_config = Arc::new(make_client_config_with_versions(
+        KeyType::Rsa,
+        &[&rustls::version::TLS12],
+    ));
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(client.get_session_id(), None);
+    assert_eq!(server.get_session_id(), None);
+    do_handshake(&mut client, &mut server);
+
+    let session_id = server
+        .get_session_id()
+        .unwrap()
+        .to_vec();
+    assert_eq!(session_id.len(), 32);
+    assert_eq!(client.get_session_id(), Some(&session_id[..]));
+
+    // Resuming by session ID gets the same one.
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.is_session_resumed(), Some(true));
+    assert_eq!(client.get_session_id(), Some(&session_id[..]));
+    assert_eq!(server.get_session_id(), Some(&session_id[..]));
+}
+
+#[test]
+fn session_id_is_not_reported_for_tls13() {
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(client.get_session_id(), None);
+    assert_eq!(server.get_session_id(), None);
+}
+
+#[test]
+fn record_counters_count_emitted_records() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        assert_eq!(
+            client.get_record_counters(),
+            rustls::RecordCounters::default()
+        );
+        do_handshake(&mut client, &mut server);
+
+        let client_before = client.get_record_counters();
+        let server_before = server.get_record_counters();
+        assert_eq!(
+            client_before.records_encrypted,
+            server_before.records_decrypted
+        );
+        assert_eq!(
+            server_before.records_encrypted,
+            client_before.records_decrypted
+        );
+
+        let mut records = 0;
+        for len in 1..=10 {
+            client
+                .writer()
+                .write_all(&vec![0x5a; len])
+                .unwrap();
+            records += transfer_records(&mut client, &mut server).len();
+            server.process_new_packets().unwrap();
+        }
+        assert_eq!(records, 10);
+
+        let client_after = client.get_record_counters();
+        let server_after = server.get_record_counters();
+        assert_eq!(
+            client_after.records_encrypted - client_before.records_encrypted,
+            10
+        );
+        assert_eq!(
+            client_after.bytes_encrypted - client_before.bytes_encrypted,
+            55
+        );
+        assert_eq!(
+            server_after.records_decrypted - server_before.records_decrypted,
+            10
+        );
+        assert_eq!(
+            server_after.bytes_decrypted - server_before.bytes_decrypted,
+            55
+        );
+        assert_eq!(
+            client_after.records_decrypted,
+            client_before.records_decrypted
+        );
+        assert_eq!(
+            server_after.records_encrypted,
+            server_before.records_encrypted
+        );
+    }
+}
+
+#[test]
+fn is_session_resumed_reports_resumption() {
+    for version in rustls::ALL_VERSIONS {
+        for use_tickets in [false, true].iter() {
+            let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+            let client_config = Arc::new(client_config);
+
+            let mut server_config = make_server_config(KeyType::Rsa);
+            if *use_tickets {
+                server_config.ticketer = rustls::Ticketer::new().unwrap();
+            }
+            let server_config = Arc::new(server_config);
+
+            // full handshake
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            assert_eq!(client.is_session_resumed(), None);
+            assert_eq!(server.is_session_resumed(), None);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.is_session_resumed(), Some(false));
+            assert_eq!(server.is_session_resumed(), Some(false));
+
+            // resumed
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            assert_eq!(client.is_session_resumed(), None);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.is_session_resumed(), Some(true));
+            assert_eq!(server.is_session_resumed(), Some(true));
+        }
+    }
+}
+
+#[test]
+fn tls13_stateful_resumption() {
+    let kt = KeyType::Rsa;
+    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    let storage = Arc::new(ServerStorage::new());
+    server_config.session_storage = storage.clone();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(storage.puts(), 1);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
+    assert!(resume_c2s > full_c2s);
+    assert!(resume_s2c < full_s2c);
+    assert_eq!(storage.puts(), 2);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 1);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+
+    // resumed again
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(resume_s2c, resume2_s2c);
+    assert_eq!(resume_c2s, resume2_c2s);
+    assert_eq!(storage.puts(), 3);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 2);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+}
+
+#[test]
+fn tls13_stateless_resumption() {
+    let kt = KeyType::Rsa;
+    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
+    let storage = Arc::new(ServerStorage::new());
+    server_config.session_storage = storage.clone();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(storage.puts(), 0);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
+    assert!(resume_c2s > full_c2s);
+    assert!(resume_s2c < full_s2c);
+    assert_eq!(storage.puts(), 0);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+
+    // resumed again
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(resume_s2c, resume2_s2c);
+    assert_eq!(resume_c2s, resume2_c2s);
+    assert_eq!(storage.puts(), 0);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+}
+
+/// Counts the TLS1.3 tickets a client receives.
+struct ClientTicketCounter {
+    storage: Arc<dyn rustls::client::StoresClientSessions>,
+    tickets: AtomicUsize,
+}
+
+impl ClientTicketCounter {
+    fn new() -> Self {
+        ClientTicketCounter {
+            storage: rustls::client::ClientSessionMemoryCache::new(32),
+            tickets: AtomicUsize::new(0),
+        }
+    }
+
+    fn tickets(&self) -> usize {
+        self.tickets.load(Ordering::SeqCst)
+    }
+}
+
+impl rustls::client::StoresClientSessions for ClientTicketCounter {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        if key.starts_with(b"session") {
+            self.tickets
+                .fetch_add(1, Ordering::SeqCst);
+        }
+        self.storage.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.storage.get(key)
+    }
+}
+
+#[test]
+fn tls13_server_sends_configured_number_of_tickets() {
+    let kt = KeyType::Rsa;
+    for &tickets in &[0, 1, 3] {
+        let client_storage = Arc::new(ClientTicketCounter::new());
+        let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+        client_config.session_storage = client_storage.clone();
+        let client_config = Arc::new(client_config);
+
+        let mut server_config = make_server_config(kt);
+        let storage = Arc::new(ServerStorage::new());
+        server_config.session_storage = storage.clone();
+        server_config.send_tls13_tickets = tickets;
+        let server_config = Arc::new(server_config);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(storage.puts(), tickets);
+        assert_eq!(client_storage.tickets(), tickets);
+
+        // the client keeps the last ticket, which must work as well as the first
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(server.is_session_resumed(), Some(tickets > 0));
+        assert_eq!(storage.takes(), if tickets > 0 { 1 } else { 0 });
+    }
+}
+
+#[test]
+fn tls13_stateless_server_sends_configured_number_of_tickets() {
+    let kt = KeyType::Rsa;
+    for &tickets in &[0, 2] {
+        let client_storage = Arc::new(ClientTicketCounter::new());
+        let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+        client_config.session_storage = client_storage.clone();
+        let client_config = Arc::new(client_config);
+
+        let mut server_config = make_server_config(kt);
+        server_config.ticketer = rustls::Ticketer::new().unwrap();
+        server_config.send_tls13_tickets = tickets;
+        let server_config = Arc::new(server_config);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client_storage.tickets(), tickets);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(server.is_session_resumed(), Some(tickets > 0));
+    }
+}
+
+#[test]
+fn early_data_not_available() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert!(client.early_data().is_none());
+}
+
+fn early_data_configs() -> (Arc<ClientConfig>, Arc<ServerConfig>) {
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config(kt);
+    client_config.enable_early_data = true;
+    client_config.session_storage = Arc::new(ClientStorage::new());
+
+    let mut server_config = make_server_config(kt);
+    server_config.max_early_data_size = 1234;
+    (Arc::new(client_config), Arc::new(server_config))
+}
+
+#[test]
+fn early_data_is_available_on_resumption() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert!(client.early_data().is_some());
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .bytes_left(),
+        1234
+    );
+    client
+        .early_data()
+        .unwrap()
+        .flush()
+        .unwrap();
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+    do_handshake(&mut client, &mut server);
+
+    let mut received_early_data = [0u8; 5];
+    assert_eq!(
+        server
+            .early_data()
+            .expect("early_data didn't happen")
+            .read(&mut received_early_data)
+            .expect("early_data failed unexpectedly"),
+        5
+    );
+    assert_eq!(&received_early_data[..], b"hello");
+}
+
+#[test]
+fn early_data_is_not_subject_to_record_size_limit() {
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config(kt);
+    client_config.enable_early_data = true;
+    client_config.session_storage = Arc::new(ClientStorage::new());
+    client_config.record_size_limit = Some(16385);
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.max_early_data_size = 1234;
+    server_config.record_size_limit = Some(64);
+    let server_config = Arc::new(server_config);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    // The limit is negotiated by the handshake that carries the early data,
+    // so it can't apply to that data.
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let early_data = [0x5a; 1000];
+    client
+        .early_data()
+        .unwrap()
+        .write_all(&early_data)
+        .unwrap();
+    do_handshake(&mut client, &mut server);
+    assert!(client.is_early_data_accepted());
+
+    let mut received_early_data = vec![];
+    server
+        .early_data()
+        .unwrap()
+        .read_to_end(&mut received_early_data)
+        .unwrap();
+    assert_eq!(&received_early_data[..], &early_data[..]);
+}
+
+fn certified_key_with_resumption_overrides(
+    kt: KeyType,
+    ticket_lifetime: u32,
+    max_early_data_size: u32,
+) -> Arc<sign::CertifiedKey> {
+    let mut certified_key = sign::CertifiedKey::new(
+        kt.get_chain(),
+        sign::any_supported_type(&kt.get_key()).unwrap(),
+    );
+    certified_key.ticket_lifetime = Some(ticket_lifetime);
+    certified_key.max_early_data_size = Some(max_early_data_size);
+    Arc::new(certified_key)
+}
+
+#[test]
+fn resolver_overrides_ticket_lifetime_and_early_data_size() {
+    let kt = KeyType::Rsa;
+    let storage = Arc::new(ClientStorage::new());
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.enable_early_data = true;
+    client_config.session_storage = storage.clone();
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.max_early_data_size = 1234;
+    server_config.cert_resolver = Arc::new(AlwaysResolves(
+        certified_key_with_resumption_overrides(kt, 60 * 60, 4321),
+    ));
+    let server_config = Arc::new(server_config);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let before = SystemTime::now();
+    do_handshake(&mut client, &mut server);
+    let after = SystemTime::now();
+
+    let expiry = storage
+        .last_put_expiry
+        .lock()
+        .unwrap()
+        .expect("session stored without expiry");
+    assert!(expiry + Duration::from_secs(1) >= before + Duration::from_secs(60 * 60));
+    assert!(expiry <= after + Duration::from_secs(60 * 60));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .bytes_left(),
+        4321
+    );
+    client
+        .early_data()
+        .unwrap()
+        .write_all(b"hello")
+        .unwrap();
+    do_handshake(&mut client, &mut server);
+    assert!(client.is_early_data_accepted());
+}
+
+#[test]
+fn resolver_ticket_lifetime_over_seven_days_is_rejected() {
+    let kt = KeyType::Rsa;
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(AlwaysResolves(
+        certified_key_with_resumption_overrides(kt, 7 * 24 * 60 * 60 + 1, 0),
+    ));
+
+    let (mut client, mut server) = make_pair_for_configs(make_client_config(kt), server_config);
+    transfer(&mut client, &mut server);
+    assert_eq!(
+        server.process_new_packets().err(),
+        Some(Error::General(
+            "resolved certificate has a ticket lifetime over 7 days".to_string()
+        ))
+    );
+}
+
+#[test]
+fn early_data_not_available_on_server_before_client_hello() {
+    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
+    assert!(server.early_data().is_none());
+}
+
+#[test]
+fn early_data_can_be_rejected_by_server() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert!(client.early_data().is_some());
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .bytes_left(),
+        1234
+    );
+    client
+        .early_data()
+        .unwrap()
+        .flush()
+        .unwrap();
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+    server.reject_early_data();
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(client.is_early_data_accepted(), false);
+}
+
+struct RecordingReplayDetector {
+    seen: Mutex<Vec<Vec<u8>>>,
+}
+
+impl DetectsEarlyDataReplay for RecordingReplayDetector {
+    fn is_replay(&self, client_random: &[u8]) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        if seen
+            .iter()
+            .any(|random| random == client_random)
+        {
+            return true;
+        }
+        seen.push(client_random.to_vec());
+        false
+    }
+}
+
+fn early_data_configs_with_replay_detector(
+    detector: Arc<dyn DetectsEarlyDataReplay>,
+) -> (Arc<ClientConfig>, Arc<ServerConfig>) {
+    let (client_config, server_config) = early_data_configs();
+    let mut server_config = (*server_config).clone();
+    server_config.early_data_replay_detector = Some(detector);
+    (client_config, Arc::new(server_config))
+}
+
+#[test]
+fn early_data_is_read_before_client_finished() {
+    let detector = Arc::new(RecordingReplayDetector {
+        seen: Mutex::new(Vec::new()),
+    });
+    let (client_config, server_config) = early_data_configs_with_replay_detector(detector.clone());
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(detector.seen.lock().unwrap().is_empty());
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+
+    // Only the client's first flight has arrived.
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(server.is_handshaking());
+
+    let mut received_early_data = [0u8; 5];
+    assert_eq!(
+        server
+            .early_data()
+            .expect("early_data didn't happen")
+            .read(&mut received_early_data)
+            .expect("early_data failed unexpectedly"),
+        5
+    );
+    assert_eq!(&received_early_data[..], b"hello");
+    assert_eq!(detector.seen.lock().unwrap().len(), 1);
+
+    do_handshake(&mut client, &mut server);
+    assert!(client.is_early_data_accepted());
+}
+
+#[test]
+fn early_data_is_rejected_as_replay() {
+    struct AlwaysReplayed;
+
+    impl DetectsEarlyDataReplay for AlwaysReplayed {
+        fn is_replay(&self, _client_random: &[u8]) -> bool {
+            true
+        }
+    }
+
+    let (client_config, server_config) =
+        early_data_configs_with_replay_detector(Arc::new(AlwaysReplayed));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+    do_handshake(&mut client, &mut server);
+
+    assert!(!client.is_early_data_accepted());
+    assert!(server.early_data().is_none());
+    assert_eq!(client.is_session_resumed(), Some(true));
+}
+
+#[test]
+fn client_can_resend_rejected_early_data() {
+    let (client_config, server_config) = early_data_configs();
+    let mut rejecting_server_config = (*server_config).clone();
+    rejecting_server_config.max_early_data_size = 0;
+    let rejecting_server_config = Arc::new(rejecting_server_config);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&client_config, &rejecting_server_config);
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+
+    // The server's EncryptedExtensions tells the client its early data
+    // was rejected.
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert!(!client.is_early_data_accepted());
+    assert!(client.early_data().is_none());
+
+    // So the client sends it again, as normal data.
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.is_session_resumed(), Some(true));
+    assert!(!client.is_early_data_accepted());
+    assert!(server.early_data().is_none());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"hello");
+}
+
+#[test]
+fn early_exporter_agrees_when_early_data_is_used() {
+    let (client_config, server_config) = early_data_configs();
+    let not_used = Err(Error::General("early data was not used".into()));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    let mut client_early = [0u8; 32];
+    let mut server_early = [0u8; 32];
+    assert_eq!(
+        client.export_keying_material_early(&mut client_early, b"label", None),
+        not_used
+    );
+    assert_eq!(
+        server.export_keying_material_early(&mut server_early, b"label", None),
+        not_used
+    );
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    client
+        .export_keying_material_early(&mut client_early, b"label", Some(b"context"))
+        .unwrap();
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    server
+        .export_keying_material_early(&mut server_early, b"label", Some(b"context"))
+        .unwrap();
+    assert_eq!(client_early, server_early);
+
+    do_handshake(&mut client, &mut server);
+    assert!(client.is_early_data_accepted());
+    let mut after_handshake = [0u8; 32];
+    client
+        .export_keying_material_early(&mut after_handshake, b"label", Some(b"context"))
+        .unwrap();
+    assert_eq!(client_early, after_handshake);
+
+    // The early exporter is separate from the main one.
+    let mut exported = [0u8; 32];
+    client
+        .export_keying_material(&mut exported, b"label", Some(b"context"))
+        .unwrap();
+    assert_ne!(client_early, exported);
+}
+
+#[test]
+fn early_exporter_fails_once_early_data_is_rejected() {
+    let (client_config, server_config) = early_data_configs();
+    let mut rejecting_server_config = (*server_config).clone();
+    rejecting_server_config.max_early_data_size = 0;
+    let rejecting_server_config = Arc::new(rejecting_server_config);
+    let not_used = Err(Error::General("early data was not used".into()));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&client_config, &rejecting_server_config);
+    let mut output = [0u8; 32];
+    assert!(client
+        .export_keying_material_early(&mut output, b"label", None)
+        .is_ok());
+
+    do_handshake(&mut client, &mut server);
+    assert!(!client.is_early_data_accepted());
+    assert_eq!(
+        client.export_keying_material_early(&mut output, b"label", None),
+        not_used
+    );
+    assert_eq!(
+        server.export_keying_material_early(&mut output, b"label", None),
+        not_used
+    );
+}
+
+#[cfg(feature = "quic")]
+mod test_quic {
+    use super::*;
+    use rustls::Connection;
+
+    // Returns the sender's next secrets to use, or the receiver's error.
+    fn step(
+        send: &mut dyn QuicExt,
+        recv: &mut dyn QuicExt,
+    ) -> Result<Option<quic::KeyChange>, Error> {
+        let mut buf = Vec::new();
+        let change = loop {
+            let prev = buf.len();
+            if let Some(x) = send.write_hs(&mut buf) {
+                break Some(x);
+            }
+            if prev == buf.len() {
+                break None;
+            }
+        };
+        if let Err(e) = recv.read_hs(&buf) {
+            return Err(e);
+        } else {
+            assert_eq!(recv.alert(), None);
+        }
+
+        Ok(change)
+    }
+
+    fn equal_packet_keys(x: &quic::PacketKey, y: &quic::PacketKey) -> bool {
+        // Check that these two sets of keys are equal.
+        let mut buf = vec![0; 32];
+        let (header, payload_tag) = buf.split_at_mut(8);
+        let (payload, tag_buf) = payload_tag.split_at_mut(8);
+        let tag = x
+            .encrypt_in_place(42, &*header, payload)
+            .unwrap();
+        tag_buf.copy_from_slice(tag.as_ref());
+
+        let result = y.decrypt_in_place(42, &*header, payload_tag);
+        match result {
+            Ok(payload) => payload == &[0; 8],
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_quic_handshake() {
+        fn compatible_keys(x: &quic::KeyChange, y: &quic::KeyChange) -> bool {
+            fn keys(kc: &quic::KeyChange) -> &quic::Keys {
+                match kc {
+                    quic::KeyChange::Handshake { keys, .. } => keys,
+                    quic::KeyChange::OneRtt { keys, .. } => keys,
+                }
+            }
+
+            let (x, y) = (keys(x), keys(y));
+            equal_packet_keys(&x.local.packet, &y.remote.packet)
+                && equal_packet_keys(&x.remote.packet, &y.local.packet)
+        }
+
+        let kt = KeyType::Rsa;
+        let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+        client_config.enable_early_data = true;
+        let client_config = Arc::new(client_config);
+        let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+        server_config.max_early_data_size = 0xffffffff;
+        let server_config = Arc::new(server_config);
+        let client_params = &b"client params"[..];
+        let server_params = &b"server params"[..];
+
+        // full handshake
+        let mut client = Connection::from(
+            ClientConnection::new_quic(
+                Arc::clone(&client_config),
+                quic::Version::V1,
+                dns_name("localhost"),
+                client_params.into(),
+            )
+            .unwrap(),
+        );
+
+        let mut server = Connection::from(
+            ServerConnection::new_quic(
+                Arc::clone(&server_config),
+                quic::Version::V1,
+                server_params.into(),
+            )
+            .unwrap(),
+        );
+
+        let client_initial = step(&mut client, &mut server).unwrap();
+        assert!(client_initial.is_none());
+        assert!(client.zero_rtt_keys().is_none());
+        assert_eq!(server.quic_transport_parameters(), Some(client_params));
+        let server_hs = step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(server.zero_rtt_keys().is_none());
+        let client_hs = step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        assert!(compatible_keys(&server_hs, &client_hs));
+        assert!(client.is_handshaking());
+        let server_1rtt = step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(!client.is_handshaking());
+        assert_eq!(client.quic_transport_parameters(), Some(server_params));
+        assert!(server.is_handshaking());
+        let client_1rtt = step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        assert!(!server.is_handshaking());
+        assert!(compatible_keys(&server_1rtt, &client_1rtt));
+        assert!(!compatible_keys(&server_hs, &server_1rtt));
+        assert!(step(&mut client, &mut server)
+            .unwrap()
+            .is_none());
+        assert!(step(&mut server, &mut client)
+            .unwrap()
+            .is_none());
+
+        // 0-RTT handshake
+        let mut client = ClientConnection::new_quic(
+            Arc::clone(&client_config),
+            quic::Version::V1,
+            dns_name("localhost"),
+            client_params.into(),
+        )
+        .unwrap();
+        assert!(client
+            .negotiated_cipher_suite()
+            .is_some());
+
+        let mut server = ServerConnection::new_quic(
+            Arc::clone(&server_config),
+            quic::Version::V1,
+            server_params.into(),
+        )
+        .unwrap();
+
+        step(&mut client, &mut server).unwrap();
+        assert_eq!(client.quic_transport_parameters(), Some(server_params));
+        {
+            let client_early = client.zero_rtt_keys().unwrap();
+            let server_early = server.zero_rtt_keys().unwrap();
+            assert!(equal_packet_keys(
+                &client_early.packet,
+                &server_early.packet
+            ));
+        }
+        step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(client.is_early_data_accepted());
+
+        // 0-RTT rejection
+        {
+            let client_config = (*client_config).clone();
+            let mut client = ClientConnection::new_quic(
+                Arc::new(client_config),
+                quic::Version::V1,
+                dns_name("localhost"),
+                client_params.into(),
+            )
+            .unwrap();
+
+            let mut server = ServerConnection::new_quic(
+                Arc::clone(&server_config),
+                quic::Version::V1,
+                server_params.into(),
+            )
+            .unwrap();
+
+            step(&mut client, &mut server).unwrap();
+            assert_eq!(client.quic_transport_parameters(), Some(server_params));
+            assert!(client.zero_rtt_keys().is_some());
+            assert!(server.zero_rtt_keys().is_none());
+            step(&mut server, &mut client)
+                .unwrap()
+                .unwrap();
+            step(&mut client, &mut server)
+                .unwrap()
+                .unwrap();
+            step(&mut server, &mut client)
+                .unwrap()
+                .unwrap();
+            assert!(!client.is_early_data_accepted());
+        }
+
+        // failed handshake
+        let mut client = ClientConnection::new_quic(
+            client_config,
+            quic::Version::V1,
+            dns_name("example.com"),
+            client_params.into(),
+        )
+        .unwrap();
+
+        let mut server =
+            ServerConnection::new_quic(server_config, quic::Version::V1, server_params.into())
+                .unwrap();
+
+        step(&mut client, &mut server).unwrap();
+        step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(step(&mut server, &mut client).is_err());
+        assert_eq!(
+            client.alert(),
+            Some(rustls::internal::msgs::enums::AlertDescription::BadCertificate)
+        );
+
+        // Key updates
+
+        let (mut client_secrets, mut server_secrets) = match (client_1rtt, server_1rtt) {
+            (quic::KeyChange::OneRtt { next: c, .. }, quic::KeyChange::OneRtt { next: s, .. }) => {
+                (c, s)
+            }
+            _ => unreachable!(),
+        };
+
+        let mut client_next = client_secrets.next_packet_keys();
+        let mut server_next = server_secrets.next_packet_keys();
+        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
+        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+
+        client_next = client_secrets.next_packet_keys();
+        server_next = server_secrets.next_packet_keys();
+        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
+        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+    }
+
+    #[test]
+    fn test_quic_key_changes_follow_encryption_levels() {
+        // Handshake keys can be rederived from the accompanying secrets.
+        fn check_secrets(change: &quic::KeyChange) {
+            if let quic::KeyChange::Handshake { keys, secrets } = change {
+                let derived = secrets.keys();
+                assert!(equal_packet_keys(&keys.local.packet, &derived.local.packet));
+                assert!(equal_packet_keys(
+                    &keys.remote.packet,
+                    &derived.remote.packet
+                ));
+            }
+        }
+
+        // Drives a handshake, recording the levels at which each side gets keys.
+        fn levels_in_order(
+            client: &mut ClientConnection,
+            server: &mut ServerConnection,
+        ) -> (Vec<quic::Level>, Vec<quic::Level>) {
+            let mut client_levels = vec![quic::Level::Initial];
+            let mut server_levels = vec![quic::Level::Initial];
+
+            loop {
+                let client_change = step(client, server).unwrap();
+                if client.zero_rtt_keys().is_some()
+                    && !client_levels.contains(&quic::Level::ZeroRtt)
+                {
+                    client_levels.push(quic::Level::ZeroRtt);
+                }
+                if server.zero_rtt_keys().is_some()
+                    && !server_levels.contains(&quic::Level::ZeroRtt)
+                {
+                    server_levels.push(quic::Level::ZeroRtt);
+                }
+                let server_change = step(server, client).unwrap();
+
+                for (change, levels) in &mut [
+                    (client_change, &mut client_levels),
+                    (server_change, &mut server_levels),
+                ] {
+                    if let Some(change) = change {
+                        check_secrets(change);
+                        levels.push(change.level());
+                    }
+                }
+
+                if !client.is_handshaking() && !server.is_handshaking() {
+                    return (client_levels, server_levels);
+                }
+            }
+        }
+
+        let kt = KeyType::Rsa;
+        let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+        client_config.enable_early_data = true;
+        let client_config = Arc::new(client_config);
+        let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+        server_config.max_early_data_size = 0xffffffff;
+        let server_config = Arc::new(server_config);
+
+        let new_pair = || {
+            let client = ClientConnection::new_quic(
+                Arc::clone(&client_config),
+                quic::Version::V1,
+                dns_name("localhost"),
+                b"client params".to_vec(),
+            )
+            .unwrap();
+            let server = ServerConnection::new_quic(
+                Arc::clone(&server_config),
+                quic::Version::V1,
+                b"server params".to_vec(),
+            )
+            .unwrap();
+            (client, server)
+        };
+
+        use quic::Level::*;
+        let (mut client, mut server) = new_pair();
+        assert_eq!(
+            levels_in_order(&mut client, &mut server),
+            (
+                vec![Initial, Handshake, OneRtt],
+                vec![Initial, Handshake, OneRtt]
+            )
+        );
+
+        let (mut client, mut server) = new_pair();
+        let (client_levels, server_levels) = levels_in_order(&mut client, &mut server);
+        assert_eq!(client_levels, vec![Initial, ZeroRtt, Handshake, OneRtt]);
+        assert_eq!(server_levels, vec![Initial, ZeroRtt, Handshake, OneRtt]);
+        assert!(client.is_early_data_accepted());
+
+        // Initial secrets derive the same keys as `Keys::initial`, and agree
+        // between client and server.
+        let cid = b"connection id";
+        let client_initial = quic::Secrets::initial(quic::Version::V1, cid, true).keys();
+        let server_initial = quic::Keys::initial(quic::Version::V1, cid, false);
+        assert!(equal_packet_keys(
+            &client_initial.local.packet,
+            &server_initial.remote.packet
+        ));
+        assert!(equal_packet_keys(
+            &server_initial.local.packet,
+            &client_initial.remote.packet
+        ));
+    }
+
+    #[test]
+    fn test_quic_transport_parameters_round_trip() {
+        let kt = KeyType::Rsa;
+        let client_config = Arc::new(make_client_config_with_versions(
+            kt,
+            &[&rustls::version::TLS13],
+        ));
+        let server_config = Arc::new(make_server_config_with_versions(
+            kt,
+            &[&rustls::version::TLS13],
+        ));
+
+        // Long enough to need a two-byte length, and not valid as parameters:
+        // rustls should not care.
+        let client_params = (0..300u16)
+            .map(|i| i as u8)
+            .collect::<Vec<u8>>();
+        let server_params = (0..600u16)
+            .map(|i| !(i as u8))
+            .collect::<Vec<u8>>();
+
+        for version in &[quic::Version::V1Draft, quic::Version::V1] {
+            let mut client = ClientConnection::new_quic(
+                Arc::clone(&client_config),
+                *version,
+                dns_name("localhost"),
+                client_params.clone(),
+            )
+            .unwrap();
+            let mut server = ServerConnection::new_quic(
+                Arc::clone(&server_config),
+                *version,
+                server_params.clone(),
+            )
+            .unwrap();
+            assert_eq!(client.get_quic_transport_parameters(), None);
+            assert_eq!(server.get_quic_transport_parameters(), None);
+
+            step(&mut client, &mut server).unwrap();
+            assert_eq!(
+                server.get_quic_transport_parameters(),
+                Some(&client_params[..])
+            );
+            step(&mut server, &mut client).unwrap();
+            step(&mut client, &mut server).unwrap();
+            step(&mut server, &mut client).unwrap();
+            step(&mut client, &mut server).unwrap();
+            assert!(!client.is_handshaking());
+            assert!(!server.is_handshaking());
+
+            assert_eq!(
+                client.get_quic_transport_parameters(),
+                Some(&server_params[..])
+            );
+            assert_eq!(
+                server.get_quic_transport_parameters(),
+                Some(&client_params[..])
+            );
+        }
+    }
+
+    #[test]
+    fn test_quic_zero_rtt_acceptance_is_signalled() {
+        let kt = KeyType::Rsa;
+        let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+        client_config.enable_early_data = true;
+        let client_config = Arc::new(client_config);
+        let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+        server_config.max_early_data_size = 0xffffffff;
+        let server_config = Arc::new(server_config);
+
+        let new_client = || {
+            ClientConnection::new_quic(
+                Arc::clone(&client_config),
+                quic::Version::V1,
+                dns_name("localhost"),
+                b"client params".to_vec(),
+            )
+            .unwrap()
+        };
+        let new_server = |config: &Arc<ServerConfig>| {
+            ServerConnection::new_quic(
+                Arc::clone(config),
+                quic::Version::V1,
+                b"server params".to_vec(),
+            )
+            .unwrap()
+        };
+
+        // Full handshake, to get a ticket.  No 0-RTT is attempted.
+        let mut client = new_client();
+        let mut server = new_server(&server_config);
+        assert!(client.zero_rtt_keys().is_none());
+        step(&mut client, &mut server).unwrap();
+        step(&mut server, &mut client).unwrap();
+        step(&mut client, &mut server).unwrap();
+        step(&mut server, &mut client).unwrap();
+        step(&mut client, &mut server).unwrap();
+        assert!(!client.is_handshaking());
+        assert!(!server.is_handshaking());
+        assert_eq!(client.zero_rtt_accepted(), None);
+        // Deliver the ticket.
+        step(&mut server, &mut client).unwrap();
+
+        // 0-RTT accepted
+        let mut client = new_client();
+        let mut server = new_server(&server_config);
+        assert_eq!(client.zero_rtt_accepted(), None);
+        assert_eq!(server.zero_rtt_accepted(), None);
+        step(&mut client, &mut server).unwrap();
+        assert_eq!(client.zero_rtt_accepted(), None);
+        assert_eq!(server.zero_rtt_accepted(), Some(true));
+        let client_early = client.zero_rtt_keys().unwrap();
+        let server_early = server.zero_rtt_keys().unwrap();
+        assert!(equal_packet_keys(
+            &client_early.packet,
+            &server_early.packet
+        ));
+        step(&mut server, &mut client).unwrap();
+        step(&mut client, &mut server).unwrap();
+        step(&mut server, &mut client).unwrap();
+        assert_eq!(client.zero_rtt_accepted(), Some(true));
+        assert!(client.zero_rtt_keys().is_some());
+        assert!(client.is_early_data_accepted());
+
+        // 0-RTT rejected: the server accepts the ticket but not early data.
+        let mut server_config = (*server_config).clone();
+        server_config.max_early_data_size = 0;
+        let server_config = Arc::new(server_config);
+
+        let mut client = new_client();
+        let mut server = new_server(&server_config);
+        step(&mut client, &mut server).unwrap();
+        assert!(client.zero_rtt_keys().is_some());
+        assert_eq!(client.zero_rtt_accepted(), None);
+        assert_eq!(server.zero_rtt_accepted(), Some(false));
+        assert!(server.zero_rtt_keys().is_none());
+
+        let server_hs = step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert_eq!(server_hs.level(), quic::Level::Handshake);
+        step(&mut client, &mut server).unwrap();
+        let server_1rtt = step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.zero_rtt_accepted(), Some(false));
+        assert!(client.zero_rtt_keys().is_none());
+        assert!(!client.is_early_data_accepted());
+
+        // Whatever was sent in 0-RTT can be resent with the 1-RTT keys.
+        let client_1rtt = step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        assert!(!client.is_handshaking());
+        assert!(!server.is_handshaking());
+        match (client_1rtt, server_1rtt) {
+            (quic::KeyChange::OneRtt { keys: c, .. }, quic::KeyChange::OneRtt { keys: s, .. }) => {
+                assert!(equal_packet_keys(&c.local.packet, &s.remote.packet));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_quic_rejects_missing_alpn() {
+        let client_params = &b"client params"[..];
+        let server_params = &b"server params"[..];
+
+        for &kt in ALL_KEY_TYPES.iter() {
+            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+            let client_config = Arc::new(client_config);
+
+            let mut server_config =
+                make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+            server_config.alpn_protocols = vec!["foo".into()];
+            let server_config = Arc::new(server_config);
+
+            let mut client = ClientConnection::new_quic(
+                client_config,
+                quic::Version::V1,
+                dns_name("localhost"),
+                client_params.into(),
+            )
+            .unwrap();
+            let mut server =
+                ServerConnection::new_quic(server_config, quic::Version::V1, server_params.into())
+                    .unwrap();
+
+            assert_eq!(
+                step(&mut client, &mut server)
+                    .err()
+                    .unwrap(),
+                Error::NoApplicationProtocol
+            );
+
+            assert_eq!(
+                server.alert(),
+                Some(rustls::internal::msgs::enums::AlertDescription::NoApplicationProtocol)
+            );
+        }
+    }
+
+    #[test]
+    fn test_quic_no_tls13_error() {
+        let mut client_config =
+            make_client_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
+        client_config.alpn_protocols = vec!["foo".into()];
+        let client_config = Arc::new(client_config);
+
+        assert!(ClientConnection::new_quic(
+            client_config,
+            quic::Version::V1,
+            dns_name("localhost"),
+            b"client params".to_vec(),
+        )
+        .is_err());
+
+        let mut server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
+        server_config.alpn_protocols = vec!["foo".into()];
+        let server_config = Arc::new(server_config);
+
+        assert!(ServerConnection::new_quic(
+            server_config,
+            quic::Version::V1,
+            b"server params".to_vec(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_quic_invalid_early_data_size() {
+        let mut server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
+        server_config.alpn_protocols = vec!["foo".into()];
+
+        let cases = [
+            (None, true),
+            (Some(0u32), true),
+            (Some(5), false),
+            (Some(0xffff_ffff), true),
+        ];
+
+        for &(size, ok) in cases.iter() {
+            println!("early data size case: {:?}", size);
+            if let Some(new) = size {
+                server_config.max_early_data_size = new;
+            }
+
+            let wrapped = Arc::new(server_config.clone());
+            assert_eq!(
+                ServerConnection::new_quic(wrapped, quic::Version::V1, b"server params".to_vec(),)
+                    .is_ok(),
+                ok
+            );
+        }
+    }
+
+    #[test]
+    fn test_quic_server_no_params_received() {
+        let server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
+        let server_config = Arc::new(server_config);
+
+        let mut server =
+            ServerConnection::new_quic(server_config, quic::Version::V1, b"server params".to_vec())
+                .unwrap();
+
+        use ring::rand::SecureRandom;
+        use rustls::internal::msgs::base::PayloadU16;
+        use rustls::internal::msgs::enums::{
+            CipherSuite, Compression, HandshakeType, NamedGroup, SignatureScheme,
+        };
+        use rustls::internal::msgs::handshake::{
+            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionID,
+        };
+        use rustls::internal::msgs::message::PlainMessage;
+
+        let rng = ring::rand::SystemRandom::new();
+        let mut random = [0; 32];
+        rng.fill(&mut random).unwrap();
+        let random = Random::from(random);
+
+        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
+            .unwrap()
+            .compute_public_key()
+            .unwrap();
+
+        let client_hello = Message {
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::Handshake(HandshakeMessagePayload {
+                typ: HandshakeType::ClientHello,
+                payload: HandshakePayload::ClientHello(ClientHelloPayload {
+                    client_version: ProtocolVersion::TLSv1_3,
+                    random,
+                    session_id: SessionID::random(&rustls::DefaultSecureRandom).unwrap(),
+                    cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
+                    compression_methods: vec![Compression::Null],
+                    extensions: vec![
+                        ClientExtension::SupportedVersions(vec![ProtocolVersion::TLSv1_3]),
+                        ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
+                        ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
+                        ClientExtension::KeyShare(vec![KeyShareEntry {
+                            group: NamedGroup::X25519,
+                            payload: PayloadU16::new(kx.as_ref().to_vec()),
+                        }]),
+                    ],
+                }),
+            }),
+        };
+
+        let buf = PlainMessage::from(client_hello)
+            .into_unencrypted_opaque()
+            .encode();
+        server
+            .read_tls(&mut buf.as_slice())
+            .unwrap();
+        assert_eq!(
+            server.process_new_packets().err(),
+            Some(Error::PeerMisbehavedError(
+                "QUIC transport parameters not found".into(),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_quic_server_no_tls12() {
+        let mut server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
+        server_config.alpn_protocols = vec!["foo".into()];
+        let server_config = Arc::new(server_config);
+
+        use ring::rand::SecureRandom;
+        use rustls::internal::msgs::base::PayloadU16;
+        use rustls::internal::msgs::enums::{
+            CipherSuite, Compression, HandshakeType, NamedGroup, SignatureScheme,
+        };
+        use rustls::internal::msgs::handshake::{
+            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionID,
+        };
+        use rustls::internal::msgs::message::PlainMessage;
+
+        let rng = ring::rand::SystemRandom::new();
+        let mut random = [0; 32];
+        rng.fill(&mut random).unwrap();
+        let random = Random::from(random);
+
+        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
+            .unwrap()
+            .compute_public_key()
+            .unwrap();
+
+        let mut server =
+            ServerConnection::new_quic(server_config, quic::Version::V1, b"server params".to_vec())
+                .unwrap();
+
+        let client_hello = Message {
+            version: ProtocolVersion::TLSv1_2,
+            payload: MessagePayload::Handshake(HandshakeMessagePayload {
+                typ: HandshakeType::ClientHello,
+                payload: HandshakePayload::ClientHello(ClientHelloPayload {
+                    client_version: ProtocolVersion::TLSv1_2,
+                    random: random.clone(),
+                    session_id: SessionID::random(&rustls::DefaultSecureRandom).unwrap(),
+                    cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
+                    compression_methods: vec![Compression::Null],
+                    extensions: vec![
+                        ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
+                        ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
+                        ClientExtension::KeyShare(vec![KeyShareEntry {
+                            group: NamedGroup::X25519,
+                            payload: PayloadU16::new(kx.as_ref().to_vec()),
+                        }]),
+                    ],
+                }),
+            }),
+        };
+
+        let buf = PlainMessage::from(client_hello)
+            .into_unencrypted_opaque()
+            .encode();
+        server
+            .read_tls(&mut buf.as_slice())
+            .unwrap();
+        assert_eq!(
+            server.process_new_packets().err(),
+            Some(Error::PeerIncompatibleError(
+                "Server requires TLS1.3, but client omitted versions ext".into(),
+            )),
+        );
+    }
+
+    #[test]
+    fn packet_key_api() {
         use rustls::quic::{Keys, Version};
 
-        // Test vectors: https://www.rfc-editor.org/rfc/rfc9001.html#name-client-initial
-        const CONNECTION_ID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
-        const PACKET_NUMBER: u64 = 2;
-        const PLAIN_HEADER: &[u8] = &[
-            0xc3, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
-            0x00, 0x00, 0x44, 0x9e, 0x00, 0x00, 0x00, 0x02,
-        ];
+        // Test vectors: https://www.rfc-editor.org/rfc/rfc9001.html#name-client-initial
+        const CONNECTION_ID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        const PACKET_NUMBER: u64 = 2;
+        const PLAIN_HEADER: &[u8] = &[
+            0xc3, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
+            0x00, 0x00, 0x44, 0x9e, 0x00, 0x00, 0x00, 0x02,
+        ];
+
+        const PAYLOAD: &[u8] = &[
+            0x06, 0x00, 0x40, 0xf1, 0x01, 0x00, 0x00, 0xed, 0x03, 0x03, 0xeb, 0xf8, 0xfa, 0x56,
+            0xf1, 0x29, 0x39, 0xb9, 0x58, 0x4a, 0x38, 0x96, 0x47, 0x2e, 0xc4, 0x0b, 0xb8, 0x63,
+            0xcf, 0xd3, 0xe8, 0x68, 0x04, 0xfe, 0x3a, 0x47, 0xf0, 0x6a, 0x2b, 0x69, 0x48, 0x4c,
+            0x00, 0x00, 0x04, 0x13, 0x01, 0x13, 0x02, 0x01, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00,
+            0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
+            0x63, 0x6f, 0x6d, 0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x06,
+            0x00, 0x1d, 0x00, 0x17, 0x00, 0x18, 0x00, 0x10, 0x00, 0x07, 0x00, 0x05, 0x04, 0x61,
+            0x6c, 0x70, 0x6e, 0x00, 0x05, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33,
+            0x00, 0x26, 0x00, 0x24, 0x00, 0x1d, 0x00, 0x20, 0x93, 0x70, 0xb2, 0xc9, 0xca, 0xa4,
+            0x7f, 0xba, 0xba, 0xf4, 0x55, 0x9f, 0xed, 0xba, 0x75, 0x3d, 0xe1, 0x71, 0xfa, 0x71,
+            0xf5, 0x0f, 0x1c, 0xe1, 0x5d, 0x43, 0xe9, 0x94, 0xec, 0x74, 0xd7, 0x48, 0x00, 0x2b,
+            0x00, 0x03, 0x02, 0x03, 0x04, 0x00, 0x0d, 0x00, 0x10, 0x00, 0x0e, 0x04, 0x03, 0x05,
+            0x03, 0x06, 0x03, 0x02, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08, 0x06, 0x00, 0x2d, 0x00,
+            0x02, 0x01, 0x01, 0x00, 0x1c, 0x00, 0x02, 0x40, 0x01, 0x00, 0x39, 0x00, 0x32, 0x04,
+            0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x05, 0x04, 0x80, 0x00, 0xff,
+            0xff, 0x07, 0x04, 0x80, 0x00, 0xff, 0xff, 0x08, 0x01, 0x10, 0x01, 0x04, 0x80, 0x00,
+            0x75, 0x30, 0x09, 0x01, 0x10, 0x0f, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57,
+            0x08, 0x06, 0x04, 0x80, 0x00, 0xff, 0xff,
+        ];
+
+        let client_keys = Keys::initial(Version::V1, &CONNECTION_ID, true);
+        assert_eq!(
+            client_keys
+                .local
+                .packet
+                .confidentiality_limit(),
+            2u64.pow(23)
+        );
+        assert_eq!(
+            client_keys
+                .local
+                .packet
+                .integrity_limit(),
+            2u64.pow(52)
+        );
+        assert_eq!(client_keys.local.packet.tag_len(), 16);
+
+        let mut buf = Vec::new();
+        buf.extend(PLAIN_HEADER);
+        buf.extend(PAYLOAD);
+        let header_len = PLAIN_HEADER.len();
+        let tag_len = client_keys.local.packet.tag_len();
+        let padding_len = 1200 - header_len - PAYLOAD.len() - tag_len;
+        buf.extend(std::iter::repeat(0).take(padding_len));
+        let (header, payload) = buf.split_at_mut(header_len);
+        let tag = client_keys
+            .local
+            .packet
+            .encrypt_in_place(PACKET_NUMBER, &*header, payload)
+            .unwrap();
+
+        let sample_len = client_keys.local.header.sample_len();
+        let sample = &payload[..sample_len];
+        let (first, rest) = header.split_at_mut(1);
+        client_keys
+            .local
+            .header
+            .encrypt_in_place(sample, &mut first[0], &mut rest[17..21])
+            .unwrap();
+        buf.extend_from_slice(tag.as_ref());
+
+        const PROTECTED: &[u8] = &[
+            0xc0, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
+            0x00, 0x00, 0x44, 0x9e, 0x7b, 0x9a, 0xec, 0x34, 0xd1, 0xb1, 0xc9, 0x8d, 0xd7, 0x68,
+            0x9f, 0xb8, 0xec, 0x11, 0xd2, 0x42, 0xb1, 0x23, 0xdc, 0x9b, 0xd8, 0xba, 0xb9, 0x36,
+            0xb4, 0x7d, 0x92, 0xec, 0x35, 0x6c, 0x0b, 0xab, 0x7d, 0xf5, 0x97, 0x6d, 0x27, 0xcd,
+            0x44, 0x9f, 0x63, 0x30, 0x00, 0x99, 0xf3, 0x99, 0x1c, 0x26, 0x0e, 0xc4, 0xc6, 0x0d,
+            0x17, 0xb3, 0x1f, 0x84, 0x29, 0x15, 0x7b, 0xb3, 0x5a, 0x12, 0x82, 0xa6, 0x43, 0xa8,
+            0xd2, 0x26, 0x2c, 0xad, 0x67, 0x50, 0x0c, 0xad, 0xb8, 0xe7, 0x37, 0x8c, 0x8e, 0xb7,
+            0x53, 0x9e, 0xc4, 0xd4, 0x90, 0x5f, 0xed, 0x1b, 0xee, 0x1f, 0xc8, 0xaa, 0xfb, 0xa1,
+            0x7c, 0x75, 0x0e, 0x2c, 0x7a, 0xce, 0x01, 0xe6, 0x00, 0x5f, 0x80, 0xfc, 0xb7, 0xdf,
+            0x62, 0x12, 0x30, 0xc8, 0x37, 0x11, 0xb3, 0x93, 0x43, 0xfa, 0x02, 0x8c, 0xea, 0x7f,
+            0x7f, 0xb5, 0xff, 0x89, 0xea, 0xc2, 0x30, 0x82, 0x49, 0xa0, 0x22, 0x52, 0x15, 0x5e,
+            0x23, 0x47, 0xb6, 0x3d, 0x58, 0xc5, 0x45, 0x7a, 0xfd, 0x84, 0xd0, 0x5d, 0xff, 0xfd,
+            0xb2, 0x03, 0x92, 0x84, 0x4a, 0xe8, 0x12, 0x15, 0x46, 0x82, 0xe9, 0xcf, 0x01, 0x2f,
+            0x90, 0x21, 0xa6, 0xf0, 0xbe, 0x17, 0xdd, 0xd0, 0xc2, 0x08, 0x4d, 0xce, 0x25, 0xff,
+            0x9b, 0x06, 0xcd, 0xe5, 0x35, 0xd0, 0xf9, 0x20, 0xa2, 0xdb, 0x1b, 0xf3, 0x62, 0xc2,
+            0x3e, 0x59, 0x6d, 0x11, 0xa4, 0xf5, 0xa6, 0xcf, 0x39, 0x48, 0x83, 0x8a, 0x3a, 0xec,
+            0x4e, 0x15, 0xda, 0xf8, 0x50, 0x0a, 0x6e, 0xf6, 0x9e, 0xc4, 0xe3, 0xfe, 0xb6, 0xb1,
+            0xd9, 0x8e, 0x61, 0x0a, 0xc8, 0xb7, 0xec, 0x3f, 0xaf, 0x6a, 0xd7, 0x60, 0xb7, 0xba,
+            0xd1, 0xdb, 0x4b, 0xa3, 0x48, 0x5e, 0x8a, 0x94, 0xdc, 0x25, 0x0a, 0xe3, 0xfd, 0xb4,
+            0x1e, 0xd1, 0x5f, 0xb6, 0xa8, 0xe5, 0xeb, 0xa0, 0xfc, 0x3d, 0xd6, 0x0b, 0xc8, 0xe3,
+            0x0c, 0x5c, 0x42, 0x87, 0xe5, 0x38, 0x05, 0xdb, 0x05, 0x9a, 0xe0, 0x64, 0x8d, 0xb2,
+            0xf6, 0x42, 0x64, 0xed, 0x5e, 0x39, 0xbe, 0x2e, 0x20, 0xd8, 0x2d, 0xf5, 0x66, 0xda,
+            0x8d, 0xd5, 0x99, 0x8c, 0xca, 0xbd, 0xae, 0x05, 0x30, 0x60, 0xae, 0x6c, 0x7b, 0x43,
+            0x78, 0xe8, 0x46, 0xd2, 0x9f, 0x37, 0xed, 0x7b, 0x4e, 0xa9, 0xec, 0x5d, 0x82, 0xe7,
+            0x96, 0x1b, 0x7f, 0x25, 0xa9, 0x32, 0x38, 0x51, 0xf6, 0x81, 0xd5, 0x82, 0x36, 0x3a,
+            0xa5, 0xf8, 0x99, 0x37, 0xf5, 0xa6, 0x72, 0x58, 0xbf, 0x63, 0xad, 0x6f, 0x1a, 0x0b,
+            0x1d, 0x96, 0xdb, 0xd4, 0xfa, 0xdd, 0xfc, 0xef, 0xc5, 0x26, 0x6b, 0xa6, 0x61, 0x17,
+            0x22, 0x39, 0x5c, 0x90, 0x65, 0x56, 0xbe, 0x52, 0xaf, 0xe3, 0xf5, 0x65, 0x63, 0x6a,
+            0xd1, 0xb1, 0x7d, 0x50, 0x8b, 0x73, 0xd8, 0x74, 0x3e, 0xeb, 0x52, 0x4b, 0xe2, 0x2b,
+            0x3d, 0xcb, 0xc2, 0xc7, 0x46, 0x8d, 0x54, 0x11, 0x9c, 0x74, 0x68, 0x44, 0x9a, 0x13,
+            0xd8, 0xe3, 0xb9, 0x58, 0x11, 0xa1, 0x98, 0xf3, 0x49, 0x1d, 0xe3, 0xe7, 0xfe, 0x94,
+            0x2b, 0x33, 0x04, 0x07, 0xab, 0xf8, 0x2a, 0x4e, 0xd7, 0xc1, 0xb3, 0x11, 0x66, 0x3a,
+            0xc6, 0x98, 0x90, 0xf4, 0x15, 0x70, 0x15, 0x85, 0x3d, 0x91, 0xe9, 0x23, 0x03, 0x7c,
+            0x22, 0x7a, 0x33, 0xcd, 0xd5, 0xec, 0x28, 0x1c, 0xa3, 0xf7, 0x9c, 0x44, 0x54, 0x6b,
+            0x9d, 0x90, 0xca, 0x00, 0xf0, 0x64, 0xc9, 0x9e, 0x3d, 0xd9, 0x79, 0x11, 0xd3, 0x9f,
+            0xe9, 0xc5, 0xd0, 0xb2, 0x3a, 0x22, 0x9a, 0x23, 0x4c, 0xb3, 0x61, 0x86, 0xc4, 0x81,
+            0x9e, 0x8b, 0x9c, 0x59, 0x27, 0x72, 0x66, 0x32, 0x29, 0x1d, 0x6a, 0x41, 0x82, 0x11,
+            0xcc, 0x29, 0x62, 0xe2, 0x0f, 0xe4, 0x7f, 0xeb, 0x3e, 0xdf, 0x33, 0x0f, 0x2c, 0x60,
+            0x3a, 0x9d, 0x48, 0xc0, 0xfc, 0xb5, 0x69, 0x9d, 0xbf, 0xe5, 0x89, 0x64, 0x25, 0xc5,
+            0xba, 0xc4, 0xae, 0xe8, 0x2e, 0x57, 0xa8, 0x5a, 0xaf, 0x4e, 0x25, 0x13, 0xe4, 0xf0,
+            0x57, 0x96, 0xb0, 0x7b, 0xa2, 0xee, 0x47, 0xd8, 0x05, 0x06, 0xf8, 0xd2, 0xc2, 0x5e,
+            0x50, 0xfd, 0x14, 0xde, 0x71, 0xe6, 0xc4, 0x18, 0x55, 0x93, 0x02, 0xf9, 0x39, 0xb0,
+            0xe1, 0xab, 0xd5, 0x76, 0xf2, 0x79, 0xc4, 0xb2, 0xe0, 0xfe, 0xb8, 0x5c, 0x1f, 0x28,
+            0xff, 0x18, 0xf5, 0x88, 0x91, 0xff, 0xef, 0x13, 0x2e, 0xef, 0x2f, 0xa0, 0x93, 0x46,
+            0xae, 0xe3, 0x3c, 0x28, 0xeb, 0x13, 0x0f, 0xf2, 0x8f, 0x5b, 0x76, 0x69, 0x53, 0x33,
+            0x41, 0x13, 0x21, 0x19, 0x96, 0xd2, 0x00, 0x11, 0xa1, 0x98, 0xe3, 0xfc, 0x43, 0x3f,
+            0x9f, 0x25, 0x41, 0x01, 0x0a, 0xe1, 0x7c, 0x1b, 0xf2, 0x02, 0x58, 0x0f, 0x60, 0x47,
+            0x47, 0x2f, 0xb3, 0x68, 0x57, 0xfe, 0x84, 0x3b, 0x19, 0xf5, 0x98, 0x40, 0x09, 0xdd,
+            0xc3, 0x24, 0x04, 0x4e, 0x84, 0x7a, 0x4f, 0x4a, 0x0a, 0xb3, 0x4f, 0x71, 0x95, 0x95,
+            0xde, 0x37, 0x25, 0x2d, 0x62, 0x35, 0x36, 0x5e, 0x9b, 0x84, 0x39, 0x2b, 0x06, 0x10,
+            0x85, 0x34, 0x9d, 0x73, 0x20, 0x3a, 0x4a, 0x13, 0xe9, 0x6f, 0x54, 0x32, 0xec, 0x0f,
+            0xd4, 0xa1, 0xee, 0x65, 0xac, 0xcd, 0xd5, 0xe3, 0x90, 0x4d, 0xf5, 0x4c, 0x1d, 0xa5,
+            0x10, 0xb0, 0xff, 0x20, 0xdc, 0xc0, 0xc7, 0x7f, 0xcb, 0x2c, 0x0e, 0x0e, 0xb6, 0x05,
+            0xcb, 0x05, 0x04, 0xdb, 0x87, 0x63, 0x2c, 0xf3, 0xd8, 0xb4, 0xda, 0xe6, 0xe7, 0x05,
+            0x76, 0x9d, 0x1d, 0xe3, 0x54, 0x27, 0x01, 0x23, 0xcb, 0x11, 0x45, 0x0e, 0xfc, 0x60,
+            0xac, 0x47, 0x68, 0x3d, 0x7b, 0x8d, 0x0f, 0x81, 0x13, 0x65, 0x56, 0x5f, 0xd9, 0x8c,
+            0x4c, 0x8e, 0xb9, 0x36, 0xbc, 0xab, 0x8d, 0x06, 0x9f, 0xc3, 0x3b, 0xd8, 0x01, 0xb0,
+            0x3a, 0xde, 0xa2, 0xe1, 0xfb, 0xc5, 0xaa, 0x46, 0x3d, 0x08, 0xca, 0x19, 0x89, 0x6d,
+            0x2b, 0xf5, 0x9a, 0x07, 0x1b, 0x85, 0x1e, 0x6c, 0x23, 0x90, 0x52, 0x17, 0x2f, 0x29,
+            0x6b, 0xfb, 0x5e, 0x72, 0x40, 0x47, 0x90, 0xa2, 0x18, 0x10, 0x14, 0xf3, 0xb9, 0x4a,
+            0x4e, 0x97, 0xd1, 0x17, 0xb4, 0x38, 0x13, 0x03, 0x68, 0xcc, 0x39, 0xdb, 0xb2, 0xd1,
+            0x98, 0x06, 0x5a, 0xe3, 0x98, 0x65, 0x47, 0x92, 0x6c, 0xd2, 0x16, 0x2f, 0x40, 0xa2,
+            0x9f, 0x0c, 0x3c, 0x87, 0x45, 0xc0, 0xf5, 0x0f, 0xba, 0x38, 0x52, 0xe5, 0x66, 0xd4,
+            0x45, 0x75, 0xc2, 0x9d, 0x39, 0xa0, 0x3f, 0x0c, 0xda, 0x72, 0x19, 0x84, 0xb6, 0xf4,
+            0x40, 0x59, 0x1f, 0x35, 0x5e, 0x12, 0xd4, 0x39, 0xff, 0x15, 0x0a, 0xab, 0x76, 0x13,
+            0x49, 0x9d, 0xbd, 0x49, 0xad, 0xab, 0xc8, 0x67, 0x6e, 0xef, 0x02, 0x3b, 0x15, 0xb6,
+            0x5b, 0xfc, 0x5c, 0xa0, 0x69, 0x48, 0x10, 0x9f, 0x23, 0xf3, 0x50, 0xdb, 0x82, 0x12,
+            0x35, 0x35, 0xeb, 0x8a, 0x74, 0x33, 0xbd, 0xab, 0xcb, 0x90, 0x92, 0x71, 0xa6, 0xec,
+            0xbc, 0xb5, 0x8b, 0x93, 0x6a, 0x88, 0xcd, 0x4e, 0x8f, 0x2e, 0x6f, 0xf5, 0x80, 0x01,
+            0x75, 0xf1, 0x13, 0x25, 0x3d, 0x8f, 0xa9, 0xca, 0x88, 0x85, 0xc2, 0xf5, 0x52, 0xe6,
+            0x57, 0xdc, 0x60, 0x3f, 0x25, 0x2e, 0x1a, 0x8e, 0x30, 0x8f, 0x76, 0xf0, 0xbe, 0x79,
+            0xe2, 0xfb, 0x8f, 0x5d, 0x5f, 0xbb, 0xe2, 0xe3, 0x0e, 0xca, 0xdd, 0x22, 0x07, 0x23,
+            0xc8, 0xc0, 0xae, 0xa8, 0x07, 0x8c, 0xdf, 0xcb, 0x38, 0x68, 0x26, 0x3f, 0xf8, 0xf0,
+            0x94, 0x00, 0x54, 0xda, 0x48, 0x78, 0x18, 0x93, 0xa7, 0xe4, 0x9a, 0xd5, 0xaf, 0xf4,
+            0xaf, 0x30, 0x0c, 0xd8, 0x04, 0xa6, 0xb6, 0x27, 0x9a, 0xb3, 0xff, 0x3a, 0xfb, 0x64,
+            0x49, 0x1c, 0x85, 0x19, 0x4a, 0xab, 0x76, 0x0d, 0x58, 0xa6, 0x06, 0x65, 0x4f, 0x9f,
+            0x44, 0x00, 0xe8, 0xb3, 0x85, 0x91, 0x35, 0x6f, 0xbf, 0x64, 0x25, 0xac, 0xa2, 0x6d,
+            0xc8, 0x52, 0x44, 0x25, 0x9f, 0xf2, 0xb1, 0x9c, 0x41, 0xb9, 0xf9, 0x6f, 0x3c, 0xa9,
+            0xec, 0x1d, 0xde, 0x43, 0x4d, 0xa7, 0xd2, 0xd3, 0x92, 0xb9, 0x05, 0xdd, 0xf3, 0xd1,
+            0xf9, 0xaf, 0x93, 0xd1, 0xaf, 0x59, 0x50, 0xbd, 0x49, 0x3f, 0x5a, 0xa7, 0x31, 0xb4,
+            0x05, 0x6d, 0xf3, 0x1b, 0xd2, 0x67, 0xb6, 0xb9, 0x0a, 0x07, 0x98, 0x31, 0xaa, 0xf5,
+            0x79, 0xbe, 0x0a, 0x39, 0x01, 0x31, 0x37, 0xaa, 0xc6, 0xd4, 0x04, 0xf5, 0x18, 0xcf,
+            0xd4, 0x68, 0x40, 0x64, 0x7e, 0x78, 0xbf, 0xe7, 0x06, 0xca, 0x4c, 0xf5, 0xe9, 0xc5,
+            0x45, 0x3e, 0x9f, 0x7c, 0xfd, 0x2b, 0x8b, 0x4c, 0x8d, 0x16, 0x9a, 0x44, 0xe5, 0x5c,
+            0x88, 0xd4, 0xa9, 0xa7, 0xf9, 0x47, 0x42, 0x41, 0xe2, 0x21, 0xaf, 0x44, 0x86, 0x00,
+            0x18, 0xab, 0x08, 0x56, 0x97, 0x2e, 0x19, 0x4c, 0xd9, 0x34,
+        ];
+
+        assert_eq!(&buf, PROTECTED);
+
+        let (header, payload) = buf.split_at_mut(header_len);
+        let (first, rest) = header.split_at_mut(1);
+        let sample = &payload[..sample_len];
+
+        let server_keys = Keys::initial(Version::V1, &CONNECTION_ID, false);
+        server_keys
+            .remote
+            .header
+            .decrypt_in_place(sample, &mut first[0], &mut rest[17..21])
+            .unwrap();
+        let payload = server_keys
+            .remote
+            .packet
+            .decrypt_in_place(PACKET_NUMBER, &*header, payload)
+            .unwrap();
+
+        assert_eq!(&payload[..PAYLOAD.len()], PAYLOAD);
+        assert_eq!(payload.len(), buf.len() - header_len - tag_len);
+    }
+
+    #[test]
+    fn test_quic_exporter() {
+        for &kt in ALL_KEY_TYPES.iter() {
+            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+            let server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+
+            do_exporter_test(client_config, server_config);
+        }
+    }
+} // mod test_quic
+
+#[test]
+fn test_client_does_not_offer_sha1() {
+    use rustls::internal::msgs::{
+        codec::Reader, enums::HandshakeType, handshake::HandshakePayload, message::MessagePayload,
+        message::OpaqueMessage,
+    };
+
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, _) = make_pair_for_configs(client_config, make_server_config(*kt));
+
+            assert!(client.wants_write());
+            let mut buf = [0u8; 262144];
+            let sz = client
+                .write_tls(&mut buf.as_mut())
+                .unwrap();
+            let msg = OpaqueMessage::read(&mut Reader::init(&buf[..sz])).unwrap();
+            let msg = Message::try_from(msg.into_plain_message()).unwrap();
+            assert!(msg.is_handshake_type(HandshakeType::ClientHello));
+
+            let client_hello = match msg.payload {
+                MessagePayload::Handshake(hs) => match hs.payload {
+                    HandshakePayload::ClientHello(ch) => ch,
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            };
+
+            let sigalgs = client_hello
+                .get_sigalgs_extension()
+                .unwrap();
+            assert!(
+                !sigalgs.contains(&SignatureScheme::RSA_PKCS1_SHA1),
+                "sha1 unexpectedly offered"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_client_config_keyshare() {
+    let client_config =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn test_client_config_keyshare_mismatch() {
+    let client_config =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+}
+
+#[test]
+fn negotiated_key_exchange_group() {
+    for version in rustls::ALL_VERSIONS {
+        for kx_group in &[&rustls::kx_group::X25519, &rustls::kx_group::SECP256R1] {
+            let client_config = finish_client_config(
+                KeyType::Rsa,
+                ClientConfig::builder()
+                    .with_safe_default_cipher_suites()
+                    .with_kx_groups(&[kx_group])
+                    .with_protocol_versions(&[version])
+                    .unwrap(),
+            );
+            let server_config = make_server_config(KeyType::Rsa);
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+            assert_eq!(client.get_negotiated_key_exchange_group(), None);
+            assert_eq!(server.get_negotiated_key_exchange_group(), None);
+
+            transfer(&mut client, &mut server);
+            assert_eq!(client.get_negotiated_key_exchange_group(), None);
+
+            do_handshake(&mut client, &mut server);
+            assert_eq!(
+                client.get_negotiated_key_exchange_group(),
+                Some(kx_group.name)
+            );
+            assert_eq!(
+                server.get_negotiated_key_exchange_group(),
+                Some(kx_group.name)
+            );
+        }
+    }
+}
+
+#[cfg(feature = "kyber")]
+#[test]
+fn hybrid_kx_group_is_negotiated() {
+    let kx_groups = [
+        &rustls::kx_group::X25519_KYBER768_DRAFT00,
+        &rustls::kx_group::X25519,
+    ];
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_kx_groups(*kt, &kx_groups);
+        let server_config = make_server_config_with_kx_groups(*kt, &kx_groups);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        let expected = Some(rustls::kx_group::X25519_KYBER768_DRAFT00.name);
+        assert_eq!(client.get_negotiated_key_exchange_group(), expected);
+        assert_eq!(server.get_negotiated_key_exchange_group(), expected);
+        assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    }
+}
+
+#[cfg(feature = "kyber")]
+#[test]
+fn hybrid_kx_group_falls_back_to_x25519() {
+    let client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[
+            &rustls::kx_group::X25519_KYBER768_DRAFT00,
+            &rustls::kx_group::X25519,
+        ],
+    );
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    let expected = Some(rustls::kx_group::X25519.name);
+    assert_eq!(client.get_negotiated_key_exchange_group(), expected);
+    assert_eq!(server.get_negotiated_key_exchange_group(), expected);
+}
+
+#[cfg(all(feature = "kyber", feature = "tls12"))]
+#[test]
+fn hybrid_kx_group_is_not_used_for_tls12() {
+    let kx_groups = [
+        &rustls::kx_group::X25519_KYBER768_DRAFT00,
+        &rustls::kx_group::X25519,
+    ];
+    let client_config = finish_client_config(
+        KeyType::Rsa,
+        ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_kx_groups(&kx_groups)
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .unwrap(),
+    );
+    let server_config = make_server_config_with_kx_groups(KeyType::Rsa, &kx_groups);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    let expected = Some(rustls::kx_group::X25519.name);
+    assert_eq!(client.get_negotiated_key_exchange_group(), expected);
+    assert_eq!(server.get_negotiated_key_exchange_group(), expected);
+}
+
+#[test]
+fn test_client_sends_helloretryrequest() {
+    // client sends a secp384r1 key share
+    let mut client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+
+    let storage = Arc::new(ClientStorage::new());
+    client_config.session_storage = storage.clone();
+
+    // but server only accepts x25519, so a HRR is required
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    // client sends hello
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200);
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0].len() == 1);
+    }
+
+    // server sends HRR
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen < 100); // just the hello retry request
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 2); // hello retry request and CCS
+    }
+
+    // client sends fixed hello
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200); // just the client hello retry
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 2); // only a CCS & client hello retry
+    }
+
+    // server completes handshake
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200);
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0].len() == 5); // server hello / encrypted exts / cert / cert-verify / finished
+    }
+
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+
+    // client only did two storage queries: one for a session, another for a kx type
+    assert_eq!(storage.gets(), 2);
+    assert_eq!(storage.puts(), 2);
+}
+
+#[test]
+fn test_client_attempts_to_use_unsupported_kx_group() {
+    // common to both client configs
+    let shared_storage = Arc::new(ClientStorage::new());
+
+    // first, client sends a x25519 and server agrees. x25519 is inserted
+    //   into kx group cache.
+    let mut client_config_1 =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    client_config_1.session_storage = shared_storage.clone();
+
+    // second, client only supports secp-384 and so kx group cache
+    //   contains an unusable value.
+    let mut client_config_2 =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    client_config_2.session_storage = shared_storage;
+
+    let server_config = make_server_config(KeyType::Rsa);
+
+    // first handshake
+    let (mut client_1, mut server) = make_pair_for_configs(client_config_1, server_config.clone());
+    do_handshake_until_error(&mut client_1, &mut server).unwrap();
+
+    // second handshake
+    let (mut client_2, mut server) = make_pair_for_configs(client_config_2, server_config);
+    do_handshake_until_error(&mut client_2, &mut server).unwrap();
+}
+
+#[test]
+fn test_client_mtu_reduction() {
+    struct CollectWrites {
+        writevs: Vec<Vec<usize>>,
+    }
+
+    impl io::Write for CollectWrites {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            panic!()
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            panic!()
+        }
+        fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
+            let writes = b
+                .iter()
+                .map(|slice| slice.len())
+                .collect::<Vec<usize>>();
+            let len = writes.iter().sum();
+            self.writevs.push(writes);
+            Ok(len)
+        }
+    }
+
+    fn collect_write_lengths(client: &mut ClientConnection) -> Vec<usize> {
+        let mut collector = CollectWrites { writevs: vec![] };
+
+        client
+            .write_tls(&mut collector)
+            .unwrap();
+        assert_eq!(collector.writevs.len(), 1);
+        collector.writevs[0].clone()
+    }
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.max_fragment_size = Some(64);
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
+        let writes = collect_write_lengths(&mut client);
+        println!("writes at mtu=64: {:?}", writes);
+        assert!(writes.iter().all(|x| *x <= 64));
+        assert!(writes.len() > 1);
+    }
+}
+
+#[test]
+fn test_server_mtu_reduction() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.max_fragment_size = Some(64);
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+
+    let big_data = [0u8; 2048];
+    server
+        .writer()
+        .write_all(&big_data)
+        .unwrap();
 
-        const PAYLOAD: &[u8] = &[
-            0x06, 0x00, 0x40, 0xf1, 0x01, 0x00, 0x00, 0xed, 0x03, 0x03, 0xeb, 0xf8, 0xfa, 0x56,
-            0xf1, 0x29, 0x39, 0xb9, 0x58, 0x4a, 0x38, 0x96, 0x47, 0x2e, 0xc4, 0x0b, 0xb8, 0x63,
-            0xcf, 0xd3, 0xe8, 0x68, 0x04, 0xfe, 0x3a, 0x47, 0xf0, 0x6a, 0x2b, 0x69, 0x48, 0x4c,
-            0x00, 0x00, 0x04, 0x13, 0x01, 0x13, 0x02, 0x01, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00,
-            0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
-            0x63, 0x6f, 0x6d, 0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x06,
-            0x00, 0x1d, 0x00, 0x17, 0x00, 0x18, 0x00, 0x10, 0x00, 0x07, 0x00, 0x05, 0x04, 0x61,
-            0x6c, 0x70, 0x6e, 0x00, 0x05, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33,
-            0x00, 0x26, 0x00, 0x24, 0x00, 0x1d, 0x00, 0x20, 0x93, 0x70, 0xb2, 0xc9, 0xca, 0xa4,
-            0x7f, 0xba, 0xba, 0xf4, 0x55, 0x9f, 0xed, 0xba, 0x75, 0x3d, 0xe1, 0x71, 0xfa, 0x71,
-            0xf5, 0x0f, 0x1c, 0xe1, 0x5d, 0x43, 0xe9, 0x94, 0xec, 0x74, 0xd7, 0x48, 0x00, 0x2b,
-            0x00, 0x03, 0x02, 0x03, 0x04, 0x00, 0x0d, 0x00, 0x10, 0x00, 0x0e, 0x04, 0x03, 0x05,
-            0x03, 0x06, 0x03, 0x02, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08, 0x06, 0x00, 0x2d, 0x00,
-            0x02, 0x01, 0x01, 0x00, 0x1c, 0x00, 0x02, 0x40, 0x01, 0x00, 0x39, 0x00, 0x32, 0x04,
-            0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x05, 0x04, 0x80, 0x00, 0xff,
-            0xff, 0x07, 0x04, 0x80, 0x00, 0xff, 0xff, 0x08, 0x01, 0x10, 0x01, 0x04, 0x80, 0x00,
-            0x75, 0x30, 0x09, 0x01, 0x10, 0x0f, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57,
-            0x08, 0x06, 0x04, 0x80, 0x00, 0xff, 0xff,
-        ];
+    let encryption_overhead = 20; // FIXME: see issue #991
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        server.write_tls(&mut pipe).unwrap();
+
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0]
+            .iter()
+            .all(|x| *x <= 64 + encryption_overhead));
+    }
+
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        server.write_tls(&mut pipe).unwrap();
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0]
+            .iter()
+            .all(|x| *x <= 64 + encryption_overhead));
+    }
+
+    client.process_new_packets().unwrap();
+    check_read(&mut client.reader(), &big_data);
+}
+
+#[test]
+fn connection_max_fragment_size_can_be_changed() {
+    // TLS1.2 AES-GCM explicit nonce and tag; see issue #991
+    let encryption_overhead = 24;
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
+
+        for size in &[64, 1000, 4096] {
+            server
+                .set_max_fragment_size(Some(*size))
+                .unwrap();
+            server
+                .writer()
+                .write_all(&[0u8; 8192])
+                .unwrap();
+            let records = transfer_records(&mut server, &mut client);
+            assert!(records
+                .iter()
+                .all(|r| *r <= size + encryption_overhead));
+            assert!(records.iter().any(|r| *r >= size - 5));
+            client.process_new_packets().unwrap();
+            check_read(&mut client.reader(), &[0u8; 8192]);
+        }
 
-        let client_keys = Keys::initial(Version::V1, &CONNECTION_ID, true);
         assert_eq!(
-            client_keys
-                .local
-                .packet
-                .confidentiality_limit(),
-            2u64.pow(23)
+            server.set_max_fragment_size(Some(31)),
+            Err(Error::BadMaxFragmentSize)
         );
         assert_eq!(
-            client_keys
-                .local
-                .packet
-                .integrity_limit(),
-            2u64.pow(52)
+            server.set_max_fragment_size(Some(0x4006)),
+            Err(Error::BadMaxFragmentSize)
         );
-        assert_eq!(client_keys.local.packet.tag_len(), 16);
 
-        let mut buf = Vec::new();
-        buf.extend(PLAIN_HEADER);
-        buf.extend(PAYLOAD);
-        let header_len = PLAIN_HEADER.len();
-        let tag_len = client_keys.local.packet.tag_len();
-        let padding_len = 1200 - header_len - PAYLOAD.len() - tag_len;
-        buf.extend(std::iter::repeat(0).take(padding_len));
-        let (header, payload) = buf.split_at_mut(header_len);
-        let tag = client_keys
-            .local
-            .packet
-            .encrypt_in_place(PACKET_NUMBER, &*header, payload)
+        // rejected values leave the previous setting alone
+        server
+            .writer()
+            .write_all(&[0u8; 8192])
             .unwrap();
+        let records = transfer_records(&mut server, &mut client);
+        assert!(records
+            .iter()
+            .all(|r| *r <= 4096 + encryption_overhead));
+        client.process_new_packets().unwrap();
+    }
+}
 
-        let sample_len = client_keys.local.header.sample_len();
-        let sample = &payload[..sample_len];
-        let (first, rest) = header.split_at_mut(1);
-        client_keys
-            .local
-            .header
-            .encrypt_in_place(sample, &mut first[0], &mut rest[17..21])
-            .unwrap();
-        buf.extend_from_slice(tag.as_ref());
+#[test]
+fn connection_max_fragment_size_is_limited_by_record_size_limit() {
+    let encryption_overhead = 20; // FIXME: see issue #991
 
-        const PROTECTED: &[u8] = &[
-            0xc0, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
-            0x00, 0x00, 0x44, 0x9e, 0x7b, 0x9a, 0xec, 0x34, 0xd1, 0xb1, 0xc9, 0x8d, 0xd7, 0x68,
-            0x9f, 0xb8, 0xec, 0x11, 0xd2, 0x42, 0xb1, 0x23, 0xdc, 0x9b, 0xd8, 0xba, 0xb9, 0x36,
-            0xb4, 0x7d, 0x92, 0xec, 0x35, 0x6c, 0x0b, 0xab, 0x7d, 0xf5, 0x97, 0x6d, 0x27, 0xcd,
-            0x44, 0x9f, 0x63, 0x30, 0x00, 0x99, 0xf3, 0x99, 0x1c, 0x26, 0x0e, 0xc4, 0xc6, 0x0d,
-            0x17, 0xb3, 0x1f, 0x84, 0x29, 0x15, 0x7b, 0xb3, 0x5a, 0x12, 0x82, 0xa6, 0x43, 0xa8,
-            0xd2, 0x26, 0x2c, 0xad, 0x67, 0x50, 0x0c, 0xad, 0xb8, 0xe7, 0x37, 0x8c, 0x8e, 0xb7,
-            0x53, 0x9e, 0xc4, 0xd4, 0x90, 0x5f, 0xed, 0x1b, 0xee, 0x1f, 0xc8, 0xaa, 0xfb, 0xa1,
-            0x7c, 0x75, 0x0e, 0x2c, 0x7a, 0xce, 0x01, 0xe6, 0x00, 0x5f, 0x80, 0xfc, 0xb7, 0xdf,
-            0x62, 0x12, 0x30, 0xc8, 0x37, 0x11, 0xb3, 0x93, 0x43, 0xfa, 0x02, 0x8c, 0xea, 0x7f,
-            0x7f, 0xb5, 0xff, 0x89, 0xea, 0xc2, 0x30, 0x82, 0x49, 0xa0, 0x22, 0x52, 0x15, 0x5e,
-            0x23, 0x47, 0xb6, 0x3d, 0x58, 0xc5, 0x45, 0x7a, 0xfd, 0x84, 0xd0, 0x5d, 0xff, 0xfd,
-            0xb2, 0x03, 0x92, 0x84, 0x4a, 0xe8, 0x12, 0x15, 0x46, 0x82, 0xe9, 0xcf, 0x01, 0x2f,
-            0x90, 0x21, 0xa6, 0xf0, 0xbe, 0x17, 0xdd, 0xd0, 0xc2, 0x08, 0x4d, 0xce, 0x25, 0xff,
-            0x9b, 0x06, 0xcd, 0xe5, 0x35, 0xd0, 0xf9, 0x20, 0xa2, 0xdb, 0x1b, 0xf3, 0x62, 0xc2,
-            0x3e, 0x59, 0x6d, 0x11, 0xa4, 0xf5, 0xa6, 0xcf, 0x39, 0x48, 0x83, 0x8a, 0x3a, 0xec,
-            0x4e, 0x15, 0xda, 0xf8, 0x50, 0x0a, 0x6e, 0xf6, 0x9e, 0xc4, 0xe3, 0xfe, 0xb6, 0xb1,
-            0xd9, 0x8e, 0x61, 0x0a, 0xc8, 0xb7, 0xec, 0x3f, 0xaf, 0x6a, 0xd7, 0x60, 0xb7, 0xba,
-            0xd1, 0xdb, 0x4b, 0xa3, 0x48, 0x5e, 0x8a, 0x94, 0xdc, 0x25, 0x0a, 0xe3, 0xfd, 0xb4,
-            0x1e, 0xd1, 0x5f, 0xb6, 0xa8, 0xe5, 0xeb, 0xa0, 0xfc, 0x3d, 0xd6, 0x0b, 0xc8, 0xe3,
-            0x0c, 0x5c, 0x42, 0x87, 0xe5, 0x38, 0x05, 0xdb, 0x05, 0x9a, 0xe0, 0x64, 0x8d, 0xb2,
-            0xf6, 0x42, 0x64, 0xed, 0x5e, 0x39, 0xbe, 0x2e, 0x20, 0xd8, 0x2d, 0xf5, 0x66, 0xda,
-            0x8d, 0xd5, 0x99, 0x8c, 0xca, 0xbd, 0xae, 0x05, 0x30, 0x60, 0xae, 0x6c, 0x7b, 0x43,
-            0x78, 0xe8, 0x46, 0xd2, 0x9f, 0x37, 0xed, 0x7b, 0x4e, 0xa9, 0xec, 0x5d, 0x82, 0xe7,
-            0x96, 0x1b, 0x7f, 0x25, 0xa9, 0x32, 0x38, 0x51, 0xf6, 0x81, 0xd5, 0x82, 0x36, 0x3a,
-            0xa5, 0xf8, 0x99, 0x37, 0xf5, 0xa6, 0x72, 0x58, 0xbf, 0x63, 0xad, 0x6f, 0x1a, 0x0b,
-            0x1d, 0x96, 0xdb, 0xd4, 0xfa, 0xdd, 0xfc, 0xef, 0xc5, 0x26, 0x6b, 0xa6, 0x61, 0x17,
-            0x22, 0x39, 0x5c, 0x90, 0x65, 0x56, 0xbe, 0x52, 0xaf, 0xe3, 0xf5, 0x65, 0x63, 0x6a,
-            0xd1, 0xb1, 0x7d, 0x50, 0x8b, 0x73, 0xd8, 0x74, 0x3e, 0xeb, 0x52, 0x4b, 0xe2, 0x2b,
-            0x3d, 0xcb, 0xc2, 0xc7, 0x46, 0x8d, 0x54, 0x11, 0x9c, 0x74, 0x68, 0x44, 0x9a, 0x13,
-            0xd8, 0xe3, 0xb9, 0x58, 0x11, 0xa1, 0x98, 0xf3, 0x49, 0x1d, 0xe3, 0xe7, 0xfe, 0x94,
-            0x2b, 0x33, 0x04, 0x07, 0xab, 0xf8, 0x2a, 0x4e, 0xd7, 0xc1, 0xb3, 0x11, 0x66, 0x3a,
-            0xc6, 0x98, 0x90, 0xf4, 0x15, 0x70, 0x15, 0x85, 0x3d, 0x91, 0xe9, 0x23, 0x03, 0x7c,
-            0x22, 0x7a, 0x33, 0xcd, 0xd5, 0xec, 0x28, 0x1c, 0xa3, 0xf7, 0x9c, 0x44, 0x54, 0x6b,
-            0x9d, 0x90, 0xca, 0x00, 0xf0, 0x64, 0xc9, 0x9e, 0x3d, 0xd9, 0x79, 0x11, 0xd3, 0x9f,
-            0xe9, 0xc5, 0xd0, 0xb2, 0x3a, 0x22, 0x9a, 0x23, 0x4c, 0xb3, 0x61, 0x86, 0xc4, 0x81,
-            0x9e, 0x8b, 0x9c, 0x59, 0x27, 0x72, 0x66, 0x32, 0x29, 0x1d, 0x6a, 0x41, 0x82, 0x11,
-            0xcc, 0x29, 0x62, 0xe2, 0x0f, 0xe4, 0x7f, 0xeb, 0x3e, 0xdf, 0x33, 0x0f, 0x2c, 0x60,
-            0x3a, 0x9d, 0x48, 0xc0, 0xfc, 0xb5, 0x69, 0x9d, 0xbf, 0xe5, 0x89, 0x64, 0x25, 0xc5,
-            0xba, 0xc4, 0xae, 0xe8, 0x2e, 0x57, 0xa8, 0x5a, 0xaf, 0x4e, 0x25, 0x13, 0xe4, 0xf0,
-            0x57, 0x96, 0xb0, 0x7b, 0xa2, 0xee, 0x47, 0xd8, 0x05, 0x06, 0xf8, 0xd2, 0xc2, 0x5e,
-            0x50, 0xfd, 0x14, 0xde, 0x71, 0xe6, 0xc4, 0x18, 0x55, 0x93, 0x02, 0xf9, 0x39, 0xb0,
-            0xe1, 0xab, 0xd5, 0x76, 0xf2, 0x79, 0xc4, 0xb2, 0xe0, 0xfe, 0xb8, 0x5c, 0x1f, 0x28,
-            0xff, 0x18, 0xf5, 0x88, 0x91, 0xff, 0xef, 0x13, 0x2e, 0xef, 0x2f, 0xa0, 0x93, 0x46,
-            0xae, 0xe3, 0x3c, 0x28, 0xeb, 0x13, 0x0f, 0xf2, 0x8f, 0x5b, 0x76, 0x69, 0x53, 0x33,
-            0x41, 0x13, 0x21, 0x19, 0x96, 0xd2, 0x00, 0x11, 0xa1, 0x98, 0xe3, 0xfc, 0x43, 0x3f,
-            0x9f, 0x25, 0x41, 0x01, 0x0a, 0xe1, 0x7c, 0x1b, 0xf2, 0x02, 0x58, 0x0f, 0x60, 0x47,
-            0x47, 0x2f, 0xb3, 0x68, 0x57, 0xfe, 0x84, 0x3b, 0x19, 0xf5, 0x98, 0x40, 0x09, 0xdd,
-            0xc3, 0x24, 0x04, 0x4e, 0x84, 0x7a, 0x4f, 0x4a, 0x0a, 0xb3, 0x4f, 0x71, 0x95, 0x95,
-            0xde, 0x37, 0x25, 0x2d, 0x62, 0x35, 0x36, 0x5e, 0x9b, 0x84, 0x39, 0x2b, 0x06, 0x10,
-            0x85, 0x34, 0x9d, 0x73, 0x20, 0x3a, 0x4a, 0x13, 0xe9, 0x6f, 0x54, 0x32, 0xec, 0x0f,
-            0xd4, 0xa1, 0xee, 0x65, 0xac, 0xcd, 0xd5, 0xe3, 0x90, 0x4d, 0xf5, 0x4c, 0x1d, 0xa5,
-            0x10, 0xb0, 0xff, 0x20, 0xdc, 0xc0, 0xc7, 0x7f, 0xcb, 0x2c, 0x0e, 0x0e, 0xb6, 0x05,
-            0xcb, 0x05, 0x04, 0xdb, 0x87, 0x63, 0x2c, 0xf3, 0xd8, 0xb4, 0xda, 0xe6, 0xe7, 0x05,
-            0x76, 0x9d, 0x1d, 0xe3, 0x54, 0x27, 0x01, 0x23, 0xcb, 0x11, 0x45, 0x0e, 0xfc, 0x60,
-            0xac, 0x47, 0x68, 0x3d, 0x7b, 0x8d, 0x0f, 0x81, 0x13, 0x65, 0x56, 0x5f, 0xd9, 0x8c,
-            0x4c, 0x8e, 0xb9, 0x36, 0xbc, 0xab, 0x8d, 0x06, 0x9f, 0xc3, 0x3b, 0xd8, 0x01, 0xb0,
-            0x3a, 0xde, 0xa2, 0xe1, 0xfb, 0xc5, 0xaa, 0x46, 0x3d, 0x08, 0xca, 0x19, 0x89, 0x6d,
-            0x2b, 0xf5, 0x9a, 0x07, 0x1b, 0x85, 0x1e, 0x6c, 0x23, 0x90, 0x52, 0x17, 0x2f, 0x29,
-            0x6b, 0xfb, 0x5e, 0x72, 0x40, 0x47, 0x90, 0xa2, 0x18, 0x10, 0x14, 0xf3, 0xb9, 0x4a,
-            0x4e, 0x97, 0xd1, 0x17, 0xb4, 0x38, 0x13, 0x03, 0x68, 0xcc, 0x39, 0xdb, 0xb2, 0xd1,
-            0x98, 0x06, 0x5a, 0xe3, 0x98, 0x65, 0x47, 0x92, 0x6c, 0xd2, 0x16, 0x2f, 0x40, 0xa2,
-            0x9f, 0x0c, 0x3c, 0x87, 0x45, 0xc0, 0xf5, 0x0f, 0xba, 0x38, 0x52, 0xe5, 0x66, 0xd4,
-            0x45, 0x75, 0xc2, 0x9d, 0x39, 0xa0, 0x3f, 0x0c, 0xda, 0x72, 0x19, 0x84, 0xb6, 0xf4,
-            0x40, 0x59, 0x1f, 0x35, 0x5e, 0x12, 0xd4, 0x39, 0xff, 0x15, 0x0a, 0xab, 0x76, 0x13,
-            0x49, 0x9d, 0xbd, 0x49, 0xad, 0xab, 0xc8, 0x67, 0x6e, 0xef, 0x02, 0x3b, 0x15, 0xb6,
-            0x5b, 0xfc, 0x5c, 0xa0, 0x69, 0x48, 0x10, 0x9f, 0x23, 0xf3, 0x50, 0xdb, 0x82, 0x12,
-            0x35, 0x35, 0xeb, 0x8a, 0x74, 0x33, 0xbd, 0xab, 0xcb, 0x90, 0x92, 0x71, 0xa6, 0xec,
-            0xbc, 0xb5, 0x8b, 0x93, 0x6a, 0x88, 0xcd, 0x4e, 0x8f, 0x2e, 0x6f, 0xf5, 0x80, 0x01,
-            0x75, 0xf1, 0x13, 0x25, 0x3d, 0x8f, 0xa9, 0xca, 0x88, 0x85, 0xc2, 0xf5, 0x52, 0xe6,
-            0x57, 0xdc, 0x60, 0x3f, 0x25, 0x2e, 0x1a, 0x8e, 0x30, 0x8f, 0x76, 0xf0, 0xbe, 0x79,
-            0xe2, 0xfb, 0x8f, 0x5d, 0x5f, 0xbb, 0xe2, 0xe3, 0x0e, 0xca, 0xdd, 0x22, 0x07, 0x23,
-            0xc8, 0xc0, 0xae, 0xa8, 0x07, 0x8c, 0xdf, 0xcb, 0x38, 0x68, 0x26, 0x3f, 0xf8, 0xf0,
-            0x94, 0x00, 0x54, 0xda, 0x48, 0x78, 0x18, 0x93, 0xa7, 0xe4, 0x9a, 0xd5, 0xaf, 0xf4,
-            0xaf, 0x30, 0x0c, 0xd8, 0x04, 0xa6, 0xb6, 0x27, 0x9a, 0xb3, 0xff, 0x3a, 0xfb, 0x64,
-            0x49, 0x1c, 0x85, 0x19, 0x4a, 0xab, 0x76, 0x0d, 0x58, 0xa6, 0x06, 0x65, 0x4f, 0x9f,
-            0x44, 0x00, 0xe8, 0xb3, 0x85, 0x91, 0x35, 0x6f, 0xbf, 0x64, 0x25, 0xac, 0xa2, 0x6d,
-            0xc8, 0x52, 0x44, 0x25, 0x9f, 0xf2, 0xb1, 0x9c, 0x41, 0xb9, 0xf9, 0x6f, 0x3c, 0xa9,
-            0xec, 0x1d, 0xde, 0x43, 0x4d, 0xa7, 0xd2, 0xd3, 0x92, 0xb9, 0x05, 0xdd, 0xf3, 0xd1,
-            0xf9, 0xaf, 0x93, 0xd1, 0xaf, 0x59, 0x50, 0xbd, 0x49, 0x3f, 0x5a, 0xa7, 0x31, 0xb4,
-            0x05, 0x6d, 0xf3, 0x1b, 0xd2, 0x67, 0xb6, 0xb9, 0x0a, 0x07, 0x98, 0x31, 0xaa, 0xf5,
-            0x79, 0xbe, 0x0a, 0x39, 0x01, 0x31, 0x37, 0xaa, 0xc6, 0xd4, 0x04, 0xf5, 0x18, 0xcf,
-            0xd4, 0x68, 0x40, 0x64, 0x7e, 0x78, 0xbf, 0xe7, 0x06, 0xca, 0x4c, 0xf5, 0xe9, 0xc5,
-            0x45, 0x3e, 0x9f, 0x7c, 0xfd, 0x2b, 0x8b, 0x4c, 0x8d, 0x16, 0x9a, 0x44, 0xe5, 0x5c,
-            0x88, 0xd4, 0xa9, 0xa7, 0xf9, 0x47, 0x42, 0x41, 0xe2, 0x21, 0xaf, 0x44, 0x86, 0x00,
-            0x18, 0xab, 0x08, 0x56, 0x97, 0x2e, 0x19, 0x4c, 0xd9, 0x34,
-        ];
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.record_size_limit = Some(512);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
 
-        assert_eq!(&buf, PROTECTED);
+    server
+        .set_max_fragment_size(None)
+        .unwrap();
+    server
+        .writer()
+        .write_all(&[0u8; 4096])
+        .unwrap();
+    let records = transfer_records(&mut server, &mut client);
+    assert!(records
+        .iter()
+        .all(|r| *r <= 512 + 5 + encryption_overhead));
+    client.process_new_packets().unwrap();
+}
 
-        let (header, payload) = buf.split_at_mut(header_len);
-        let (first, rest) = header.split_at_mut(1);
-        let sample = &payload[..sample_len];
+fn check_client_max_fragment_size(size: usize) -> Option<Error> {
+    let mut client_config = make_client_config(KeyType::Ed25519);
+    client_config.max_fragment_size = Some(size);
+    ClientConnection::new(Arc::new(client_config), dns_name("localhost")).err()
+}
 
-        let server_keys = Keys::initial(Version::V1, &CONNECTION_ID, false);
-        server_keys
-            .remote
-            .header
-            .decrypt_in_place(sample, &mut first[0], &mut rest[17..21])
-            .unwrap();
-        let payload = server_keys
-            .remote
-            .packet
-            .decrypt_in_place(PACKET_NUMBER, &*header, payload)
-            .unwrap();
+#[test]
+fn bad_client_max_fragment_sizes() {
+    assert_eq!(
+        check_client_max_fragment_size(31),
+        Some(Error::BadMaxFragmentSize)
+    );
+    assert_eq!(check_client_max_fragment_size(32), None);
+    assert_eq!(check_client_max_fragment_size(64), None);
+    assert_eq!(check_client_max_fragment_size(1460), None);
+    assert_eq!(check_client_max_fragment_size(0x4000), None);
+    assert_eq!(check_client_max_fragment_size(0x4005), None);
+    assert_eq!(
+        check_client_max_fragment_size(0x4006),
+        Some(Error::BadMaxFragmentSize)
+    );
+    assert_eq!(
+        check_client_max_fragment_size(0xffff),
+        Some(Error::BadMaxFragmentSize)
+    );
+}
+
+#[test]
+fn bad_client_max_fragment_lengths() {
+    fn check(len: usize) -> Option<Error> {
+        let mut client_config = make_client_config(KeyType::Ed25519);
+        client_config.max_fragment_length = Some(len);
+        ClientConnection::new(Arc::new(client_config), dns_name("localhost")).err()
+    }
 
-        assert_eq!(&payload[..PAYLOAD.len()], PAYLOAD);
-        assert_eq!(payload.len(), buf.len() - header_len - tag_len);
+    fn check_builder(len: usize) -> Result<ClientConfig, Error> {
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_max_fragment_length(len)
+            .map(|builder| builder.with_no_client_auth())
     }
 
-    #[test]
-    fn test_quic_exporter() {
-        for &kt in ALL_KEY_TYPES.iter() {
-            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-            let server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+    for len in &[512, 1024, 2048, 4096] {
+        assert_eq!(check(*len), None);
+        assert_eq!(
+            check_builder(*len)
+                .unwrap()
+                .max_fragment_length,
+            Some(*len)
+        );
+    }
+    for len in &[0, 511, 1000, 8192, 16384] {
+        assert_eq!(check(*len), Some(Error::BadMaxFragmentSize));
+        assert_eq!(check_builder(*len).err(), Some(Error::BadMaxFragmentSize));
+    }
+}
 
-            do_exporter_test(client_config, server_config);
-        }
+/// Transfer everything `left` wants to write to `right`, returning the
+/// length of each record.
+fn transfer_records<L, LS, R, RS>(left: &mut L, right: &mut R) -> Vec<usize>
+where
+    L: DerefMut + Deref<Target = ConnectionCommon<LS>>,
+    R: DerefMut + Deref<Target = ConnectionCommon<RS>>,
+    LS: SideData,
+    RS: SideData,
+{
+    let mut records = vec![];
+    while left.wants_write() {
+        let mut pipe = OtherSession::new(right);
+        left.write_tls(&mut pipe).unwrap();
+        records.extend(pipe.writevs.concat());
     }
-} // mod test_quic
+    records
+}
 
 #[test]
-fn test_client_does_not_offer_sha1() {
-    use rustls::internal::msgs::{
-        codec::Reader, enums::HandshakeType, handshake::HandshakePayload, message::MessagePayload,
-        message::OpaqueMessage,
-    };
+fn test_max_fragment_length_negotiation() {
+    // Record header, plus worst-case AEAD expansion (TLS1.2 explicit
+    // nonce and tag).
+    let record_overhead = 5 + 8 + 16;
 
-    for kt in ALL_KEY_TYPES.iter() {
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions(*kt, &[version]);
-            let (mut client, _) = make_pair_for_configs(client_config, make_server_config(*kt));
+    for version in rustls::ALL_VERSIONS {
+        for len in &[512, 1024, 2048, 4096] {
+            println!("max_fragment_length {} for {:?}", len, version);
+            let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+            client_config.max_fragment_length = Some(*len);
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
 
-            assert!(client.wants_write());
-            let mut buf = [0u8; 262144];
-            let sz = client
-                .write_tls(&mut buf.as_mut())
+            let big_data = [0x5a; 10000];
+            client
+                .writer()
+                .write_all(&big_data)
+                .unwrap();
+            server
+                .writer()
+                .write_all(&big_data)
                 .unwrap();
-            let msg = OpaqueMessage::read(&mut Reader::init(&buf[..sz])).unwrap();
-            let msg = Message::try_from(msg.into_plain_message()).unwrap();
-            assert!(msg.is_handshake_type(HandshakeType::ClientHello));
 
-            let client_hello = match msg.payload {
-                MessagePayload::Handshake(hs) => match hs.payload {
-                    HandshakePayload::ClientHello(ch) => ch,
-                    _ => unreachable!(),
-                },
-                _ => unreachable!(),
-            };
+            while client.is_handshaking()
+                || server.is_handshaking()
+                || client.wants_write()
+                || server.wants_write()
+            {
+                let records = transfer_records(&mut client, &mut server);
+                assert!(records
+                    .iter()
+                    .all(|r| *r <= len + record_overhead));
+                server.process_new_packets().unwrap();
+
+                let records = transfer_records(&mut server, &mut client);
+                assert!(records
+                    .iter()
+                    .all(|r| *r <= len + record_overhead));
+                client.process_new_packets().unwrap();
+            }
 
-            let sigalgs = client_hello
-                .get_sigalgs_extension()
-                .unwrap();
-            assert!(
-                !sigalgs.contains(&SignatureScheme::RSA_PKCS1_SHA1),
-                "sha1 unexpectedly offered"
-            );
+            check_read(&mut server.reader(), &big_data);
+            check_read(&mut client.reader(), &big_data);
         }
     }
 }
 
 #[test]
-fn test_client_config_keyshare() {
-    let client_config =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-    do_handshake_until_error(&mut client, &mut server).unwrap();
+fn test_max_fragment_length_respects_smaller_max_fragment_size() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.max_fragment_length = Some(4096);
+    client_config.max_fragment_size = Some(64);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+
+    client
+        .writer()
+        .write_all(&[0u8; 1024])
+        .unwrap();
+    let records = transfer_records(&mut client, &mut server);
+    let encryption_overhead = 20; // FIXME: see issue #991
+    assert!(records
+        .iter()
+        .all(|r| *r <= 64 + encryption_overhead));
 }
 
 #[test]
-fn test_client_config_keyshare_mismatch() {
-    let client_config =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+fn bad_record_size_limits() {
+    for limit in &[0, 63, 16386, 0xffff] {
+        let mut client_config = make_client_config(KeyType::Rsa);
+        client_config.record_size_limit = Some(*limit);
+        assert_eq!(
+            ClientConnection::new(Arc::new(client_config), dns_name("localhost")).err(),
+            Some(Error::BadMaxFragmentSize)
+        );
+
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.record_size_limit = Some(*limit);
+        assert_eq!(
+            ServerConnection::new(Arc::new(server_config)).err(),
+            Some(Error::BadMaxFragmentSize)
+        );
+    }
 }
 
-#[test]
-fn test_client_sends_helloretryrequest() {
-    // client sends a secp384r1 key share
-    let mut client_config = make_client_config_with_kx_groups(
-        KeyType::Rsa,
-        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
-    );
+/// Transfer everything `left` wants to write to `right`, returning the
+/// content type and length of each record.
+fn transfer_typed_records<L, LS, R, RS>(left: &mut L, right: &mut R) -> Vec<(u8, usize)>
+where
+    L: DerefMut + Deref<Target = ConnectionCommon<LS>>,
+    R: DerefMut + Deref<Target = ConnectionCommon<RS>>,
+    LS: SideData,
+    RS: SideData,
+{
+    let mut buf = Vec::new();
+    while left.wants_write() {
+        left.write_tls(&mut buf).unwrap();
+    }
 
-    let storage = Arc::new(ClientStorage::new());
-    client_config.session_storage = storage.clone();
+    let mut records = vec![];
+    let mut rest = &buf[..];
+    while !rest.is_empty() {
+        let len = 5 + u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        records.push((rest[0], len));
 
-    // but server only accepts x25519, so a HRR is required
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+        let mut record = &rest[..len];
+        while !record.is_empty() {
+            right.read_tls(&mut record).unwrap();
+        }
+        rest = &rest[len..];
+    }
+    records
+}
 
+fn check_record_size_limit(
+    version: &'static rustls::SupportedProtocolVersion,
+    client_limit: u16,
+    server_limit: Option<u16>,
+) {
+    println!(
+        "record_size_limit client={} server={:?} for {:?}",
+        client_limit, server_limit, version
+    );
+    let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+    client_config.record_size_limit = Some(client_limit);
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.record_size_limit = server_limit;
     let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-    // client sends hello
+    // The limit counts plaintext, which in TLS1.3 includes the content
+    // type byte.  Records then carry a header, and (for TLS1.2 AES-GCM)
+    // an explicit nonce, and a tag.
+    let overhead = match version.version {
+        ProtocolVersion::TLSv1_3 => 5 + 16,
+        _ => 5 + 8 + 16,
+    };
+    let max_record = |limit: u16| (limit as usize).min(16384 + 1) + overhead;
+    let to_server = max_record(server_limit.unwrap_or(16385));
+    let to_client = max_record(client_limit);
+
+    let big_data = [0x5a; 40000];
+    client
+        .writer()
+        .write_all(&big_data)
+        .unwrap();
+    server
+        .writer()
+        .write_all(&big_data)
+        .unwrap();
+
+    // The limit only applies to protected records, which are all we check.
+    // (In TLS1.3 every protected record has the application_data type.)
+    let largest_protected = |records: Vec<(u8, usize)>, largest: usize| {
+        records
+            .into_iter()
+            .filter(|(typ, _)| *typ == 0x17)
+            .map(|(_, len)| len)
+            .fold(largest, usize::max)
+    };
+
+    let mut largest_to_server = 0;
+    let mut largest_to_client = 0;
+    while client.is_handshaking()
+        || server.is_handshaking()
+        || client.wants_write()
+        || server.wants_write()
     {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200);
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0].len() == 1);
+        let records = transfer_typed_records(&mut client, &mut server);
+        largest_to_server = largest_protected(records, largest_to_server);
+        server.process_new_packets().unwrap();
+
+        let records = transfer_typed_records(&mut server, &mut client);
+        largest_to_client = largest_protected(records, largest_to_client);
+        client.process_new_packets().unwrap();
     }
 
-    // server sends HRR
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert!(wrlen < 100); // just the hello retry request
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 2); // hello retry request and CCS
+    assert!(largest_to_client <= to_client);
+    assert!(largest_to_server <= to_server);
+
+    // We should also make full use of the limit in TLS1.3, where there is
+    // no per-suite variation in overhead.
+    if version.version == ProtocolVersion::TLSv1_3 {
+        assert_eq!(largest_to_client, to_client);
+        assert_eq!(largest_to_server, to_server);
     }
 
-    // client sends fixed hello
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200); // just the client hello retry
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 2); // only a CCS & client hello retry
+    check_read(&mut server.reader(), &big_data);
+    check_read(&mut client.reader(), &big_data);
+}
+
+#[test]
+fn test_record_size_limit() {
+    for version in rustls::ALL_VERSIONS {
+        for client_limit in &[64, 1000, 16385] {
+            for server_limit in &[None, Some(64), Some(16385)] {
+                check_record_size_limit(version, *client_limit, *server_limit);
+            }
+        }
     }
+}
 
-    // server completes handshake
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200);
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0].len() == 5); // server hello / encrypted exts / cert / cert-verify / finished
+#[test]
+fn test_record_size_limit_overrides_max_fragment_length() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.record_size_limit = Some(16385);
+    client_config.max_fragment_length = Some(512);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+
+    server
+        .writer()
+        .write_all(&[0u8; 4096])
+        .unwrap();
+    let records = transfer_records(&mut server, &mut client);
+    assert!(records
+        .iter()
+        .any(|r| *r > 512 + 5 + 8 + 16));
+    client.process_new_packets().unwrap();
+}
+
+/// Derive `len` bytes from a TLS1.3 traffic secret with HKDF-Expand-Label,
+/// for TLS13_AES_128_GCM_SHA256.
+fn tls13_expand_label(secret: &[u8], label: &[u8], len: usize) -> Vec<u8> {
+    struct Len(usize);
+
+    impl ring::hkdf::KeyType for Len {
+        fn len(&self) -> usize {
+            self.0
+        }
     }
 
-    do_handshake_until_error(&mut client, &mut server).unwrap();
+    let prk = ring::hkdf::Prk::new_less_safe(ring::hkdf::HKDF_SHA256, secret);
+    let out_len = (len as u16).to_be_bytes();
+    let label_len = [(6 + label.len()) as u8];
+    let info = [&out_len[..], &label_len[..], b"tls13 ", label, &[0u8][..]];
+    let mut out = vec![0u8; len];
+    prk.expand(&info, Len(len))
+        .unwrap()
+        .fill(&mut out)
+        .unwrap();
+    out
+}
 
-    // client only did two storage queries: one for a session, another for a kx type
-    assert_eq!(storage.gets(), 2);
-    assert_eq!(storage.puts(), 2);
+/// Encrypt the first application data record under the TLS1.3 traffic
+/// `secret`, with `len` bytes of data, ignoring any limit the recipient
+/// asked for.
+fn forge_tls13_record(secret: &[u8], len: usize) -> Vec<u8> {
+    use ring::aead;
+
+    let key = tls13_expand_label(secret, b"key", 16);
+    let iv = tls13_expand_label(secret, b"iv", 12);
+
+    let mut payload = vec![0x5a; len];
+    payload.push(0x17);
+    let header = {
+        let encrypted_len = (payload.len() + 16) as u16;
+        let [hi, lo] = encrypted_len.to_be_bytes();
+        [0x17, 0x03, 0x03, hi, lo]
+    };
+
+    let key = aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_128_GCM, &key).unwrap());
+    key.seal_in_place_append_tag(
+        aead::Nonce::try_assume_unique_for_key(&iv).unwrap(),
+        aead::Aad::from(header),
+        &mut payload,
+    )
+    .unwrap();
+
+    let mut record = header.to_vec();
+    record.extend(payload);
+    record
+}
+
+/// Complete a TLS1.3 handshake, then send one application data record of
+/// `len` bytes to the server (if `to_server`) or client, regardless of the
+/// limits they negotiated.  Returns the recipient's result of processing it.
+fn send_unlimited_record(
+    mut client_config: ClientConfig,
+    mut server_config: ServerConfig,
+    to_server: bool,
+    len: usize,
+) -> Result<(), Error> {
+    let key_log = Arc::new(KeyLogToVec::new("client"));
+    client_config.key_log = key_log.clone();
+    server_config.send_tls13_tickets = 0;
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    let label = match to_server {
+        true => "CLIENT_TRAFFIC_SECRET_0",
+        false => "SERVER_TRAFFIC_SECRET_0",
+    };
+    let secret = key_log
+        .take()
+        .into_iter()
+        .find(|item| item.label == label)
+        .unwrap()
+        .secret;
+    let record = forge_tls13_record(&secret, len);
+
+    if to_server {
+        server
+            .read_tls(&mut &record[..])
+            .unwrap();
+        let result = server.process_new_packets().map(|_| ());
+        transfer(&mut server, &mut client);
+        if result.is_err() {
+            assert_eq!(
+                client.process_new_packets().err(),
+                Some(Error::AlertReceived(AlertDescription::RecordOverflow))
+            );
+        }
+        result
+    } else {
+        client
+            .read_tls(&mut &record[..])
+            .unwrap();
+        let result = client.process_new_packets().map(|_| ());
+        transfer(&mut client, &mut server);
+        if result.is_err() {
+            assert_eq!(
+                server.process_new_packets().err(),
+                Some(Error::AlertReceived(AlertDescription::RecordOverflow))
+            );
+        }
+        result
+    }
+}
+
+fn make_client_config_for_forged_records() -> ClientConfig {
+    let builder = ClientConfig::builder()
+        .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_128_GCM_SHA256])
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap();
+    finish_client_config(KeyType::Rsa, builder)
 }
 
 #[test]
-fn test_client_attempts_to_use_unsupported_kx_group() {
-    // common to both client configs
-    let shared_storage = Arc::new(ClientStorage::new());
+fn test_record_size_limit_is_enforced_on_received_records() {
+    // Each side advertises a limit of 64, which in TLS1.3 counts the
+    // inner content type byte.
+    let mut client_config = make_client_config_for_forged_records();
+    client_config.record_size_limit = Some(64);
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.record_size_limit = Some(64);
 
-    // first, client sends a x25519 and server agrees. x25519 is inserted
-    //   into kx group cache.
-    let mut client_config_1 =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
-    client_config_1.session_storage = shared_storage.clone();
+    for to_server in &[true, false] {
+        println!("to_server={}", to_server);
+        assert_eq!(
+            send_unlimited_record(client_config.clone(), server_config.clone(), *to_server, 63),
+            Ok(())
+        );
+        assert_eq!(
+            send_unlimited_record(client_config.clone(), server_config.clone(), *to_server, 64),
+            Err(Error::PeerSentOversizedRecord)
+        );
+    }
 
-    // second, client only supports secp-384 and so kx group cache
-    //   contains an unusable value.
-    let mut client_config_2 =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    client_config_2.session_storage = shared_storage;
+    // Without a limit of our own, only the TLS maximum applies.
+    client_config.record_size_limit = Some(16385);
+    server_config.record_size_limit = None;
+    for to_server in &[true, false] {
+        assert_eq!(
+            send_unlimited_record(
+                client_config.clone(),
+                server_config.clone(),
+                *to_server,
+                16384
+            ),
+            Ok(())
+        );
+    }
+}
 
+#[test]
+fn test_max_fragment_length_is_enforced_on_received_records() {
+    let mut client_config = make_client_config_for_forged_records();
+    client_config.max_fragment_length = Some(512);
     let server_config = make_server_config(KeyType::Rsa);
 
-    // first handshake
-    let (mut client_1, mut server) = make_pair_for_configs(client_config_1, server_config.clone());
-    do_handshake_until_error(&mut client_1, &mut server).unwrap();
+    for to_server in &[true, false] {
+        println!("to_server={}", to_server);
+        assert_eq!(
+            send_unlimited_record(
+                client_config.clone(),
+                server_config.clone(),
+                *to_server,
+                512
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            send_unlimited_record(
+                client_config.clone(),
+                server_config.clone(),
+                *to_server,
+                513
+            ),
+            Err(Error::PeerSentOversizedRecord)
+        );
+    }
+}
 
-    // second handshake
-    let (mut client_2, mut server) = make_pair_for_configs(client_config_2, server_config);
-    do_handshake_until_error(&mut client_2, &mut server).unwrap();
+fn make_pair_with_record_padding(
+    padding: rustls::PaddingPolicy,
+    client_record_size_limit: Option<u16>,
+) -> (ClientConnection, ServerConnection) {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.record_padding = Some(padding);
+    client_config.record_size_limit = client_record_size_limit;
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.record_padding = Some(padding);
+    make_pair_for_configs(client_config, server_config)
 }
 
 #[test]
-fn test_client_mtu_reduction() {
-    struct CollectWrites {
-        writevs: Vec<Vec<usize>>,
-    }
+fn tls13_records_are_padded_to_multiple() {
+    for &multiple in &[32, 256, 1000] {
+        let (mut client, mut server) =
+            make_pair_with_record_padding(rustls::PaddingPolicy::PadToMultiple(multiple), None);
+        do_handshake(&mut client, &mut server);
+
+        for &len in &[1, 100, 1000, 10000] {
+            let data = vec![0x5a; len];
+            client
+                .writer()
+                .write_all(&data)
+                .unwrap();
+            let records = transfer_records(&mut client, &mut server);
+            assert!(records
+                .iter()
+                .all(|r| (r - 5) % multiple == 0));
+            server.process_new_packets().unwrap();
+            check_read(&mut server.reader(), &data);
 
-    impl io::Write for CollectWrites {
-        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
-            panic!()
-        }
-        fn flush(&mut self) -> io::Result<()> {
-            panic!()
-        }
-        fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
-            let writes = b
+            server
+                .writer()
+                .write_all(&data)
+                .unwrap();
+            let records = transfer_records(&mut server, &mut client);
+            assert!(records
                 .iter()
-                .map(|slice| slice.len())
-                .collect::<Vec<usize>>();
-            let len = writes.iter().sum();
-            self.writevs.push(writes);
-            Ok(len)
+                .all(|r| (r - 5) % multiple == 0));
+            client.process_new_packets().unwrap();
+            check_read(&mut client.reader(), &data);
         }
     }
+}
 
-    fn collect_write_lengths(client: &mut ClientConnection) -> Vec<usize> {
-        let mut collector = CollectWrites { writevs: vec![] };
+#[test]
+fn tls13_records_are_padded_to_fixed_length() {
+    let (mut client, mut server) =
+        make_pair_with_record_padding(rustls::PaddingPolicy::PadToFixed(1024), None);
+    do_handshake(&mut client, &mut server);
 
+    for &len in &[1, 100, 1000] {
+        let data = vec![0x5a; len];
         client
-            .write_tls(&mut collector)
+            .writer()
+            .write_all(&data)
             .unwrap();
-        assert_eq!(collector.writevs.len(), 1);
-        collector.writevs[0].clone()
+        assert_eq!(transfer_records(&mut client, &mut server), vec![5 + 1024]);
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), &data);
     }
 
-    for kt in ALL_KEY_TYPES.iter() {
-        let mut client_config = make_client_config(*kt);
-        client_config.max_fragment_size = Some(64);
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
-        let writes = collect_write_lengths(&mut client);
-        println!("writes at mtu=64: {:?}", writes);
-        assert!(writes.iter().all(|x| *x <= 64));
-        assert!(writes.len() > 1);
-    }
+    // longer records are not padded
+    let data = vec![0x5a; 2000];
+    client
+        .writer()
+        .write_all(&data)
+        .unwrap();
+    assert_eq!(
+        transfer_records(&mut client, &mut server),
+        vec![5 + 2000 + 1 + 16]
+    );
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), &data);
 }
 
 #[test]
-fn test_server_mtu_reduction() {
-    let mut server_config = make_server_config(KeyType::Rsa);
-    server_config.max_fragment_size = Some(64);
+fn tls13_record_padding_respects_record_size_limit() {
     let (mut client, mut server) =
-        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+        make_pair_with_record_padding(rustls::PaddingPolicy::PadToFixed(1024), Some(512));
+    do_handshake(&mut client, &mut server);
 
-    let big_data = [0u8; 2048];
+    // The client's limit counts the content type and padding, but not the tag.
+    let data = vec![0x5a; 1000];
     server
         .writer()
-        .write_all(&big_data)
+        .write_all(&data)
         .unwrap();
-
-    let encryption_overhead = 20; // FIXME: see issue #991
-
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        server.write_tls(&mut pipe).unwrap();
-
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0]
-            .iter()
-            .all(|x| *x <= 64 + encryption_overhead));
-    }
-
-    client.process_new_packets().unwrap();
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        server.write_tls(&mut pipe).unwrap();
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0]
-            .iter()
-            .all(|x| *x <= 64 + encryption_overhead));
-    }
-
+    let records = transfer_records(&mut server, &mut client);
+    assert_eq!(records, vec![5 + 512 + 16, 5 + 512 + 16]);
     client.process_new_packets().unwrap();
-    check_read(&mut client.reader(), &big_data);
-}
-
-fn check_client_max_fragment_size(size: usize) -> Option<Error> {
-    let mut client_config = make_client_config(KeyType::Ed25519);
-    client_config.max_fragment_size = Some(size);
-    ClientConnection::new(Arc::new(client_config), dns_name("localhost")).err()
+    check_read(&mut client.reader(), &data);
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn bad_client_max_fragment_sizes() {
-    assert_eq!(
-        check_client_max_fragment_size(31),
-        Some(Error::BadMaxFragmentSize)
-    );
-    assert_eq!(check_client_max_fragment_size(32), None);
-    assert_eq!(check_client_max_fragment_size(64), None);
-    assert_eq!(check_client_max_fragment_size(1460), None);
-    assert_eq!(check_client_max_fragment_size(0x4000), None);
-    assert_eq!(check_client_max_fragment_size(0x4005), None);
-    assert_eq!(
-        check_client_max_fragment_size(0x4006),
-        Some(Error::BadMaxFragmentSize)
-    );
-    assert_eq!(
-        check_client_max_fragment_size(0xffff),
-        Some(Error::BadMaxFragmentSize)
-    );
+fn tls12_records_are_not_padded() {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    client_config.record_padding = Some(rustls::PaddingPolicy::PadToFixed(1024));
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    let records = transfer_records(&mut client, &mut server);
+    assert_eq!(records.len(), 1);
+    assert!(records[0] < 100);
 }
 
 fn assert_lt(left: usize, right: usize) {
@@ -3752,8 +9498,8 @@ fn connection_types_are_not_huge() {
 }
 
 use rustls::internal::msgs::{
-    handshake::ClientExtension, handshake::HandshakePayload, message::Message,
-    message::MessagePayload,
+    handshake::ClientExtension, handshake::HandshakePayload, handshake::HelloRetryExtension,
+    message::Message, message::MessagePayload,
 };
 
 #[test]
@@ -3781,6 +9527,128 @@ fn test_server_rejects_duplicate_sni_names() {
     );
 }
 
+#[cfg(feature = "tls12")]
+fn strip_ems_request(msg: &mut Message) {
+    if let MessagePayload::Handshake(hs) = &mut msg.payload {
+        if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+            ch.extensions
+                .retain(|ext| !matches!(ext, ClientExtension::ExtendedMasterSecretRequest));
+        }
+    }
+}
+
+#[cfg(feature = "tls12")]
+fn strip_ems_ack(msg: &mut Message) {
+    use rustls::internal::msgs::handshake::ServerExtension;
+
+    if let MessagePayload::Handshake(hs) = &mut msg.payload {
+        if let HandshakePayload::ServerHello(sh) = &mut hs.payload {
+            sh.extensions
+                .retain(|ext| !matches!(ext, ServerExtension::ExtendedMasterSecretAck));
+        }
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn server_requiring_ems_rejects_client_without_it() {
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.require_ems = true;
+
+    let (client, server) = make_pair_for_configs(client_config, server_config);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, strip_ems_request, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerIncompatibleError(
+            "client does not support extended master secret".into()
+        ))
+    );
+
+    // fatal handshake_failure alert
+    let mut sent = Vec::new();
+    server.write_tls(&mut sent).unwrap();
+    assert_eq!(&sent[sent.len() - 2..], &[0x02, 0x28]);
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn client_requiring_ems_rejects_server_without_it() {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    client_config.require_ems = true;
+    let server_config = make_server_config(KeyType::Rsa);
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut server, strip_ems_ack, &mut client);
+    assert_eq!(
+        client.process_new_packets(),
+        Err(Error::PeerIncompatibleError(
+            "server does not support extended master secret".into()
+        ))
+    );
+}
+
+/// Keeps the last session value the server stores.
+#[cfg(feature = "tls12")]
+#[derive(Default)]
+struct LastServerSession(Mutex<Option<Vec<u8>>>);
+
+#[cfg(feature = "tls12")]
+impl rustls::server::StoresServerSessions for LastServerSession {
+    fn put(&self, _key: Vec<u8>, value: Vec<u8>) -> bool {
+        *self.0.lock().unwrap() = Some(value);
+        true
+    }
+
+    fn get(&self, _key: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn take(&self, _key: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn can_cache(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn ems_required_by_both_peers_is_used() {
+    use rustls::internal::msgs::codec::Codec;
+    use rustls::internal::msgs::persist::ServerSessionValue;
+
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    client_config.require_ems = true;
+    let storage = Arc::new(LastServerSession::default());
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.require_ems = true;
+    server_config.session_storage = storage.clone();
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    // The session records which master secret derivation was used.
+    let stored = storage
+        .0
+        .lock()
+        .unwrap()
+        .take()
+        .unwrap();
+    assert!(
+        ServerSessionValue::read_bytes(&stored)
+            .unwrap()
+            .extended_ms
+    );
+}
+
 #[test]
 fn test_server_rejects_empty_sni_extension() {
     fn empty_sni_payload(msg: &mut Message) {
@@ -3834,6 +9702,150 @@ fn test_server_rejects_clients_without_any_kx_group_overlap() {
     );
 }
 
+fn make_server_config_with_stateless_retry(kt: KeyType) -> ServerConfig {
+    let mut server_config = make_server_config(kt);
+    server_config.stateless_retry = rustls::Ticketer::new().unwrap();
+    server_config
+}
+
+#[test]
+fn server_stateless_retry_round_trip() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for client_groups in [
+            &[&rustls::kx_group::X25519][..],
+            &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519][..],
+        ]
+        .iter()
+        {
+            let client_config = make_client_config_with_kx_groups(*kt, client_groups);
+            let mut server_config =
+                make_server_config_with_kx_groups(*kt, &[&rustls::kx_group::X25519]);
+            server_config.stateless_retry = rustls::Ticketer::new().unwrap();
+            let server_config = Arc::new(server_config);
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+
+            do_handshake(&mut client, &mut server);
+            assert_eq!(
+                client.get_negotiated_key_exchange_group(),
+                Some(rustls::kx_group::X25519.name)
+            );
+        }
+    }
+}
+
+#[test]
+fn server_stateless_retry_with_client_auth_and_resumption() {
+    let kt = KeyType::Rsa;
+    let client_config = Arc::new(make_client_config_with_versions_with_auth(
+        kt,
+        &[&rustls::version::TLS13],
+    ));
+    let mut server_config = make_server_config_with_mandatory_client_auth(kt);
+    server_config.stateless_retry = rustls::Ticketer::new().unwrap();
+    let server_config = Arc::new(server_config);
+
+    for expect_resumed in [false, true].iter() {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(server.is_session_resumed(), Some(*expect_resumed));
+        assert!(server.peer_certificates().is_some());
+    }
+}
+
+#[test]
+fn server_stateless_retry_completes_on_fresh_connection() {
+    let client_config = Arc::new(make_client_config(KeyType::Rsa));
+    let server_config = Arc::new(make_server_config_with_stateless_retry(KeyType::Rsa));
+
+    let (mut client, mut first_server) = make_pair_for_arc_configs(&client_config, &server_config);
+    transfer(&mut client, &mut first_server);
+    first_server
+        .process_new_packets()
+        .unwrap();
+    transfer(&mut first_server, &mut client);
+    client.process_new_packets().unwrap();
+    drop(first_server);
+
+    // The client's middlebox CCS and second ClientHello arrive at a
+    // server which has never seen this client.
+    let mut server = ServerConnection::new(server_config).unwrap();
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        server.protocol_version(),
+        Some(rustls::ProtocolVersion::TLSv1_3)
+    );
+    client_sends(&mut client, &mut server, b"hello");
+}
+
+fn alter_retry_cookie(msg: &mut Message) {
+    if let MessagePayload::Handshake(hs) = &mut msg.payload {
+        if let HandshakePayload::HelloRetryRequest(hrr) = &mut hs.payload {
+            for ext in hrr.extensions.iter_mut() {
+                if let HelloRetryExtension::Cookie(cookie) = ext {
+                    cookie.0[0] ^= 1;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn server_stateless_retry_rejects_tampered_cookie() {
+    let client_config = make_client_config(KeyType::Rsa);
+    let server_config = make_server_config_with_stateless_retry(KeyType::Rsa);
+    let (client, server) = make_pair_for_configs(client_config, server_config);
+    let (mut client, mut server) = (client.into(), server.into());
+
+    transfer_altered(&mut client, |_| {}, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, alter_retry_cookie, &mut client);
+    client.process_new_packets().unwrap();
+    transfer_altered(&mut client, |_| {}, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerMisbehavedError(
+            "client sent invalid retry cookie".into()
+        ))
+    );
+}
+
+struct AdjustableTime(Mutex<SystemTime>);
+
+impl rustls::TimeProvider for AdjustableTime {
+    fn now(&self) -> Result<SystemTime, Error> {
+        Ok(*self.0.lock().unwrap())
+    }
+}
+
+#[test]
+fn server_stateless_retry_rejects_stale_cookie() {
+    let client_config = Arc::new(make_client_config(KeyType::Rsa));
+    let now = SystemTime::now();
+
+    for (age, expect_err) in [(60, None), (61, Some("client sent stale retry cookie"))].iter() {
+        let time = Arc::new(AdjustableTime(Mutex::new(now)));
+        let mut server_config = make_server_config_with_stateless_retry(KeyType::Rsa);
+        server_config.time_provider = time.clone();
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&client_config, &Arc::new(server_config));
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+
+        *time.0.lock().unwrap() = now + Duration::from_secs(*age);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            expect_err.map_or(Ok(()), |err| Err(ErrorFromPeer::Server(
+                Error::PeerMisbehavedError(err.into())
+            )))
+        );
+    }
+}
+
 /// https://github.com/rustls/rustls/issues/797
 #[cfg(feature = "tls12")]
 #[test]
@@ -10,7 +10,7 @@ use crate::common::*;
 
 use rustls::ServerConnection;
 
-use std::io;
+use std::io::{self, Read, Write};
 use std::sync::Arc;
 
 fn bench_ewouldblock(c: &mut Criterion) {
@@ -22,5 +22,30 @@ fn bench_ewouldblock(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, bench_ewouldblock);
+fn bench_process_new_packets(c: &mut Criterion) {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    let plaintext = vec![0u8; 65536];
+    let mut received = vec![0u8; 65536];
+    c.bench_function(
+        "process_new_packets with 64KB of application data",
+        move |b| {
+            b.iter(|| {
+                client
+                    .writer()
+                    .write_all(&plaintext)
+                    .unwrap();
+                transfer(&mut client, &mut server);
+                server.process_new_packets().unwrap();
+                server
+                    .reader()
+                    .read_exact(&mut received)
+                    .unwrap();
+            })
+        },
+    );
+}
+
+criterion_group!(benches, bench_ewouldblock, bench_process_new_packets);
 criterion_main!(benches);
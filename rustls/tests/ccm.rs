@@ -0,0 +1,537 @@
+//! Tests of the TLS1.2 AES-CCM cipher suites.
+//!
+//! *ring* does not implement AES-CCM, so these use a (slow, simple)
+//! software implementation installed as a [`CryptoProvider`].
+
+#![cfg(feature = "tls12")]
+
+#[allow(dead_code)]
+mod common;
+
+use crate::common::{
+    do_handshake, do_handshake_until_error, finish_client_config, finish_server_config,
+    make_pair_for_arc_configs, transfer, ErrorFromPeer, KeyType,
+};
+use rustls::cipher_suite::{
+    TLS_ECDHE_ECDSA_WITH_AES_128_CCM, TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8,
+    TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+};
+use rustls::crypto::ring::Ring;
+use rustls::crypto::{AeadKey, CryptoProvider, HashAlgorithm, HashContext, HmacKey, Output};
+use rustls::{
+    BulkAlgorithm, ClientConfig, Error, ServerConfig, SupportedCipherSuite, DEFAULT_CIPHER_SUITES,
+};
+
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+/// A provider which adds AES-CCM to *ring*.
+#[derive(Debug)]
+struct CcmProvider;
+
+impl CryptoProvider for CcmProvider {
+    fn start_hash(&self, alg: HashAlgorithm) -> Box<dyn HashContext> {
+        Ring.start_hash(alg)
+    }
+
+    fn hmac_key(&self, alg: HashAlgorithm, key: &[u8]) -> Box<dyn HmacKey> {
+        Ring.hmac_key(alg, key)
+    }
+
+    fn hkdf_extract(&self, alg: HashAlgorithm, salt: &[u8], ikm: &[u8]) -> Output {
+        Ring.hkdf_extract(alg, salt, ikm)
+    }
+
+    fn hkdf_expand(
+        &self,
+        alg: HashAlgorithm,
+        prk: &[u8],
+        info: &[&[u8]],
+        out: &mut [u8],
+    ) -> Result<(), Error> {
+        Ring.hkdf_expand(alg, prk, info, out)
+    }
+
+    fn aead_key(&self, alg: BulkAlgorithm, key: &[u8]) -> Result<Box<dyn AeadKey>, Error> {
+        match alg {
+            BulkAlgorithm::Aes128Ccm => Ok(Box::new(AesCcmKey::new(key, 16))),
+            BulkAlgorithm::Aes128Ccm8 => Ok(Box::new(AesCcmKey::new(key, 8))),
+            _ => Ring.aead_key(alg, key),
+        }
+    }
+
+    fn supports_aead(&self, _alg: BulkAlgorithm) -> bool {
+        true
+    }
+}
+
+/// AES-128 from FIPS-197.  Encryption only, which is all CCM needs.
+struct Aes128 {
+    round_keys: [[u8; 16]; 11],
+    sbox: [u8; 256],
+}
+
+impl Aes128 {
+    fn new(key: &[u8]) -> Self {
+        let sbox = make_sbox();
+        let mut w = [[0u8; 4]; 44];
+        for (i, word) in key.chunks(4).enumerate() {
+            w[i].copy_from_slice(word);
+        }
+
+        let mut rcon = 1u8;
+        for i in 4..44 {
+            let mut t = w[i - 1];
+            if i % 4 == 0 {
+                t = [
+                    sbox[t[1] as usize] ^ rcon,
+                    sbox[t[2] as usize],
+                    sbox[t[3] as usize],
+                    sbox[t[0] as usize],
+                ];
+                rcon = xtime(rcon);
+            }
+            for j in 0..4 {
+                w[i][j] = w[i - 4][j] ^ t[j];
+            }
+        }
+
+        let mut round_keys = [[0u8; 16]; 11];
+        for (i, rk) in round_keys.iter_mut().enumerate() {
+            for j in 0..4 {
+                rk[j * 4..j * 4 + 4].copy_from_slice(&w[i * 4 + j]);
+            }
+        }
+
+        Self { round_keys, sbox }
+    }
+
+    fn encrypt(&self, block: [u8; 16]) -> [u8; 16] {
+        let mut state = block;
+        xor(&mut state, &self.round_keys[0]);
+
+        for round in 1..11 {
+            for b in state.iter_mut() {
+                *b = self.sbox[*b as usize];
+            }
+
+            let old = state;
+            for c in 0..4 {
+                for r in 0..4 {
+                    state[c * 4 + r] = old[((c + r) % 4) * 4 + r];
+                }
+            }
+
+            if round != 10 {
+                for col in state.chunks_mut(4) {
+                    let a = [col[0], col[1], col[2], col[3]];
+                    let all = a[0] ^ a[1] ^ a[2] ^ a[3];
+                    for r in 0..4 {
+                        col[r] = a[r] ^ all ^ xtime(a[r] ^ a[(r + 1) % 4]);
+                    }
+                }
+            }
+
+            xor(&mut state, &self.round_keys[round]);
+        }
+
+        state
+    }
+}
+
+fn make_sbox() -> [u8; 256] {
+    let mut sbox = [0u8; 256];
+    let (mut p, mut q) = (1u8, 1u8);
+    loop {
+        // p walks the multiplicative group, q tracks its inverse.
+        p = p ^ (p << 1) ^ if p & 0x80 != 0 { 0x1b } else { 0 };
+        q ^= q << 1;
+        q ^= q << 2;
+        q ^= q << 4;
+        if q & 0x80 != 0 {
+            q ^= 0x09;
+        }
+
+        sbox[p as usize] =
+            q ^ q.rotate_left(1) ^ q.rotate_left(2) ^ q.rotate_left(3) ^ q.rotate_left(4) ^ 0x63;
+
+        if p == 1 {
+            break;
+        }
+    }
+    sbox[0] = 0x63;
+    sbox
+}
+
+fn xtime(x: u8) -> u8 {
+    (x << 1) ^ if x & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+fn pad_to_block(buf: &mut Vec<u8>) {
+    let padding = (16 - buf.len() % 16) % 16;
+    buf.resize(buf.len() + padding, 0);
+}
+
+fn xor(out: &mut [u8], other: &[u8]) {
+    for (o, x) in out.iter_mut().zip(other) {
+        *o ^= x;
+    }
+}
+
+/// AES-CCM from NIST SP800-38C, with a 12-byte nonce (so a 3-byte
+/// length field) as used by TLS.
+struct AesCcmKey {
+    aes: Aes128,
+    tag_len: usize,
+}
+
+impl AesCcmKey {
+    fn new(key: &[u8], tag_len: usize) -> Self {
+        Self {
+            aes: Aes128::new(key),
+            tag_len,
+        }
+    }
+
+    fn counter_block(nonce: &[u8; 12], counter: usize) -> [u8; 16] {
+        let mut block = [0u8; 16];
+        block[0] = 2;
+        block[1..13].copy_from_slice(nonce);
+        block[13..].copy_from_slice(&(counter as u32).to_be_bytes()[1..]);
+        block
+    }
+
+    fn cbc_mac(&self, nonce: &[u8; 12], aad: &[u8], plain: &[u8]) -> [u8; 16] {
+        let mut b0 = [0u8; 16];
+        b0[0] = (((self.tag_len - 2) / 2) << 3) as u8 | 2;
+        if !aad.is_empty() {
+            b0[0] |= 0x40;
+        }
+        b0[1..13].copy_from_slice(nonce);
+        b0[13..].copy_from_slice(&(plain.len() as u32).to_be_bytes()[1..]);
+
+        let mut input = Vec::new();
+        if !aad.is_empty() {
+            input.extend_from_slice(&(aad.len() as u16).to_be_bytes());
+            input.extend_from_slice(aad);
+            pad_to_block(&mut input);
+        }
+        input.extend_from_slice(plain);
+        pad_to_block(&mut input);
+
+        let mut mac = self.aes.encrypt(b0);
+        for block in input.chunks(16) {
+            xor(&mut mac, block);
+            mac = self.aes.encrypt(mac);
+        }
+        mac
+    }
+
+    fn ctr(&self, nonce: &[u8; 12], in_out: &mut [u8]) {
+        for (i, chunk) in in_out.chunks_mut(16).enumerate() {
+            let keystream = self
+                .aes
+                .encrypt(Self::counter_block(nonce, i + 1));
+            xor(chunk, &keystream);
+        }
+    }
+
+    fn tag(&self, nonce: &[u8; 12], aad: &[u8], plain: &[u8]) -> Output {
+        let mut tag = self.cbc_mac(nonce, aad, plain);
+        xor(
+            &mut tag,
+            &self
+                .aes
+                .encrypt(Self::counter_block(nonce, 0)),
+        );
+        Output::new(&tag[..self.tag_len])
+    }
+}
+
+impl AeadKey for AesCcmKey {
+    fn tag_len(&self) -> usize {
+        self.tag_len
+    }
+
+    fn seal_in_place_separate_tag(
+        &self,
+        nonce: [u8; 12],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<Output, Error> {
+        let tag = self.tag(&nonce, aad, in_out);
+        self.ctr(&nonce, in_out);
+        Ok(tag)
+    }
+
+    fn open_in_place(
+        &self,
+        nonce: [u8; 12],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<usize, Error> {
+        let plain_len = in_out
+            .len()
+            .checked_sub(self.tag_len)
+            .ok_or(Error::DecryptError)?;
+        let (ciphertext, received_tag) = in_out.split_at_mut(plain_len);
+        self.ctr(&nonce, ciphertext);
+
+        if self
+            .tag(&nonce, aad, ciphertext)
+            .as_ref()
+            != received_tag
+        {
+            return Err(Error::DecryptError);
+        }
+        Ok(plain_len)
+    }
+}
+
+#[test]
+fn aes_128_known_answer() {
+    // FIPS-197 appendix C.1.
+    let aes = Aes128::new(&[
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f,
+    ]);
+    assert_eq!(
+        aes.encrypt([
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+            0xee, 0xff
+        ]),
+        [
+            0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4,
+            0xc5, 0x5a
+        ]
+    );
+}
+
+fn ccm_round_trip(alg: BulkAlgorithm, expected: &[u8]) {
+    // The inputs of example 3 from NIST SP800-38C, appendix C.
+    let key = (0x40..0x50).collect::<Vec<u8>>();
+    let mut nonce = [0u8; 12];
+    nonce
+        .iter_mut()
+        .zip(0x10..)
+        .for_each(|(n, v)| *n = v);
+    let aad = (0x00..0x14).collect::<Vec<u8>>();
+    let plain = (0x20..0x38).collect::<Vec<u8>>();
+
+    let key = CcmProvider.aead_key(alg, &key).unwrap();
+    let mut buf = plain.clone();
+    let tag = key
+        .seal_in_place_separate_tag(nonce, &aad, &mut buf)
+        .unwrap();
+    buf.extend_from_slice(tag.as_ref());
+    assert_eq!(buf, expected);
+
+    let mut opened = buf.clone();
+    assert_eq!(key.open_in_place(nonce, &aad, &mut opened), Ok(plain.len()));
+    assert_eq!(&opened[..plain.len()], &plain[..]);
+
+    // Any change to the ciphertext, tag or additional data is detected.
+    for i in 0..buf.len() {
+        let mut tampered = buf.clone();
+        tampered[i] ^= 1;
+        assert_eq!(
+            key.open_in_place(nonce, &aad, &mut tampered),
+            Err(Error::DecryptError)
+        );
+    }
+    assert_eq!(
+        key.open_in_place(nonce, &aad[1..], &mut buf.clone()),
+        Err(Error::DecryptError)
+    );
+    assert_eq!(
+        key.open_in_place(nonce, &aad, &mut buf[..key.tag_len() - 1].to_vec()),
+        Err(Error::DecryptError)
+    );
+}
+
+#[test]
+fn aes_128_ccm_8_round_trip() {
+    // Exactly example 3 from NIST SP800-38C.
+    ccm_round_trip(
+        BulkAlgorithm::Aes128Ccm8,
+        &[
+            0xe3, 0xb2, 0x01, 0xa9, 0xf5, 0xb7, 0x1a, 0x7a, 0x9b, 0x1c, 0xea, 0xec, 0xcd, 0x97,
+            0xe7, 0x0b, 0x61, 0x76, 0xaa, 0xd9, 0xa4, 0x42, 0x8a, 0xa5, 0x48, 0x43, 0x92, 0xfb,
+            0xc1, 0xb0, 0x99, 0x51,
+        ],
+    );
+}
+
+#[test]
+fn aes_128_ccm_round_trip() {
+    // The same inputs with a 16-byte tag.
+    ccm_round_trip(
+        BulkAlgorithm::Aes128Ccm,
+        &[
+            0xe3, 0xb2, 0x01, 0xa9, 0xf5, 0xb7, 0x1a, 0x7a, 0x9b, 0x1c, 0xea, 0xec, 0xcd, 0x97,
+            0xe7, 0x0b, 0x61, 0x76, 0xaa, 0xd9, 0xa4, 0x42, 0x8a, 0xa5, 0xc8, 0x7a, 0xe4, 0x88,
+            0x91, 0x8d, 0xe9, 0x3f, 0x17, 0xdd, 0x3e, 0x49, 0x34, 0x34, 0x7f, 0x44,
+        ],
+    );
+}
+
+#[test]
+fn ring_does_not_support_ccm() {
+    assert!(!Ring.supports_aead(BulkAlgorithm::Aes128Ccm));
+    assert!(!Ring.supports_aead(BulkAlgorithm::Aes128Ccm8));
+    assert!(Ring
+        .aead_key(BulkAlgorithm::Aes128Ccm, &[0u8; 16])
+        .is_err());
+}
+
+#[test]
+fn ccm_is_not_default() {
+    assert!(!DEFAULT_CIPHER_SUITES.contains(&TLS_ECDHE_ECDSA_WITH_AES_128_CCM));
+    assert!(!DEFAULT_CIPHER_SUITES.contains(&TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8));
+}
+
+fn client_config(
+    suites: &[SupportedCipherSuite],
+    provider: Arc<dyn CryptoProvider>,
+) -> ClientConfig {
+    let mut config = finish_client_config(
+        KeyType::Ecdsa,
+        ClientConfig::builder()
+            .with_cipher_suites(suites)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .unwrap(),
+    );
+    config.crypto_provider = provider;
+    config
+}
+
+/// A server which only speaks the AES-CCM suites, like many constrained
+/// devices.
+fn ccm_only_server_config() -> ServerConfig {
+    let mut config = finish_server_config(
+        KeyType::Ecdsa,
+        ServerConfig::builder()
+            .with_cipher_suites(&[
+                TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8,
+                TLS_ECDHE_ECDSA_WITH_AES_128_CCM,
+            ])
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .unwrap(),
+    );
+    config.crypto_provider = Arc::new(CcmProvider);
+    config
+}
+
+fn handshake_and_exchange_data(suite: SupportedCipherSuite) {
+    let client_config = client_config(&[suite], Arc::new(CcmProvider));
+    let (mut client, mut server) = make_pair_for_arc_configs(
+        &Arc::new(client_config),
+        &Arc::new(ccm_only_server_config()),
+    );
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(client.negotiated_cipher_suite(), Some(suite));
+    assert_eq!(server.negotiated_cipher_suite(), Some(suite));
+
+    // Several blocks' worth, and not a multiple of the block size.
+    let data = (0..1000u32)
+        .map(|i| i as u8)
+        .collect::<Vec<u8>>();
+
+    client
+        .writer()
+        .write_all(&data)
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    let mut buf = vec![0u8; data.len()];
+    server
+        .reader()
+        .read_exact(&mut buf)
+        .unwrap();
+    assert_eq!(buf, data);
+
+    server
+        .writer()
+        .write_all(&data)
+        .unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    let mut buf = vec![0u8; data.len()];
+    client
+        .reader()
+        .read_exact(&mut buf)
+        .unwrap();
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn handshake_with_ccm_only_server() {
+    handshake_and_exchange_data(TLS_ECDHE_ECDSA_WITH_AES_128_CCM);
+}
+
+#[test]
+fn handshake_with_ccm_8_only_server() {
+    handshake_and_exchange_data(TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8);
+}
+
+#[test]
+fn ccm_not_offered_without_provider_support() {
+    // The client is configured with CCM but its provider cannot do it,
+    // so only GCM is offered.
+    let client_config = client_config(
+        &[
+            TLS_ECDHE_ECDSA_WITH_AES_128_CCM,
+            TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+        ],
+        Arc::new(Ring),
+    );
+    let (mut client, mut server) = make_pair_for_arc_configs(
+        &Arc::new(client_config),
+        &Arc::new(ccm_only_server_config()),
+    );
+
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::PeerIncompatibleError(
+            "no ciphersuites in common".to_string()
+        )))
+    );
+}
+
+#[test]
+fn ccm_not_selected_without_provider_support() {
+    // The server is configured with CCM first, but its provider cannot do
+    // it, so GCM is chosen.
+    let mut server_config = finish_server_config(
+        KeyType::Ecdsa,
+        ServerConfig::builder()
+            .with_cipher_suites(&[
+                TLS_ECDHE_ECDSA_WITH_AES_128_CCM,
+                TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            ])
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .unwrap(),
+    );
+    server_config.ignore_client_order = true;
+    server_config.crypto_provider = Arc::new(Ring);
+
+    let client_config = client_config(
+        &[
+            TLS_ECDHE_ECDSA_WITH_AES_128_CCM,
+            TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+        ],
+        Arc::new(CcmProvider),
+    );
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), &Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(
+        server.negotiated_cipher_suite(),
+        Some(TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256)
+    );
+}
@@ -9,15 +9,16 @@ use crate::common::{
     make_client_config_with_versions, make_client_config_with_versions_with_auth,
     make_pair_for_arc_configs, ErrorFromPeer, KeyType, ALL_KEY_TYPES,
 };
-use rustls::client::WebPkiVerifier;
+use rustls::client::{ResolvesClientCert, WebPkiVerifier};
 use rustls::internal::msgs::enums::AlertDescription;
 use rustls::internal::msgs::enums::ContentType;
-use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier};
+use rustls::sign;
 use rustls::{
-    Certificate, ClientConnection, DistinguishedNames, Error, ServerConfig, ServerConnection,
-    SignatureScheme,
+    Certificate, ClientConnection, DistinguishedNames, Error, ProtocolVersion, ServerConfig,
+    ServerConnection, SignatureScheme,
 };
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 // Client is authorized!
 fn ver_ok() -> Result<ClientCertVerified, Error> {
@@ -34,9 +35,18 @@ fn ver_err() -> Result<ClientCertVerified, Error> {
     Err(Error::General("test err".to_string()))
 }
 
+// The alert a server sends when a client declines mandatory authentication.
+fn no_certificate_alert(version: &rustls::SupportedProtocolVersion) -> AlertDescription {
+    if version.version == ProtocolVersion::TLSv1_3 {
+        AlertDescription::CertificateRequired
+    } else {
+        AlertDescription::HandshakeFailure
+    }
+}
+
 fn server_config_with_verifier(
     kt: KeyType,
-    client_cert_verifier: MockClientVerifier,
+    client_cert_verifier: impl ClientCertVerifier + 'static,
 ) -> ServerConfig {
     ServerConfig::builder()
         .with_safe_defaults()
@@ -188,9 +198,7 @@ fn client_verifier_no_auth_yes_root() {
                 errs,
                 Err(vec![
                     ErrorFromPeer::Server(Error::NoCertificatesPresented),
-                    ErrorFromPeer::Client(Error::AlertReceived(
-                        AlertDescription::CertificateRequired
-                    ))
+                    ErrorFromPeer::Client(Error::AlertReceived(no_certificate_alert(version)))
                 ])
             );
         }
@@ -258,6 +266,222 @@ fn client_verifier_must_determine_client_auth_requirement_to_continue() {
     }
 }
 
+/// Verifier which only requires client authentication for one server
+/// name, and which rejects client certificates with a given subject.
+struct PolicyByServerName {
+    inner: Arc<dyn ClientCertVerifier>,
+    mandatory_for: &'static str,
+    denied_subject: &'static [u8],
+}
+
+impl PolicyByServerName {
+    fn new(kt: KeyType, denied_subject: &'static [u8]) -> Self {
+        Self {
+            inner: AllowAnyAuthenticatedClient::new(get_client_root_store(kt)),
+            mandatory_for: "second.testserver.com",
+            denied_subject,
+        }
+    }
+}
+
+impl ClientCertVerifier for PolicyByServerName {
+    fn client_auth_mandatory(&self, server_name: Option<&str>) -> Option<bool> {
+        Some(server_name == Some(self.mandatory_for))
+    }
+
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: std::time::SystemTime,
+    ) -> Result<ClientCertVerified, Error> {
+        // Good enough for a test: the subject CN appears verbatim in the DER.
+        if end_entity
+            .0
+            .windows(self.denied_subject.len())
+            .any(|window| window == self.denied_subject)
+        {
+            return Err(Error::General("client subject denied".into()));
+        }
+        self.inner
+            .verify_client_cert(end_entity, intermediates, now)
+    }
+}
+
+#[test]
+fn client_verifier_optional_auth_accepts_anonymous_client() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_verifier = PolicyByServerName::new(*kt, b"nobody");
+        let server_config = Arc::new(server_config_with_verifier(*kt, client_verifier));
+
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+            let mut client =
+                ClientConnection::new(Arc::new(client_config), dns_name("testserver.com")).unwrap();
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(err, Ok(()));
+            assert!(server.peer_certificates().is_none());
+        }
+    }
+}
+
+#[test]
+fn client_verifier_optional_auth_rejects_denied_subject() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_verifier = PolicyByServerName::new(*kt, b"ponytown client");
+        let server_config = Arc::new(server_config_with_verifier(*kt, client_verifier));
+
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+            let mut client =
+                ClientConnection::new(Arc::new(client_config), dns_name("testserver.com")).unwrap();
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(
+                err,
+                Err(ErrorFromPeer::Server(Error::General(
+                    "client subject denied".into()
+                )))
+            );
+        }
+    }
+}
+
+#[test]
+fn client_verifier_mandatory_auth_for_server_name_rejects_anonymous_client() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_verifier = PolicyByServerName::new(*kt, b"nobody");
+        let server_config = Arc::new(server_config_with_verifier(*kt, client_verifier));
+
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+            let mut client =
+                ClientConnection::new(Arc::new(client_config), dns_name("second.testserver.com"))
+                    .unwrap();
+            let errs = do_handshake_until_both_error(&mut client, &mut server);
+            assert_eq!(
+                errs,
+                Err(vec![
+                    ErrorFromPeer::Server(Error::NoCertificatesPresented),
+                    ErrorFromPeer::Client(Error::AlertReceived(no_certificate_alert(version)))
+                ])
+            );
+        }
+    }
+}
+
+/// Client certificate resolver which records the acceptable issuers
+/// the server sent, and then authenticates with the client certificate.
+struct RecordingResolver {
+    certkey: Arc<sign::CertifiedKey>,
+    issuers: Mutex<Option<Vec<Vec<u8>>>>,
+}
+
+impl RecordingResolver {
+    fn new(kt: KeyType) -> Self {
+        Self {
+            certkey: Arc::new(sign::CertifiedKey::new(
+                kt.get_client_chain(),
+                sign::any_supported_type(&kt.get_client_key()).unwrap(),
+            )),
+            issuers: Mutex::new(None),
+        }
+    }
+
+    fn issuers(&self) -> Option<Vec<Vec<u8>>> {
+        self.issuers.lock().unwrap().clone()
+    }
+}
+
+impl ResolvesClientCert for RecordingResolver {
+    fn resolve(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        *self.issuers.lock().unwrap() = Some(
+            acceptable_issuers
+                .iter()
+                .map(|issuer| issuer.to_vec())
+                .collect(),
+        );
+        Some(Arc::clone(&self.certkey))
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+fn received_issuers(
+    kt: KeyType,
+    version: &'static rustls::SupportedProtocolVersion,
+    server_config: &Arc<ServerConfig>,
+) -> Option<Vec<Vec<u8>>> {
+    let resolver = Arc::new(RecordingResolver::new(kt));
+    let mut client_config = make_client_config_with_versions(kt, &[version]);
+    client_config.client_auth_cert_resolver = resolver.clone();
+
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), server_config);
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert_eq!(err, Ok(()));
+    resolver.issuers()
+}
+
+#[test]
+// The verifier's root subjects are sent to the client as acceptable issuers
+fn client_verifier_sends_root_subjects() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let subjects = get_client_root_store(*kt).subjects();
+        let client_verifier = MockClientVerifier {
+            verified: ver_ok,
+            subjects: Some(subjects.clone()),
+            mandatory: Some(true),
+            offered_schemes: None,
+        };
+
+        let server_config = Arc::new(server_config_with_verifier(*kt, client_verifier));
+        let expected = subjects
+            .iter()
+            .map(|name| name.0.clone())
+            .collect::<Vec<_>>();
+        assert!(!expected.is_empty());
+
+        for version in rustls::ALL_VERSIONS {
+            assert_eq!(
+                received_issuers(*kt, version, &server_config),
+                Some(expected.clone())
+            );
+        }
+    }
+}
+
+#[test]
+// An empty list of root subjects means any issuer is acceptable
+fn client_verifier_empty_root_subjects_sends_no_hints() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_verifier = MockClientVerifier {
+            verified: ver_ok,
+            subjects: Some(DistinguishedNames::new()),
+            mandatory: Some(true),
+            offered_schemes: None,
+        };
+
+        let server_config = Arc::new(server_config_with_verifier(*kt, client_verifier));
+
+        for version in rustls::ALL_VERSIONS {
+            assert_eq!(received_issuers(*kt, version, &server_config), Some(vec![]));
+        }
+    }
+}
+
 pub struct MockClientVerifier {
     pub verified: fn() -> Result<ClientCertVerified, Error>,
     pub subjects: Option<DistinguishedNames>,
@@ -266,7 +490,7 @@ pub struct MockClientVerifier {
 }
 
 impl ClientCertVerifier for MockClientVerifier {
-    fn client_auth_mandatory(&self) -> Option<bool> {
+    fn client_auth_mandatory(&self, _server_name: Option<&str>) -> Option<bool> {
         self.mandatory
     }
 
@@ -4,10 +4,11 @@ use std::convert::{TryFrom, TryInto};
 use std::io;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use rustls::internal::msgs::codec::Reader;
 use rustls::internal::msgs::message::{Message, OpaqueMessage, PlainMessage};
-use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::server::{AllowAnyAuthenticatedClient, CrlExpiryPolicy};
 use rustls::Connection;
 use rustls::Error;
 use rustls::RootCertStore;
@@ -46,16 +47,35 @@ embed_files! {
     (ECDSA_CLIENT_FULLCHAIN, "ecdsa", "client.fullchain");
     (ECDSA_CLIENT_KEY, "ecdsa", "client.key");
     (ECDSA_CLIENT_REQ, "ecdsa", "client.req");
+    (ECDSA_CLIENT_REVOKED_CRL, "ecdsa", "client.revoked.crl.der");
     (ECDSA_END_CERT, "ecdsa", "end.cert");
     (ECDSA_END_CHAIN, "ecdsa", "end.chain");
+    (ECDSA_END_DC_FULLCHAIN, "ecdsa", "end.dc.fullchain");
+    (ECDSA_END_DC_NO_EXTENSION_FULLCHAIN, "ecdsa", "end.dc-no-extension.fullchain");
+    (ECDSA_END_DC_NO_KEY_USAGE_FULLCHAIN, "ecdsa", "end.dc-no-key-usage.fullchain");
     (ECDSA_END_FULLCHAIN, "ecdsa", "end.fullchain");
+    (ECDSA_END_IDN_FULLCHAIN, "ecdsa", "end.idn.fullchain");
+    (ECDSA_END_IP_FULLCHAIN, "ecdsa", "end.ip.fullchain");
     (ECDSA_END_KEY, "ecdsa", "end.key");
+    (ECDSA_END_MUSTSTAPLE_FULLCHAIN, "ecdsa", "end.muststaple.fullchain");
+    (ECDSA_END_OCSP_CERT, "ecdsa", "end.ocsp.cert");
     (ECDSA_END_REQ, "ecdsa", "end.req");
+    (ECDSA_END_REVOKED_CRL, "ecdsa", "end.revoked.crl.der");
     (ECDSA_INTER_CERT, "ecdsa", "inter.cert");
+    (ECDSA_INTER_EXPIRED_CRL, "ecdsa", "inter.expired.crl.der");
     (ECDSA_INTER_KEY, "ecdsa", "inter.key");
     (ECDSA_INTER_REQ, "ecdsa", "inter.req");
+    (ECDSA_INTER_REVOKED_CRL, "ecdsa", "inter.revoked.crl.der");
+    (ECDSA_MUSTSTAPLE_DELEGATED_OCSP, "ecdsa", "muststaple.delegated.ocsp.der");
+    (ECDSA_MUSTSTAPLE_EXPIRED_OCSP, "ecdsa", "muststaple.expired.ocsp.der");
+    (ECDSA_MUSTSTAPLE_GOOD_SHA256_OCSP, "ecdsa", "muststaple.good-sha256.ocsp.der");
+    (ECDSA_MUSTSTAPLE_GOOD_OCSP, "ecdsa", "muststaple.good.ocsp.der");
+    (ECDSA_MUSTSTAPLE_NOT_RESPONDER_OCSP, "ecdsa", "muststaple.not-responder.ocsp.der");
+    (ECDSA_MUSTSTAPLE_REVOKED_OCSP, "ecdsa", "muststaple.revoked.ocsp.der");
+    (ECDSA_MUSTSTAPLE_WRONG_SIGNER_OCSP, "ecdsa", "muststaple.wrong-signer.ocsp.der");
     (ECDSA_NISTP256_PEM, "ecdsa", "nistp256.pem");
     (ECDSA_NISTP384_PEM, "ecdsa", "nistp384.pem");
+    (ECDSA_OTHER_SERIAL_GOOD_OCSP, "ecdsa", "other-serial.good.ocsp.der");
 
     (EDDSA_CA_CERT, "eddsa", "ca.cert");
     (EDDSA_CA_DER, "eddsa", "ca.der");
@@ -65,14 +85,33 @@ embed_files! {
     (EDDSA_CLIENT_FULLCHAIN, "eddsa", "client.fullchain");
     (EDDSA_CLIENT_KEY, "eddsa", "client.key");
     (EDDSA_CLIENT_REQ, "eddsa", "client.req");
+    (EDDSA_CLIENT_REVOKED_CRL, "eddsa", "client.revoked.crl.der");
     (EDDSA_END_CERT, "eddsa", "end.cert");
     (EDDSA_END_CHAIN, "eddsa", "end.chain");
+    (EDDSA_END_DC_FULLCHAIN, "eddsa", "end.dc.fullchain");
+    (EDDSA_END_DC_NO_EXTENSION_FULLCHAIN, "eddsa", "end.dc-no-extension.fullchain");
+    (EDDSA_END_DC_NO_KEY_USAGE_FULLCHAIN, "eddsa", "end.dc-no-key-usage.fullchain");
     (EDDSA_END_FULLCHAIN, "eddsa", "end.fullchain");
+    (EDDSA_END_IDN_FULLCHAIN, "eddsa", "end.idn.fullchain");
+    (EDDSA_END_IP_FULLCHAIN, "eddsa", "end.ip.fullchain");
     (EDDSA_END_KEY, "eddsa", "end.key");
+    (EDDSA_END_MUSTSTAPLE_FULLCHAIN, "eddsa", "end.muststaple.fullchain");
+    (EDDSA_END_OCSP_CERT, "eddsa", "end.ocsp.cert");
     (EDDSA_END_REQ, "eddsa", "end.req");
+    (EDDSA_END_REVOKED_CRL, "eddsa", "end.revoked.crl.der");
     (EDDSA_INTER_CERT, "eddsa", "inter.cert");
+    (EDDSA_INTER_EXPIRED_CRL, "eddsa", "inter.expired.crl.der");
     (EDDSA_INTER_KEY, "eddsa", "inter.key");
     (EDDSA_INTER_REQ, "eddsa", "inter.req");
+    (EDDSA_INTER_REVOKED_CRL, "eddsa", "inter.revoked.crl.der");
+    (EDDSA_MUSTSTAPLE_DELEGATED_OCSP, "eddsa", "muststaple.delegated.ocsp.der");
+    (EDDSA_MUSTSTAPLE_EXPIRED_OCSP, "eddsa", "muststaple.expired.ocsp.der");
+    (EDDSA_MUSTSTAPLE_GOOD_SHA256_OCSP, "eddsa", "muststaple.good-sha256.ocsp.der");
+    (EDDSA_MUSTSTAPLE_GOOD_OCSP, "eddsa", "muststaple.good.ocsp.der");
+    (EDDSA_MUSTSTAPLE_NOT_RESPONDER_OCSP, "eddsa", "muststaple.not-responder.ocsp.der");
+    (EDDSA_MUSTSTAPLE_REVOKED_OCSP, "eddsa", "muststaple.revoked.ocsp.der");
+    (EDDSA_MUSTSTAPLE_WRONG_SIGNER_OCSP, "eddsa", "muststaple.wrong-signer.ocsp.der");
+    (EDDSA_OTHER_SERIAL_GOOD_OCSP, "eddsa", "other-serial.good.ocsp.der");
 
     (RSA_CA_CERT, "rsa", "ca.cert");
     (RSA_CA_DER, "rsa", "ca.der");
@@ -82,16 +121,113 @@ embed_files! {
     (RSA_CLIENT_FULLCHAIN, "rsa", "client.fullchain");
     (RSA_CLIENT_KEY, "rsa", "client.key");
     (RSA_CLIENT_REQ, "rsa", "client.req");
+    (RSA_CLIENT_REVOKED_CRL, "rsa", "client.revoked.crl.der");
     (RSA_CLIENT_RSA, "rsa", "client.rsa");
     (RSA_END_CERT, "rsa", "end.cert");
     (RSA_END_CHAIN, "rsa", "end.chain");
+    (RSA_END_DC_FULLCHAIN, "rsa", "end.dc.fullchain");
+    (RSA_END_DC_NO_EXTENSION_FULLCHAIN, "rsa", "end.dc-no-extension.fullchain");
+    (RSA_END_DC_NO_KEY_USAGE_FULLCHAIN, "rsa", "end.dc-no-key-usage.fullchain");
     (RSA_END_FULLCHAIN, "rsa", "end.fullchain");
+    (RSA_END_IDN_FULLCHAIN, "rsa", "end.idn.fullchain");
+    (RSA_END_IP_FULLCHAIN, "rsa", "end.ip.fullchain");
     (RSA_END_KEY, "rsa", "end.key");
+    (RSA_END_MUSTSTAPLE_FULLCHAIN, "rsa", "end.muststaple.fullchain");
+    (RSA_END_OCSP_CERT, "rsa", "end.ocsp.cert");
     (RSA_END_REQ, "rsa", "end.req");
+    (RSA_END_REVOKED_CRL, "rsa", "end.revoked.crl.der");
     (RSA_END_RSA, "rsa", "end.rsa");
     (RSA_INTER_CERT, "rsa", "inter.cert");
+    (RSA_INTER_EXPIRED_CRL, "rsa", "inter.expired.crl.der");
     (RSA_INTER_KEY, "rsa", "inter.key");
+    (RSA_INTER_PSS_CRL, "rsa", "inter.pss.crl.der");
     (RSA_INTER_REQ, "rsa", "inter.req");
+    (RSA_INTER_REVOKED_CRL, "rsa", "inter.revoked.crl.der");
+    (RSA_INTER_SHA1_CERT, "rsa", "inter.sha1.cert");
+    (RSA_MUSTSTAPLE_DELEGATED_OCSP, "rsa", "muststaple.delegated.ocsp.der");
+    (RSA_MUSTSTAPLE_EXPIRED_OCSP, "rsa", "muststaple.expired.ocsp.der");
+    (RSA_MUSTSTAPLE_GOOD_SHA256_OCSP, "rsa", "muststaple.good-sha256.ocsp.der");
+    (RSA_MUSTSTAPLE_GOOD_OCSP, "rsa", "muststaple.good.ocsp.der");
+    (RSA_MUSTSTAPLE_NOT_RESPONDER_OCSP, "rsa", "muststaple.not-responder.ocsp.der");
+    (RSA_MUSTSTAPLE_REVOKED_OCSP, "rsa", "muststaple.revoked.ocsp.der");
+    (RSA_MUSTSTAPLE_WRONG_SIGNER_OCSP, "rsa", "muststaple.wrong-signer.ocsp.der");
+    (RSA_OTHER_SERIAL_GOOD_OCSP, "rsa", "other-serial.good.ocsp.der");
+}
+
+/// Returns the notAfter time of `cert`.
+pub fn cert_not_after(cert: &Certificate) -> SystemTime {
+    fn read(input: &[u8]) -> (u8, &[u8], &[u8]) {
+        let (tag, len, rest) = match input[1] {
+            len @ 0..=0x7f => (input[0], len as usize, &input[2..]),
+            0x81 => (input[0], input[2] as usize, &input[3..]),
+            0x82 => (
+                input[0],
+                (input[2] as usize) << 8 | input[3] as usize,
+                &input[4..],
+            ),
+            _ => panic!("unsupported length"),
+        };
+        (tag, &rest[..len], &rest[len..])
+    }
+
+    let (_, cert, _) = read(&cert.0);
+    let (_, tbs, _) = read(cert);
+    let (tag, _version, rest) = read(tbs);
+    assert_eq!(tag, 0xa0);
+    let (_serial, _, rest) = read(rest);
+    let (_signature, _, rest) = read(rest);
+    let (_issuer, _, rest) = read(rest);
+    let (_, validity, _) = read(rest);
+    let (_not_before, _, rest) = read(validity);
+    let (tag, not_after, _) = read(rest);
+
+    // UTCTime: YYMMDDHHMMSSZ
+    assert_eq!(tag, 0x17);
+    let field = |i: usize| -> u64 {
+        std::str::from_utf8(&not_after[i..i + 2])
+            .unwrap()
+            .parse()
+            .unwrap()
+    };
+    let (year, month, day) = (2000 + field(0), field(2), field(4));
+
+    let days_before_year = (1970..year)
+        .map(|y| if y % 4 == 0 { 366 } else { 365 })
+        .sum::<u64>();
+    let days_before_month = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31][..month as usize - 1]
+        .iter()
+        .sum::<u64>()
+        + if month > 2 && year % 4 == 0 { 1 } else { 0 };
+    let days = days_before_year + days_before_month + day - 1;
+    let secs = ((days * 24 + field(6)) * 60 + field(8)) * 60 + field(10);
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Returns the DER-encoded SubjectPublicKeyInfo of `cert`.
+pub fn cert_spki(cert: &Certificate) -> Vec<u8> {
+    // Returns the first element of `input` with and without its header,
+    // and the rest of `input`.
+    fn read(input: &[u8]) -> (&[u8], &[u8], &[u8]) {
+        let (header, len) = match input[1] {
+            len @ 0..=0x7f => (2, len as usize),
+            0x81 => (3, input[2] as usize),
+            0x82 => (4, (input[2] as usize) << 8 | input[3] as usize),
+            _ => panic!("unsupported length"),
+        };
+        let (element, rest) = input.split_at(header + len);
+        (element, &element[header..], rest)
+    }
+
+    let (_, cert, _) = read(&cert.0);
+    let (_, tbs, _) = read(cert);
+    let (_version, _, rest) = read(tbs);
+    let (_serial, _, rest) = read(rest);
+    let (_signature, _, rest) = read(rest);
+    let (_issuer, _, rest) = read(rest);
+    let (_validity, _, rest) = read(rest);
+    let (_subject, _, rest) = read(rest);
+    let (spki, _, _) = read(rest);
+    spki.to_vec()
 }
 
 pub fn transfer(
@@ -193,6 +329,52 @@ impl KeyType {
             .collect()
     }
 
+    pub fn get_must_staple_chain(&self) -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut io::BufReader::new(
+            self.bytes_for("end.muststaple.fullchain"),
+        ))
+        .unwrap()
+        .iter()
+        .map(|v| Certificate(v.clone()))
+        .collect()
+    }
+
+    pub fn get_dc_chain(&self, name: &str) -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut io::BufReader::new(
+            self.bytes_for(&format!("{}.fullchain", name)),
+        ))
+        .unwrap()
+        .iter()
+        .map(|v| Certificate(v.clone()))
+        .collect()
+    }
+
+    pub fn get_ip_chain(&self) -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut io::BufReader::new(self.bytes_for("end.ip.fullchain")))
+            .unwrap()
+            .iter()
+            .map(|v| Certificate(v.clone()))
+            .collect()
+    }
+
+    pub fn get_idn_chain(&self) -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut io::BufReader::new(self.bytes_for("end.idn.fullchain")))
+            .unwrap()
+            .iter()
+            .map(|v| Certificate(v.clone()))
+            .collect()
+    }
+
+    /// The intermediate certificate, signed by the CA using SHA-1.
+    /// Only available for RSA.
+    pub fn get_sha1_intermediate(&self) -> Certificate {
+        Certificate(
+            rustls_pemfile::certs(&mut io::BufReader::new(self.bytes_for("inter.sha1.cert")))
+                .unwrap()
+                .remove(0),
+        )
+    }
+
     pub fn get_key(&self) -> PrivateKey {
         PrivateKey(
             rustls_pemfile::pkcs8_private_keys(&mut io::BufReader::new(self.bytes_for("end.key")))
@@ -209,7 +391,17 @@ impl KeyType {
             .collect()
     }
 
-    fn get_client_key(&self) -> PrivateKey {
+    pub fn get_crl(&self, name: &str) -> Vec<u8> {
+        self.bytes_for(&format!("{}.crl.der", name))
+            .to_vec()
+    }
+
+    pub fn get_ocsp(&self, name: &str) -> Vec<u8> {
+        self.bytes_for(&format!("{}.ocsp.der", name))
+            .to_vec()
+    }
+
+    pub fn get_client_key(&self) -> PrivateKey {
         PrivateKey(
             rustls_pemfile::pkcs8_private_keys(&mut io::BufReader::new(
                 self.bytes_for("client.key"),
@@ -282,6 +474,26 @@ pub fn make_server_config_with_mandatory_client_auth(kt: KeyType) -> ServerConfi
         .unwrap()
 }
 
+pub fn make_server_config_with_client_crls(
+    kt: KeyType,
+    crls: &[&str],
+    expiry_policy: CrlExpiryPolicy,
+) -> ServerConfig {
+    let crls = crls
+        .iter()
+        .map(|name| kt.get_crl(name))
+        .collect();
+    let client_auth =
+        AllowAnyAuthenticatedClient::new_with_crls(get_client_root_store(kt), crls, expiry_policy)
+            .unwrap();
+
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_auth)
+        .with_single_cert(kt.get_chain(), kt.get_key())
+        .unwrap()
+}
+
 pub fn finish_client_config(
     kt: KeyType,
     config: rustls::ConfigBuilder<ClientConfig, rustls::WantsVerifier>,
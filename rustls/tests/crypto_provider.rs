@@ -0,0 +1,108 @@
+//! Tests of [`rustls::crypto::CryptoProvider`] using a provider that
+//! counts its uses and defers to *ring*.
+
+#[allow(dead_code)]
+mod common;
+
+use crate::common::{
+    do_handshake, make_client_config_with_versions, make_pair_for_arc_configs,
+    make_server_config_with_versions, transfer, KeyType,
+};
+use rustls::crypto::ring::Ring;
+use rustls::crypto::{AeadKey, CryptoProvider, HashAlgorithm, HashContext, HmacKey, Output};
+use rustls::{BulkAlgorithm, Error};
+
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Default)]
+struct CountingProvider {
+    hashes: AtomicUsize,
+    hmacs: AtomicUsize,
+    hkdfs: AtomicUsize,
+    aeads: AtomicUsize,
+}
+
+impl CryptoProvider for CountingProvider {
+    fn start_hash(&self, alg: HashAlgorithm) -> Box<dyn HashContext> {
+        self.hashes
+            .fetch_add(1, Ordering::SeqCst);
+        Ring.start_hash(alg)
+    }
+
+    fn hmac_key(&self, alg: HashAlgorithm, key: &[u8]) -> Box<dyn HmacKey> {
+        self.hmacs
+            .fetch_add(1, Ordering::SeqCst);
+        Ring.hmac_key(alg, key)
+    }
+
+    fn hkdf_extract(&self, alg: HashAlgorithm, salt: &[u8], ikm: &[u8]) -> Output {
+        self.hkdfs
+            .fetch_add(1, Ordering::SeqCst);
+        Ring.hkdf_extract(alg, salt, ikm)
+    }
+
+    fn hkdf_expand(
+        &self,
+        alg: HashAlgorithm,
+        prk: &[u8],
+        info: &[&[u8]],
+        out: &mut [u8],
+    ) -> Result<(), Error> {
+        self.hkdfs
+            .fetch_add(1, Ordering::SeqCst);
+        Ring.hkdf_expand(alg, prk, info, out)
+    }
+
+    fn aead_key(&self, alg: BulkAlgorithm, key: &[u8]) -> Result<Box<dyn AeadKey>, Error> {
+        self.aeads
+            .fetch_add(1, Ordering::SeqCst);
+        Ring.aead_key(alg, key)
+    }
+}
+
+fn handshake_and_exchange_data(version: &'static rustls::SupportedProtocolVersion) {
+    let provider = Arc::new(CountingProvider::default());
+
+    let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+    client_config.crypto_provider = provider.clone();
+    let mut server_config = make_server_config_with_versions(KeyType::Rsa, &[version]);
+    server_config.crypto_provider = provider.clone();
+
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), &Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    let mut buf = [0u8; 5];
+    server
+        .reader()
+        .read_exact(&mut buf)
+        .unwrap();
+    assert_eq!(&buf, b"hello");
+
+    assert!(provider.hashes.load(Ordering::SeqCst) > 0);
+    assert!(provider.hmacs.load(Ordering::SeqCst) > 0);
+    // Two traffic keys each for client and server.
+    assert!(provider.aeads.load(Ordering::SeqCst) >= 4);
+    if version == &rustls::version::TLS13 {
+        assert!(provider.hkdfs.load(Ordering::SeqCst) > 0);
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn tls12_uses_configured_provider() {
+    handshake_and_exchange_data(&rustls::version::TLS12);
+}
+
+#[test]
+fn tls13_uses_configured_provider() {
+    handshake_and_exchange_data(&rustls::version::TLS13);
+}
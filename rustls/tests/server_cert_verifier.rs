@@ -4,16 +4,24 @@
 
 mod common;
 use crate::common::{
-    do_handshake, do_handshake_until_both_error, make_client_config_with_versions,
-    make_pair_for_arc_configs, make_server_config, ErrorFromPeer, ALL_KEY_TYPES,
+    cert_not_after, cert_spki, do_handshake, do_handshake_until_both_error,
+    make_client_config_with_versions, make_pair_for_arc_configs, make_server_config, ErrorFromPeer,
+    KeyType, ALL_KEY_TYPES,
 };
 use rustls::client::{
-    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier, WebPkiVerifier,
+    AndVerifier, CrlExpiryPolicy, HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+    SpkiPinVerifier, WebPkiVerifier,
 };
 use rustls::internal::msgs::enums::AlertDescription;
 use rustls::internal::msgs::handshake::DigitallySignedStruct;
-use rustls::{Certificate, Error, SignatureScheme};
+use rustls::{
+    Certificate, ClientConfig, Error, OtherError, RootCertStore, ServerName, SignatureScheme,
+};
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 #[test]
 fn client_can_override_certificate_verification() {
@@ -31,10 +39,50 @@ fn client_can_override_certificate_verification() {
             let (mut client, mut server) =
                 make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
             do_handshake(&mut client, &mut server);
+            assert_eq!(client.get_verified_cert_chain(), None);
         }
     }
 }
 
+#[test]
+fn cloned_client_config_shares_certificate_verifier() {
+    let mut root_store = RootCertStore::empty();
+    for _ in 0..100 {
+        for kt in ALL_KEY_TYPES.iter() {
+            for cert in kt.get_chain() {
+                root_store.add(&cert).unwrap();
+            }
+        }
+    }
+
+    let mut client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    let mut cloned = client_config.clone();
+    assert!(Arc::ptr_eq(
+        client_config
+            .dangerous()
+            .certificate_verifier(),
+        cloned
+            .dangerous()
+            .certificate_verifier()
+    ));
+
+    // Replacing the clone's verifier leaves the original alone.
+    cloned
+        .dangerous()
+        .set_certificate_verifier(Arc::new(MockServerVerifier::accepts_anything()));
+    assert!(!Arc::ptr_eq(
+        client_config
+            .dangerous()
+            .certificate_verifier(),
+        cloned
+            .dangerous()
+            .certificate_verifier()
+    ));
+}
+
 #[test]
 fn client_can_override_certificate_verification_and_reject_certificate() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -64,6 +112,54 @@ fn client_can_override_certificate_verification_and_reject_certificate() {
     }
 }
 
+/// An application-defined reason for rejecting a certificate.
+#[derive(Debug, PartialEq)]
+enum VerifierVerdict {
+    RetryLater,
+}
+
+impl fmt::Display for VerifierVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for VerifierVerdict {}
+
+#[test]
+fn client_can_downcast_error_from_certificate_verifier() {
+    let verifier = Arc::new(MockServerVerifier::rejects_certificate(Error::Other(
+        OtherError(Arc::new(VerifierVerdict::RetryLater)),
+    )));
+
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config
+            .dangerous()
+            .set_certificate_verifier(verifier.clone());
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        let errs = do_handshake_until_both_error(&mut client, &mut server).unwrap_err();
+
+        match &errs[..] {
+            [ErrorFromPeer::Client(Error::Other(err)), ErrorFromPeer::Server(server_err)] => {
+                assert_eq!(
+                    err.0.downcast_ref::<VerifierVerdict>(),
+                    Some(&VerifierVerdict::RetryLater)
+                );
+                assert_eq!(
+                    server_err,
+                    &Error::AlertReceived(AlertDescription::BadCertificate)
+                );
+            }
+            _ => panic!("unexpected errors {:?}", errs),
+        }
+    }
+}
+
 #[test]
 fn client_can_override_certificate_verification_and_reject_tls12_signatures() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -147,6 +243,341 @@ fn client_can_override_certificate_verification_and_offer_no_signature_schemes()
     }
 }
 
+fn make_crl_verifier(kt: KeyType, crls: &[&str], expiry_policy: CrlExpiryPolicy) -> WebPkiVerifier {
+    let mut roots = RootCertStore::empty();
+    roots.add(&kt.get_chain()[2]).unwrap();
+
+    let mut verifier = WebPkiVerifier::new(roots, None);
+    verifier
+        .with_crls(
+            crls.iter()
+                .map(|name| kt.get_crl(name))
+                .collect(),
+        )
+        .unwrap();
+    verifier.set_crl_expiry_policy(expiry_policy);
+    verifier
+}
+
+fn crl_test(crls: &[&str], expiry_policy: CrlExpiryPolicy, expect_err: Option<Error>) {
+    for kt in ALL_KEY_TYPES.iter() {
+        let verifier = Arc::new(make_crl_verifier(*kt, crls, expiry_policy));
+        let server_config = Arc::new(make_server_config(*kt));
+
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config
+                .dangerous()
+                .set_certificate_verifier(verifier.clone());
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            match &expect_err {
+                None => {
+                    do_handshake(&mut client, &mut server);
+                }
+                Some(err) => {
+                    let errs = do_handshake_until_both_error(&mut client, &mut server);
+                    let alert = match err {
+                        Error::CertRevoked => AlertDescription::CertificateRevoked,
+                        _ => AlertDescription::BadCertificate,
+                    };
+                    assert_eq!(
+                        errs,
+                        Err(vec![
+                            ErrorFromPeer::Client(err.clone()),
+                            ErrorFromPeer::Server(Error::AlertReceived(alert)),
+                        ])
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn client_accepts_server_cert_not_in_crl() {
+    crl_test(&["client.revoked"], CrlExpiryPolicy::Reject, None);
+}
+
+#[test]
+fn client_rejects_revoked_server_cert() {
+    crl_test(
+        &["client.revoked", "end.revoked"],
+        CrlExpiryPolicy::Reject,
+        Some(Error::CertRevoked),
+    );
+}
+
+#[test]
+fn client_rejects_revoked_intermediate() {
+    crl_test(
+        &["client.revoked", "inter.revoked"],
+        CrlExpiryPolicy::Reject,
+        Some(Error::CertRevoked),
+    );
+}
+
+#[test]
+fn client_ignores_crl_from_other_issuer() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let other_kt = match kt {
+            KeyType::Rsa => KeyType::Ecdsa,
+            _ => KeyType::Rsa,
+        };
+        let mut roots = RootCertStore::empty();
+        roots.add(&kt.get_chain()[2]).unwrap();
+        let mut verifier = WebPkiVerifier::new(roots, None);
+        verifier
+            .with_crls(vec![other_kt.get_crl("end.revoked")])
+            .unwrap();
+
+        let mut client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+        client_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(verifier));
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &Arc::new(make_server_config(*kt)));
+        do_handshake(&mut client, &mut server);
+    }
+}
+
+#[test]
+fn client_rejects_crl_not_signed_by_issuer() {
+    for kt in ALL_KEY_TYPES.iter() {
+        // This names the server certificate's issuer, but its signature
+        // no longer verifies.
+        let mut crl = kt.get_crl("client.revoked");
+        *crl.last_mut().unwrap() ^= 0x01;
+
+        let mut roots = RootCertStore::empty();
+        roots.add(&kt.get_chain()[2]).unwrap();
+        let mut verifier = WebPkiVerifier::new(roots, None);
+        verifier.with_crls(vec![crl]).unwrap();
+
+        let mut client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+        client_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(verifier));
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &Arc::new(make_server_config(*kt)));
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Client(Error::InvalidCrl(
+                    "CRL is not signed by certificate issuer".into()
+                )),
+                ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::BadCertificate)),
+            ])
+        );
+    }
+}
+
+#[test]
+fn verifier_rejects_crl_with_unsupported_signature() {
+    let mut verifier = WebPkiVerifier::new(RootCertStore::empty(), None);
+    assert!(matches!(
+        verifier.with_crls(vec![KeyType::Rsa.get_crl("inter.pss")]),
+        Err(Error::InvalidCrl(_))
+    ));
+}
+
+#[test]
+fn client_rejects_expired_crl() {
+    crl_test(
+        &["inter.expired"],
+        CrlExpiryPolicy::Reject,
+        Some(Error::InvalidCrl("CRL has expired".into())),
+    );
+}
+
+#[test]
+fn client_can_allow_expired_crl() {
+    crl_test(&["inter.expired"], CrlExpiryPolicy::Warn, None);
+}
+
+#[test]
+fn client_rejects_malformed_crl() {
+    let mut verifier = WebPkiVerifier::new(RootCertStore::empty(), None);
+    assert_eq!(
+        verifier.with_crls(vec![b"not a crl".to_vec()]),
+        Err(Error::InvalidCrl("unsupported or malformed CRL".into()))
+    );
+}
+
+fn spki_pin(cert: &Certificate) -> [u8; 32] {
+    let mut pin = [0u8; 32];
+    pin.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, &cert_spki(cert)).as_ref());
+    pin
+}
+
+fn pin_test(pin: impl Fn(KeyType) -> Certificate, leaf_only: bool, expect_err: Option<Error>) {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut roots = RootCertStore::empty();
+        roots.add(&kt.get_chain()[2]).unwrap();
+        let mut verifier =
+            SpkiPinVerifier::new(WebPkiVerifier::new(roots, None), vec![spki_pin(&pin(*kt))]);
+        verifier.set_leaf_only(leaf_only);
+        let verifier = Arc::new(verifier);
+        let server_config = Arc::new(make_server_config(*kt));
+
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config
+                .dangerous()
+                .set_certificate_verifier(verifier.clone());
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            match &expect_err {
+                None => {
+                    do_handshake(&mut client, &mut server);
+                }
+                Some(err) => {
+                    let errs = do_handshake_until_both_error(&mut client, &mut server);
+                    assert_eq!(
+                        errs,
+                        Err(vec![
+                            ErrorFromPeer::Client(err.clone()),
+                            ErrorFromPeer::Server(Error::AlertReceived(
+                                AlertDescription::BadCertificate
+                            )),
+                        ])
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn client_accepts_pinned_leaf() {
+    pin_test(|kt| kt.get_chain()[0].clone(), false, None);
+    pin_test(|kt| kt.get_chain()[0].clone(), true, None);
+}
+
+#[test]
+fn client_accepts_pinned_intermediate() {
+    pin_test(|kt| kt.get_chain()[1].clone(), false, None);
+}
+
+#[test]
+fn client_rejects_pinned_intermediate_when_leaf_only() {
+    pin_test(
+        |kt| kt.get_chain()[1].clone(),
+        true,
+        Some(Error::PinMismatch),
+    );
+}
+
+#[test]
+fn client_rejects_unpinned_chain() {
+    pin_test(
+        |kt| kt.get_client_chain()[0].clone(),
+        false,
+        Some(Error::PinMismatch),
+    );
+}
+
+fn and_verifier_test(verifier: AndVerifier, kt: KeyType, expect_err: Option<Error>) {
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(verifier));
+
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), &Arc::new(make_server_config(kt)));
+    match expect_err {
+        None => {
+            do_handshake(&mut client, &mut server);
+        }
+        Some(err) => {
+            let errs = do_handshake_until_both_error(&mut client, &mut server);
+            assert_eq!(
+                errs,
+                Err(vec![
+                    ErrorFromPeer::Client(err),
+                    ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::BadCertificate)),
+                ])
+            );
+        }
+    }
+}
+
+fn make_webpki_verifier(kt: KeyType) -> Arc<dyn ServerCertVerifier> {
+    let mut roots = RootCertStore::empty();
+    roots.add(&kt.get_chain()[2]).unwrap();
+    Arc::new(WebPkiVerifier::new(roots, None))
+}
+
+#[test]
+fn client_and_verifier_runs_check_after_inner_verifier() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let chain = kt.get_chain();
+        let verifier = AndVerifier::with_check(
+            make_webpki_verifier(*kt),
+            move |end_entity, intermediates| {
+                assert_eq!(end_entity, &chain[0]);
+                assert_eq!(intermediates, &chain[1..]);
+                Ok(())
+            },
+        );
+        and_verifier_test(verifier, *kt, None);
+    }
+}
+
+#[test]
+fn client_and_verifier_rejects_when_check_fails() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let verifier = AndVerifier::with_check(make_webpki_verifier(*kt), |_, _| {
+            Err(Error::General("wrong organization".into()))
+        });
+        and_verifier_test(
+            verifier,
+            *kt,
+            Some(Error::General("wrong organization".into())),
+        );
+    }
+}
+
+#[test]
+fn client_and_verifier_rejects_when_inner_verifier_fails() {
+    let checked = Arc::new(AtomicBool::new(false));
+    let verifier = {
+        let checked = checked.clone();
+        AndVerifier::with_check(
+            Arc::new(MockServerVerifier::rejects_certificate(
+                Error::CorruptMessage,
+            )),
+            move |_, _| {
+                checked.store(true, Ordering::SeqCst);
+                Ok(())
+            },
+        )
+    };
+    and_verifier_test(verifier, KeyType::Rsa, Some(Error::CorruptMessage));
+    assert!(!checked.load(Ordering::SeqCst));
+}
+
+#[test]
+fn client_and_verifier_combines_two_verifiers() {
+    let verifier = AndVerifier::new(
+        make_webpki_verifier(KeyType::Rsa),
+        Arc::new(MockServerVerifier::rejects_certificate(
+            Error::CorruptMessage,
+        )),
+    );
+    and_verifier_test(verifier, KeyType::Rsa, Some(Error::CorruptMessage));
+
+    let verifier = AndVerifier::new(
+        make_webpki_verifier(KeyType::Rsa),
+        Arc::new(MockServerVerifier::accepts_anything()),
+    );
+    and_verifier_test(verifier, KeyType::Rsa, None);
+}
+
 pub struct MockServerVerifier {
     cert_rejection_error: Option<Error>,
     tls12_signature_error: Option<Error>,
@@ -269,3 +700,191 @@ impl Default for MockServerVerifier {
         }
     }
 }
+
+fn verify_after_expiry(kt: KeyType, clock_skew: Duration) -> Result<ServerCertVerified, Error> {
+    let chain = kt.get_chain();
+    let mut roots = RootCertStore::empty();
+    roots.add(&chain[2]).unwrap();
+    let mut verifier = WebPkiVerifier::new(roots, None);
+    verifier.set_clock_skew(clock_skew);
+
+    verifier.verify_server_cert(
+        &chain[0],
+        &chain[1..],
+        &ServerName::try_from("testserver.com").unwrap(),
+        &mut std::iter::empty(),
+        &[],
+        cert_not_after(&chain[0]) + Duration::from_secs(30),
+    )
+}
+
+#[test]
+fn client_rejects_recently_expired_cert_without_clock_skew() {
+    for kt in ALL_KEY_TYPES.iter() {
+        assert_eq!(
+            verify_after_expiry(*kt, Duration::from_secs(0)).err(),
+            Some(Error::CertExpired)
+        );
+    }
+}
+
+#[test]
+fn client_accepts_recently_expired_cert_within_clock_skew() {
+    for kt in ALL_KEY_TYPES.iter() {
+        assert!(verify_after_expiry(*kt, Duration::from_secs(60)).is_ok());
+    }
+}
+
+fn verify_with_intermediates(
+    intermediates: &[Certificate],
+    reject_weak_signature_algorithms: bool,
+) -> Result<ServerCertVerified, Error> {
+    let chain = KeyType::Rsa.get_chain();
+    let mut roots = RootCertStore::empty();
+    roots.add(&chain[2]).unwrap();
+    let mut verifier = WebPkiVerifier::new(roots, None);
+    verifier.set_reject_weak_signature_algorithms(reject_weak_signature_algorithms);
+
+    verifier.verify_server_cert(
+        &chain[0],
+        intermediates,
+        &ServerName::try_from("testserver.com").unwrap(),
+        &mut std::iter::empty(),
+        &[],
+        cert_not_after(&chain[0]) - Duration::from_secs(30),
+    )
+}
+
+#[test]
+fn client_always_rejects_sha1_intermediate_in_path() {
+    let sha1_inter = KeyType::Rsa.get_sha1_intermediate();
+
+    // webpki cannot build a path, but doesn't say why
+    assert_eq!(
+        verify_with_intermediates(std::slice::from_ref(&sha1_inter), false).err(),
+        Some(Error::UnknownIssuer)
+    );
+    assert_eq!(
+        verify_with_intermediates(&[sha1_inter], true).err(),
+        Some(Error::InvalidCertificateSignatureType)
+    );
+}
+
+#[test]
+fn client_rejects_unused_sha1_intermediate_only_if_configured() {
+    let chain = KeyType::Rsa.get_chain();
+    let intermediates = [chain[1].clone(), KeyType::Rsa.get_sha1_intermediate()];
+
+    assert!(verify_with_intermediates(&chain[1..2], true).is_ok());
+    assert!(verify_with_intermediates(&intermediates, false).is_ok());
+    assert_eq!(
+        verify_with_intermediates(&intermediates, true).err(),
+        Some(Error::InvalidCertificateSignatureType)
+    );
+}
+
+fn verify_at(
+    kt: KeyType,
+    roots_kt: KeyType,
+    name: &str,
+    now: SystemTime,
+) -> Result<ServerCertVerified, Error> {
+    let chain = kt.get_chain();
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(&roots_kt.get_chain()[2])
+        .unwrap();
+    let verifier = WebPkiVerifier::new(roots, None);
+
+    verifier.verify_server_cert(
+        &chain[0],
+        &chain[1..],
+        &ServerName::try_from(name).unwrap(),
+        &mut std::iter::empty(),
+        &[],
+        now,
+    )
+}
+
+#[test]
+fn client_rejects_cert_not_valid_yet() {
+    let long_ago = SystemTime::UNIX_EPOCH + Duration::from_secs(86_400);
+    for kt in ALL_KEY_TYPES.iter() {
+        assert_eq!(
+            verify_at(*kt, *kt, "testserver.com", long_ago).err(),
+            Some(Error::CertNotValidYet)
+        );
+    }
+}
+
+#[test]
+fn client_rejects_cert_for_wrong_name() {
+    for kt in ALL_KEY_TYPES.iter() {
+        assert_eq!(
+            verify_at(*kt, *kt, "not-testserver.com", SystemTime::now()).err(),
+            Some(Error::CertNotValidForName)
+        );
+    }
+}
+
+fn verify_ignoring_name(
+    kt: KeyType,
+    roots_kt: KeyType,
+    name: &str,
+    ignore_name: bool,
+) -> Result<ServerCertVerified, Error> {
+    let chain = kt.get_chain();
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(&roots_kt.get_chain()[2])
+        .unwrap();
+    let mut verifier = WebPkiVerifier::new(roots, None);
+    verifier.set_verify_is_valid_cert_ignoring_name(ignore_name);
+
+    verifier.verify_server_cert(
+        &chain[0],
+        &chain[1..],
+        &ServerName::try_from(name).unwrap(),
+        &mut std::iter::empty(),
+        &[],
+        SystemTime::now(),
+    )
+}
+
+#[test]
+fn client_can_ignore_server_name_but_not_chain() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for name in &["not-testserver.com", "192.0.2.1"] {
+            assert!(verify_ignoring_name(*kt, *kt, name, true).is_ok());
+            assert_eq!(
+                verify_ignoring_name(*kt, *kt, name, false).err(),
+                Some(Error::CertNotValidForName)
+            );
+        }
+
+        let other_kt = if *kt == KeyType::Rsa {
+            KeyType::Ecdsa
+        } else {
+            KeyType::Rsa
+        };
+        assert_eq!(
+            verify_ignoring_name(*kt, other_kt, "not-testserver.com", true).err(),
+            Some(Error::UnknownIssuer)
+        );
+    }
+}
+
+#[test]
+fn client_rejects_cert_from_unknown_issuer() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let other_kt = if *kt == KeyType::Rsa {
+            KeyType::Ecdsa
+        } else {
+            KeyType::Rsa
+        };
+        assert_eq!(
+            verify_at(*kt, other_kt, "testserver.com", SystemTime::now()).err(),
+            Some(Error::UnknownIssuer)
+        );
+    }
+}
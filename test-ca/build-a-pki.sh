@@ -156,6 +156,66 @@ for kt in rsa ecdsa eddsa ; do
             -set_serial 456 \
             -extensions v3_end -extfile openssl.cnf
 
+  openssl x509 -req \
+            -in $kt/end.req \
+            -out $kt/end.muststaple.cert \
+            -CA $kt/inter.cert \
+            -CAkey $kt/inter.key \
+            -sha256 \
+            -days 2000 \
+            -set_serial 457 \
+            -extensions v3_end_must_staple -extfile openssl.cnf
+
+  openssl x509 -req \
+            -in $kt/end.req \
+            -out $kt/end.dc.cert \
+            -CA $kt/inter.cert \
+            -CAkey $kt/inter.key \
+            -sha256 \
+            -days 2000 \
+            -set_serial 461 \
+            -extensions v3_end_dc -extfile openssl.cnf
+
+  openssl x509 -req \
+            -in $kt/end.req \
+            -out $kt/end.dc-no-extension.cert \
+            -CA $kt/inter.cert \
+            -CAkey $kt/inter.key \
+            -sha256 \
+            -days 2000 \
+            -set_serial 462 \
+            -extensions v3_end -extfile openssl.cnf
+
+  openssl x509 -req \
+            -in $kt/end.req \
+            -out $kt/end.dc-no-key-usage.cert \
+            -CA $kt/inter.cert \
+            -CAkey $kt/inter.key \
+            -sha256 \
+            -days 2000 \
+            -set_serial 463 \
+            -extensions v3_end_dc_no_key_usage -extfile openssl.cnf
+
+  openssl x509 -req \
+            -in $kt/end.req \
+            -out $kt/end.ip.cert \
+            -CA $kt/inter.cert \
+            -CAkey $kt/inter.key \
+            -sha256 \
+            -days 2000 \
+            -set_serial 458 \
+            -extensions v3_end_ip -extfile openssl.cnf
+
+  openssl x509 -req \
+            -in $kt/end.req \
+            -out $kt/end.idn.cert \
+            -CA $kt/inter.cert \
+            -CAkey $kt/inter.key \
+            -sha256 \
+            -days 2000 \
+            -set_serial 459 \
+            -extensions v3_end_idn -extfile openssl.cnf
+
   openssl x509 -req \
             -in $kt/client.req \
             -out $kt/client.cert \
@@ -166,11 +226,109 @@ for kt in rsa ecdsa eddsa ; do
             -set_serial 789 \
             -extensions v3_client -extfile openssl.cnf
 
+  openssl x509 -req \
+            -in $kt/end.req \
+            -out $kt/end.ocsp.cert \
+            -CA $kt/inter.cert \
+            -CAkey $kt/inter.key \
+            -sha256 \
+            -days 2000 \
+            -set_serial 460 \
+            -extensions v3_ocsp -extfile openssl.cnf
+
   cat $kt/inter.cert $kt/ca.cert > $kt/end.chain
   cat $kt/end.cert $kt/inter.cert $kt/ca.cert > $kt/end.fullchain
+  cat $kt/end.muststaple.cert $kt/inter.cert $kt/ca.cert > $kt/end.muststaple.fullchain
+  cat $kt/end.dc.cert $kt/inter.cert $kt/ca.cert > $kt/end.dc.fullchain
+  cat $kt/end.dc-no-extension.cert $kt/inter.cert $kt/ca.cert > $kt/end.dc-no-extension.fullchain
+  cat $kt/end.dc-no-key-usage.cert $kt/inter.cert $kt/ca.cert > $kt/end.dc-no-key-usage.fullchain
+  cat $kt/end.ip.cert $kt/inter.cert $kt/ca.cert > $kt/end.ip.fullchain
+  cat $kt/end.idn.cert $kt/inter.cert $kt/ca.cert > $kt/end.idn.fullchain
 
   cat $kt/inter.cert $kt/ca.cert > $kt/client.chain
   cat $kt/client.cert $kt/inter.cert $kt/ca.cert > $kt/client.fullchain
 
   openssl asn1parse -in $kt/ca.cert -out $kt/ca.der > /dev/null
 done
+
+# an intermediate signed using SHA-1, for testing rejection of weak signatures
+openssl x509 -req \
+          -in rsa/inter.req \
+          -out rsa/inter.sha1.cert \
+          -CA rsa/ca.cert \
+          -CAkey rsa/ca.key \
+          -sha1 \
+          -days 3650 \
+          -set_serial 124 \
+          -extensions v3_inter -extfile openssl.cnf
+
+# make_crl <keytype> <issuer> <output> [<revoked cert>] [<extra args>]
+make_crl() {
+  rm -f crl.index*
+  touch crl.index
+
+  if [ -n "$4" ] ; then
+    openssl ca -config openssl.cnf -name crl_ca \
+              -keyfile $1/$2.key \
+              -cert $1/$2.cert \
+              -revoke $1/$4.cert
+  fi
+
+  openssl ca -config openssl.cnf -name crl_ca \
+            -gencrl \
+            -keyfile $1/$2.key \
+            -cert $1/$2.cert \
+            -out crl.pem \
+            $5
+
+  openssl crl -in crl.pem -outform DER -out $1/$3.crl.der
+  rm -f crl.index* crl.pem
+}
+
+for kt in rsa ecdsa eddsa ; do
+  make_crl $kt inter end.revoked end
+  make_crl $kt inter client.revoked client
+  make_crl $kt ca inter.revoked inter
+  make_crl $kt inter inter.expired "" "-crl_lastupdate 20190101000000Z -crl_nextupdate 20190102000000Z"
+done
+
+# a CRL signed using RSASSA-PSS, which is not supported
+make_crl rsa inter inter.pss "" "-sigopt rsa_padding_mode:pss"
+
+# make_ocsp <keytype> <output> <cert> <status> <signer> <validity> [<extra args>]
+make_ocsp() {
+  rm -f crl.index* ocsp.req
+  touch crl.index
+
+  openssl ca -config openssl.cnf -name crl_ca \
+            -keyfile $1/inter.key \
+            -cert $1/inter.cert \
+            -$4 $1/$3.cert
+
+  openssl ocsp -issuer $1/inter.cert \
+            $7 \
+            -cert $1/$3.cert \
+            -no_nonce \
+            -reqout ocsp.req
+
+  openssl ocsp -index crl.index \
+            -CA $1/inter.cert \
+            -rsigner $1/$5.cert \
+            -rkey $1/${5%%.*}.key \
+            -reqin ocsp.req \
+            -respout $1/$2.ocsp.der \
+            $6
+
+  rm -f crl.index* ocsp.req
+}
+
+for kt in rsa ecdsa eddsa ; do
+  make_ocsp $kt muststaple.good end.muststaple valid inter "-ndays 7"
+  make_ocsp $kt muststaple.good-sha256 end.muststaple valid inter "-ndays 7" -sha256
+  make_ocsp $kt muststaple.revoked end.muststaple revoke inter "-ndays 7"
+  make_ocsp $kt muststaple.expired end.muststaple valid inter "-nmin 1"
+  make_ocsp $kt muststaple.delegated end.muststaple valid end.ocsp "-ndays 7"
+  make_ocsp $kt muststaple.not-responder end.muststaple valid end.muststaple "-ndays 7"
+  make_ocsp $kt muststaple.wrong-signer end.muststaple valid ca "-ndays 7"
+  make_ocsp $kt other-serial.good end valid inter "-ndays 7"
+done